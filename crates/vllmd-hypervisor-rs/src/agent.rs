@@ -0,0 +1,768 @@
+use crate::forward::relay;
+use anyhow::{Result, anyhow, bail, Context};
+use log::{info, warn, debug};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// vsock port the guest agent listens on
+pub const AGENT_PORT: u32 = 1024;
+
+/// Host vsock port the guest connects to when its workload is ready
+pub const READY_PORT: u32 = 1025;
+
+// Line the guest sends on the ready port
+const READY_MESSAGE: &str = "READY";
+
+/// Protocol version reported by the guest in `Pong`
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// Oldest protocol version with `FileRead` and appending `FileWrite`. Older agents ignore
+// `append` and would overwrite each chunk of a pushed file with the next.
+const FILE_TRANSFER_VERSION: u32 = 2;
+
+// Upper bound for a single frame header or payload
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// Files are transferred in chunks well below the frame limit
+const FILE_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Largest nonce an attestation report can carry as report data
+pub const ATTESTATION_NONCE_SIZE: usize = 64;
+
+// The kernel's configfs-tsm interface for attestation reports (Linux 6.7 and newer)
+const TSM_REPORT_DIR: &str = "/sys/kernel/config/tsm/report";
+
+/// Request sent by the host. Requests and responses travel as frames: a big-endian u32
+/// length followed by the JSON message, then a big-endian u32 length followed by a raw payload
+/// (empty for most messages).
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// Check that the agent is alive
+    Ping,
+
+    /// Run a command; the guest answers with `Output` frames followed by `Exit`
+    Exec { argv: Vec<String> },
+
+    /// Write the payload to a file in the guest, appending to it when `append` is set
+    FileWrite {
+        path: String,
+        mode: Option<u32>,
+        #[serde(default)]
+        append: bool,
+    },
+
+    /// Read up to `length` bytes of a guest file starting at `offset`
+    FileRead { path: String, offset: u64, length: u32 },
+
+    /// Power off the guest
+    Shutdown,
+
+    /// Report guest resource usage
+    Metrics,
+
+    /// Relay the connection to TCP `port` on the guest loopback; after `Ok` the connection
+    /// carries raw bytes instead of frames
+    Connect { port: u16 },
+
+    /// Produce a confidential computing attestation report binding the payload (up to 64
+    /// bytes of nonce) as report data
+    Attest,
+}
+
+/// Response sent by the guest
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Pong { version: u32 },
+    Ok,
+    /// Command output; the payload holds the bytes
+    Output { stream: OutputStream },
+    Exit { code: i32 },
+    /// File contents; the payload holds the bytes read
+    FileData { size: u64, mode: u32 },
+    Metrics(GuestMetrics),
+    /// Attestation evidence from `provider` (e.g. "sev_guest", "tdx_guest"); the payload holds the report
+    Attestation { provider: String },
+    Error { message: String },
+}
+
+/// Which output stream an `Output` frame belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Guest resource usage read from /proc, and from nvidia-smi for the GPUs the guest drives
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GuestMetrics {
+    pub uptime_secs: f64,
+    pub load_average: [f64; 3],
+    pub memory_total_kb: u64,
+    pub memory_available_kb: u64,
+    /// Empty without nvidia-smi in the guest, and from agents that predate GPU metrics
+    #[serde(default)]
+    pub gpus: Vec<GuestGpu>,
+}
+
+/// One GPU as the guest driver sees it. The driver reports what it cannot read, such as the
+/// power draw of some boards, as not available; those are None.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GuestGpu {
+    pub index: u32,
+    /// Guest PCI address
+    pub pci_bus_id: String,
+    pub name: String,
+    pub utilization_percent: Option<f64>,
+    pub memory_used_bytes: Option<u64>,
+    pub memory_total_bytes: Option<u64>,
+    pub temperature_celsius: Option<f64>,
+    pub power_watts: Option<f64>,
+}
+
+// Fields of `nvidia-smi --query-gpu`, in the order of GuestGpu
+const GPU_QUERY: &str = "index,pci.bus_id,name,utilization.gpu,memory.used,memory.total,temperature.gpu,power.draw";
+
+/// Write one frame
+pub fn write_frame<W: Write, M: Serialize>(writer: &mut W, message: &M, payload: &[u8]) -> Result<()> {
+    let header = serde_json::to_vec(message).context("Failed to serialize agent message")?;
+
+    let mut frame = Vec::with_capacity(8 + header.len() + payload.len());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame).context("Failed to write agent frame")?;
+    writer.flush().context("Failed to write agent frame")
+}
+
+/// Read one frame, returning None at end of stream
+pub fn read_frame<R: Read, M: for<'de> Deserialize<'de>>(reader: &mut R) -> Result<Option<(M, Vec<u8>)>> {
+    let header = match read_chunk(reader)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let payload = read_chunk(reader)?
+        .ok_or_else(|| anyhow!("Agent connection closed inside a frame"))?;
+
+    let message = serde_json::from_slice(&header).context("Failed to parse agent message")?;
+    Ok(Some((message, payload)))
+}
+
+fn read_chunk<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(anyhow!("Failed to read agent frame: {}", e)),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        bail!("Agent frame of {} bytes exceeds the {} byte limit", length, MAX_FRAME_SIZE);
+    }
+
+    let mut chunk = vec![0u8; length];
+    reader.read_exact(&mut chunk).context("Failed to read agent frame")?;
+    Ok(Some(chunk))
+}
+
+/// Host-side connection to the guest agent
+pub struct AgentClient {
+    stream: UnixStream,
+}
+
+impl AgentClient {
+    /// Connect through the host socket of the vsock device (Cloud Hypervisor hybrid vsock)
+    pub fn connect(vsock_socket_path: &str, port: u32) -> Result<Self> {
+        Self::connect_timeout(vsock_socket_path, port, None)
+    }
+
+    /// Connect as `connect` does, with reads and writes limited to `timeout` from the start, so
+    /// a guest that does not answer cannot hold up the caller
+    pub fn connect_timeout(vsock_socket_path: &str, port: u32, timeout: Option<std::time::Duration>) -> Result<Self> {
+        let mut stream = UnixStream::connect(vsock_socket_path)
+            .context(format!("Failed to connect to vsock socket: {}", vsock_socket_path))?;
+        stream.set_read_timeout(timeout).context("Failed to set agent read timeout")?;
+        stream.set_write_timeout(timeout).context("Failed to set agent write timeout")?;
+
+        stream.write_all(format!("CONNECT {}\n", port).as_bytes())
+            .context("Failed to send vsock CONNECT")?;
+
+        // The reply is a single "OK <port>" line; read it byte by byte so no frame data is consumed
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while byte[0] != b'\n' {
+            stream.read_exact(&mut byte)
+                .context(format!("Guest agent is not listening on vsock port {}", port))?;
+            line.push(byte[0]);
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        if !line.starts_with("OK ") {
+            bail!("Unexpected vsock CONNECT reply: {}", line.trim());
+        }
+
+        Ok(Self { stream })
+    }
+
+    /// Send a request with an optional payload
+    pub fn send(&mut self, request: &Request, payload: &[u8]) -> Result<()> {
+        write_frame(&mut self.stream, request, payload)
+    }
+
+    /// Receive the next response, failing on `Error` responses
+    pub fn receive(&mut self) -> Result<(Response, Vec<u8>)> {
+        match read_frame(&mut self.stream)? {
+            Some((Response::Error { message }, _)) => bail!("Guest agent error: {}", message),
+            Some(frame) => Ok(frame),
+            None => bail!("Guest agent closed the connection"),
+        }
+    }
+
+    /// Limit how long reads and writes on the connection may block; `None` waits forever
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>) -> Result<()> {
+        self.stream.set_read_timeout(timeout).context("Failed to set agent read timeout")?;
+        self.stream.set_write_timeout(timeout).context("Failed to set agent write timeout")?;
+        Ok(())
+    }
+
+    /// Switch the connection to a raw byte stream relayed to TCP `port` inside the guest
+    pub fn connect_port(mut self, port: u16) -> Result<UnixStream> {
+        self.send(&Request::Connect { port }, &[])?;
+        match self.receive()? {
+            (Response::Ok, _) => Ok(self.stream),
+            (other, _) => bail!("Unexpected response to connect: {:?}", other),
+        }
+    }
+
+    /// Check that the agent is alive, returning its protocol version
+    pub fn ping(&mut self) -> Result<u32> {
+        self.send(&Request::Ping, &[])?;
+        match self.receive()? {
+            (Response::Pong { version }, _) => Ok(version),
+            (other, _) => bail!("Unexpected response to ping: {:?}", other),
+        }
+    }
+
+    /// Run a command in the guest, passing output to `on_output` as it arrives, and return its exit code
+    pub fn exec<F: FnMut(OutputStream, &[u8])>(&mut self, argv: &[String], mut on_output: F) -> Result<i32> {
+        self.send(&Request::Exec { argv: argv.to_vec() }, &[])?;
+        loop {
+            match self.receive()? {
+                (Response::Output { stream }, data) => on_output(stream, &data),
+                (Response::Exit { code }, _) => return Ok(code),
+                (other, _) => bail!("Unexpected response to exec: {:?}", other),
+            }
+        }
+    }
+
+    // Fail unless the agent speaks a protocol version that can transfer files
+    fn check_file_transfer(&mut self) -> Result<()> {
+        let version = self.ping()?;
+        if version < FILE_TRANSFER_VERSION {
+            bail!("The guest agent speaks protocol version {}, and copying files needs version {}; \
+                   update vllmd-hypervisor in the guest", version, FILE_TRANSFER_VERSION);
+        }
+        Ok(())
+    }
+
+    /// Copy a host file into the guest
+    pub fn push_file(&mut self, host_path: &str, guest_path: &str) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        self.check_file_transfer()?;
+
+        let mut file = File::open(host_path).context(format!("Failed to open {}", host_path))?;
+        let mode = file.metadata()?.permissions().mode() & 0o7777;
+
+        let mut buffer = vec![0u8; FILE_CHUNK_SIZE];
+        let mut append = false;
+        loop {
+            let read = read_full(&mut file, &mut buffer).context(format!("Failed to read {}", host_path))?;
+
+            // The first request truncates the file, so empty files are created as well
+            if read > 0 || !append {
+                let request = Request::FileWrite { path: guest_path.to_string(), mode: Some(mode), append };
+                self.send(&request, &buffer[..read])?;
+                match self.receive()? {
+                    (Response::Ok, _) => {},
+                    (other, _) => bail!("Unexpected response to file write: {:?}", other),
+                }
+                append = true;
+            }
+
+            if read < buffer.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Copy a guest file to the host
+    pub fn pull_file(&mut self, guest_path: &str, host_path: &str) -> Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        self.check_file_transfer()?;
+
+        let mut output = None;
+        let mut offset = 0u64;
+        loop {
+            let request = Request::FileRead { path: guest_path.to_string(), offset, length: FILE_CHUNK_SIZE as u32 };
+            self.send(&request, &[])?;
+            let (size, mode, data) = match self.receive()? {
+                (Response::FileData { size, mode }, data) => (size, mode, data),
+                (other, _) => bail!("Unexpected response to file read: {:?}", other),
+            };
+
+            // Create the host file once the guest file is known to exist, with the guest's permissions
+            if output.is_none() {
+                output = Some(std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(host_file_mode(mode))
+                    .open(host_path)
+                    .context(format!("Failed to create {}", host_path))?);
+            }
+            if let Some(output) = output.as_mut() {
+                output.write_all(&data).context(format!("Failed to write {}", host_path))?;
+            }
+
+            offset += data.len() as u64;
+            if data.is_empty() || offset >= size {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Ask the guest to power off
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.send(&Request::Shutdown, &[])?;
+        match self.receive()? {
+            (Response::Ok, _) => Ok(()),
+            (other, _) => bail!("Unexpected response to shutdown: {:?}", other),
+        }
+    }
+
+    /// Fetch guest resource usage
+    pub fn metrics(&mut self) -> Result<GuestMetrics> {
+        self.send(&Request::Metrics, &[])?;
+        match self.receive()? {
+            (Response::Metrics(metrics), _) => Ok(metrics),
+            (other, _) => bail!("Unexpected response to metrics: {:?}", other),
+        }
+    }
+
+    /// Fetch an attestation report bound to `nonce`, returning the provider and the raw report
+    pub fn attest(&mut self, nonce: &[u8]) -> Result<(String, Vec<u8>)> {
+        self.send(&Request::Attest, nonce)?;
+        match self.receive()? {
+            (Response::Attestation { provider }, report) => Ok((provider, report)),
+            (other, _) => bail!("Unexpected response to attest: {:?}", other),
+        }
+    }
+}
+
+// Mode of a file pulled from the guest. The guest is not trusted, so it cannot make the host
+// create setuid, setgid or sticky files.
+fn host_file_mode(guest_mode: u32) -> u32 {
+    guest_mode & 0o777
+}
+
+/// Host unix socket Cloud Hypervisor forwards guest connections to host `port` to
+pub fn host_port_socket_path(vsock_socket_path: &str, port: u32) -> String {
+    format!("{}_{}", vsock_socket_path, port)
+}
+
+/// Wait on a ready-port listener until a guest connection sends the ready message
+pub fn wait_ready(listener: &std::os::unix::net::UnixListener) -> Result<()> {
+    for connection in listener.incoming() {
+        let connection = connection.context("Failed to accept ready connection")?;
+        let mut line = String::new();
+        if BufReader::new(connection).read_line(&mut line).is_ok() && line.trim() == READY_MESSAGE {
+            return Ok(());
+        }
+        debug!("Ignoring unexpected message on the ready port: {:?}", line.trim());
+    }
+
+    bail!("Ready listener closed")
+}
+
+/// Tell the host that the guest workload is ready (run inside the guest)
+pub fn signal_ready() -> Result<()> {
+    use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+
+    let fd = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .map_err(|e| anyhow!("Failed to create vsock socket: {}", e))?;
+    // SAFETY: socket returned a new descriptor that nothing else owns
+    let mut stream = unsafe { File::from_raw_fd(fd) };
+
+    connect(fd, &VsockAddr::new(libc::VMADDR_CID_HOST, READY_PORT))
+        .map_err(|e| anyhow!("Failed to connect to host vsock port {}: {}", READY_PORT, e))?;
+    stream.write_all(format!("{}\n", READY_MESSAGE).as_bytes())
+        .context("Failed to send ready signal")
+}
+
+/// Run the guest side of the protocol, accepting connections on the given vsock port
+pub fn serve(port: u32) -> Result<()> {
+    use nix::sys::socket::{accept, bind, listen, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+
+    let listener = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .map_err(|e| anyhow!("Failed to create vsock socket: {}", e))?;
+    bind(listener, &VsockAddr::new(libc::VMADDR_CID_ANY, port))
+        .map_err(|e| anyhow!("Failed to bind vsock port {}: {}", port, e))?;
+    listen(listener, 16)
+        .map_err(|e| anyhow!("Failed to listen on vsock port {}: {}", port, e))?;
+
+    info!("Guest agent listening on vsock port {}", port);
+
+    loop {
+        let fd = match accept(listener) {
+            Ok(fd) => fd,
+            Err(e) => {
+                warn!("Failed to accept agent connection: {}", e);
+                continue;
+            }
+        };
+
+        // SAFETY: accept returned a new descriptor that nothing else owns
+        let connection = unsafe { File::from_raw_fd(fd) };
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(connection) {
+                debug!("Agent connection ended: {:#}", e);
+            }
+        });
+    }
+}
+
+fn handle_connection(connection: File) -> Result<()> {
+    let writer = Arc::new(Mutex::new(connection.try_clone()?));
+    let mut reader = connection;
+
+    while let Some((request, payload)) = read_frame::<_, Request>(&mut reader)? {
+        debug!("Agent request: {:?}", request);
+
+        let result = match request {
+            Request::Ping => reply(&writer, &Response::Pong { version: PROTOCOL_VERSION }, &[]),
+            Request::Exec { argv } => exec(&writer, &argv),
+            Request::FileWrite { path, mode, append } => file_write(&path, mode, append, &payload)
+                .and_then(|_| reply(&writer, &Response::Ok, &[])),
+            Request::FileRead { path, offset, length } => file_read(&path, offset, length)
+                .and_then(|(size, mode, data)| reply(&writer, &Response::FileData { size, mode }, &data)),
+            Request::Shutdown => {
+                reply(&writer, &Response::Ok, &[])?;
+                Command::new("poweroff").status()
+                    .map(|_| ())
+                    .context("Failed to run poweroff")
+            },
+            Request::Metrics => metrics()
+                .and_then(|metrics| reply(&writer, &Response::Metrics(metrics), &[])),
+            Request::Attest => attestation_report(&payload)
+                .and_then(|(provider, report)| reply(&writer, &Response::Attestation { provider }, &report)),
+            Request::Connect { port } => match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(target) => {
+                    reply(&writer, &Response::Ok, &[])?;
+                    return Ok(relay(reader, target)?);
+                },
+                Err(e) => Err(anyhow!("Failed to connect to 127.0.0.1:{}: {}", port, e)),
+            },
+        };
+
+        if let Err(e) = result {
+            reply(&writer, &Response::Error { message: format!("{:#}", e) }, &[])?;
+        }
+    }
+
+    Ok(())
+}
+
+
+fn reply(writer: &Arc<Mutex<File>>, response: &Response, payload: &[u8]) -> Result<()> {
+    let mut writer = writer.lock().map_err(|_| anyhow!("Agent connection lock poisoned"))?;
+    write_frame(&mut *writer, response, payload)
+}
+
+fn exec(writer: &Arc<Mutex<File>>, argv: &[String]) -> Result<()> {
+    let (program, args) = argv.split_first()
+        .ok_or_else(|| anyhow!("exec requires a command"))?;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {}", program))?;
+
+    // Forward both streams as they are produced
+    let mut forwarders = Vec::new();
+    let outputs: [(Box<dyn Read + Send>, OutputStream); 2] = [
+        (Box::new(child.stdout.take().unwrap()), OutputStream::Stdout),
+        (Box::new(child.stderr.take().unwrap()), OutputStream::Stderr),
+    ];
+    for (mut source, stream) in outputs {
+        let writer = writer.clone();
+        forwarders.push(thread::spawn(move || -> Result<()> {
+            let mut buffer = [0u8; 16 * 1024];
+            loop {
+                let read = source.read(&mut buffer)?;
+                if read == 0 {
+                    return Ok(());
+                }
+                reply(&writer, &Response::Output { stream }, &buffer[..read])?;
+            }
+        }));
+    }
+
+    for forwarder in forwarders {
+        forwarder.join().map_err(|_| anyhow!("Output forwarder panicked"))??;
+    }
+
+    let status = child.wait().context(format!("Failed to wait for {}", program))?;
+    reply(writer, &Response::Exit { code: status.code().unwrap_or(-1) }, &[])
+}
+
+fn file_write(path: &str, mode: Option<u32>, append: bool, contents: &[u8]) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).append(append).truncate(!append);
+    if let Some(mode) = mode {
+        options.mode(mode);
+    }
+
+    let mut file = options.open(path).context(format!("Failed to open {}", path))?;
+    file.write_all(contents).context(format!("Failed to write {}", path))
+}
+
+fn file_read(path: &str, offset: u64, length: u32) -> Result<(u64, u32, Vec<u8>)> {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut file = File::open(path).context(format!("Failed to open {}", path))?;
+    let metadata = file.metadata().context(format!("Failed to read metadata of {}", path))?;
+    if metadata.is_dir() {
+        bail!("{} is a directory", path);
+    }
+
+    file.seek(SeekFrom::Start(offset)).context(format!("Failed to seek in {}", path))?;
+    let mut data = vec![0u8; (length as usize).min(FILE_CHUNK_SIZE)];
+    let read = read_full(&mut file, &mut data).context(format!("Failed to read {}", path))?;
+    data.truncate(read);
+
+    Ok((metadata.len(), metadata.permissions().mode() & 0o7777, data))
+}
+
+// Fill the buffer unless end of file comes first, returning the number of bytes read
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn attestation_report(nonce: &[u8]) -> Result<(String, Vec<u8>)> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT_REPORT: AtomicU64 = AtomicU64::new(0);
+
+    if nonce.len() > ATTESTATION_NONCE_SIZE {
+        bail!("Nonce of {} bytes exceeds the {} byte limit", nonce.len(), ATTESTATION_NONCE_SIZE);
+    }
+
+    // Each report is an entry directory: write the report data, then read the evidence back
+    let entry = format!("{}/vllmd-{}-{}", TSM_REPORT_DIR, std::process::id(), NEXT_REPORT.fetch_add(1, Ordering::SeqCst));
+    std::fs::create_dir(&entry)
+        .context(format!("Failed to create {}; is this a confidential guest?", entry))?;
+
+    let result = (|| -> Result<(String, Vec<u8>)> {
+        let mut report_data = nonce.to_vec();
+        report_data.resize(ATTESTATION_NONCE_SIZE, 0);
+        std::fs::write(format!("{}/inblob", entry), &report_data).context("Failed to write report data")?;
+
+        let report = std::fs::read(format!("{}/outblob", entry)).context("Failed to read attestation report")?;
+        let provider = std::fs::read_to_string(format!("{}/provider", entry)).context("Failed to read report provider")?;
+        Ok((provider.trim().to_string(), report))
+    })();
+
+    let _ = std::fs::remove_dir(&entry);
+    result
+}
+
+fn metrics() -> Result<GuestMetrics> {
+    let mut metrics = GuestMetrics::default();
+
+    let uptime = std::fs::read_to_string("/proc/uptime").context("Failed to read /proc/uptime")?;
+    metrics.uptime_secs = uptime.split_whitespace().next().and_then(|s| s.parse().ok()).unwrap_or(0.0);
+
+    let loadavg = std::fs::read_to_string("/proc/loadavg").context("Failed to read /proc/loadavg")?;
+    for (slot, value) in metrics.load_average.iter_mut().zip(loadavg.split_whitespace()) {
+        *slot = value.parse().unwrap_or(0.0);
+    }
+
+    let meminfo = File::open("/proc/meminfo").context("Failed to read /proc/meminfo")?;
+    for line in BufReader::new(meminfo).lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let key = fields.next();
+        let value = fields.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+        match key {
+            Some("MemTotal:") => metrics.memory_total_kb = value,
+            Some("MemAvailable:") => metrics.memory_available_kb = value,
+            _ => {}
+        }
+    }
+
+    metrics.gpus = gpus();
+    Ok(metrics)
+}
+
+// GPUs the NVIDIA driver of the guest drives; none when nvidia-smi is missing or fails
+fn gpus() -> Vec<GuestGpu> {
+    let output = Command::new("nvidia-smi")
+        .args([&format!("--query-gpu={}", GPU_QUERY), "--format=csv,noheader,nounits"])
+        .stderr(Stdio::null())
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            debug!("nvidia-smi exited with {}", output.status);
+            return Vec::new();
+        },
+        Err(_) => return Vec::new(),
+    };
+
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let [index, pci_bus_id, name, utilization, memory_used, memory_total, temperature, power] = fields[..] else {
+                return None;
+            };
+            // Unavailable values read "[N/A]" or "[Not Supported]"; memory is in MiB
+            let number = |field: &str| field.parse::<f64>().ok();
+            let mebibytes = |field: &str| field.parse::<u64>().ok().map(|mib| mib * 1024 * 1024);
+            Some(GuestGpu {
+                index: index.parse().ok()?,
+                pci_bus_id: pci_bus_id.to_string(),
+                name: name.to_string(),
+                utilization_percent: number(utilization),
+                memory_used_bytes: mebibytes(memory_used),
+                memory_total_bytes: mebibytes(memory_total),
+                temperature_celsius: number(temperature),
+                power_watts: number(power),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn frame_round_trip() {
+        let request = Request::FileWrite { path: "/tmp/a".to_string(), mode: Some(0o644), append: true };
+        let header = serde_json::to_vec(&request).unwrap();
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &request, b"data").unwrap();
+        assert_eq!(stream.len(), 4 + header.len() + 4 + 4);
+        assert_eq!(&stream[..4], &(header.len() as u32).to_be_bytes());
+        assert_eq!(&stream[4 + header.len()..][..4], &4u32.to_be_bytes());
+        write_frame(&mut stream, &Request::Ping, &[]).unwrap();
+
+        let mut reader = Cursor::new(stream);
+        match read_frame::<_, Request>(&mut reader).unwrap() {
+            Some((Request::FileWrite { path, mode, append }, payload)) => {
+                assert_eq!((path.as_str(), mode, append), ("/tmp/a", Some(0o644), true));
+                assert_eq!(payload, b"data");
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(read_frame::<_, Request>(&mut reader).unwrap(), Some((Request::Ping, payload)) if payload.is_empty()));
+        assert!(read_frame::<_, Request>(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &Response::Exit { code: 3 }, b"payload").unwrap();
+
+        // The stream ends after the header
+        let header_end = 4 + u32::from_be_bytes(stream[..4].try_into().unwrap()) as usize;
+        let error = read_frame::<_, Response>(&mut Cursor::new(&stream[..header_end])).unwrap_err();
+        assert!(error.to_string().contains("closed inside a frame"), "{:#}", error);
+
+        // The stream ends inside the payload
+        let error = read_frame::<_, Response>(&mut Cursor::new(&stream[..stream.len() - 1])).unwrap_err();
+        assert!(error.to_string().contains("Failed to read agent frame"), "{:#}", error);
+    }
+
+    #[test]
+    fn oversize_frame() {
+        let mut stream = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        stream.extend_from_slice(b"{}");
+        let error = read_frame::<_, Request>(&mut Cursor::new(stream)).unwrap_err();
+        assert!(error.to_string().contains("exceeds the 67108864 byte limit"), "{:#}", error);
+
+        // The limit applies to the payload as well
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &Response::Ok, &[]).unwrap();
+        let payload_length = stream.len() - 4;
+        stream[payload_length..].copy_from_slice(&((MAX_FRAME_SIZE + 1) as u32).to_be_bytes());
+        let error = read_frame::<_, Response>(&mut Cursor::new(stream)).unwrap_err();
+        assert!(error.to_string().contains("exceeds"), "{:#}", error);
+    }
+
+    #[test]
+    fn message_tags() {
+        assert_eq!(serde_json::to_value(Request::Ping).unwrap(), json!({"type": "ping"}));
+        assert_eq!(serde_json::to_value(Request::Exec { argv: vec!["true".to_string()] }).unwrap(),
+                   json!({"type": "exec", "argv": ["true"]}));
+        assert_eq!(serde_json::to_value(Request::FileRead { path: "/x".to_string(), offset: 8, length: 16 }).unwrap(),
+                   json!({"type": "file_read", "path": "/x", "offset": 8, "length": 16}));
+        assert_eq!(serde_json::to_value(Request::Connect { port: 8000 }).unwrap(), json!({"type": "connect", "port": 8000}));
+
+        assert_eq!(serde_json::to_value(Response::Pong { version: PROTOCOL_VERSION }).unwrap(), json!({"type": "pong", "version": 2}));
+        assert_eq!(serde_json::to_value(Response::Output { stream: OutputStream::Stderr }).unwrap(),
+                   json!({"type": "output", "stream": "stderr"}));
+        assert_eq!(serde_json::to_value(Response::FileData { size: 5, mode: 0o644 }).unwrap(),
+                   json!({"type": "file_data", "size": 5, "mode": 0o644}));
+        assert_eq!(serde_json::to_value(Response::Error { message: "no".to_string() }).unwrap(),
+                   json!({"type": "error", "message": "no"}));
+        let metrics = serde_json::to_value(Response::Metrics(GuestMetrics::default())).unwrap();
+        assert_eq!(metrics["type"], "metrics");
+        assert_eq!(metrics["memory_total_kb"], 0);
+
+        // Agents before protocol version 2 send neither `append` nor GPU metrics
+        let request: Request = serde_json::from_value(json!({"type": "file_write", "path": "/x", "mode": null})).unwrap();
+        assert!(matches!(request, Request::FileWrite { append: false, mode: None, .. }), "{:?}", request);
+        let response: Response = serde_json::from_value(json!({"type": "metrics", "uptime_secs": 1.0, "load_average": [0.0, 0.0, 0.0],
+                                                               "memory_total_kb": 1, "memory_available_kb": 1})).unwrap();
+        assert!(matches!(response, Response::Metrics(metrics) if metrics.gpus.is_empty()));
+        assert!(serde_json::from_value::<Request>(json!({"type": "reboot"})).is_err());
+    }
+
+    #[test]
+    fn pulled_file_mode_drops_special_bits() {
+        assert_eq!(host_file_mode(0o644), 0o644);
+        assert_eq!(host_file_mode(0o4755), 0o755);
+        assert_eq!(host_file_mode(0o2750), 0o750);
+        assert_eq!(host_file_mode(0o1777), 0o777);
+        assert_eq!(host_file_mode(0o7777), 0o777);
+    }
+}
@@ -0,0 +1,235 @@
+use anyhow::{Result, bail, Context};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// `prev` of the first record of a log
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Bytes read at a time from the end of the log to find the last record
+const TAIL_SIZE: u64 = 64 * 1024;
+
+/// One line of the audit log. `hash` covers every other field, including the hash of the
+/// previous record, so changing, removing or reordering records breaks the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub seq: u64,
+    /// RFC 3339 UTC timestamp
+    pub time: String,
+    /// Effective uid of the process that performed the operation
+    pub uid: u32,
+    /// Login uid of the session, which sudo keeps; None when the kernel has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login_uid: Option<u32>,
+    pub pid: u32,
+    /// Runtime name, None for the unnamed runtime
+    pub runtime: Option<String>,
+    pub operation: String,
+    /// Resolved parameters of the operation, with `error` set when it failed
+    pub params: serde_json::Value,
+    pub prev: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+impl Record {
+    // SHA-256 of the record serialized with an empty hash
+    fn digest(&self) -> Result<String> {
+        let mut unsigned = self.clone();
+        unsigned.hash = String::new();
+        let digest = Sha256::digest(serde_json::to_string(&unsigned)?.as_bytes());
+        Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+}
+
+/// Append-only, hash-chained log of the privileged operations on the runtimes of a host
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: String,
+    runtime: Option<String>,
+    // Opened by `hold` and shared by the clones, so records are appended to it without opening
+    // the log again; the mutex also keeps the appends of this process from interleaving
+    held: Arc<Mutex<Option<File>>>,
+}
+
+impl AuditLog {
+    pub fn new(path: String, runtime: Option<String>) -> Self {
+        Self { path, runtime, held: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Open the log now and append every later record to the open file. A process about to give
+    /// up root calls this first: the log belongs to root with mode 0600, so it could not open it
+    /// again afterwards.
+    pub fn hold(&self) -> Result<()> {
+        let file = self.open()?;
+        *self.held.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Append a record of `operation`. A failure to write is logged as an error but does not stop
+    /// the operation, so a full disk cannot take a running VM down.
+    pub fn record(&self, operation: &str, params: serde_json::Value) {
+        if let Err(e) = self.append(operation, params) {
+            error!("Failed to write audit log {}: {:#}", self.path, e);
+        }
+    }
+
+    fn open(&self) -> Result<File> {
+        if let Some(parent) = Path::new(&self.path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create audit log directory: {}", parent.display()))?;
+        }
+        OpenOptions::new().create(true).read(true).append(true).mode(0o600).open(&self.path)
+            .context(format!("Failed to open audit log: {}", self.path))
+    }
+
+    fn append(&self, operation: &str, params: serde_json::Value) -> Result<()> {
+        let held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        let opened;
+        let file = match held.as_ref() {
+            Some(file) => file,
+            None => {
+                opened = self.open()?;
+                &opened
+            },
+        };
+
+        // Every runtime of the host appends to the same log; the lock keeps the chain linear. It
+        // is released when the file is closed, or below for a held file that stays open.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error()).context(format!("Failed to lock audit log: {}", self.path));
+        }
+        let appended = self.append_locked(file, operation, params);
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+        appended
+    }
+
+    fn append_locked(&self, mut file: &File, operation: &str, params: serde_json::Value) -> Result<()> {
+        let (seq, prev) = match last_record(file)? {
+            Some(last) => (last.seq + 1, last.hash),
+            None => (0, GENESIS.to_string()),
+        };
+        let mut record = Record {
+            seq,
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            uid: unsafe { libc::geteuid() },
+            login_uid: login_uid(),
+            pid: std::process::id(),
+            runtime: self.runtime.clone(),
+            operation: operation.to_string(),
+            params,
+            prev,
+            hash: String::new(),
+        };
+        record.hash = record.digest()?;
+
+        file.write_all(format!("{}\n", serde_json::to_string(&record)?).as_bytes())
+            .context(format!("Failed to append to audit log: {}", self.path))
+    }
+}
+
+/// `params` with the error of a failed operation added
+pub fn outcome<T>(mut params: serde_json::Value, result: &Result<T>) -> serde_json::Value {
+    if let (Err(e), Some(params)) = (result, params.as_object_mut()) {
+        params.insert("error".to_string(), format!("{:#}", e).into());
+    }
+    params
+}
+
+// Login uid of this process; (uid_t)-1 means none was set
+fn login_uid() -> Option<u32> {
+    std::fs::read_to_string("/proc/self/loginuid").ok()?
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|uid| *uid != u32::MAX)
+}
+
+// Last record of the log, read backwards from its tail until the whole of its last line is read
+fn last_record(mut file: &File) -> Result<Option<Record>> {
+    let mut start = file.metadata()?.len();
+    let mut tail = Vec::new();
+    loop {
+        let from = start.saturating_sub(TAIL_SIZE);
+        let mut chunk = vec![0; (start - from) as usize];
+        file.seek(SeekFrom::Start(from))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        start = from;
+
+        // The last line is whole once a newline comes before it or the start of the log is read
+        let Some(end) = tail.iter().rposition(|byte| !byte.is_ascii_whitespace()) else {
+            if start == 0 {
+                return Ok(None);
+            }
+            continue;
+        };
+        let line_start = match tail[..end].iter().rposition(|byte| *byte == b'\n') {
+            Some(newline) => newline + 1,
+            None if start == 0 => 0,
+            None => continue,
+        };
+        let line = String::from_utf8_lossy(&tail[line_start..=end]);
+        return Ok(Some(serde_json::from_str(&line).context("The last audit record is corrupt")?));
+    }
+}
+
+/// Check the hash chain of the log at `path`, returning the number of records. Fails at the first
+/// record that was changed, or that does not follow its predecessor. Removing records from the
+/// end of the log leaves a valid chain, so keep a copy of the log or its last hash off the host.
+pub fn verify(path: &str) -> Result<u64> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Failed to read audit log: {}", path))?;
+
+    let mut prev = GENESIS.to_string();
+    let mut count = 0;
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let record: Record = serde_json::from_str(line)
+            .context(format!("Line {} of {} is not an audit record", line_number, path))?;
+        if record.seq != count {
+            bail!("Line {} of {}: expected record {}, found record {}; records were removed or reordered",
+                  line_number, path, count, record.seq);
+        }
+        if record.prev != prev {
+            bail!("Line {} of {}: record {} does not link to the record before it", line_number, path, record.seq);
+        }
+        if record.digest()? != record.hash {
+            bail!("Line {} of {}: record {} was modified", line_number, path, record.seq);
+        }
+        prev = record.hash;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_after_a_record_longer_than_the_tail() {
+        let path = std::env::temp_dir().join(format!("vllmd-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::new(path.display().to_string(), None);
+
+        log.append("start", serde_json::json!({})).unwrap();
+        let error = "x".repeat(3 * TAIL_SIZE as usize);
+        log.append("start", serde_json::json!({ "error": error })).unwrap();
+        log.append("stop", serde_json::json!({})).unwrap();
+        assert_eq!(verify(&path.display().to_string()).unwrap(), 3);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
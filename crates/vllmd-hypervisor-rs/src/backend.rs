@@ -0,0 +1,115 @@
+use anyhow::{Result, anyhow, bail};
+use hypervisor::Hypervisor as ChHypervisor;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::hypervisor::{HypervisorBackend, HypervisorManager};
+use crate::mock::MockBackend;
+
+/// Host virtualization interface the VMM runs guests on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// The first of KVM and MSHV that is built in and available on the host
+    #[default]
+    Auto,
+    /// Linux KVM (/dev/kvm)
+    Kvm,
+    /// Microsoft Hypervisor, as on Azure hosts (/dev/mshv)
+    Mshv,
+    /// No guest at all: the lifecycle runs against a simulated VM, for tests and CI
+    Mock,
+}
+
+impl BackendKind {
+    /// Parse the value of an environment variable
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(BackendKind::Auto),
+            "kvm" => Ok(BackendKind::Kvm),
+            "mshv" => Ok(BackendKind::Mshv),
+            "mock" => Ok(BackendKind::Mock),
+            _ => bail!("Unknown hypervisor backend '{}', expected auto, kvm, mshv or mock", s),
+        }
+    }
+
+    /// Name used in configuration and messages
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackendKind::Auto => "auto",
+            BackendKind::Kvm => "kvm",
+            BackendKind::Mshv => "mshv",
+            BackendKind::Mock => "mock",
+        }
+    }
+
+    /// Device node the backend is driven through
+    pub fn device_path(&self) -> Option<&'static str> {
+        match self {
+            BackendKind::Auto | BackendKind::Mock => None,
+            BackendKind::Kvm => Some("/dev/kvm"),
+            BackendKind::Mshv => Some("/dev/mshv"),
+        }
+    }
+
+    /// Whether this binary was built with the backend's Cargo feature
+    pub fn compiled_in(&self) -> bool {
+        match self {
+            BackendKind::Auto | BackendKind::Mock => true,
+            BackendKind::Kvm => cfg!(feature = "kvm"),
+            BackendKind::Mshv => cfg!(feature = "mshv"),
+        }
+    }
+
+    /// The concrete backend to use: `Auto` becomes the first one that is built in and whose
+    /// device exists, while an explicit choice must be both. The mock needs neither.
+    pub fn resolve(&self) -> Result<BackendKind> {
+        let available = |kind: &BackendKind| {
+            kind.compiled_in() && kind.device_path().is_some_and(|path| Path::new(path).exists())
+        };
+
+        match self {
+            BackendKind::Auto => [BackendKind::Kvm, BackendKind::Mshv].into_iter()
+                .find(available)
+                .ok_or_else(|| anyhow!("No hypervisor backend available: neither /dev/kvm nor /dev/mshv exists \
+                                        in a backend built into this binary")),
+            BackendKind::Mock => Ok(BackendKind::Mock),
+            kind if !kind.compiled_in() => {
+                bail!("Hypervisor backend {} requires building with the {} feature", kind.as_str(), kind.as_str())
+            },
+            kind if !available(kind) => {
+                bail!("Hypervisor backend {} is not available: {} does not exist",
+                      kind.as_str(), kind.device_path().unwrap_or_default())
+            },
+            kind => Ok(*kind),
+        }
+    }
+}
+
+/// Lifecycle driver for the selected backend: the mock, or a manager running a real VMM
+pub fn manager(kind: BackendKind) -> Result<Box<dyn HypervisorBackend>> {
+    if kind == BackendKind::Mock {
+        warn!("Using the mock hypervisor backend; no guest will run");
+        return Ok(Box::new(MockBackend::new()));
+    }
+    Ok(Box::new(HypervisorManager::new()?))
+}
+
+/// Open the selected backend for the VMM
+pub fn create(kind: BackendKind) -> Result<Arc<dyn ChHypervisor>> {
+    let kind = kind.resolve()?;
+    info!("Using the {} hypervisor backend", kind.as_str());
+
+    match kind {
+        #[cfg(feature = "kvm")]
+        BackendKind::Kvm => hypervisor::kvm::KvmHypervisor::new()
+            .map_err(|e| anyhow!("Failed to open KVM: {:?}", e)),
+        #[cfg(feature = "mshv")]
+        BackendKind::Mshv => hypervisor::mshv::MshvHypervisor::new()
+            .map_err(|e| anyhow!("Failed to open MSHV: {:?}", e)),
+        // resolve() only returns backends that are built in, and the mock never opens one
+        _ => unreachable!("backend {} is not built in", kind.as_str()),
+    }
+}
@@ -0,0 +1,233 @@
+use crate::control;
+use crate::dump;
+use crate::hypervisor::parse_size;
+use anyhow::{Context, Result, bail};
+use chrono::NaiveTime;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Balloon sizing that follows the load of the guest (`[runtimes.balloon]`). While the vCPUs
+/// stay below `idle_cpu_percent` for `idle_secs` the balloon inflates until the guest keeps only
+/// `floor`; as soon as they get busy, or a `busy_hours` window begins, it deflates to `ceiling`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonPolicy {
+    /// Memory the guest keeps while idle, e.g. "4G"
+    pub floor: String,
+
+    /// Memory the guest gets back while busy; all of guest memory when unset
+    #[serde(default)]
+    pub ceiling: Option<String>,
+
+    /// Seconds of low load before the balloon inflates
+    #[serde(default = "default_idle_secs")]
+    pub idle_secs: u64,
+
+    /// vCPU use, in percent of one host CPU, below which the guest counts as idle
+    #[serde(default = "default_idle_cpu_percent")]
+    pub idle_cpu_percent: f64,
+
+    /// Seconds between load samples
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+
+    /// Local time windows such as "08:00-18:00" during which the balloon stays deflated, so
+    /// expected load finds the memory in place
+    #[serde(default)]
+    pub busy_hours: Vec<String>,
+}
+
+fn default_idle_secs() -> u64 { 600 }
+fn default_idle_cpu_percent() -> f64 { 5.0 }
+fn default_interval_secs() -> u64 { 10 }
+
+impl BalloonPolicy {
+    /// Check the policy against the guest memory, returning the floor and ceiling in bytes
+    pub fn validate(&self, memory_size: u64) -> Result<(u64, u64)> {
+        let floor = parse_size(&self.floor).context("Invalid balloon floor")?;
+        let ceiling = match &self.ceiling {
+            Some(ceiling) => parse_size(ceiling).context("Invalid balloon ceiling")?,
+            None => memory_size,
+        };
+        if floor == 0 {
+            bail!("Balloon floor must be greater than zero");
+        }
+        if floor > ceiling {
+            bail!("Balloon floor {} is above the ceiling {}", self.floor, self.ceiling.as_deref().unwrap_or("of guest memory"));
+        }
+        if ceiling > memory_size {
+            bail!("Balloon ceiling {} is above the {} bytes of guest memory", self.ceiling.as_deref().unwrap_or_default(), memory_size);
+        }
+        if self.interval_secs == 0 {
+            bail!("Balloon interval must be greater than zero");
+        }
+        if self.idle_cpu_percent.is_nan() || self.idle_cpu_percent <= 0.0 {
+            bail!("Balloon idle_cpu_percent must be greater than zero");
+        }
+        self.windows()?;
+        Ok((floor, ceiling))
+    }
+
+    // Start and end of each busy window; a window whose end is before its start spans midnight
+    fn windows(&self) -> Result<Vec<(NaiveTime, NaiveTime)>> {
+        self.busy_hours.iter().map(|window| {
+            let parsed = window.split_once('-').and_then(|(start, end)| {
+                Some((NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?, NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?))
+            });
+            parsed.ok_or_else(|| anyhow::anyhow!("Invalid balloon busy_hours window '{}', expected HH:MM-HH:MM", window))
+        }).collect()
+    }
+}
+
+// Whether `now` falls in one of the windows
+fn in_window(windows: &[(NaiveTime, NaiveTime)], now: NaiveTime) -> bool {
+    windows.iter().any(|(start, end)| {
+        if start <= end {
+            *start <= now && now < *end
+        } else {
+            now >= *start || now < *end
+        }
+    })
+}
+
+// Balloon size the policy wants after each load sample: the guest keeps `floor` once it has been
+// idle for `idle_after`, and `ceiling` otherwise
+struct Sizing {
+    floor: u64,
+    ceiling: u64,
+    memory_size: u64,
+    idle_cpu_percent: f64,
+    idle_after: Duration,
+    idle_since: Instant,
+}
+
+impl Sizing {
+    fn wanted(&mut self, now: Instant, cpu_percent: f64, busy_hours: bool) -> (u64, bool) {
+        if cpu_percent >= self.idle_cpu_percent || busy_hours {
+            self.idle_since = now;
+        }
+        let idle = now.duration_since(self.idle_since) >= self.idle_after;
+        (self.memory_size - if idle { self.floor } else { self.ceiling }, idle)
+    }
+}
+
+// CPU time of the vCPU threads in clock ticks; they only run while the guest does
+fn vcpu_ticks() -> u64 {
+    dump::threads().iter()
+        .filter(|thread| thread.name.starts_with("vcpu"))
+        .map(|thread| thread.cpu_ticks)
+        .sum()
+}
+
+/// Resize the balloon of the VM behind `api_socket_path` on an interval in a background thread,
+/// for as long as the process runs. `memory_size` is all of guest memory in bytes.
+pub fn spawn(policy: BalloonPolicy, api_socket_path: String, memory_size: u64) -> Result<()> {
+    let (floor, ceiling) = policy.validate(memory_size)?;
+    let windows = policy.windows()?;
+
+    thread::spawn(move || {
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let interval = Duration::from_secs(policy.interval_secs);
+        let mut sizing = Sizing {
+            floor,
+            ceiling,
+            memory_size,
+            idle_cpu_percent: policy.idle_cpu_percent,
+            idle_after: Duration::from_secs(policy.idle_secs),
+            idle_since: Instant::now(),
+        };
+
+        // The guest boots with the balloon empty
+        let mut balloon = 0;
+        let mut previous = (Instant::now(), vcpu_ticks());
+        loop {
+            thread::sleep(interval);
+
+            let sample = (Instant::now(), vcpu_ticks());
+            let elapsed = sample.0.duration_since(previous.0).as_secs_f64();
+            let cpu_percent = (sample.1.saturating_sub(previous.1)) as f64 / ticks_per_second / elapsed * 100.0;
+            previous = sample;
+
+            let busy_hours = in_window(&windows, chrono::Local::now().time());
+            let (wanted, idle) = sizing.wanted(sample.0, cpu_percent, busy_hours);
+            debug!("Balloon policy: vCPUs at {:.1}%, busy hours {}, idle {}", cpu_percent, busy_hours, idle);
+            if wanted == balloon {
+                continue;
+            }
+
+            match control::resize(&api_socket_path, None, None, Some(wanted)) {
+                Ok(()) => {
+                    if wanted > balloon {
+                        info!("Inflated the balloon to {} bytes after {}s idle; the guest keeps {} bytes", wanted, policy.idle_secs, memory_size - wanted);
+                    } else {
+                        info!("Deflated the balloon to {} bytes; the guest has {} bytes", wanted, memory_size - wanted);
+                    }
+                    balloon = wanted;
+                }
+                Err(e) => warn!("Failed to resize the balloon to {} bytes: {:#}", wanted, e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hh_mm: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(hh_mm, "%H:%M").unwrap()
+    }
+
+    fn policy(busy_hours: &[&str]) -> BalloonPolicy {
+        BalloonPolicy {
+            floor: "4G".to_string(),
+            ceiling: None,
+            idle_secs: default_idle_secs(),
+            idle_cpu_percent: default_idle_cpu_percent(),
+            interval_secs: default_interval_secs(),
+            busy_hours: busy_hours.iter().map(|window| window.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn windows_include_their_start_and_may_span_midnight() {
+        let windows = policy(&["08:00-18:00", "22:30-02:00"]).windows().unwrap();
+        for now in ["08:00", "12:00", "17:59", "22:30", "23:59", "00:00", "01:59"] {
+            assert!(in_window(&windows, time(now)), "{}", now);
+        }
+        for now in ["07:59", "18:00", "20:00", "22:29", "02:00", "03:00"] {
+            assert!(!in_window(&windows, time(now)), "{}", now);
+        }
+        assert!(!in_window(&[], time("12:00")));
+        assert!(policy(&["8-18"]).windows().is_err());
+        assert!(policy(&["25:00-26:00"]).windows().is_err());
+    }
+
+    #[test]
+    fn inflates_once_idle_long_enough_and_deflates_on_load() {
+        let gib = 1u64 << 30;
+        let start = Instant::now();
+        let mut sizing = Sizing {
+            floor: 4 * gib,
+            ceiling: 16 * gib,
+            memory_size: 16 * gib,
+            idle_cpu_percent: 5.0,
+            idle_after: Duration::from_secs(600),
+            idle_since: start,
+        };
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        assert_eq!(sizing.wanted(at(10), 1.0, false), (0, false));
+        assert_eq!(sizing.wanted(at(600), 1.0, false), (12 * gib, true));
+        // Load, or a busy window, deflates at once and restarts the idle time
+        assert_eq!(sizing.wanted(at(610), 50.0, false), (0, false));
+        assert_eq!(sizing.wanted(at(1200), 1.0, false), (0, false));
+        assert_eq!(sizing.wanted(at(1210), 1.0, false), (12 * gib, true));
+        assert_eq!(sizing.wanted(at(1220), 0.0, true), (0, false));
+        assert_eq!(sizing.wanted(at(1819), 0.0, false), (0, false));
+        assert_eq!(sizing.wanted(at(1820), 0.0, false), (12 * gib, true));
+    }
+}
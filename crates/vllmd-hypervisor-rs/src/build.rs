@@ -0,0 +1,471 @@
+use anyhow::{Result, anyhow, bail, Context};
+use log::{info, warn};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use crate::images::{ImageStore, BUILT_SCHEME, ZSTD_MAGIC};
+use crate::oci::{self, ContainerConfig, OciReference};
+
+/// Start of the root partition, leaving room for the partition table
+const PARTITION_OFFSET: u64 = 1 << 20;
+
+/// Label of the root filesystem
+const LABEL: &str = "vllmd-root";
+
+/// Directories the init mounts the kernel filesystems on
+const MOUNT_POINTS: &[&str] = &["proc", "sys", "dev", "run", "tmp"];
+
+/// Start of the init of the guest, before the environment and command of the container image
+const INIT_PROLOGUE: &str = r#"mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs devtmpfs /dev
+mkdir -p /dev/pts /dev/shm
+mount -t devpts devpts /dev/pts
+mount -t tmpfs tmpfs /dev/shm
+mount -t tmpfs tmpfs /run
+mount -t tmpfs tmpfs /tmp
+for param in $(cat /proc/cmdline); do
+    case "$param" in
+        systemd.hostname=*) echo "${param#systemd.hostname=}" > /proc/sys/kernel/hostname ;;
+    esac
+done
+ip link set lo up 2>/dev/null
+"#;
+
+/// Filesystems a system image can be built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    /// Writable; sized from the contents unless given
+    Ext4,
+    /// Read-only and compact
+    Erofs,
+}
+
+// Staging directory, removed with whatever a failed build left in it
+struct Staging(PathBuf);
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove {}: {}", self.0.display(), e);
+            }
+        }
+    }
+}
+
+/// Build a bootable system image from the container image `image` and record it in the store as
+/// `image://<name>`, which is returned. The layers are unpacked into a root filesystem, an init
+/// running the entrypoint of the image is installed as /sbin/init, and the filesystem is put in
+/// the first partition of a disk, where the guest mounts its root.
+pub fn build_image(store: &ImageStore, image: &OciReference, name: &str, filesystem: Filesystem, size: Option<u64>) -> Result<String> {
+    if image.title.is_some() {
+        bail!("A container image is pulled whole, without #<title>: {}", image);
+    }
+    let container = oci::pull_container(store, image)?;
+
+    let key = format!("{}{}", BUILT_SCHEME, name);
+    let stem: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect();
+    let staging = Staging(store.download_path(&format!("{}.build", stem)));
+    if staging.0.exists() {
+        std::fs::remove_dir_all(&staging.0).context(format!("Failed to remove {}", staging.0.display()))?;
+    }
+    let rootfs = staging.0.join("rootfs");
+    std::fs::create_dir_all(&rootfs).context(format!("Failed to create {}", rootfs.display()))?;
+
+    for (index, layer) in container.layers.iter().enumerate() {
+        info!("Unpacking layer {} of {}", index + 1, container.layers.len());
+        unpack_layer(layer, &rootfs)?;
+    }
+    write_init(&rootfs, &container.config, image)?;
+
+    let root_filesystem = staging.0.join("rootfs.img");
+    info!("Creating the {} filesystem", match filesystem { Filesystem::Ext4 => "ext4", Filesystem::Erofs => "erofs" });
+    make_filesystem(filesystem, &rootfs, &root_filesystem, size)?;
+    let disk = staging.0.join("disk.raw");
+    write_disk(&root_filesystem, &disk)?;
+
+    let digest = store.commit(&disk, None)?;
+    store.tag(&key, &digest, None)?;
+    Ok(key)
+}
+
+// Apply a layer over `rootfs`. Whiteouts remove what lower layers put there before anything of
+// the layer is unpacked, so the layer is read twice.
+fn unpack_layer(layer: &Path, rootfs: &Path) -> Result<()> {
+    let root = std::fs::canonicalize(rootfs)?;
+    let mut archive = open_layer(layer)?;
+    for entry in archive.entries().context(format!("Failed to read layer {}", layer.display()))? {
+        let path = entry?.path()?.into_owned();
+        let Some(hidden) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_prefix(".wh.")) else { continue };
+        if path.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)) {
+            continue;
+        }
+        // `.wh..` or `.wh...` would hide the directory of the whiteout or the one above it
+        if hidden.is_empty() || hidden == "." || hidden == ".." || hidden.contains('/') {
+            bail!("Layer {} has an invalid whiteout: {}", layer.display(), path.display());
+        }
+        // A symlink of a lower layer must not lead outside the root filesystem
+        let Ok(parent) = std::fs::canonicalize(rootfs.join(path.parent().unwrap_or(Path::new("")))) else { continue };
+        if !parent.starts_with(&root) {
+            continue;
+        }
+        if hidden == ".wh..opq" {
+            for child in std::fs::read_dir(&parent)?.flatten() {
+                remove(&child.path())?;
+            }
+        } else {
+            remove(&parent.join(hidden))?;
+        }
+    }
+
+    let mut archive = open_layer(layer)?;
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+    // Owners can only be set by root
+    archive.set_preserve_ownerships(unsafe { libc::geteuid() } == 0);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(".wh.")) {
+            continue;
+        }
+        entry.unpack_in(rootfs)
+            .context(format!("Failed to unpack {} of layer {}", path.display(), layer.display()))?;
+    }
+    Ok(())
+}
+
+// A layer archive, compressed with gzip or zstd or not at all
+fn open_layer(layer: &Path) -> Result<tar::Archive<Box<dyn Read>>> {
+    let mut file = File::open(layer).context(format!("Failed to open layer {}", layer.display()))?;
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic).context(format!("Layer {} is not an archive", layer.display()))?;
+    file.seek(SeekFrom::Start(0))?;
+    let reader: Box<dyn Read> = match magic {
+        [0x1f, 0x8b, _, _] => Box::new(flate2::read::GzDecoder::new(BufReader::new(file))),
+        magic if magic == ZSTD_MAGIC => Box::new(zstd::stream::read::Decoder::new(file)?),
+        _ => Box::new(BufReader::new(file)),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+// Remove a file, symlink or directory tree; nothing to remove is fine
+fn remove(path: &Path) -> Result<()> {
+    let removed = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    removed.context(format!("Failed to remove {}", path.display()))
+}
+
+// Install the init as /sbin/init: it mounts the kernel filesystems, sets the hostname given on the
+// kernel command line, runs the entrypoint of the container image with its environment and
+// working directory, and powers the VM off once that exits
+fn write_init(rootfs: &Path, config: &ContainerConfig, image: &OciReference) -> Result<()> {
+    let command: Vec<&String> = config.entrypoint.iter().chain(config.cmd.iter()).flatten().collect();
+    if command.is_empty() {
+        bail!("{} has neither an entrypoint nor a command to run", image);
+    }
+    if !rootfs.join("bin/sh").exists() && !rootfs.join("usr/bin/sh").exists() {
+        bail!("{} has no /bin/sh to run the init with", image);
+    }
+    if let Some(user) = config.user.as_deref().filter(|user| !user.is_empty() && !matches!(*user, "root" | "0" | "0:0")) {
+        warn!("{} runs as user {}; the system image runs it as root", image, user);
+    }
+
+    let mut script = format!("#!/bin/sh\n# Written by vllmd-hypervisor build-image from {}\n", image);
+    script.push_str(INIT_PROLOGUE);
+    for variable in config.env.iter().flatten() {
+        script.push_str(&format!("export {}\n", quote(variable)));
+    }
+    if let Some(dir) = config.working_dir.as_deref().filter(|dir| !dir.is_empty()) {
+        script.push_str(&format!("cd {}\n", quote(dir)));
+    }
+    let words: Vec<String> = command.iter().map(|word| quote(word)).collect();
+    script.push_str(&format!("{}\n", words.join(" ")));
+    script.push_str("echo \"vllmd-init: the workload exited with status $?\" >&2\n");
+    script.push_str("sync\necho o > /proc/sysrq-trigger\nwhile :; do sleep 60; done\n");
+
+    // The directories may be links in the image, such as /sbin to usr/sbin
+    for dir in MOUNT_POINTS {
+        image_dir(rootfs, Path::new(dir))?;
+    }
+    let init = image_dir(rootfs, Path::new("sbin"))?.join("init");
+    remove(&init)?;
+    std::fs::write(&init, script).context(format!("Failed to write {}", init.display()))?;
+    std::fs::set_permissions(&init, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+// Directory `path` of the image under `rootfs`, created where it is missing. Symlinks are followed
+// as the guest would, with absolute targets taken from the root of the image, and a path that leads
+// outside the image is refused, so a hostile image cannot make the build write elsewhere on the host.
+fn image_dir(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    let root = std::fs::canonicalize(rootfs)?;
+    let mut dir = root.clone();
+    let mut pending: Vec<OsString> = path.iter().rev().map(OsString::from).collect();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        match Path::new(&name).components().next() {
+            Some(Component::Normal(_)) => {},
+            Some(Component::ParentDir) if dir != root => {
+                dir.pop();
+                continue;
+            },
+            Some(Component::ParentDir) => bail!("/{} leads outside the image", path.display()),
+            _ => continue,
+        }
+        let next = dir.join(&name);
+        match std::fs::symlink_metadata(&next) {
+            Ok(metadata) if metadata.is_symlink() => {
+                links += 1;
+                if links > 40 {
+                    bail!("Too many levels of symbolic links in /{} of the image", path.display());
+                }
+                let target = std::fs::read_link(&next).context(format!("Failed to read {}", next.display()))?;
+                if target.is_absolute() {
+                    dir = root.clone();
+                }
+                pending.extend(target.iter().rev().map(OsString::from));
+            },
+            Ok(metadata) if metadata.is_dir() => dir = next,
+            Ok(_) => bail!("/{} of the image is not a directory", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir(&next).context(format!("Failed to create /{} in the image", path.display()))?;
+                dir = next;
+            },
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", next.display(), e)),
+        }
+    }
+    Ok(dir)
+}
+
+// A word for sh, in single quotes
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+// Make the root filesystem image `output` from the directory `rootfs`
+fn make_filesystem(filesystem: Filesystem, rootfs: &Path, output: &Path, size: Option<u64>) -> Result<()> {
+    let root = unsafe { libc::geteuid() } == 0;
+    let (tool, package, mut command) = match filesystem {
+        Filesystem::Ext4 => {
+            // Room to write on top of the contents
+            let size = match size {
+                Some(size) => size,
+                None => {
+                    let used = disk_usage(rootfs)?;
+                    used + used / 4 + (256 << 20)
+                },
+            };
+            File::create(output)?.set_len(size.div_ceil(1 << 20) << 20)
+                .context(format!("Failed to create {}", output.display()))?;
+            if !root {
+                warn!("Building as an unprivileged user: the files of the image belong to uid {} rather than to their owners in the container image",
+                      unsafe { libc::geteuid() });
+            }
+            let mut command = Command::new("mkfs.ext4");
+            command.args(["-q", "-F", "-L", LABEL, "-E", "root_owner=0:0", "-d"]).arg(rootfs).arg(output);
+            ("mkfs.ext4", "e2fsprogs", command)
+        },
+        Filesystem::Erofs => {
+            if size.is_some() {
+                warn!("--size does not apply to erofs, whose images take the size of their contents");
+            }
+            let mut command = Command::new("mkfs.erofs");
+            command.arg(format!("-L{}", LABEL));
+            if !root {
+                command.arg("--all-root");
+            }
+            command.arg(output).arg(rootfs);
+            ("mkfs.erofs", "erofs-utils", command)
+        },
+    };
+
+    let result = command.output().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => anyhow!("{} not found; install {}", tool, package),
+        _ => anyhow!("Failed to run {}: {}", tool, e),
+    })?;
+    if !result.status.success() {
+        bail!("{} failed: {}", tool, String::from_utf8_lossy(&result.stderr).trim());
+    }
+    Ok(())
+}
+
+// Bytes the files under `dir` take up, with a block for each directory entry
+fn disk_usage(dir: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let metadata = entry.metadata()?;
+        bytes += metadata.blocks() * 512 + 4096;
+        if metadata.is_dir() {
+            bytes += disk_usage(&entry.path())?;
+        }
+    }
+    Ok(bytes)
+}
+
+// A disk whose MBR partition table has one Linux partition holding `filesystem`, the /dev/vda1
+// the guest mounts as root. Zero blocks are skipped, so the disk is as sparse as the filesystem.
+fn write_disk(filesystem: &Path, disk: &Path) -> Result<()> {
+    let size = std::fs::metadata(filesystem)?.len().div_ceil(512) * 512;
+    let sectors = u32::try_from(size / 512)
+        .map_err(|_| anyhow!("A {} byte filesystem does not fit in an MBR partition", size))?;
+
+    let mut mbr = [0u8; 512];
+    let partition = &mut mbr[446..462];
+    // CHS addresses are unused
+    partition[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    partition[4] = 0x83;
+    partition[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    partition[8..12].copy_from_slice(&((PARTITION_OFFSET / 512) as u32).to_le_bytes());
+    partition[12..16].copy_from_slice(&sectors.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+
+    let mut output = File::create(disk).context(format!("Failed to create {}", disk.display()))?;
+    output.write_all(&mbr)?;
+    output.seek(SeekFrom::Start(PARTITION_OFFSET))?;
+    let mut input = File::open(filesystem)?;
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        if buffer[..read].iter().all(|&byte| byte == 0) {
+            output.seek(SeekFrom::Current(read as i64))?;
+        } else {
+            output.write_all(&buffer[..read])?;
+        }
+    }
+    output.set_len(PARTITION_OFFSET + size)?;
+    output.sync_all().context(format!("Failed to write {}", disk.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn image_dir_stays_in_the_image() {
+        let dir = std::env::temp_dir().join(format!("vllmd-build-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rootfs = dir.join("rootfs");
+        std::fs::create_dir_all(rootfs.join("usr/sbin")).unwrap();
+        let root = std::fs::canonicalize(&rootfs).unwrap();
+
+        // Absolute and relative links resolve within the image
+        symlink("/usr/sbin", rootfs.join("sbin")).unwrap();
+        assert_eq!(image_dir(&rootfs, Path::new("sbin")).unwrap(), root.join("usr/sbin"));
+        symlink("usr/../var/run", rootfs.join("run")).unwrap();
+        assert_eq!(image_dir(&rootfs, Path::new("run")).unwrap(), root.join("var/run"));
+        assert_eq!(image_dir(&rootfs, Path::new("tmp")).unwrap(), root.join("tmp"));
+
+        // A link to / is the root of the image, not of the host
+        symlink("/", rootfs.join("usr/root")).unwrap();
+        assert_eq!(image_dir(&rootfs, Path::new("usr/root/etc")).unwrap(), root.join("etc"));
+
+        symlink("../../../etc", rootfs.join("proc")).unwrap();
+        let error = image_dir(&rootfs, Path::new("proc")).unwrap_err();
+        assert!(error.to_string().contains("/proc leads outside the image"), "{:#}", error);
+
+        symlink("loop", rootfs.join("loop")).unwrap();
+        assert!(image_dir(&rootfs, Path::new("loop")).unwrap_err().to_string().contains("Too many levels"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A layer archive of empty files at `names` under `dir`
+    fn layer(dir: &Path, names: &[&str]) -> PathBuf {
+        let path = dir.join("layer.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        for name in names {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(0);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, std::io::empty()).unwrap();
+        }
+        builder.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn whiteouts_remove_lower_files() {
+        let dir = std::env::temp_dir().join(format!("vllmd-build-whiteout-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rootfs = dir.join("rootfs");
+        std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+        std::fs::create_dir_all(rootfs.join("opt/lib")).unwrap();
+        for file in ["etc/passwd", "etc/hosts", "opt/bin", "opt/lib/libc.so"] {
+            std::fs::write(rootfs.join(file), "").unwrap();
+        }
+
+        unpack_layer(&layer(&dir, &["etc/.wh.passwd", "opt/.wh..wh..opq", "opt/new"]), &rootfs).unwrap();
+        assert!(!rootfs.join("etc/passwd").exists());
+        assert!(rootfs.join("etc/hosts").exists());
+        assert!(!rootfs.join("opt/bin").exists());
+        assert!(!rootfs.join("opt/lib").exists());
+        assert!(rootfs.join("opt/new").exists());
+        assert!(!rootfs.join("etc/.wh.passwd").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn whiteouts_cannot_remove_their_directory() {
+        let dir = std::env::temp_dir().join(format!("vllmd-build-escape-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rootfs = dir.join("rootfs");
+        std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+        std::fs::write(rootfs.join("etc/hosts"), "").unwrap();
+
+        for name in ["etc/.wh.", "etc/.wh..", "etc/.wh...", ".wh..."] {
+            let error = unpack_layer(&layer(&dir, &[name]), &rootfs).unwrap_err();
+            assert!(error.to_string().contains("invalid whiteout"), "{}: {:#}", name, error);
+            assert!(rootfs.join("etc/hosts").exists(), "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quote_escapes_single_quotes() {
+        assert_eq!(quote("python3"), "'python3'");
+        assert_eq!(quote("$HOME dir"), "'$HOME dir'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn disk_holds_the_filesystem_in_its_first_partition() {
+        let dir = std::env::temp_dir().join(format!("vllmd-build-disk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // A filesystem whose size is not a whole number of sectors, with a zero block in the middle
+        let mut filesystem = vec![0xabu8; 1000];
+        filesystem.extend(vec![0u8; 1 << 20]);
+        filesystem.extend(b"end");
+        std::fs::write(dir.join("rootfs.img"), &filesystem).unwrap();
+
+        write_disk(&dir.join("rootfs.img"), &dir.join("disk.raw")).unwrap();
+        let disk = std::fs::read(dir.join("disk.raw")).unwrap();
+        let sectors = filesystem.len().div_ceil(512);
+        assert_eq!(disk.len() as u64, PARTITION_OFFSET + sectors as u64 * 512);
+        assert_eq!(&disk[510..512], &[0x55, 0xaa]);
+        let partition = &disk[446..462];
+        assert_eq!(partition[4], 0x83);
+        assert_eq!(u32::from_le_bytes(partition[8..12].try_into().unwrap()) as u64, PARTITION_OFFSET / 512);
+        assert_eq!(u32::from_le_bytes(partition[12..16].try_into().unwrap()) as usize, sectors);
+        let start = PARTITION_OFFSET as usize;
+        assert_eq!(&disk[start..start + filesystem.len()], filesystem.as_slice());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
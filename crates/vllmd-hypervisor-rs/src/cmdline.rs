@@ -0,0 +1,240 @@
+use anyhow::{Result, bail};
+
+// Parameters the kernel accepts more than once; a layer setting one replaces all values below it
+const MULTI_VALUED: &[&str] = &["console"];
+
+// Flags that exclude each other and count as one parameter
+const EXCLUSIVE: &[&[&str]] = &[&["ro", "rw"]];
+
+// Source of the parameters vllmd-hypervisor derives itself
+const GENERATED: &str = "generated";
+
+// A single `key`, `key=value` or `key="quoted value"` parameter
+#[derive(Debug, Clone)]
+struct Param {
+    key: String,
+    value: Option<String>,
+    source: &'static str,
+}
+
+impl Param {
+    fn parse(token: &str, source: &'static str) -> Self {
+        match token.split_once('=') {
+            Some((key, value)) => Self { key: key.to_string(), value: Some(value.to_string()), source },
+            None => Self { key: token.to_string(), value: None, source },
+        }
+    }
+
+    // Parameters in the same slot override each other
+    fn slot(&self) -> &str {
+        EXCLUSIVE.iter()
+            .find(|group| group.contains(&self.key.as_str()))
+            .map(|group| group[0])
+            .unwrap_or(&self.key)
+    }
+
+    fn multi_valued(&self) -> bool {
+        MULTI_VALUED.contains(&self.key.as_str())
+    }
+
+    fn render(&self) -> String {
+        match &self.value {
+            Some(value) => format!("{}={}", self.key, value),
+            None => self.key.clone(),
+        }
+    }
+}
+
+// Split a command line on whitespace outside double quotes
+fn tokenize(line: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.push(c);
+            },
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            },
+            c => token.push(c),
+        }
+    }
+
+    if quoted {
+        bail!("Unterminated quote in kernel command line: {}", line);
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// Kernel command line merged from layers added in increasing precedence, such as the `[global]`
+/// base and the runtime's own parameters, with generated defaults for what none of them sets. A
+/// later layer replaces a parameter of an earlier one; the same parameter set twice within a layer
+/// is an error.
+#[derive(Debug, Default)]
+pub struct CmdlineBuilder {
+    params: Vec<Param>,
+    // Arguments after `--`, passed to init; the last layer that has any wins
+    init_args: Vec<String>,
+    overrides: Vec<String>,
+}
+
+impl CmdlineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merge the parameters of `line` over those added so far, naming the layer `source`
+    pub fn add(&mut self, source: &'static str, line: &str) -> Result<&mut Self> {
+        let tokens = tokenize(line)?;
+        let (kernel_tokens, init_args) = match tokens.iter().position(|token| token == "--") {
+            Some(separator) => (&tokens[..separator], Some(&tokens[separator + 1..])),
+            None => (&tokens[..], None),
+        };
+
+        // Conflicts within the layer are mistakes rather than overrides
+        let mut layer: Vec<Param> = Vec::new();
+        for token in kernel_tokens {
+            let param = Param::parse(token, source);
+            if !param.multi_valued() {
+                if let Some(existing) = layer.iter().find(|existing| existing.slot() == param.slot()) {
+                    if existing.render() == param.render() {
+                        continue;
+                    }
+                    bail!("Conflicting kernel parameters in {} command line: {} and {}",
+                          source, existing.render(), param.render());
+                }
+            }
+            layer.push(param);
+        }
+
+        for param in layer {
+            if param.multi_valued() {
+                // Drop the values of earlier layers the first time this layer names the parameter
+                let earlier: Vec<String> = self.params.iter()
+                    .filter(|existing| existing.key == param.key && existing.source != source)
+                    .map(Param::render)
+                    .collect();
+                if !earlier.is_empty() {
+                    self.overrides.push(format!("{} {} replaces {}", source, param.render(), earlier.join(" ")));
+                }
+                self.params.retain(|existing| existing.key != param.key || existing.source == source);
+                self.params.push(param);
+                continue;
+            }
+
+            match self.params.iter_mut().find(|existing| existing.slot() == param.slot()) {
+                Some(existing) => {
+                    if existing.render() != param.render() {
+                        self.overrides.push(format!("{} {} replaces {} {}",
+                                                    source, param.render(), existing.source, existing.render()));
+                    }
+                    *existing = param;
+                },
+                None => self.params.push(param),
+            }
+        }
+
+        if let Some(init_args) = init_args {
+            self.init_args = init_args.to_vec();
+        }
+
+        Ok(self)
+    }
+
+    /// Add the generated parameters of `line` whose key no layer sets, ahead of the others. A
+    /// layer that sets `root=` or `console=` thus never has a generated one next to its own.
+    pub fn add_defaults(&mut self, line: &str) -> Result<&mut Self> {
+        let mut defaults = Vec::new();
+        for token in tokenize(line)? {
+            let param = Param::parse(&token, GENERATED);
+            if !self.params.iter().any(|existing| existing.slot() == param.slot()) {
+                defaults.push(param);
+            }
+        }
+        self.params.splice(0..0, defaults);
+        Ok(self)
+    }
+
+    /// Parameters of earlier layers that later ones replaced, one line each
+    pub fn overrides(&self) -> &[String] {
+        &self.overrides
+    }
+
+    /// The merged command line
+    pub fn build(&self) -> String {
+        let mut parts: Vec<String> = self.params.iter().map(Param::render).collect();
+        if !self.init_args.is_empty() {
+            parts.push("--".to_string());
+            parts.extend(self.init_args.iter().cloned());
+        }
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_layers_replace_earlier_ones() {
+        let mut cmdline = CmdlineBuilder::new();
+        cmdline.add("global", "quiet loglevel=3 rw").unwrap()
+            .add("runtime", "loglevel=7 ro").unwrap();
+        assert_eq!(cmdline.build(), "quiet loglevel=7 ro");
+        assert_eq!(cmdline.overrides(), ["runtime loglevel=7 replaces global loglevel=3", "runtime ro replaces global rw"]);
+    }
+
+    #[test]
+    fn defaults_only_fill_in_unset_parameters() {
+        let mut cmdline = CmdlineBuilder::new();
+        cmdline.add("runtime", "root=/dev/vdb2 ro console=tty0 quiet").unwrap()
+            .add_defaults("console=ttyS0 console=hvc0 root=/dev/vda1 rw systemd.hostname=llama").unwrap();
+        assert_eq!(cmdline.build(), "systemd.hostname=llama root=/dev/vdb2 ro console=tty0 quiet");
+        assert!(cmdline.overrides().is_empty());
+
+        let mut cmdline = CmdlineBuilder::new();
+        cmdline.add("global", "quiet -- --single").unwrap()
+            .add_defaults("console=ttyS0 console=hvc0 root=/dev/vda1 rw").unwrap();
+        assert_eq!(cmdline.build(), "console=ttyS0 console=hvc0 root=/dev/vda1 rw quiet -- --single");
+    }
+
+    #[test]
+    fn conflicts_within_a_layer_are_errors() {
+        let error = CmdlineBuilder::new().add("runtime", "loglevel=3 quiet loglevel=7").unwrap_err();
+        assert_eq!(error.to_string(), "Conflicting kernel parameters in runtime command line: loglevel=3 and loglevel=7");
+        assert!(CmdlineBuilder::new().add("global", "ro rw").is_err());
+
+        // The same parameter twice is only redundant
+        let mut cmdline = CmdlineBuilder::new();
+        cmdline.add("runtime", "quiet quiet loglevel=3 loglevel=3").unwrap();
+        assert_eq!(cmdline.build(), "quiet loglevel=3");
+    }
+
+    #[test]
+    fn multi_valued_parameters_are_replaced_as_a_whole() {
+        let mut cmdline = CmdlineBuilder::new();
+        cmdline.add("global", "console=ttyS0 console=hvc0").unwrap()
+            .add("runtime", "console=tty0").unwrap();
+        assert_eq!(cmdline.build(), "console=tty0");
+        assert_eq!(cmdline.overrides(), ["runtime console=tty0 replaces console=ttyS0 console=hvc0"]);
+    }
+
+    #[test]
+    fn quotes_and_init_arguments() {
+        let mut cmdline = CmdlineBuilder::new();
+        cmdline.add("global", r#"dyndbg="file drivers/* +p" -- --verbose"#).unwrap()
+            .add("runtime", "quiet -- --single").unwrap();
+        assert_eq!(cmdline.build(), r#"dyndbg="file drivers/* +p" quiet -- --single"#);
+        assert!(CmdlineBuilder::new().add("runtime", r#"dyndbg="file"#).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::forward::relay;
+use anyhow::{Result, bail};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+/// Host vsock port TDX guests reach the Quote Generation Service on
+pub const QGS_PORT: u32 = 4050;
+
+/// Default unix socket of the Intel TDX Quote Generation Service on the host
+pub const DEFAULT_QGS_SOCKET_PATH: &str = "/var/run/tdx-qgs/qgs.socket";
+
+/// Confidential computing technology protecting guest memory from the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConfidentialMode {
+    /// AMD Secure Encrypted Virtualization with Secure Nested Paging
+    #[serde(rename = "sev-snp")]
+    SevSnp,
+
+    /// Intel Trust Domain Extensions
+    #[serde(rename = "tdx")]
+    Tdx,
+}
+
+impl ConfidentialMode {
+    /// Parse the value of an environment variable
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "sev-snp" => Ok(ConfidentialMode::SevSnp),
+            "tdx" => Ok(ConfidentialMode::Tdx),
+            _ => bail!("Unknown confidential mode '{}', expected sev-snp or tdx", s),
+        }
+    }
+
+    /// Name used in configuration and messages
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfidentialMode::SevSnp => "sev-snp",
+            ConfidentialMode::Tdx => "tdx",
+        }
+    }
+
+    /// Cargo feature enabling the Cloud Hypervisor support for the mode
+    pub fn feature(&self) -> &'static str {
+        match self {
+            ConfidentialMode::SevSnp => "sev_snp",
+            ConfidentialMode::Tdx => "tdx",
+        }
+    }
+
+    /// Whether this binary was built with that feature
+    pub fn compiled_in(&self) -> bool {
+        match self {
+            ConfidentialMode::SevSnp => cfg!(feature = "sev_snp"),
+            ConfidentialMode::Tdx => cfg!(feature = "tdx"),
+        }
+    }
+}
+
+// Whether /proc/cpuinfo lists the given CPU flag
+fn has_cpu_flag(name: &str) -> bool {
+    std::fs::read_to_string("/proc/cpuinfo")
+        .map(|cpuinfo| cpuinfo.lines()
+            .filter(|line| line.starts_with("flags"))
+            .any(|line| line.split_whitespace().any(|flag| flag == name)))
+        .unwrap_or(false)
+}
+
+// Whether a boolean kernel module parameter is enabled
+fn module_parameter_enabled(path: &str) -> bool {
+    std::fs::read_to_string(path)
+        .map(|value| matches!(value.trim(), "Y" | "1"))
+        .unwrap_or(false)
+}
+
+/// Host support for SEV-SNP: each entry is a requirement and whether the host meets it
+pub fn sev_snp_host_support() -> Vec<(&'static str, bool)> {
+    vec![
+        ("CPU advertises sev_snp", has_cpu_flag("sev_snp")),
+        ("AMD PSP device /dev/sev present", Path::new("/dev/sev").exists()),
+        // kvm_amd only offers the parameter when the PSP firmware supports SNP
+        ("kvm_amd has SEV-SNP enabled", module_parameter_enabled("/sys/module/kvm_amd/parameters/sev_snp")),
+    ]
+}
+
+/// Host support for TDX, in the same form as `sev_snp_host_support`
+pub fn tdx_host_support(qgs_socket_path: &str) -> Vec<(String, bool)> {
+    vec![
+        ("CPU advertises tdx_host_platform".to_string(), has_cpu_flag("tdx_host_platform")),
+        ("kvm_intel has TDX enabled".to_string(), module_parameter_enabled("/sys/module/kvm_intel/parameters/tdx")),
+        (format!("Quote Generation Service listening on {}", qgs_socket_path), Path::new(qgs_socket_path).exists()),
+    ]
+}
+
+/// Relay guest connections to the Quote Generation Service port on to the host QGS socket,
+/// so TDX guests can turn their reports into quotes. Runs until the listener fails.
+pub fn relay_quotes(listener: UnixListener, qgs_socket_path: String) {
+    info!("Relaying TDX quote requests to {}", qgs_socket_path);
+
+    for guest in listener.incoming() {
+        let guest = match guest {
+            Ok(guest) => guest,
+            Err(e) => {
+                warn!("Quote relay listener failed: {}", e);
+                return;
+            }
+        };
+
+        let qgs_socket_path = qgs_socket_path.clone();
+        thread::spawn(move || {
+            let result = UnixStream::connect(&qgs_socket_path)
+                .and_then(|qgs| relay(guest, qgs));
+            if let Err(e) = result {
+                warn!("Quote request relay to {} failed: {}", qgs_socket_path, e);
+            }
+        });
+    }
+}
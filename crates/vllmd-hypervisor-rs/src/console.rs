@@ -0,0 +1,102 @@
+use anyhow::{Result, anyhow, Context};
+use nix::sys::termios::{self, SetArg, Termios};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+// Ctrl-] detaches, as with telnet
+const DETACH_KEY: u8 = 0x1d;
+
+/// Restores the terminal settings when dropped
+struct RawTerminal {
+    fd: i32,
+    original: Termios,
+}
+
+impl RawTerminal {
+    fn enable(fd: i32) -> Result<Self> {
+        let original = termios::tcgetattr(fd)
+            .map_err(|e| anyhow!("Failed to read terminal settings: {}", e))?;
+
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw)
+            .map_err(|e| anyhow!("Failed to switch terminal to raw mode: {}", e))?;
+
+        Ok(Self { fd, original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Attach the terminal to the guest serial console socket until Ctrl-] is pressed or the socket
+/// closes
+pub fn attach(socket_path: &str) -> Result<()> {
+    let mut socket = UnixStream::connect(socket_path)
+        .context(format!("Failed to connect to console socket: {}", socket_path))?;
+
+    println!("Connected to {}. Press Ctrl-] to detach.", socket_path);
+
+    let stdin = std::io::stdin().as_raw_fd();
+    let _raw = RawTerminal::enable(stdin)?;
+
+    // Both directions are polled together, so the session ends as soon as the VM closes the
+    // socket rather than at the next key press. The terminal is read unbuffered, since input
+    // left in a buffer would not wake the poll.
+    let mut stdout = std::io::stdout();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let mut fds = [
+            libc::pollfd { fd: stdin, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e).context("Failed to wait for console input");
+        }
+
+        // Guest output is copied to stdout until the socket closes
+        if fds[1].revents != 0 {
+            match socket.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    if stdout.write_all(&buffer[..read]).is_err() {
+                        break;
+                    }
+                    let _ = stdout.flush();
+                },
+            }
+        }
+
+        if fds[0].revents != 0 {
+            let read = unsafe { libc::read(stdin, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if read < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e).context("Failed to read from terminal");
+            }
+            if read == 0 {
+                break;
+            }
+
+            let chunk = &buffer[..read as usize];
+            if let Some(position) = chunk.iter().position(|&b| b == DETACH_KEY) {
+                socket.write_all(&chunk[..position]).context("Failed to write to console")?;
+                break;
+            }
+            socket.write_all(chunk).context("Failed to write to console")?;
+        }
+    }
+
+    let _ = socket.shutdown(std::net::Shutdown::Both);
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use anyhow::{Result, anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::os::unix::net::UnixStream;
+
+// Cloud Hypervisor crates
+use vmm::api::{VmRemoveDeviceData, VmResizeData, VmSnapshotConfig};
+use vmm::vm_config::NetConfig;
+
+use crate::hypervisor::{size_label, BootTimings};
+
+/// Send a request to the API socket of a running hypervisor and return the response body
+pub fn api_command(socket_path: &str, method: &str, command: &str, body: Option<&str>) -> Result<Option<String>> {
+    let mut socket = UnixStream::connect(socket_path)
+        .context(format!("Failed to connect to API socket: {}", socket_path))?;
+
+    api_client::simple_api_full_command_and_response(
+        &mut socket,
+        method,
+        &format!("/api/v1/vm.{}", command),
+        body,
+    )
+    .map_err(|e| anyhow!("API request {} failed: {}", command, e))
+}
+
+/// Hotplug a network device described by a Cloud Hypervisor net string (e.g. "tap=tap0,mac=...")
+pub fn add_net(socket_path: &str, net: &str) -> Result<Option<String>> {
+    let net_config = NetConfig::parse(net)
+        .map_err(|e| anyhow!("Invalid network configuration {}: {:?}", net, e))?;
+
+    let body = serde_json::to_string(&net_config)
+        .context("Failed to serialize network configuration")?;
+
+    api_command(socket_path, "PUT", "add-net", Some(&body))
+}
+
+/// Hot-remove a device by its identifier
+pub fn remove_device(socket_path: &str, id: &str) -> Result<()> {
+    let body = serde_json::to_string(&VmRemoveDeviceData { id: id.to_string() })
+        .context("Failed to serialize device removal request")?;
+
+    api_command(socket_path, "PUT", "remove-device", Some(&body))?;
+    Ok(())
+}
+
+/// Pause the vCPUs of the VM
+pub fn pause(socket_path: &str) -> Result<()> {
+    api_command(socket_path, "PUT", "pause", None)?;
+    Ok(())
+}
+
+/// Let the vCPUs of a paused VM run again
+pub fn resume(socket_path: &str) -> Result<()> {
+    api_command(socket_path, "PUT", "resume", None)?;
+    Ok(())
+}
+
+/// Change the number of vCPUs, the memory size or the balloon size of the VM, in bytes; None
+/// leaves a setting as it is
+pub fn resize(socket_path: &str, vcpus: Option<u8>, memory: Option<u64>, balloon: Option<u64>) -> Result<()> {
+    let body = serde_json::to_string(&VmResizeData { desired_vcpus: vcpus, desired_ram: memory, desired_balloon: balloon })
+        .context("Failed to serialize resize request")?;
+
+    api_command(socket_path, "PUT", "resize", Some(&body))?;
+    Ok(())
+}
+
+/// Check that the VM can take `desired` bytes of memory: memory grows only into the hotplug range
+/// the VM booted with, and never shrinks below its boot memory
+pub fn check_memory_resize(memory: &MemoryDetails, desired: u64) -> Result<()> {
+    if memory.hotplug_size == 0 {
+        bail!("The VM booted without a hotplug_size in its memory configuration, so its memory cannot be resized");
+    }
+    let limit = memory.size.saturating_add(memory.hotplug_size);
+    if desired > limit {
+        bail!("Cannot resize memory to {}: the VM booted with {} and a hotplug_size of {}, so it can grow to {} at most",
+            size_label(desired), size_label(memory.size), size_label(memory.hotplug_size), size_label(limit));
+    }
+    if desired < memory.size {
+        bail!("Cannot resize memory to {}, below the {} the VM booted with", size_label(desired), size_label(memory.size));
+    }
+    Ok(())
+}
+
+/// Write a snapshot of the paused VM into the existing directory `destination_dir`
+pub fn snapshot(socket_path: &str, destination_dir: &str) -> Result<()> {
+    let body = serde_json::to_string(&VmSnapshotConfig { destination_url: format!("file://{}", destination_dir) })
+        .context("Failed to serialize snapshot request")?;
+
+    api_command(socket_path, "PUT", "snapshot", Some(&body))?;
+    Ok(())
+}
+
+/// What a running VM reports through the vm.info API
+#[derive(Debug, Clone, Serialize)]
+pub struct VmDetails {
+    /// VMM state of the VM: Created, Running, Paused, Shutdown or BreakPoint
+    pub state: String,
+    pub vcpus: VcpuDetails,
+    pub memory: MemoryDetails,
+    /// Disks, network devices and passed-through devices
+    pub devices: Vec<DeviceDetails>,
+    /// Phases of the last start, kept by `start` rather than the VMM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_timing: Option<BootTimings>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VcpuDetails {
+    pub boot: u8,
+    pub max: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryDetails {
+    /// Boot memory in bytes
+    pub size: u64,
+    /// Memory that can be hotplugged on top of `size` in bytes, 0 without hotplug
+    pub hotplug_size: u64,
+    /// Memory hotplugged since boot in bytes
+    pub hotplugged_size: u64,
+    /// Memory the guest can use in bytes, without what the balloon holds
+    pub actual_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceDetails {
+    pub id: String,
+    /// "disk", "net" or "vfio"
+    pub kind: &'static str,
+    /// Image path, tap device or host device path
+    pub source: Option<String>,
+    /// Guest PCI address
+    pub pci_bdf: Option<String>,
+}
+
+// The parts of the vm.info response read into VmDetails
+#[derive(Deserialize)]
+struct InfoResponse {
+    config: InfoConfig,
+    state: String,
+    memory_actual_size: u64,
+    #[serde(default)]
+    device_tree: Option<BTreeMap<String, InfoDeviceNode>>,
+}
+
+#[derive(Deserialize)]
+struct InfoConfig {
+    cpus: InfoCpus,
+    memory: InfoMemory,
+    #[serde(default)]
+    disks: Option<Vec<InfoDevice>>,
+    #[serde(default)]
+    net: Option<Vec<InfoDevice>>,
+    #[serde(default)]
+    devices: Option<Vec<InfoDevice>>,
+}
+
+#[derive(Deserialize)]
+struct InfoCpus {
+    boot_vcpus: u8,
+    max_vcpus: u8,
+}
+
+#[derive(Deserialize)]
+struct InfoMemory {
+    size: u64,
+    #[serde(default)]
+    hotplug_size: Option<u64>,
+    #[serde(default)]
+    hotplugged_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct InfoDevice {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    tap: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct InfoDeviceNode {
+    #[serde(default)]
+    pci_bdf: Option<serde_json::Value>,
+}
+
+impl VmDetails {
+    /// Read the JSON body of a vm.info response
+    pub fn from_response(body: &str) -> Result<Self> {
+        let response: InfoResponse = serde_json::from_str(body)
+            .context("Failed to parse VM info response")?;
+        let device_tree = response.device_tree.unwrap_or_default();
+
+        let config = response.config;
+        let kinds = [("disk", config.disks), ("net", config.net), ("vfio", config.devices)];
+        let devices = kinds.into_iter()
+            .flat_map(|(kind, devices)| devices.unwrap_or_default().into_iter().map(move |device| (kind, device)))
+            .map(|(kind, device)| {
+                let id = device.id.unwrap_or_default();
+                let pci_bdf = device_tree.get(&id)
+                    .and_then(|node| node.pci_bdf.as_ref())
+                    .map(|bdf| bdf.as_str().map(String::from).unwrap_or_else(|| bdf.to_string()));
+                DeviceDetails { id, kind, source: device.tap.or(device.path), pci_bdf }
+            })
+            .collect();
+
+        Ok(Self {
+            state: response.state,
+            vcpus: VcpuDetails { boot: config.cpus.boot_vcpus, max: config.cpus.max_vcpus },
+            memory: MemoryDetails {
+                size: config.memory.size,
+                hotplug_size: config.memory.hotplug_size.unwrap_or(0),
+                hotplugged_size: config.memory.hotplugged_size.unwrap_or(0),
+                actual_size: response.memory_actual_size,
+            },
+            devices,
+            boot_timing: None,
+        })
+    }
+}
+
+/// Per-device counters of the VM behind the API socket, such as `read_bytes` of a disk or
+/// `rx_bytes` of a network device, keyed by device id
+pub fn counters(socket_path: &str) -> Result<BTreeMap<String, BTreeMap<String, u64>>> {
+    let body = api_command(socket_path, "GET", "counters", None)?
+        .ok_or_else(|| anyhow!("Empty response to the counters request"))?;
+    serde_json::from_str(&body).context("Failed to parse counters response")
+}
+
+/// Details of the VM behind the API socket
+pub fn info(socket_path: &str) -> Result<VmDetails> {
+    VmDetails::from_response(&raw_info(socket_path)?)
+}
+
+/// The vm.info response of the VMM as it sent it
+pub fn raw_info(socket_path: &str) -> Result<String> {
+    api_command(socket_path, "GET", "info", None)?
+        .ok_or_else(|| anyhow!("Empty response to the VM info request"))
+}
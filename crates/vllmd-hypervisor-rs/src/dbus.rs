@@ -0,0 +1,272 @@
+use anyhow::{Result, Context};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use zbus::message::Header;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::Value;
+
+/// Well-known name and interface of the service
+pub const SERVICE_NAME: &str = "org.vllmd.Hypervisor1";
+
+/// Object the interface is served at
+pub const OBJECT_PATH: &str = "/org/vllmd/Hypervisor1";
+
+/// polkit action a caller on the system bus needs to start or stop a runtime
+pub const MANAGE_ACTION: &str = "org.vllmd.hypervisor.manage";
+
+/// How often the runtimes are checked for state changes to signal
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What the service does with the runtimes of the host. Runtimes are named as in the
+/// configuration file; the empty name is the unnamed runtime.
+pub trait Runtimes: Send + Sync + 'static {
+    /// Runtimes of the configuration file in start order, after the unnamed runtime while it runs
+    fn names(&self) -> Result<Vec<String>>;
+    /// State (stopped, running or ready) and PID of a runtime
+    fn state(&self, name: &str) -> (String, Option<u32>);
+    /// Boot a runtime in a hypervisor of its own, returning its PID
+    fn start(&self, name: &str) -> Result<u32>;
+    /// Ask the hypervisor of a runtime to shut down, returning its PID
+    fn stop(&self, name: &str) -> Result<u32>;
+}
+
+struct Hypervisor<R: Runtimes> {
+    runtimes: R,
+    /// Ask polkit whether the caller may start and stop runtimes; off on the session bus
+    authorize: bool,
+}
+
+impl<R: Runtimes> Hypervisor<R> {
+    fn check_name(&self, name: &str) -> zbus::fdo::Result<()> {
+        match self.runtimes.names() {
+            Ok(names) if name.is_empty() || names.iter().any(|known| known == name) => Ok(()),
+            Ok(_) => Err(zbus::fdo::Error::InvalidArgs(format!("Runtime {} is not defined in the configuration file", name))),
+            Err(e) => Err(zbus::fdo::Error::Failed(format!("{:#}", e))),
+        }
+    }
+
+    // The caller must hold MANAGE_ACTION; polkit may ask the user to authenticate first
+    async fn check_authorized(&self, connection: &zbus::Connection, header: &Header<'_>) -> zbus::fdo::Result<()> {
+        if !self.authorize {
+            return Ok(());
+        }
+        let sender = header.sender()
+            .ok_or_else(|| zbus::fdo::Error::AccessDenied("The request has no sender".to_string()))?;
+        let subject = ("system-bus-name", HashMap::from([("name", Value::from(sender.as_str()))]));
+        // AllowUserInteraction
+        let flags = 1u32;
+        let reply = connection.call_method(
+            Some("org.freedesktop.PolicyKit1"),
+            "/org/freedesktop/PolicyKit1/Authority",
+            Some("org.freedesktop.PolicyKit1.Authority"),
+            "CheckAuthorization",
+            &(subject, MANAGE_ACTION, HashMap::<&str, &str>::new(), flags, ""),
+        ).await?;
+        let (authorized, _, _): (bool, bool, HashMap<String, String>) = reply.body().deserialize()?;
+        if authorized {
+            Ok(())
+        } else {
+            Err(zbus::fdo::Error::AccessDenied(format!("Not authorized for {}", MANAGE_ACTION)))
+        }
+    }
+
+    // Start once the caller is known to be allowed to
+    fn start_runtime(&self, name: &str, caller: &str) -> zbus::fdo::Result<u32> {
+        if let (_, Some(pid)) = self.runtimes.state(name) {
+            return Err(zbus::fdo::Error::Failed(format!("Runtime {} is already running (PID: {})", name, pid)));
+        }
+        info!("Starting runtime {} for {}", name, caller);
+        self.runtimes.start(name).map_err(|e| zbus::fdo::Error::Failed(format!("{:#}", e)))
+    }
+
+    // Stop once the caller is known to be allowed to
+    fn stop_runtime(&self, name: &str, caller: &str) -> zbus::fdo::Result<u32> {
+        info!("Stopping runtime {} for {}", name, caller);
+        self.runtimes.stop(name).map_err(|e| zbus::fdo::Error::Failed(format!("{:#}", e)))
+    }
+}
+
+#[zbus::interface(name = "org.vllmd.Hypervisor1")]
+impl<R: Runtimes> Hypervisor<R> {
+    /// Every runtime of the configuration file with its state and PID (0 when stopped)
+    fn list(&self) -> zbus::fdo::Result<Vec<(String, String, u32)>> {
+        let names = self.runtimes.names().map_err(|e| zbus::fdo::Error::Failed(format!("{:#}", e)))?;
+        Ok(names.into_iter().map(|name| {
+            let (state, pid) = self.runtimes.state(&name);
+            (name, state, pid.unwrap_or(0))
+        }).collect())
+    }
+
+    /// State and PID (0 when stopped) of one runtime
+    fn status(&self, name: &str) -> zbus::fdo::Result<(String, u32)> {
+        self.check_name(name)?;
+        let (state, pid) = self.runtimes.state(name);
+        Ok((state, pid.unwrap_or(0)))
+    }
+
+    /// Boot a runtime; StateChanged reports when its VM runs
+    async fn start(&self, #[zbus(connection)] connection: &zbus::Connection, #[zbus(header)] header: Header<'_>,
+                   name: &str) -> zbus::fdo::Result<u32> {
+        self.check_name(name)?;
+        self.check_authorized(connection, &header).await?;
+        self.start_runtime(name, header.sender().map(|sender| sender.as_str()).unwrap_or("-"))
+    }
+
+    /// Shut a runtime down; StateChanged reports when its hypervisor has exited
+    async fn stop(&self, #[zbus(connection)] connection: &zbus::Connection, #[zbus(header)] header: Header<'_>,
+                  name: &str) -> zbus::fdo::Result<u32> {
+        self.check_name(name)?;
+        self.check_authorized(connection, &header).await?;
+        self.stop_runtime(name, header.sender().map(|sender| sender.as_str()).unwrap_or("-"))
+    }
+
+    /// A runtime went from one of stopped, running and ready to another
+    #[zbus(signal)]
+    async fn state_changed(emitter: &SignalEmitter<'_>, name: &str, state: &str) -> zbus::Result<()>;
+}
+
+/// Own SERVICE_NAME on the system bus, or on the session bus of the user with `session`, and
+/// serve requests until the process is stopped. State changes are signalled as they are seen.
+pub fn serve(runtimes: impl Runtimes + Clone, session: bool) -> Result<()> {
+    let builder = if session {
+        zbus::blocking::connection::Builder::session()
+    } else {
+        zbus::blocking::connection::Builder::system()
+    }.context("Failed to connect to the D-Bus bus")?;
+    let service = Hypervisor { runtimes: runtimes.clone(), authorize: !session };
+    let connection = builder
+        .name(SERVICE_NAME).and_then(|builder| builder.serve_at(OBJECT_PATH, service))
+        .and_then(|builder| builder.build())
+        .context(format!("Failed to own {} on the {} bus", SERVICE_NAME, if session { "session" } else { "system" }))?;
+    info!("Serving {} at {}", SERVICE_NAME, OBJECT_PATH);
+
+    let mut states: BTreeMap<String, String> = BTreeMap::new();
+    // The first pass only learns the current states
+    changes(&runtimes, &mut states);
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        for (name, state) in changes(&runtimes, &mut states) {
+            let signalled = connection.emit_signal(None::<()>, OBJECT_PATH, SERVICE_NAME, "StateChanged", &(name.as_str(), state.as_str()));
+            if let Err(e) = signalled {
+                warn!("Failed to signal the state of runtime {}: {}", name, e);
+            }
+        }
+    }
+}
+
+// Runtimes whose state differs from the one in `states`, which is brought up to date
+fn changes(runtimes: &impl Runtimes, states: &mut BTreeMap<String, String>) -> Vec<(String, String)> {
+    let mut names = runtimes.names().unwrap_or_else(|e| {
+        warn!("Failed to list runtimes: {:#}", e);
+        Vec::new()
+    });
+    // A runtime that left the list, such as the unnamed one stopping, still gets its signal
+    let gone: Vec<String> = states.keys().filter(|name| !names.contains(name)).cloned().collect();
+    names.extend(gone);
+    let mut changed = Vec::new();
+    for name in names {
+        let (state, _) = runtimes.state(&name);
+        if states.get(&name) != Some(&state) {
+            states.insert(name.clone(), state.clone());
+            changed.push((name, state));
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::Mutex;
+
+    // Runtimes of a configuration file, with the PIDs of those that run
+    #[derive(Default)]
+    struct Fake {
+        names: Vec<String>,
+        running: Mutex<BTreeMap<String, u32>>,
+    }
+
+    impl Fake {
+        fn new(names: &[&str]) -> Self {
+            Self { names: names.iter().map(|name| name.to_string()).collect(), ..Default::default() }
+        }
+    }
+
+    impl Runtimes for Fake {
+        fn names(&self) -> Result<Vec<String>> {
+            let mut names = self.names.clone();
+            if self.running.lock().unwrap().contains_key("") {
+                names.insert(0, String::new());
+            }
+            Ok(names)
+        }
+
+        fn state(&self, name: &str) -> (String, Option<u32>) {
+            match self.running.lock().unwrap().get(name) {
+                Some(pid) => ("running".to_string(), Some(*pid)),
+                None => ("stopped".to_string(), None),
+            }
+        }
+
+        fn start(&self, name: &str) -> Result<u32> {
+            let mut running = self.running.lock().unwrap();
+            let pid = 100 + running.len() as u32;
+            running.insert(name.to_string(), pid);
+            Ok(pid)
+        }
+
+        fn stop(&self, name: &str) -> Result<u32> {
+            match self.running.lock().unwrap().remove(name) {
+                Some(pid) => Ok(pid),
+                None => bail!("Runtime {} is not running", name),
+            }
+        }
+    }
+
+    fn hypervisor(names: &[&str]) -> Hypervisor<Fake> {
+        Hypervisor { runtimes: Fake::new(names), authorize: false }
+    }
+
+    #[test]
+    fn list_and_status_report_state_and_pid() {
+        let service = hypervisor(&["alpha", "beta"]);
+        service.runtimes.running.lock().unwrap().insert("beta".to_string(), 42);
+        assert_eq!(service.list().unwrap(), [("alpha".to_string(), "stopped".to_string(), 0), ("beta".to_string(), "running".to_string(), 42)]);
+        assert_eq!(service.status("beta").unwrap(), ("running".to_string(), 42));
+        assert_eq!(service.status("alpha").unwrap(), ("stopped".to_string(), 0));
+        // The unnamed runtime is always known
+        assert_eq!(service.status("").unwrap(), ("stopped".to_string(), 0));
+        assert!(matches!(service.status("gamma"), Err(zbus::fdo::Error::InvalidArgs(message)) if message.contains("gamma")));
+    }
+
+    #[test]
+    fn start_and_stop_reach_the_runtimes() {
+        let service = hypervisor(&["alpha"]);
+        let pid = service.start_runtime("alpha", ":1.5").unwrap();
+        assert_eq!(service.status("alpha").unwrap(), ("running".to_string(), pid));
+        let error = service.start_runtime("alpha", ":1.5").unwrap_err();
+        assert!(matches!(&error, zbus::fdo::Error::Failed(message) if message.contains("already running")), "{}", error);
+
+        assert_eq!(service.stop_runtime("alpha", ":1.5").unwrap(), pid);
+        let error = service.stop_runtime("alpha", ":1.5").unwrap_err();
+        assert!(matches!(&error, zbus::fdo::Error::Failed(message) if message.contains("not running")), "{}", error);
+    }
+
+    #[test]
+    fn changes_report_each_new_state_once() {
+        let runtimes = Fake::new(&["alpha"]);
+        let mut states = BTreeMap::new();
+        assert_eq!(changes(&runtimes, &mut states), [("alpha".to_string(), "stopped".to_string())]);
+        assert!(changes(&runtimes, &mut states).is_empty());
+
+        runtimes.start("alpha").unwrap();
+        runtimes.start("").unwrap();
+        assert_eq!(changes(&runtimes, &mut states), [("".to_string(), "running".to_string()), ("alpha".to_string(), "running".to_string())]);
+        // The unnamed runtime leaves the list when it stops and is still reported
+        runtimes.stop("").unwrap();
+        assert_eq!(changes(&runtimes, &mut states), [("".to_string(), "stopped".to_string())]);
+        assert!(changes(&runtimes, &mut states).is_empty());
+    }
+}
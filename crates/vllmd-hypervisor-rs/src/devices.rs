@@ -0,0 +1,1139 @@
+use anyhow::{Result, Context, anyhow, bail};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::audit::{self, AuditLog};
+use crate::fleet::is_alive;
+use crate::hypervisor::parse_size;
+
+/// Where the kernel lists the PCI devices of the host
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// Directory standing in for /sys, so tests can list devices from a fixture tree
+const SYSFS_VAR: &str = "VLLMD_MOCK_SYSFS";
+
+/// PCI base classes of GPUs and accelerators: display controllers, coprocessors (class 0x0b,
+/// subclass 0x40) and processing accelerators
+const DISPLAY_CLASS: u32 = 0x03;
+const COPROCESSOR_CLASS: u32 = 0x0b40;
+const ACCELERATOR_CLASS: u32 = 0x12;
+
+/// NVMe controllers: mass storage, non-volatile memory, NVM Express
+const NVME_CLASS: u32 = 0x010802;
+
+/// Network controllers, Ethernet (RoCE) and InfiniBand alike
+const NETWORK_CLASS: u32 = 0x02;
+
+/// PCIe extended capability of Address Translation Services, which lets a NIC cache IOMMU
+/// translations for peer-to-peer DMA with GPUs
+const ATS_CAPABILITY: u32 = 0x000f;
+
+/// Capability ID of the PCI Express capability, which holds the Device Status register
+const PCI_EXPRESS_CAPABILITY: u8 = 0x10;
+
+/// PCI-to-PCI bridges, which may share an IOMMU group with the devices behind them
+const BRIDGE_CLASS: u32 = 0x0604;
+
+/// Flag of the memory BARs in the `resource` file of a device
+const IORESOURCE_MEM: u64 = 0x200;
+
+/// How long a device may take to answer config space reads again after a reset; the PCIe
+/// specification allows 1 s after FLR, and accelerators with large firmware take longer
+const RESET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Vendors of GPUs and accelerators, by PCI vendor ID
+const VENDORS: &[(&str, &str)] = &[
+    ("10de", "NVIDIA"), ("1002", "AMD"), ("8086", "Intel"), ("1da3", "Habana"), ("1e52", "Tenstorrent"),
+    ("1ed5", "MooreThreads"), ("1a03", "ASPEED"),
+];
+
+/// Where the kernel lists the PCI devices of the host, under `VLLMD_MOCK_SYSFS` when it is set
+pub fn pci_devices() -> PathBuf {
+    match std::env::var_os(SYSFS_VAR) {
+        Some(root) => Path::new(&root).join("bus/pci/devices"),
+        None => PathBuf::from(PCI_DEVICES),
+    }
+}
+
+/// A GPU or accelerator of the host
+#[derive(Debug, Clone, Serialize)]
+pub struct PciDevice {
+    /// Domain, bus, slot and function, e.g. 0000:41:00.0
+    pub address: String,
+    /// Vendor ID in hex, e.g. 10de
+    pub vendor_id: String,
+    /// Device ID in hex
+    pub device_id: String,
+    /// Vendor name when known
+    pub vendor: Option<String>,
+    /// Class code in hex, e.g. 030200
+    pub class: String,
+    /// Driver the device is bound to, if any
+    pub driver: Option<String>,
+    /// None when the IOMMU is off
+    pub iommu_group: Option<u32>,
+    /// Other devices of the IOMMU group, which VFIO hands to the VM along with this one
+    pub group_devices: Vec<String>,
+    /// None when the kernel has no NUMA information for the device, which it reports as -1,
+    /// as on hosts with a single NUMA node
+    pub numa_node: Option<u32>,
+    /// Size of the largest memory BAR in bytes, which on data-center GPUs maps the device memory
+    pub memory: Option<u64>,
+    /// The IOMMU group holds only functions of this device and bridges, so the device can be
+    /// passed through without taking unrelated host devices along
+    pub passable: bool,
+    /// Why the device cannot be passed through cleanly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The running hypervisor holding the device, as in Claims
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claim: Option<Claim>,
+}
+
+impl PciDevice {
+    /// Read the device at `address` from sysfs
+    pub fn read(address: &str) -> Result<Self> {
+        let path = pci_devices().join(address);
+        let class = read_hex(&path.join("class"))
+            .context(format!("No PCI device {}", address))?;
+        let vendor_id = format!("{:04x}", read_hex(&path.join("vendor"))?);
+        let iommu_group = link_name(&path.join("iommu_group")).and_then(|group| group.parse().ok());
+        let group_devices: Vec<String> = std::fs::read_dir(path.join("iommu_group/devices"))
+            .map(|entries| entries.flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|member| member != address)
+                .collect())
+            .unwrap_or_default();
+
+        let mut device = Self {
+            address: address.to_string(),
+            vendor: VENDORS.iter().find(|(id, _)| *id == vendor_id).map(|(_, name)| name.to_string()),
+            vendor_id,
+            device_id: format!("{:04x}", read_hex(&path.join("device"))?),
+            class: format!("{:06x}", class),
+            driver: link_name(&path.join("driver")),
+            iommu_group,
+            group_devices,
+            numa_node: std::fs::read_to_string(path.join("numa_node")).ok()
+                .and_then(|node| match node.trim().parse::<i32>().ok()? {
+                    -1 => None,
+                    node => u32::try_from(node).ok(),
+                }),
+            memory: largest_bar(&path.join("resource")),
+            passable: false,
+            reason: None,
+            claim: None,
+        };
+        device.reason = device.blocker();
+        device.passable = device.reason.is_none();
+        Ok(device)
+    }
+
+    /// Bus and slot, the address without its function
+    pub fn slot(&self) -> &str {
+        self.address.rsplit_once('.').map_or(&self.address, |(slot, _)| slot)
+    }
+
+    fn blocker(&self) -> Option<String> {
+        let path = pci_devices().join(&self.address);
+        if std::fs::read_to_string(path.join("boot_vga")).is_ok_and(|boot_vga| boot_vga.trim() == "1") {
+            return Some("drives the host console".to_string());
+        }
+        let Some(group) = self.iommu_group else {
+            return Some("in no IOMMU group (is the IOMMU enabled?)".to_string());
+        };
+        let unrelated: Vec<String> = self.group_devices.iter()
+            .filter(|member| member.rsplit_once('.').map(|(slot, _)| slot) != Some(self.slot()))
+            .filter(|member| read_hex(&pci_devices().join(member).join("class")).map_or(true, |class| class >> 8 != BRIDGE_CLASS))
+            .map(|member| match link_name(&pci_devices().join(member).join("driver")) {
+                Some(driver) => format!("{} ({})", member, driver),
+                None => member.clone(),
+            })
+            .collect();
+        if unrelated.is_empty() {
+            None
+        } else {
+            Some(format!("shares IOMMU group {} with {}", group, unrelated.join(", ")))
+        }
+    }
+}
+
+/// GPUs and accelerators of the host, by address
+pub fn accelerators() -> Result<Vec<PciDevice>> {
+    let mut addresses: Vec<PathBuf> = std::fs::read_dir(pci_devices())
+        .context(format!("Failed to read {}", pci_devices().display()))?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    addresses.sort();
+
+    let mut devices = Vec::new();
+    for path in addresses {
+        let Ok(class) = read_hex(&path.join("class")) else { continue };
+        if is_accelerator(class) {
+            devices.push(PciDevice::read(&path.file_name().unwrap_or_default().to_string_lossy())?);
+        }
+    }
+    Ok(devices)
+}
+
+/// Whether the PCI device at `address` is a GPU or accelerator
+pub fn is_accelerator_address(address: &str) -> bool {
+    read_hex(&pci_devices().join(address).join("class")).is_ok_and(is_accelerator)
+}
+
+fn is_accelerator(class: u32) -> bool {
+    class >> 16 == DISPLAY_CLASS || class >> 8 == COPROCESSOR_CLASS || class >> 16 == ACCELERATOR_CLASS
+}
+
+/// Devices sharing the IOMMU group of the device at the sysfs path `device` that are bound to a
+/// host driver, with the driver. VFIO refuses a group while any of them is; bridges are exempt.
+pub fn group_conflicts(device: &Path) -> Vec<String> {
+    let address = device.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let Ok(members) = std::fs::read_dir(device.join("iommu_group/devices")) else { return Vec::new() };
+    let mut conflicts: Vec<String> = members.flatten()
+        .map(|member| member.file_name().to_string_lossy().into_owned())
+        .filter(|member| *member != address)
+        .filter(|member| read_hex(&pci_devices().join(member).join("class")).map_or(true, |class| class >> 8 != BRIDGE_CLASS))
+        .filter_map(|member| {
+            let driver = link_name(&pci_devices().join(&member).join("driver"))?;
+            (driver != "vfio-pci").then(|| format!("{} ({})", member, driver))
+        })
+        .collect();
+    conflicts.sort();
+    conflicts
+}
+
+/// A `[[runtimes.devices]]` entry: a PCI device other than a GPU to hand to the guest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: DeviceKind,
+    /// PCI address, e.g. 0000:c1:00.0
+    pub pci: String,
+    /// NVMe namespace to hand over as a virtio disk instead of the whole controller
+    pub namespace: Option<u32>,
+    /// SR-IOV virtual function of the RDMA NIC at `pci` to pass through instead of the NIC
+    pub vf: Option<u32>,
+    /// InfiniBand node GUID given to the VF, e.g. 00:11:22:33:44:55:66:77
+    pub node_guid: Option<String>,
+    /// InfiniBand port GUID given to the VF
+    pub port_guid: Option<String>,
+}
+
+/// What a `[[runtimes.devices]]` entry is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Nvme,
+    /// ConnectX-class InfiniBand or RoCE NIC, or one of its VFs
+    Rdma,
+}
+
+/// How a device reaches the guest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Passthrough {
+    /// sysfs path of a PCI function the VM gets through VFIO
+    Vfio(String),
+    /// Host block device the VM gets as a virtio disk
+    Block(String),
+}
+
+impl DeviceConfig {
+    /// Check that the device is what the entry says it is and find how it is handed over
+    pub fn resolve(&self) -> Result<Vec<Passthrough>> {
+        if self.kind != DeviceKind::Nvme && self.namespace.is_some() {
+            bail!("namespace only applies to NVMe devices");
+        }
+        if self.kind != DeviceKind::Rdma && (self.vf.is_some() || self.node_guid.is_some() || self.port_guid.is_some()) {
+            bail!("vf, node_guid and port_guid only apply to RDMA devices");
+        }
+        let path = pci_devices().join(&self.pci);
+        let class = read_hex(&path.join("class")).context(format!("No PCI device {}", self.pci))?;
+        match self.kind {
+            DeviceKind::Nvme => {
+                if class != NVME_CLASS {
+                    bail!("Device {} is not an NVMe controller (class {:06x})", self.pci, class);
+                }
+                match self.namespace {
+                    None => Ok(vec![Passthrough::Vfio(path.to_string_lossy().into_owned())]),
+                    Some(namespace) => Ok(vec![Passthrough::Block(nvme_namespace(&path, &self.pci, namespace)?)]),
+                }
+            },
+            DeviceKind::Rdma => self.resolve_rdma(&path, class),
+        }
+    }
+
+    // The NIC, or its VF, and the other functions of the NIC in its IOMMU group, which VFIO
+    // hands over with it
+    fn resolve_rdma(&self, path: &Path, class: u32) -> Result<Vec<Passthrough>> {
+        if class >> 16 != NETWORK_CLASS {
+            bail!("Device {} is not a network controller (class {:06x})", self.pci, class);
+        }
+        for guid in [&self.node_guid, &self.port_guid].into_iter().flatten() {
+            let valid = guid.split(':').count() == 8
+                && guid.split(':').all(|byte| byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit()));
+            if !valid {
+                bail!("Invalid GUID '{}': expected 8 colon-separated hex bytes", guid);
+            }
+        }
+        let function = match self.vf {
+            None if self.node_guid.is_some() || self.port_guid.is_some() => bail!("node_guid and port_guid need vf"),
+            None => self.pci.clone(),
+            Some(vf) => {
+                let enabled: u32 = std::fs::read_to_string(path.join("sriov_numvfs")).ok()
+                    .and_then(|count| count.trim().parse().ok())
+                    .ok_or_else(|| anyhow!("Device {} does not support SR-IOV", self.pci))?;
+                if vf >= enabled {
+                    bail!("Device {} has {} VF(s) enabled, so VF {} does not exist; raise {}",
+                          self.pci, enabled, vf, path.join("sriov_numvfs").display());
+                }
+                link_name(&path.join(format!("virtfn{}", vf)))
+                    .ok_or_else(|| anyhow!("VF {} of device {} is missing", vf, self.pci))?
+            },
+        };
+
+        let function_path = pci_devices().join(&function);
+        if extended_capability(&function_path, ATS_CAPABILITY) == Some(false) {
+            warn!("Device {} does not support ATS; its peer-to-peer DMA with GPUs goes through the IOMMU", function);
+        }
+        let device = PciDevice::read(&function)?;
+        let mut functions = vec![function_path.to_string_lossy().into_owned()];
+        for member in &device.group_devices {
+            if member.rsplit_once('.').map(|(slot, _)| slot) == Some(device.slot()) {
+                info!("Passing {} through along with {}, its IOMMU group holds both", member, function);
+                functions.push(pci_devices().join(member).to_string_lossy().into_owned());
+            }
+        }
+        Ok(functions.into_iter().map(Passthrough::Vfio).collect())
+    }
+
+    /// What configure() changes on the host, one line per change
+    pub fn planned_changes(&self) -> Vec<String> {
+        let Some(vf) = self.vf.filter(|_| self.kind == DeviceKind::Rdma) else { return Vec::new() };
+        [("node_guid", &self.node_guid), ("port_guid", &self.port_guid)].into_iter()
+            .filter_map(|(kind, guid)| guid.as_ref().map(|guid| format!("set {} of VF {} of {} to {}", kind, vf, self.pci, guid)))
+            .collect()
+    }
+
+    /// Prepare the device on the host before the VM boots: give an RDMA VF the GUIDs of the
+    /// entry through the network interface of its NIC
+    pub fn configure(&self) -> Result<()> {
+        let Some(vf) = self.vf.filter(|_| self.kind == DeviceKind::Rdma) else { return Ok(()) };
+        let guids = [("node_guid", &self.node_guid), ("port_guid", &self.port_guid)];
+        if guids.iter().all(|(_, guid)| guid.is_none()) {
+            return Ok(());
+        }
+        let netdev = std::fs::read_dir(pci_devices().join(&self.pci).join("net")).ok()
+            .and_then(|mut entries| entries.next())
+            .and_then(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .ok_or_else(|| anyhow!("Device {} has no network interface to set the GUIDs of VF {} through", self.pci, vf))?;
+        for (kind, guid) in guids {
+            let Some(guid) = guid else { continue };
+            info!("Setting {} of VF {} of {} to {}", kind, vf, netdev, guid);
+            let vf = vf.to_string();
+            let output = std::process::Command::new("ip")
+                .args(["link", "set", "dev", &netdev, "vf", &vf, kind, guid])
+                .output()
+                .context("Failed to run ip")?;
+            if !output.status.success() {
+                bail!("Failed to set {} of VF {} of {}: {}", kind, vf, netdev, String::from_utf8_lossy(&output.stderr).trim());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether the device has PCIe extended capability `id`; None when the extended configuration
+// space cannot be read, as without root
+fn extended_capability(device: &Path, id: u32) -> Option<bool> {
+    let config = std::fs::read(device.join("config")).ok().filter(|config| config.len() >= 4096)?;
+    let mut offset = 0x100;
+    // Each header holds the ID, a version and the offset of the next capability
+    for _ in 0..((4096 - 0x100) / 4) {
+        let header = u32::from_le_bytes(config[offset..offset + 4].try_into().ok()?);
+        if header == 0 || header == u32::MAX {
+            return Some(false);
+        }
+        if header & 0xffff == id {
+            return Some(true);
+        }
+        offset = (header >> 20) as usize;
+        if offset < 0x100 || offset + 4 > config.len() {
+            return Some(false);
+        }
+    }
+    Some(false)
+}
+
+// Block device of namespace `namespace` of the NVMe controller at `path`. The host nvme driver
+// has to hold the controller, and nothing on the host may have the namespace mounted.
+fn nvme_namespace(path: &Path, address: &str, namespace: u32) -> Result<String> {
+    let controller = std::fs::read_dir(path.join("nvme")).ok()
+        .and_then(|mut entries| entries.next())
+        .and_then(|entry| entry.ok())
+        .map(|entry| entry.path());
+    let Some(controller) = controller else {
+        bail!("NVMe controller {} is not bound to the nvme driver (bound to {}), so its namespaces are not available; leave out namespace to pass the whole controller",
+              address, link_name(&path.join("driver")).as_deref().unwrap_or("nothing"));
+    };
+    // nvme0n1, or nvme0c0n1 for the path of this controller to a multipath namespace nvme0n1
+    let suffix = format!("n{}", namespace);
+    let block = std::fs::read_dir(&controller).into_iter().flatten().flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .find_map(|name| {
+            let middle = name.strip_prefix("nvme")?.strip_suffix(&suffix)?;
+            let instance = middle.split_once('c').map_or(middle, |(subsystem, _)| subsystem);
+            (!instance.is_empty() && middle.chars().all(|c| c.is_ascii_digit() || c == 'c'))
+                .then(|| format!("nvme{}{}", instance, suffix))
+        });
+    let Some(block) = block else {
+        bail!("NVMe controller {} has no namespace {}", address, namespace);
+    };
+
+    let device = format!("/dev/{}", block);
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let mounted = mounts.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .find(|source| source.strip_prefix(&device).is_some_and(|rest| rest.is_empty() || rest.starts_with('p')));
+    if let Some(source) = mounted {
+        bail!("{} of NVMe controller {} is mounted on the host ({})", device, address, source);
+    }
+    Ok(device)
+}
+
+/// A `gpu_selector` of comma-separated requirements a GPU must all meet. `vendor` (name or ID),
+/// `device`, `driver` and `numa` compare with `=` or `!=`; `memory` also with `<`, `<=`, `>` and
+/// `>=` against a size such as `80G`. The size is that of the device memory, which the largest
+/// BAR maps rounded up to a power of two, so it is rounded up the same way before comparing.
+#[derive(Debug, Clone)]
+pub struct GpuSelector {
+    requirements: Vec<(String, String, String)>,
+}
+
+impl GpuSelector {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut requirements = Vec::new();
+        for requirement in spec.split(',').map(str::trim).filter(|requirement| !requirement.is_empty()) {
+            let Some(start) = requirement.find(['=', '!', '<', '>']) else {
+                bail!("Invalid GPU requirement '{}': expected key=value, or memory>=size", requirement);
+            };
+            let (key, rest) = requirement.split_at(start);
+            let operator: String = rest.chars().take_while(|c| "=!<>".contains(*c)).collect();
+            let value = rest[operator.len()..].trim().to_string();
+            let key = key.trim().to_ascii_lowercase();
+            match (key.as_str(), operator.as_str()) {
+                ("vendor" | "device" | "driver" | "numa", "=" | "==" | "!=") => {},
+                ("memory", "=" | "==" | "!=" | "<" | "<=" | ">" | ">=") => {
+                    parse_size(&value).context(format!("Invalid GPU requirement '{}'", requirement))?;
+                },
+                ("vendor" | "device" | "driver" | "numa" | "memory", _) => {
+                    bail!("Invalid GPU requirement '{}': {} cannot be compared with {}", requirement, key, operator);
+                },
+                _ => bail!("Unknown GPU property '{}' in '{}', expected vendor, device, driver, numa or memory", key, requirement),
+            }
+            requirements.push((key, operator, value));
+        }
+        if requirements.is_empty() {
+            bail!("The GPU selector is empty");
+        }
+        Ok(Self { requirements })
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.requirements.iter().all(|(key, operator, value)| {
+            if key == "memory" {
+                let (Some(memory), Ok(wanted)) = (device.memory, parse_size(value)) else { return false };
+                let wanted = wanted.checked_next_power_of_two().unwrap_or(u64::MAX);
+                return match operator.as_str() {
+                    "<" => memory < wanted,
+                    "<=" => memory <= wanted,
+                    ">" => memory > wanted,
+                    ">=" => memory >= wanted,
+                    "!=" => memory != wanted,
+                    _ => memory == wanted,
+                };
+            }
+            let equal = match key.as_str() {
+                "vendor" => device.vendor_id.eq_ignore_ascii_case(value)
+                    || device.vendor.as_deref().is_some_and(|vendor| vendor.eq_ignore_ascii_case(value)),
+                "device" => device.device_id.eq_ignore_ascii_case(value),
+                "driver" => device.driver.as_deref() == Some(value.as_str()),
+                _ => device.numa_node.map(|node| node.to_string()).as_deref() == Some(value.as_str()),
+            };
+            equal == (operator != "!=")
+        })
+    }
+}
+
+/// A GPU held by a running hypervisor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claim {
+    /// None for the unnamed runtime
+    pub runtime: Option<String>,
+    pub pid: u32,
+}
+
+/// GPUs claimed by running hypervisors, by address, in `<state dir>/gpus.json`. A claim ends when
+/// its hypervisor releases it or exits, so a crashed hypervisor does not keep its GPUs.
+#[derive(Debug, Clone)]
+pub struct Claims {
+    path: PathBuf,
+}
+
+impl Claims {
+    pub fn new(state_dir: &str) -> Self {
+        Self { path: Path::new(state_dir).join("gpus.json") }
+    }
+
+    /// Claims of the hypervisors still running
+    pub fn load(&self) -> BTreeMap<String, Claim> {
+        let mut claims: BTreeMap<String, Claim> = std::fs::read_to_string(&self.path).ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        claims.retain(|_, claim| is_alive(claim.pid));
+        claims
+    }
+
+    /// Claim the `pinned` addresses and `count` more passable GPUs meeting `selector` for the
+    /// hypervisor of this process, returning the allocated addresses. GPUs claimed by other
+    /// hypervisors are never handed out, nor are the `reserved` ones other runtimes pin. A
+    /// hypervisor that restarted in place gets the GPUs it held back.
+    pub fn claim(&self, runtime: Option<&str>, pinned: &[String], count: u32, selector: Option<&GpuSelector>,
+                 reserved: &[String]) -> Result<Vec<String>> {
+        let pid = std::process::id();
+        self.modify(|claims| {
+            let allocated = allocate(claims, pinned, count, selector, reserved)?;
+
+            // GPUs a restarted hypervisor no longer passes through go back to the pool
+            claims.retain(|_, claim| claim.pid != pid);
+            for address in pinned.iter().chain(&allocated) {
+                claims.insert(address.clone(), Claim { runtime: runtime.map(str::to_string), pid });
+            }
+            Ok(allocated)
+        })
+    }
+
+    /// The GPUs `claim` would allocate now, failing as it would, without claiming any
+    pub fn plan(&self, pinned: &[String], count: u32, selector: Option<&GpuSelector>, reserved: &[String]) -> Result<Vec<String>> {
+        allocate(&self.load(), pinned, count, selector, reserved)
+    }
+
+    /// Release the GPUs of the hypervisor of this process
+    pub fn release(&self) -> Result<()> {
+        let pid = std::process::id();
+        self.modify(|claims| {
+            claims.retain(|_, claim| claim.pid != pid);
+            Ok(())
+        })
+    }
+
+    // Read, change and write the claims under a lock, so hypervisors starting at the same time
+    // cannot take the same GPU; claims of exited hypervisors are dropped on the way
+    fn modify<T>(&self, change: impl FnOnce(&mut BTreeMap<String, Claim>) -> Result<T>) -> Result<T> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create state directory: {}", parent.display()))?;
+        }
+        let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&self.path)
+            .context(format!("Failed to open GPU claims: {}", self.path.display()))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error()).context(format!("Failed to lock GPU claims: {}", self.path.display()));
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut claims: BTreeMap<String, Claim> = if contents.trim().is_empty() {
+            BTreeMap::new()
+        } else {
+            serde_json::from_str(&contents).context(format!("Failed to parse GPU claims: {}", self.path.display()))?
+        };
+        claims.retain(|_, claim| is_alive(claim.pid));
+        let result = change(&mut claims)?;
+
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(0)?;
+        file.write_all(serde_json::to_string_pretty(&claims)?.as_bytes())?;
+        file.sync_data()?;
+        Ok(result)
+    }
+}
+
+// Check that the `pinned` addresses are free for the hypervisor of this process and choose `count`
+// more GPUs meeting `selector` among those `claims` and `reserved` leave
+fn allocate(claims: &BTreeMap<String, Claim>, pinned: &[String], count: u32, selector: Option<&GpuSelector>,
+            reserved: &[String]) -> Result<Vec<String>> {
+    let pid = std::process::id();
+    for address in pinned {
+        if let Some(claim) = claims.get(address).filter(|claim| claim.pid != pid) {
+            bail!("GPU {} is claimed by runtime {} (PID: {})", address,
+                  claim.runtime.as_deref().unwrap_or("(unnamed)"), claim.pid);
+        }
+    }
+
+    let mut allocated = Vec::new();
+    if count > 0 {
+        let devices = accelerators()?;
+        let matching: Vec<&PciDevice> = devices.iter()
+            .filter(|device| selector.is_none_or(|selector| selector.matches(device)))
+            .collect();
+        let free: Vec<&PciDevice> = matching.iter().copied()
+            .filter(|device| device.passable && !pinned.contains(&device.address))
+            .filter(|device| !reserved.contains(&device.address))
+            .filter(|device| claims.get(&device.address).is_none_or(|claim| claim.pid == pid))
+            .collect();
+        if matching.is_empty() {
+            bail!("{} GPU(s) requested but {}", count,
+                  if selector.is_some() { "no GPU of the host meets gpu_selector" } else { "the host has no GPUs" });
+        }
+        if free.len() < count as usize {
+            let claimed = matching.iter().filter(|device| claims.get(&device.address).is_some_and(|claim| claim.pid != pid)).count();
+            let pinned_elsewhere = matching.iter().filter(|device| reserved.contains(&device.address)).count();
+            let blocked = matching.iter().filter(|device| !device.passable).count();
+            bail!("{} GPU(s) requested but only {} of the {} matching are free ({} claimed by running runtimes, {} pinned by other runtimes, {} not passable)",
+                  count, free.len(), matching.len(), claimed, pinned_elsewhere, blocked);
+        }
+        let held: Vec<String> = free.iter()
+            .filter(|device| claims.contains_key(&device.address))
+            .map(|device| device.address.clone())
+            .collect();
+        let addresses: Vec<String> = free.iter().map(|device| device.address.clone()).collect();
+        allocated = pick_connected(&addresses, count as usize, &held);
+    }
+    Ok(allocated)
+}
+
+/// How two GPUs reach each other, fastest first. The PCIe levels follow `nvidia-smi topo -m`: a
+/// switch (PIX/PXB), a host bridge (PHB), the host bridges of one NUMA node (NODE) and the
+/// interconnect between sockets (SYS).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Link {
+    /// NVLink, directly or through NVSwitch
+    Nvlink,
+    /// Behind the same PCIe switch
+    PcieSwitch,
+    /// Under the same PCIe host bridge
+    HostBridge,
+    /// Under different host bridges of one NUMA node
+    NumaNode,
+    /// Across sockets
+    System,
+}
+
+impl Link {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Link::Nvlink => "nvlink",
+            Link::PcieSwitch => "pcie-switch",
+            Link::HostBridge => "host-bridge",
+            Link::NumaNode => "numa-node",
+            Link::System => "system",
+        }
+    }
+}
+
+/// The link between two GPUs of a runtime, as `inspect` shows it
+#[derive(Debug, Clone, Serialize)]
+pub struct GpuLink {
+    pub gpus: [String; 2],
+    pub link: Link,
+}
+
+/// The GPU interconnect of the host. PCIe placement comes from sysfs; NVLink from `nvidia-smi`,
+/// so only between GPUs the NVIDIA driver still holds, which it does until they are bound to
+/// vfio-pci.
+#[derive(Debug, Clone, Default)]
+pub struct Topology {
+    nvlinks: BTreeSet<(String, String)>,
+}
+
+impl Topology {
+    pub fn read() -> Self {
+        Self { nvlinks: nvlinks().unwrap_or_default() }
+    }
+
+    pub fn link(&self, a: &str, b: &str) -> Link {
+        let pair = if a < b { (a.to_string(), b.to_string()) } else { (b.to_string(), a.to_string()) };
+        if self.nvlinks.contains(&pair) {
+            return Link::Nvlink;
+        }
+        // /sys/devices/pci0000:40/<root port>/<switch upstream port>/<downstream port>/<device>;
+        // a root port links to one device, so two devices below it are behind a switch
+        let path = |address: &str| -> Vec<String> {
+            std::fs::canonicalize(pci_devices().join(address)).ok()
+                .and_then(|path| path.strip_prefix("/sys/devices").ok().map(Path::to_path_buf))
+                .map(|path| path.iter().map(|component| component.to_string_lossy().into_owned()).collect())
+                .unwrap_or_default()
+        };
+        let (a_path, b_path) = (path(a), path(b));
+        let shared = a_path.iter().zip(&b_path).take_while(|(a, b)| a == b).count();
+        let numa_node = |address: &str| std::fs::read_to_string(pci_devices().join(address).join("numa_node")).ok()
+            .map(|node| node.trim().to_string());
+        if shared >= 2 {
+            Link::PcieSwitch
+        } else if shared >= 1 {
+            Link::HostBridge
+        } else if numa_node(a).is_some_and(|node| node != "-1" && Some(&node) == numa_node(b).as_ref()) {
+            Link::NumaNode
+        } else {
+            Link::System
+        }
+    }
+
+    /// Every pair of `addresses` with its link
+    pub fn links(&self, addresses: &[String]) -> Vec<GpuLink> {
+        let mut links = Vec::new();
+        for (i, a) in addresses.iter().enumerate() {
+            for b in &addresses[i + 1..] {
+                links.push(GpuLink { gpus: [a.clone(), b.clone()], link: self.link(a, b) });
+            }
+        }
+        links
+    }
+}
+
+// Pairs of GPUs `nvidia-smi topo -m` reports an NVLink between, by PCI address
+fn nvlinks() -> Option<BTreeSet<(String, String)>> {
+    let run = |args: &[&str]| -> Option<String> {
+        let output = std::process::Command::new("nvidia-smi").args(args).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    // 00000000:41:00.0, in index order
+    let addresses: Vec<String> = run(&["--query-gpu=pci.bus_id", "--format=csv,noheader"])?
+        .lines()
+        .filter_map(|line| {
+            let (domain, rest) = line.trim().split_once(':')?;
+            Some(format!("{:04x}:{}", u32::from_str_radix(domain, 16).ok()?, rest.to_ascii_lowercase()))
+        })
+        .collect();
+    let matrix = run(&["topo", "-m"])?;
+
+    let mut pairs = BTreeSet::new();
+    for line in matrix.lines() {
+        // Rows start with their GPU and hold one column per GPU, X on the diagonal
+        let mut fields = line.split_whitespace();
+        let Some(row) = fields.next().and_then(|name| name.strip_prefix("GPU")).and_then(|index| index.parse::<usize>().ok()) else { continue };
+        for (column, link) in fields.take(addresses.len()).enumerate() {
+            if link.starts_with("NV") {
+                if let (Some(a), Some(b)) = (addresses.get(row), addresses.get(column)) {
+                    pairs.insert(if a < b { (a.clone(), b.clone()) } else { (b.clone(), a.clone()) });
+                }
+            }
+        }
+    }
+    Some(pairs)
+}
+
+// `count` of the free GPUs with the fastest links between them. A hypervisor that restarted in
+// place keeps the GPUs it held.
+fn pick_connected(free: &[String], count: usize, held: &[String]) -> Vec<String> {
+    let topology = if count > 1 { Topology::read() } else { Topology::default() };
+    let links: Vec<Vec<Link>> = free.iter()
+        .map(|a| free.iter().map(|b| topology.link(a, b)).collect())
+        .collect();
+    let held: Vec<usize> = (0..free.len()).filter(|i| held.contains(&free[*i])).collect();
+    let mut picked: Vec<String> = pick_fastest(&links, count, &held).into_iter().map(|i| free[i].clone()).collect();
+    picked.sort();
+    picked
+}
+
+// `count` indexes into the link matrix `links` whose slowest link is the fastest, then with the
+// fewest slow links, grown greedily from each index in turn, or from `held` when it is not empty
+fn pick_fastest(links: &[Vec<Link>], count: usize, held: &[usize]) -> Vec<usize> {
+    // Slowest link, then the sum of all links
+    type Score = (Option<Link>, usize);
+    let score = |set: &[usize]| -> Score {
+        let pairs: Vec<Link> = set.iter().enumerate()
+            .flat_map(|(i, a)| set[i + 1..].iter().map(|b| links[*a][*b]))
+            .collect();
+        (pairs.iter().max().copied(), pairs.iter().map(|link| *link as usize).sum())
+    };
+
+    let seeds: Vec<Vec<usize>> = if held.is_empty() {
+        (0..links.len()).map(|i| vec![i]).collect()
+    } else {
+        vec![held.iter().copied().take(count).collect()]
+    };
+    let mut best: Option<(Score, Vec<usize>)> = None;
+    for mut set in seeds {
+        while set.len() < count {
+            let Some(next) = (0..links.len()).filter(|i| !set.contains(i))
+                .min_by_key(|i| score(&[set.as_slice(), &[*i]].concat())) else { break };
+            set.push(next);
+        }
+        let set_score = score(&set);
+        if best.as_ref().is_none_or(|(best_score, _)| set_score < *best_score) {
+            best = Some((set_score, set));
+        }
+    }
+    best.map(|(_, set)| set).unwrap_or_default()
+}
+
+/// Reset the device at the sysfs path `device` and wait until it answers with its own IDs again.
+/// The kernel tries the methods listed in `reset_method` in order, typically FLR on devices that
+/// support it and a secondary bus reset of the parent bridge otherwise. Returns the method it
+/// tries first, None on kernels without `reset_method`.
+pub fn reset(device: &Path) -> Result<Option<String>> {
+    let address = device.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let reset = device.join("reset");
+    // The kernel removes `reset` from devices without a method, and empties `reset_method` when
+    // every method was turned off
+    let methods = std::fs::read_to_string(device.join("reset_method")).ok();
+    if !reset.exists() || methods.as_deref().is_some_and(|methods| methods.trim().is_empty()) {
+        bail!("Device {} supports no reset method", address);
+    }
+    let method = methods.as_deref().and_then(|methods| methods.split_whitespace().next()).map(str::to_string);
+    info!("Resetting device {} ({})", address, methods.as_deref().map(str::trim).unwrap_or("method chosen by the kernel"));
+    std::fs::write(&reset, "1").context(format!("Failed to reset device {}", address))?;
+
+    // A device that is stuck, or still coming back from the reset, reads all ones
+    let expected = sysfs_ids(device)?;
+    let started = Instant::now();
+    loop {
+        let ids = config_ids(device)?;
+        if ids == Some(expected) {
+            return Ok(method);
+        }
+        if started.elapsed() >= RESET_TIMEOUT {
+            let (vendor, device) = ids.unwrap_or((0xffff, 0xffff));
+            bail!("Device {} did not recover from the reset within {}s: it reads {:04x}:{:04x}, expected {:04x}:{:04x}",
+                  address, RESET_TIMEOUT.as_secs(), vendor, device, expected.0, expected.1);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Reset each device of `device_paths` in turn, recording each reset in `audit`, and fail after
+/// trying them all when any could not be reset
+pub fn reset_all(device_paths: &[String], audit: &AuditLog) -> Result<()> {
+    let mut failures = Vec::new();
+    for device_path in device_paths {
+        let reset = reset(Path::new(device_path));
+        let method = reset.as_ref().ok().cloned().flatten();
+        audit.record("reset", audit::outcome(serde_json::json!({ "device": device_path, "method": method }), &reset));
+        if let Err(e) = reset {
+            failures.push(format!("{:#}", e));
+        }
+    }
+    if !failures.is_empty() {
+        bail!("Failed to reset {} device(s): {}", failures.len(), failures.join("; "));
+    }
+    Ok(())
+}
+
+/// Whether the device at the sysfs path `device` answers config space reads with the vendor and
+/// device ID the kernel found at enumeration. One that fell off the bus reads all ones.
+pub fn responds(device: &Path) -> bool {
+    matches!((sysfs_ids(device), config_ids(device)), (Ok(expected), Ok(Some(ids))) if ids == expected)
+}
+
+/// Errors flagged in the Device Status register of a PCI Express device, which stay set until
+/// software clears them
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PendingErrors {
+    /// Fatal and non-fatal errors, after which a new driver may fail to initialize the device
+    pub uncorrectable: Vec<&'static str>,
+    /// Correctable errors and unsupported requests, which routine config space probing also
+    /// leaves on healthy devices
+    pub benign: Vec<&'static str>,
+}
+
+/// Errors the device at the sysfs path `device` flags in the Device Status register of its PCI
+/// Express capability; conventional PCI devices flag none. None when the register cannot be
+/// read: config space past the 64 bytes of the header is readable by root only.
+pub fn pending_errors(device: &Path) -> Option<PendingErrors> {
+    let config = std::fs::read(device.join("config")).ok()?;
+    if config.len() < 256 {
+        return None;
+    }
+    // The status register says whether there is a capability list at all
+    let mut offset = if config[0x06] & 0x10 != 0 { config[0x34] as usize & !0x3 } else { 0 };
+    // The list has at most 48 entries in the 256 bytes of standard config space
+    for _ in 0..48 {
+        if offset == 0 || offset + 0x0c > config.len() {
+            break;
+        }
+        if config[offset] == PCI_EXPRESS_CAPABILITY {
+            let status = u16::from_le_bytes([config[offset + 0x0a], config[offset + 0x0b]]);
+            let flagged = |errors: &[(u16, &'static str)]| -> Vec<&'static str> {
+                errors.iter().filter(|(bit, _)| status & bit != 0).map(|(_, name)| *name).collect()
+            };
+            return Some(PendingErrors {
+                uncorrectable: flagged(&[(0x2, "non-fatal error"), (0x4, "fatal error")]),
+                benign: flagged(&[(0x1, "correctable error"), (0x8, "unsupported request")]),
+            });
+        }
+        offset = config[offset + 1] as usize & !0x3;
+    }
+    Some(PendingErrors::default())
+}
+
+/// Fatal and non-fatal AER errors the device at the sysfs path `device` has reported since the
+/// host booted, or None without AER
+pub fn aer_totals(device: &Path) -> Option<(u64, u64)> {
+    let total = |attribute: &str, key: &str| -> Option<u64> {
+        std::fs::read_to_string(device.join(attribute)).ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.trim().parse().ok())
+    };
+    Some((total("aer_dev_fatal", "TOTAL_ERR_FATAL")?, total("aer_dev_nonfatal", "TOTAL_ERR_NONFATAL")?))
+}
+
+/// Processes other than this one holding VFIO group `group` open, as `PID (command)`. VFIO lets
+/// one container at a time have a group, so these are typically VMMs still using the device.
+/// Only the processes of the same user are visible, unless running as root.
+pub fn group_holders(group: &str) -> Vec<String> {
+    let group_path = PathBuf::from(format!("/dev/vfio/{}", group));
+    let own = std::process::id();
+    let Ok(processes) = std::fs::read_dir("/proc") else { return Vec::new() };
+    let mut holders: Vec<(u32, String)> = processes.flatten()
+        .filter_map(|process| process.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| *pid != own)
+        .filter(|pid| {
+            std::fs::read_dir(format!("/proc/{}/fd", pid))
+                .is_ok_and(|fds| fds.flatten().any(|fd| std::fs::read_link(fd.path()).is_ok_and(|target| target == group_path)))
+        })
+        .map(|pid| {
+            let command = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+            (pid, command.trim().to_string())
+        })
+        .collect();
+    holders.sort();
+    holders.into_iter().map(|(pid, command)| format!("{} ({})", pid, command)).collect()
+}
+
+// Vendor and device ID the kernel read at enumeration
+fn sysfs_ids(device: &Path) -> Result<(u16, u16)> {
+    Ok((read_hex(&device.join("vendor"))? as u16, read_hex(&device.join("device"))? as u16))
+}
+
+// Vendor and device ID the device answers with now, from the first 4 bytes of its config space
+fn config_ids(device: &Path) -> Result<Option<(u16, u16)>> {
+    let config = std::fs::read(device.join("config"))
+        .context(format!("Failed to read config space of device {}", device.display()))?;
+    Ok((config.len() >= 4)
+        .then(|| (u16::from_le_bytes([config[0], config[1]]), u16::from_le_bytes([config[2], config[3]]))))
+}
+
+// A sysfs attribute such as `class` or `vendor`, written as 0x-prefixed hex
+fn read_hex(path: &Path) -> Result<u32> {
+    let value = std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    u32::from_str_radix(value.trim().trim_start_matches("0x"), 16)
+        .context(format!("Invalid value in {}: {}", path.display(), value.trim()))
+}
+
+// Size of the largest memory BAR listed in a `resource` file, one `start end flags` line per BAR
+fn largest_bar(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<u64> = line.split_whitespace()
+                .map(|field| u64::from_str_radix(field.trim_start_matches("0x"), 16))
+                .collect::<std::result::Result<_, _>>().ok()?;
+            match fields[..] {
+                [start, end, flags] if flags & IORESOURCE_MEM != 0 && end > start => Some(end - start + 1),
+                _ => None,
+            }
+        })
+        .max()
+}
+
+// Name of the target of a sysfs symlink such as `driver` or `iommu_group`
+fn link_name(path: &Path) -> Option<String> {
+    std::fs::read_link(path).ok()?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two sockets of two GPUs each: 0-1 and 2-3 are NVLink pairs, 1 and 2 share a NUMA node
+    fn two_sockets() -> Vec<Vec<Link>> {
+        let link = |a: usize, b: usize| match (a.min(b), a.max(b)) {
+            (0, 1) | (2, 3) => Link::Nvlink,
+            (1, 2) => Link::NumaNode,
+            _ => Link::System,
+        };
+        (0..4).map(|a| (0..4).map(|b| link(a, b)).collect()).collect()
+    }
+
+    #[test]
+    fn prefers_an_nvlink_pair_over_crossing_sockets() {
+        let mut picked = pick_fastest(&two_sockets(), 2, &[]);
+        picked.sort();
+        assert!(picked == [0, 1] || picked == [2, 3], "{:?}", picked);
+    }
+
+    #[test]
+    fn restart_keeps_the_held_gpus() {
+        // Held GPUs stay even when another set is faster, and the set grows from them
+        let picked = pick_fastest(&two_sockets(), 2, &[1, 2]);
+        assert_eq!(picked, [1, 2]);
+        let mut picked = pick_fastest(&two_sockets(), 2, &[2]);
+        picked.sort();
+        assert_eq!(picked, [2, 3]);
+    }
+
+    // A device directory with a 256 byte config space whose capability list holds power
+    // management at 0x40, then PCI Express at 0x60 with Device Status `status`
+    fn fake_device(dir: &Path, ids: (u16, u16), status: u16) -> PathBuf {
+        let device = dir.join("0000:41:00.0");
+        std::fs::create_dir_all(&device).unwrap();
+        let mut config = vec![0u8; 256];
+        config[0..2].copy_from_slice(&ids.0.to_le_bytes());
+        config[2..4].copy_from_slice(&ids.1.to_le_bytes());
+        config[0x06] = 0x10;
+        config[0x34] = 0x40;
+        config[0x40..0x42].copy_from_slice(&[0x01, 0x60]);
+        config[0x60..0x62].copy_from_slice(&[PCI_EXPRESS_CAPABILITY, 0x00]);
+        config[0x6a..0x6c].copy_from_slice(&status.to_le_bytes());
+        std::fs::write(device.join("config"), config).unwrap();
+        std::fs::write(device.join("vendor"), "0x10de\n").unwrap();
+        std::fs::write(device.join("device"), "0x2330\n").unwrap();
+        device
+    }
+
+    #[test]
+    fn reset_uses_the_first_listed_method_and_records_each_device() {
+        let dir = std::env::temp_dir().join(format!("vllmd-devices-reset-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let flr = fake_device(&dir.join("flr"), (0x10de, 0x2330), 0);
+        std::fs::write(flr.join("reset"), "").unwrap();
+        std::fs::write(flr.join("reset_method"), "flr bus\n").unwrap();
+        assert_eq!(reset(&flr).unwrap().as_deref(), Some("flr"));
+        assert_eq!(std::fs::read_to_string(flr.join("reset")).unwrap(), "1");
+
+        // Kernels before 5.15 have `reset` without `reset_method`
+        let old = fake_device(&dir.join("old"), (0x10de, 0x2330), 0);
+        std::fs::write(old.join("reset"), "").unwrap();
+        assert_eq!(reset(&old).unwrap(), None);
+
+        let disabled = fake_device(&dir.join("disabled"), (0x10de, 0x2330), 0);
+        std::fs::write(disabled.join("reset"), "").unwrap();
+        std::fs::write(disabled.join("reset_method"), "\n").unwrap();
+        assert!(reset(&disabled).unwrap_err().to_string().contains("supports no reset method"));
+        let none = fake_device(&dir.join("none"), (0x10de, 0x2330), 0);
+        assert!(reset(&none).unwrap_err().to_string().contains("supports no reset method"));
+
+        let log = dir.join("audit.jsonl");
+        let audit = AuditLog::new(log.display().to_string(), None);
+        let paths: Vec<String> = [&flr, &none].iter().map(|path| path.display().to_string()).collect();
+        let error = reset_all(&paths, &audit).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to reset 1 device(s): Device 0000:41:00.0 supports no reset method"), "{}", error);
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&log).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["operation"], "reset");
+        assert_eq!(records[0]["params"], serde_json::json!({ "device": paths[0], "method": "flr" }));
+        assert_eq!(records[1]["params"]["device"], paths[1].as_str());
+        assert!(records[1]["params"]["method"].is_null());
+        assert!(records[1]["params"]["error"].as_str().unwrap().contains("supports no reset method"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn responds_compares_config_space_with_sysfs() {
+        let dir = std::env::temp_dir().join(format!("vllmd-devices-responds-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(responds(&fake_device(&dir, (0x10de, 0x2330), 0)));
+        assert!(!responds(&fake_device(&dir, (0xffff, 0xffff), 0)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pending_errors_split_uncorrectable_from_benign() {
+        let dir = std::env::temp_dir().join(format!("vllmd-devices-status-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let ids = (0x10de, 0x2330);
+        assert_eq!(pending_errors(&fake_device(&dir, ids, 0)), Some(PendingErrors::default()));
+
+        let errors = pending_errors(&fake_device(&dir, ids, 0x9)).unwrap();
+        assert!(errors.uncorrectable.is_empty());
+        assert_eq!(errors.benign, ["correctable error", "unsupported request"]);
+
+        let errors = pending_errors(&fake_device(&dir, ids, 0xe)).unwrap();
+        assert_eq!(errors.uncorrectable, ["non-fatal error", "fatal error"]);
+        assert_eq!(errors.benign, ["unsupported request"]);
+
+        // Unprivileged readers see the 64 byte header only
+        let device = fake_device(&dir, ids, 0x4);
+        std::fs::write(device.join("config"), [0u8; 64]).unwrap();
+        assert_eq!(pending_errors(&device), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn aer_totals_read_the_total_lines() {
+        let dir = std::env::temp_dir().join(format!("vllmd-devices-aer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let device = fake_device(&dir, (0x10de, 0x2330), 0);
+        assert_eq!(aer_totals(&device), None);
+
+        std::fs::write(device.join("aer_dev_fatal"), "Undefined 0\nDLP 1\nTOTAL_ERR_FATAL 1\n").unwrap();
+        std::fs::write(device.join("aer_dev_nonfatal"), "Undefined 0\nTOTAL_ERR_NONFATAL 3\n").unwrap();
+        assert_eq!(aer_totals(&device), Some((1, 3)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn gpu(vendor_id: &str, memory: Option<u64>, numa_node: Option<u32>) -> PciDevice {
+        PciDevice {
+            address: "0000:41:00.0".to_string(),
+            vendor: VENDORS.iter().find(|(id, _)| *id == vendor_id).map(|(_, name)| name.to_string()),
+            vendor_id: vendor_id.to_string(),
+            device_id: "2330".to_string(),
+            class: "030200".to_string(),
+            driver: Some("vfio-pci".to_string()),
+            iommu_group: Some(12),
+            group_devices: Vec::new(),
+            numa_node,
+            memory,
+            passable: true,
+            reason: None,
+            claim: None,
+        }
+    }
+
+    #[test]
+    fn gpu_selector_parse_checks_keys_and_operators() {
+        assert!(GpuSelector::parse("vendor=nvidia, memory>=80G").is_ok());
+        assert!(GpuSelector::parse("NUMA!=1").is_ok());
+        for invalid in ["", " , ", "vendor", "color=red", "vendor>=nvidia", "memory>=lots", "numa<1"] {
+            assert!(GpuSelector::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn gpu_selector_matches_device_memory_by_its_bar() {
+        // An 80 GB GPU maps its memory with a 128 GiB BAR
+        let h100 = gpu("10de", Some(128 << 30), Some(0));
+        let l40 = gpu("10de", Some(64 << 30), Some(1));
+        let matches = |spec: &str, device: &PciDevice| GpuSelector::parse(spec).unwrap().matches(device);
+
+        assert!(matches("memory=80G", &h100));
+        assert!(matches("memory>=80G", &h100));
+        assert!(!matches("memory>=80G", &l40));
+        assert!(matches("memory=48G", &l40));
+        assert!(matches("memory<80G", &l40));
+        assert!(matches("memory!=80G", &l40));
+        assert!(!matches("memory=1G", &gpu("10de", None, None)));
+
+        assert!(matches("vendor=nvidia,device=2330,driver=vfio-pci", &h100));
+        assert!(matches("vendor=10DE", &h100));
+        assert!(!matches("vendor=amd", &h100));
+        assert!(matches("numa=1", &l40));
+        assert!(!matches("numa=1", &h100));
+        assert!(!matches("numa=0", &gpu("10de", None, None)));
+        assert!(matches("numa!=0", &gpu("10de", None, None)));
+    }
+}
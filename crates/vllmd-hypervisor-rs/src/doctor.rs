@@ -0,0 +1,325 @@
+use crate::backend::BackendKind;
+use crate::confidential::{self, ConfidentialMode};
+use crate::devices;
+use crate::nested;
+use crate::retry::Permanent;
+use anyhow::{Result, anyhow, bail};
+use log::{log, Level};
+use nix::sys::resource::{getrlimit, Resource, RLIM_INFINITY};
+use std::fs::OpenOptions;
+use std::path::Path;
+
+/// Capability numbers from linux/capability.h
+pub const CAP_NET_ADMIN: u32 = 12;
+pub const CAP_IPC_LOCK: u32 = 14;
+
+/// Outcome of a single host check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// Not met, but not needed by the selected runtime
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn label(&self) -> &'static str {
+        match self {
+            Status::Pass => " ok ",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        }
+    }
+}
+
+/// A host requirement and whether it is met
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub status: Status,
+    pub description: String,
+    /// Whether a failure can clear by itself, such as a group the previous VM is still releasing
+    pub transient: bool,
+}
+
+impl Check {
+    fn new(met: bool, required: bool, description: impl Into<String>) -> Self {
+        let status = match (met, required) {
+            (true, _) => Status::Pass,
+            (false, true) => Status::Fail,
+            (false, false) => Status::Warn,
+        };
+        Self { status, description: description.into(), transient: false }
+    }
+
+    fn transient(self) -> Self {
+        Self { transient: true, ..self }
+    }
+}
+
+/// Fail naming every check that failed, for a step `RetryPolicy::run` tries again. Each one is
+/// logged as an error on the last try, or once a failure cannot clear by itself, which also ends
+/// the retries; until then the retry warning names them.
+pub fn failures(what: &str, checks: &[Check], last_try: bool) -> Result<()> {
+    let failed: Vec<&Check> = checks.iter().filter(|check| check.status == Status::Fail).collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let permanent = failed.iter().any(|check| !check.transient);
+    for check in &failed {
+        log!(failure_level(last_try, permanent), "{} failed: {}", what, check.description);
+    }
+    let descriptions: Vec<&str> = failed.iter().map(|check| check.description.as_str()).collect();
+    let message = format!("{} {}(s) failed: {}", failed.len(), what.to_lowercase(), descriptions.join("; "));
+    if permanent {
+        return Err(anyhow!(Permanent(message)));
+    }
+    bail!(message)
+}
+
+// Failed checks are errors once they fail the start, and only shown while debugging before that
+fn failure_level(last_try: bool, permanent: bool) -> Level {
+    if last_try || permanent {
+        Level::Error
+    } else {
+        Level::Debug
+    }
+}
+
+fn read_write(path: &str) -> bool {
+    OpenOptions::new().read(true).write(true).open(path).is_ok()
+}
+
+/// A field of /proc/<pid>/status, such as "CapEff" or "Uid"
+pub fn process_status(pid: &str, field: &str) -> Option<String> {
+    std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':').map(|value| value.trim().to_string()))
+}
+
+/// Whether process `pid` ("self" for this one) holds capability `cap` in its effective set
+pub fn has_capability(pid: &str, cap: u32) -> bool {
+    process_status(pid, "CapEff")
+        .and_then(|mask| u64::from_str_radix(&mask, 16).ok())
+        .is_some_and(|mask| mask & (1 << cap) != 0)
+}
+
+// Human readable byte count for check descriptions
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        bytes if bytes >= 1 << 30 => format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64),
+        bytes if bytes >= 1 << 20 => format!("{:.1} MiB", bytes as f64 / (1u64 << 20) as f64),
+        bytes => format!("{} bytes", bytes),
+    }
+}
+
+// Name of the target of a sysfs symlink such as `driver` or `iommu_group`
+fn link_name(path: &Path) -> Option<String> {
+    std::fs::read_link(path).ok()?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// What this process needs to boot a VM on `backend` with `device_paths` passed through and
+/// `memory_size` bytes of guest memory. Every check is required.
+pub fn preflight(backend: BackendKind, device_paths: &[String], memory_size: u64) -> Vec<Check> {
+    let mut checks = Vec::new();
+    match backend.resolve() {
+        // The mock backend opens no device
+        Ok(backend) => if let Some(device) = backend.device_path() {
+            checks.push(Check::new(read_write(device), true, format!("{} is readable and writable", device)));
+        },
+        Err(e) => checks.push(Check::new(false, true, format!("{:#}", e))),
+    }
+    if device_paths.is_empty() {
+        return checks;
+    }
+
+    checks.push(Check::new(read_write("/dev/vfio/vfio"), true, "/dev/vfio/vfio is readable and writable"));
+    for device_path in device_paths {
+        let device = Path::new(device_path);
+        let name = device.file_name().map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| device_path.clone());
+
+        let driver = link_name(&device.join("driver"));
+        checks.push(Check::new(
+            driver.as_deref() == Some("vfio-pci"),
+            true,
+            format!("{} is bound to vfio-pci (bound to {})", name, driver.as_deref().unwrap_or("nothing")),
+        ));
+
+        match link_name(&device.join("iommu_group")) {
+            Some(group) => {
+                let group_path = format!("/dev/vfio/{}", group);
+                checks.push(Check::new(read_write(&group_path), true,
+                                       format!("{} group {} is readable and writable", name, group_path)));
+                let holders = devices::group_holders(&group);
+                checks.push(Check::new(holders.is_empty(), true, match holders.is_empty() {
+                    true => format!("{} group {} is not held by another VFIO container", name, group_path),
+                    false => format!("{} group {} is held by another VFIO container: {}", name, group_path, holders.join(", ")),
+                }).transient());
+                let conflicts = devices::group_conflicts(device);
+                checks.push(Check::new(conflicts.is_empty(), true, match conflicts.is_empty() {
+                    true => format!("{} shares IOMMU group {} with no device bound to a host driver", name, group),
+                    false => format!("{} shares IOMMU group {} with devices bound to host drivers: {}", name, group, conflicts.join(", ")),
+                }));
+            },
+            None => checks.push(Check::new(false, true,
+                                           format!("{} is in an IOMMU group (is the IOMMU enabled?)", name))),
+        }
+    }
+
+    // VFIO pins all of guest memory for DMA
+    if has_capability("self", CAP_IPC_LOCK) {
+        checks.push(Check::new(true, true, "CAP_IPC_LOCK lets VFIO pin guest memory"));
+    } else {
+        let (limit, _) = getrlimit(Resource::RLIMIT_MEMLOCK).unwrap_or((0, 0));
+        let limit_text = if limit == RLIM_INFINITY { "unlimited".to_string() } else { format_bytes(limit) };
+        checks.push(Check::new(
+            limit == RLIM_INFINITY || limit >= memory_size,
+            true,
+            format!("RLIMIT_MEMLOCK ({}) covers the {} of guest memory VFIO pins", limit_text, format_bytes(memory_size)),
+        ));
+    }
+
+    checks
+}
+
+/// Whether the devices at `device_paths` are in a state the guest driver can initialize. `start`
+/// checks this after the preflight checks and any reset, which may clear what these find.
+pub fn health(device_paths: &[String]) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for device_path in device_paths {
+        let device = Path::new(device_path);
+        let name = device.file_name().map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| device_path.clone());
+
+        checks.push(Check::new(devices::responds(device), true,
+                               format!("{} answers config space reads with its vendor and device ID", name)).transient());
+        match devices::pending_errors(device) {
+            Some(errors) => {
+                let uncorrectable = &errors.uncorrectable;
+                checks.push(Check::new(uncorrectable.is_empty(), true, match uncorrectable.is_empty() {
+                    true => format!("{} has no uncorrectable PCIe error pending", name),
+                    false => format!("{} has uncorrectable PCIe errors pending: {}", name, uncorrectable.join(", ")),
+                }));
+                if !errors.benign.is_empty() {
+                    checks.push(Check::new(false, false,
+                                           format!("{} has no correctable PCIe error or unsupported request pending ({} flagged)",
+                                                   name, errors.benign.join(", "))));
+                }
+            },
+            None => checks.push(Check::new(false, false,
+                                           format!("{} PCIe device status is readable (needs root)", name))),
+        }
+        if let Some((fatal, nonfatal)) = devices::aer_totals(device) {
+            checks.push(Check::new(fatal + nonfatal == 0, false,
+                                   format!("{} has reported no uncorrectable AER errors since boot ({} fatal, {} non-fatal)", name, fatal, nonfatal)));
+        }
+    }
+    checks
+}
+
+/// Whether the VMM process `pid` can attach tap `tap`. Cloud Hypervisor creates a missing
+/// tap, which needs CAP_NET_ADMIN; an existing tap also opens without it when the VMM owns it.
+pub fn tap_check(pid: u32, tap: &str) -> Check {
+    let pid = pid.to_string();
+    let net_admin = has_capability(&pid, CAP_NET_ADMIN);
+    let sysfs = format!("/sys/class/net/{}", tap);
+    if !Path::new(&sysfs).exists() {
+        return Check::new(net_admin, true, format!("VMM holds CAP_NET_ADMIN to create tap {}", tap));
+    }
+
+    let owner = std::fs::read_to_string(format!("{}/owner", sysfs)).ok()
+        .map(|owner| owner.trim().to_string());
+    let vmm_uid = process_status(&pid, "Uid")
+        .and_then(|uids| uids.split_whitespace().nth(1).map(String::from));
+    let owned = owner.is_some() && owner == vmm_uid;
+    Check::new(net_admin || owned, true,
+               format!("VMM holds CAP_NET_ADMIN or owns tap {}", tap))
+}
+
+/// Descriptions of the failed checks
+pub fn failures(checks: &[Check]) -> Vec<&str> {
+    checks.iter()
+        .filter(|check| check.status == Status::Fail)
+        .map(|check| check.description.as_str())
+        .collect()
+}
+
+/// Check the host and this process for what the VM needs (see `preflight`). Requirements of a
+/// confidential mode fail only when the runtime uses that mode, and those of nested virtualization
+/// only when it is `nested`; they are warnings otherwise.
+pub fn run(backend: BackendKind, confidential: Option<ConfidentialMode>, nested: bool, qgs_socket_path: &str,
+           device_paths: &[String], memory_size: u64) -> Vec<Check> {
+    let mut checks = preflight(backend, device_paths, memory_size);
+
+    let sev_snp = confidential == Some(ConfidentialMode::SevSnp);
+    checks.push(Check::new(
+        ConfidentialMode::SevSnp.compiled_in(),
+        sev_snp,
+        format!("SEV-SNP support built in ({} feature)", ConfidentialMode::SevSnp.feature()),
+    ));
+    for (description, met) in confidential::sev_snp_host_support() {
+        checks.push(Check::new(met, sev_snp, format!("SEV-SNP: {}", description)));
+    }
+
+    let tdx = confidential == Some(ConfidentialMode::Tdx);
+    checks.push(Check::new(
+        ConfidentialMode::Tdx.compiled_in(),
+        tdx,
+        format!("TDX support built in ({} feature)", ConfidentialMode::Tdx.feature()),
+    ));
+    for (description, met) in confidential::tdx_host_support(qgs_socket_path) {
+        checks.push(Check::new(met, tdx, format!("TDX: {}", description)));
+    }
+
+    // The mock backend runs no guest to nest VMs in
+    let resolved = backend.resolve().ok();
+    let nested = nested && resolved != Some(BackendKind::Mock);
+    if nested && resolved == Some(BackendKind::Mshv) {
+        checks.push(Check::new(false, true, "Nested virtualization needs the kvm backend"));
+    }
+    for (description, met) in nested::host_support() {
+        checks.push(Check::new(met, nested, description));
+    }
+
+    checks
+}
+
+/// Print one line per check, returning the number of failures
+pub fn report(checks: &[Check]) -> usize {
+    for check in checks {
+        println!("[{}] {}", check.status.label(), check.description);
+    }
+
+    failures(checks).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_end_the_retries_only_when_they_cannot_clear() {
+        let busy = Check::new(false, true, "VFIO group 12 is held").transient();
+        let missing = Check::new(false, true, "/dev/kvm is missing");
+        let optional = Check::new(false, false, "No IOMMU");
+
+        assert!(failures("Preflight check", &[Check::new(true, true, "KVM"), optional.clone()], false).is_ok());
+
+        let e = failures("Preflight check", &[busy.clone()], false).unwrap_err();
+        assert!(e.downcast_ref::<Permanent>().is_none());
+        assert_eq!(e.to_string(), "1 preflight check(s) failed: VFIO group 12 is held");
+
+        let e = failures("Preflight check", &[busy, missing], false).unwrap_err();
+        assert!(e.downcast_ref::<Permanent>().is_some());
+        assert_eq!(e.to_string(), "2 preflight check(s) failed: VFIO group 12 is held; /dev/kvm is missing");
+    }
+
+    #[test]
+    fn failures_are_errors_on_the_last_try() {
+        assert_eq!(failure_level(false, false), Level::Debug);
+        assert_eq!(failure_level(true, false), Level::Error);
+        assert_eq!(failure_level(false, true), Level::Error);
+    }
+}
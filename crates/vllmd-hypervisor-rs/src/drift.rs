@@ -0,0 +1,163 @@
+use serde_json::Value;
+
+use crate::control::{self, VmDetails};
+use crate::hypervisor::{parse_memory_string, size_label};
+
+/// Settings of the hypervisor process rather than of the VM; a change to them is no reason to
+/// boot the VM again
+pub const HOST_ONLY_FIELDS: &[&str] = &[
+    "log_filepath", "gpu_topology", "textfile_dir", "textfile_interval_secs", "labels", "device_retries", "device_retry_delay_ms",
+];
+
+/// A key of the resolved configuration whose value the running VM does not have
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub key: String,
+    /// What the VM runs with, null for a key it did not have
+    pub running: Value,
+    /// What the configuration file and environment resolve to now, null for a key they dropped
+    pub wanted: Value,
+    /// Command that applies the change to the running VM, None when it takes a restart
+    pub hot: Option<String>,
+}
+
+/// Compare the configuration the VM booted with, `recorded`, against the one resolved now. What the
+/// VMM reports in `live` wins over what was recorded, so a VM resized since boot is compared as it
+/// runs. Both configurations are JSON objects of `HypervisorConfig`.
+pub fn compare(recorded: &Value, wanted: &Value, live: Option<&VmDetails>) -> Vec<Change> {
+    let mut running = recorded.clone();
+    if let (Some(live), Some(fields)) = (live, running.as_object_mut()) {
+        fields.insert("cpu_count".to_string(), Value::from(live.vcpus.boot));
+        let memory_config = fields.get("memory_config").and_then(Value::as_str)
+            .and_then(|memory_config| with_size(memory_config, live.memory.size + live.memory.hotplugged_size));
+        if let Some(memory_config) = memory_config {
+            fields.insert("memory_config".to_string(), Value::from(memory_config));
+        }
+    }
+
+    let empty = serde_json::Map::new();
+    let running = running.as_object().unwrap_or(&empty);
+    let wanted = wanted.as_object().unwrap_or(&empty);
+    let mut keys: Vec<&String> = running.keys().chain(wanted.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let running = running.get(key).cloned().unwrap_or(Value::Null);
+            let wanted = wanted.get(key).cloned().unwrap_or(Value::Null);
+            if running == wanted || (key == "memory_config" && same_memory(&running, &wanted)) {
+                return None;
+            }
+            let hot = hot_command(key, &running, &wanted, live);
+            Some(Change { key: key.clone(), running, wanted, hot })
+        })
+        .collect()
+}
+
+// The VMM can add and remove vCPUs up to the maximum the VM booted with, and resize memory within
+// its hotplug range; everything else the VM or the hypervisor process only reads at boot. Network
+// devices are not part of the configuration, so `add-net` has no change to apply.
+fn hot_command(key: &str, running: &Value, wanted: &Value, live: Option<&VmDetails>) -> Option<String> {
+    match key {
+        "cpu_count" => {
+            let vcpus = wanted.as_u64()?;
+            let max = live?.vcpus.max as u64;
+            (vcpus >= 1 && vcpus <= max).then(|| format!("remote resize --vcpus {}", vcpus))
+        },
+        "memory_config" => {
+            let live = live?;
+            let mut running = parse_memory_string(running.as_str()?).ok()?;
+            let wanted = parse_memory_string(wanted.as_str()?).ok()?;
+            // Only the size can change while the VM runs
+            running.size = wanted.size;
+            (running == wanted && control::check_memory_resize(&live.memory, wanted.size).is_ok())
+                .then(|| format!("remote resize --memory {}", size_label(wanted.size)))
+        },
+        _ => None,
+    }
+}
+
+// The memory configuration with its size set to `bytes`, None when it already has that size
+fn with_size(memory_config: &str, bytes: u64) -> Option<String> {
+    if parse_memory_string(memory_config).ok()?.size == bytes {
+        return None;
+    }
+    let others = memory_config.split(',').filter(|part| !part.trim().starts_with("size="));
+    Some(std::iter::once(format!("size={}", size_label(bytes))).chain(others.map(String::from)).collect::<Vec<_>>().join(","))
+}
+
+// Memory configurations that differ only in how they are written, such as 32G and 32768M
+fn same_memory(running: &Value, wanted: &Value) -> bool {
+    match (running.as_str().map(parse_memory_string), wanted.as_str().map(parse_memory_string)) {
+        (Some(Ok(running)), Some(Ok(wanted))) => running == wanted,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{MemoryDetails, VcpuDetails};
+
+    const GIB: u64 = 1 << 30;
+
+    // A VM booted with 8 of up to 16 vCPUs and 16G of memory with 16G to hotplug
+    fn live(vcpus: u8, hotplugged: u64) -> VmDetails {
+        VmDetails {
+            state: "Running".to_string(),
+            vcpus: VcpuDetails { boot: vcpus, max: 16 },
+            memory: MemoryDetails { size: 16 * GIB, hotplug_size: 16 * GIB, hotplugged_size: hotplugged, actual_size: 16 * GIB + hotplugged },
+            devices: Vec::new(),
+            boot_timing: None,
+        }
+    }
+
+    fn config(cpu_count: u8, memory_config: &str) -> Value {
+        serde_json::json!({ "cpu_count": cpu_count, "memory_config": memory_config, "system_image_filepath": "/images/v1.raw" })
+    }
+
+    fn summary(changes: &[Change]) -> Vec<(String, Option<String>)> {
+        changes.iter().map(|change| (change.key.clone(), change.hot.clone())).collect()
+    }
+
+    #[test]
+    fn live_values_win_over_recorded_ones() {
+        let recorded = config(8, "size=16G,hotplug_size=16G");
+        // Resized to 12 vCPUs and 24G since boot, as the configuration now asks
+        let wanted = config(12, "size=24G,hotplug_size=16G");
+        assert!(compare(&recorded, &wanted, Some(&live(12, 8 * GIB))).is_empty());
+        assert_eq!(summary(&compare(&recorded, &wanted, None)),
+                   [("cpu_count".to_string(), None), ("memory_config".to_string(), None)]);
+
+        let changes = compare(&recorded, &config(8, "size=16G,hotplug_size=16G"), Some(&live(12, 8 * GIB)));
+        assert_eq!(changes[0].running, 12);
+        assert_eq!(changes[1].running, "size=24G,hotplug_size=16G");
+        // The same memory written another way is no change
+        assert!(compare(&recorded, &config(8, "size=16384M,hotplug_size=16G"), None).is_empty());
+    }
+
+    #[test]
+    fn hot_commands_stay_within_what_the_vm_booted_with() {
+        let recorded = config(8, "size=16G,hotplug_size=16G");
+        let live = live(8, 0);
+        let changes = compare(&recorded, &config(12, "size=32G,hotplug_size=16G"), Some(&live));
+        assert_eq!(summary(&changes), [
+            ("cpu_count".to_string(), Some("remote resize --vcpus 12".to_string())),
+            ("memory_config".to_string(), Some("remote resize --memory 32G".to_string())),
+        ]);
+
+        // Past the maximum vCPUs or the hotplug range, or another memory setting, takes a restart
+        let changes = compare(&recorded, &config(17, "size=48G,hotplug_size=16G"), Some(&live));
+        assert_eq!(summary(&changes), [("cpu_count".to_string(), None), ("memory_config".to_string(), None)]);
+        let changes = compare(&recorded, &config(8, "size=16G,hotplug_size=16G,shared=on"), Some(&live));
+        assert_eq!(summary(&changes), [("memory_config".to_string(), None)]);
+
+        let mut wanted = config(8, "size=16G,hotplug_size=16G");
+        wanted["system_image_filepath"] = Value::from("/images/v2.raw");
+        wanted["tpm"] = Value::from(true);
+        let changes = compare(&recorded, &wanted, Some(&live));
+        assert_eq!(summary(&changes), [("system_image_filepath".to_string(), None), ("tpm".to_string(), None)]);
+        assert_eq!(changes[1].running, Value::Null);
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Result, Context};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use crate::control;
+
+/// Warnings and errors kept for the next dump
+const RECENT_ERRORS: usize = 32;
+
+/// How long the VMM may take to answer the info request of a dump
+const INFO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A warning or error logged by this process
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub time: String,
+    pub level: String,
+    pub message: String,
+}
+
+static RECENT: Mutex<VecDeque<LogEntry>> = Mutex::new(VecDeque::new());
+
+/// Remember a log record for the next dump; only warnings and errors are kept
+pub fn note(record: &log::Record) {
+    if record.level() > log::Level::Warn {
+        return;
+    }
+    if let Ok(mut recent) = RECENT.lock() {
+        if recent.len() == RECENT_ERRORS {
+            recent.pop_front();
+        }
+        recent.push_back(LogEntry {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            level: record.level().to_string().to_lowercase(),
+            message: record.args().to_string(),
+        });
+    }
+}
+
+/// The latest warnings and errors, oldest first
+pub fn recent_errors() -> Vec<LogEntry> {
+    RECENT.lock().map(|recent| recent.iter().cloned().collect()).unwrap_or_default()
+}
+
+/// A thread of this process as the kernel sees it
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadInfo {
+    pub tid: u32,
+    pub name: String,
+    /// Scheduler state: R running, S sleeping, D uninterruptible wait, ...
+    pub state: String,
+    /// User plus system time, in clock ticks
+    pub cpu_ticks: u64,
+    /// Kernel function a blocked thread waits in, when the kernel exposes it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wchan: Option<String>,
+}
+
+/// Every thread of this process, so a dump shows which vCPU or device thread is stuck
+pub fn threads() -> Vec<ThreadInfo> {
+    let mut threads = Vec::new();
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else { return threads };
+    for task in tasks.flatten() {
+        let Some(tid) = task.file_name().to_str().and_then(|tid| tid.parse::<u32>().ok()) else { continue };
+        let Ok(stat) = std::fs::read_to_string(task.path().join("stat")) else { continue };
+        // The name is in parentheses and may contain spaces; the state and counters follow it
+        let (Some(open), Some(close)) = (stat.find('('), stat.rfind(')')) else { continue };
+        let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+        let wchan = std::fs::read_to_string(task.path().join("wchan")).ok()
+            .map(|wchan| wchan.trim().to_string())
+            .filter(|wchan| !wchan.is_empty() && wchan != "0");
+        threads.push(ThreadInfo {
+            tid,
+            name: stat[open + 1..close].to_string(),
+            state: fields.first().unwrap_or(&"?").to_string(),
+            cpu_ticks: fields.get(11..13).unwrap_or_default().iter().filter_map(|field| field.parse::<u64>().ok()).sum(),
+            wchan,
+        });
+    }
+    threads.sort_by_key(|thread| thread.tid);
+    threads
+}
+
+/// Devices and sizing the VMM reports, or the reason it did not. A hung VMM may never answer,
+/// so the request runs on its own thread and is abandoned after a short wait.
+pub fn vm_details(api_socket_path: &str) -> serde_json::Value {
+    let (sender, receiver) = channel();
+    let socket = api_socket_path.to_string();
+    std::thread::spawn(move || {
+        let _ = sender.send(control::info(&socket));
+    });
+    match receiver.recv_timeout(INFO_TIMEOUT) {
+        Ok(Ok(details)) => serde_json::to_value(details).unwrap_or_default(),
+        Ok(Err(e)) => serde_json::json!({ "error": format!("{:#}", e) }),
+        Err(_) => serde_json::json!({ "error": format!("VMM did not answer within {}s", INFO_TIMEOUT.as_secs()) }),
+    }
+}
+
+/// Replace the dump at `path`; readers never see a partial file
+pub fn write(path: &str, dump: &serde_json::Value) -> Result<()> {
+    let partial = format!("{}.tmp", path);
+    std::fs::write(&partial, serde_json::to_string_pretty(dump)?)
+        .context(format!("Failed to write diagnostic dump: {}", partial))?;
+    std::fs::rename(&partial, path)
+        .context(format!("Failed to write diagnostic dump: {}", path))
+}
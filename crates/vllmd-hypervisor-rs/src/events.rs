@@ -0,0 +1,201 @@
+use anyhow::{Result, Context};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::webhook::WebhookConfig;
+
+/// Largest event datagram accepted by `subscribe`
+const MAX_EVENT_SIZE: usize = 64 * 1024;
+
+/// Lifecycle events of a runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// The VMM accepted the VM configuration
+    Configured,
+    /// The VM booted
+    Booted,
+    /// The guest workload reported ready
+    Ready,
+    /// The guest was rebooted by the host, or reset by its watchdog
+    Rebooted,
+    /// The VM was paused, for instance to take a snapshot
+    Paused,
+    /// The VM runs again after a pause
+    Resumed,
+    /// The liveness probe failed `threshold` times in a row
+    ProbeFailed,
+    /// The VM failed to boot or to reboot, or its watchdog reset it under `watchdog = "crash"`
+    Crashed,
+    /// The VM was shut down
+    Shutdown,
+}
+
+/// One line of the event stream
+#[derive(Debug, Clone, Serialize)]
+pub struct Event<'a> {
+    /// RFC 3339 UTC timestamp
+    pub time: String,
+    pub event: EventKind,
+    /// Runtime name, None for the unnamed runtime
+    pub runtime: Option<&'a str>,
+    /// PID of the process that emitted the event
+    pub pid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<&'a str>,
+}
+
+/// Publishes events of one runtime as JSON datagrams to every subscriber socket in the host event
+/// socket directory, as JSON lines appended to an optional log file, and as HTTP requests to the
+/// configured webhooks
+#[derive(Debug, Clone)]
+pub struct EventSink {
+    runtime: Option<String>,
+    socket_dir: String,
+    log_path: Option<String>,
+    webhooks: Vec<WebhookConfig>,
+    // Webhook deliveries still in flight, shared between clones
+    deliveries: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl EventSink {
+    pub fn new(runtime: Option<String>, socket_dir: String, log_path: Option<String>, webhooks: Vec<WebhookConfig>) -> Self {
+        Self { runtime, socket_dir, log_path, webhooks, deliveries: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn log_path(&self) -> Option<&str> {
+        self.log_path.as_deref()
+    }
+
+    /// Publish an event. Delivery is best effort: events are dropped when nobody subscribes, and
+    /// failures never affect the VM.
+    pub fn emit(&self, kind: EventKind, detail: Option<&str>) {
+        let event = Event {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            event: kind,
+            runtime: self.runtime.as_deref(),
+            pid: std::process::id(),
+            detail,
+        };
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize {:?} event: {}", kind, e);
+                return;
+            },
+        };
+        debug!("Event: {}", line);
+
+        // Each subscriber binds its own socket in the directory, so every one of them gets the event
+        if let Ok(entries) = std::fs::read_dir(&self.socket_dir) {
+            match UnixDatagram::unbound() {
+                Ok(socket) => {
+                    for path in entries.flatten().map(|entry| entry.path()).filter(|path| is_subscriber_socket(path)) {
+                        if let Err(e) = socket.send_to(line.as_bytes(), &path) {
+                            debug!("No subscriber on event socket {}: {}", path.display(), e);
+                        }
+                    }
+                },
+                Err(e) => warn!("Failed to create a socket to publish events: {}", e),
+            }
+        }
+
+        if let Some(log_path) = &self.log_path {
+            // One write per line, so concurrent runtimes appending to the same file do not interleave
+            let appended = OpenOptions::new().create(true).append(true).open(log_path)
+                .and_then(|mut file| file.write_all(format!("{}\n", line).as_bytes()));
+            if let Err(e) = appended {
+                warn!("Failed to append to event log {}: {}", log_path, e);
+            }
+        }
+
+        // Webhooks are delivered in the background so slow endpoints never delay the VM
+        for webhook in self.webhooks.iter().filter(|webhook| webhook.wants(kind)) {
+            let webhook = webhook.clone();
+            let body = line.clone();
+            let delivery = std::thread::spawn(move || {
+                if let Err(e) = webhook.deliver(&body) {
+                    warn!("{:#}", e);
+                }
+            });
+            if let Ok(mut deliveries) = self.deliveries.lock() {
+                deliveries.retain(|delivery| !delivery.is_finished());
+                deliveries.push(delivery);
+            }
+        }
+    }
+
+    /// Wait for pending webhook deliveries, including their retries, so the last events of a
+    /// runtime are not lost when the process exits
+    pub fn flush(&self) {
+        let pending: Vec<JoinHandle<()>> = match self.deliveries.lock() {
+            Ok(mut deliveries) => deliveries.drain(..).collect(),
+            Err(_) => return,
+        };
+        for delivery in pending {
+            let _ = delivery.join();
+        }
+    }
+}
+
+/// The last `count` events of `runtime` in the event log at `log_path`, oldest first. Lines that
+/// are not events are skipped.
+pub fn recent(log_path: &str, runtime: Option<&str>, count: usize) -> Result<Vec<serde_json::Value>> {
+    let contents = match std::fs::read_to_string(log_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("Failed to read event log: {}", log_path)),
+    };
+    let mut events: Vec<serde_json::Value> = contents.lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|event| event.get("runtime").and_then(|name| name.as_str()) == runtime)
+        .collect();
+    events.drain(..events.len().saturating_sub(count));
+    Ok(events)
+}
+
+/// Bind a socket of this process in the event socket directory and pass each event received to
+/// `handler` until the process is interrupted. Every subscriber gets every event.
+pub fn subscribe(socket_dir: &str, mut handler: impl FnMut(&str)) -> Result<()> {
+    std::fs::create_dir_all(socket_dir)
+        .context(format!("Failed to create event socket directory: {}", socket_dir))?;
+    remove_stale_sockets(socket_dir)?;
+
+    let socket_path = Path::new(socket_dir).join(format!("{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    let socket = UnixDatagram::bind(&socket_path)
+        .context(format!("Failed to bind event socket: {}", socket_path.display()))?;
+
+    let mut buffer = vec![0u8; MAX_EVENT_SIZE];
+    loop {
+        let size = socket.recv(&mut buffer)
+            .context(format!("Failed to receive from event socket: {}", socket_path.display()))?;
+        handler(&String::from_utf8_lossy(&buffer[..size]));
+    }
+}
+
+fn is_subscriber_socket(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "sock")
+}
+
+// Remove the sockets of subscribers that exited without unbinding. A datagram socket someone is
+// bound to refuses stream connections with EPROTOTYPE rather than ECONNREFUSED, so a stale socket
+// is one nobody answers on at all.
+fn remove_stale_sockets(socket_dir: &str) -> Result<()> {
+    let entries = std::fs::read_dir(socket_dir)
+        .context(format!("Failed to read event socket directory: {}", socket_dir))?;
+    for path in entries.flatten().map(|entry| entry.path()).filter(|path| is_subscriber_socket(path)) {
+        if let Err(e) = UnixStream::connect(&path) {
+            if e.kind() == std::io::ErrorKind::ConnectionRefused {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+    Ok(())
+}
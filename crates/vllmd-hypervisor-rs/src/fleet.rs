@@ -0,0 +1,381 @@
+use anyhow::{Result, bail};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::doctor::process_status;
+
+/// How often a launched runtime is checked for its state file or its exit
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A runtime of the configuration file and the `start` process that boots it
+pub struct Launch {
+    pub name: String,
+    pub pid_file: PathBuf,
+    pub command: Command,
+    /// Runtimes that must have started before this one is launched
+    pub depends_on: Vec<String>,
+    /// Count the runtime as started only once its guest is ready, not once its VM runs
+    pub wait_ready: bool,
+    /// Detach the hypervisor, in a process group of its own with its output appended to this
+    /// file, so it outlives the launching process and the terminal it runs in
+    pub detached_log: Option<PathBuf>,
+}
+
+impl Launch {
+    fn runtime_file(&self, extension: &str) -> PathBuf {
+        self.pid_file.with_extension(extension)
+    }
+
+    // PID of a hypervisor already running the runtime
+    fn running_pid(&self) -> Option<u32> {
+        running_pid(&self.pid_file)
+    }
+}
+
+/// PID of the hypervisor that wrote `pid_file`, if it is alive
+pub fn running_pid(pid_file: &Path) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(pid_file).ok()?.trim().parse().ok()?;
+    is_alive(pid).then_some(pid)
+}
+
+/// Whether the process runs; exited processes stay zombies until their parent reaps them
+pub fn is_alive(pid: u32) -> bool {
+    process_status(&pid.to_string(), "State").is_some_and(|state| !state.starts_with('Z'))
+}
+
+/// How the launch of one runtime ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The VM runs, or is ready when readiness was awaited
+    Started(&'static str),
+    /// Another process already runs the runtime; it is left alone
+    AlreadyRunning(u32),
+    /// The hypervisor could not be spawned or exited before the VM ran
+    Failed(String),
+    /// A termination signal arrived first
+    Interrupted,
+}
+
+/// Result of launching one runtime, with the hypervisor process when it was spawned
+pub struct Report {
+    pub name: String,
+    pub outcome: Outcome,
+    pub elapsed: Duration,
+    pub child: Option<Child>,
+}
+
+impl Report {
+    pub fn started(&self) -> bool {
+        matches!(self.outcome, Outcome::Started(_) | Outcome::AlreadyRunning(_))
+    }
+
+    /// One line of the summary `start --all` prints
+    pub fn describe(&self) -> String {
+        let pid = self.child.as_ref().map(|child| child.id()).unwrap_or_default();
+        let elapsed = self.elapsed.as_secs_f64();
+        match &self.outcome {
+            Outcome::Started(state) => format!("{} (PID: {}) after {:.2}s", state, pid, elapsed),
+            Outcome::AlreadyRunning(pid) => format!("already running (PID: {})", pid),
+            Outcome::Failed(reason) => format!("failed after {:.2}s: {}", elapsed, reason),
+            Outcome::Interrupted => "interrupted".to_string(),
+        }
+    }
+}
+
+// Launches still to run, and whether each finished one started
+struct Schedule {
+    pending: Vec<(usize, Launch)>,
+    finished: BTreeMap<String, bool>,
+}
+
+impl Schedule {
+    // The first launch whose dependencies have all finished, with the first of them that did not
+    // start; None while every remaining launch waits for a runtime still booting
+    fn next(&mut self) -> Option<(usize, Launch, Option<String>)> {
+        let position = self.pending.iter()
+            .position(|(_, launch)| launch.depends_on.iter().all(|name| self.finished.contains_key(name)))?;
+        let (index, launch) = self.pending.remove(position);
+        let failed = launch.depends_on.iter().find(|name| !self.finished[*name]).cloned();
+        Some((index, launch, failed))
+    }
+}
+
+/// Boot every runtime, at most `parallelism` at a time and each only after the runtimes it
+/// depends on have started. A runtime counts as started once its state file reads `running`, or
+/// `ready` with `wait_ready`; its hypervisor keeps running after that. A runtime whose dependency
+/// failed is not launched, and no further runtime is launched once `exit` is set. `launches` must
+/// not depend on runtimes outside of it, nor on each other in a cycle.
+pub fn launch(launches: Vec<Launch>, parallelism: usize, exit: &AtomicBool) -> Vec<Report> {
+    let count = launches.len();
+    let schedule = Mutex::new(Schedule {
+        pending: launches.into_iter().enumerate().collect(),
+        finished: BTreeMap::new(),
+    });
+    let reports: Vec<Mutex<Option<Report>>> = (0..count).map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..parallelism.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let next = {
+                    let mut schedule = schedule.lock().unwrap();
+                    if schedule.pending.is_empty() {
+                        break;
+                    }
+                    schedule.next()
+                };
+                let Some((index, launch, failed_dependency)) = next else {
+                    std::thread::sleep(POLL_INTERVAL);
+                    continue;
+                };
+
+                let report = if exit.load(Ordering::SeqCst) {
+                    Report { name: launch.name, outcome: Outcome::Interrupted, elapsed: Duration::ZERO, child: None }
+                } else if let Some(dependency) = failed_dependency {
+                    let outcome = Outcome::Failed(format!("dependency {} did not start", dependency));
+                    Report { name: launch.name, outcome, elapsed: Duration::ZERO, child: None }
+                } else {
+                    launch_one(launch, exit)
+                };
+                info!("Runtime {}: {}", report.name, report.describe());
+                schedule.lock().unwrap().finished.insert(report.name.clone(), report.started());
+                *reports[index].lock().unwrap() = Some(report);
+            });
+        }
+    });
+
+    reports.into_iter().filter_map(|report| report.into_inner().unwrap()).collect()
+}
+
+fn launch_one(mut launch: Launch, exit: &AtomicBool) -> Report {
+    let started_at = Instant::now();
+    let report = |launch: &Launch, outcome, child| Report {
+        name: launch.name.clone(),
+        outcome,
+        elapsed: started_at.elapsed(),
+        child,
+    };
+
+    if let Some(pid) = launch.running_pid() {
+        return report(&launch, Outcome::AlreadyRunning(pid), None);
+    }
+
+    // A state file left by a crashed run would pass for the new one
+    let state_file = launch.runtime_file("state");
+    let _ = std::fs::remove_file(&state_file);
+
+    info!("Starting runtime {}", launch.name);
+    let spawned = match &launch.detached_log {
+        Some(log) => detach(&mut launch.command, log).and_then(|command| command.spawn()),
+        None => launch.command.stderr(Stdio::piped()).spawn(),
+    };
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => return report(&launch, Outcome::Failed(format!("Failed to spawn hypervisor: {}", e)), None),
+    };
+    let relayed = relay(&launch.name, &mut child);
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                // The error main() printed on the way out says more than the exit status
+                let reason = relayed.and_then(|relayed| relayed.join().ok().flatten())
+                    .or_else(|| launch.detached_log.as_deref().and_then(last_error))
+                    .map(|line| line.trim_start_matches("Error: ").to_string())
+                    .unwrap_or_else(|| format!("hypervisor exited with {}", status));
+                return report(&launch, Outcome::Failed(reason), None);
+            },
+            Ok(None) => {},
+            Err(e) => warn!("Failed to check hypervisor of runtime {}: {}", launch.name, e),
+        }
+
+        let state = std::fs::read_to_string(&state_file).unwrap_or_default();
+        match state.trim() {
+            "ready" => return report(&launch, Outcome::Started("ready"), Some(child)),
+            "running" if !launch.wait_ready => return report(&launch, Outcome::Started("running"), Some(child)),
+            _ => {},
+        }
+
+        if exit.load(Ordering::SeqCst) {
+            return report(&launch, Outcome::Interrupted, Some(child));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Point the output of `command` at the end of `log` and give it a process group of its own, out of
+/// reach of the signals the terminal sends to the launching process
+pub fn detach<'a>(command: &'a mut Command, log: &Path) -> std::io::Result<&'a mut Command> {
+    use std::os::unix::process::CommandExt;
+
+    if let Some(parent) = log.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(log)?;
+    Ok(command.stdin(Stdio::null()).stdout(file.try_clone()?).stderr(file).process_group(0))
+}
+
+// The error main() printed on the way out, as the last line of a detached hypervisor's log
+fn last_error(log: &Path) -> Option<String> {
+    let contents = std::fs::read_to_string(log).ok()?;
+    contents.lines().last().filter(|line| line.starts_with("Error: ")).map(str::to_string)
+}
+
+// Copy the log of a hypervisor to stderr with the runtime name in front of each line. The
+// thread ends with the process and returns its last line, for the failure summary.
+fn relay(name: &str, child: &mut Child) -> Option<JoinHandle<Option<String>>> {
+    let stderr = child.stderr.take()?;
+    let name = name.to_string();
+    Some(std::thread::spawn(move || {
+        let mut last_line = None;
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            let _ = writeln!(std::io::stderr().lock(), "{}: {}", name, line);
+            last_line = Some(line);
+        }
+        last_line
+    }))
+}
+
+/// Wait for the hypervisors spawned by `launch` to exit, asking each to shut down with SIGTERM
+/// once `exit` is set. Returns the exit status of each, by runtime name.
+pub fn supervise(reports: &mut [Report], exit: &AtomicBool) -> Vec<(String, ExitStatus)> {
+    let mut statuses = Vec::new();
+    let mut stopping = false;
+    loop {
+        if exit.load(Ordering::SeqCst) && !stopping {
+            stopping = true;
+            for report in reports.iter().filter(|report| report.child.is_some()) {
+                info!("Stopping runtime {}", report.name);
+                terminate(report.child.as_ref().unwrap());
+            }
+        }
+
+        for report in reports.iter_mut() {
+            let Some(child) = report.child.as_mut() else { continue };
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if status.success() {
+                        info!("Runtime {} exited", report.name);
+                    } else {
+                        warn!("Runtime {} exited with {}", report.name, status);
+                    }
+                    statuses.push((report.name.clone(), status));
+                    report.child = None;
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    warn!("Failed to check hypervisor of runtime {}: {}", report.name, e);
+                    report.child = None;
+                },
+            }
+        }
+
+        if reports.iter().all(|report| report.child.is_none()) {
+            return statuses;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// SIGTERM lets the hypervisor shut its VM down cleanly, as `stop` does
+fn terminate(child: &Child) {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    if let Err(e) = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM) {
+        warn!("Failed to send SIGTERM to process {}: {}", child.id(), e);
+    }
+}
+
+/// Wait for the hypervisors of `runtimes` (name and PID) to exit, printing each as it does. The
+/// runtimes still running after `timeout` are named in the error.
+pub fn wait_stopped(runtimes: &[(String, u32)], timeout: Duration) -> Result<()> {
+    let width = runtimes.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let started_at = Instant::now();
+    let mut pending: Vec<&(String, u32)> = runtimes.iter().collect();
+    while !pending.is_empty() {
+        pending.retain(|(name, pid)| {
+            if is_alive(*pid) {
+                return true;
+            }
+            println!("{:<width$}  stopped after {:.2}s", name, started_at.elapsed().as_secs_f64(), width = width);
+            false
+        });
+        if !pending.is_empty() && started_at.elapsed() > timeout {
+            let names: Vec<&str> = pending.iter().map(|(name, _)| name.as_str()).collect();
+            bail!("Runtime(s) still running after {}s: {}", timeout.as_secs(), names.join(", "));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// A running runtime `restart` boots again
+pub struct Restart {
+    pub name: String,
+    pub pid: u32,
+    /// Created empty before the request and removed by the runtime once it is back, or replaced
+    /// with the reason it refused
+    pub restart_file: PathBuf,
+}
+
+/// Restart `runtimes` in batches of at most `max_unavailable`, with `request` asking one of them
+/// to restart. A batch is done once each of its runtimes is back: ready when it can report
+/// readiness, running otherwise. The rollout stops after the first batch in which a runtime did
+/// not come back within `timeout`, so the runtimes not restarted yet keep serving.
+pub fn rolling_restart(runtimes: &[Restart], max_unavailable: usize, timeout: Duration,
+                       request: impl Fn(&Restart) -> Result<()>) -> Result<()> {
+    let width = runtimes.iter().map(|runtime| runtime.name.len()).max().unwrap_or(0);
+    let batches: Vec<&[Restart]> = runtimes.chunks(max_unavailable.max(1)).collect();
+    for (number, batch) in batches.iter().enumerate() {
+        let names: Vec<&str> = batch.iter().map(|runtime| runtime.name.as_str()).collect();
+        info!("Restarting batch {} of {}: {}", number + 1, batches.len(), names.join(", "));
+
+        let started_at = Instant::now();
+        let mut failures = Vec::new();
+        let mut pending = Vec::new();
+        for runtime in batch.iter() {
+            match request(runtime) {
+                Ok(()) => pending.push(runtime),
+                Err(e) => {
+                    println!("{:<width$}  failed: {:#}", runtime.name, e, width = width);
+                    failures.push(format!("{}: {:#}", runtime.name, e));
+                },
+            }
+        }
+
+        while !pending.is_empty() {
+            std::thread::sleep(POLL_INTERVAL);
+            pending.retain(|runtime| {
+                let refused = std::fs::read_to_string(&runtime.restart_file).ok();
+                let failure = if refused.is_none() {
+                    println!("{:<width$}  back after {:.2}s", runtime.name, started_at.elapsed().as_secs_f64(), width = width);
+                    return false;
+                } else if let Some(reason) = refused.filter(|reason| !reason.is_empty()) {
+                    let _ = std::fs::remove_file(&runtime.restart_file);
+                    reason
+                } else if !is_alive(runtime.pid) {
+                    "the hypervisor exited".to_string()
+                } else if started_at.elapsed() > timeout {
+                    format!("not back after {}s", timeout.as_secs())
+                } else {
+                    return true;
+                };
+                println!("{:<width$}  failed: {}", runtime.name, failure, width = width);
+                failures.push(format!("{}: {}", runtime.name, failure));
+                false
+            });
+        }
+
+        if !failures.is_empty() {
+            bail!("Rolling restart stopped after batch {} of {}: {}", number + 1, batches.len(), failures.join("; "));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use crate::agent::{AgentClient, AGENT_PORT};
+use anyhow::{Result, anyhow, bail, Context};
+use log::{info, warn, debug};
+use std::fs::File;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::process::Command;
+use std::thread;
+
+/// Parse a "<host-port>:<guest-port>" specification
+pub fn parse_ports(spec: &str) -> Result<(u16, u16)> {
+    let (host, guest) = spec.split_once(':')
+        .ok_or_else(|| anyhow!("Expected <host-port>:<guest-port>, got {}", spec))?;
+    let parse = |port: &str| port.trim().parse::<u16>()
+        .ok()
+        .filter(|&port| port != 0)
+        .ok_or_else(|| anyhow!("Invalid port '{}' in {}", port, spec));
+    Ok((parse(host)?, parse(guest)?))
+}
+
+/// Accept TCP connections on `address:host_port` and relay each one to `guest_port` on the
+/// guest loopback through the agent. Runs until the process is stopped.
+pub fn proxy(vsock_socket_path: &str, address: &str, host_port: u16, guest_port: u16) -> Result<()> {
+    // Fail early when the agent is not reachable rather than on the first connection
+    AgentClient::connect(vsock_socket_path, AGENT_PORT)?.ping()
+        .context("Guest agent is not answering")?;
+
+    let listener = TcpListener::bind((address, host_port))
+        .context(format!("Failed to listen on {}:{}", address, host_port))?;
+
+    info!("Forwarding {}:{} to guest port {} over vsock", address, host_port, guest_port);
+    println!("Forwarding {}:{} -> guest:{}. Press Ctrl-C to stop.", address, host_port, guest_port);
+
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let vsock_socket_path = vsock_socket_path.to_string();
+        thread::spawn(move || {
+            let peer = client.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            let result = AgentClient::connect(&vsock_socket_path, AGENT_PORT)
+                .and_then(|agent| agent.connect_port(guest_port))
+                .and_then(|guest| Ok(relay(client, guest)?));
+            match result {
+                Ok(_) => debug!("Connection from {} closed", peer),
+                Err(e) => warn!("Forwarding connection from {} failed: {:#}", peer, e),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Copy bytes both ways between two connected sockets until each side has closed its half of the
+/// connection. Used for every relay of the host and the guest agent, whatever the socket family.
+pub fn relay(a: impl Into<OwnedFd>, b: impl Into<OwnedFd>) -> std::io::Result<()> {
+    use nix::sys::socket::{shutdown, Shutdown};
+
+    let a = File::from(a.into());
+    let b = File::from(b.into());
+    let mut a_reader = a.try_clone()?;
+    let mut b_writer = b.try_clone()?;
+    let upstream = thread::spawn(move || {
+        let _ = std::io::copy(&mut a_reader, &mut b_writer);
+        let _ = shutdown(b_writer.as_raw_fd(), Shutdown::Write);
+    });
+
+    let mut b_reader = b;
+    let mut a_writer = a;
+    let _ = std::io::copy(&mut b_reader, &mut a_writer);
+    let _ = shutdown(a_writer.as_raw_fd(), Shutdown::Write);
+
+    let _ = upstream.join();
+    Ok(())
+}
+
+/// Add or remove iptables DNAT rules sending TCP `host_port` to `guest_ip:guest_port`. Rules are
+/// tagged with `comment` so they can be told apart from rules added by other tools.
+pub fn dnat(host_port: u16, guest_ip: &str, guest_port: u16, comment: &str, remove: bool) -> Result<()> {
+    let operation = if remove { "-D" } else { "-A" };
+    let destination = format!("{}:{}", guest_ip, guest_port);
+    let host_port = host_port.to_string();
+    let guest_port = guest_port.to_string();
+
+    // Connections from other machines, connections from the host itself, and the forwarded traffic
+    let rules: [Vec<&str>; 3] = [
+        vec!["-t", "nat", operation, "PREROUTING", "-p", "tcp", "--dport", &host_port,
+             "-j", "DNAT", "--to-destination", &destination],
+        vec!["-t", "nat", operation, "OUTPUT", "-p", "tcp", "-m", "addrtype", "--dst-type", "LOCAL",
+             "--dport", &host_port, "-j", "DNAT", "--to-destination", &destination],
+        vec![operation, "FORWARD", "-p", "tcp", "-d", guest_ip, "--dport", &guest_port, "-j", "ACCEPT"],
+    ];
+
+    for rule in rules {
+        let status = Command::new("iptables")
+            .args(&rule)
+            .args(["-m", "comment", "--comment", comment])
+            .status()
+            .context("Failed to run iptables")?;
+
+        if !status.success() {
+            if remove {
+                // Removing what is already gone should not stop the rest of the cleanup
+                warn!("iptables {} failed with {}", rule.join(" "), status);
+                continue;
+            }
+            bail!("iptables {} failed with {}", rule.join(" "), status);
+        }
+    }
+
+    info!("{} DNAT of port {} to {}", if remove { "Removed" } else { "Added" }, host_port, destination);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn relay_copies_both_ways_until_each_side_closes() {
+        let (mut client, client_end) = UnixStream::pair().unwrap();
+        let (guest_end, mut guest) = UnixStream::pair().unwrap();
+        let relaying = thread::spawn(move || relay(client_end, guest_end));
+
+        client.write_all(b"request").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut request = String::new();
+        guest.read_to_string(&mut request).unwrap();
+        assert_eq!(request, "request");
+
+        guest.write_all(b"response").unwrap();
+        guest.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "response");
+        relaying.join().unwrap().unwrap();
+    }
+}
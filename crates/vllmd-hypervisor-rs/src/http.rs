@@ -0,0 +1,110 @@
+use anyhow::{Result, anyhow, bail, Context};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+use crate::images::ImageStore;
+
+/// Fetch an `https://` (or `http://`) image into the image store and return its blob path.
+///
+/// A cached copy is revalidated with its ETag and reused when the server answers 304 or
+/// cannot be reached. Interrupted downloads resume from where they stopped.
+pub fn fetch(store: &ImageStore, url: &str) -> Result<PathBuf> {
+    let cached = store.lookup_ref(url)?;
+
+    // Without a validator there is nothing to revalidate against, so the cached copy is kept
+    if let Some((path, entry)) = &cached {
+        if entry.etag.is_none() {
+            return Ok(path.clone());
+        }
+    }
+
+    let agent = ureq::AgentBuilder::new().build();
+
+    // Partial downloads are keyed by URL and remember the ETag they were started with
+    let key = format!("{:x}", Sha256::digest(url.as_bytes()));
+    let download = store.download_path(&format!("{}.partial", key));
+    let download_etag = store.download_path(&format!("{}.etag", key));
+
+    for _ in 0..2 {
+        let partial_etag = std::fs::read_to_string(&download_etag).ok();
+        let offset = match (&partial_etag, std::fs::metadata(&download)) {
+            (Some(_), Ok(metadata)) => metadata.len(),
+            _ => 0,
+        };
+
+        let mut request = agent.get(url);
+        if let Some((_, entry)) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.set("If-None-Match", etag);
+            }
+        }
+        if let (Some(etag), true) = (&partial_etag, offset > 0) {
+            request = request
+                .set("Range", &format!("bytes={}-", offset))
+                .set("If-Range", etag);
+        }
+
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(416, _)) => {
+                // The partial file no longer matches the remote size; start over
+                let _ = std::fs::remove_file(&download);
+                let _ = std::fs::remove_file(&download_etag);
+                continue;
+            },
+            Err(e) => match &cached {
+                Some((path, _)) => {
+                    warn!("Failed to revalidate {}, using cached copy: {}", url, e);
+                    return Ok(path.clone());
+                },
+                None => return Err(anyhow!("Failed to fetch {}: {}", url, e)),
+            },
+        };
+
+        let append = match response.status() {
+            304 => {
+                if let Some((path, _)) = cached {
+                    return Ok(path);
+                }
+                bail!("Server returned 304 for {} without a cached copy", url);
+            },
+            206 => true,
+            200 => false,
+            status => bail!("Unexpected status {} fetching {}", status, url),
+        };
+
+        let etag = response.header("etag").map(String::from);
+
+        if append {
+            info!("Resuming download of {} at byte {}", url, offset);
+        } else {
+            info!("Downloading {}", url);
+            match &etag {
+                Some(etag) => std::fs::write(&download_etag, etag)
+                    .context(format!("Failed to write {}", download_etag.display()))?,
+                None => { let _ = std::fs::remove_file(&download_etag); },
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&download)
+            .context(format!("Failed to open {}", download.display()))?;
+
+        std::io::copy(&mut response.into_reader(), &mut file)
+            .context(format!("Failed to download {}", url))?;
+
+        let _ = std::fs::remove_file(&download_etag);
+        let digest = store.commit(&download, None)?;
+        store.tag(url, &digest, etag.as_deref())?;
+
+        return store.blob_path(&digest);
+    }
+
+    bail!("Failed to fetch {}: server rejected the resume request", url)
+}
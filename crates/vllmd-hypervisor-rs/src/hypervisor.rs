@@ -1,20 +1,38 @@
 use anyhow::{Result, anyhow, Context};
-use log::{info, warn, error};
-use std::path::Path;
+use log::{info, warn, error, debug};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // Cloud Hypervisor crates
 use hypervisor as ch_hypervisor;
 use hypervisor::Hypervisor as ChHypervisor;
-use vmm::api::{ApiRequest, VmCreate, VmBoot, VmShutdown, VmInfo, ApiAction};
+use vmm::api::{ApiRequest, VmCreate, VmBoot, VmShutdown, VmReboot, VmInfo, VmPause, VmResume, VmSnapshot, VmSnapshotConfig, VmRestore, ApiAction};
+use vmm::config::RestoreConfig;
 use vmm::config::VmParams;
 use vmm::vm_config::VmConfig as ChVmConfig;
 use vmm::VmmVersionInfo;
 use vmm::VmmThreadHandle;
 use seccompiler::SeccompAction;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::agent;
+use crate::tpm::{Swtpm, SwtpmSlot};
+use crate::confidential::ConfidentialMode;
+use crate::sched::VcpuSched;
+use crate::privilege::RunAs;
+use crate::backend::{self, BackendKind};
+use crate::control::VmDetails;
+use std::os::unix::net::UnixListener;
+
+/// Device the VMM maps SGX EPC sections from
+const SGX_VEPC_PATH: &str = "/dev/sgx_vepc";
 
 /// Error type for hypervisor operations
 #[derive(Error, Debug)]
@@ -42,6 +60,9 @@ pub enum HypervisorError {
     
     #[error("API communication error: {0}")]
     ApiError(String),
+    
+    #[error("The VM did not boot within {0}s")]
+    BootTimeout(u64),
 }
 
 /// Configuration for a virtual machine
@@ -50,8 +71,8 @@ pub struct VmConfig {
     /// UUID of the VM
     pub id: String,
     
-    /// Path to kernel
-    pub kernel_path: String,
+    /// Path to kernel; without one the firmware boots the system image's own bootloader
+    pub kernel_path: Option<String>,
     
     /// Kernel command line
     pub cmdline: String,
@@ -62,30 +83,250 @@ pub struct VmConfig {
     /// Path to config image
     pub config_image_path: String,
     
-    /// Number of vCPUs
+    /// Number of vCPUs online at boot
     pub vcpu_count: u8,
     
+    /// Number of vCPUs the guest can be resized to without a reboot, at least `vcpu_count`
+    pub max_vcpu_count: u8,
+    
+    /// What the vCPUs look like to the guest beyond their number
+    pub cpu_config: CpuConfig,
+    
     /// Memory configuration
     pub memory_config: MemoryConfig,
     
+    /// Zones guest memory is made of instead of a single region, with the guest NUMA nodes they form
+    pub memory_zones: Vec<MemoryZoneConfig>,
+    
     /// Devices to passthrough
     pub device_paths: Vec<String>,
     
+    /// Additional disks (model caches, scratch space)
+    pub disks: Vec<DiskConfig>,
+    
+    /// Rate limit groups shared by several disks
+    pub rate_limit_groups: Vec<RateLimitGroupConfig>,
+    
+    /// Rate limit group the system and config images join
+    pub image_rate_limit_group: Option<String>,
+    
     /// Debug mode
     pub debug: bool,
+    
+    /// Path of the API socket used by out-of-process commands
+    pub api_socket_path: Option<String>,
+    
+    /// When set, the system image is a read-only base and guest writes go to a qcow2 overlay at this path
+    pub ephemeral_overlay_path: Option<String>,
+    
+    /// Let the guest report free pages through a balloon device so the host can reclaim them
+    pub free_page_reporting: bool,
+    
+    /// Host file the virtio-rng device reads entropy from
+    pub rng_source: String,
+    
+    /// Add a balloon device for a policy to resize at runtime
+    pub balloon: bool,
+    
+    /// Unix socket the guest serial console is connected to
+    pub serial_socket_path: Option<String>,
+    
+    /// File the guest virtio console is written to, used as the guest log channel
+    pub console_log_path: Option<String>,
+    
+    /// Host/guest vsock channel
+    pub vsock: Option<VsockConfig>,
+    
+    /// Software TPM started alongside the VM
+    pub tpm: Option<TpmConfig>,
+    
+    /// Confidential computing mode; for SEV-SNP `kernel_path` is an IGVM image
+    pub confidential: Option<ConfidentialMode>,
+    
+    /// UEFI firmware (CLOUDHV.fd, rust-hypervisor-firmware, or TDVF for TDX guests)
+    pub firmware_path: Option<String>,
+    
+    /// Initramfs loaded together with the kernel
+    pub initramfs_path: Option<String>,
+    
+    /// 64 hex digits of host data included in SEV-SNP attestation reports
+    pub host_data: Option<String>,
+    
+    /// What the VMM's seccomp filters do with syscalls outside their allow lists
+    pub seccomp: SeccompAction,
+    
+    /// Confine the VMM with Landlock to the paths this configuration uses
+    pub landlock: bool,
+    
+    /// Further paths the VMM may use, such as the log file, as `(path, access)` with access `r` or `rw`
+    pub landlock_paths: Vec<(String, &'static str)>,
+    
+    /// Scheduling policy of the vCPU threads, applied once they exist
+    pub vcpu_sched: Option<VcpuSched>,
+    
+    /// User the process switches to before the VMM starts, after opening /dev/kvm
+    pub run_as: Option<RunAs>,
+    
+    /// How long `start` waits for the VMM to create and boot the VM, or to restore it, before it
+    /// fails with `BootTimeout`
+    pub boot_timeout: Option<Duration>,
+    
+    /// SGX Enclave Page Cache sections of the guest (x86_64 hosts with SGX only)
+    pub sgx_epc: Vec<SgxEpcConfig>,
+    
+    /// Give the guest a watchdog device that resets the VM when the guest stops petting it
+    pub watchdog: bool,
+    
+    /// SMBIOS OEM strings the guest reads its identity from, such as "vllmd:name=llama"
+    pub oem_strings: Vec<String>,
+    
+    /// Snapshot directory to restore the running VM of a previous hypervisor process from instead
+    /// of booting it; the ephemeral overlay is then the one that VM already uses
+    pub restore_path: Option<String>,
+    
+    /// Host virtualization interface to run the guest on
+    pub backend: BackendKind,
+}
+
+/// virtio-vsock device: the guest reaches the host through the unix socket at `socket_path`
+#[derive(Debug, Clone)]
+pub struct VsockConfig {
+    /// Guest context identifier (3 or above)
+    pub cid: u32,
+    
+    /// Host unix socket Cloud Hypervisor listens on
+    pub socket_path: String,
+}
+
+/// vTPM backed by swtpm, which keeps its state in `state_dir` across restarts
+#[derive(Debug, Clone)]
+pub struct TpmConfig {
+    /// Control socket swtpm listens on and Cloud Hypervisor connects to
+    pub socket_path: String,
+    
+    /// Directory holding the persistent TPM state
+    pub state_dir: String,
 }
 
 /// State of a virtual machine
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum VmState {
+    #[default]
     Created,
     Configured,
     Running,
+    Ready,
     Paused,
     Shutdown,
     Error,
 }
 
+/// How a VM ended without the host stopping it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmExit {
+    /// The guest powered itself off
+    Poweroff,
+    /// The VMM stopped with an error
+    VmmError(String),
+}
+
+/// Time spent in each phase of bringing up a VM, in milliseconds; None for phases not reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootTimings {
+    /// Overlay, swtpm and hypervisor setup, up to a running VMM thread
+    pub vmm_init_ms: Option<u64>,
+    /// VM creation: guest memory allocation and prefault, devices and VFIO DMA mapping
+    pub vm_create_ms: Option<u64>,
+    /// Kernel or firmware load and vCPU start
+    pub vm_boot_ms: Option<u64>,
+    /// From boot until the guest workload first reported ready
+    pub guest_ready_ms: Option<u64>,
+}
+
+impl BootTimings {
+    /// One line such as `vmm init 0.12s, vm create 0.84s, vm boot 0.05s, total 1.01s`
+    pub fn summary(&self) -> String {
+        let phases = [
+            ("vmm init", self.vmm_init_ms),
+            ("vm create", self.vm_create_ms),
+            ("vm boot", self.vm_boot_ms),
+            ("guest ready", self.guest_ready_ms),
+        ];
+        let seconds = |ms: u64| format!("{:.2}s", ms as f64 / 1000.0);
+        let mut parts: Vec<String> = phases.iter()
+            .filter_map(|(name, ms)| ms.map(|ms| format!("{} {}", name, seconds(ms))))
+            .collect();
+        parts.push(format!("total {}", seconds(phases.iter().filter_map(|(_, ms)| *ms).sum())));
+        parts.join(", ")
+    }
+}
+
+// Milliseconds since `since`
+pub fn elapsed_ms(since: Instant) -> u64 {
+    since.elapsed().as_millis() as u64
+}
+
+/// VM lifecycle the `start` command drives. `HypervisorManager` runs a real guest; the mock
+/// backend only walks the same state transitions, for tests and CI without /dev/kvm.
+pub trait HypervisorBackend {
+    /// Validate and store the configuration (Created to Configured)
+    fn configure(&mut self, config: VmConfig) -> Result<()>;
+    
+    /// Create and boot the VM (Configured to Running)
+    fn start(&mut self) -> Result<()>;
+    
+    /// Stop the VM and release what it holds; also cleans up after a failed start
+    fn shutdown(&mut self) -> Result<()>;
+    
+    /// Reboot the guest; it is no longer ready until it reports ready again
+    fn reboot(&mut self) -> Result<()>;
+    
+    /// Record that the guest reset without the host asking, as the watchdog does; like after a
+    /// reboot it is no longer ready
+    fn guest_reset(&mut self);
+    
+    /// Record that vCPUs were hotplugged or unplugged, so settings of the vCPU threads apply to
+    /// the new ones
+    fn vcpus_resized(&mut self);
+    
+    /// How the VM ended, once it has ended without the host stopping it (Running to Shutdown);
+    /// cheap to call on every loop iteration
+    fn exited(&mut self) -> Option<VmExit>;
+    
+    /// Pause the VM and snapshot it into `directory` for a new hypervisor process to restore
+    /// (Running to Paused); on failure the VM runs on
+    fn suspend(&mut self, directory: &str) -> Result<()>;
+    
+    /// Resume a VM `suspend` paused when no new process takes it over (Paused to Running, or to
+    /// Ready when the workload was ready before)
+    fn resume_suspended(&mut self, ready: bool) -> Result<()>;
+    
+    /// Record that the guest workload is ready (Running to Ready)
+    fn mark_ready(&mut self);
+    
+    /// Receiver that fires once the guest has signalled it booted
+    fn ready_signal(&self) -> Result<Receiver<()>>;
+    
+    /// Relay TDX guest connections to the Quote Generation Service at `qgs_socket_path`
+    fn quote_relay(&self, qgs_socket_path: &str) -> Result<()>;
+    
+    /// Get the current state of the VM
+    fn state(&self) -> VmState;
+    
+    /// Duration of the phases of the last start; `guest_ready_ms` is measured by the caller
+    fn boot_timings(&self) -> BootTimings;
+    
+    /// Check if the guest workload has been reported ready
+    fn is_ready(&self) -> bool {
+        self.state() == VmState::Ready
+    }
+    
+    /// Check if the VM is running
+    fn is_running(&self) -> bool {
+        matches!(self.state(), VmState::Running | VmState::Ready)
+    }
+}
+
 /// Struct representing the hypervisor manager
 pub struct HypervisorManager {
     /// VM state
@@ -103,6 +344,9 @@ pub struct HypervisorManager {
     /// API sender
     api_sender: Sender<ApiRequest>,
     
+    /// API receiver, handed to the VMM thread on start
+    api_receiver: Option<Receiver<ApiRequest>>,
+    
     /// VMM thread handle
     vmm_thread_handle: Option<VmmThreadHandle>,
     
@@ -114,6 +358,18 @@ pub struct HypervisorManager {
     
     /// Whether the VM was successfully booted
     vm_booted: bool,
+    
+    /// swtpm process backing the vTPM, stopped when dropped
+    swtpm: SwtpmSlot,
+    
+    /// The VM was snapshotted for another process to restore, which takes over its overlay
+    suspended: bool,
+    
+    /// The VM runs from the snapshot of another process and owns the overlay it handed over
+    restored: bool,
+    
+    /// Phases of the last start
+    timings: BootTimings,
 }
 
 impl HypervisorManager {
@@ -127,7 +383,7 @@ impl HypervisorManager {
             .map_err(|e| anyhow!("Failed to create exit EventFd: {}", e))?;
         
         // Create channel for API requests
-        let (api_sender, _) = channel();
+        let (api_sender, api_receiver) = channel();
         
         Ok(Self {
             state: VmState::Created,
@@ -135,10 +391,15 @@ impl HypervisorManager {
             exit_evt,
             api_evt,
             api_sender,
+            api_receiver: Some(api_receiver),
             vmm_thread_handle: None,
             hypervisor: None,
             vm_created: false,
             vm_booted: false,
+            timings: BootTimings::default(),
+            swtpm: SwtpmSlot::default(),
+            suspended: false,
+            restored: false,
         })
     }
     
@@ -152,7 +413,7 @@ impl HypervisorManager {
         }
         
         // Validate configuration
-        self.validate_config(&config)?;
+        Self::validate_config(&config)?;
         
         // Store configuration
         self.config = Some(config);
@@ -162,15 +423,59 @@ impl HypervisorManager {
         Ok(())
     }
     
-    /// Validate VM configuration
-    fn validate_config(&self, config: &VmConfig) -> Result<()> {
+    /// Validate VM configuration; shared with the mock backend so tests see the same errors
+    pub(crate) fn validate_config(config: &VmConfig) -> Result<()> {
         // Validate kernel path
-        if !Path::new(&config.kernel_path).exists() {
+        match &config.kernel_path {
+            Some(kernel_path) if !Path::new(kernel_path).exists() => {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Kernel path does not exist: {}", kernel_path)
+                )));
+            },
+            Some(_) => {},
+            None if config.firmware_path.is_none() => {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    "A kernel or firmware is required to boot".to_string()
+                )));
+            },
+            None => {
+                if !config.cmdline.is_empty() {
+                    warn!("Kernel command line is ignored when booting through firmware without a kernel");
+                }
+            },
+        }
+        
+        // Validate initramfs path
+        if let Some(initramfs_path) = &config.initramfs_path {
+            if config.kernel_path.is_none() {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    "An initramfs requires a kernel".to_string()
+                )));
+            }
+            if !Path::new(initramfs_path).exists() {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Initramfs path does not exist: {}", initramfs_path)
+                )));
+            }
+        }
+        
+        if config.vcpu_count == 0 || config.max_vcpu_count < config.vcpu_count {
             return Err(anyhow!(HypervisorError::ConfigError(
-                format!("Kernel path does not exist: {}", config.kernel_path)
+                format!("The VM boots with {} vCPUs, more than its maximum of {}", config.vcpu_count, config.max_vcpu_count)
             )));
         }
         
+        // The VMM lays the vCPUs out in the topology, which must account for each one the VM can have
+        if let Some(topology) = config.cpu_config.topology {
+            let vcpus: u32 = topology.iter().map(|count| *count as u32).product();
+            if vcpus != config.max_vcpu_count as u32 {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("CPU topology {}:{}:{}:{} makes {} vCPUs, the VM has {}",
+                            topology[0], topology[1], topology[2], topology[3], vcpus, config.max_vcpu_count)
+                )));
+            }
+        }
+        
         // Validate system image path
         if !Path::new(&config.system_image_path).exists() {
             return Err(anyhow!(HypervisorError::ConfigError(
@@ -194,33 +499,289 @@ impl HypervisorManager {
             }
         }
         
+        // Passthrough devices pin all guest memory, so reported pages cannot be returned
+        if config.free_page_reporting && !config.device_paths.is_empty() {
+            warn!("Free page reporting has no effect while devices are passed through");
+        }
+        if config.balloon && !config.device_paths.is_empty() {
+            warn!("A balloon policy cannot reclaim memory while devices are passed through");
+        }
+        
+        Self::validate_rng(&config.rng_source)?;
+        
+        // CIDs 0-2 are reserved for the hypervisor, local loopback and the host
+        if let Some(vsock) = &config.vsock {
+            if vsock.cid < 3 || vsock.cid == u32::MAX {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Invalid vsock CID {}: must be between 3 and {}", vsock.cid, u32::MAX - 1)
+                )));
+            }
+        }
+        
+        if let Some(mode) = config.confidential {
+            Self::validate_confidential(config, mode)?;
+        }
+        
+        // Validate additional disks
+        let mut disk_ids = vec!["system".to_string(), "config".to_string()];
+        for (index, disk) in config.disks.iter().enumerate() {
+            if !Path::new(&disk.path).exists() {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Disk path does not exist: {}", disk.path)
+                )));
+            }
+            
+            let disk_id = disk.id_or_default(index);
+            if disk_ids.contains(&disk_id) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Duplicate disk id: {}", disk_id)
+                )));
+            }
+            disk_ids.push(disk_id);
+            
+            if let Some(format) = disk.format {
+                let detected = detect_disk_format(&disk.path)?;
+                if detected != format {
+                    return Err(anyhow!(HypervisorError::ConfigError(
+                        format!("Disk {} is configured as {:?} but the image is {:?}", disk.path, format, detected)
+                    )));
+                }
+            }
+            
+            if let (Some(direct), Some(cache)) = (disk.direct, disk.cache) {
+                if direct != (cache == DiskCache::None) {
+                    return Err(anyhow!(HypervisorError::ConfigError(
+                        format!("Disk {} has conflicting direct and cache values", disk.path)
+                    )));
+                }
+            }
+            
+            if disk.aio == Some(DiskIoEngine::IoUring) && !cfg!(feature = "io_uring") {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Disk {} requests aio=io_uring but io_uring support is not compiled in", disk.path)
+                )));
+            }
+            
+            if disk.num_queues == Some(0) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Disk {} must have at least one queue", disk.path)
+                )));
+            }
+            
+            if let Some(queue_size) = disk.queue_size {
+                if !queue_size.is_power_of_two() {
+                    return Err(anyhow!(HypervisorError::ConfigError(
+                        format!("Disk {} has queue_size {}, which is not a power of two", disk.path, queue_size)
+                    )));
+                }
+            }
+            
+            if let Some(group) = &disk.rate_limit_group {
+                if disk.bandwidth.is_some() || disk.iops.is_some() {
+                    return Err(anyhow!(HypervisorError::ConfigError(
+                        format!("Disk {} cannot combine its own rate limits with rate_limit_group {}", disk.path, group)
+                    )));
+                }
+                
+                if !config.rate_limit_groups.iter().any(|g| &g.id == group) {
+                    return Err(anyhow!(HypervisorError::ConfigError(
+                        format!("Disk {} references undefined rate limit group: {}", disk.path, group)
+                    )));
+                }
+            }
+            
+            rate_limit_options(&disk.bandwidth, disk.iops)?;
+        }
+        
+        if let Some(group) = &config.image_rate_limit_group {
+            if !config.rate_limit_groups.iter().any(|g| &g.id == group) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("The system and config images reference undefined rate limit group: {}", group)
+                )));
+            }
+        }
+        
+        Self::validate_memory_zones(config)?;
+        Self::validate_sgx_epc(config)?;
+        
+        // Fail now rather than when the VMM cannot map guest memory
+        if config.memory_config.hugepages && config.memory_zones.is_empty() {
+            check_hugepage_pool(config.memory_config.hugepage_size, config.memory_config.size)?;
+        }
+        
+        // Validate rate limit groups
+        for (index, group) in config.rate_limit_groups.iter().enumerate() {
+            if config.rate_limit_groups[..index].iter().any(|g| g.id == group.id) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Duplicate rate limit group id: {}", group.id)
+                )));
+            }
+            
+            if group.bandwidth.is_none() && group.iops.is_none() {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Rate limit group {} sets neither bandwidth nor iops", group.id)
+                )));
+            }
+            
+            rate_limit_options(&group.bandwidth, group.iops)?;
+        }
+        
         Ok(())
     }
     
     /// Convert our VmConfig to Cloud Hypervisor's VmParams
-    fn create_vm_params(&self) -> Result<VmParams<'static>> {
-        // Get config
-        let config = self.config.as_ref()
-            .ok_or_else(|| anyhow!(HypervisorError::InvalidState("VM not configured".to_string())))?;
-        
+    fn create_vm_params(config: &VmConfig) -> Result<VmParams<'static>> {
         // Create string arguments for Cloud Hypervisor
-        let cpus = format!("boot={},max={}", config.vcpu_count, config.vcpu_count);
+        let mut cpus = format!("boot={},max={}", config.vcpu_count, config.max_vcpu_count);
+        if let Some([threads, cores, dies, packages]) = config.cpu_config.topology {
+            cpus.push_str(&format!(",topology={}:{}:{}:{}", threads, cores, dies, packages));
+        }
+        if let Some(bits) = config.cpu_config.max_phys_bits {
+            cpus.push_str(&format!(",max_phys_bits={}", bits));
+        }
+        if config.cpu_config.kvm_hyperv {
+            cpus.push_str(",kvm_hyperv=on");
+        }
+        if config.cpu_config.amx {
+            cpus.push_str(",features=amx");
+        }
+        
+        // Create memory configuration; with zones the zones provide all of guest memory
+        let memory = if !config.memory_zones.is_empty() {
+            "size=0".to_string()
+        } else {
+            let mut memory = format!("size={}M", config.memory_config.size / (1024 * 1024));
+            if config.memory_config.shared {
+                memory.push_str(",shared=on");
+            }
+            if config.memory_config.hugepages {
+                memory.push_str(",hugepages=on");
+            }
+            if let Some(page_size) = config.memory_config.hugepage_size {
+                memory.push_str(&format!(",hugepage_size={}", size_label(page_size)));
+            }
+            if let Some(hotplug_size) = config.memory_config.hotplug_size {
+                memory.push_str(&format!(",hotplug_size={}M", hotplug_size / (1024 * 1024)));
+                if config.memory_config.hotplug_method == HotplugMethod::VirtioMem {
+                    memory.push_str(",hotplug_method=virtio-mem");
+                }
+            }
+            if !config.memory_config.hugepages && config.memory_config.thp != ThpMode::On {
+                memory.push_str(",thp=off");
+            }
+            memory
+        };
+        
+        #[cfg(target_arch = "x86_64")]
+        let sgx_epc_option: Option<Vec<&'static str>> = if !config.sgx_epc.is_empty() {
+            let mut leaked_sections: Vec<&'static str> = Vec::new();
+            for section in &config.sgx_epc {
+                let size = parse_size(section.size.trim())
+                    .context(format!("Invalid size of SGX EPC section {}", section.id))?;
+                let section_arg = format!("id={},size={}K,prefault={}", section.id, size / 1024,
+                                          if section.prefault { "on" } else { "off" });
+                leaked_sections.push(Box::leak(section_arg.into_boxed_str()));
+            }
+            Some(leaked_sections)
+        } else {
+            None
+        };
         
-        // Create memory configuration
-        let memory = if config.memory_config.shared {
-            format!("size={}M,shared=on", config.memory_config.size / (1024 * 1024))
+        let memory_zones_option: Option<Vec<&'static str>> = if !config.memory_zones.is_empty() {
+            let mut leaked_zones: Vec<&'static str> = Vec::new();
+            for zone in &config.memory_zones {
+                let size = parse_size(zone.size.trim()).context(format!("Invalid size of memory zone {}", zone.id))?;
+                let mut zone_arg = format!("id={},size={}M", zone.id, size / (1024 * 1024));
+                if let Some(node) = zone.host_numa_node {
+                    zone_arg.push_str(&format!(",host_numa_node={}", node));
+                }
+                if zone.hugepages {
+                    zone_arg.push_str(",hugepages=on");
+                }
+                if let Some(file) = &zone.file {
+                    zone_arg.push_str(&format!(",file={}", file));
+                }
+                if zone.shared.unwrap_or(config.memory_config.shared) {
+                    zone_arg.push_str(",shared=on");
+                }
+                leaked_zones.push(Box::leak(zone_arg.into_boxed_str()));
+            }
+            Some(leaked_zones)
         } else {
-            format!("size={}M", config.memory_config.size / (1024 * 1024))
+            None
         };
         
+        // Guest NUMA nodes gather the zones, and the vCPUs, that name them
+        let mut numa_nodes: BTreeMap<u32, (Vec<String>, Vec<String>)> = BTreeMap::new();
+        for zone in &config.memory_zones {
+            if let Some(node) = zone.guest_numa_node {
+                let (zones, cpus) = numa_nodes.entry(node).or_default();
+                zones.push(zone.id.clone());
+                cpus.extend(zone.guest_cpus.iter().map(|cpus| cpus.trim().to_string()));
+            }
+        }
+        let numa_option: Option<Vec<&'static str>> = (!numa_nodes.is_empty()).then(|| {
+            numa_nodes.into_iter()
+                .map(|(node, (zones, cpus))| {
+                    let mut numa_arg = format!("guest_numa_id={},memory_zones=[{}]", node, zones.join(","));
+                    if !cpus.is_empty() {
+                        numa_arg.push_str(&format!(",cpus=[{}]", cpus.join(",")));
+                    }
+                    &*Box::leak(numa_arg.into_boxed_str())
+                })
+                .collect()
+        });
+        
         // Kernel and cmdline
         let kernel = config.kernel_path.clone();
-        let cmdline = config.cmdline.clone();
+        // The command line belongs to the kernel; firmware boots take theirs from the bootloader
+        let cmdline = if kernel.is_some() { config.cmdline.clone() } else { String::new() };
+        
+        // Ephemeral runtimes boot from their overlay instead of the base image
+        let system_disk_path = config.ephemeral_overlay_path.as_ref()
+            .unwrap_or(&config.system_image_path);
         
         // Create disk arguments
+        let image_group = config.image_rate_limit_group.as_ref()
+            .map(|group| format!(",rate_limit_group={}", group))
+            .unwrap_or_default();
+        // A queue per vCPU lets each vCPU submit IO without contending for a shared ring
         let mut disks = Vec::new();
-        disks.push(format!("path={},id=system", config.system_image_path));
-        disks.push(format!("path={},readonly=on,id=config", config.config_image_path));
+        disks.push(format!("path={},num_queues={}{},id=system", system_disk_path, config.vcpu_count, image_group));
+        disks.push(format!("path={},readonly=on{},id=config", config.config_image_path, image_group));
+        
+        // Additional disks follow the system and config images
+        for (index, disk) in config.disks.iter().enumerate() {
+            let mut disk_arg = format!("path={}", disk.path);
+            if disk.readonly {
+                disk_arg.push_str(",readonly=on");
+            }
+            if disk.is_direct() {
+                disk_arg.push_str(",direct=on");
+            }
+            disk_arg.push_str(&format!(",num_queues={}", disk.num_queues.unwrap_or(config.vcpu_count as usize)));
+            if let Some(queue_size) = disk.queue_size {
+                disk_arg.push_str(&format!(",queue_size={}", queue_size));
+            }
+            disk_arg.push_str(&rate_limit_options(&disk.bandwidth, disk.iops)?);
+            if let Some(group) = &disk.rate_limit_group {
+                disk_arg.push_str(&format!(",rate_limit_group={}", group));
+            }
+            disk_arg.push_str(&format!(",id={}", disk.id_or_default(index)));
+            disks.push(disk_arg);
+        }
+        
+        // Create rate limit group arguments
+        let rate_limit_groups_option: Option<Vec<&'static str>> = if !config.rate_limit_groups.is_empty() {
+            let mut leaked_groups: Vec<&'static str> = Vec::new();
+            for group in &config.rate_limit_groups {
+                let group_arg = format!("id={}{}", group.id, rate_limit_options(&group.bandwidth, group.iops)?);
+                leaked_groups.push(Box::leak(group_arg.into_boxed_str()));
+            }
+            Some(leaked_groups)
+        } else {
+            None
+        };
         
         // Convert disks to Vec<&'static str>
         let disks_option: Option<Vec<&'static str>> = if !disks.is_empty() {
@@ -260,7 +821,34 @@ impl HypervisorManager {
         // Leak strings for static lifetime
         let cpus_static: &'static str = Box::leak(cpus.into_boxed_str());
         let memory_static: &'static str = Box::leak(memory.into_boxed_str());
-        let kernel_static = Some(Box::leak(kernel.into_boxed_str()) as &'static str);
+        // SEV-SNP guests boot from an IGVM image that carries the firmware and kernel
+        let sev_snp = config.confidential == Some(ConfidentialMode::SevSnp);
+        let kernel_static = kernel.map(|kernel| &*Box::leak(kernel.into_boxed_str()));
+        // The SMBIOS system UUID is the VM id, next to the OEM strings
+        let mut platform = Vec::new();
+        match config.confidential {
+            Some(ConfidentialMode::SevSnp) => platform.push("sev_snp=on".to_string()),
+            Some(ConfidentialMode::Tdx) => platform.push("tdx=on".to_string()),
+            None => {},
+        }
+        if uuid::Uuid::parse_str(&config.id).is_ok() {
+            platform.push(format!("uuid={}", config.id));
+        }
+        if !config.oem_strings.is_empty() {
+            platform.push(format!("oem_strings=[{}]", config.oem_strings.join(",")));
+        }
+        let platform_static = if platform.is_empty() {
+            None
+        } else {
+            Some(Box::leak(platform.join(",").into_boxed_str()) as &'static str)
+        };
+        let firmware_static = config.firmware_path.as_ref()
+            .map(|firmware| &*Box::leak(firmware.clone().into_boxed_str()));
+        let initramfs_static = config.initramfs_path.as_ref()
+            .map(|initramfs| &*Box::leak(initramfs.clone().into_boxed_str()));
+        #[cfg(feature = "sev_snp")]
+        let host_data_static = config.host_data.as_ref()
+            .map(|host_data| &*Box::leak(host_data.clone().into_boxed_str()));
         let cmdline_static = if cmdline.is_empty() { 
             None 
         } else { 
@@ -268,92 +856,504 @@ impl HypervisorManager {
         };
         
         // Create standard parameters
+        // Serial console on a unix socket so `console` can attach from another process
+        let serial_static: &'static str = match &config.serial_socket_path {
+            Some(path) => Box::leak(format!("socket={}", path).into_boxed_str()),
+            None => "null",
+        };
+        
+        let console_static: &'static str = match &config.console_log_path {
+            Some(path) => Box::leak(format!("file={}", path).into_boxed_str()),
+            None => "tty",
+        };
+        
+        let vsock_option: Option<&'static str> = config.vsock.as_ref().map(|vsock| {
+            &*Box::leak(format!("cid={},socket={}", vsock.cid, vsock.socket_path).into_boxed_str())
+        });
+        
+        let rng_static: &'static str = Box::leak(format!("src={}", config.rng_source).into_boxed_str());
+        
+        // A policy needs the balloon to give memory back when the guest runs short, so its
+        // growth never takes the guest into the OOM killer
+        let balloon_static: Option<&'static str> = match (config.balloon, config.free_page_reporting) {
+            (true, true) => Some("size=0,deflate_on_oom=on,free_page_reporting=on"),
+            (true, false) => Some("size=0,deflate_on_oom=on"),
+            (false, true) => Some("size=0,free_page_reporting=on"),
+            (false, false) => None,
+        };
+        
+        let tpm_option: Option<&'static str> = config.tpm.as_ref().map(|tpm| {
+            &*Box::leak(format!("socket={}", tpm.socket_path).into_boxed_str())
+        });
+        
+        let landlock_rules_option: Option<Vec<&'static str>> = if config.landlock {
+            let rules = Self::landlock_rules(config);
+            debug!("Landlock rules: {:?}", rules);
+            Some(rules.into_iter().map(|rule| &*Box::leak(rule.into_boxed_str())).collect())
+        } else {
+            None
+        };
+        
         let params = VmParams {
             cpus: cpus_static,
             memory: memory_static,
-            memory_zones: None,
-            firmware: None,
-            kernel: kernel_static,
-            initramfs: None,
+            memory_zones: memory_zones_option,
+            firmware: firmware_static,
+            kernel: if sev_snp { None } else { kernel_static },
+            initramfs: if sev_snp { None } else { initramfs_static },
             cmdline: cmdline_static,
-            rate_limit_groups: None,
+            rate_limit_groups: rate_limit_groups_option,
             disks: disks_option,
             net: None,
-            rng: "src=/dev/urandom",
-            balloon: None,
+            rng: rng_static,
+            balloon: balloon_static,
             fs: None,
             pmem: None,
-            serial: "null",
-            console: "tty",
+            serial: serial_static,
+            console: console_static,
             #[cfg(target_arch = "x86_64")]
             debug_console: "off",
             devices: devices_option,
             user_devices: None,
             vdpa: None,
-            vsock: None,
+            vsock: vsock_option,
             pvpanic: false,
             #[cfg(target_arch = "x86_64")]
-            sgx_epc: None,
-            numa: None,
-            watchdog: false,
+            sgx_epc: sgx_epc_option,
+            numa: numa_option,
+            watchdog: config.watchdog,
             #[cfg(feature = "guest_debug")]
             gdb: false,
             pci_segments: None,
-            platform: None,
-            tpm: None,
-            landlock_enable: false,
-            landlock_rules: None,
+            platform: platform_static,
+            tpm: tpm_option,
+            #[cfg(feature = "igvm")]
+            igvm: if sev_snp { kernel_static } else { None },
+            #[cfg(feature = "sev_snp")]
+            host_data: host_data_static,
+            landlock_enable: config.landlock,
+            landlock_rules: landlock_rules_option,
         };
         
         Ok(params)
     }
     
-    /// Start the hypervisor
-    pub fn start(&mut self) -> Result<()> {
-        // Validate VM is in the correct state
-        if self.state != VmState::Configured {
-            return Err(anyhow!(HypervisorError::InvalidState(
-                format!("VM must be in Configured state to start, current state: {:?}", self.state)
-            )));
+    /// Paths in the configuration the VMM opens, as `(path, access)` with access `r` or `rw`
+    pub fn vmm_paths(config: &VmConfig) -> Vec<(String, &'static str)> {
+        let mut paths: Vec<(String, &'static str)> = Vec::new();
+        
+        if let Some(kernel_path) = &config.kernel_path {
+            paths.push((kernel_path.clone(), "r"));
+        }
+        if let Some(firmware_path) = &config.firmware_path {
+            paths.push((firmware_path.clone(), "r"));
+        }
+        if let Some(initramfs_path) = &config.initramfs_path {
+            paths.push((initramfs_path.clone(), "r"));
         }
         
-        // Create VM parameters
-        let vm_params = self.create_vm_params()?;
+        // An ephemeral base image is only read; the guest writes to the overlay
+        match &config.ephemeral_overlay_path {
+            Some(overlay_path) => {
+                paths.push((config.system_image_path.clone(), "r"));
+                paths.push((overlay_path.clone(), "rw"));
+            },
+            None => paths.push((config.system_image_path.clone(), "rw")),
+        }
+        paths.push((config.config_image_path.clone(), "r"));
         
-        // Parse VM parameters into VM config
-        let ch_vm_config = ChVmConfig::parse(vm_params)
-            .map_err(|e| HypervisorError::ParsingError(format!("{:?}", e)))?;
+        for disk in &config.disks {
+            paths.push((disk.path.clone(), if disk.readonly { "r" } else { "rw" }));
+        }
         
-        // Create and setup hypervisor
-        info!("Initializing hypervisor");
-        let hypervisor = ch_hypervisor::new()
-            .map_err(|e| HypervisorError::HypervisorError(format!("{:?}", e)))?;
+        for file in config.memory_zones.iter().filter_map(|zone| zone.file.as_ref()) {
+            paths.push((file.clone(), "rw"));
+        }
         
-        // Clone event FDs
-        let api_evt_clone = self.api_evt.try_clone()
-            .map_err(|e| HypervisorError::IoError(e))?;
+        // Each EPC section is a mapping of the virtual EPC device
+        if !config.sgx_epc.is_empty() {
+            paths.push((SGX_VEPC_PATH.to_string(), "rw"));
+        }
         
-        let _exit_evt_clone = self.exit_evt.try_clone()
-            .map_err(|e| HypervisorError::IoError(e))?;
+        // VFIO follows the sysfs device to its IOMMU group and opens the group under /dev/vfio.
+        // Landlock checks resolved paths, so allow the device's real location.
+        if !config.device_paths.is_empty() {
+            for device_path in &config.device_paths {
+                let device_path = std::fs::canonicalize(device_path)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_else(|_| device_path.clone());
+                paths.push((device_path, "rw"));
+            }
+            paths.push(("/sys/kernel/iommu_groups".to_string(), "r"));
+            paths.push(("/dev/vfio".to_string(), "rw"));
+        }
         
-        // Setup seccomp
-        let seccomp_action = SeccompAction::Allow;
+        // Sockets are created by the VMM or connected to, which needs their directory
+        let socket_paths = [
+            config.api_socket_path.as_ref(),
+            config.serial_socket_path.as_ref(),
+            config.vsock.as_ref().map(|vsock| &vsock.socket_path),
+            config.tpm.as_ref().map(|tpm| &tpm.socket_path),
+        ];
+        for socket_path in socket_paths.into_iter().flatten() {
+            if let Some(dir) = Path::new(socket_path).parent() {
+                paths.push((dir.to_string_lossy().into_owned(), "rw"));
+            }
+        }
         
-        // Build the VMM version info
-        let vmm_version = VmmVersionInfo::new(
-            env!("CARGO_PKG_VERSION"),
-            env!("CARGO_PKG_VERSION")
-        );
+        if let Some(console_log_path) = &config.console_log_path {
+            paths.push((console_log_path.clone(), "rw"));
+        }
+        
+        paths.push(("/dev/urandom".to_string(), "r"));
+        paths
+    }
+    
+    /// Landlock allow-list derived from the paths in the configuration, so the VMM cannot
+    /// touch anything else on the host filesystem
+    fn landlock_rules(config: &VmConfig) -> Vec<String> {
+        let mut paths = Self::vmm_paths(config);
+        paths.extend(config.landlock_paths.iter().cloned());
+        
+        let mut rules: Vec<String> = Vec::new();
+        for (path, access) in paths {
+            let rule = format!("path={},access={}", path, access);
+            if !rules.contains(&rule) {
+                rules.push(rule);
+            }
+        }
+        rules
+    }
+    
+    /// The entropy source must exist; /dev/hwrng also needs a hardware RNG behind it, or every
+    /// read the guest makes fails
+    fn validate_rng(source: &str) -> Result<()> {
+        // Cloud Hypervisor v44 always creates the device, so it can only be pointed elsewhere
+        if source == "off" || source == "none" {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                "Cloud Hypervisor always gives the guest a virtio-rng device; set rng to an entropy source such as /dev/urandom".to_string()
+            )));
+        }
+        if !Path::new(source).exists() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                format!("Entropy source does not exist: {}", source)
+            )));
+        }
+        if Path::new(source) == Path::new("/dev/hwrng") {
+            let current = std::fs::read_to_string("/sys/class/misc/hw_random/rng_current").unwrap_or_default();
+            if current.trim().is_empty() || current.trim() == "none" {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    "/dev/hwrng has no hardware RNG behind it (rng_current in /sys/class/misc/hw_random is none)".to_string()
+                )));
+            }
+            info!("Guest entropy comes from the hardware RNG {}", current.trim());
+        }
+        Ok(())
+    }
+    
+    /// EPC sections need SGX on an x86_64 host, unique ids and page-aligned sizes
+    fn validate_sgx_epc(config: &VmConfig) -> Result<()> {
+        if config.sgx_epc.is_empty() {
+            return Ok(());
+        }
+        if !cfg!(target_arch = "x86_64") {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                "SGX EPC sections need an x86_64 host".to_string()
+            )));
+        }
+        if config.confidential.is_some() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                "SGX EPC sections cannot be combined with a confidential mode".to_string()
+            )));
+        }
+        // The kernel creates the device once SGX is enabled in the firmware and supports KVM guests
+        if !Path::new(SGX_VEPC_PATH).exists() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                format!("SGX EPC sections need {}; enable SGX in the firmware and use a kernel with CONFIG_X86_SGX_KVM", SGX_VEPC_PATH)
+            )));
+        }
+        
+        for (index, section) in config.sgx_epc.iter().enumerate() {
+            if config.sgx_epc[..index].iter().any(|other| other.id == section.id) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Duplicate SGX EPC section id: {}", section.id)
+                )));
+            }
+            let size = parse_size(section.size.trim())
+                .context(format!("Invalid size of SGX EPC section {}", section.id))?;
+            if size == 0 || !size.is_multiple_of(4096) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("SGX EPC section {} must be a non-zero multiple of 4K, got {}", section.id, section.size)
+                )));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Zones need unique ids and a size; a guest NUMA node needs every zone and vCPU in one place
+    fn validate_memory_zones(config: &VmConfig) -> Result<()> {
+        let zones = &config.memory_zones;
+        let mut assigned_cpus: Vec<u32> = Vec::new();
+        for (index, zone) in zones.iter().enumerate() {
+            if zones[..index].iter().any(|z| z.id == zone.id) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Duplicate memory zone id: {}", zone.id)
+                )));
+            }
+            
+            if parse_size(zone.size.trim()).context(format!("Invalid size of memory zone {}", zone.id))? == 0 {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Memory zone {} has no size", zone.id)
+                )));
+            }
+            
+            // A file backs the zone itself, so it cannot also come from the hugepage pool
+            if zone.hugepages && zone.file.is_some() {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Memory zone {} cannot combine hugepages with a backing file", zone.id)
+                )));
+            }
+            
+            if let Some(node) = zone.host_numa_node {
+                if !Path::new(&format!("/sys/devices/system/node/node{}", node)).exists() {
+                    return Err(anyhow!(HypervisorError::ConfigError(
+                        format!("Memory zone {} is placed on host NUMA node {}, which the host does not have", zone.id, node)
+                    )));
+                }
+            }
+            
+            if zone.guest_numa_node.is_some() != zones[0].guest_numa_node.is_some() {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    "Either every memory zone or none sets guest_numa_node".to_string()
+                )));
+            }
+            
+            if let Some(cpus) = &zone.guest_cpus {
+                if zone.guest_numa_node.is_none() {
+                    return Err(anyhow!(HypervisorError::ConfigError(
+                        format!("Memory zone {} sets guest_cpus without guest_numa_node", zone.id)
+                    )));
+                }
+                for cpu in parse_cpu_list(cpus)? {
+                    if cpu >= config.max_vcpu_count as u32 {
+                        return Err(anyhow!(HypervisorError::ConfigError(
+                            format!("Memory zone {} assigns vCPU {}, but the VM has {} vCPUs", zone.id, cpu, config.max_vcpu_count)
+                        )));
+                    }
+                    if assigned_cpus.contains(&cpu) {
+                        return Err(anyhow!(HypervisorError::ConfigError(
+                            format!("vCPU {} is assigned to more than one guest NUMA node zone", cpu)
+                        )));
+                    }
+                    assigned_cpus.push(cpu);
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Refuse options a confidential guest cannot use
+    fn validate_confidential(config: &VmConfig, mode: ConfidentialMode) -> Result<()> {
+        if !mode.compiled_in() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                format!("Confidential mode {} requires building with the {} feature", mode.as_str(), mode.feature())
+            )));
+        }
+        
+        // Devices would need direct access to guest memory, which is encrypted
+        if !config.device_paths.is_empty() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                format!("Device passthrough is not supported in confidential mode {}", mode.as_str())
+            )));
+        }
+        
+        // The host cannot reclaim pages it is not allowed to see
+        if config.balloon {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                format!("A balloon policy is not supported in confidential mode {}", mode.as_str())
+            )));
+        }
+        if config.free_page_reporting {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                format!("Free page reporting is not supported in confidential mode {}", mode.as_str())
+            )));
+        }
+        
+        // The IGVM image takes the place of the kernel
+        if mode == ConfidentialMode::SevSnp && config.kernel_path.is_none() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                "Confidential mode sev-snp requires the IGVM image as the kernel".to_string()
+            )));
+        }
+        
+        // TDX guests start in the TD firmware, which then loads the kernel
+        if mode == ConfidentialMode::Tdx {
+            match &config.firmware_path {
+                Some(firmware) if Path::new(firmware).exists() => {},
+                Some(firmware) => return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("TD firmware path does not exist: {}", firmware)
+                ))),
+                None => return Err(anyhow!(HypervisorError::ConfigError(
+                    "Confidential mode tdx requires a TD firmware (TDVF) path".to_string()
+                ))),
+            }
+        }
+        
+        if config.host_data.is_some() && mode != ConfidentialMode::SevSnp {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                format!("Host data is only supported in confidential mode sev-snp, not {}", mode.as_str())
+            )));
+        }
+        
+        if let Some(host_data) = &config.host_data {
+            if host_data.len() != 64 || !host_data.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow!(HypervisorError::ConfigError(
+                    format!("Host data must be 64 hex digits, got {}", host_data)
+                )));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Select the IO engine of each additional disk in the parsed Cloud Hypervisor config
+    fn apply_disk_io_engines(config: &VmConfig, ch_vm_config: &mut ChVmConfig) {
+        let Some(ch_disks) = ch_vm_config.disks.as_mut() else {
+            return;
+        };
+        
+        for (index, disk) in config.disks.iter().enumerate() {
+            if disk.aio != Some(DiskIoEngine::Threads) {
+                continue;
+            }
+            
+            // Without io_uring and native AIO, Cloud Hypervisor falls back to synchronous IO
+            let disk_id = disk.id_or_default(index);
+            if let Some(ch_disk) = ch_disks.iter_mut().find(|d| d.id.as_deref() == Some(disk_id.as_str())) {
+                ch_disk.disable_io_uring = true;
+                ch_disk.disable_aio = true;
+            }
+        }
+    }
+    
+    /// Cloud Hypervisor's configuration of the VM, as VmCreate receives it
+    fn create_vm_config(config: &VmConfig) -> Result<ChVmConfig> {
+        let vm_params = Self::create_vm_params(config)?;
+        let mut ch_vm_config = ChVmConfig::parse(vm_params)
+            .map_err(|e| HypervisorError::ParsingError(format!("{:?}", e)))?;
+        
+        // The IO engine is not part of the disk string, so apply it to the parsed config
+        Self::apply_disk_io_engines(config, &mut ch_vm_config);
+        Ok(ch_vm_config)
+    }
+    
+    /// The configuration start() would send the VMM for `config`, as JSON, without touching the
+    /// host; shared with the mock backend
+    pub fn planned_vm_config(config: &VmConfig) -> Result<serde_json::Value> {
+        Self::validate_config(config)?;
+        let ch_vm_config = Self::create_vm_config(config)?;
+        serde_json::to_value(&ch_vm_config).context("Failed to serialize the VM configuration")
+    }
+    
+    /// Start the hypervisor
+    pub fn start(&mut self) -> Result<()> {
+        // Validate VM is in the correct state
+        if self.state != VmState::Configured {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Configured state to start, current state: {:?}", self.state)
+            )));
+        }
+        
+        let phase = Instant::now();
+        self.timings = BootTimings::default();
+        let deadline = self.config.as_ref().and_then(|config| config.boot_timeout).map(|timeout| (phase + timeout, timeout));
+        
+        // Create the ephemeral overlay before the VM parameters refer to it
+        if let Some(config) = self.config.as_ref() {
+            if let Some(overlay_path) = config.ephemeral_overlay_path.as_ref().filter(|_| config.restore_path.is_none()) {
+                info!("Creating ephemeral overlay {} backed by {}", overlay_path, config.system_image_path);
+                crate::overlay::create_qcow2_overlay(&config.system_image_path, overlay_path)?;
+            }
+            
+            // The VMM connects to the TPM socket while creating the VM
+            if let Some(tpm) = &config.tpm {
+                self.swtpm.set(Swtpm::spawn(&tpm.state_dir, &tpm.socket_path)?);
+            }
+        }
+        
+        // Create the VM configuration from the VM parameters
+        let config = self.config.as_ref()
+            .ok_or_else(|| anyhow!(HypervisorError::InvalidState("VM not configured".to_string())))?;
+        let ch_vm_config = Self::create_vm_config(config)?;
+        
+        // Create and setup hypervisor
+        info!("Initializing hypervisor");
+        let backend_kind = self.config.as_ref().map(|config| config.backend).unwrap_or_default();
+        let hypervisor = backend::create(backend_kind)
+            .map_err(|e| HypervisorError::HypervisorError(format!("{:#}", e)))?;
+        
+        // The VMM threads take the identity of this one, with /dev/kvm already open
+        if let Some(run_as) = self.config.as_ref().and_then(|config| config.run_as.clone()) {
+            self.drop_privileges(&run_as)?;
+        }
+        
+        // Clone event FDs
+        let api_evt_clone = self.api_evt.try_clone()
+            .map_err(|e| HypervisorError::IoError(e))?;
+        
+        let _exit_evt_clone = self.exit_evt.try_clone()
+            .map_err(|e| HypervisorError::IoError(e))?;
+        
+        // The VMM thread owns the receiving end of the API channel
+        let api_receiver = self.api_receiver.take()
+            .ok_or_else(|| HypervisorError::InvalidState("API receiver already in use".to_string()))?;
+        
+        // Serve the HTTP API for out-of-process commands if requested
+        let api_socket_path = self.config.as_ref()
+            .and_then(|config| config.api_socket_path.clone());
+        
+        // Setup seccomp
+        let seccomp_action = self.config.as_ref()
+            .map(|config| config.seccomp.clone())
+            .unwrap_or(SeccompAction::Trap);
+        match seccomp_action {
+            SeccompAction::Allow => warn!("Seccomp filtering is disabled; the VMM may make any syscall"),
+            SeccompAction::Log => warn!("Seccomp filtering only logs syscalls outside the allow lists"),
+            _ => info!("Seccomp filtering enforced"),
+        }
+        
+        let landlock_enable = self.config.as_ref().is_some_and(|config| config.landlock);
+        
+        // Guest memory is mapped by this process, so disabling THP here covers all of it
+        let memory = self.config.as_ref().map(|config| &config.memory_config).filter(|memory| !memory.hugepages);
+        if let Some(memory) = memory {
+            if memory.thp == ThpMode::Never && unsafe { libc::prctl(libc::PR_SET_THP_DISABLE, 1, 0, 0, 0) } != 0 {
+                return Err(anyhow!(HypervisorError::StartError(
+                    format!("Failed to disable Transparent Huge Pages: {}", std::io::Error::last_os_error())
+                )));
+            }
+            let policy = std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled").unwrap_or_default();
+            if memory.thp == ThpMode::On && policy.contains("[never]") {
+                warn!("Transparent Huge Pages are disabled on the host, so guest memory uses 4K pages");
+            }
+        }
+        
+        // Build the VMM version info
+        let vmm_version = VmmVersionInfo::new(
+            env!("CARGO_PKG_VERSION"),
+            env!("CARGO_PKG_VERSION")
+        );
         
         // Start VMM thread
         let vmm_thread_handle = vmm::start_vmm_thread(
             vmm_version,
-            &None, // No API socket path
+            &api_socket_path, // API socket path
             None,  // No API socket fd
             self.api_evt.try_clone()
                 .map_err(|e| HypervisorError::IoError(e))?, // API event
             self.api_sender.clone(), // API sender
-            channel().1, // API receiver (we created our own)
+            api_receiver, // API receiver
             #[cfg(feature = "guest_debug")]
             None, // No GDB socket path
             #[cfg(feature = "guest_debug")]
@@ -364,46 +1364,80 @@ impl HypervisorManager {
                 .map_err(|e| HypervisorError::IoError(e))?, // exit event
             &seccomp_action,
             hypervisor,
-            false, // No landlock
+            landlock_enable,
         )
         .map_err(|e| HypervisorError::StartError(format!("{:?}", e)))?;
         
         // Store hypervisor
         self.vmm_thread_handle = Some(vmm_thread_handle);
+        self.timings.vmm_init_ms = Some(elapsed_ms(phase));
+        
+        // A restored VM has the configuration the snapshot holds and runs from where it was paused
+        if let Some(source) = self.config.as_ref().and_then(|config| config.restore_path.clone()) {
+            info!("Restoring VM from {}", source);
+            let phase = Instant::now();
+            let restore_config = RestoreConfig {
+                source_url: PathBuf::from(format!("file://{}", source)),
+                prefault: false,
+                net_fds: None,
+            };
+            let (api_evt, api_sender) = (api_evt_clone.try_clone().unwrap(), self.api_sender.clone());
+            within(deadline, move || VmRestore.send(api_evt, api_sender, restore_config).map(drop).map_err(|e| format!("{:?}", e)))?
+                .map_err(|e| anyhow!(HypervisorError::ApiError(format!("Failed to restore VM: {}", e))))?;
+            self.vm_created = true;
+            self.vm_booted = true;
+            self.timings.vm_create_ms = Some(elapsed_ms(phase));
+            
+            let phase = Instant::now();
+            let api_sender = self.api_sender.clone();
+            within(deadline, move || VmResume.send(api_evt_clone, api_sender, ()).map(drop).map_err(|e| format!("{:?}", e)))?
+                .map_err(|e| anyhow!(HypervisorError::ApiError(format!("Failed to resume restored VM: {}", e))))?;
+            self.timings.vm_boot_ms = Some(elapsed_ms(phase));
+            self.state = VmState::Running;
+            self.restored = true;
+            self.apply_vcpu_sched();
+            info!("VM restored successfully");
+            return Ok(());
+        }
         
         // Create the VM
         info!("Creating VM");
-        let vm_create_result = VmCreate.send(
-            api_evt_clone.try_clone().unwrap(), 
-            self.api_sender.clone(), 
-            Box::new(ch_vm_config)
-        );
+        let phase = Instant::now();
+        let (api_evt, api_sender) = (api_evt_clone.try_clone().unwrap(), self.api_sender.clone());
+        let vm_create_result = within(deadline, move || {
+            VmCreate.send(api_evt, api_sender, Box::new(ch_vm_config)).map(drop).map_err(|e| format!("{:?}", e))
+        })?;
         
         match vm_create_result {
             Ok(_) => {
                 info!("VM created successfully");
                 self.vm_created = true;
+                self.timings.vm_create_ms = Some(elapsed_ms(phase));
             },
             Err(e) => {
                 return Err(anyhow!(HypervisorError::ApiError(
-                    format!("Failed to create VM: {:?}", e)
+                    format!("Failed to create VM: {}", e)
                 )));
             }
         }
         
         // Boot the VM
         info!("Booting VM");
-        let vm_boot_result = VmBoot.send(api_evt_clone, self.api_sender.clone(), ());
+        let phase = Instant::now();
+        let api_sender = self.api_sender.clone();
+        let vm_boot_result = within(deadline, move || VmBoot.send(api_evt_clone, api_sender, ()).map(drop).map_err(|e| format!("{:?}", e)))?;
         
         match vm_boot_result {
             Ok(_) => {
                 info!("VM booted successfully");
                 self.vm_booted = true;
+                self.timings.vm_boot_ms = Some(elapsed_ms(phase));
                 self.state = VmState::Running;
+                self.apply_vcpu_sched();
             },
             Err(e) => {
                 return Err(anyhow!(HypervisorError::ApiError(
-                    format!("Failed to boot VM: {:?}", e)
+                    format!("Failed to boot VM: {}", e)
                 )));
             }
         }
@@ -412,11 +1446,55 @@ impl HypervisorManager {
         Ok(())
     }
     
+    /// Remove the ephemeral overlay, if one was created and no other process took it over. The
+    /// overlay of a snapshot that failed to restore is kept with the snapshot.
+    fn remove_overlay(&self) {
+        let unrestored = self.config.as_ref().is_some_and(|config| config.restore_path.is_some()) && !self.restored;
+        if self.suspended || unrestored {
+            return;
+        }
+        if let Some(overlay_path) = self.config.as_ref().and_then(|c| c.ephemeral_overlay_path.as_ref()) {
+            if Path::new(overlay_path).exists() {
+                info!("Removing ephemeral overlay {}", overlay_path);
+                if let Err(e) = std::fs::remove_file(overlay_path) {
+                    warn!("Failed to remove ephemeral overlay {}: {}", overlay_path, e);
+                }
+            }
+        }
+    }
+    
+    /// How the VM ended, if the VMM thread has returned on its own. The VMM returns once the
+    /// guest powers off, and with an error when it fails.
+    pub fn exited(&mut self) -> Option<VmExit> {
+        if !self.vmm_thread_handle.as_ref().is_some_and(|handle| handle.thread_handle.is_finished()) {
+            return None;
+        }
+        let handle = self.vmm_thread_handle.take()?;
+        if let Some(api_handle) = handle.http_api_handle {
+            if let Err(e) = vmm::api::http::http_api_graceful_shutdown(api_handle) {
+                error!("Failed to shutdown HTTP API: {:?}", e);
+            }
+        }
+        
+        self.state = VmState::Shutdown;
+        self.vm_created = false;
+        self.vm_booted = false;
+        match handle.thread_handle.join() {
+            Ok(Ok(())) => Some(VmExit::Poweroff),
+            Ok(Err(e)) => Some(VmExit::VmmError(format!("{:?}", e))),
+            Err(_) => Some(VmExit::VmmError("the VMM thread panicked".to_string())),
+        }
+    }
+    
     /// Shutdown the hypervisor
     pub fn shutdown(&mut self) -> Result<()> {
         // Check if a VM is running
-        if self.state != VmState::Running && self.state != VmState::Paused {
+        if !self.is_running() && self.state != VmState::Paused {
             info!("No running VM to shut down");
+            
+            // A failed start may still have left an overlay or swtpm behind
+            self.remove_overlay();
+            self.swtpm.stop();
             return Ok(());
         }
         
@@ -467,23 +1545,193 @@ impl HypervisorManager {
         self.vm_created = false;
         self.vm_booted = false;
         
+        // Discard guest writes to the system image
+        self.remove_overlay();
+        
+        // swtpm normally exits with the VMM; make sure it is gone
+        self.swtpm.stop();
+        
         info!("VM shutdown complete");
         Ok(())
     }
     
-    /// Check if the hypervisor is running
-    pub fn is_running(&self) -> bool {
-        self.state == VmState::Running
+    /// Listen for the guest's boot-complete signal on the vsock ready port. The returned
+    /// receiver fires once the guest has signalled, after which the caller calls `mark_ready`.
+    pub fn ready_signal(&self) -> Result<Receiver<()>> {
+        let vsock = self.config.as_ref()
+            .and_then(|config| config.vsock.as_ref())
+            .ok_or_else(|| anyhow!(HypervisorError::ConfigError("vsock is not configured".to_string())))?;
+        
+        let socket_path = agent::host_port_socket_path(&vsock.socket_path, agent::READY_PORT);
+        if Path::new(&socket_path).exists() {
+            std::fs::remove_file(&socket_path)
+                .context(format!("Failed to remove stale ready socket: {}", socket_path))?;
+        }
+        
+        let listener = UnixListener::bind(&socket_path)
+            .context(format!("Failed to listen on ready socket: {}", socket_path))?;
+        
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            match agent::wait_ready(&listener) {
+                Ok(()) => { let _ = sender.send(()); },
+                Err(e) => warn!("Ready listener failed: {}", e),
+            }
+            let _ = std::fs::remove_file(&socket_path);
+        });
+        
+        Ok(receiver)
+    }
+    
+    /// Record that the guest workload is ready
+    pub fn mark_ready(&mut self) {
+        if self.state == VmState::Running {
+            info!("Guest workload is ready");
+            self.state = VmState::Ready;
+        }
+    }
+    
+    /// Relay TDX guest connections to the Quote Generation Service port to `qgs_socket_path`
+    pub fn quote_relay(&self, qgs_socket_path: &str) -> Result<()> {
+        let vsock = self.config.as_ref()
+            .and_then(|config| config.vsock.as_ref())
+            .ok_or_else(|| anyhow!(HypervisorError::ConfigError("vsock is not configured".to_string())))?;
+        
+        let socket_path = agent::host_port_socket_path(&vsock.socket_path, crate::confidential::QGS_PORT);
+        if Path::new(&socket_path).exists() {
+            std::fs::remove_file(&socket_path)
+                .context(format!("Failed to remove stale quote socket: {}", socket_path))?;
+        }
+        
+        let listener = UnixListener::bind(&socket_path)
+            .context(format!("Failed to listen on quote socket: {}", socket_path))?;
+        
+        let qgs_socket_path = qgs_socket_path.to_string();
+        std::thread::spawn(move || crate::confidential::relay_quotes(listener, qgs_socket_path));
+        
+        Ok(())
+    }
+    
+    /// Reboot the guest; it is no longer ready until it reports ready again
+    pub fn reboot(&mut self) -> Result<()> {
+        if !self.is_running() {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Running state to reboot, current state: {:?}", self.state)
+            )));
+        }
+        
+        // Clone event FD
+        let api_evt_clone = self.api_evt.try_clone()
+            .map_err(HypervisorError::IoError)?;
+        
+        VmReboot.send(api_evt_clone, self.api_sender.clone(), ())
+            .map_err(|e| anyhow!(HypervisorError::ApiError(format!("Failed to reboot VM: {:?}", e))))?;
+        
+        info!("VM reboot requested");
+        self.state = VmState::Running;
+        
+        // The reboot replaced the vCPU threads
+        self.apply_vcpu_sched();
+        Ok(())
     }
     
-    /// Get the current state of the hypervisor
-    pub fn state(&self) -> VmState {
-        self.state
+    /// Pause the VM and write a snapshot of it into the existing `directory`, for a new hypervisor
+    /// process to restore. The VM stays paused, and shutting it down keeps its overlay. When the
+    /// snapshot fails the VM runs on.
+    pub fn suspend(&mut self, directory: &str) -> Result<()> {
+        if !self.is_running() {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Running state to suspend, current state: {:?}", self.state)
+            )));
+        }
+        if let Some(config) = self.config.as_ref() {
+            Self::check_snapshottable(config)?;
+        }
+        
+        let api_evt_clone = self.api_evt.try_clone()
+            .map_err(HypervisorError::IoError)?;
+        VmPause.send(api_evt_clone.try_clone().unwrap(), self.api_sender.clone(), ())
+            .map_err(|e| anyhow!(HypervisorError::ApiError(format!("Failed to pause VM: {:?}", e))))?;
+        let snapshot_config = VmSnapshotConfig { destination_url: format!("file://{}", directory) };
+        if let Err(e) = VmSnapshot.send(api_evt_clone.try_clone().unwrap(), self.api_sender.clone(), snapshot_config) {
+            if let Err(e) = VmResume.send(api_evt_clone, self.api_sender.clone(), ()) {
+                error!("Failed to resume VM after a failed snapshot: {:?}", e);
+            }
+            return Err(anyhow!(HypervisorError::ApiError(format!("Failed to snapshot VM: {:?}", e))));
+        }
+        
+        info!("VM suspended into {}", directory);
+        self.state = VmState::Paused;
+        self.suspended = true;
+        Ok(())
+    }
+    
+    /// Resume the VM `suspend` paused, which then runs on here and removes its overlay on
+    /// shutdown again
+    pub fn resume_suspended(&mut self, ready: bool) -> Result<()> {
+        if !self.suspended {
+            return Err(anyhow!(HypervisorError::InvalidState("VM is not suspended".to_string())));
+        }
+        
+        let api_evt_clone = self.api_evt.try_clone()
+            .map_err(HypervisorError::IoError)?;
+        VmResume.send(api_evt_clone, self.api_sender.clone(), ())
+            .map_err(|e| anyhow!(HypervisorError::ApiError(format!("Failed to resume VM: {:?}", e))))?;
+        
+        info!("VM resumed after a failed handoff");
+        self.state = if ready { VmState::Ready } else { VmState::Running };
+        self.suspended = false;
+        Ok(())
+    }
+    
+    /// Check that the VM can be snapshotted and restored: VFIO devices and the memory of
+    /// confidential guests cannot be saved
+    pub fn check_snapshottable(config: &VmConfig) -> Result<()> {
+        if !config.device_paths.is_empty() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                "A VM with passthrough devices cannot be snapshotted".to_string()
+            )));
+        }
+        if config.confidential.is_some() {
+            return Err(anyhow!(HypervisorError::ConfigError(
+                "A confidential VM cannot be snapshotted".to_string()
+            )));
+        }
+        Ok(())
+    }
+    
+    /// Give up root for `run_as`. The files created for the VM as root, and the VFIO groups the
+    /// VMM opens, are handed over first, and the scheduling limits are raised so the policy of
+    /// the vCPU threads can still be applied without CAP_SYS_NICE.
+    fn drop_privileges(&self, run_as: &RunAs) -> Result<()> {
+        let config = self.config.as_ref()
+            .ok_or_else(|| anyhow!(HypervisorError::InvalidState("VM not configured".to_string())))?;
+        // The VFIO groups are the host's and get their owners back when the runtime exits
+        let groups = vfio_group_paths(&config.device_paths);
+        run_as.lend(&groups.iter().map(String::as_str).collect::<Vec<_>>())?;
+        let mut owned: Vec<String> = Vec::new();
+        owned.extend(config.ephemeral_overlay_path.clone().filter(|_| config.restore_path.is_none()));
+        owned.extend(config.tpm.as_ref().map(|tpm| tpm.socket_path.clone()));
+        run_as.chown(&owned.iter().map(String::as_str).collect::<Vec<_>>())?;
+        if let Some(sched) = config.vcpu_sched {
+            sched.allow_unprivileged()?;
+        }
+        run_as.drop_privileges()
+    }
+    
+    /// Apply the configured scheduling policy to the current vCPU threads. The VM keeps
+    /// running with default scheduling when that fails.
+    fn apply_vcpu_sched(&self) {
+        if let Some(sched) = self.config.as_ref().and_then(|config| config.vcpu_sched) {
+            if let Err(e) = sched.apply() {
+                warn!("Failed to apply vCPU scheduling: {:#}", e);
+            }
+        }
     }
     
     /// Get the VM info
-    pub fn info(&self) -> Result<String> {
-        if self.state != VmState::Running {
+    pub fn info(&self) -> Result<VmDetails> {
+        if !self.is_running() {
             return Err(anyhow!(HypervisorError::InvalidState(
                 format!("VM must be in Running state to get info, current state: {:?}", self.state)
             )));
@@ -497,10 +1745,11 @@ impl HypervisorManager {
         let info_result = VmInfo.send(api_evt_clone, self.api_sender.clone(), ());
         
         match info_result {
-            Ok(_response) => {
-                // In a real implementation, we would parse the VM info
-                // For now, just return a generic message
-                Ok(format!("VM is running (state: active)"))
+            Ok(response) => {
+                // Read it the way clients of the API socket do
+                let body = serde_json::to_string(&response)
+                    .context("Failed to serialize VM info")?;
+                VmDetails::from_response(&body)
             },
             Err(e) => {
                 Err(anyhow!(HypervisorError::ApiError(
@@ -511,12 +1760,140 @@ impl HypervisorManager {
     }
 }
 
-/// Create a new hypervisor instance
+impl HypervisorBackend for HypervisorManager {
+    fn configure(&mut self, config: VmConfig) -> Result<()> {
+        HypervisorManager::configure(self, config)
+    }
+    
+    fn start(&mut self) -> Result<()> {
+        HypervisorManager::start(self)
+    }
+    
+    fn shutdown(&mut self) -> Result<()> {
+        HypervisorManager::shutdown(self)
+    }
+    
+    fn reboot(&mut self) -> Result<()> {
+        HypervisorManager::reboot(self)
+    }
+    
+    fn guest_reset(&mut self) {
+        if self.is_running() {
+            self.state = VmState::Running;
+            // The reset replaced the vCPU threads
+            self.apply_vcpu_sched();
+        }
+    }
+    
+    fn vcpus_resized(&mut self) {
+        if self.is_running() {
+            self.apply_vcpu_sched();
+        }
+    }
+    
+    fn exited(&mut self) -> Option<VmExit> {
+        HypervisorManager::exited(self)
+    }
+    
+    fn suspend(&mut self, directory: &str) -> Result<()> {
+        HypervisorManager::suspend(self, directory)
+    }
+    
+    fn resume_suspended(&mut self, ready: bool) -> Result<()> {
+        HypervisorManager::resume_suspended(self, ready)
+    }
+    
+    fn mark_ready(&mut self) {
+        HypervisorManager::mark_ready(self)
+    }
+    
+    fn ready_signal(&self) -> Result<Receiver<()>> {
+        HypervisorManager::ready_signal(self)
+    }
+    
+    fn quote_relay(&self, qgs_socket_path: &str) -> Result<()> {
+        HypervisorManager::quote_relay(self, qgs_socket_path)
+    }
+    
+    fn state(&self) -> VmState {
+        self.state
+    }
+    
+    fn boot_timings(&self) -> BootTimings {
+        self.timings
+    }
+}
+
+// Make an API request of the boot from a thread of its own and wait for its answer until the
+// deadline, which comes with the timeout it was set from. The VMM may hang while creating or
+// booting the VM, as in a device that never answers; the thread waiting for it is left to the
+// exit of the process.
+fn within(deadline: Option<(Instant, Duration)>, request: impl FnOnce() -> std::result::Result<(), String> + Send + 'static)
+          -> Result<std::result::Result<(), String>> {
+    let Some((deadline, timeout)) = deadline else { return Ok(request()) };
+    let (sender, answer) = channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(request());
+    });
+    match answer.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => Ok(result),
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Err(anyhow!(HypervisorError::BootTimeout(timeout.as_secs()))),
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Ok(Err("the API request failed without an answer".to_string())),
+    }
+}
+
+// VFIO group files of the passed-through devices, which the VMM opens while creating the VM
+fn vfio_group_paths(device_paths: &[String]) -> Vec<String> {
+    device_paths.iter()
+        .filter_map(|device| std::fs::read_link(Path::new(device).join("iommu_group")).ok())
+        .filter_map(|group| group.file_name().map(|group| format!("/dev/vfio/{}", group.to_string_lossy())))
+        .collect()
+}
+
+/// Create a new hypervisor instance
 pub fn new() -> Result<Arc<dyn ChHypervisor>> {
     ch_hypervisor::new()
         .map_err(|e| anyhow!("Failed to create hypervisor: {:?}", e))
 }
 
+/// Parse a seccomp setting the way Cloud Hypervisor's `--seccomp` does: `true` traps syscalls
+/// outside the allow lists, `log` only logs them and `false` disables filtering
+pub fn parse_seccomp_action(s: &str) -> Result<SeccompAction> {
+    match s.trim().to_lowercase().as_str() {
+        "true" | "on" => Ok(SeccompAction::Trap),
+        "false" | "off" => Ok(SeccompAction::Allow),
+        "log" => Ok(SeccompAction::Log),
+        _ => Err(anyhow!("Invalid seccomp setting '{}', expected true, false or log", s)),
+    }
+}
+
+/// Parse a size in bytes with an optional suffix: K, M, G and T or KiB, MiB, GiB and TiB are
+/// binary, KB, MB, GB and TB decimal, as in "16G", "512MiB" or "100GB"
+pub fn parse_size(size_str: &str) -> Result<u64> {
+    let split = size_str.find(|c: char| !c.is_ascii_digit()).unwrap_or(size_str.len());
+    let (number, unit) = size_str.split_at(split);
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(anyhow!("Invalid size unit, expected K, M, G, T, KiB, MiB, GiB, TiB, KB, MB, GB or TB: {}", size_str)),
+    };
+    
+    let size_num = number.parse::<u64>()
+        .context(format!("Failed to parse size: {}", size_str))?;
+    
+    size_num.checked_mul(multiplier).ok_or_else(|| anyhow!("Size is too large: {}", size_str))
+}
+
+/// Guest memory sizes have to be a multiple of this
+pub const MEMORY_ALIGNMENT: u64 = 2 << 20;
+
 /// Parse a memory configuration string
 pub fn parse_memory_string(memory_config: &str) -> Result<MemoryConfig> {
     // Parse a string like "size=16G,shared=on"
@@ -524,8 +1901,13 @@ pub fn parse_memory_string(memory_config: &str) -> Result<MemoryConfig> {
         size: 16 * 1024 * 1024 * 1024, // Default 16G
         shared: false,
         hugepages: false,
+        hugepage_size: None,
+        hotplug_size: None,
+        hotplug_method: HotplugMethod::Acpi,
+        thp: ThpMode::On,
         shared_memory_size: None,
     };
+    let mut method_given = false;
     
     for part in memory_config.split(',') {
         let kv: Vec<&str> = part.splitn(2, '=').collect();
@@ -535,25 +1917,7 @@ pub fn parse_memory_string(memory_config: &str) -> Result<MemoryConfig> {
         
         match kv[0].trim() {
             "size" => {
-                let size_str = kv[1].trim();
-                let multiplier = match size_str.chars().last() {
-                    Some('K') | Some('k') => 1024,
-                    Some('M') | Some('m') => 1024 * 1024,
-                    Some('G') | Some('g') => 1024 * 1024 * 1024,
-                    Some('T') | Some('t') => 1024 * 1024 * 1024 * 1024,
-                    Some(c) if c.is_digit(10) => 1,
-                    _ => return Err(anyhow!("Invalid size unit in memory configuration: {}", size_str)),
-                };
-                
-                let size_num = if size_str.chars().last().unwrap().is_alphabetic() {
-                    size_str[..size_str.len()-1].parse::<u64>()
-                        .context(format!("Failed to parse memory size: {}", size_str))?
-                } else {
-                    size_str.parse::<u64>()
-                        .context(format!("Failed to parse memory size: {}", size_str))?
-                };
-                
-                config.size = size_num * multiplier;
+                config.size = parse_size(kv[1].trim()).context("Invalid size in memory configuration")?;
             },
             "shared" => {
                 match kv[1].trim() {
@@ -569,17 +1933,204 @@ pub fn parse_memory_string(memory_config: &str) -> Result<MemoryConfig> {
                     _ => return Err(anyhow!("Invalid hugepages value in memory configuration: {}", kv[1])),
                 }
             },
+            "hotplug_size" => {
+                config.hotplug_size = Some(parse_size(kv[1].trim()).context("Invalid hotplug_size in memory configuration")?);
+            },
+            "hotplug_method" => {
+                config.hotplug_method = match kv[1].trim() {
+                    "acpi" => HotplugMethod::Acpi,
+                    "virtio-mem" => HotplugMethod::VirtioMem,
+                    _ => return Err(anyhow!("Invalid hotplug_method in memory configuration, expected acpi or virtio-mem: {}", kv[1])),
+                };
+                method_given = true;
+            },
+            "hugepage_size" => {
+                let page_size = parse_size(kv[1].trim()).context("Invalid hugepage_size in memory configuration")?;
+                if !page_size.is_power_of_two() || page_size < 4096 {
+                    return Err(anyhow!("Invalid hugepage_size in memory configuration, expected a page size such as 2M or 1G: {}", kv[1]));
+                }
+                config.hugepage_size = Some(page_size);
+            },
+            "thp" => {
+                config.thp = match kv[1].trim() {
+                    "on" => ThpMode::On,
+                    "off" => ThpMode::Off,
+                    "never" => ThpMode::Never,
+                    _ => return Err(anyhow!("Invalid thp value in memory configuration, expected on, off or never: {}", kv[1])),
+                };
+            },
             _ => {
-                warn!("Unknown memory configuration option: {}", kv[0]);
+                return Err(anyhow!("Unknown memory configuration option '{}', expected size, shared, hugepages, hugepage_size, hotplug_size, hotplug_method or thp", kv[0].trim()));
             }
         }
     }
     
+    // A page size only makes sense for hugepages
+    if config.hugepage_size.is_some() {
+        config.hugepages = true;
+    }
+    
+    if config.size == 0 {
+        return Err(anyhow!("Invalid size in memory configuration, guest memory cannot be empty"));
+    }
+    
+    if method_given && config.hotplug_size.is_none() {
+        return Err(anyhow!("hotplug_method in memory configuration needs a hotplug_size to hotplug into"));
+    }
+    
+    // The VMM maps guest memory in whole pages, 2M at the least and the hugepage size above that
+    let alignment = config.hugepage_size.unwrap_or(0).max(MEMORY_ALIGNMENT);
+    config.size = align_memory_size("size", config.size, alignment)?;
+    if let Some(hotplug_size) = config.hotplug_size {
+        config.hotplug_size = Some(align_memory_size("hotplug_size", hotplug_size, alignment)?);
+    }
+    
     Ok(config)
 }
 
+/// Parse a CPU configuration string such as "max_phys_bits=46,features=amx"; the empty string
+/// leaves every setting to the VMM
+pub fn parse_cpu_string(cpu_config: &str) -> Result<CpuConfig> {
+    let mut config = CpuConfig::default();
+    
+    for part in cpu_config.split(',').filter(|part| !part.trim().is_empty()) {
+        let kv: Vec<&str> = part.splitn(2, '=').collect();
+        if kv.len() != 2 {
+            return Err(anyhow!("Invalid CPU configuration format: {}", part));
+        }
+        
+        match kv[0].trim() {
+            "max_phys_bits" => {
+                match kv[1].trim().parse::<u8>() {
+                    Ok(bits) if (32..=52).contains(&bits) => config.max_phys_bits = Some(bits),
+                    _ => return Err(anyhow!("Invalid max_phys_bits in CPU configuration, expected 32 to 52: {}", kv[1])),
+                }
+            },
+            "kvm_hyperv" => {
+                match kv[1].trim() {
+                    "on" | "true" | "yes" | "1" => config.kvm_hyperv = true,
+                    "off" | "false" | "no" | "0" => config.kvm_hyperv = false,
+                    _ => return Err(anyhow!("Invalid kvm_hyperv value in CPU configuration: {}", kv[1])),
+                }
+            },
+            "features" => {
+                // Several features would be separated by `:`, as `,` separates the options
+                for feature in kv[1].split(':').map(str::trim).filter(|feature| !feature.is_empty()) {
+                    match feature {
+                        "amx" => config.amx = true,
+                        _ => return Err(anyhow!("Unknown CPU feature '{}' in CPU configuration, expected amx", feature)),
+                    }
+                }
+            },
+            "topology" => {
+                let counts: Vec<u8> = kv[1].split(':')
+                    .map(|count| count.trim().parse::<u8>().ok().filter(|count| *count > 0))
+                    .collect::<Option<_>>()
+                    .unwrap_or_default();
+                let topology: [u8; 4] = counts.try_into()
+                    .map_err(|_| anyhow!("Invalid topology in CPU configuration, expected threads:cores:dies:packages: {}", kv[1]))?;
+                config.topology = Some(topology);
+            },
+            _ => {
+                return Err(anyhow!("Unknown CPU configuration option '{}', expected max_phys_bits, kvm_hyperv, features or topology", kv[0].trim()));
+            }
+        }
+    }
+    
+    Ok(config)
+}
+
+// Round `size` up to a multiple of `alignment`, saying so when that changes it
+fn align_memory_size(option: &str, size: u64, alignment: u64) -> Result<u64> {
+    let aligned = size.checked_next_multiple_of(alignment)
+        .ok_or_else(|| anyhow!("Memory {} is too large: {}", option, size))?;
+    if aligned != size {
+        warn!("Memory {} of {} bytes is not a multiple of {}, rounding it up to {}", option, size, size_label(alignment), size_label(aligned));
+    }
+    Ok(aligned)
+}
+
+/// Check that the hugetlbfs pool of `page_size` (the default pool without one) exists and has
+/// enough free pages for `size` bytes of guest memory. Pools other than the default one have
+/// to be set up on the kernel command line, as in `hugepagesz=1G hugepages=64`.
+fn check_hugepage_pool(page_size: Option<u64>, size: u64) -> Result<()> {
+    let page_size = match page_size {
+        Some(page_size) => page_size,
+        None => {
+            let meminfo = std::fs::read_to_string("/proc/meminfo").context("Failed to read /proc/meminfo")?;
+            meminfo.lines()
+                .find_map(|line| line.strip_prefix("Hugepagesize:")?.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kib| kib * 1024)
+                .ok_or_else(|| anyhow!(HypervisorError::ConfigError("The host kernel has no hugepage support".to_string())))?
+        },
+    };
+    let label = size_label(page_size);
+    let pool = format!("/sys/kernel/mm/hugepages/hugepages-{}kB", page_size / 1024);
+    let free: u64 = match std::fs::read_to_string(format!("{}/free_hugepages", pool)) {
+        Ok(free) => free.trim().parse().context(format!("Invalid free_hugepages in {}", pool))?,
+        Err(_) => return Err(anyhow!(HypervisorError::ConfigError(
+            format!("The host has no {} hugepage pool; reserve one with hugepagesz={} hugepages=<count> on the kernel command line", label, label)
+        ))),
+    };
+    let needed = size.div_ceil(page_size);
+    if free < needed {
+        return Err(anyhow!(HypervisorError::ConfigError(
+            format!("The {} hugepage pool has {} free pages, guest memory needs {}", label, free, needed)
+        )));
+    }
+    Ok(())
+}
+
+/// Size with the largest binary suffix that divides it, as in "2M" or "1G"
+pub fn size_label(bytes: u64) -> String {
+    let units = [(1 << 40, "T"), (1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    units.iter()
+        .find(|(unit, _)| bytes.is_multiple_of(*unit))
+        .map(|(unit, suffix)| format!("{}{}", bytes / unit, suffix))
+        .unwrap_or_else(|| bytes.to_string())
+}
+
+/// What the vCPUs of the VM show the guest; None and false leave a setting to the VMM, which
+/// passes on what the host CPU has
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuConfig {
+    /// Physical address bits the guest sees; fewer than the host has gives guests on different
+    /// hosts the same address width
+    pub max_phys_bits: Option<u8>,
+    
+    /// Hyper-V enlightenments, for Windows guests
+    pub kvm_hyperv: bool,
+    
+    /// Advanced Matrix Extensions, which the VMM hides unless asked for
+    pub amx: bool,
+    
+    /// Threads per core, cores per die, dies per package and packages
+    pub topology: Option<[u8; 4]>,
+}
+
+/// How guest memory outside hugetlbfs uses Transparent Huge Pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThpMode {
+    /// The VMM asks for huge pages with `madvise(MADV_HUGEPAGE)`, which the `madvise` and `always`
+    /// host policies grant
+    On,
+    /// No advice; the host policy alone decides
+    Off,
+    /// THP is disabled for the hypervisor process, even under the `always` policy
+    Never,
+}
+
+/// How memory is hotplugged into a running guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugMethod {
+    /// ACPI memory hotplug; the guest can take memory but never give it back
+    Acpi,
+    /// A virtio-mem device, through which the guest can also unplug memory again
+    VirtioMem,
+}
+
 /// Configuration for VM memory
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MemoryConfig {
     /// Memory size in bytes
     pub size: u64,
@@ -590,6 +2141,537 @@ pub struct MemoryConfig {
     /// Whether to use hugepages
     pub hugepages: bool,
     
+    /// Page size of the hugepages in bytes; None takes the default size of the host
+    pub hugepage_size: Option<u64>,
+    
+    /// Bytes of memory that can be hotplugged into the guest on top of `size`
+    pub hotplug_size: Option<u64>,
+    
+    /// How hotplugged memory reaches the guest
+    pub hotplug_method: HotplugMethod,
+    
+    /// Transparent Huge Pages for guest memory that is not on hugetlbfs
+    pub thp: ThpMode,
+    
     /// Size of shared memory region (if used)
     pub shared_memory_size: Option<u64>,
-}
\ No newline at end of file
+}
+
+/// Configuration for an additional VM disk
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskConfig {
+    /// Path to the disk image or block device
+    pub path: String,
+    
+    /// Whether the disk is exposed read-only
+    #[serde(default)]
+    pub readonly: bool,
+    
+    /// Device identifier (defaults to diskN)
+    pub id: Option<String>,
+    
+    /// Expected image format, verified against the image header
+    pub format: Option<DiskFormat>,
+    
+    /// Open the image with O_DIRECT, bypassing the host page cache
+    pub direct: Option<bool>,
+    
+    /// Host page cache mode (alternative spelling of `direct`)
+    pub cache: Option<DiskCache>,
+    
+    /// IO engine used by the virtio-block backend
+    pub aio: Option<DiskIoEngine>,
+    
+    /// Number of virtio queues; one per vCPU when unset
+    pub num_queues: Option<usize>,
+    
+    /// Descriptors in each virtio queue, a power of two
+    pub queue_size: Option<u16>,
+    
+    /// Bandwidth limit in bytes per second (K/M/G suffixes allowed)
+    pub bandwidth: Option<String>,
+    
+    /// Operations per second limit
+    pub iops: Option<u64>,
+    
+    /// Rate limit group shared with other disks
+    pub rate_limit_group: Option<String>,
+}
+
+impl DiskConfig {
+    /// Device identifier, falling back to the disk's position in the list
+    pub fn id_or_default(&self, index: usize) -> String {
+        self.id.clone().unwrap_or_else(|| format!("disk{}", index))
+    }
+    
+    /// Whether the host page cache is bypassed
+    pub fn is_direct(&self) -> bool {
+        self.direct.unwrap_or(self.cache == Some(DiskCache::None))
+    }
+}
+
+/// Host page cache modes for a disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskCache {
+    /// Bypass the page cache (O_DIRECT)
+    None,
+    /// Go through the page cache
+    Writeback,
+}
+
+/// IO engines for a disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiskIoEngine {
+    #[serde(rename = "io_uring")]
+    IoUring,
+    #[serde(rename = "threads")]
+    Threads,
+}
+
+/// Disk image formats understood by Cloud Hypervisor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+    Vhd,
+    Vhdx,
+}
+
+/// Parse a disk configuration string
+pub fn parse_disk_string(disk_config: &str) -> Result<DiskConfig> {
+    // Parse a string like "path=/var/lib/vllmd/cache.raw,readonly=on,id=cache,format=raw"
+    let mut config = DiskConfig::default();
+    
+    for part in disk_config.split(',') {
+        let kv: Vec<&str> = part.splitn(2, '=').collect();
+        if kv.len() != 2 {
+            return Err(anyhow!("Invalid disk configuration format: {}", part));
+        }
+        
+        match kv[0].trim() {
+            "path" => config.path = kv[1].trim().to_string(),
+            "readonly" => {
+                match kv[1].trim() {
+                    "on" | "true" | "yes" | "1" => config.readonly = true,
+                    "off" | "false" | "no" | "0" => config.readonly = false,
+                    _ => return Err(anyhow!("Invalid readonly value in disk configuration: {}", kv[1])),
+                }
+            },
+            "id" => config.id = Some(kv[1].trim().to_string()),
+            "format" => {
+                config.format = Some(match kv[1].trim() {
+                    "raw" => DiskFormat::Raw,
+                    "qcow2" => DiskFormat::Qcow2,
+                    "vhd" => DiskFormat::Vhd,
+                    "vhdx" => DiskFormat::Vhdx,
+                    _ => return Err(anyhow!("Invalid format value in disk configuration: {}", kv[1])),
+                });
+            },
+            "direct" => {
+                match kv[1].trim() {
+                    "on" | "true" | "yes" | "1" => config.direct = Some(true),
+                    "off" | "false" | "no" | "0" => config.direct = Some(false),
+                    _ => return Err(anyhow!("Invalid direct value in disk configuration: {}", kv[1])),
+                }
+            },
+            "cache" => {
+                config.cache = Some(match kv[1].trim() {
+                    "none" => DiskCache::None,
+                    "writeback" => DiskCache::Writeback,
+                    _ => return Err(anyhow!("Invalid cache value in disk configuration: {}", kv[1])),
+                });
+            },
+            "aio" => {
+                config.aio = Some(match kv[1].trim() {
+                    "io_uring" => DiskIoEngine::IoUring,
+                    "threads" => DiskIoEngine::Threads,
+                    _ => return Err(anyhow!("Invalid aio value in disk configuration: {}", kv[1])),
+                });
+            },
+            "num_queues" => {
+                config.num_queues = Some(kv[1].trim().parse::<usize>()
+                    .context(format!("Failed to parse num_queues: {}", kv[1]))?);
+            },
+            "queue_size" => {
+                config.queue_size = Some(kv[1].trim().parse::<u16>()
+                    .context(format!("Failed to parse queue_size: {}", kv[1]))?);
+            },
+            "bandwidth" => config.bandwidth = Some(kv[1].trim().to_string()),
+            "iops" => {
+                config.iops = Some(kv[1].trim().parse::<u64>()
+                    .context(format!("Failed to parse iops: {}", kv[1]))?);
+            },
+            "rate_limit_group" => config.rate_limit_group = Some(kv[1].trim().to_string()),
+            _ => {
+                return Err(anyhow!("Unknown disk configuration option: {}", kv[0]));
+            }
+        }
+    }
+    
+    if config.path.is_empty() {
+        return Err(anyhow!("Disk configuration is missing a path: {}", disk_config));
+    }
+    
+    Ok(config)
+}
+
+/// Part of guest memory with its own backing, such as the hugepages of one host NUMA node
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryZoneConfig {
+    /// Zone identifier
+    pub id: String,
+    
+    /// Size of the zone (K/M/G/T suffixes allowed)
+    pub size: String,
+    
+    /// Host NUMA node the memory of the zone is allocated on
+    pub host_numa_node: Option<u32>,
+    
+    /// Back the zone with hugepages
+    #[serde(default)]
+    pub hugepages: bool,
+    
+    /// File the zone is mapped from, such as one on a hugetlbfs or DAX mount
+    pub file: Option<String>,
+    
+    /// Map the zone shared; defaults to `shared` of the memory configuration
+    pub shared: Option<bool>,
+    
+    /// Guest NUMA node the zone belongs to
+    pub guest_numa_node: Option<u32>,
+    
+    /// vCPUs of that guest NUMA node, such as "0-15"
+    pub guest_cpus: Option<String>,
+}
+
+/// Parse a memory zone string
+pub fn parse_memory_zone_string(zone_config: &str) -> Result<MemoryZoneConfig> {
+    // Parse a string like "id=node0,size=256G,host_numa_node=0,hugepages=on,guest_numa_node=0,guest_cpus=0-31"
+    let mut config = MemoryZoneConfig::default();
+    
+    // guest_cpus may list several ranges, as in "guest_cpus=0-15:32-47"
+    for part in zone_config.split(',') {
+        let kv: Vec<&str> = part.splitn(2, '=').collect();
+        if kv.len() != 2 {
+            return Err(anyhow!("Invalid memory zone format: {}", part));
+        }
+        
+        let value = kv[1].trim();
+        let flag = |name: &str| match value {
+            "on" | "true" | "yes" | "1" => Ok(true),
+            "off" | "false" | "no" | "0" => Ok(false),
+            _ => Err(anyhow!("Invalid {} value in memory zone: {}", name, value)),
+        };
+        let node = |name: &str| value.parse::<u32>().context(format!("Failed to parse {}: {}", name, value));
+        match kv[0].trim() {
+            "id" => config.id = value.to_string(),
+            "size" => {
+                parse_size(value).context("Invalid size in memory zone")?;
+                config.size = value.to_string();
+            },
+            "host_numa_node" => config.host_numa_node = Some(node("host_numa_node")?),
+            "hugepages" => config.hugepages = flag("hugepages")?,
+            "file" => config.file = Some(value.to_string()),
+            "shared" => config.shared = Some(flag("shared")?),
+            "guest_numa_node" => config.guest_numa_node = Some(node("guest_numa_node")?),
+            "guest_cpus" => {
+                let cpus = value.replace(':', ",");
+                parse_cpu_list(&cpus)?;
+                config.guest_cpus = Some(cpus);
+            },
+            _ => {
+                return Err(anyhow!("Unknown memory zone option: {}", kv[0]));
+            }
+        }
+    }
+    
+    if config.id.is_empty() {
+        return Err(anyhow!("Memory zone is missing an id: {}", zone_config));
+    }
+    if config.size.is_empty() {
+        return Err(anyhow!("Memory zone {} is missing a size", config.id));
+    }
+    
+    Ok(config)
+}
+
+/// SGX Enclave Page Cache section of the guest, carved out of the host EPC through /dev/sgx_vepc
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SgxEpcConfig {
+    /// Section identifier
+    pub id: String,
+    
+    /// Size of the section (K/M/G suffixes allowed), a multiple of 4K
+    pub size: String,
+    
+    /// Populate the section at boot rather than on first use by an enclave
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+/// Parse an SGX EPC section string
+pub fn parse_sgx_epc_string(section_config: &str) -> Result<SgxEpcConfig> {
+    // Parse a string like "id=epc0,size=64M,prefault=on"
+    let mut config = SgxEpcConfig::default();
+    
+    for part in section_config.split(',') {
+        let kv: Vec<&str> = part.splitn(2, '=').collect();
+        if kv.len() != 2 {
+            return Err(anyhow!("Invalid SGX EPC section format: {}", part));
+        }
+        
+        let value = kv[1].trim();
+        match kv[0].trim() {
+            "id" => config.id = value.to_string(),
+            "size" => {
+                parse_size(value).context("Invalid size in SGX EPC section")?;
+                config.size = value.to_string();
+            },
+            "prefault" => {
+                config.prefault = match value {
+                    "on" | "true" | "yes" | "1" => true,
+                    "off" | "false" | "no" | "0" => false,
+                    _ => return Err(anyhow!("Invalid prefault value in SGX EPC section: {}", value)),
+                };
+            },
+            _ => {
+                return Err(anyhow!("Unknown SGX EPC section option: {}", kv[0]));
+            }
+        }
+    }
+    
+    if config.id.is_empty() {
+        return Err(anyhow!("SGX EPC section is missing an id: {}", section_config));
+    }
+    if config.size.is_empty() {
+        return Err(anyhow!("SGX EPC section {} is missing a size", config.id));
+    }
+    
+    Ok(config)
+}
+
+/// Total size in bytes of the memory zones
+pub fn memory_zones_size(zones: &[MemoryZoneConfig]) -> Result<u64> {
+    zones.iter().try_fold(0u64, |total, zone| {
+        let size = parse_size(zone.size.trim()).context(format!("Invalid size of memory zone {}", zone.id))?;
+        Ok(total.saturating_add(size))
+    })
+}
+
+/// vCPU indices of a list such as "0-15,32-47"
+fn parse_cpu_list(cpus: &str) -> Result<Vec<u32>> {
+    let mut list = Vec::new();
+    for range in cpus.split(',').map(str::trim) {
+        let number = |cpu: &str| cpu.trim().parse::<u32>().context(format!("Invalid vCPU list: {}", cpus));
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(range)?, number(range)?),
+        };
+        if first > last {
+            return Err(anyhow!("Invalid vCPU range {} in {}", range, cpus));
+        }
+        list.extend(first..=last);
+    }
+    Ok(list)
+}
+
+/// Rate limit group shared by several disks
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitGroupConfig {
+    /// Group identifier referenced by `rate_limit_group` on disks
+    pub id: String,
+    
+    /// Bandwidth limit in bytes per second (K/M/G suffixes allowed)
+    pub bandwidth: Option<String>,
+    
+    /// Operations per second limit
+    pub iops: Option<u64>,
+}
+
+/// Parse a rate limit group string
+pub fn parse_rate_limit_group_string(group_config: &str) -> Result<RateLimitGroupConfig> {
+    // Parse a string like "id=nvme0,bandwidth=1G,iops=20000"
+    let mut config = RateLimitGroupConfig::default();
+    
+    for part in group_config.split(',') {
+        let kv: Vec<&str> = part.splitn(2, '=').collect();
+        if kv.len() != 2 {
+            return Err(anyhow!("Invalid rate limit group format: {}", part));
+        }
+        
+        match kv[0].trim() {
+            "id" => config.id = kv[1].trim().to_string(),
+            "bandwidth" => config.bandwidth = Some(kv[1].trim().to_string()),
+            "iops" => {
+                config.iops = Some(kv[1].trim().parse::<u64>()
+                    .context(format!("Failed to parse iops: {}", kv[1]))?);
+            },
+            _ => {
+                return Err(anyhow!("Unknown rate limit group option: {}", kv[0]));
+            }
+        }
+    }
+    
+    if config.id.is_empty() {
+        return Err(anyhow!("Rate limit group is missing an id: {}", group_config));
+    }
+    
+    Ok(config)
+}
+
+/// Build Cloud Hypervisor rate limiter options, refilling the token buckets every second
+fn rate_limit_options(bandwidth: &Option<String>, iops: Option<u64>) -> Result<String> {
+    let mut options = String::new();
+    
+    if let Some(bandwidth) = bandwidth {
+        let bytes = parse_size(bandwidth.trim()).context("Invalid bandwidth")?;
+        options.push_str(&format!(",bw_size={},bw_refill_time=1000", bytes));
+    }
+    
+    if let Some(iops) = iops {
+        options.push_str(&format!(",ops_size={},ops_refill_time=1000", iops));
+    }
+    
+    Ok(options)
+}
+
+/// Detect the format of a disk image from its header (or footer, for fixed VHD)
+fn detect_disk_format(path: &str) -> Result<DiskFormat> {
+    let mut file = File::open(path)
+        .context(format!("Failed to open disk image: {}", path))?;
+    
+    let mut header = [0u8; 8];
+    let read = file.read(&mut header)
+        .context(format!("Failed to read disk image header: {}", path))?;
+    
+    if read >= 4 && header.starts_with(b"QFI\xfb") {
+        return Ok(DiskFormat::Qcow2);
+    }
+    if read == 8 && &header == b"vhdxfile" {
+        return Ok(DiskFormat::Vhdx);
+    }
+    if read == 8 && &header == b"conectix" {
+        return Ok(DiskFormat::Vhd);
+    }
+    
+    // Fixed VHD images only carry their footer at the end of the file
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    if len >= 512 {
+        let mut footer = [0u8; 8];
+        file.seek(SeekFrom::End(-512))
+            .and_then(|_| file.read_exact(&mut footer))
+            .context(format!("Failed to read disk image footer: {}", path))?;
+        if &footer == b"conectix" {
+            return Ok(DiskFormat::Vhd);
+        }
+    }
+    
+    Ok(DiskFormat::Raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_and_decimal_suffixes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("16G").unwrap(), 16 << 30);
+        assert_eq!(parse_size("16GiB").unwrap(), 16 << 30);
+        assert_eq!(parse_size("16gib").unwrap(), 16 << 30);
+        assert_eq!(parse_size("16GB").unwrap(), 16_000_000_000);
+        assert_eq!(parse_size("1TB").unwrap(), 1_000_000_000_000);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert!(parse_size("16Gi").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1G").is_err());
+    }
+
+    #[test]
+    fn sizes_that_overflow_are_rejected() {
+        assert_eq!(parse_size("16777215T").unwrap(), 16777215 << 40);
+        let error = parse_size("16777216T").unwrap_err();
+        assert_eq!(error.to_string(), "Size is too large: 16777216T");
+        assert!(parse_size("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn memory_sizes_are_rounded_up_to_the_page_size() {
+        let config = parse_memory_string("size=16GB").unwrap();
+        assert_eq!(config.size, 16_000_000_000u64.next_multiple_of(MEMORY_ALIGNMENT));
+        assert_eq!(config.size % MEMORY_ALIGNMENT, 0);
+
+        let config = parse_memory_string("size=1025M,hotplug_size=1M").unwrap();
+        assert_eq!(config.size, 1026 << 20);
+        assert_eq!(config.hotplug_size, Some(2 << 20));
+
+        // 1G hugepages align to 1G, and set hugepages on their own
+        let config = parse_memory_string("size=3G,hugepage_size=1G").unwrap();
+        assert!(config.hugepages);
+        assert_eq!(config.size, 3 << 30);
+        let config = parse_memory_string("size=2500M,hugepage_size=1G").unwrap();
+        assert_eq!(config.size, 3 << 30);
+    }
+
+    #[test]
+    fn memory_string_options() {
+        let config = parse_memory_string("size=8G,shared=on,hotplug_size=64G,hotplug_method=virtio-mem,thp=never").unwrap();
+        assert_eq!(config.size, 8 << 30);
+        assert!(config.shared);
+        assert!(!config.hugepages);
+        assert_eq!(config.hotplug_size, Some(64 << 30));
+        assert_eq!(config.hotplug_method, HotplugMethod::VirtioMem);
+        assert_eq!(config.thp, ThpMode::Never);
+
+        let config = parse_memory_string("size=8G").unwrap();
+        assert_eq!(config.hotplug_method, HotplugMethod::Acpi);
+        assert_eq!(config.thp, ThpMode::On);
+    }
+
+    #[test]
+    fn bad_memory_strings_are_rejected() {
+        for memory in ["size=0", "size", "size=1G,hotplug=4G", "size=1G,shared=maybe", "size=1G,hugepage_size=3M",
+                       "size=1G,hugepage_size=1K", "size=1G,hotplug_method=virtio-mem", "size=1G,thp=always"] {
+            assert!(parse_memory_string(memory).is_err(), "{}", memory);
+        }
+        let error = parse_memory_string("size=1G,hotplug_method=virtio-mem").unwrap_err();
+        assert!(error.to_string().contains("needs a hotplug_size"), "{}", error);
+    }
+
+    #[test]
+    fn cpu_string_options() {
+        assert_eq!(parse_cpu_string("").unwrap(), CpuConfig::default());
+        let config = parse_cpu_string("max_phys_bits=46,kvm_hyperv=on,features=amx,topology=2:8:1:2").unwrap();
+        assert_eq!(config.max_phys_bits, Some(46));
+        assert!(config.kvm_hyperv);
+        assert!(config.amx);
+        assert_eq!(config.topology, Some([2, 8, 1, 2]));
+
+        for cpu in ["max_phys_bits=31", "max_phys_bits=53", "features=avx512", "topology=2:8:1", "topology=2:0:1:1", "smt=on"] {
+            assert!(parse_cpu_string(cpu).is_err(), "{}", cpu);
+        }
+    }
+
+    #[test]
+    fn disk_string_options() {
+        let config = parse_disk_string("path=/var/lib/vllmd/cache.raw,readonly=on,id=cache,format=raw,cache=none,aio=io_uring,num_queues=4,queue_size=256,iops=1000").unwrap();
+        assert_eq!(config.path, "/var/lib/vllmd/cache.raw");
+        assert!(config.readonly);
+        assert_eq!(config.id.as_deref(), Some("cache"));
+        assert_eq!(config.format, Some(DiskFormat::Raw));
+        assert_eq!(config.cache, Some(DiskCache::None));
+        assert_eq!(config.num_queues, Some(4));
+        assert_eq!(config.queue_size, Some(256));
+        assert_eq!(config.iops, Some(1000));
+
+        for disk in ["readonly=on", "path=/a,readonly=maybe", "path=/a,format=vmdk", "path=/a,queue_size=65536", "path=/a,serial=x", "path"] {
+            assert!(parse_disk_string(disk).is_err(), "{}", disk);
+        }
+    }
+}
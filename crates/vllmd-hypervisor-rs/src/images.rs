@@ -0,0 +1,374 @@
+use anyhow::{Result, anyhow, bail, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::http;
+use crate::oci::{self, OciReference};
+
+// zstd frame magic number (little-endian 0xFD2FB528)
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Prefix of the references of system images made by `build-image`, which only the store has
+pub const BUILT_SCHEME: &str = "image://";
+
+/// Content-addressed store for kernels and disk images fetched from remote sources
+pub struct ImageStore {
+    /// Root directory of the store
+    root: PathBuf,
+    /// Shared lock on the store, held while it is open so a prune waits for the pulls of other
+    /// processes
+    lock: File,
+}
+
+/// Entry of the store index mapping a reference to the blob it resolved to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageRef {
+    /// Digest of the blob ("sha256:<hex>")
+    pub digest: String,
+
+    /// Validator used to detect changes of the source: the HTTP entity tag for URLs, or the
+    /// size and modification time of a local compressed image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+}
+
+impl ImageStore {
+    /// Open the image store, creating its directories if needed
+    pub fn open() -> Result<Self> {
+        let root = store_root();
+
+        for dir in ["blobs/sha256", "downloads"] {
+            std::fs::create_dir_all(root.join(dir))
+                .context(format!("Failed to create image store directory: {}", root.join(dir).display()))?;
+        }
+
+        let lock_path = root.join("lock");
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+            .context(format!("Failed to open {}", lock_path.display()))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_SH) } != 0 {
+            return Err(std::io::Error::last_os_error()).context(format!("Failed to lock {}", lock_path.display()));
+        }
+
+        Ok(Self { root, lock })
+    }
+
+    /// Path of the blob with the given digest ("sha256:<hex>")
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let hex = digest.strip_prefix("sha256:")
+            .ok_or_else(|| anyhow!("Unsupported digest algorithm: {}", digest))?;
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid digest: {}", digest);
+        }
+
+        Ok(self.root.join("blobs/sha256").join(hex))
+    }
+
+    /// Path where an in-progress download is staged before it is committed
+    pub fn download_path(&self, name: &str) -> PathBuf {
+        self.root.join("downloads").join(name)
+    }
+
+    /// Move a completed download into the store, verifying its digest if one is expected
+    pub fn commit(&self, download: &Path, expected_digest: Option<&str>) -> Result<String> {
+        let digest = sha256_file(download)?;
+
+        if let Some(expected) = expected_digest {
+            if digest != expected {
+                let _ = std::fs::remove_file(download);
+                bail!("Digest mismatch for {}: expected {}, got {}", download.display(), expected, digest);
+            }
+        }
+
+        let blob = self.blob_path(&digest)?;
+        std::fs::rename(download, &blob)
+            .context(format!("Failed to move {} into the image store", download.display()))?;
+
+        Ok(digest)
+    }
+
+    /// Blob path previously recorded for a reference, if it is still present
+    pub fn lookup(&self, reference: &str) -> Result<Option<PathBuf>> {
+        Ok(self.lookup_ref(reference)?.map(|(path, _)| path))
+    }
+
+    /// Index entry and blob path for a reference, if the blob is still present
+    pub fn lookup_ref(&self, reference: &str) -> Result<Option<(PathBuf, ImageRef)>> {
+        match self.read_refs()?.remove(reference) {
+            Some(entry) => {
+                let blob = self.blob_path(&entry.digest)?;
+                Ok(if blob.exists() { Some((blob, entry)) } else { None })
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Record that a reference resolves to the given digest
+    pub fn tag(&self, reference: &str, digest: &str, etag: Option<&str>) -> Result<()> {
+        let mut refs = self.read_refs()?;
+        refs.insert(reference.to_string(), ImageRef {
+            digest: digest.to_string(),
+            etag: etag.map(String::from),
+        });
+        self.write_refs(&refs)
+    }
+
+    /// All references recorded in the store, with the size of their blob (None if missing)
+    pub fn list(&self) -> Result<Vec<(String, ImageRef, Option<u64>)>> {
+        let mut images = Vec::new();
+        for (reference, entry) in self.read_refs()? {
+            let size = std::fs::metadata(self.blob_path(&entry.digest)?).ok().map(|m| m.len());
+            images.push((reference, entry, size));
+        }
+        Ok(images)
+    }
+
+    /// Drop references not in `keep`, then delete blobs and partial downloads nothing refers to
+    pub fn prune(&self, keep: &HashSet<String>) -> Result<PruneSummary> {
+        // Until a pull finishes, its partial download and its blob before it is tagged look
+        // unreferenced; the exclusive lock waits for every other process to close the store
+        let fd = self.lock.as_raw_fd();
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            info!("Waiting for pulls into the image store to finish");
+            if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("Failed to lock the image store");
+            }
+        }
+
+        let mut summary = PruneSummary::default();
+
+        let mut refs = self.read_refs()?;
+        refs.retain(|reference, _| {
+            let keep = keep.contains(reference);
+            if !keep {
+                summary.references.push(reference.clone());
+            }
+            keep
+        });
+        self.write_refs(&refs)?;
+
+        let referenced: HashSet<&str> = refs.values().map(|entry| entry.digest.as_str()).collect();
+
+        for dir in ["blobs/sha256", "downloads"] {
+            let dir = self.root.join(dir);
+            let entries = std::fs::read_dir(&dir)
+                .context(format!("Failed to read {}", dir.display()))?;
+
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if dir.ends_with("sha256") && referenced.contains(format!("sha256:{}", name).as_str()) {
+                    continue;
+                }
+
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                std::fs::remove_file(entry.path())
+                    .context(format!("Failed to remove {}", entry.path().display()))?;
+                summary.files += 1;
+                summary.bytes += size;
+            }
+        }
+
+        Ok(summary)
+    }
+
+    fn refs_path(&self) -> PathBuf {
+        self.root.join("refs.json")
+    }
+
+    fn read_refs(&self) -> Result<BTreeMap<String, ImageRef>> {
+        let path = self.refs_path();
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .context(format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .context(format!("Failed to parse {}", path.display()))
+    }
+
+    fn write_refs(&self, refs: &BTreeMap<String, ImageRef>) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a truncated index
+        let path = self.refs_path();
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(refs)?)
+            .context(format!("Failed to write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &path)
+            .context(format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Result of an image store prune
+#[derive(Debug, Default)]
+pub struct PruneSummary {
+    /// References that were dropped
+    pub references: Vec<String>,
+
+    /// Number of blob and partial download files deleted
+    pub files: usize,
+
+    /// Bytes reclaimed
+    pub bytes: u64,
+}
+
+/// Key under which a remote location is recorded in the store, or None for local paths
+pub fn store_key(location: &str) -> Result<Option<String>> {
+    if location.starts_with("oci://") {
+        return Ok(Some(OciReference::parse(location)?.to_string()));
+    }
+
+    if location.starts_with("https://") || location.starts_with("http://") || location.starts_with(BUILT_SCHEME) {
+        return Ok(Some(location.to_string()));
+    }
+
+    Ok(None)
+}
+
+/// Every key under which the store holds what `location` resolves to: the reference of a remote
+/// location, and the decompressed copy of a zstd image, whether the compressed image is a local
+/// file or a blob the remote reference points at. Nothing is fetched or decompressed.
+pub fn store_keys(location: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let path = match store_key(location)? {
+        Some(key) => {
+            let blob = ImageStore::open()?.lookup(&key)?;
+            keys.push(key);
+            blob
+        },
+        None => Some(PathBuf::from(location)),
+    };
+    if let Some(path) = path.filter(|path| is_zstd(path)) {
+        keys.push(decompressed_key(&ImageStore::open()?, &path)?.0);
+    }
+    Ok(keys)
+}
+
+/// Compute the "sha256:<hex>" digest of a file
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .context(format!("Failed to open {}", path.display()))?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)
+            .context(format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// Resolve a kernel or image location to a local path, fetching remote references into the store
+/// and decompressing zstd-compressed images
+pub fn resolve_image_path(location: &str) -> Result<String> {
+    let path = if location.starts_with("oci://") {
+        let store = ImageStore::open()?;
+        let reference = OciReference::parse(location)?;
+
+        match store.lookup(&reference.to_string())? {
+            Some(path) => path,
+            None => {
+                info!("Image {} is not cached, pulling", location);
+                oci::pull(&store, &reference)?
+            },
+        }
+    } else if location.starts_with("https://") || location.starts_with("http://") {
+        let store = ImageStore::open()?;
+        http::fetch(&store, location)?
+    } else if location.starts_with(BUILT_SCHEME) {
+        ImageStore::open()?.lookup(location)?
+            .ok_or_else(|| anyhow!("{} is not in the image store; create it with build-image", location))?
+    } else {
+        PathBuf::from(location)
+    };
+
+    let path = if is_zstd(&path) {
+        decompress(&ImageStore::open()?, &path)?
+    } else {
+        path
+    };
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Whether a path lies inside the image store, and is therefore shared between runtimes
+pub fn is_store_path(path: &str) -> bool {
+    Path::new(path).starts_with(store_root())
+}
+
+/// Root directory of the image store
+fn store_root() -> PathBuf {
+    match std::env::var("XDG_DATA_HOME") {
+        Ok(data_home) => PathBuf::from(data_home).join("vllmd/images"),
+        Err(_) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".local/share/vllmd/images"),
+    }
+}
+
+/// Whether a file starts with the zstd frame magic number
+fn is_zstd(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    match File::open(path) {
+        Ok(mut file) => file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// Key of the decompressed copy of a zstd image in the store, and the validator telling whether
+/// the copy is still current
+fn decompressed_key(store: &ImageStore, path: &Path) -> Result<(String, Option<String>)> {
+    // Blobs are immutable, so their digest identifies the source. Local files are keyed by
+    // path and revalidated by size and modification time.
+    Ok(match path.parent() {
+        Some(parent) if parent == store.root.join("blobs/sha256") => {
+            let hex = path.file_name().unwrap_or_default().to_string_lossy();
+            (format!("zstd:sha256:{}", hex), None)
+        },
+        _ => {
+            let path = std::fs::canonicalize(path)
+                .context(format!("Failed to resolve {}", path.display()))?;
+            let metadata = std::fs::metadata(&path)
+                .context(format!("Failed to read metadata of {}", path.display()))?;
+            let modified = metadata.modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            (format!("zstd:file://{}", path.display()), Some(format!("{}-{}", metadata.len(), modified)))
+        },
+    })
+}
+
+/// Decompress a zstd image into the store, reusing a previous decompression of the same source
+fn decompress(store: &ImageStore, path: &Path) -> Result<PathBuf> {
+    let (key, validator) = decompressed_key(store, path)?;
+
+    if let Some((blob, entry)) = store.lookup_ref(&key)? {
+        if entry.etag == validator {
+            return Ok(blob);
+        }
+    }
+
+    info!("Decompressing {}", path.display());
+
+    let download = store.download_path(&format!("{:x}.zst-decode", Sha256::digest(key.as_bytes())));
+    let source = File::open(path)
+        .context(format!("Failed to open {}", path.display()))?;
+    let mut output = File::create(&download)
+        .context(format!("Failed to create {}", download.display()))?;
+    zstd::stream::copy_decode(source, &mut output)
+        .context(format!("Failed to decompress {}", path.display()))?;
+
+    let digest = store.commit(&download, None)?;
+    store.tag(&key, &digest, validator.as_deref())?;
+
+    store.blob_path(&digest)
+}
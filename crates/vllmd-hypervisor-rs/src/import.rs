@@ -0,0 +1,708 @@
+use anyhow::{Result, anyhow, bail, Context};
+use roxmltree::{Document, Node};
+use std::collections::BTreeMap;
+
+use crate::manifest::RuntimeConfig;
+
+/// Children of `<domain>` that have no bearing on a Cloud Hypervisor VM
+const LIBVIRT_IGNORED: &[&str] = &[
+    "uuid", "title", "description", "metadata", "currentMemory", "on_poweroff", "on_crash", "clock",
+    "features", "pm", "resource", "seclabel", "genid",
+];
+
+/// Devices every VM has or that the guest does not see
+const LIBVIRT_IGNORED_DEVICES: &[&str] = &["emulator", "controller", "input", "console", "serial"];
+
+/// QEMU options without an argument
+const QEMU_FLAGS: &[&str] = &[
+    "enable-kvm", "nographic", "daemonize", "nodefaults", "no-user-config", "no-reboot", "no-shutdown", "S", "s",
+    "snapshot", "no-hpet", "no-acpi", "full-screen",
+];
+
+/// QEMU options for what the hypervisor sets up itself: acceleration, console, monitor and PID file
+const QEMU_IGNORED: &[&str] = &[
+    "enable-kvm", "accel", "machine", "M", "nographic", "display", "vga", "serial", "chardev", "monitor", "qmp",
+    "daemonize", "pidfile", "nodefaults", "no-user-config", "boot", "runas", "D", "msg", "uuid", "tpmdev",
+];
+
+/// Devices QEMU attaches `-drive` disks with, which are imported from the `-drive` options
+const QEMU_DISK_DEVICES: &[&str] = &[
+    "virtio-blk-pci", "virtio-blk", "virtio-blk-device", "virtio-scsi-pci", "scsi-hd", "ide-hd", "nvme",
+];
+
+/// A `[[runtimes]]` entry translated from the VM definition of another tool
+#[derive(Debug, Clone)]
+pub struct Imported {
+    /// The entry, without `index`
+    pub runtime: toml::Table,
+    /// What the definition has that the entry leaves out or changes, one line each
+    pub notes: Vec<String>,
+}
+
+impl Imported {
+    fn new() -> Self {
+        Self { runtime: toml::Table::new(), notes: Vec::new() }
+    }
+
+    /// The entry as TOML to append to a configuration file, checked to load as one
+    pub fn to_toml(&self) -> Result<String> {
+        toml::Value::Table(self.runtime.clone()).try_into::<RuntimeConfig>()
+            .context("The imported runtime is not a valid [[runtimes]] entry")?;
+        let mut document = toml::Table::new();
+        document.insert("runtimes".to_string(), toml::Value::Array(vec![toml::Value::Table(self.runtime.clone())]));
+        Ok(toml::to_string(&document)?)
+    }
+
+    fn set(&mut self, key: &str, value: impl Into<toml::Value>) {
+        self.runtime.insert(key.to_string(), value.into());
+    }
+
+    fn push(&mut self, key: &str, value: impl Into<toml::Value>) {
+        let entry = self.runtime.entry(key.to_string()).or_insert_with(|| toml::Value::Array(Vec::new()));
+        if let toml::Value::Array(values) = entry {
+            values.push(value.into());
+        }
+    }
+
+    // Runtime names only have letters, digits, - and _
+    fn set_name(&mut self, name: &str) {
+        let runtime: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+            .collect();
+        if runtime != name {
+            self.notes.push(format!("{} is named {} as runtime names only have letters, digits, - and _", name, runtime));
+        }
+        self.set("name", runtime);
+    }
+
+    // Memory in whole GiB, rounded up
+    fn set_memory(&mut self, bytes: u64) {
+        let gb = bytes.div_ceil(1 << 30);
+        if gb << 30 != bytes {
+            self.notes.push(format!("Memory rounded up from {} MiB to {} GiB", bytes >> 20, gb));
+        }
+        self.set("memory_gb", gb as i64);
+    }
+
+    // A plain count when every vCPU is online at boot, `{ boot, max }` otherwise
+    fn set_cpus(&mut self, boot: u8, max: u8) {
+        if boot >= max {
+            self.set("cpus", max as i64);
+            return;
+        }
+        let mut cpus = toml::Table::new();
+        cpus.insert("boot".to_string(), (boot as i64).into());
+        cpus.insert("max".to_string(), (max as i64).into());
+        self.set("cpus", cpus);
+    }
+
+    // The first disk becomes the system image, the others additional disks
+    fn add_disk(&mut self, disk: toml::Table, label: &str) {
+        if self.runtime.contains_key("system_image") {
+            self.push("disks", disk);
+            return;
+        }
+        // The system image takes no disk settings
+        let dropped: Vec<&str> = disk.keys().map(String::as_str).filter(|key| !matches!(*key, "path" | "id")).collect();
+        if !dropped.is_empty() {
+            self.notes.push(format!("Not imported: {} of system image {}", dropped.join(", "), label));
+        }
+        if let Some(path) = disk.get("path") {
+            self.set("system_image", path.clone());
+        }
+    }
+}
+
+/// Translate libvirt domain XML (`virsh dumpxml`) into a runtime: name, memory, vCPUs, direct
+/// kernel boot or firmware, file and block disks, PCI host devices, vsock and vTPM
+pub fn libvirt(xml: &str) -> Result<Imported> {
+    let document = Document::parse(xml).context("Failed to parse domain XML")?;
+    let domain = document.root_element();
+    if domain.tag_name().name() != "domain" {
+        bail!("Expected a <domain> element, found <{}>", domain.tag_name().name());
+    }
+
+    let mut imported = Imported::new();
+    for element in domain.children().filter(Node::is_element) {
+        match element.tag_name().name() {
+            "name" => imported.set_name(text(element, "name")?),
+            "memory" => {
+                imported.set_memory(libvirt_size(text(element, "memory")?, element.attribute("unit"))?);
+            },
+            "vcpu" => {
+                let value = text(element, "vcpu")?;
+                let count: u8 = value.parse().map_err(|_| anyhow!("Unsupported vCPU count: {}", value))?;
+                let current = match element.attribute("current") {
+                    Some(current) => current.parse().map_err(|_| anyhow!("Unsupported vCPU count: {}", current))?,
+                    None => count,
+                };
+                imported.set_cpus(current, count);
+            },
+            "os" => libvirt_os(element, &mut imported)?,
+            "on_reboot" => match text(element, "on_reboot")? {
+                "restart" => {},
+                "destroy" => imported.set("on_reboot", "stop"),
+                other => imported.notes.push(format!("Not imported: on_reboot {}", other)),
+            },
+            "devices" => {
+                for device in element.children().filter(Node::is_element) {
+                    match device.tag_name().name() {
+                        "disk" => libvirt_disk(device, &mut imported)?,
+                        "hostdev" => libvirt_hostdev(device, &mut imported)?,
+                        "vsock" => match device.children().find(|child| child.has_tag_name("cid")).and_then(|cid| cid.attribute("address")) {
+                            Some(cid) => imported.set("vsock_cid", cid.parse::<i64>().context(format!("Invalid vsock CID: {}", cid))?),
+                            None => imported.notes.push("Not imported: <vsock> without a fixed CID".to_string()),
+                        },
+                        "tpm" => imported.set("tpm", true),
+                        "memballoon" if device.attribute("freePageReporting") == Some("on") => imported.set("free_page_reporting", true),
+                        "memballoon" => {},
+                        "rng" => match device.children().find(|child| child.has_tag_name("backend")) {
+                            Some(backend) if backend.attribute("model") == Some("random") => {
+                                if let Some(source) = backend.text() {
+                                    imported.set("rng", source.trim());
+                                }
+                            },
+                            _ => imported.notes.push(format!("Not imported: <rng>{}", describe(device))),
+                        },
+                        name if LIBVIRT_IGNORED_DEVICES.contains(&name) => {},
+                        name => imported.notes.push(format!("Not imported: <{}>{}", name, describe(device))),
+                    }
+                }
+            },
+            name if LIBVIRT_IGNORED.contains(&name) => {},
+            name => imported.notes.push(format!("Not imported: <{}>", name)),
+        }
+    }
+    if !imported.runtime.contains_key("name") {
+        bail!("The domain has no <name>");
+    }
+    Ok(imported)
+}
+
+/// Translate a QEMU command line into a runtime: `-name`, `-m`, `-smp`, `-kernel`, `-initrd`,
+/// `-append`, `-bios`, `-snapshot`, `-drive`, and `-device` for VFIO, vsock, the balloon and the vTPM. Words
+/// up to the QEMU binary, such as `sudo` or environment assignments, are skipped.
+pub fn qemu_args(args: &[String]) -> Result<Imported> {
+    let start = args.iter().position(|arg| is_qemu(arg)).map(|binary| binary + 1)
+        .or_else(|| args.iter().position(|arg| arg.starts_with('-')))
+        .unwrap_or(args.len());
+    let mut imported = Imported::new();
+    let mut options = args[start..].iter();
+    while let Some(arg) = options.next() {
+        let Some(option) = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) else {
+            imported.notes.push(format!("Not imported: {}", arg));
+            continue;
+        };
+        if option == "snapshot" {
+            // Writes go to a throwaway overlay
+            imported.set("ephemeral", true);
+            continue;
+        }
+        if option == "no-reboot" {
+            // QEMU exits instead of rebooting
+            imported.set("on_reboot", "stop");
+            continue;
+        }
+        if QEMU_FLAGS.contains(&option) {
+            if !QEMU_IGNORED.contains(&option) {
+                imported.notes.push(format!("Not imported: -{}", option));
+            }
+            continue;
+        }
+        let value = options.next().ok_or_else(|| anyhow!("-{} needs a value", option))?;
+        match option {
+            "name" => match qemu_value(value, "guest").remove("guest") {
+                Some(name) => imported.set_name(&name),
+                None => imported.notes.push(format!("Not imported: -name {}", value)),
+            },
+            "m" => {
+                let mut memory = qemu_value(value, "size");
+                let size = memory.remove("size").ok_or_else(|| anyhow!("-m {} has no size", value))?;
+                // Megabytes without a suffix
+                let bytes = if size.chars().all(|c| c.is_ascii_digit()) {
+                    size.parse::<u64>().ok().and_then(|size| size.checked_mul(1 << 20))
+                        .ok_or_else(|| anyhow!("Invalid memory size: {}", size))?
+                } else {
+                    crate::hypervisor::parse_size(&size).context(format!("Invalid memory size in -m {}", value))?
+                };
+                imported.set_memory(bytes);
+                for (key, value) in memory {
+                    imported.notes.push(format!("Not imported: -m {}={}", key, value));
+                }
+            },
+            "smp" => {
+                let mut smp = qemu_value(value, "cpus");
+                let count = match smp.remove("cpus") {
+                    Some(cpus) => cpus.parse::<u8>().map_err(|_| anyhow!("Unsupported vCPU count: {}", cpus))?,
+                    None => {
+                        let mut count: u64 = 1;
+                        for key in ["sockets", "dies", "clusters", "cores", "threads"] {
+                            count *= smp.get(key).map_or(Ok(1), |value| value.parse::<u64>())
+                                .context(format!("Invalid -smp {}", value))?;
+                        }
+                        u8::try_from(count).map_err(|_| anyhow!("Unsupported vCPU count: {}", count))?
+                    },
+                };
+                let max = match smp.remove("maxcpus") {
+                    Some(max) => max.parse::<u8>().map_err(|_| anyhow!("Unsupported vCPU count: {}", max))?,
+                    None => count,
+                };
+                imported.set_cpus(count, max);
+                for (key, value) in smp {
+                    imported.notes.push(format!("Not imported: -smp {}={}", key, value));
+                }
+            },
+            "kernel" => imported.set("kernel", value.as_str()),
+            "initrd" => imported.set("initramfs", value.as_str()),
+            "append" => imported.set("cmdline", value.as_str()),
+            "bios" => imported.set("firmware", value.as_str()),
+            "drive" => qemu_drive(value, &mut imported),
+            "device" => qemu_device(value, &mut imported),
+            "cpu" if value == "host" || value.starts_with("host,") => {},
+            option if QEMU_IGNORED.contains(&option) => {},
+            option => imported.notes.push(format!("Not imported: -{} {}", option, value)),
+        }
+    }
+    Ok(imported)
+}
+
+// A `-drive`: a disk, or the firmware for the first pflash drive
+fn qemu_drive(value: &str, imported: &mut Imported) {
+    let mut drive = qemu_value(value, "file");
+    let Some(path) = drive.remove("file") else {
+        imported.notes.push(format!("Not imported: -drive {} without a file", value));
+        return;
+    };
+    let label = drive.get("id").cloned().unwrap_or_else(|| path.clone());
+    match (drive.remove("if").as_deref(), drive.remove("media").as_deref()) {
+        (Some("pflash"), _) if !imported.runtime.contains_key("firmware") => {
+            imported.set("firmware", path);
+            return;
+        },
+        (Some("pflash"), _) => {
+            imported.notes.push(format!("Not imported: pflash drive {}", path));
+            return;
+        },
+        (_, Some("cdrom")) => {
+            imported.notes.push(format!("Not imported: cdrom {}", path));
+            return;
+        },
+        _ => {},
+    }
+
+    let mut entry = toml::Table::new();
+    entry.insert("path".to_string(), path.into());
+    for (key, value) in drive {
+        match (key.as_str(), value.as_str()) {
+            ("id", id) => { entry.insert("id".to_string(), id.into()); },
+            ("readonly", "on" | "yes" | "true") => { entry.insert("readonly".to_string(), true.into()); },
+            ("readonly", _) | ("index", _) | ("node-name", _) => {},
+            ("format", format @ ("raw" | "qcow2" | "vhdx")) => { entry.insert("format".to_string(), format.into()); },
+            // QEMU calls VHD images vpc
+            ("format", "vpc") => { entry.insert("format".to_string(), "vhd".into()); },
+            ("cache", cache @ ("none" | "writeback")) => { entry.insert("cache".to_string(), cache.into()); },
+            ("aio", aio @ ("io_uring" | "threads")) => { entry.insert("aio".to_string(), aio.into()); },
+            (key, value) => imported.notes.push(format!("Not imported: {}={} of drive {}", key, value, label)),
+        }
+    }
+    imported.add_disk(entry, &label);
+}
+
+// A `-device`: VFIO devices, vsock, the balloon and the vTPM
+fn qemu_device(value: &str, imported: &mut Imported) {
+    let mut device = qemu_value(value, "driver");
+    let driver = device.remove("driver").unwrap_or_default();
+    match driver.as_str() {
+        "vfio-pci" => {
+            let address = device.get("host").cloned()
+                .or_else(|| device.get("sysfsdev").and_then(|path| path.rsplit('/').next().map(str::to_string)));
+            match address {
+                // The PCI domain is optional in host=
+                Some(address) if address.matches(':').count() == 1 => imported.push("gpus", format!("0000:{}", address)),
+                Some(address) => imported.push("gpus", address),
+                None => imported.notes.push(format!("Not imported: -device {}", value)),
+            }
+        },
+        "vhost-vsock-pci" | "vhost-vsock-device" => match device.get("guest-cid").map(|cid| cid.parse::<i64>()) {
+            Some(Ok(cid)) => imported.set("vsock_cid", cid),
+            _ => imported.notes.push(format!("Not imported: -device {}", value)),
+        },
+        "virtio-balloon-pci" | "virtio-balloon" | "virtio-balloon-device" => {
+            if device.get("free-page-reporting").is_some_and(|on| on == "on") {
+                imported.set("free_page_reporting", true);
+            }
+        },
+        "tpm-tis" | "tpm-crb" => imported.set("tpm", true),
+        driver if QEMU_DISK_DEVICES.contains(&driver) => {},
+        _ => imported.notes.push(format!("Not imported: -device {}", value)),
+    }
+}
+
+// `key=value` pairs of a QEMU option value; a first part without a key is `implied`. A doubled
+// comma is a comma within a value.
+fn qemu_value(value: &str, implied: &str) -> BTreeMap<String, String> {
+    let mut parts = vec![String::new()];
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' if chars.peek() == Some(&',') => {
+                chars.next();
+                parts.last_mut().unwrap().push(',');
+            },
+            ',' => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts.into_iter().enumerate().filter(|(_, part)| !part.is_empty()).map(|(index, part)| match part.split_once('=') {
+        Some((key, value)) => (key.to_string(), value.to_string()),
+        None if index == 0 => (implied.to_string(), part),
+        // Boolean options, such as readonly
+        None => (part, "on".to_string()),
+    }).collect()
+}
+
+fn is_qemu(word: &str) -> bool {
+    word.rsplit('/').next().is_some_and(|name| name.starts_with("qemu"))
+}
+
+/// Words of the first command in `script` that runs QEMU, or of its only command, split as a
+/// POSIX shell would without expansions: quotes, backslashes, line continuations and comments
+pub fn qemu_command(script: &str) -> Result<Vec<String>> {
+    let mut commands: Vec<Vec<String>> = vec![Vec::new()];
+    let mut word: Option<String> = None;
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\n') | None => {},
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+            },
+            '\'' => {
+                let quoted = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => quoted.push(c),
+                        None => bail!("Unterminated single quote"),
+                    }
+                }
+            },
+            '"' => {
+                let quoted = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"' | '\\' | '$' | '`')) => quoted.push(chars.next().unwrap()),
+                        Some('\\') if chars.peek() == Some(&'\n') => { chars.next(); },
+                        Some(c) => quoted.push(c),
+                        None => bail!("Unterminated double quote"),
+                    }
+                }
+            },
+            '#' if word.is_none() => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            },
+            '\n' | ';' | '&' | '|' => {
+                commands.last_mut().unwrap().extend(word.take());
+                commands.push(Vec::new());
+            },
+            c if c.is_whitespace() => commands.last_mut().unwrap().extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    commands.last_mut().unwrap().extend(word.take());
+    commands.retain(|command| !command.is_empty());
+
+    match commands.iter().position(|command| command.iter().any(|word| is_qemu(word))) {
+        Some(index) => Ok(commands.swap_remove(index)),
+        None if commands.len() == 1 => Ok(commands.remove(0)),
+        None => bail!("No QEMU command found"),
+    }
+}
+
+// Kernel, initrd, command line and loader of <os>
+fn libvirt_os(os: Node, imported: &mut Imported) -> Result<()> {
+    for element in os.children().filter(Node::is_element) {
+        match element.tag_name().name() {
+            "kernel" => imported.set("kernel", text(element, "kernel")?),
+            "initrd" => imported.set("initramfs", text(element, "initrd")?),
+            "cmdline" => imported.set("cmdline", text(element, "cmdline")?),
+            "loader" => imported.set("firmware", text(element, "loader")?),
+            "type" | "boot" | "bootmenu" | "smbios" => {},
+            name => imported.notes.push(format!("Not imported: <os><{}>", name)),
+        }
+    }
+    Ok(())
+}
+
+// File and block disks
+fn libvirt_disk(disk: Node, imported: &mut Imported) -> Result<()> {
+    let target = child(disk, "target").and_then(|target| target.attribute("dev"));
+    let label = target.unwrap_or("disk");
+    let source = child(disk, "source");
+    let path = match (disk.attribute("device").unwrap_or("disk"), disk.attribute("type")) {
+        ("disk", Some("file")) => source.and_then(|source| source.attribute("file")),
+        ("disk", Some("block")) => source.and_then(|source| source.attribute("dev")),
+        (device, kind) => {
+            imported.notes.push(format!("Not imported: {} {} of type {}", device, label, kind.unwrap_or("-")));
+            return Ok(());
+        },
+    };
+    let Some(path) = path else {
+        imported.notes.push(format!("Not imported: disk {} without a source", label));
+        return Ok(());
+    };
+
+    let mut entry = toml::Table::new();
+    entry.insert("path".to_string(), path.into());
+    if let Some(id) = target {
+        entry.insert("id".to_string(), id.into());
+    }
+    if child(disk, "readonly").is_some() {
+        entry.insert("readonly".to_string(), true.into());
+    }
+    if let Some(driver) = child(disk, "driver") {
+        match driver.attribute("type") {
+            Some(format @ ("raw" | "qcow2" | "vhd" | "vhdx")) => { entry.insert("format".to_string(), format.into()); },
+            Some(format) => imported.notes.push(format!("Not imported: format {} of disk {}", format, label)),
+            None => {},
+        }
+        match driver.attribute("cache") {
+            Some(cache @ ("none" | "writeback")) => { entry.insert("cache".to_string(), cache.into()); },
+            Some(cache) => imported.notes.push(format!("Not imported: cache mode {} of disk {}", cache, label)),
+            None => {},
+        }
+        match driver.attribute("io") {
+            Some(aio @ ("io_uring" | "threads")) => { entry.insert("aio".to_string(), aio.into()); },
+            Some(aio) => imported.notes.push(format!("Not imported: io mode {} of disk {}", aio, label)),
+            None => {},
+        }
+        if let Some(queues) = driver.attribute("queues") {
+            entry.insert("num_queues".to_string(), (queues.parse::<u32>().context(format!("Invalid queue count: {}", queues))? as i64).into());
+        }
+        if let Some(queue_size) = driver.attribute("queue_size") {
+            entry.insert("queue_size".to_string(), (queue_size.parse::<u16>().context(format!("Invalid queue size: {}", queue_size))? as i64).into());
+        }
+    }
+    if let Some(iotune) = child(disk, "iotune") {
+        if let Some(bytes) = child(iotune, "total_bytes_sec").and_then(|node| node.text()) {
+            entry.insert("bandwidth".to_string(), bytes.trim().into());
+        }
+        if let Some(iops) = child(iotune, "total_iops_sec").and_then(|node| node.text()) {
+            entry.insert("iops".to_string(), (iops.trim().parse::<u64>().context(format!("Invalid IOPS limit: {}", iops))? as i64).into());
+        }
+    }
+
+    imported.add_disk(entry, label);
+    Ok(())
+}
+
+// PCI host devices become GPUs; the address attributes are hex
+fn libvirt_hostdev(hostdev: Node, imported: &mut Imported) -> Result<()> {
+    let address = child(hostdev, "source").and_then(|source| child(source, "address"));
+    let (Some("subsystem"), Some("pci"), Some(address)) = (hostdev.attribute("mode"), hostdev.attribute("type"), address) else {
+        imported.notes.push(format!("Not imported: <hostdev>{}", describe(hostdev)));
+        return Ok(());
+    };
+    let field = |name: &str| -> Result<u32> {
+        let value = address.attribute(name).unwrap_or("0");
+        u32::from_str_radix(value.trim_start_matches("0x"), 16).context(format!("Invalid PCI {}: {}", name, value))
+    };
+    imported.push("gpus", format!("{:04x}:{:02x}:{:02x}.{:x}", field("domain")?, field("bus")?, field("slot")?, field("function")?));
+    Ok(())
+}
+
+// Bytes of a libvirt scaled integer; the unit defaults to KiB
+fn libvirt_size(value: &str, unit: Option<&str>) -> Result<u64> {
+    let scale: u64 = match unit.unwrap_or("KiB") {
+        "b" | "bytes" => 1,
+        "KB" => 1000,
+        "k" | "K" | "KiB" => 1 << 10,
+        "MB" => 1000 * 1000,
+        "M" | "MiB" => 1 << 20,
+        "GB" => 1000 * 1000 * 1000,
+        "G" | "GiB" => 1 << 30,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        "T" | "TiB" => 1 << 40,
+        unit => bail!("Unsupported memory unit: {}", unit),
+    };
+    value.parse::<u64>().ok()
+        .and_then(|value| value.checked_mul(scale))
+        .ok_or_else(|| anyhow!("Invalid memory size: {} {}", value, unit.unwrap_or("KiB")))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn text<'a>(node: Node<'a, '_>, name: &str) -> Result<&'a str> {
+    node.text().map(str::trim).filter(|text| !text.is_empty())
+        .ok_or_else(|| anyhow!("<{}> is empty", name))
+}
+
+// ` type='...'` of an element, for notes
+fn describe(node: Node) -> String {
+    node.attribute("type").map(|kind| format!(" of type {}", kind)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get<'a>(imported: &'a Imported, key: &str) -> &'a toml::Value {
+        imported.runtime.get(key).unwrap_or_else(|| panic!("{} is not set: {:?}", key, imported.runtime))
+    }
+
+    fn has_note(imported: &Imported, note: &str) -> bool {
+        imported.notes.iter().any(|line| line.contains(note))
+    }
+
+    #[test]
+    fn libvirt_domain() {
+        let imported = libvirt(r#"
+<domain type='kvm'>
+  <name>llama 70b</name>
+  <uuid>4dea22b3-1d52-d8f3-2516-782e98ab3fa0</uuid>
+  <memory unit='KiB'>16777216</memory>
+  <vcpu current='4'>8</vcpu>
+  <os>
+    <type arch='x86_64'>hvm</type>
+    <kernel>/boot/vmlinuz</kernel>
+    <cmdline>console=hvc0</cmdline>
+  </os>
+  <on_reboot>destroy</on_reboot>
+  <devices>
+    <emulator>/usr/bin/qemu-system-x86_64</emulator>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='raw' cache='none'/>
+      <source file='/var/lib/images/system.raw'/>
+      <target dev='vda'/>
+    </disk>
+    <disk type='block' device='disk'>
+      <driver name='qemu' type='raw' io='io_uring' queues='4'/>
+      <source dev='/dev/nvme0n1'/>
+      <target dev='vdb'/>
+      <readonly/>
+    </disk>
+    <disk type='file' device='cdrom'>
+      <source file='/var/lib/images/seed.iso'/>
+      <target dev='sda'/>
+    </disk>
+    <hostdev mode='subsystem' type='pci' managed='yes'>
+      <source><address domain='0x0000' bus='0x41' slot='0x00' function='0x0'/></source>
+    </hostdev>
+    <vsock model='virtio'><cid auto='no' address='42'/></vsock>
+    <graphics type='vnc'/>
+  </devices>
+</domain>"#).unwrap();
+
+        assert_eq!(get(&imported, "name").as_str(), Some("llama-70b"));
+        assert!(has_note(&imported, "llama 70b is named llama-70b"), "{:?}", imported.notes);
+        assert_eq!(get(&imported, "memory_gb").as_integer(), Some(16));
+        assert_eq!(get(&imported, "cpus").get("boot").and_then(toml::Value::as_integer), Some(4));
+        assert_eq!(get(&imported, "cpus").get("max").and_then(toml::Value::as_integer), Some(8));
+        assert_eq!(get(&imported, "kernel").as_str(), Some("/boot/vmlinuz"));
+        assert_eq!(get(&imported, "cmdline").as_str(), Some("console=hvc0"));
+        assert_eq!(get(&imported, "on_reboot").as_str(), Some("stop"));
+
+        // The first disk is the system image, which takes no disk settings
+        assert_eq!(get(&imported, "system_image").as_str(), Some("/var/lib/images/system.raw"));
+        assert!(has_note(&imported, "of system image vda"), "{:?}", imported.notes);
+        let disks = get(&imported, "disks").as_array().unwrap();
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].get("path").and_then(toml::Value::as_str), Some("/dev/nvme0n1"));
+        assert_eq!(disks[0].get("id").and_then(toml::Value::as_str), Some("vdb"));
+        assert_eq!(disks[0].get("readonly").and_then(toml::Value::as_bool), Some(true));
+        assert_eq!(disks[0].get("aio").and_then(toml::Value::as_str), Some("io_uring"));
+        assert_eq!(disks[0].get("num_queues").and_then(toml::Value::as_integer), Some(4));
+        assert!(has_note(&imported, "Not imported: cdrom sda of type file"), "{:?}", imported.notes);
+
+        assert_eq!(get(&imported, "gpus").as_array().unwrap()[0].as_str(), Some("0000:41:00.0"));
+        assert_eq!(get(&imported, "vsock_cid").as_integer(), Some(42));
+        assert!(has_note(&imported, "Not imported: <graphics> of type vnc"), "{:?}", imported.notes);
+        assert!(!imported.runtime.contains_key("uuid"));
+    }
+
+    #[test]
+    fn libvirt_memory_units() {
+        assert_eq!(libvirt_size("16777216", None).unwrap(), 16 << 30);
+        assert_eq!(libvirt_size("16", Some("GiB")).unwrap(), 16 << 30);
+        assert_eq!(libvirt_size("16", Some("GB")).unwrap(), 16_000_000_000);
+        assert!(libvirt_size("16", Some("pages")).is_err());
+        assert!(libvirt_size("99999999999", Some("TiB")).is_err());
+
+        let imported = libvirt("<domain><name>small</name><memory unit='MiB'>1500</memory></domain>").unwrap();
+        assert_eq!(get(&imported, "memory_gb").as_integer(), Some(2));
+        assert!(has_note(&imported, "Memory rounded up from 1500 MiB to 2 GiB"), "{:?}", imported.notes);
+    }
+
+    #[test]
+    fn libvirt_needs_a_named_domain() {
+        assert!(libvirt("<domain><memory>1048576</memory></domain>").is_err());
+        assert!(libvirt("<network><name>default</name></network>").is_err());
+        assert!(libvirt("<domain>").is_err());
+    }
+
+    #[test]
+    fn qemu_command_line() {
+        let args: Vec<String> = [
+            "sudo", "qemu-system-x86_64", "-enable-kvm", "-name", "guest=llama,debug-threads=on", "-m", "16384",
+            "-smp", "4,maxcpus=8", "-kernel", "/boot/vmlinuz", "-append", "console=ttyS0",
+            "-drive", "file=/images/system.qcow2,if=virtio,format=qcow2",
+            "-drive", "file=/images/data.raw,format=raw,readonly=on,id=data,cache=none",
+            "-drive", "if=pflash,format=raw,file=/usr/share/OVMF/OVMF_CODE.fd",
+            "-device", "vfio-pci,host=41:00.0", "-device", "vhost-vsock-pci,guest-cid=3",
+            "-snapshot", "-vnc", ":0",
+        ].iter().map(|arg| arg.to_string()).collect();
+        let imported = qemu_args(&args).unwrap();
+
+        assert_eq!(get(&imported, "name").as_str(), Some("llama"));
+        assert_eq!(get(&imported, "memory_gb").as_integer(), Some(16));
+        assert_eq!(get(&imported, "cpus").get("boot").and_then(toml::Value::as_integer), Some(4));
+        assert_eq!(get(&imported, "cpus").get("max").and_then(toml::Value::as_integer), Some(8));
+        assert_eq!(get(&imported, "kernel").as_str(), Some("/boot/vmlinuz"));
+        assert_eq!(get(&imported, "cmdline").as_str(), Some("console=ttyS0"));
+        assert_eq!(get(&imported, "system_image").as_str(), Some("/images/system.qcow2"));
+        assert_eq!(get(&imported, "firmware").as_str(), Some("/usr/share/OVMF/OVMF_CODE.fd"));
+        let disks = get(&imported, "disks").as_array().unwrap();
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].get("id").and_then(toml::Value::as_str), Some("data"));
+        assert_eq!(disks[0].get("readonly").and_then(toml::Value::as_bool), Some(true));
+        assert_eq!(disks[0].get("cache").and_then(toml::Value::as_str), Some("none"));
+        assert_eq!(get(&imported, "gpus").as_array().unwrap()[0].as_str(), Some("0000:41:00.0"));
+        assert_eq!(get(&imported, "vsock_cid").as_integer(), Some(3));
+        assert_eq!(get(&imported, "ephemeral").as_bool(), Some(true));
+        assert!(has_note(&imported, "Not imported: -vnc :0"), "{:?}", imported.notes);
+        assert!(!has_note(&imported, "enable-kvm"), "{:?}", imported.notes);
+    }
+
+    #[test]
+    fn qemu_topology_and_sizes() {
+        let args = |line: &str| -> Vec<String> { line.split(' ').map(str::to_string).collect() };
+        let imported = qemu_args(&args("qemu-kvm -smp sockets=2,cores=4,threads=2 -m 8G")).unwrap();
+        assert_eq!(get(&imported, "cpus").as_integer(), Some(16));
+        assert_eq!(get(&imported, "memory_gb").as_integer(), Some(8));
+
+        assert!(qemu_args(&args("qemu-kvm -smp 512")).is_err());
+        assert!(qemu_args(&args("qemu-kvm -m 8X")).is_err());
+        assert!(qemu_args(&args("qemu-kvm -kernel")).is_err());
+    }
+
+    #[test]
+    fn qemu_option_values() {
+        let value = qemu_value("/images/a,,b.raw,readonly,format=raw", "file");
+        assert_eq!(value.get("file").map(String::as_str), Some("/images/a,b.raw"));
+        assert_eq!(value.get("readonly").map(String::as_str), Some("on"));
+        assert_eq!(value.get("format").map(String::as_str), Some("raw"));
+    }
+
+    #[test]
+    fn qemu_command_in_a_script() {
+        let script = "#!/bin/sh\nset -e\nexec sudo qemu-system-x86_64 \\\n  -name 'my guest' \\\n  -append \"console=hvc0 root=\\\"/dev/vda\\\"\" # boot\necho done\n";
+        assert_eq!(qemu_command(script).unwrap(),
+                   ["exec", "sudo", "qemu-system-x86_64", "-name", "my guest", "-append", "console=hvc0 root=\"/dev/vda\""]);
+        assert_eq!(qemu_command("/opt/vm/run -m 4G").unwrap(), ["/opt/vm/run", "-m", "4G"]);
+        assert!(qemu_command("true; false").is_err());
+        assert!(qemu_command("qemu-kvm -name 'guest").is_err());
+    }
+}
@@ -0,0 +1,34 @@
+use anyhow::{Result, bail, Context};
+use log::{info, debug};
+use nix::sys::resource::{getrlimit, setrlimit, Resource, RLIM_INFINITY};
+
+/// Set how readily the kernel OOM killer picks this process, from -1000 (never) to 1000.
+/// Values below the current one need CAP_SYS_RESOURCE.
+pub fn set_oom_score_adj(value: i32) -> Result<()> {
+    if !(-1000..=1000).contains(&value) {
+        bail!("oom_score_adj {} is outside -1000 to 1000", value);
+    }
+
+    std::fs::write("/proc/self/oom_score_adj", value.to_string())
+        .context(format!("Failed to set oom_score_adj to {}", value))?;
+    info!("Set oom_score_adj to {}", value);
+
+    Ok(())
+}
+
+/// Raise RLIMIT_MEMLOCK to at least `bytes`, for VFIO to pin guest memory. The soft limit is
+/// raised up to the hard limit freely; going past the hard limit needs CAP_SYS_RESOURCE.
+pub fn raise_memlock(bytes: u64) -> Result<()> {
+    let (soft, hard) = getrlimit(Resource::RLIMIT_MEMLOCK).context("Failed to read RLIMIT_MEMLOCK")?;
+    if soft == RLIM_INFINITY || soft >= bytes {
+        debug!("RLIMIT_MEMLOCK of {} already covers {}", soft, bytes);
+        return Ok(());
+    }
+
+    let hard = if hard == RLIM_INFINITY || hard >= bytes { hard } else { bytes };
+    setrlimit(Resource::RLIMIT_MEMLOCK, bytes, hard)
+        .context(format!("Failed to raise RLIMIT_MEMLOCK from {} to {} bytes", soft, bytes))?;
+    info!("Raised RLIMIT_MEMLOCK from {} to {} bytes for VFIO", soft, bytes);
+
+    Ok(())
+}
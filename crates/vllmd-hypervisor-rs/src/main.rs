@@ -1,22 +1,165 @@
 use std::env;
 use std::path::Path;
-use log::{info, debug};
+use std::collections::BTreeMap;
+use log::{info, warn, debug, error};
 use anyhow::{Result, Context, bail, anyhow};
 use clap::{Command as ClapCommand};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::fs::File;
 use signal_hook::iterator::Signals;
-use signal_hook::consts::signal::{SIGTERM, SIGINT, SIGHUP};
+use signal_hook::consts::signal::{SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2};
 use std::thread;
 // use vmm_sys_util::eventfd::EventFd;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::UnixDatagram;
 // use std::sync::mpsc::channel;
 use termimad;
 
 // Import our hypervisor abstraction
 mod hypervisor;
-use hypervisor::{HypervisorManager, VmConfig, parse_memory_string};
+use hypervisor::{HypervisorBackend, HypervisorError, HypervisorManager, VmConfig, VmExit, DiskConfig, RateLimitGroupConfig, MemoryZoneConfig, SgxEpcConfig, VsockConfig, TpmConfig, BootTimings};
+use hypervisor::{parse_memory_string, parse_cpu_string, parse_memory_zone_string, parse_sgx_epc_string, memory_zones_size, parse_size, parse_disk_string,
+                 parse_rate_limit_group_string, parse_seccomp_action};
+use seccompiler::SeccompAction;
+
+// TOML runtime configuration file
+mod manifest;
+use manifest::{Manifest, GlobalConfig, RuntimeConfig, Selector, Gpus};
+
+// Client for the API socket of a running hypervisor
+mod control;
+
+// qcow2 overlays for ephemeral system images
+mod overlay;
+
+// Interactive attach to the guest serial console
+mod console;
+
+// Host/guest agent protocol over vsock
+mod agent;
+
+// Liveness checks of the guest workload with remediation
+mod probe;
+use probe::{ProbeAction, ProbeConfig, ProbeKind};
+
+// Balloon sizing that follows guest load
+mod balloon;
+use balloon::BalloonPolicy;
+
+// Guest watchdog, reboot policy and detection of the resets they act on
+mod watchdog;
+use watchdog::{PanicWatch, RebootPolicy, ResetDetector, VcpuChange, WatchdogAction};
+
+// Host-to-guest port forwarding
+mod forward;
+
+// swtpm lifecycle for the vTPM
+mod tpm;
+
+// Confidential computing modes and host capability checks
+mod confidential;
+use confidential::ConfidentialMode;
+mod doctor;
+
+// Nested virtualization in the KVM module of the host
+mod nested;
+
+// KVM or MSHV, chosen at runtime
+mod backend;
+
+// Simulated VM lifecycle for tests and CI
+mod mock;
+use backend::BackendKind;
+
+// Unique scratch directories for the unit tests, shared with the CLI tests
+#[cfg(test)]
+mod scratch;
+
+// Scheduling policy of the vCPU threads
+mod sched;
+use sched::VcpuSched;
+
+// OOM score and memlock limit of the VMM process
+mod limits;
+
+// Switching to an unprivileged user once the VM is running
+mod privilege;
+use privilege::RunAs;
+
+// Kernel command line assembled from generated, global and runtime parameters
+mod cmdline;
+use cmdline::CmdlineBuilder;
+
+// Signature verification of boot artifacts
+mod trust;
+use trust::{SignatureTool, TrustPolicy};
+
+// Local image store and remote image sources
+mod images;
+mod oci;
+mod http;
+
+// Bootable system images built from container images
+mod build;
+
+// Variables documented in VARIABLES.md, checked against the ones read here
+mod registry;
+
+// Live resource view of all runtimes
+mod top;
+
+// Lifecycle events for external agents
+mod events;
+mod webhook;
+use events::{EventKind, EventSink};
+use webhook::WebhookConfig;
+
+// Tamper-evident record of privileged operations
+mod audit;
+use audit::AuditLog;
+
+// State dump for bug reports, written on SIGUSR1
+mod dump;
+
+// Concurrent startup of every runtime of the configuration file
+mod fleet;
+
+// Removal of the files stopped runtimes leave behind
+mod prune;
+
+// Per-runtime record that outlives the hypervisor process and host reboots
+mod store;
+use store::Store;
+
+// Snapshot catalog and retention
+mod snapshots;
+use snapshots::Retention;
+
+// Differences between a running VM and its configuration
+mod drift;
+
+// SMBIOS OEM strings that identify the runtime to the guest
+mod oem;
+
+// D-Bus service for system integration
+mod dbus;
+
+// Runtime entries translated from the VM definitions of other tools
+mod import;
+
+// GPUs and accelerators of the host, their IOMMU groups and which runtimes claim them
+mod devices;
+use devices::GpuSelector;
+
+// Retries of host-side device steps that fail while a device is still busy
+mod retry;
+use retry::RetryPolicy;
+
+// Metrics for the textfile collector of node_exporter
+mod textfile;
+use textfile::Textfile;
 
 // Define constants for environment variable names
 const LOG_FILEPATH_VAR: &str = "VLLMD_HYPERVISOR_LOG_FILEPATH";
@@ -25,88 +168,910 @@ const SYSTEM_IMAGE_FILEPATH_VAR: &str = "VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH"
 const CONFIG_IMAGE_FILEPATH_VAR: &str = "VLLMD_HYPERVISOR_CONFIG_IMAGE_FILEPATH";
 const CPU_COUNT_VAR: &str = "VLLMD_HYPERVISOR_CPU_COUNT";
 const MEMORY_CONFIG_VAR: &str = "VLLMD_HYPERVISOR_MEMORY_CONFIG";
+const MAX_CPU_COUNT_VAR: &str = "VLLMD_HYPERVISOR_MAX_CPU_COUNT";
+const CPU_CONFIG_VAR: &str = "VLLMD_HYPERVISOR_CPU_CONFIG";
 const DEVICE_FILEPATH_LIST_VAR: &str = "VLLMD_HYPERVISOR_DEVICE_FILEPATH_LIST";
 const CMDLINE_VAR: &str = "VLLMD_HYPERVISOR_CMDLINE";
+const SECCOMP_VAR: &str = "VLLMD_HYPERVISOR_SECCOMP";
+const LANDLOCK_VAR: &str = "VLLMD_HYPERVISOR_LANDLOCK";
+const GPU_RESET_VAR: &str = "VLLMD_HYPERVISOR_GPU_RESET";
+const DEVICE_RETRIES_VAR: &str = "VLLMD_HYPERVISOR_DEVICE_RETRIES";
+const DEVICE_RETRY_DELAY_MS_VAR: &str = "VLLMD_HYPERVISOR_DEVICE_RETRY_DELAY_MS";
+const WATCHDOG_VAR: &str = "VLLMD_HYPERVISOR_WATCHDOG";
+const ON_REBOOT_VAR: &str = "VLLMD_HYPERVISOR_ON_REBOOT";
+const SNAPSHOT_KEEP_VAR: &str = "VLLMD_HYPERVISOR_SNAPSHOT_KEEP";
+const SNAPSHOT_MAX_AGE_VAR: &str = "VLLMD_HYPERVISOR_SNAPSHOT_MAX_AGE";
+const VCPU_SCHED_VAR: &str = "VLLMD_HYPERVISOR_VCPU_SCHED";
+const OOM_SCORE_ADJ_VAR: &str = "VLLMD_HYPERVISOR_OOM_SCORE_ADJ";
+const BACKEND_VAR: &str = "VLLMD_HYPERVISOR_BACKEND";
 const DEBUG_VAR: &str = "VLLMD_HYPERVISOR_DEBUG";
+const DISK_LIST_VAR: &str = "VLLMD_HYPERVISOR_DISK_LIST";
+const RATE_LIMIT_GROUP_LIST_VAR: &str = "VLLMD_HYPERVISOR_RATE_LIMIT_GROUP_LIST";
+const DISK_RATE_LIMIT_GROUP_VAR: &str = "VLLMD_HYPERVISOR_DISK_RATE_LIMIT_GROUP";
+const MEMORY_ZONE_LIST_VAR: &str = "VLLMD_HYPERVISOR_MEMORY_ZONE_LIST";
+const SGX_EPC_LIST_VAR: &str = "VLLMD_HYPERVISOR_SGX_EPC_LIST";
+const CONFIG_VAR: &str = "VLLMD_HYPERVISOR_CONFIG";
+const RUNTIME_NAME_VAR: &str = "VLLMD_HYPERVISOR_RUNTIME_NAME";
+const SYSTEM_IMAGE_EPHEMERAL_VAR: &str = "VLLMD_HYPERVISOR_SYSTEM_IMAGE_EPHEMERAL";
+const STATE_DIR_VAR: &str = "VLLMD_HYPERVISOR_STATE_DIR";
+const FREE_PAGE_REPORTING_VAR: &str = "VLLMD_HYPERVISOR_FREE_PAGE_REPORTING";
+const RNG_VAR: &str = "VLLMD_HYPERVISOR_RNG";
+const GUEST_LOG_VAR: &str = "VLLMD_HYPERVISOR_GUEST_LOG";
+const VSOCK_CID_VAR: &str = "VLLMD_HYPERVISOR_VSOCK_CID";
+const VSOCK_SOCKET_PATH_VAR: &str = "VLLMD_HYPERVISOR_VSOCK_SOCKET_PATH";
+const TPM_VAR: &str = "VLLMD_HYPERVISOR_TPM";
+const NESTED_VAR: &str = "VLLMD_HYPERVISOR_NESTED";
+const CONFIDENTIAL_VAR: &str = "VLLMD_HYPERVISOR_CONFIDENTIAL";
+const HOST_DATA_VAR: &str = "VLLMD_HYPERVISOR_HOST_DATA";
+const FIRMWARE_FILEPATH_VAR: &str = "VLLMD_HYPERVISOR_FIRMWARE_FILEPATH";
+const INITRAMFS_FILEPATH_VAR: &str = "VLLMD_HYPERVISOR_INITRAMFS_FILEPATH";
+const QGS_SOCKET_PATH_VAR: &str = "VLLMD_HYPERVISOR_QGS_SOCKET_PATH";
+const TRUST_PUBLIC_KEYS_VAR: &str = "VLLMD_HYPERVISOR_TRUST_PUBLIC_KEYS";
+const TRUST_TOOL_VAR: &str = "VLLMD_HYPERVISOR_TRUST_TOOL";
+const TRUST_ENFORCE_VAR: &str = "VLLMD_HYPERVISOR_TRUST_ENFORCE";
+const PROBE_URL_VAR: &str = "VLLMD_HYPERVISOR_PROBE_URL";
+const PROBE_INTERVAL_VAR: &str = "VLLMD_HYPERVISOR_PROBE_INTERVAL";
+const PROBE_THRESHOLD_VAR: &str = "VLLMD_HYPERVISOR_PROBE_THRESHOLD";
+const PROBE_KIND_VAR: &str = "VLLMD_HYPERVISOR_PROBE_KIND";
+const PROBE_ACTION_VAR: &str = "VLLMD_HYPERVISOR_PROBE_ACTION";
+const EVENT_SOCKET_DIR_VAR: &str = "VLLMD_HYPERVISOR_EVENT_SOCKET_DIR";
+const EVENT_LOG_FILEPATH_VAR: &str = "VLLMD_HYPERVISOR_EVENT_LOG_FILEPATH";
+const WEBHOOK_URL_VAR: &str = "VLLMD_HYPERVISOR_WEBHOOK_URL";
+const AUDIT_LOG_FILEPATH_VAR: &str = "VLLMD_HYPERVISOR_AUDIT_LOG_FILEPATH";
+const START_PARALLELISM_VAR: &str = "VLLMD_HYPERVISOR_START_PARALLELISM";
+const TEXTFILE_DIR_VAR: &str = "VLLMD_HYPERVISOR_TEXTFILE_DIR";
+const TEXTFILE_INTERVAL_VAR: &str = "VLLMD_HYPERVISOR_TEXTFILE_INTERVAL";
+const LOG_TIMESTAMP_FORMAT_VAR: &str = "VLLMD_HYPERVISOR_LOG_TIMESTAMP_FORMAT";
+const LOG_UTC_VAR: &str = "VLLMD_HYPERVISOR_LOG_UTC";
 
 // Define default values
 const DEFAULT_CPU_COUNT: u8 = 4;
 const DEFAULT_MEMORY_CONFIG: &str = "size=16G,shared=on";
 const DEFAULT_LOG_FILEPATH: &str = "/dev/stdout";
+const DEFAULT_RNG: &str = "/dev/urandom";
+const DEFAULT_START_PARALLELISM: usize = 4;
+const DEFAULT_DEVICE_RETRIES: u32 = 2;
+const DEFAULT_DEVICE_RETRY_DELAY_MS: u64 = 500;
+const DEFAULT_TEXTFILE_INTERVAL_SECS: u64 = 15;
+
+// Latest lifecycle events shown by inspect
+const INSPECT_EVENT_COUNT: usize = 20;
+
+// How long inspect waits for the guest agent before reporting it as unreachable
+const INSPECT_AGENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+// Exit codes of `start` for the ways a VM fails that a restart can cure; other errors exit with 1,
+// and a VM stopped by the host or powered off by its guest exits with 0
+const EXIT_CODE_GUEST_PANIC: i32 = 3;
+const EXIT_CODE_WATCHDOG: i32 = 4;
+const EXIT_CODE_VMM_ERROR: i32 = 5;
+const EXIT_CODE_BOOT_TIMEOUT: i32 = 6;
+
+// Expand $HOME in paths taken from the configuration file
+fn expand_home(path: &str) -> String {
+    path.replace("$HOME", &env::var("HOME").unwrap_or_default())
+}
+
+// Parse an on/off style boolean environment variable, returning None when it is not set
+fn parse_bool_var(var_name: &str) -> Result<Option<bool>> {
+    match env::var(var_name) {
+        Ok(value) => match value.trim() {
+            "on" | "true" | "yes" | "1" => Ok(Some(true)),
+            "off" | "false" | "no" | "0" => Ok(Some(false)),
+            _ => bail!("Invalid boolean value for {}: {}", var_name, value),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+// Path of the TOML configuration file holding [[runtimes]] definitions
+fn get_config_file_path() -> String {
+    env::var(CONFIG_VAR).unwrap_or_else(|_| {
+        format!("{}/.config/vllmd/config.toml", env::var("HOME").unwrap_or_default())
+    })
+}
+
+// Load the [[runtimes]] entry named by VLLMD_HYPERVISOR_RUNTIME_NAME together with [global]
+fn load_runtime() -> Result<(GlobalConfig, RuntimeConfig)> {
+    match env::var(RUNTIME_NAME_VAR) {
+        Ok(name) => {
+            let manifest = Manifest::load(&get_config_file_path())?;
+            let runtime = manifest.runtime(&name)?.clone();
+            Ok((manifest.global, runtime))
+        },
+        Err(_) => Ok(Default::default()),
+    }
+}
+
+// Confidential computing mode of the runtime, from the environment or the configuration file
+fn get_confidential_mode(configured: Option<ConfidentialMode>) -> Result<Option<ConfidentialMode>> {
+    match env::var(CONFIDENTIAL_VAR) {
+        Ok(s) if s.trim().is_empty() || s.trim() == "off" => Ok(None),
+        Ok(s) => Ok(Some(ConfidentialMode::parse(&s)
+            .context(format!("Invalid value for {}", CONFIDENTIAL_VAR))?)),
+        Err(_) => Ok(configured),
+    }
+}
+
+// Nested virtualization from the environment or the runtime, off by default
+fn get_nested(configured: Option<bool>) -> Result<bool> {
+    Ok(parse_bool_var(NESTED_VAR)?.or(configured).unwrap_or(false))
+}
+
+
+// Kernel parameters matching how vllmd-hypervisor wires up the guest: the serial console `console`
+// attaches to, hvc0 when it feeds the guest log, the system image as the first virtio disk, and
+// the runtime name as hostname
+fn generated_cmdline(guest_log: bool) -> String {
+    let mut params = vec!["console=ttyS0".to_string()];
+    if guest_log {
+        params.push("console=hvc0".to_string());
+    }
+    params.push("root=/dev/vda1".to_string());
+    params.push("rw".to_string());
+    if let Ok(name) = env::var(RUNTIME_NAME_VAR) {
+        params.push(format!("systemd.hostname={}", name));
+    }
+    params.join(" ")
+}
+
+// OEM strings of the VM of the runtime named in the environment
+fn oem_strings(config: &HypervisorConfig, vm_id: &str) -> Vec<String> {
+    let labels = match load_runtime() {
+        Ok((_, runtime)) => runtime.labels,
+        Err(e) => {
+            warn!("Failed to load the labels of the runtime for the OEM strings: {:#}", e);
+            BTreeMap::new()
+        }
+    };
+    let configuration = serde_json::to_value(config).unwrap_or_default();
+    oem::strings(env::var(RUNTIME_NAME_VAR).ok().as_deref(), vm_id, &labels, &configuration)
+}
+
+// Memory configuration string, from the environment or the configured size in GB
+fn get_memory_config(memory_gb: Option<u64>) -> String {
+    env::var(MEMORY_CONFIG_VAR).unwrap_or_else(|_| {
+        match memory_gb {
+            Some(memory_gb) => format!("size={}G,shared=on", memory_gb),
+            None => DEFAULT_MEMORY_CONFIG.to_string(),
+        }
+    })
+}
+
+// Bytes of guest memory: the sum of the memory zones when there are any, as they replace the
+// single region of the memory configuration
+fn guest_memory_size(memory_config: &str, memory_zones: &[MemoryZoneConfig]) -> Result<u64> {
+    if memory_zones.is_empty() {
+        Ok(parse_memory_string(memory_config)?.size)
+    } else {
+        memory_zones_size(memory_zones)
+    }
+}
+
+// sysfs paths of the devices to pass through, from the environment or the runtime's GPUs; GPUs
+// allocated at start are added then
+fn get_device_filepath_list(gpus: &Gpus) -> Vec<String> {
+    env::var(DEVICE_FILEPATH_LIST_VAR)
+        .map(|s| s.split(',').filter(|s| !s.is_empty()).map(String::from).collect())
+        .unwrap_or_else(|_| {
+            gpus.addresses().iter()
+                .map(|address| devices::pci_devices().join(address).to_string_lossy().into_owned())
+                .collect()
+        })
+}
+
+// The `[[runtimes.devices]]` of a runtime: sysfs paths of those passed through with VFIO, and
+// disks for the host block devices handed over instead, bypassing the host page cache
+fn resolve_devices(entries: &[devices::DeviceConfig]) -> Result<(Vec<String>, Vec<DiskConfig>)> {
+    let mut device_paths = Vec::new();
+    let mut disks = Vec::new();
+    for entry in entries {
+        for passthrough in entry.resolve().context(format!("Invalid device {}", entry.pci))? {
+            match passthrough {
+                devices::Passthrough::Vfio(path) => device_paths.push(path),
+                devices::Passthrough::Block(path) => disks.push(DiskConfig {
+                    id: Path::new(&path).file_name().map(|name| name.to_string_lossy().into_owned()),
+                    path,
+                    direct: Some(true),
+                    ..Default::default()
+                }),
+            }
+        }
+    }
+    Ok((device_paths, disks))
+}
+
+// Add the `[[runtimes.devices]]` of the runtime to its devices and disks. Only `start` and the
+// commands that check what it would boot resolve them, as that reads sysfs and fails on a device
+// that is not there.
+fn add_runtime_devices(config: &mut HypervisorConfig) -> Result<()> {
+    let (device_paths, block_devices) = resolve_devices(&config.device_list)?;
+    config.device_filepath_list.extend(device_paths);
+    // NVMe namespaces share the runtime-wide budget with the other disks
+    for mut disk in block_devices {
+        disk.rate_limit_group = config.disk_rate_limit_group.clone();
+        config.disk_list.push(disk);
+    }
+    Ok(())
+}
+
+// Hypervisor backend, from the environment or the configuration file
+fn get_backend(configured: Option<BackendKind>) -> Result<BackendKind> {
+    match env::var(BACKEND_VAR) {
+        Ok(s) => BackendKind::parse(&s).context(format!("Invalid value for {}", BACKEND_VAR)),
+        Err(_) => Ok(configured.unwrap_or_default()),
+    }
+}
+
+// Host socket of the TDX Quote Generation Service
+fn get_qgs_socket_path() -> String {
+    env::var(QGS_SOCKET_PATH_VAR).unwrap_or_else(|_| confidential::DEFAULT_QGS_SOCKET_PATH.to_string())
+}
+
+// Directory for runtime state data such as overlays and guest logs
+fn get_state_dir(global: &GlobalConfig) -> String {
+    env::var(STATE_DIR_VAR).ok()
+        .or_else(|| global.state_dir.as_deref().map(expand_home))
+        .or_else(|| env::var("XDG_STATE_HOME").ok().map(|state_home| format!("{}/vllmd-hypervisor", state_home)))
+        .unwrap_or_else(|| expand_home("$HOME/.local/state/vllmd-hypervisor"))
+}
+
+// State record of the runtime named in the environment, in the state directory
+fn get_store(global: &GlobalConfig) -> Store {
+    Store::new(&get_state_dir(global), env::var(RUNTIME_NAME_VAR).ok().as_deref())
+}
+
+// Directory of the event subscriber sockets shared by all runtimes of the host, next to their PID
+// files
+fn get_event_socket_dir(global: &GlobalConfig) -> String {
+    env::var(EVENT_SOCKET_DIR_VAR).ok()
+        .or_else(|| global.event_socket_dir.as_deref().map(expand_home))
+        .unwrap_or_else(|| {
+            Path::new(&runtime_pid_file_path(None))
+                .with_extension("events")
+                .to_string_lossy()
+                .into_owned()
+        })
+}
+
+// Publisher of the lifecycle events of the runtime named in the environment. The webhook from the
+// environment is added to the configured ones with the default events and retry policy.
+fn get_event_sink(global: &GlobalConfig) -> Result<EventSink> {
+    let log_path = env::var(EVENT_LOG_FILEPATH_VAR).ok()
+        .or_else(|| global.event_log.as_deref().map(expand_home));
+    let mut webhooks = global.webhooks.clone();
+    if let Ok(url) = env::var(WEBHOOK_URL_VAR) {
+        webhooks.push(WebhookConfig::new(&url));
+    }
+    for webhook in &webhooks {
+        webhook.validate()?;
+    }
+    Ok(EventSink::new(env::var(RUNTIME_NAME_VAR).ok(), get_event_socket_dir(global), log_path, webhooks))
+}
+
+// Audit log shared by all runtimes of the host, in the state directory
+fn get_audit_log(global: &GlobalConfig) -> AuditLog {
+    let path = env::var(AUDIT_LOG_FILEPATH_VAR).ok()
+        .or_else(|| global.audit_log.as_deref().map(expand_home))
+        .unwrap_or_else(|| format!("{}/audit.jsonl", get_state_dir(global)));
+    AuditLog::new(path, env::var(RUNTIME_NAME_VAR).ok())
+}
+
+// Runtimes `start --all` boots at the same time, from the environment or the configuration file
+fn get_start_parallelism(global: &GlobalConfig) -> Result<usize> {
+    let parallelism = match env::var(START_PARALLELISM_VAR) {
+        Ok(s) => s.trim().parse::<usize>()
+            .context(format!("Invalid value for {}: {}", START_PARALLELISM_VAR, s))?,
+        Err(_) => global.start_parallelism.unwrap_or(DEFAULT_START_PARALLELISM),
+    };
+    if parallelism == 0 {
+        bail!("The start parallelism must be at least 1");
+    }
+    Ok(parallelism)
+}
+
+// Log file of one runtime of `start --all`: hypervisor.log becomes hypervisor-<name>.log
+fn runtime_log_path(log_filepath: &str, name: &str) -> String {
+    let path = Path::new(log_filepath);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, name, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, name),
+    };
+    path.with_file_name(file_name).to_string_lossy().into_owned()
+}
+
+// File the guest virtio console (hvc0) is written to when the guest log channel is enabled. The
+// virtio console of Cloud Hypervisor v44 cannot go to a socket, so it is not a second console socket.
+fn get_guest_log_path(state_dir: &str) -> String {
+    let name = env::var(RUNTIME_NAME_VAR).unwrap_or_else(|_| "hypervisor".to_string());
+    format!("{}/logs/{}-guest.log", state_dir, name)
+}
 
 // Define path to store the VM PID for stop command - use XDG runtime dir or fallback to /var/run if available
+// Each named runtime gets its own PID file (and sockets next to it)
 fn get_pid_file_path() -> String {
+    runtime_pid_file_path(env::var(RUNTIME_NAME_VAR).ok().as_deref())
+}
+
+fn runtime_pid_file_path(name: Option<&str>) -> String {
+    let suffix = name.map(|name| format!("-{}", name)).unwrap_or_default();
+    
     if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
-        return format!("{}/vllmd-hypervisor.pid", runtime_dir);
+        return format!("{}/vllmd-hypervisor{}.pid", runtime_dir, suffix);
     } else if let Ok(home_dir) = std::env::var("HOME") {
         // Create directory if it doesn't exist
         let run_dir = format!("{}/.local/run/vllmd", home_dir);
         let _ = std::fs::create_dir_all(&run_dir);
-        return format!("{}/hypervisor.pid", run_dir);
+        return format!("{}/hypervisor{}.pid", run_dir, suffix);
     } else {
         // Fallback to system runtime directory if accessible
         if std::path::Path::new("/var/run/vllmd").exists() && std::fs::metadata("/var/run/vllmd").map(|m| m.is_dir()).unwrap_or(false) {
-            return "/var/run/vllmd/hypervisor.pid".to_string();
+            return format!("/var/run/vllmd/hypervisor{}.pid", suffix);
         }
         
         // Last resort - this is still not ideal but better than plain /tmp
-        "/var/tmp/vllmd-hypervisor.pid".to_string()
+        format!("/var/tmp/vllmd-hypervisor{}.pid", suffix)
     }
 }
 
+// The API socket lives next to the PID file so out-of-process commands can find it
+fn get_api_socket_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("sock")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// The guest serial console socket also lives next to the PID file
+fn get_console_socket_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("console.sock")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Lifecycle state file read by status
+fn get_state_file_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("state")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Diagnostic dump written on SIGUSR1
+fn get_dump_file_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("dump.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Present from a restart request until the runtime is back, for `restart` to wait on
+fn get_restart_file_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("restart")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Real-time signal `restart` sends to ask `start` to boot its VM again in place
+fn restart_signal() -> i32 {
+    libc::SIGRTMIN()
+}
+
+// What `start` hands over to the new binary it execs into for an upgrade, which takes the
+// file away before restoring the VM
+fn get_upgrade_file_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("upgrade")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Real-time signal `upgrade` sends to ask `start` to hand its running VM to a new binary
+fn upgrade_signal() -> i32 {
+    libc::SIGRTMIN() + 1
+}
+
+// Duration of the boot phases, read by status and info
+fn get_timing_file_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("timing.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Resolved configuration the running VM booted with, read by inspect
+fn get_resolved_config_file_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("config.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Result of the latest endpoint probe, read by status
+fn get_health_file_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("health")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Host side of the vsock device, next to the PID file unless configured
+fn get_vsock_socket_path() -> String {
+    env::var(VSOCK_SOCKET_PATH_VAR).unwrap_or_else(|_| {
+        Path::new(&get_pid_file_path())
+            .with_extension("vsock")
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+// Control socket of the swtpm process backing the vTPM
+fn get_tpm_socket_path() -> String {
+    Path::new(&get_pid_file_path())
+        .with_extension("tpm.sock")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// TPM state outlives the VM so keys sealed to the TPM survive restarts
+fn get_tpm_state_dir(state_dir: &str) -> String {
+    let name = env::var(RUNTIME_NAME_VAR).unwrap_or_else(|_| "hypervisor".to_string());
+    format!("{}/tpm/{}", state_dir, name)
+}
+
 // Define command verbs
 enum CommandVerb {
     Start,
     Stop,
+    Restart,
+    Upgrade,
+    Up,
+    Down,
+    Drain,
+    Uncordon,
+    EnableNested,
     Status,
+    List,
+    Info,
+    Remote,
+    Import,
+    Inspect,
+    Top,
+    Events,
+    Doctor,
     Env,
+    AddNet,
+    RemoveNet,
+    Snapshot,
+    SnapshotsList,
+    SnapshotsDelete,
+    SnapshotsPrune,
+    Pull,
+    BuildImage,
+    ImagesList,
+    DevicesList,
+    ImagesPrune,
+    Prune,
+    Console,
+    Logs,
+    Agent,
+    Exec,
+    Copy,
+    Attest,
+    Forward,
+    VarsList,
+    VarsCheck,
+    AuditVerify,
+    Dbus,
+}
+
+// GPUs a runtime has allocated when it starts rather than naming them
+#[derive(Debug, Clone, serde::Serialize)]
+struct GpuRequest {
+    count: u32,
+    selector: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize)]
 struct HypervisorConfig {
     log_filepath: String,
-    kernel_filepath: String,
+    kernel_filepath: Option<String>,
     system_image_filepath: String,
+    system_image_ephemeral: bool,
+    free_page_reporting: bool,
+    rng: String,
+    guest_log_filepath: Option<String>,
+    vsock_cid: Option<u32>,
+    tpm: bool,
+    nested: bool,
+    confidential: Option<ConfidentialMode>,
+    host_data: Option<String>,
+    firmware_filepath: Option<String>,
+    initramfs_filepath: Option<String>,
+    qgs_socket_path: String,
+    trust: Option<TrustPolicy>,
+    kernel_signature: Option<String>,
+    system_image_signature: Option<String>,
+    probe: Option<ProbeConfig>,
+    balloon: Option<BalloonPolicy>,
     config_image_filepath: String,
+    state_dir: String,
     cpu_count: u8,
+    max_cpu_count: Option<u8>,
+    cpu_config: String,
     memory_config: String,
+    memory_zone_list: Vec<MemoryZoneConfig>,
+    sgx_epc_list: Vec<SgxEpcConfig>,
     device_filepath_list: Vec<String>,
+    device_list: Vec<devices::DeviceConfig>,
+    gpu_request: Option<GpuRequest>,
+    /// Links between the GPUs, known once they are claimed at start
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    gpu_topology: Vec<devices::GpuLink>,
+    gpu_reset: bool,
+    device_retries: u32,
+    device_retry_delay_ms: u64,
+    /// Directory of the node_exporter textfile collector the metrics of the runtime are written to
+    textfile_dir: Option<String>,
+    textfile_interval_secs: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    watchdog: Option<WatchdogAction>,
+    on_reboot: RebootPolicy,
+    disk_list: Vec<DiskConfig>,
+    rate_limit_group_list: Vec<RateLimitGroupConfig>,
+    disk_rate_limit_group: Option<String>,
     cmdline: String,
+    cmdline_overrides: Vec<String>,
+    #[serde(serialize_with = "serialize_seccomp")]
+    seccomp: SeccompAction,
+    landlock: bool,
+    vcpu_sched: Option<VcpuSched>,
+    oom_score_adj: Option<i32>,
+    backend: BackendKind,
+    #[serde(skip)]
+    events: EventSink,
+    #[serde(skip)]
+    audit: AuditLog,
+    #[serde(skip)]
+    store: Store,
     debug: bool,
 }
 
+// Seccomp setting in the spelling of VLLMD_HYPERVISOR_SECCOMP
+fn serialize_seccomp<S: serde::Serializer>(seccomp: &SeccompAction, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match seccomp {
+        SeccompAction::Allow => serializer.serialize_str("off"),
+        SeccompAction::Log => serializer.serialize_str("log"),
+        _ => serializer.serialize_str("on"),
+    }
+}
+
 impl HypervisorConfig {
     fn from_env() -> Result<Self> {
-        // Required variables
-        let kernel_filepath = env::var(KERNEL_FILEPATH_VAR)
-            .context(format!("Required environment variable {} not set", KERNEL_FILEPATH_VAR))?;
+        // Runtime selected from the configuration file, if any. Environment variables take precedence.
+        let (global, runtime) = load_runtime()?;
+        
+        let firmware_filepath = match env::var(FIRMWARE_FILEPATH_VAR).ok().or_else(|| runtime.firmware.clone()) {
+            Some(firmware) => Some(images::resolve_image_path(&expand_home(&firmware))?),
+            None => None,
+        };
         
-        let system_image_filepath = env::var(SYSTEM_IMAGE_FILEPATH_VAR)
-            .context(format!("Required environment variable {} not set", SYSTEM_IMAGE_FILEPATH_VAR))?;
+        // Required variables; with firmware the kernel is optional and the system image boots on its own
+        let kernel_filepath = match env::var(KERNEL_FILEPATH_VAR).ok().or(runtime.kernel) {
+            Some(kernel) => Some(images::resolve_image_path(&kernel)?),
+            None if firmware_filepath.is_some() => None,
+            None => bail!("Required environment variable {} not set (or set {} to boot through firmware)",
+                          KERNEL_FILEPATH_VAR, FIRMWARE_FILEPATH_VAR),
+        };
+        
+        let initramfs_filepath = env::var(INITRAMFS_FILEPATH_VAR).ok()
+            .or_else(|| runtime.initramfs.as_deref().map(expand_home));
         
-        let config_image_filepath = env::var(CONFIG_IMAGE_FILEPATH_VAR)
-            .context(format!("Required environment variable {} not set", CONFIG_IMAGE_FILEPATH_VAR))?;
+        let system_image_filepath = env::var(SYSTEM_IMAGE_FILEPATH_VAR).ok()
+            .or(runtime.system_image)
+            .ok_or_else(|| anyhow!("Required environment variable {} not set", SYSTEM_IMAGE_FILEPATH_VAR))?;
+        let system_image_filepath = images::resolve_image_path(&system_image_filepath)?;
+        
+        let config_image_filepath = env::var(CONFIG_IMAGE_FILEPATH_VAR).ok()
+            .or(runtime.config_image)
+            .ok_or_else(|| anyhow!("Required environment variable {} not set", CONFIG_IMAGE_FILEPATH_VAR))?;
+        let config_image_filepath = images::resolve_image_path(&config_image_filepath)?;
         
         // Optional variables with defaults
         let log_filepath = env::var(LOG_FILEPATH_VAR).unwrap_or_else(|_| DEFAULT_LOG_FILEPATH.to_string());
         
-        let cpu_count = env::var(CPU_COUNT_VAR)
-            .map(|s| s.parse::<u8>().unwrap_or(DEFAULT_CPU_COUNT))
+        // System images from the image store are shared between runtimes and must not be written
+        let system_image_ephemeral = parse_bool_var(SYSTEM_IMAGE_EPHEMERAL_VAR)?
+            .or(runtime.ephemeral)
+            .unwrap_or(false)
+            || images::is_store_path(&system_image_filepath);
+        
+        let free_page_reporting = parse_bool_var(FREE_PAGE_REPORTING_VAR)?
+            .or(runtime.free_page_reporting)
+            .unwrap_or(false);
+        
+        let rng = env::var(RNG_VAR).ok()
+            .filter(|s| !s.trim().is_empty())
+            .or(runtime.rng.clone())
+            .unwrap_or_else(|| DEFAULT_RNG.to_string());
+        
+        let state_dir = get_state_dir(&global);
+        
+        let guest_log_filepath = if parse_bool_var(GUEST_LOG_VAR)?.or(runtime.guest_log).unwrap_or(false) {
+            Some(get_guest_log_path(&state_dir))
+        } else {
+            None
+        };
+        
+        let vsock_cid = match env::var(VSOCK_CID_VAR) {
+            Ok(s) => Some(s.trim().parse::<u32>()
+                .context(format!("Invalid value for {}: {}", VSOCK_CID_VAR, s))?),
+            Err(_) => runtime.vsock_cid,
+        };
+        
+        let tpm = parse_bool_var(TPM_VAR)?
+            .or(runtime.tpm)
+            .unwrap_or(false);
+        
+        let confidential = get_confidential_mode(runtime.confidential)?;
+        let nested = get_nested(runtime.nested)?;
+        if nested && confidential.is_some() {
+            bail!("Nested virtualization is not available to confidential guests; turn off {} or {}", NESTED_VAR, CONFIDENTIAL_VAR);
+        }
+        let host_data = env::var(HOST_DATA_VAR).ok().or_else(|| runtime.host_data.clone());
+        
+        let qgs_socket_path = get_qgs_socket_path();
+        
+        // Public keys from the environment enable the policy; the other variables tune either source
+        let mut trust = global.trust.clone();
+        if let Ok(s) = env::var(TRUST_PUBLIC_KEYS_VAR) {
+            trust.get_or_insert_with(TrustPolicy::default).public_keys = s.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(trust) = trust.as_mut() {
+            if let Ok(s) = env::var(TRUST_TOOL_VAR) {
+                trust.tool = SignatureTool::parse(&s)
+                    .context(format!("Invalid value for {}", TRUST_TOOL_VAR))?;
+            }
+            if let Some(enforce) = parse_bool_var(TRUST_ENFORCE_VAR)? {
+                trust.enforce = enforce;
+            }
+            trust.public_keys = trust.public_keys.iter().map(|key| expand_home(key)).collect();
+        }
+        let kernel_signature = runtime.kernel_signature.as_deref().map(expand_home);
+        let system_image_signature = runtime.system_image_signature.as_deref().map(expand_home);
+        
+        // A probe URL or kind from the environment enables the probe; the other variables tune either source
+        let mut probe = runtime.probe.clone();
+        if let Ok(url) = env::var(PROBE_URL_VAR) {
+            probe.get_or_insert_with(ProbeConfig::default).url = Some(url);
+        }
+        if let Ok(s) = env::var(PROBE_KIND_VAR) {
+            probe.get_or_insert_with(ProbeConfig::default).kind = ProbeKind::parse(&s)
+                .context(format!("Invalid value for {}", PROBE_KIND_VAR))?;
+        }
+        if let Some(probe) = probe.as_mut() {
+            if let Ok(s) = env::var(PROBE_INTERVAL_VAR) {
+                probe.interval_secs = s.trim().parse()
+                    .context(format!("Invalid value for {}: {}", PROBE_INTERVAL_VAR, s))?;
+            }
+            if let Ok(s) = env::var(PROBE_THRESHOLD_VAR) {
+                probe.threshold = s.trim().parse()
+                    .context(format!("Invalid value for {}: {}", PROBE_THRESHOLD_VAR, s))?;
+            }
+            if let Ok(s) = env::var(PROBE_ACTION_VAR) {
+                probe.action = ProbeAction::parse(&s)
+                    .context(format!("Invalid value for {}", PROBE_ACTION_VAR))?;
+            }
+            probe.validate()?;
+        }
+        
+        let default_cpu_count = runtime.cpus.map(|cpus| cpus.boot())
+            .or(global.default_cpus)
             .unwrap_or(DEFAULT_CPU_COUNT);
         
-        let memory_config = env::var(MEMORY_CONFIG_VAR).unwrap_or_else(|_| DEFAULT_MEMORY_CONFIG.to_string());
+        let cpu_count = match env::var(CPU_COUNT_VAR) {
+            Ok(s) => match s.trim().parse::<u8>() {
+                Ok(count) if count > 0 => count,
+                _ => bail!("Invalid value for {}: expected a vCPU count from 1 to 255, got {}", CPU_COUNT_VAR, s),
+            },
+            Err(_) => default_cpu_count,
+        };
+        
+        // Without a maximum every vCPU is online from boot
+        let default_max_cpu_count = runtime.cpus.and_then(|cpus| cpus.max());
+        let max_cpu_count = match env::var(MAX_CPU_COUNT_VAR) {
+            Ok(s) => match s.trim().parse::<u8>() {
+                Ok(count) if count > 0 => Some(count),
+                _ => bail!("Invalid value for {}: expected a vCPU count from 1 to 255, got {}", MAX_CPU_COUNT_VAR, s),
+            },
+            Err(_) => default_max_cpu_count,
+        };
+        if let Some(max_cpu_count) = max_cpu_count.filter(|max_cpu_count| *max_cpu_count < cpu_count) {
+            bail!("The runtime boots with {} vCPUs, more than its maximum of {} ({})", cpu_count, max_cpu_count, MAX_CPU_COUNT_VAR);
+        }
+        
+        let cpu_config = env::var(CPU_CONFIG_VAR).ok()
+            .or_else(|| runtime.cpu_config.clone())
+            .unwrap_or_default();
+        parse_cpu_string(&cpu_config).context(format!("Invalid value for {}", CPU_CONFIG_VAR))?;
+        
+        let memory_config = get_memory_config(runtime.memory_gb.or(global.default_memory_gb));
+        
+        let device_filepath_list = get_device_filepath_list(&runtime.gpus);
+        
+        // A selector alone asks for one GPU; devices from the environment replace any request
+        let gpu_request = match (&runtime.gpus, runtime.gpu_selector) {
+            _ if env::var(DEVICE_FILEPATH_LIST_VAR).is_ok() => None,
+            (Gpus::Count(0), _) => None,
+            (Gpus::Count(count), selector) => Some(GpuRequest { count: *count, selector }),
+            (Gpus::Addresses(addresses), Some(selector)) if addresses.is_empty() => Some(GpuRequest { count: 1, selector: Some(selector) }),
+            (Gpus::Addresses(_), Some(_)) => bail!("gpu_selector needs gpus to be a number of GPUs rather than addresses"),
+            (Gpus::Addresses(_), None) => None,
+        };
+        if let Some(selector) = gpu_request.as_ref().and_then(|request| request.selector.as_deref()) {
+            GpuSelector::parse(selector).context("Invalid gpu_selector")?;
+        }
+        
+        let gpu_reset = parse_bool_var(GPU_RESET_VAR)?
+            .or(runtime.gpu_reset)
+            .unwrap_or(false);
+        let device_retries = match env::var(DEVICE_RETRIES_VAR) {
+            Ok(s) => s.trim().parse::<u32>()
+                .context(format!("Invalid value for {}: {}", DEVICE_RETRIES_VAR, s))?,
+            Err(_) => runtime.device_retries.unwrap_or(DEFAULT_DEVICE_RETRIES),
+        };
+        let device_retry_delay_ms = match env::var(DEVICE_RETRY_DELAY_MS_VAR) {
+            Ok(s) => s.trim().parse::<u64>()
+                .context(format!("Invalid value for {}: {}", DEVICE_RETRY_DELAY_MS_VAR, s))?,
+            Err(_) => runtime.device_retry_delay_ms.unwrap_or(DEFAULT_DEVICE_RETRY_DELAY_MS),
+        };
+        
+        let textfile_dir = env::var(TEXTFILE_DIR_VAR).ok()
+            .or_else(|| global.textfile_dir.as_deref().map(expand_home))
+            .filter(|dir| !dir.trim().is_empty());
+        let textfile_interval_secs = match env::var(TEXTFILE_INTERVAL_VAR) {
+            Ok(s) => s.trim().parse::<u64>()
+                .context(format!("Invalid value for {}: {}", TEXTFILE_INTERVAL_VAR, s))?,
+            Err(_) => global.textfile_interval_secs.unwrap_or(DEFAULT_TEXTFILE_INTERVAL_SECS),
+        };
+        if textfile_interval_secs == 0 {
+            bail!("The textfile interval must be at least 1 second");
+        }
+        
+        let watchdog = match env::var(WATCHDOG_VAR) {
+            Ok(s) => WatchdogAction::parse(&s).context(format!("Invalid value for {}", WATCHDOG_VAR))?,
+            Err(_) => runtime.watchdog,
+        };
+        let on_reboot = match env::var(ON_REBOOT_VAR) {
+            Ok(s) => RebootPolicy::parse(&s).context(format!("Invalid value for {}", ON_REBOOT_VAR))?,
+            Err(_) => runtime.on_reboot.unwrap_or_default(),
+        };
+        // A watchdog reset looks like any other reboot of the guest
+        if watchdog == Some(WatchdogAction::Reset) && on_reboot == RebootPolicy::Stop {
+            bail!("watchdog = reset needs on_reboot = restart, as the host cannot tell a watchdog reset from a guest reboot");
+        }
+        
+        // Disks are separated by ';' since each disk string uses ',' between options
+        let mut disk_list = match env::var(DISK_LIST_VAR) {
+            Ok(s) => s.split(';')
+                .filter(|s| !s.is_empty())
+                .map(parse_disk_string)
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => runtime.disks,
+        };
+        
+        let rate_limit_group_list = match env::var(RATE_LIMIT_GROUP_LIST_VAR) {
+            Ok(s) => s.split(';')
+                .filter(|s| !s.is_empty())
+                .map(parse_rate_limit_group_string)
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => runtime.rate_limit_groups,
+        };
+        
+        // The runtime-wide group takes in every disk that has no limits of its own, so the
+        // images, the extra disks and the NVMe namespaces, added at start, share one budget
+        let disk_rate_limit_group = env::var(DISK_RATE_LIMIT_GROUP_VAR).ok()
+            .filter(|group| !group.is_empty())
+            .or(runtime.disk_rate_limit_group);
+        if let Some(group) = &disk_rate_limit_group {
+            for disk in disk_list.iter_mut() {
+                if disk.rate_limit_group.is_none() && disk.bandwidth.is_none() && disk.iops.is_none() {
+                    disk.rate_limit_group = Some(group.clone());
+                }
+            }
+        }
+        
+        // Zones are separated by ';' like disks
+        let memory_zone_list = match env::var(MEMORY_ZONE_LIST_VAR) {
+            Ok(s) => s.split(';')
+                .filter(|s| !s.is_empty())
+                .map(parse_memory_zone_string)
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => runtime.memory_zones,
+        };
+        
+        let sgx_epc_list = match env::var(SGX_EPC_LIST_VAR) {
+            Ok(s) => s.split(';')
+                .filter(|s| !s.is_empty())
+                .map(parse_sgx_epc_string)
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => runtime.sgx_epc,
+        };
+        
+        let balloon = runtime.balloon;
+        if let Some(policy) = &balloon {
+            policy.validate(guest_memory_size(&memory_config, &memory_zone_list)?)?;
+        }
+        
+        // The [global] base, then the runtime's own parameters, then generated ones for what
+        // neither sets
+        let mut cmdline_builder = CmdlineBuilder::new();
+        if let Some(base) = &global.cmdline {
+            cmdline_builder.add("global", base)?;
+        }
+        if let Some(line) = env::var(CMDLINE_VAR).ok().or(runtime.cmdline) {
+            cmdline_builder.add("runtime", &line)?;
+        }
+        if kernel_filepath.is_some() {
+            cmdline_builder.add_defaults(&generated_cmdline(guest_log_filepath.is_some()))?;
+        }
+        let cmdline = cmdline_builder.build();
+        let cmdline_overrides = cmdline_builder.overrides().to_vec();
+        
+        // Seccomp is enforced unless turned off explicitly
+        let seccomp = match env::var(SECCOMP_VAR) {
+            Ok(s) => parse_seccomp_action(&s).context(format!("Invalid value for {}", SECCOMP_VAR))?,
+            Err(_) => SeccompAction::Trap,
+        };
+        
+        let landlock = parse_bool_var(LANDLOCK_VAR)?
+            .or(runtime.landlock)
+            .unwrap_or(false);
+        
+        let vcpu_sched = match env::var(VCPU_SCHED_VAR) {
+            Ok(s) => Some(VcpuSched::parse(&s).context(format!("Invalid value for {}", VCPU_SCHED_VAR))?),
+            Err(_) => runtime.vcpu_sched,
+        };
         
-        let device_filepath_list = env::var(DEVICE_FILEPATH_LIST_VAR)
-            .map(|s| s.split(',').filter(|s| !s.is_empty()).map(String::from).collect())
-            .unwrap_or_else(|_| Vec::new());
+        let oom_score_adj = match env::var(OOM_SCORE_ADJ_VAR) {
+            Ok(s) => Some(s.trim().parse::<i32>()
+                .context(format!("Invalid value for {}: {}", OOM_SCORE_ADJ_VAR, s))?),
+            Err(_) => runtime.oom_score_adj,
+        };
+        
+        let backend = get_backend(runtime.backend)?;
         
-        let cmdline = env::var(CMDLINE_VAR).unwrap_or_else(|_| String::new());
+        let events = get_event_sink(&global)?;
+        let audit = get_audit_log(&global);
+        let store = get_store(&global);
         
         let debug = env::var(DEBUG_VAR).is_ok();
         
         // Validate paths
-        if !Path::new(&kernel_filepath).exists() {
-            bail!("Kernel filepath does not exist: {}", kernel_filepath);
+        if let Some(kernel_filepath) = &kernel_filepath {
+            if !Path::new(kernel_filepath).exists() {
+                bail!("Kernel filepath does not exist: {}", kernel_filepath);
+            }
+        }
+        
+        if let Some(firmware_filepath) = &firmware_filepath {
+            if !Path::new(firmware_filepath).exists() {
+                bail!("Firmware filepath does not exist: {}", firmware_filepath);
+            }
+        }
+        
+        if let Some(initramfs_filepath) = &initramfs_filepath {
+            if !Path::new(initramfs_filepath).exists() {
+                bail!("Initramfs filepath does not exist: {}", initramfs_filepath);
+            }
         }
         
         if !Path::new(&system_image_filepath).exists() {
@@ -123,30 +1088,147 @@ impl HypervisorConfig {
             }
         }
         
+        for disk in &disk_list {
+            if !Path::new(&disk.path).exists() {
+                bail!("Disk path does not exist: {}", disk.path);
+            }
+        }
+        
         Ok(Self {
             log_filepath,
             kernel_filepath,
             system_image_filepath,
+            system_image_ephemeral,
+            free_page_reporting,
+            rng,
+            guest_log_filepath,
+            vsock_cid,
+            tpm,
+            nested,
+            confidential,
+            host_data,
+            firmware_filepath,
+            initramfs_filepath,
+            qgs_socket_path,
+            trust,
+            kernel_signature,
+            system_image_signature,
+            probe,
+            balloon,
             config_image_filepath,
+            state_dir,
             cpu_count,
+            max_cpu_count,
+            cpu_config,
             memory_config,
+            memory_zone_list,
+            sgx_epc_list,
             device_filepath_list,
+            device_list: runtime.devices,
+            gpu_request,
+            gpu_topology: Vec::new(),
+            gpu_reset,
+            device_retries,
+            device_retry_delay_ms,
+            textfile_dir,
+            textfile_interval_secs,
+            labels: runtime.labels,
+            watchdog,
+            on_reboot,
+            disk_list,
+            rate_limit_group_list,
+            disk_rate_limit_group,
             cmdline,
+            cmdline_overrides,
+            seccomp,
+            landlock,
+            vcpu_sched,
+            oom_score_adj,
+            backend,
+            events,
+            audit,
+            store,
             debug,
         })
     }
 }
 
-fn setup_logger(log_filepath: &str, debug: bool) -> Result<()> {
-    let env = env_logger::Env::default().filter_or("RUST_LOG", if debug { "debug" } else { "info" });
+// ANSI styling only on a terminal, and never with NO_COLOR set (https://no-color.org)
+fn use_color(terminal: bool) -> bool {
+    terminal && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+// Set on SIGUSR2; the log file is reopened before the next line is written to it, so logrotate
+// can move it away without restarting the VM
+static REOPEN_LOG: AtomicBool = AtomicBool::new(false);
+
+// Set by `start --lenient`: unknown variables and values that do not parse are ignored
+static LENIENT_ENV: AtomicBool = AtomicBool::new(false);
+
+// Timestamp of each log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimestampFormat {
+    /// YYYYMMDD-HHMMSS, with a Z suffix in UTC
+    Compact,
+    /// RFC 3339 with milliseconds and the UTC offset
+    Rfc3339,
+    /// Milliseconds since the Unix epoch
+    EpochMillis,
+}
+
+impl TimestampFormat {
+    fn parse(s: &str) -> Result<Self> {
+        match s.trim() {
+            "compact" => Ok(Self::Compact),
+            "rfc3339" => Ok(Self::Rfc3339),
+            "epoch-millis" => Ok(Self::EpochMillis),
+            _ => bail!("Unknown log timestamp format '{}', expected compact, rfc3339 or epoch-millis", s),
+        }
+    }
+    
+    fn render(self, utc: bool) -> String {
+        let now = chrono::Utc::now();
+        match (self, utc) {
+            (Self::Compact, false) => now.with_timezone(&chrono::Local).format("%Y%m%d-%H%M%S").to_string(),
+            (Self::Compact, true) => now.format("%Y%m%d-%H%M%SZ").to_string(),
+            (Self::Rfc3339, false) => now.with_timezone(&chrono::Local).to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            (Self::Rfc3339, true) => now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            (Self::EpochMillis, _) => now.timestamp_millis().to_string(),
+        }
+    }
+}
+
+// `base` raised by one level for each -v and lowered by one for each -q
+fn log_level(base: log::LevelFilter, verbosity: i8) -> log::LevelFilter {
+    use log::LevelFilter::*;
+    let levels = [Off, Error, Warn, Info, Debug, Trace];
+    let index = levels.iter().position(|level| *level == base).unwrap_or(3) as i8 + verbosity;
+    levels[index.clamp(0, levels.len() as i8 - 1) as usize]
+}
+
+fn setup_logger(log_filepath: &str, level: log::LevelFilter, append: bool) -> Result<()> {
+    let env = env_logger::Env::default().filter_or("RUST_LOG", level.to_string());
     
     let mut builder = env_logger::Builder::from_env(env);
     
-    // Set a colorized format with wide pipe separators
-    builder.format(|buf, record| {
+    let timestamp_format = match env::var(LOG_TIMESTAMP_FORMAT_VAR) {
+        Ok(s) => TimestampFormat::parse(&s)?,
+        Err(_) => TimestampFormat::Compact,
+    };
+    let utc = parse_bool_var(LOG_UTC_VAR)?.unwrap_or(false);
+    
+    // Set a colorized format with wide pipe separators, or plain text when piped
+    let color = use_color(std::io::stderr().is_terminal());
+    builder.format(move |buf, record| {
         use std::io::Write;
-        // Format as YYYYMMDD-HHMMSS
-        let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        // Warnings and errors also go into the next diagnostic dump
+        dump::note(record);
+        
+        let timestamp = timestamp_format.render(utc);
+        
+        if !color {
+            return writeln!(buf, "{}  {:<5}  {}", timestamp, record.level().to_string().to_lowercase(), record.args());
+        }
         
         // Define colors for each field and determine message color based on level
         let level_color = match record.level() {
@@ -197,6 +1279,7 @@ fn setup_logger(log_filepath: &str, debug: bool) -> Result<()> {
         // Create a custom logger that writes to both stdout and the file
         struct DualWriter {
             file: File,
+            path: String,
             // Static mutex to ensure synchronized writes across all threads
             mutex: std::sync::Mutex<()>,
         }
@@ -206,6 +1289,14 @@ fn setup_logger(log_filepath: &str, debug: bool) -> Result<()> {
                 // Lock the mutex for the entire write operation
                 let _guard = self.mutex.lock().unwrap();
                 
+                // Reopen by path after a rotation; keep the old file if that fails
+                if REOPEN_LOG.swap(false, Ordering::SeqCst) {
+                    match std::fs::OpenOptions::new().create(true).append(true).open(&self.path) {
+                        Ok(file) => self.file = file,
+                        Err(e) => eprintln!("Failed to reopen log file {}: {}", self.path, e),
+                    }
+                }
+                
                 // Make sure the buffer ends with a newline to prevent incomplete lines
                 let buf_to_write = if !buf.ends_with(b"\n") {
                     let mut new_buf = buf.to_vec();
@@ -234,12 +1325,13 @@ fn setup_logger(log_filepath: &str, debug: bool) -> Result<()> {
         }
         
         // Open the log file
-        let log_file = File::create(log_filepath)
+        let log_file = std::fs::OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(log_filepath)
             .context(format!("Failed to create log file: {}", log_filepath))?;
         
         // Create the dual writer with a mutex
         let dual_writer = DualWriter { 
             file: log_file,
+            path: log_filepath.to_string(),
             mutex: std::sync::Mutex::new(()),
         };
         
@@ -250,7 +1342,7 @@ fn setup_logger(log_filepath: &str, debug: bool) -> Result<()> {
     // Initialize the logger
     builder.init();
     
-    info!("Logger initialized with level: {}", if debug { "DEBUG" } else { "INFO" });
+    info!("Logger initialized with level: {}", level);
     Ok(())
 }
 
@@ -288,198 +1380,3593 @@ fn get_vm_pid() -> Result<u32> {
     pid_str.trim().parse::<u32>().context("Failed to parse PID from file")
 }
 
-fn start_hypervisor(config: &HypervisorConfig) -> Result<()> {
+/// Take the configured action once the liveness check has failed `threshold` times in a row,
+/// returning whether the guest was rebooted
+fn remediate(hypervisor_manager: &mut dyn HypervisorBackend, probe: &ProbeConfig, events: &EventSink, audit: &AuditLog, store: &Store, exit_signal: &AtomicBool) -> bool {
+    let target = probe.target();
+    match probe.action {
+        ProbeAction::Log => {
+            notify_systemd("STATUS=Inference endpoint not answering");
+            false
+        },
+        ProbeAction::Event => {
+            error!("event=probe_failed target=\"{}\" failures={}", target, probe.threshold);
+            events.emit(EventKind::ProbeFailed, Some(&target));
+            notify_systemd(&format!("STATUS=Probe of {} failed", target));
+            false
+        },
+        ProbeAction::Reboot => {
+            warn!("Rebooting guest after failed probe of {}", target);
+            notify_systemd("STATUS=Rebooting guest after failed probe");
+            let rebooted = hypervisor_manager.reboot();
+            audit.record("reboot", audit::outcome(serde_json::json!({ "reason": format!("probe of {} failed", target) }), &rebooted));
+            match rebooted {
+                Ok(_) => {
+                    write_runtime_state("running", store);
+                    events.emit(EventKind::Rebooted, Some(&format!("probe of {} failed", target)));
+                    true
+                },
+                Err(e) => {
+                    error!("Failed to reboot guest: {}", e);
+                    events.emit(EventKind::Crashed, Some(&format!("reboot failed: {:#}", e)));
+                    false
+                },
+            }
+        },
+        ProbeAction::Shutdown => {
+            error!("Shutting down VM after failed probe of {}", target);
+            exit_signal.store(true, Ordering::SeqCst);
+            false
+        },
+    }
+}
+
+// How `start` ends once its VM has shut down
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Exit {
+    /// Stopped by the host, through `stop` or a signal
+    Stop,
+    /// Boot again in place, as asked by `restart`
+    Restart,
+    /// Exec the new binary, which restores the paused VM, as asked by `upgrade`
+    Upgrade,
+    /// The guest reset on its own with `watchdog = "crash"`
+    Crashed,
+    /// The guest powered itself off
+    Poweroff,
+    /// The guest rebooted itself with `on_reboot = "stop"`
+    Reboot,
+    /// The guest kernel panicked, as its log shows
+    Panic,
+    /// The VMM stopped with an error while the VM ran
+    VmmError(String),
+    /// The VM did not boot, or the guest did not report ready, within `--boot-timeout`, in seconds
+    BootTimeout(u64),
+}
+
+impl Exit {
+    // Reason recorded as `last_exit` in the state record, with its detail
+    fn reason(&self) -> (&'static str, Option<String>) {
+        match self {
+            Exit::Stop => ("stopped", None),
+            Exit::Restart => ("restart", None),
+            Exit::Upgrade => ("upgrade", None),
+            Exit::Crashed => ("watchdog", Some("guest reset by the watchdog".to_string())),
+            Exit::Poweroff => ("poweroff", None),
+            Exit::Reboot => ("reboot", None),
+            Exit::Panic => ("panic", Some("guest kernel panic".to_string())),
+            Exit::VmmError(e) => ("vmm_error", Some(e.clone())),
+            Exit::BootTimeout(secs) => ("boot_timeout", Some(format!("VM not booted or guest not ready within {}s", secs))),
+        }
+    }
+}
+
+// The running VM an upgrade hands to the new binary, in the upgrade file
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Handoff {
+    /// Snapshot directory of the paused VM
+    snapshot: String,
+    /// ID of the VM, which names its overlay and its SMBIOS UUID
+    id: String,
+    /// Ephemeral overlay the VM writes to
+    overlay: Option<String>,
+    /// Whether the workload was ready, as it still is once the VM runs on
+    ready: bool,
+}
+
+// Take the handoff an upgrade left for this process, if any
+fn take_handoff() -> Option<Handoff> {
+    let upgrade_file = get_upgrade_file_path();
+    let contents = std::fs::read_to_string(&upgrade_file).ok()?;
+    let _ = std::fs::remove_file(&upgrade_file);
+    match serde_json::from_str(&contents) {
+        Ok(handoff) => Some(handoff),
+        Err(e) => {
+            warn!("Ignoring unreadable upgrade file {}: {}", upgrade_file, e);
+            None
+        },
+    }
+}
+
+// Snapshot the running VM into the state directory and leave the handoff for the new binary;
+// on failure the VM runs on and `upgrade` gets the reason
+fn suspend_for_upgrade(hypervisor_manager: &mut dyn HypervisorBackend, config: &HypervisorConfig,
+                       vm_id: &str, ephemeral_overlay_path: Option<&str>) -> Result<()> {
+    let snapshot = format!("{}/{}", get_upgrade_snapshot_dir(config), vm_id);
+    let _ = std::fs::remove_dir_all(&snapshot);
+    std::fs::create_dir_all(&snapshot)
+        .context(format!("Failed to create snapshot directory: {}", snapshot))?;
+    // Suspending leaves the VM paused, so readiness has to be read before
+    let ready = hypervisor_manager.is_ready();
+    let suspended = hypervisor_manager.suspend(&snapshot);
+    config.audit.record("suspend", audit::outcome(serde_json::json!({ "snapshot": snapshot }), &suspended));
+    if let Err(e) = suspended {
+        let _ = std::fs::remove_dir_all(&snapshot);
+        return Err(e);
+    }
+    
+    let handoff = Handoff {
+        snapshot: snapshot.clone(),
+        id: vm_id.to_string(),
+        overlay: ephemeral_overlay_path.map(str::to_string),
+        ready,
+    };
+    let upgrade_file = get_upgrade_file_path();
+    let written = serde_json::to_string(&handoff).map_err(anyhow::Error::from)
+        .and_then(|handoff| std::fs::write(&upgrade_file, handoff)
+            .context(format!("Failed to write upgrade file: {}", upgrade_file)));
+    if let Err(e) = written {
+        // No new process will restore the snapshot, so the VM resumes here
+        let _ = std::fs::remove_dir_all(&snapshot);
+        if let Err(resume) = hypervisor_manager.resume_suspended(ready) {
+            error!("Failed to resume VM after a failed upgrade: {:#}", resume);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Claim the GPUs the runtime names and allocate those it only counts, adding them to its devices.
+// The claims hold until the process exits or releases them, over restarts in place too. Without
+// `claim`, as for a dry run, the GPUs are chosen as they would be but nothing is claimed.
+fn allocate_gpus(config: &mut HypervisorConfig, claim: bool) -> Result<Option<devices::Claims>> {
+    let pinned: Vec<String> = config.device_filepath_list.iter()
+        .filter(|path| Path::new(path).starts_with(devices::pci_devices()))
+        .filter_map(|path| Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()))
+        .collect();
+    let count = config.gpu_request.as_ref().map_or(0, |request| request.count);
+    if pinned.is_empty() && count == 0 {
+        return Ok(None);
+    }
+    let selector = config.gpu_request.as_ref()
+        .and_then(|request| request.selector.as_deref())
+        .map(GpuSelector::parse)
+        .transpose()?;
+    
+    // GPUs other runtimes of the configuration file name stay theirs, running or not
+    let name = env::var(RUNTIME_NAME_VAR).ok();
+    let reserved: Vec<String> = match Manifest::load(&get_config_file_path()) {
+        Ok(manifest) if count > 0 => manifest.runtimes.iter()
+            .filter(|runtime| Some(&runtime.name) != name.as_ref())
+            .flat_map(|runtime| runtime.gpus.addresses().iter().cloned())
+            .collect(),
+        _ => Vec::new(),
+    };
+    
+    let claims = devices::Claims::new(&config.state_dir);
+    let allocated = if claim {
+        claims.claim(name.as_deref(), &pinned, count, selector.as_ref(), &reserved)?
+    } else {
+        claims.plan(&pinned, count, selector.as_ref(), &reserved)?
+    };
+    for address in &allocated {
+        info!("Allocated GPU {}", address);
+        config.device_filepath_list.push(devices::pci_devices().join(address).to_string_lossy().into_owned());
+    }
+    
+    // Tensor parallelism is only as fast as the slowest link between the GPUs
+    let gpus: Vec<String> = pinned.into_iter()
+        .filter(|address| devices::is_accelerator_address(address))
+        .chain(allocated)
+        .collect();
+    if gpus.len() > 1 {
+        config.gpu_topology = devices::Topology::read().links(&gpus);
+        let links: Vec<String> = config.gpu_topology.iter()
+            .map(|link| format!("{}-{} {}", link.gpus[0], link.gpus[1], link.link.as_str()))
+            .collect();
+        info!("GPU links: {}", links.join(", "));
+        if let Some(link) = config.gpu_topology.iter().find(|link| link.link == devices::Link::System) {
+            warn!("GPUs {} and {} are on different sockets; traffic between them crosses the socket interconnect",
+                  link.gpus[0], link.gpus[1]);
+        }
+    }
+    Ok(claim.then_some(claims))
+}
+
+fn start_hypervisor(config: &HypervisorConfig, wait_ready: bool, run_as: Option<&RunAs>,
+                    boot_timeout: Option<std::time::Duration>) -> Result<Exit> {
     info!("Starting hypervisor with configuration: {:?}", config);
     
+    // Readiness comes from the guest over vsock or from the first healthy probe
+    if wait_ready && config.vsock_cid.is_none() && config.probe.is_none() {
+        bail!("--wait-ready requires {} or a probe to be set", VSOCK_CID_VAR);
+    }
+    
+    // The runtime files, and the overlay named after the UUID of the runtime, belong to the
+    // hypervisor running it; a process that restarts or upgrades in place keeps its PID
+    if let Some(pid) = fleet::running_pid(Path::new(&get_pid_file_path())).filter(|pid| *pid != std::process::id()) {
+        bail!("The runtime is already running (PID: {})", pid);
+    }
+    
+    // Check signatures before anything reads the artifacts; ephemeral runtimes verify the base image
+    if let Some(trust) = &config.trust {
+        if let Some(kernel_filepath) = &config.kernel_filepath {
+            trust.check("kernel", kernel_filepath, config.kernel_signature.as_deref(), Some(&config.audit))?;
+        }
+        trust.check("system image", &config.system_image_filepath, config.system_image_signature.as_deref(), Some(&config.audit))?;
+    }
+    
+    for message in &config.cmdline_overrides {
+        warn!("Kernel command line override: {}", message);
+    }
+    
+    if let Some(oom_score_adj) = config.oom_score_adj {
+        limits::set_oom_score_adj(oom_score_adj)?;
+    }
+    
+    // VFIO pins all of guest memory; raise the limit now rather than fail DMA mapping at boot
+    let memory_size = guest_memory_size(&config.memory_config, &config.memory_zone_list)?;
+    if !config.device_filepath_list.is_empty() && !doctor::has_capability("self", doctor::CAP_IPC_LOCK) {
+        if let Err(e) = limits::raise_memlock(memory_size) {
+            // The preflight check below reports the limit
+            warn!("{:#}", e);
+        }
+    }
+    
+    // A device the previous VM released a moment ago can still be held or busy, so the device
+    // steps are tried again before the start fails
+    let retry = RetryPolicy {
+        retries: config.device_retries,
+        delay: std::time::Duration::from_millis(config.device_retry_delay_ms),
+    };
+    
+    // Report every missing prerequisite at once rather than the first EPERM from the VMM
+    let mut tries = 0;
+    retry.run("Preflight checks", || {
+        tries += 1;
+        let checks = doctor::run(config.backend, config.confidential, config.nested,
+                                 &config.qgs_socket_path, &config.device_filepath_list, memory_size);
+        doctor::failures("Preflight check", &checks, tries > retry.retries)
+    })?;
+    
+    // Host side settings of the devices, such as the GUIDs of an RDMA VF
+    for device in &config.device_list {
+        let configured = retry.run(&format!("Configuring device {}", device.pci), || device.configure());
+        if device.node_guid.is_some() || device.port_guid.is_some() {
+            config.audit.record("configure_device", audit::outcome(serde_json::json!({ "device": device.pci, "vf": device.vf }), &configured));
+        }
+        configured?;
+    }
+    
+    // A VM that crashed can leave a device stuck; reset it before this VM opens it
+    if config.gpu_reset {
+        retry.run("Device reset", || devices::reset_all(&config.device_filepath_list, &config.audit))?;
+    }
+    
+    // A device that fell off the bus or flags an error boots fine and fails the guest driver later
+    let mut tries = 0;
+    retry.run("Device health checks", || {
+        tries += 1;
+        doctor::failures("Device health check", &doctor::health(&config.device_filepath_list), tries > retry.retries)
+    })?;
+    
+    // Host resources are acquired as root; fail now if the user could not maintain the runtime files later
+    let pid_file = get_pid_file_path();
+    let pid_dir = Path::new(&pid_file).parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_else(|| ".".to_string());
+    let overlay_dir = format!("{}/overlays", config.state_dir);
+    let vsock_dir = Path::new(&get_vsock_socket_path()).parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .filter(|_| config.vsock_cid.is_some());
+    if let Some(run_as) = run_as {
+        if !nix::unistd::Uid::effective().is_root() {
+            bail!("--run-as requires starting as root");
+        }
+        // The VMM creates its sockets as the user
+        let mut dirs = vec![pid_dir.as_str()];
+        dirs.extend(vsock_dir.as_deref());
+        if config.system_image_ephemeral {
+            std::fs::create_dir_all(&overlay_dir)
+                .context(format!("Failed to create overlay directory: {}", overlay_dir))?;
+            run_as.chown(&[&overlay_dir])?;
+            dirs.push(&overlay_dir);
+        }
+        run_as.check_writable(&dirs)?;
+    }
+    
     // Create exit signal for clean shutdown
     let exit_signal = Arc::new(AtomicBool::new(false));
     let exit_signal_clone = exit_signal.clone();
+    let dump_signal = Arc::new(AtomicBool::new(false));
+    let dump_signal_clone = dump_signal.clone();
+    let restart_requested = Arc::new(AtomicBool::new(false));
+    let restart_requested_clone = restart_requested.clone();
+    let upgrade_requested = Arc::new(AtomicBool::new(false));
+    let upgrade_requested_clone = upgrade_requested.clone();
+    
+    // A VM the process it replaced suspended for an upgrade is restored instead of booted
+    let handoff = take_handoff();
     
     // Set up signal handler
-    let mut signals = Signals::new(&[SIGTERM, SIGINT, SIGHUP])?;
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2, restart_signal(), upgrade_signal()])?;
     let handle = signals.handle();
     
     // Save process ID to file for stop command
     save_vm_pid()?;
+    // A restored VM runs with the configuration it booted with, which the file still holds
+    if handoff.is_none() {
+        write_resolved_config(config);
+    }
+    config.store.booting(std::process::id());
+    
+    // Remove any API socket left behind by a previous run
+    let api_socket_path = get_api_socket_path();
+    if Path::new(&api_socket_path).exists() {
+        std::fs::remove_file(&api_socket_path)
+            .context(format!("Failed to remove stale API socket: {}", api_socket_path))?;
+    }
+    
+    let console_socket_path = get_console_socket_path();
+    if Path::new(&console_socket_path).exists() {
+        std::fs::remove_file(&console_socket_path)
+            .context(format!("Failed to remove stale console socket: {}", console_socket_path))?;
+    }
+    
+    let vsock_socket_path = get_vsock_socket_path();
+    if config.vsock_cid.is_some() && Path::new(&vsock_socket_path).exists() {
+        std::fs::remove_file(&vsock_socket_path)
+            .context(format!("Failed to remove stale vsock socket: {}", vsock_socket_path))?;
+    }
+    
+    // Once root is dropped, a new `start` could not acquire the host resources again
+    let can_restart = run_as.is_none();
+    let restart_file = get_restart_file_path();
     
     thread::spawn(move || {
         for sig in signals.forever() {
+            if sig == restart_signal() {
+                if can_restart {
+                    info!("Received restart request");
+                    restart_requested_clone.store(true, Ordering::SeqCst);
+                    exit_signal_clone.store(true, Ordering::SeqCst);
+                } else {
+                    // `restart` reports the reason it finds in the file
+                    let reason = "the hypervisor runs without root and cannot boot again in place";
+                    warn!("Ignoring restart request: {}", reason);
+                    let _ = std::fs::write(&restart_file, reason);
+                }
+                continue;
+            }
+            if sig == upgrade_signal() {
+                if can_restart {
+                    info!("Received upgrade request");
+                    upgrade_requested_clone.store(true, Ordering::SeqCst);
+                } else {
+                    let reason = "the hypervisor runs without root and cannot exec a new binary";
+                    warn!("Ignoring upgrade request: {}", reason);
+                    let _ = std::fs::write(&restart_file, reason);
+                }
+                continue;
+            }
+            if sig == SIGUSR2 {
+                // Logged after the flag is set, so the message opens the new file
+                REOPEN_LOG.store(true, Ordering::SeqCst);
+                info!("Received signal {:?}, reopened log file", sig);
+                continue;
+            }
             info!("Received signal {:?}", sig);
+            if sig == SIGUSR1 {
+                dump_signal_clone.store(true, Ordering::SeqCst);
+                continue;
+            }
             exit_signal_clone.store(true, Ordering::SeqCst);
         }
     });
     
     // Create a new hypervisor manager
-    let mut hypervisor_manager = HypervisorManager::new()?;
+    let mut hypervisor_manager = backend::manager(config.backend)?;
     
-    // Parse memory configuration
-    let memory_config = parse_memory_string(&config.memory_config)?;
+    // The VM takes the UUID of the runtime, the same across restarts; a restored VM keeps its own,
+    // and the overlay it writes to
+    let vm_id = match &handoff {
+        Some(handoff) => handoff.id.clone(),
+        None => config.store.uuid()?,
+    };
     
-    // Generate a UUID for the VM
-    let vm_id = uuid::Uuid::new_v4().to_string();
+    let ephemeral_overlay_path = if let Some(handoff) = &handoff {
+        handoff.overlay.clone()
+    } else if config.system_image_ephemeral {
+        // A hypervisor of the runtime that crashed left its overlay behind under the same name
+        let path = format!("{}/{}-system.qcow2", overlay_dir, vm_id);
+        match std::fs::remove_file(&path) {
+            Ok(()) => warn!("Removed the stale ephemeral overlay {}", path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e).context(format!("Failed to remove stale overlay: {}", path)),
+        }
+        Some(path)
+    } else {
+        None
+    };
+    // The VMM writes the snapshot of an upgrade; Landlock only admits a path that exists
+    if config.landlock {
+        let snapshot_dir = get_upgrade_snapshot_dir(config);
+        std::fs::create_dir_all(&snapshot_dir)
+            .context(format!("Failed to create snapshot directory: {}", snapshot_dir))?;
+    }
     
     // Create VM configuration
+    let restore_path = handoff.as_ref().map(|handoff| handoff.snapshot.clone());
     let vm_config = VmConfig {
-        id: vm_id,
-        kernel_path: config.kernel_filepath.clone(),
-        cmdline: config.cmdline.clone(),
-        system_image_path: config.system_image_filepath.clone(),
-        config_image_path: config.config_image_filepath.clone(),
-        vcpu_count: config.cpu_count,
-        memory_config,
-        device_paths: config.device_filepath_list.clone(),
-        debug: config.debug,
+        run_as: run_as.cloned(),
+        boot_timeout,
+        ..vm_config(config, &vm_id, ephemeral_overlay_path.clone(), restore_path)?
     };
     
+    if let Some(parent) = config.guest_log_filepath.as_deref().and_then(|path| Path::new(path).parent()) {
+        std::fs::create_dir_all(parent)
+            .context(format!("Failed to create guest log directory: {}", parent.display()))?;
+    }
+    // A panic the guest log shows from here on, while booting too, belongs to this VM
+    let mut panic_watch = config.guest_log_filepath.as_deref().map(|path| PanicWatch::new(Path::new(path)));
+    
+    // The VMM starts as the user and opens everything after the switch. Hand over what the
+    // runtime keeps using, and fail now on the files the user could not open.
+    if let Some(run_as) = run_as {
+        let mut owned = vec![pid_file.as_str()];
+        if let Some(guest_log) = config.guest_log_filepath.as_deref() {
+            std::fs::OpenOptions::new().create(true).append(true).open(guest_log)
+                .context(format!("Failed to create guest log: {}", guest_log))?;
+            owned.push(guest_log);
+        }
+        owned.extend(config.store.path().to_str().filter(|_| config.store.path().exists()));
+        run_as.chown(&owned)?;
+        run_as.check_accessible(&HypervisorManager::vmm_paths(&vm_config))?;
+        // The audit log is shared by every runtime of the host and stays root's; keep it open instead
+        config.audit.hold()?;
+    }
+    
     // Configure the hypervisor
-    hypervisor_manager.configure(vm_config)?;
+    let create_params = serde_json::json!({
+        "id": vm_config.id,
+        "backend": format!("{:?}", config.backend).to_lowercase(),
+        "kernel": vm_config.kernel_path,
+        "firmware": vm_config.firmware_path,
+        "initramfs": vm_config.initramfs_path,
+        "cmdline": vm_config.cmdline,
+        "system_image": vm_config.system_image_path,
+        "config_image": vm_config.config_image_path,
+        "ephemeral_overlay": vm_config.ephemeral_overlay_path,
+        "vcpus": vm_config.vcpu_count,
+        "max_vcpus": vm_config.max_vcpu_count,
+        "cpu": config.cpu_config,
+        "memory": config.memory_config,
+        "devices": vm_config.device_paths,
+        "disks": vm_config.disks.iter().map(|disk| disk.path.clone()).collect::<Vec<_>>(),
+        "confidential": config.confidential.map(|mode| format!("{:?}", mode).to_lowercase()),
+    });
+    let configured = hypervisor_manager.configure(vm_config);
+    config.audit.record("create", audit::outcome(create_params.clone(), &configured));
+    configured?;
+    config.events.emit(EventKind::Configured, None);
+    
+    // Listen for the guest ready signal before the guest can send it
+    let ready_receiver = if config.vsock_cid.is_some() {
+        Some(hypervisor_manager.ready_signal()?)
+    } else {
+        None
+    };
+    
+    // TDX guests reach the Quote Generation Service through the vsock device
+    if config.confidential == Some(ConfidentialMode::Tdx) {
+        if config.vsock_cid.is_some() {
+            hypervisor_manager.quote_relay(&config.qgs_socket_path)?;
+        } else {
+            warn!("TDX guest without {}; it cannot obtain attestation quotes", VSOCK_CID_VAR);
+        }
+    }
+    
+    let boot_started = std::time::Instant::now();
     
-    // Start the hypervisor
-    hypervisor_manager.start()?;
+    // Start the hypervisor, releasing anything a partial start left behind
+    let started = hypervisor_manager.start();
+    config.audit.record(if handoff.is_some() { "restore" } else { "boot" }, audit::outcome(serde_json::json!({}), &started));
+    // The VMM thread that hangs is left to the exit of the process; the rest shuts down as usual
+    let timed_out = started.as_ref().err()
+        .and_then(|e| e.downcast_ref::<HypervisorError>())
+        .and_then(|e| match e {
+            HypervisorError::BootTimeout(secs) => Some(*secs),
+            _ => None,
+        });
+    if let Some(secs) = timed_out {
+        error!("The VM did not boot within {}s; giving up", secs);
+        config.events.emit(EventKind::Crashed, Some("boot timeout"));
+        if let Some(handoff) = &handoff {
+            error!("Kept snapshot {} and overlay {} of the VM that failed to restore", handoff.snapshot,
+                   handoff.overlay.as_deref().unwrap_or("(none)"));
+        }
+        return shut_down(Exit::BootTimeout(secs), hypervisor_manager.as_mut(), config, run_as, &handle);
+    }
+    if let Err(e) = started {
+        config.events.emit(EventKind::Crashed, Some(&format!("boot failed: {:#}", e)));
+        let _ = hypervisor_manager.shutdown();
+        // The snapshot and overlay hold the only state of the guest
+        if let Some(handoff) = &handoff {
+            error!("Kept snapshot {} and overlay {} of the VM that failed to restore", handoff.snapshot,
+                   handoff.overlay.as_deref().unwrap_or("(none)"));
+        }
+        return Err(e);
+    }
+    // The restored VM no longer needs its snapshot
+    if let Some(handoff) = &handoff {
+        if let Err(e) = std::fs::remove_dir_all(&handoff.snapshot) {
+            warn!("Failed to remove snapshot {}: {}", handoff.snapshot, e);
+        }
+    }
     
     info!("VM started successfully");
+    if handoff.is_some() {
+        config.events.emit(EventKind::Resumed, Some("upgrade"));
+    } else {
+        config.events.emit(EventKind::Booted, None);
+    }
+    // `up` and `down` find the named runtimes they started by the configuration file
+    let manifest = env::var(RUNTIME_NAME_VAR).ok()
+        .and_then(|_| std::fs::canonicalize(get_config_file_path()).ok())
+        .map(|path| path.to_string_lossy().into_owned());
+    config.store.update(|record| {
+        if handoff.is_none() {
+            record.boot_count += 1;
+        }
+        record.manifest = manifest;
+    });
+    
+    let booted_at = std::time::Instant::now();
+    let mut timings = hypervisor_manager.boot_timings();
+    info!("Boot timing: {}", timings.summary());
+    write_boot_timings(&timings);
+    
+    write_runtime_state("running", &config.store);
+    if handoff.as_ref().is_some_and(|handoff| handoff.ready) {
+        // The guest told the binary this one replaced; it will not tell again
+        hypervisor_manager.mark_ready();
+        write_runtime_state("ready", &config.store);
+        finish_restart();
+        notify_systemd("READY=1\nSTATUS=Workload ready");
+    } else if !wait_ready {
+        notify_systemd("READY=1\nSTATUS=VM running");
+    }
+    // A runtime that cannot report readiness is back from a restart once its VM runs
+    if config.vsock_cid.is_none() && config.probe.is_none() {
+        finish_restart();
+    }
+    
+    if config.probe.as_ref().is_some_and(|probe| probe.kind == ProbeKind::Agent) && config.vsock_cid.is_none() {
+        warn!("Agent probe configured without {}; the probe will never pass", VSOCK_CID_VAR);
+    }
+    let probe = config.probe.clone()
+        .map(|probe| probe::spawn(probe, vsock_socket_path.clone()));
+    if let Some(policy) = config.balloon.clone() {
+        balloon::spawn(policy, api_socket_path.clone(), memory_size)?;
+    }
+    // Deleted on every way out of here, so node_exporter stops exporting a runtime that stopped
+    let _textfile = match &config.textfile_dir {
+        Some(dir) => {
+            let runtime = env::var(RUNTIME_NAME_VAR).ok();
+            let source = textfile::Source {
+                runtime: runtime.clone().unwrap_or_else(|| "hypervisor".to_string()),
+                uuid: vm_id.clone(),
+                labels: config.labels.clone(),
+                api_socket_path: api_socket_path.clone(),
+                vsock_socket_path: config.vsock_cid.map(|_| vsock_socket_path.clone()),
+                state_file_path: get_state_file_path(),
+                store: config.store.clone(),
+            };
+            let name = runtime.map(|name| format!("vllmd-hypervisor-{}", name)).unwrap_or_else(|| "vllmd-hypervisor".to_string());
+            let interval = std::time::Duration::from_secs(config.textfile_interval_secs);
+            Some(Textfile::spawn(dir, &name, interval, source)?)
+        },
+        None => None,
+    };
+    let health_file = get_health_file_path();
+    let _ = std::fs::remove_file(&health_file);
+    let mut reset_detector = ResetDetector::new(config.cpu_count);
+    let mut crashed = false;
+    let mut ended = None;
     
     // Wait for exit signal
     while !exit_signal.load(Ordering::SeqCst) {
+        // The guest powered off, or the VMM failed, without the host asking
+        if let Some(vm_exit) = hypervisor_manager.exited() {
+            ended = Some(match vm_exit {
+                VmExit::Poweroff if panic_watch.as_mut().is_some_and(|watch| watch.panicked()) => {
+                    error!("Guest kernel panicked and the VM powered off");
+                    config.events.emit(EventKind::Crashed, Some("guest kernel panic"));
+                    Exit::Panic
+                },
+                VmExit::Poweroff => {
+                    info!("Guest powered off");
+                    Exit::Poweroff
+                },
+                VmExit::VmmError(e) => {
+                    error!("VMM stopped with an error: {}", e);
+                    config.events.emit(EventKind::Crashed, Some(&format!("VMM error: {}", e)));
+                    Exit::VmmError(e)
+                },
+            });
+            break;
+        }
+        
+        // A panicked guest halts, or resets with panic= on its command line, without powering off
+        if panic_watch.as_mut().is_some_and(|watch| watch.check()) {
+            error!("Guest kernel panicked; shutting down the VM");
+            config.events.emit(EventKind::Crashed, Some("guest kernel panic"));
+            ended = Some(Exit::Panic);
+            break;
+        }
+        
+        let mut became_ready = ready_receiver.as_ref().is_some_and(|receiver| receiver.try_recv().is_ok());
+        
+        if let Some(health) = probe.as_ref().and_then(|probe| probe.health.try_recv().ok()) {
+            if let Err(e) = std::fs::write(&health_file, health.as_str()) {
+                warn!("Failed to write health file {}: {}", health_file, e);
+            }
+            if health == probe::Health::Healthy {
+                became_ready = true;
+            } else if let Some(probe_config) = config.probe.as_ref() {
+                let rebooted = remediate(hypervisor_manager.as_mut(), probe_config, &config.events, &config.audit, &config.store, &exit_signal);
+                // A reboot the probe asked for is not one the guest or the watchdog caused
+                reset_detector.rebase();
+                if let Some(probe) = probe.as_ref().filter(|_| rebooted) {
+                    probe.rearm();
+                }
+            }
+        }
+        
+        // The VMM resets the guest itself when the guest reboots or the watchdog expires; the host
+        // only sees new vCPUs
+        let vcpu_change = reset_detector.check();
+        if vcpu_change == Some(VcpuChange::Resized) {
+            info!("vCPUs were added or removed");
+            hypervisor_manager.vcpus_resized();
+        }
+        if vcpu_change == Some(VcpuChange::Reset) {
+            if config.watchdog == Some(WatchdogAction::Crash) {
+                error!("Guest reset without a host request, as the watchdog does; shutting down the VM");
+                config.events.emit(EventKind::Crashed, Some("guest reset by the watchdog"));
+                crashed = true;
+                exit_signal.store(true, Ordering::SeqCst);
+            } else if config.on_reboot == RebootPolicy::Stop {
+                info!("Guest rebooted and on_reboot is stop; shutting down the VM");
+                ended = Some(Exit::Reboot);
+                break;
+            } else if config.watchdog == Some(WatchdogAction::Reset) {
+                warn!("Guest reset without a host request, as the watchdog does; waiting for it to become ready again");
+                hypervisor_manager.guest_reset();
+                if let Some(probe) = &probe {
+                    probe.rearm();
+                }
+                write_runtime_state("running", &config.store);
+                config.events.emit(EventKind::Rebooted, Some("guest reset by the watchdog"));
+            } else {
+                info!("Guest rebooted; waiting for it to become ready again");
+                hypervisor_manager.guest_reset();
+                if let Some(probe) = &probe {
+                    probe.rearm();
+                }
+                write_runtime_state("running", &config.store);
+                config.events.emit(EventKind::Rebooted, Some("guest reboot"));
+            }
+        }
+        
+        if became_ready && hypervisor_manager.is_running() && !hypervisor_manager.is_ready() {
+            hypervisor_manager.mark_ready();
+            write_runtime_state("ready", &config.store);
+            config.events.emit(EventKind::Ready, None);
+            // Only the first boot counts; a guest rebooted by a probe becomes ready again later
+            if timings.guest_ready_ms.is_none() {
+                timings.guest_ready_ms = Some(hypervisor::elapsed_ms(booted_at));
+                info!("Boot timing: {}", timings.summary());
+                write_boot_timings(&timings);
+            }
+            finish_restart();
+            notify_systemd(if wait_ready { "READY=1\nSTATUS=Workload ready" } else { "STATUS=Workload ready" });
+        }
+        
+        // A guest that never reports ready after booting is given up like a boot that hangs
+        if let Some(timeout) = boot_timeout.filter(|_| config.vsock_cid.is_some() || config.probe.is_some()) {
+            if timings.guest_ready_ms.is_none() && !hypervisor_manager.is_ready() && boot_started.elapsed() > timeout {
+                error!("Guest not ready within {}s of booting; shutting down the VM", timeout.as_secs());
+                config.events.emit(EventKind::Crashed, Some("boot timeout"));
+                ended = Some(Exit::BootTimeout(timeout.as_secs()));
+                break;
+            }
+        }
+        
+        if dump_signal.swap(false, Ordering::SeqCst) {
+            dump_state(config, hypervisor_manager.as_ref(), &create_params);
+        }
+        
+        if upgrade_requested.swap(false, Ordering::SeqCst) {
+            match suspend_for_upgrade(hypervisor_manager.as_mut(), config, &vm_id, ephemeral_overlay_path.as_deref()) {
+                Ok(()) => {
+                    ended = Some(Exit::Upgrade);
+                    break;
+                },
+                Err(e) => {
+                    // `upgrade` reports the reason it finds in the file
+                    warn!("Upgrade failed, the VM runs on: {:#}", e);
+                    let _ = std::fs::write(get_restart_file_path(), format!("{:#}", e));
+                },
+            }
+        }
+        
         thread::sleep(std::time::Duration::from_millis(100));
     }
     
+    let exit = if let Some(exit) = ended {
+        exit
+    } else if crashed {
+        Exit::Crashed
+    } else if restart_requested.load(Ordering::SeqCst) {
+        Exit::Restart
+    } else {
+        Exit::Stop
+    };
+    shut_down(exit, hypervisor_manager.as_mut(), config, run_as, &handle)
+}
+
+// Shut the VM down and leave the host as it was before `start`, except for what a restart or an
+// upgrade in place keeps
+fn shut_down(exit: Exit, hypervisor_manager: &mut dyn HypervisorBackend, config: &HypervisorConfig, run_as: Option<&RunAs>,
+             handle: &signal_hook::iterator::Handle) -> Result<Exit> {
     info!("Shutting down VM");
+    match exit {
+        // systemd keeps the service up until the new process reports READY=1
+        Exit::Restart => notify_systemd("RELOADING=1\nSTATUS=Restarting VM"),
+        Exit::Upgrade => notify_systemd("RELOADING=1\nSTATUS=Upgrading the hypervisor"),
+        Exit::Stop => notify_systemd("STOPPING=1"),
+        Exit::Crashed => notify_systemd("STOPPING=1\nSTATUS=Guest reset by the watchdog"),
+        Exit::Poweroff => notify_systemd("STOPPING=1\nSTATUS=Guest powered off"),
+        Exit::Reboot => notify_systemd("STOPPING=1\nSTATUS=Guest rebooted"),
+        Exit::Panic => notify_systemd("STOPPING=1\nSTATUS=Guest kernel panic"),
+        Exit::VmmError(_) => notify_systemd("STOPPING=1\nSTATUS=VMM error"),
+        Exit::BootTimeout(_) => notify_systemd("STOPPING=1\nSTATUS=Boot timed out"),
+    }
     
     // Shutdown the hypervisor
-    hypervisor_manager.shutdown()?;
+    let stopped = hypervisor_manager.shutdown();
+    config.audit.record("shutdown", audit::outcome(serde_json::json!({ "restart": exit == Exit::Restart }), &stopped));
+    stopped?;
+    config.events.emit(EventKind::Shutdown, match exit {
+        Exit::Restart => Some("restart"),
+        Exit::Upgrade => Some("upgrade"),
+        Exit::Poweroff => Some("poweroff"),
+        Exit::Reboot => Some("reboot"),
+        _ => None,
+    });
+    
+    // Leave the devices clean for the next VM. Without root only the next start can reset them.
+    if config.gpu_reset && run_as.is_none() {
+        if let Err(e) = devices::reset_all(&config.device_filepath_list, &config.audit) {
+            warn!("{:#}", e);
+        }
+    }
     
     // Clean up signal handler
     handle.close();
     
-    // Remove PID file
-    let pid_file = get_pid_file_path();
-    if let Err(e) = std::fs::remove_file(&pid_file) {
-        debug!("Failed to remove PID file {}: {}", pid_file, e);
+    remove_runtime_files(exit != Exit::Upgrade);
+    if !matches!(exit, Exit::Restart | Exit::Upgrade) {
+        finish_restart();
+    }
+    
+    info!("VM shutdown complete");
+    
+    Ok(exit)
+}
+
+// Remove the PID file, the sockets and the state files of the runtime, and with
+// `resolved_config` the configuration it booted with, which an upgrade hands on
+fn remove_runtime_files(resolved_config: bool) {
+    let pid_file = get_pid_file_path();
+    if let Err(e) = std::fs::remove_file(&pid_file) {
+        debug!("Failed to remove PID file {}: {}", pid_file, e);
+    }
+    for socket in [get_api_socket_path(), get_console_socket_path(), get_vsock_socket_path()] {
+        if let Err(e) = std::fs::remove_file(&socket) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                debug!("Failed to remove socket {}: {}", socket, e);
+            }
+        }
+    }
+    if let Err(e) = std::fs::remove_file(get_state_file_path()) {
+        debug!("Failed to remove state file: {}", e);
+    }
+    let _ = std::fs::remove_file(get_health_file_path());
+    let _ = std::fs::remove_file(get_timing_file_path());
+    if resolved_config {
+        let _ = std::fs::remove_file(get_resolved_config_file_path());
+    }
+}
+
+// Check what `start` would boot as it would, then print the configuration it would send the VMM,
+// the host changes it would make and the preflight checks, without booting or changing anything.
+// Exits with an error after printing when a check fails.
+fn dry_run_start(mut config: HypervisorConfig) -> Result<()> {
+    // The VM gets the GPUs `start` would allocate now
+    let configured_devices = config.device_filepath_list.len();
+    allocate_gpus(&mut config, false)?;
+    let config = &config;
+    
+    // Signature checks only read, and the dry run leaves them out of the audit log
+    if let Some(trust) = &config.trust {
+        if let Some(kernel_filepath) = &config.kernel_filepath {
+            trust.check("kernel", kernel_filepath, config.kernel_signature.as_deref(), None)?;
+        }
+        trust.check("system image", &config.system_image_filepath, config.system_image_signature.as_deref(), None)?;
+    }
+    
+    let memory_size = guest_memory_size(&config.memory_config, &config.memory_zone_list)?;
+    let mut checks = doctor::run(config.backend, config.confidential, config.nested,
+                                 &config.qgs_socket_path, &config.device_filepath_list, memory_size);
+    checks.extend(doctor::health(&config.device_filepath_list));
+    if let Err(e) = check_not_drained(&config.state_dir) {
+        checks.push(doctor::Check { status: doctor::Status::Fail, description: format!("{:#}", e), transient: false });
+    }
+    
+    // A runtime that never ran gets its UUID on the first real start
+    let vm_id = config.store.load()?
+        .map(|record| record.uuid)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let ephemeral_overlay_path = config.system_image_ephemeral
+        .then(|| format!("{}/overlays/{}-system.qcow2", config.state_dir, vm_id));
+    let vm_config = vm_config(config, &vm_id, ephemeral_overlay_path.clone(), None)?;
+    let planned = HypervisorManager::planned_vm_config(&vm_config)?;
+    
+    let mut host_changes = Vec::new();
+    if let Some(oom_score_adj) = config.oom_score_adj {
+        host_changes.push(format!("set oom_score_adj of the hypervisor to {}", oom_score_adj));
+    }
+    if !config.device_filepath_list.is_empty() && !doctor::has_capability("self", doctor::CAP_IPC_LOCK) {
+        host_changes.push(format!("raise RLIMIT_MEMLOCK to {} bytes", memory_size));
+    }
+    for device in &config.device_filepath_list[configured_devices..] {
+        host_changes.push(format!("allocate GPU {}", device));
+    }
+    for device in &config.device_list {
+        host_changes.extend(device.planned_changes());
+    }
+    if config.gpu_reset {
+        for device in &config.device_filepath_list {
+            host_changes.push(format!("reset device {}", device));
+        }
+    }
+    if let Some(overlay_path) = &ephemeral_overlay_path {
+        host_changes.push(format!("create overlay {} backed by {}", overlay_path, config.system_image_filepath));
+    }
+    if config.tpm {
+        host_changes.push(format!("start swtpm on {} with its state in {}", get_tpm_socket_path(), get_tpm_state_dir(&config.state_dir)));
+    }
+    
+    let report = serde_json::json!({
+        "vm_config": planned,
+        "host_changes": host_changes,
+        "checks": checks.iter().map(|check| serde_json::json!({
+            "status": format!("{:?}", check.status).to_lowercase(),
+            "description": check.description,
+        })).collect::<Vec<_>>(),
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    
+    let failures = doctor::failures(&checks);
+    if !failures.is_empty() {
+        bail!("{} preflight check(s) failed: {}", failures.len(), failures.join("; "));
+    }
+    Ok(())
+}
+
+// The VM `start` boots for `config` under `vm_id`, or restores from `restore_path`
+fn vm_config(config: &HypervisorConfig, vm_id: &str, ephemeral_overlay_path: Option<String>,
+             restore_path: Option<String>) -> Result<VmConfig> {
+    Ok(VmConfig {
+        id: vm_id.to_string(),
+        kernel_path: config.kernel_filepath.clone(),
+        cmdline: config.cmdline.clone(),
+        system_image_path: config.system_image_filepath.clone(),
+        config_image_path: config.config_image_filepath.clone(),
+        vcpu_count: config.cpu_count,
+        max_vcpu_count: config.max_cpu_count.unwrap_or(config.cpu_count),
+        cpu_config: parse_cpu_string(&config.cpu_config)?,
+        memory_config: parse_memory_string(&config.memory_config)?,
+        memory_zones: config.memory_zone_list.clone(),
+        sgx_epc: config.sgx_epc_list.clone(),
+        device_paths: config.device_filepath_list.clone(),
+        disks: config.disk_list.clone(),
+        rate_limit_groups: config.rate_limit_group_list.clone(),
+        image_rate_limit_group: config.disk_rate_limit_group.clone(),
+        debug: config.debug,
+        api_socket_path: Some(get_api_socket_path()),
+        ephemeral_overlay_path,
+        free_page_reporting: config.free_page_reporting,
+        rng_source: config.rng.clone(),
+        balloon: config.balloon.is_some(),
+        serial_socket_path: Some(get_console_socket_path()),
+        console_log_path: config.guest_log_filepath.clone(),
+        vsock: config.vsock_cid.map(|cid| VsockConfig {
+            cid,
+            socket_path: get_vsock_socket_path(),
+        }),
+        tpm: config.tpm.then(|| TpmConfig {
+            socket_path: get_tpm_socket_path(),
+            state_dir: get_tpm_state_dir(&config.state_dir),
+        }),
+        confidential: config.confidential,
+        host_data: config.host_data.clone(),
+        firmware_path: config.firmware_filepath.clone(),
+        initramfs_path: config.initramfs_filepath.clone(),
+        seccomp: config.seccomp.clone(),
+        landlock: config.landlock,
+        // The VMM threads log through the host log file too, and write the snapshot of an upgrade
+        landlock_paths: vec![(config.log_filepath.clone(), "rw"), (get_upgrade_snapshot_dir(config), "rw")],
+        vcpu_sched: config.vcpu_sched,
+        run_as: None,
+        watchdog: config.watchdog.is_some(),
+        oem_strings: oem_strings(config, vm_id),
+        restore_path,
+        boot_timeout: None,
+        backend: config.backend,
+    })
+}
+
+// Directory the snapshots of upgrades are written to, one per VM
+fn get_upgrade_snapshot_dir(config: &HypervisorConfig) -> String {
+    format!("{}/upgrade", config.state_dir)
+}
+
+// Replace this process with a new `start` with the same arguments, which reads the configuration
+// file and images again. The PID stays the same, so systemd and `start --all` keep tracking it.
+fn restart_in_place() -> Result<()> {
+    use std::os::unix::process::CommandExt;
+    
+    let mut args = env::args_os();
+    let program = args.next().ok_or_else(|| anyhow!("Failed to restart: no program name"))?;
+    info!("Restarting hypervisor");
+    let error = std::process::Command::new(program).args(args).exec();
+    Err(error).context("Failed to restart the hypervisor")
+}
+
+// Tell a waiting `restart` that the runtime is back
+fn finish_restart() {
+    let restart_file = get_restart_file_path();
+    if Path::new(&restart_file).exists() {
+        info!("Restart complete");
+        if let Err(e) = std::fs::remove_file(&restart_file) {
+            warn!("Failed to remove restart file {}: {}", restart_file, e);
+        }
+    }
+}
+
+// Publish the lifecycle state ("running" or "ready") for status and record it
+fn write_runtime_state(state: &str, store: &Store) {
+    let state_file = get_state_file_path();
+    if let Err(e) = std::fs::write(&state_file, state) {
+        warn!("Failed to write state file {}: {}", state_file, e);
+    }
+    store.transition(state);
+}
+
+// Publish the boot phase durations for status and info
+fn write_boot_timings(timings: &BootTimings) {
+    let timing_file = get_timing_file_path();
+    let json = serde_json::to_string(timings).unwrap_or_default();
+    if let Err(e) = std::fs::write(&timing_file, json) {
+        warn!("Failed to write timing file {}: {}", timing_file, e);
+    }
+}
+
+// Publish the configuration the VM boots with for inspect
+fn write_resolved_config(config: &HypervisorConfig) {
+    let config_file = get_resolved_config_file_path();
+    let json = serde_json::to_string_pretty(config).unwrap_or_default();
+    if let Err(e) = std::fs::write(&config_file, json) {
+        warn!("Failed to write configuration file {}: {}", config_file, e);
+    }
+}
+
+// Boot phase durations of the running VM, if it got that far
+fn read_boot_timings() -> Option<BootTimings> {
+    serde_json::from_str(&std::fs::read_to_string(get_timing_file_path()).ok()?).ok()
+}
+
+// Send a message to systemd when started as a Type=notify service
+fn notify_systemd(message: &str) {
+    let Ok(socket_path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    
+    let result = (|| -> std::io::Result<usize> {
+        let socket = UnixDatagram::unbound()?;
+        match socket_path.strip_prefix('@') {
+            // Abstract namespace socket
+            Some(name) => {
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+                socket.send_to_addr(message.as_bytes(), &address)
+            },
+            None => socket.send_to(message.as_bytes(), &socket_path),
+        }
+    })();
+    
+    if let Err(e) = result {
+        debug!("Failed to notify systemd at {}: {}", socket_path, e);
+    }
+}
+
+/// Write the state of the running manager to the log and to the dump file, for bug reports about
+/// a hung VM
+fn dump_state(config: &HypervisorConfig, hypervisor_manager: &dyn HypervisorBackend, vm_config: &serde_json::Value) {
+    let dump = serde_json::json!({
+        "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "pid": std::process::id(),
+        "runtime": env::var(RUNTIME_NAME_VAR).ok(),
+        "state": format!("{:?}", hypervisor_manager.state()),
+        "config": vm_config,
+        "files": {
+            "pid": get_pid_file_path(),
+            "api_socket": get_api_socket_path(),
+            "console_socket": get_console_socket_path(),
+            "vsock_socket": config.vsock_cid.map(|_| get_vsock_socket_path()),
+            "log": config.log_filepath,
+            "guest_log": config.guest_log_filepath,
+            "audit_log": config.audit.path(),
+        },
+        "vm": dump::vm_details(&get_api_socket_path()),
+        "threads": dump::threads(),
+        "recent_errors": dump::recent_errors(),
+    });
+    info!("Diagnostic dump: {}", dump);
+    
+    let path = get_dump_file_path();
+    match dump::write(&path, &dump) {
+        Ok(_) => info!("Diagnostic dump written to {}", path),
+        Err(e) => warn!("{:#}", e),
+    }
+}
+
+// Readiness comes from the guest over vsock or from the first healthy probe, configured for the
+// runtime or for all of them in the environment
+fn reports_readiness(runtime: &RuntimeConfig) -> bool {
+    runtime.vsock_cid.is_some() || runtime.probe.is_some()
+        || env::var(VSOCK_CID_VAR).is_ok() || env::var(PROBE_URL_VAR).is_ok() || env::var(PROBE_KIND_VAR).is_ok()
+}
+
+// Boot every runtime of the configuration file, each in a `start` process of its own and after
+// the runtimes it depends on, then stay in the foreground until they have all exited. SIGTERM is
+// passed on to every runtime.
+fn start_all(wait_ready: bool, run_as: Option<&String>, parallel: Option<usize>, verbosity: i8, lenient: bool,
+             boot_timeout: Option<u64>) -> Result<()> {
+    let config_file = get_config_file_path();
+    let manifest = Manifest::load(&config_file)?;
+    if manifest.runtimes.is_empty() {
+        bail!("No runtimes are defined in {}", config_file);
+    }
+    check_not_drained(&get_state_dir(&manifest.global))?;
+    let parallelism = match parallel {
+        Some(0) => bail!("The start parallelism must be at least 1"),
+        Some(parallelism) => parallelism,
+        None => get_start_parallelism(&manifest.global)?,
+    };
+    
+    // Listen before launching, so a signal during startup also stops the runtimes still booting
+    let exit_signal = Arc::new(AtomicBool::new(false));
+    let exit_signal_clone = exit_signal.clone();
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    let handle = signals.handle();
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            info!("Received signal {:?}", sig);
+            exit_signal_clone.store(true, Ordering::SeqCst);
+        }
+    });
+    
+    let executable = env::current_exe().context("Failed to locate the vllmd-hypervisor executable")?;
+    let log_filepath = env::var(LOG_FILEPATH_VAR).unwrap_or_else(|_| DEFAULT_LOG_FILEPATH.to_string());
+    let runtimes = manifest.start_order()?;
+    let launches = runtimes.iter().map(|runtime| {
+        let mut command = std::process::Command::new(&executable);
+        command.arg("start");
+        if wait_ready {
+            command.arg("--wait-ready");
+        }
+        if let Some(run_as) = run_as {
+            command.arg("--run-as").arg(run_as);
+        }
+        if lenient {
+            command.arg("--lenient");
+        }
+        if let Some(boot_timeout) = boot_timeout {
+            command.arg("--boot-timeout").arg(boot_timeout.to_string());
+        }
+        let verbosity_flag = if verbosity > 0 { "-v" } else { "-q" };
+        command.args(std::iter::repeat_n(verbosity_flag, verbosity.unsigned_abs() as usize));
+        // Only this process reports to systemd
+        command.env(RUNTIME_NAME_VAR, &runtime.name).env_remove("NOTIFY_SOCKET");
+        if log_filepath != DEFAULT_LOG_FILEPATH {
+            command.env(LOG_FILEPATH_VAR, runtime_log_path(&log_filepath, &runtime.name));
+        }
+        // Runtimes others depend on must be ready first, when they can tell
+        let depended_on = runtimes.iter().any(|other| other.depends_on.contains(&runtime.name));
+        fleet::Launch {
+            name: runtime.name.clone(),
+            pid_file: runtime_pid_file_path(Some(&runtime.name)).into(),
+            command,
+            depends_on: runtime.depends_on.clone(),
+            wait_ready: wait_ready || (depended_on && reports_readiness(runtime)),
+            detached_log: None,
+        }
+    }).collect::<Vec<_>>();
+    
+    info!("Starting {} runtime(s), {} at a time", launches.len(), parallelism);
+    let mut reports = fleet::launch(launches, parallelism, &exit_signal);
+    
+    let width = reports.iter().map(|report| report.name.len()).max().unwrap_or(0);
+    for report in &reports {
+        println!("{:<width$}  {}", report.name, report.describe(), width = width);
+    }
+    let started = reports.iter().filter(|report| report.started()).count();
+    let failed = reports.iter().filter(|report| matches!(report.outcome, fleet::Outcome::Failed(_))).count();
+    println!("{} of {} runtime(s) started", started, reports.len());
+    notify_systemd(&format!("READY=1\nSTATUS={} of {} runtimes started", started, reports.len()));
+    
+    let statuses = fleet::supervise(&mut reports, &exit_signal);
+    handle.close();
+    
+    if failed > 0 {
+        bail!("{} of {} runtime(s) failed to start", failed, reports.len());
+    }
+    let crashed: Vec<&str> = statuses.iter()
+        .filter(|(_, status)| !status.success())
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if !crashed.is_empty() {
+        bail!("Runtime(s) exited with an error: {}", crashed.join(", "));
+    }
+    
+    Ok(())
+}
+
+// Restart the runtime named in the environment, or with `all` every running runtime of the
+// configuration file in start order, `max_unavailable` at a time. With `upgrade` each hands its
+// running VM to the binary installed now instead of booting it again.
+fn restart_runtimes(all: bool, selector: Option<&Selector>, max_unavailable: usize, timeout_secs: u64, upgrade: bool) -> Result<()> {
+    if max_unavailable == 0 {
+        bail!("--max-unavailable must be at least 1");
+    }
+    
+    let (global, _) = load_runtime()?;
+    let names: Vec<Option<String>> = if all {
+        let manifest = Manifest::load(&get_config_file_path())?;
+        manifest.select(selector)?.iter().map(|runtime| Some(runtime.name.clone())).collect()
+    } else {
+        vec![env::var(RUNTIME_NAME_VAR).ok()]
+    };
+    
+    let mut runtimes = Vec::new();
+    for name in names {
+        let label = name.clone().unwrap_or_else(|| "(unnamed)".to_string());
+        let pid_file = runtime_pid_file_path(name.as_deref());
+        match fleet::running_pid(Path::new(&pid_file)) {
+            Some(pid) => runtimes.push(fleet::Restart {
+                name: label,
+                pid,
+                restart_file: Path::new(&pid_file).with_extension("restart"),
+            }),
+            None => println!("{}  not running, skipped", label),
+        }
+    }
+    if runtimes.is_empty() {
+        bail!("No runtime to {} is running", if upgrade { "upgrade" } else { "restart" });
+    }
+    
+    // Each runtime gets its own audit records, whichever runtime the environment names
+    let audit_path = get_audit_log(&global).path().to_string();
+    fleet::rolling_restart(&runtimes, max_unavailable, std::time::Duration::from_secs(timeout_secs),
+                           |runtime| request_restart(&audit_path, runtime, upgrade))
+}
+
+// Ask a running hypervisor to boot again in place, or with `upgrade` to exec the installed
+// binary with its VM kept running, recording the request in the audit log at `audit_path` under
+// the runtime's own name
+fn request_restart(audit_path: &str, runtime: &fleet::Restart, upgrade: bool) -> Result<()> {
+    let (operation, signal) = if upgrade { ("upgrade", upgrade_signal()) } else { ("restart", restart_signal()) };
+    std::fs::write(&runtime.restart_file, "")
+        .context(format!("Failed to create restart file: {}", runtime.restart_file.display()))?;
+    let sent = match unsafe { libc::kill(runtime.pid as i32, signal) } {
+        0 => Ok(()),
+        _ => Err(anyhow!("Failed to send the {} signal to process {}: {}", operation, runtime.pid, std::io::Error::last_os_error())),
+    };
+    let name = (runtime.name != "(unnamed)").then(|| runtime.name.clone());
+    AuditLog::new(audit_path.to_string(), name)
+        .record(operation, audit::outcome(serde_json::json!({ "pid": runtime.pid }), &sent));
+    if sent.is_err() {
+        let _ = std::fs::remove_file(&runtime.restart_file);
+    }
+    sent
+}
+
+// Ask the hypervisor `pid` of a runtime to shut down, recording the request in the audit log at
+// `audit_path` under the runtime's name
+fn request_stop(audit_path: &str, name: Option<&str>, pid: u32) -> Result<()> {
+    let sent = match unsafe { libc::kill(pid as i32, libc::SIGTERM) } {
+        0 => Ok(()),
+        _ => Err(anyhow!("Failed to send SIGTERM to process {}: {}", pid, std::io::Error::last_os_error())),
+    };
+    AuditLog::new(audit_path.to_string(), name.map(str::to_string))
+        .record("stop", audit::outcome(serde_json::json!({ "pid": pid, "signal": "SIGTERM" }), &sent));
+    sent
+}
+
+// The configuration file `up` and `down` act on, as it is recorded by the runtimes they start
+fn compose_file(config_file: &str) -> Result<String> {
+    let path = std::fs::canonicalize(config_file)
+        .context(format!("Failed to read configuration file: {}", config_file))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// Runtimes booted from `config_file` that it no longer defines and that still run, by name and PID
+fn orphaned_runtimes(manifest: &Manifest, config_file: &str) -> Result<Vec<(String, u32)>> {
+    let state_dir = get_state_dir(&manifest.global);
+    let mut orphaned = Vec::new();
+    for name in store::runtimes(&state_dir)?.into_iter().flatten() {
+        if manifest.runtimes.iter().any(|runtime| runtime.name == name) {
+            continue;
+        }
+        let record = Store::new(&state_dir, Some(&name)).load().ok().flatten();
+        if record.and_then(|record| record.manifest).as_deref() != Some(config_file) {
+            continue;
+        }
+        if let Some(pid) = fleet::running_pid(Path::new(&runtime_pid_file_path(Some(&name)))) {
+            orphaned.push((name, pid));
+        }
+    }
+    Ok(orphaned)
+}
+
+// Whether the runtime named in the environment now resolves to another configuration than the one
+// its running VM booted with, which `pid_file.config.json` holds. A VM booted before
+// configurations were recorded counts as unchanged.
+fn configuration_changed(pid_file: &str) -> Result<bool> {
+    Ok(recorded_and_wanted_configuration(pid_file)?.is_some_and(|(recorded, wanted)| recorded != wanted))
+}
+
+// The configuration the running VM booted with and the one the runtime named in the environment
+// resolves to now, without what is not part of the VM: the host-only fields and allocated GPUs
+fn recorded_and_wanted_configuration(pid_file: &str) -> Result<Option<(serde_json::Value, serde_json::Value)>> {
+    let recorded = std::fs::read_to_string(Path::new(pid_file).with_extension("config.json")).ok()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok());
+    let Some(mut recorded) = recorded else { return Ok(None) };
+    let mut wanted = HypervisorConfig::from_env()?;
+    add_runtime_devices(&mut wanted)?;
+    let mut wanted = serde_json::to_value(wanted)?;
+    // GPUs allocated at start are not part of the configuration
+    let allocated = wanted.get("gpu_request").is_some_and(|request| !request.is_null());
+    for configuration in [&mut recorded, &mut wanted] {
+        if let Some(fields) = configuration.as_object_mut() {
+            for field in drift::HOST_ONLY_FIELDS {
+                fields.remove(*field);
+            }
+            if allocated {
+                fields.remove("device_filepath_list");
+            }
+        }
+    }
+    Ok(Some((recorded, wanted)))
+}
+
+// `start` of a named runtime that runs on its own, and the log file it writes: next to
+// VLLMD_HYPERVISOR_LOG_FILEPATH as with `start --all`, or `<state dir>/logs/<name>.log`
+fn detached_start(executable: &Path, name: &str, state_dir: &str, wait_ready: bool) -> (std::process::Command, String) {
+    let log = match env::var(LOG_FILEPATH_VAR) {
+        Ok(log_filepath) => runtime_log_path(&log_filepath, name),
+        Err(_) => format!("{}/logs/{}.log", state_dir, name),
+    };
+    let mut command = std::process::Command::new(executable);
+    command.arg("start");
+    if wait_ready {
+        command.arg("--wait-ready");
+    }
+    command.env(RUNTIME_NAME_VAR, name).env(LOG_FILEPATH_VAR, &log).env_remove("NOTIFY_SOCKET");
+    (command, log)
+}
+
+// The runtimes of the configuration file as the D-Bus service manages them
+#[derive(Clone)]
+struct BusRuntimes;
+
+impl dbus::Runtimes for BusRuntimes {
+    fn names(&self) -> Result<Vec<String>> {
+        let config_file = get_config_file_path();
+        let mut names: Vec<String> = match Path::new(&config_file).exists() {
+            true => Manifest::load(&config_file)?.start_order()?.iter().map(|runtime| runtime.name.clone()).collect(),
+            false => Vec::new(),
+        };
+        if runtime_state(None).1.is_some() {
+            names.insert(0, String::new());
+        }
+        Ok(names)
+    }
+    
+    fn state(&self, name: &str) -> (String, Option<u32>) {
+        runtime_state(Some(name).filter(|name| !name.is_empty()))
+    }
+    
+    fn start(&self, name: &str) -> Result<u32> {
+        if name.is_empty() {
+            bail!("Only runtimes of the configuration file can be started over D-Bus");
+        }
+        let manifest = Manifest::load(&get_config_file_path())?;
+        let executable = env::current_exe().context("Failed to locate the vllmd-hypervisor executable")?;
+        let (mut command, log) = detached_start(&executable, name, &get_state_dir(&manifest.global), false);
+        let mut child = fleet::detach(&mut command, Path::new(&log))
+            .and_then(|command| command.spawn())
+            .context("Failed to spawn hypervisor")?;
+        let pid = child.id();
+        // The service outlives the hypervisors it starts and reaps them
+        thread::spawn(move || child.wait());
+        Ok(pid)
+    }
+    
+    fn stop(&self, name: &str) -> Result<u32> {
+        let name = Some(name).filter(|name| !name.is_empty());
+        let Some(pid) = runtime_state(name).1 else {
+            bail!("Runtime {} is not running", name.unwrap_or("(unnamed)"));
+        };
+        let config_file = get_config_file_path();
+        let global = match Path::new(&config_file).exists() {
+            true => Manifest::load(&config_file)?.global,
+            false => GlobalConfig::default(),
+        };
+        request_stop(get_audit_log(&global).path(), name, pid).map(|_| pid)
+    }
+}
+
+// Bring the running runtimes in line with `config_file`: stop those started from it that it no
+// longer defines, boot again those whose configuration changed since their VM booted, and start
+// the missing ones detached from this process, each logging to `<state dir>/logs/<name>.log`
+fn up_runtimes(config_file: &str, wait_ready: bool, timeout_secs: u64) -> Result<()> {
+    let config_file = compose_file(config_file)?;
+    // The configurations resolved here and the hypervisors started below read the same file
+    env::set_var(CONFIG_VAR, &config_file);
+    let manifest = Manifest::load(&config_file)?;
+    let runtimes = manifest.start_order()?;
+    let audit_path = get_audit_log(&manifest.global).path().to_string();
+    let timeout = std::time::Duration::from_secs(timeout_secs);
+    check_not_drained(&get_state_dir(&manifest.global))?;
+    
+    let removed = orphaned_runtimes(&manifest, &config_file)?;
+    for (name, pid) in &removed {
+        request_stop(&audit_path, Some(name), *pid)?;
+        println!("{}  removed, stopping (PID: {})", name, pid);
+    }
+    fleet::wait_stopped(&removed, timeout)?;
+    
+    let executable = env::current_exe().context("Failed to locate the vllmd-hypervisor executable")?;
+    let state_dir = get_state_dir(&manifest.global);
+    let is_running = |name: &str| fleet::running_pid(Path::new(&runtime_pid_file_path(Some(name))));
+    let mut launches = Vec::new();
+    let mut changed = Vec::new();
+    let mut unchanged = 0;
+    for runtime in &runtimes {
+        let pid_file = runtime_pid_file_path(Some(&runtime.name));
+        if let Some(pid) = is_running(&runtime.name) {
+            env::set_var(RUNTIME_NAME_VAR, &runtime.name);
+            let restart = configuration_changed(&pid_file)
+                .context(format!("Failed to resolve the configuration of runtime {}", runtime.name))?;
+            if restart {
+                changed.push(fleet::Restart {
+                    name: runtime.name.clone(),
+                    pid,
+                    restart_file: Path::new(&pid_file).with_extension("restart"),
+                });
+            } else {
+                println!("{}  unchanged (PID: {})", runtime.name, pid);
+                unchanged += 1;
+            }
+            continue;
+        }
+        
+        let (command, log) = detached_start(&executable, &runtime.name, &state_dir, wait_ready);
+        let depended_on = runtimes.iter().any(|other| other.depends_on.contains(&runtime.name));
+        launches.push(fleet::Launch {
+            name: runtime.name.clone(),
+            pid_file: pid_file.into(),
+            command,
+            // Dependencies that already run are met
+            depends_on: runtime.depends_on.iter().filter(|name| is_running(name).is_none()).cloned().collect(),
+            wait_ready: wait_ready || (depended_on && reports_readiness(runtime)),
+            detached_log: Some(log.into()),
+        });
+    }
+    
+    if !changed.is_empty() {
+        fleet::rolling_restart(&changed, changed.len(), timeout, |runtime| request_restart(&audit_path, runtime, false))?;
+    }
+    
+    let reports = fleet::launch(launches, get_start_parallelism(&manifest.global)?, &AtomicBool::new(false));
+    for report in &reports {
+        println!("{}  {}", report.name, report.describe());
+    }
+    let started = reports.iter().filter(|report| report.started()).count();
+    println!("{} started, {} restarted, {} stopped, {} unchanged", started, changed.len(), removed.len(), unchanged);
+    if started < reports.len() {
+        bail!("{} of {} runtime(s) failed to start", reports.len() - started, reports.len());
+    }
+    
+    Ok(())
+}
+
+// Stop every running runtime of `config_file` and those started from it that it no longer defines,
+// then wait for them to exit
+fn down_runtimes(config_file: &str, timeout_secs: u64) -> Result<()> {
+    let config_file = compose_file(config_file)?;
+    let manifest = Manifest::load(&config_file)?;
+    let audit_path = get_audit_log(&manifest.global).path().to_string();
+    
+    let mut running = orphaned_runtimes(&manifest, &config_file)?;
+    for runtime in manifest.start_order()?.iter().rev() {
+        match fleet::running_pid(Path::new(&runtime_pid_file_path(Some(&runtime.name)))) {
+            Some(pid) => running.push((runtime.name.clone(), pid)),
+            None => println!("{}  not running", runtime.name),
+        }
+    }
+    // One runtime that cannot be stopped does not keep the others running
+    let mut stopping = Vec::new();
+    let mut failed = 0;
+    for (name, pid) in running {
+        match request_stop(&audit_path, Some(&name), pid) {
+            Ok(()) => {
+                println!("{}  stopping (PID: {})", name, pid);
+                stopping.push((name, pid));
+            },
+            Err(e) => {
+                println!("{}  failed: {:#}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    
+    let waited = fleet::wait_stopped(&stopping, std::time::Duration::from_secs(timeout_secs));
+    if failed > 0 {
+        bail!("{} runtime(s) could not be stopped", failed);
+    }
+    waited
+}
+
+fn stop_hypervisor() -> Result<()> {
+    info!("Stopping hypervisor");
+    
+    // Get VM PID
+    let pid = match get_vm_pid() {
+        Ok(pid) => pid,
+        Err(e) => {
+            info!("No running hypervisor found: {}", e);
+            return Ok(());
+        }
+    };
+    
+    info!("Sending SIGTERM to hypervisor process with PID: {}", pid);
+    
+    // On Unix, we can send a signal to another process
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        
+        let sent = kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
+            .map_err(|e| anyhow!("Failed to send SIGTERM to process {}: {}", pid, e));
+        // Record who asked; the shutdown itself is recorded by the hypervisor process
+        let global = load_runtime().unwrap_or_default().0;
+        get_audit_log(&global).record("stop", audit::outcome(serde_json::json!({ "pid": pid, "signal": "SIGTERM" }), &sent));
+        sent?;
+        
+        info!("SIGTERM sent successfully");
+    }
+    
+    // On non-Unix platforms, this won't work
+    #[cfg(not(unix))]
+    {
+        info!("Stop command not supported on this platform");
+    }
+    
+    Ok(())
+}
+
+// Stop every running runtime of the configuration file whose labels match, or with `all` and no
+// selector also those the state store has registered that the file no longer defines, then wait
+// for them to exit. All are asked at once, so they shut down concurrently; each gets its own line
+// as it is asked and as it exits.
+fn stop_runtimes(all: bool, selector: Option<&Selector>, timeout_secs: u64) -> Result<()> {
+    let config_file = get_config_file_path();
+    // Without a selector there are no labels to read, and the uninstaller may have removed the file
+    let manifest = if selector.is_none() && !Path::new(&config_file).exists() {
+        Manifest::default()
+    } else {
+        Manifest::load(&config_file)?
+    };
+    let mut names: Vec<Option<String>> = manifest.select(selector)?.iter().rev()
+        .map(|runtime| Some(runtime.name.clone()))
+        .collect();
+    if all && selector.is_none() {
+        for name in store::runtimes(&get_state_dir(&manifest.global))? {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    if names.is_empty() {
+        match selector {
+            Some(_) => bail!("No runtime of {} matches the selector", config_file),
+            None => {
+                println!("No runtimes are defined in {} or registered in the state store", config_file);
+                return Ok(());
+            },
+        }
+    }
+    
+    let audit_path = get_audit_log(&manifest.global).path().to_string();
+    let label = |name: &Option<String>| name.clone().unwrap_or_else(|| "(unnamed)".to_string());
+    let width = names.iter().map(|name| label(name).len()).max().unwrap_or(0);
+    let mut stopping = Vec::new();
+    let mut failed = 0;
+    for name in &names {
+        let Some(pid) = fleet::running_pid(Path::new(&runtime_pid_file_path(name.as_deref()))) else {
+            println!("{:<width$}  not running", label(name), width = width);
+            continue;
+        };
+        match request_stop(&audit_path, name.as_deref(), pid) {
+            Ok(()) => {
+                println!("{:<width$}  stopping (PID: {})", label(name), pid, width = width);
+                stopping.push((label(name), pid));
+            },
+            Err(e) => {
+                println!("{:<width$}  failed: {:#}", label(name), e, width = width);
+                failed += 1;
+            }
+        }
+    }
+    
+    let waited = fleet::wait_stopped(&stopping, std::time::Duration::from_secs(timeout_secs));
+    if failed > 0 {
+        bail!("{} runtime(s) could not be stopped", failed);
+    }
+    waited
+}
+
+// Fail while `drain` keeps VMs from booting on the host
+fn check_not_drained(state_dir: &str) -> Result<()> {
+    if let Some(drain) = store::drained(state_dir)? {
+        let reason = drain.reason.map(|reason| format!(" ({})", reason)).unwrap_or_default();
+        bail!("The host is drained since {}{}; run uncordon to boot VMs on it again", drain.time, reason);
+    }
+    Ok(())
+}
+
+// Keep VMs from booting on the host, optionally snapshot the running runtimes of the configuration
+// file, then stop every runtime. The host is marked first, so nothing boots while the runtimes
+// shut down, and stays marked when a step fails.
+fn drain_host(snapshot: bool, reason: Option<&String>, timeout_secs: u64) -> Result<()> {
+    let config_file = get_config_file_path();
+    let manifest = if Path::new(&config_file).exists() { Manifest::load(&config_file)? } else { Manifest::default() };
+    let state_dir = get_state_dir(&manifest.global);
+    
+    let drained = store::drain(&state_dir, reason.cloned());
+    get_audit_log(&manifest.global).record("drain", audit::outcome(serde_json::json!({ "reason": reason, "snapshot": snapshot }), &drained));
+    drained?;
+    println!("Host drained, start refuses to boot VMs until uncordon");
+    
+    if snapshot {
+        for runtime in manifest.start_order()? {
+            let pid_file = runtime_pid_file_path(Some(&runtime.name));
+            if fleet::running_pid(Path::new(&pid_file)).is_none() {
+                continue;
+            }
+            if let Some(why) = snapshot_blocker(&pid_file) {
+                warn!("Not snapshotting {}: {}; it is stopped without a snapshot", runtime.name, why);
+                continue;
+            }
+            env::set_var(RUNTIME_NAME_VAR, &runtime.name);
+            snapshot_vm(None).context(format!("Failed to snapshot {}, no runtime was stopped", runtime.name))?;
+        }
+    }
+    
+    stop_runtimes(true, None, timeout_secs)
+}
+
+// Why the running VM of the PID file cannot be snapshotted, from the configuration it was booted with
+fn snapshot_blocker(pid_file: &str) -> Option<&'static str> {
+    let recorded = std::fs::read_to_string(Path::new(pid_file).with_extension("config.json")).ok()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())?;
+    if recorded["device_filepath_list"].as_array().is_some_and(|devices| !devices.is_empty()) {
+        Some("it has passthrough devices")
+    } else if !recorded["confidential"].is_null() {
+        Some("it is a confidential VM")
+    } else {
+        None
+    }
+}
+
+// Let VMs boot on the host again after `drain`
+fn uncordon_host() -> Result<()> {
+    let config_file = get_config_file_path();
+    let global = if Path::new(&config_file).exists() { Manifest::load(&config_file)?.global } else { GlobalConfig::default() };
+    let lifted = store::uncordon(&get_state_dir(&global));
+    get_audit_log(&global).record("uncordon", audit::outcome(serde_json::json!({}), &lifted));
+    match lifted? {
+        Some(drain) => println!("Host uncordoned, drained since {}", drain.time),
+        None => println!("Host was not drained"),
+    }
+    Ok(())
+}
+
+// Reload the KVM module of the host with nested=1, for runtimes with nested virtualization.
+// This affects every VM of the host, so `start` never does it.
+fn enable_nested_host() -> Result<()> {
+    let config_file = get_config_file_path();
+    let global = if Path::new(&config_file).exists() { Manifest::load(&config_file)?.global } else { GlobalConfig::default() };
+    let enabled = nested::enable();
+    get_audit_log(&global).record("enable_nested", audit::outcome(serde_json::json!({ "module": nested::module() }), &enabled));
+    enabled?;
+    println!("Nested virtualization enabled in {}", nested::module().unwrap_or("the KVM module"));
+    Ok(())
+}
+
+// Lifecycle state of a runtime (stopped, running or ready) and the PID of its hypervisor
+fn runtime_state(name: Option<&str>) -> (String, Option<u32>) {
+    let pid_file = runtime_pid_file_path(name);
+    match fleet::running_pid(Path::new(&pid_file)) {
+        Some(pid) => {
+            let state = std::fs::read_to_string(Path::new(&pid_file).with_extension("state")).unwrap_or_default();
+            let state = if state.trim().is_empty() { "running".to_string() } else { state.trim().to_string() };
+            (state, Some(pid))
+        }
+        None => ("stopped".to_string(), None),
+    }
+}
+
+// Runtimes of the configuration file whose labels match, with their state
+fn list_runtimes(selector: Option<&Selector>) -> Result<()> {
+    let manifest = Manifest::load(&get_config_file_path())?;
+    let mut rows = vec![["NAME", "INDEX", "STATE", "PID", "LABELS"].map(String::from)];
+    for runtime in manifest.select(selector)? {
+        let (state, pid) = runtime_state(Some(&runtime.name));
+        let pid = pid.map(|pid| pid.to_string()).unwrap_or_else(|| "-".to_string());
+        let labels: Vec<String> = runtime.labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        let labels = if labels.is_empty() { "-".to_string() } else { labels.join(",") };
+        rows.push([runtime.name.clone(), runtime.index.to_string(), state, pid, labels]);
+    }
+    
+    let widths: Vec<usize> = (0..4).map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0)).collect();
+    for row in &rows {
+        println!("{:<w0$}  {:<w1$}  {:<w2$}  {:<w3$}  {}", row[0], row[1], row[2], row[3], row[4],
+                 w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3]);
+    }
+    Ok(())
+}
+
+fn check_hypervisor_status(diff: bool) -> Result<()> {
+    info!("Checking hypervisor status");
+    
+    // Get VM PID
+    let pid = match get_vm_pid() {
+        Ok(pid) => pid,
+        Err(e) => {
+            info!("No running hypervisor found: {}", e);
+            println!("Status: Not running");
+            return Ok(());
+        }
+    };
+    
+    // Check if process is running
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        
+        match kill(Pid::from_raw(pid as i32), Signal::SIGCONT) {
+            Ok(_) => {
+                info!("Hypervisor is running with PID: {}", pid);
+                
+                // Ready once the guest has signalled that its workload is up
+                let state = match std::fs::read_to_string(get_state_file_path()).as_deref().map(str::trim) {
+                    Ok("ready") => "Ready",
+                    _ => "Running",
+                };
+                
+                // With a probe configured, report whether the inference endpoint answers
+                match std::fs::read_to_string(get_health_file_path()).as_deref().map(str::trim) {
+                    Ok("healthy") => println!("Status: {} (PID: {}, endpoint healthy)", state, pid),
+                    Ok("unhealthy") => println!("Status: {} (PID: {}, endpoint not answering)", state, pid),
+                    _ => println!("Status: {} (PID: {})", state, pid),
+                }
+                if let Some(timings) = read_boot_timings() {
+                    println!("Boot: {}", timings.summary());
+                }
+                if diff {
+                    print_drift()?;
+                }
+            },
+            Err(_) => {
+                info!("Hypervisor process with PID {} is not running", pid);
+                println!("Status: Not running (stale PID file)");
+                
+                // Remove stale PID file
+                let pid_file = get_pid_file_path();
+                if let Err(e) = std::fs::remove_file(&pid_file) {
+                    debug!("Failed to remove stale PID file {}: {}", pid_file, e);
+                }
+            }
+        }
+    }
+    
+    // On non-Unix platforms, this won't work
+    #[cfg(not(unix))]
+    {
+        println!("Status: Unknown (status check not supported on this platform)");
+    }
+    
+    Ok(())
+}
+
+// Print how the running VM differs from the configuration the runtime resolves to now, and which
+// changes can be applied to it without a restart
+fn print_drift() -> Result<()> {
+    let Some((recorded, wanted)) = recorded_and_wanted_configuration(&get_pid_file_path())? else {
+        println!("Drift: unknown, the VM booted without a recorded configuration");
+        return Ok(());
+    };
+    let live = control::info(&get_api_socket_path()).ok();
+    let changes = drift::compare(&recorded, &wanted, live.as_ref());
+    if changes.is_empty() {
+        println!("Drift: none");
+        return Ok(());
+    }
+    
+    let restart = changes.iter().filter(|change| change.hot.is_none()).count();
+    println!("Drift: {} change(s), {} requiring a restart", changes.len(), restart);
+    for change in &changes {
+        let applied = match &change.hot {
+            Some(command) => format!("hot: vllmd-hypervisor {}", command),
+            None => "restart".to_string(),
+        };
+        println!("  {}: {} -> {} ({})", change.key, change.running, change.wanted, applied);
+    }
+    Ok(())
+}
+
+fn add_network_device(tap: &str, mac: Option<&String>, id: Option<&String>, num_queues: Option<usize>, queue_size: Option<u16>) -> Result<()> {
+    let (global, runtime) = load_runtime()?;
+    
+    // A queue pair per vCPU lets every vCPU stream tokens without sharing a ring; the VM
+    // reports how many vCPUs it booted with
+    let num_queues = match num_queues.or(runtime.net_num_queues) {
+        Some(num_queues) => Some(num_queues),
+        None => control::info(&get_api_socket_path()).ok().map(|details| 2 * details.vcpus.boot as usize),
+    };
+    if num_queues.is_some_and(|num_queues| num_queues == 0 || !num_queues.is_multiple_of(2)) {
+        bail!("A network device needs an even number of queues, one receive and one transmit queue per pair");
+    }
+    let queue_size = queue_size.or(runtime.net_queue_size);
+    if queue_size.is_some_and(|queue_size| !queue_size.is_power_of_two()) {
+        bail!("Queue size {} is not a power of two", queue_size.unwrap_or_default());
+    }
+    
+    // Build a Cloud Hypervisor net string from the provided parameters
+    let mut net = format!("tap={}", tap);
+    if let Some(mac) = mac {
+        net.push_str(&format!(",mac={}", mac));
+    }
+    if let Some(id) = id {
+        net.push_str(&format!(",id={}", id));
+    }
+    if let Some(num_queues) = num_queues {
+        net.push_str(&format!(",num_queues={}", num_queues));
+    }
+    if let Some(queue_size) = queue_size {
+        net.push_str(&format!(",queue_size={}", queue_size));
+    }
+    
+    // A stopped VMM has no process to check; the API call reports that
+    if let Ok(pid) = get_vm_pid() {
+        let check = doctor::tap_check(pid, tap);
+        if check.status == doctor::Status::Fail {
+            bail!("Preflight check failed: {}", check.description);
+        }
+    }
+    
+    info!("Adding network device: {}", net);
+    
+    let added = control::add_net(&get_api_socket_path(), &net);
+    get_audit_log(&global).record("hotplug_net", audit::outcome(serde_json::json!({ "net": net }), &added));
+    match added? {
+        Some(response) => println!("{}", response),
+        None => println!("Network device added"),
+    }
+    
+    Ok(())
+}
+
+// State, sizing and devices of the running VM, as a summary or as JSON
+fn show_vm_info(json: bool) -> Result<()> {
+    let mut details = control::info(&get_api_socket_path())?;
+    details.boot_timing = read_boot_timings();
+    
+    if json {
+        println!("{}", serde_json::to_string_pretty(&details).context("Failed to serialize VM info")?);
+        return Ok(());
+    }
+    
+    let gib = |bytes: u64| format!("{:.1}G", bytes as f64 / (1u64 << 30) as f64);
+    println!("State:   {}", details.state);
+    println!("vCPUs:   {} (max {})", details.vcpus.boot, details.vcpus.max);
+    println!("Memory:  {} (hotplugged {}, usable {})", gib(details.memory.size),
+             gib(details.memory.hotplugged_size), gib(details.memory.actual_size));
+    if let Some(timings) = &details.boot_timing {
+        println!("Boot:    {}", timings.summary());
+    }
+    println!();
+    println!("{:<16} {:<5} {:<13} SOURCE", "DEVICE", "KIND", "PCI");
+    for device in &details.devices {
+        println!("{:<16} {:<5} {:<13} {}", device.id, device.kind, device.pci_bdf.as_deref().unwrap_or("-"),
+                 device.source.as_deref().unwrap_or("-"));
+    }
+    
+    Ok(())
+}
+
+// Print the [[runtimes]] entry `format` translates a definition of another tool into; what it
+// leaves out goes to stderr, so the entry can be appended to the configuration file
+fn import_runtime(format: &str, args: &clap::ArgMatches) -> Result<()> {
+    let read = |file: &str| if file == "-" {
+        std::io::read_to_string(std::io::stdin()).context("Failed to read stdin")
+    } else {
+        std::fs::read_to_string(file).context(format!("Failed to read {}", file))
+    };
+    let mut imported = match (format, args.get_one::<String>("file")) {
+        ("libvirt", Some(file)) => import::libvirt(&read(file)?).context(format!("Failed to import {}", file))?,
+        ("qemu-args", Some(file)) => import::qemu_command(&read(file)?)
+            .and_then(|command| import::qemu_args(&command))
+            .context(format!("Failed to import {}", file))?,
+        ("qemu-args", None) => {
+            let command: Vec<String> = args.get_many::<String>("args").unwrap().cloned().collect();
+            import::qemu_args(&command).context("Failed to import the QEMU command line")?
+        },
+        _ => bail!("Unknown import format: {}", format),
+    };
+    
+    if let Some(name) = args.get_one::<String>("name") {
+        imported.runtime.insert("name".to_string(), name.as_str().into());
+    }
+    if !imported.runtime.contains_key("name") {
+        bail!("The definition does not name the runtime; pass --name");
+    }
+    let index = args.get_one::<u32>("index").copied();
+    let config_file = get_config_file_path();
+    let manifest = if Path::new(&config_file).exists() { Some(Manifest::load(&config_file)?) } else { None };
+    let runtimes = manifest.as_ref().map(|manifest| manifest.runtimes.as_slice()).unwrap_or_default();
+    let index = index.unwrap_or_else(|| runtimes.iter().map(|runtime| runtime.index + 1).max().unwrap_or(1));
+    imported.runtime.insert("index".to_string(), (index as i64).into());
+    
+    let runtime_name = imported.runtime.get("name").and_then(|name| name.as_str()).unwrap_or_default();
+    if runtimes.iter().any(|runtime| runtime.name == runtime_name) {
+        imported.notes.push(format!("{} already defines a runtime named {}; pass --name", config_file, runtime_name));
+    }
+    if runtimes.iter().any(|runtime| runtime.index == index) {
+        imported.notes.push(format!("{} already defines a runtime with index {}; pass --index", config_file, index));
+    }
+    for note in &imported.notes {
+        eprintln!("note: {}", note);
+    }
+    print!("{}", imported.to_toml()?);
+    Ok(())
+}
+
+// Send one request of `remote` to the API socket of the runtime named in the environment, which
+// must be running. Pausing, resuming and resizing are audited and published as lifecycle events
+// like the operations of the hypervisor itself.
+fn remote_command(action: &str, args: &clap::ArgMatches) -> Result<()> {
+    let size = |arg: &str| args.try_get_one::<String>(arg).ok().flatten()
+        .map(|size| parse_size(size).context(format!("Invalid --{} size", arg)))
+        .transpose();
+    let (memory, balloon) = (size("memory")?, size("balloon")?);
+    let vcpus = args.try_get_one::<u8>("vcpus").ok().flatten().copied();
+    
+    let (global, _) = load_runtime()?;
+    let name = env::var(RUNTIME_NAME_VAR).unwrap_or_else(|_| "(unnamed)".to_string());
+    let Some(pid) = fleet::running_pid(Path::new(&get_pid_file_path())) else {
+        bail!("Runtime {} is not running", name);
+    };
+    let socket = get_api_socket_path();
+    
+    if action == "info" {
+        let body = control::raw_info(&socket)?;
+        let info: serde_json::Value = serde_json::from_str(&body).context("Failed to parse VM info response")?;
+        println!("{}", serde_json::to_string_pretty(&info).context("Failed to serialize VM info")?);
+        return Ok(());
+    }
+    
+    if let (Some(memory), "resize") = (memory, action) {
+        control::check_memory_resize(&control::info(&socket)?.memory, memory)?;
+    }
+    
+    let events = get_event_sink(&global)?;
+    let (result, params) = match action {
+        "pause" => (control::pause(&socket), serde_json::json!({ "pid": pid })),
+        "resume" => (control::resume(&socket), serde_json::json!({ "pid": pid })),
+        "resize" => (control::resize(&socket, vcpus, memory, balloon),
+                     serde_json::json!({ "pid": pid, "vcpus": vcpus, "memory": memory, "balloon": balloon })),
+        _ => unreachable!("remote action {}", action),
+    };
+    get_audit_log(&global).record(action, audit::outcome(params, &result));
+    match action {
+        "pause" if result.is_ok() => events.emit(EventKind::Paused, Some("remote")),
+        "resume" if result.is_ok() => events.emit(EventKind::Resumed, Some("remote")),
+        _ => {},
+    }
+    events.flush();
+    result?;
+    
+    match action {
+        "pause" => println!("{}  paused", name),
+        "resume" => println!("{}  resumed", name),
+        _ => println!("{}  resized", name),
+    }
+    Ok(())
+}
+
+// Everything known about the runtime named in the environment as one JSON document: the
+// configuration it booted with (or would boot with, when stopped), its state, the devices the VMM
+// reports, the sockets it listens on and its latest lifecycle events
+fn inspect_runtime() -> Result<()> {
+    let (global, runtime) = load_runtime()?;
+    let pid = fleet::running_pid(Path::new(&get_pid_file_path()));
+    let read_trimmed = |path: String| std::fs::read_to_string(path).ok()
+        .map(|contents| contents.trim().to_string())
+        .filter(|contents| !contents.is_empty());
+    
+    let (status, configuration, vm) = match pid {
+        Some(_) => (
+            read_trimmed(get_state_file_path()).unwrap_or_else(|| "running".to_string()),
+            read_trimmed(get_resolved_config_file_path())
+                .and_then(|json| serde_json::from_str(&json).ok())
+                .unwrap_or(serde_json::Value::Null),
+            dump::vm_details(&get_api_socket_path()),
+        ),
+        None => {
+            let configuration = match HypervisorConfig::from_env() {
+                Ok(config) => serde_json::to_value(&config).context("Failed to serialize the configuration")?,
+                Err(e) => serde_json::json!({ "error": format!("{:#}", e) }),
+            };
+            ("stopped".to_string(), configuration, serde_json::Value::Null)
+        },
+    };
+    
+    let mut endpoints = serde_json::json!({
+        "pid_file": get_pid_file_path(),
+        "api_socket": get_api_socket_path(),
+        "console_socket": get_console_socket_path(),
+        "event_socket_dir": get_event_socket_dir(&global),
+    });
+    if configuration.get("vsock_cid").is_some_and(|cid| !cid.is_null()) {
+        endpoints["vsock_socket"] = get_vsock_socket_path().into();
+    }
+    
+    let events = match get_event_sink(&global)?.log_path() {
+        Some(log_path) => events::recent(log_path, env::var(RUNTIME_NAME_VAR).ok().as_deref(), INSPECT_EVENT_COUNT)?,
+        None => Vec::new(),
+    };
+    
+    // What the guest agent sees, including the passed-through GPUs the host no longer can
+    let guest = match endpoints.get("vsock_socket").and_then(|socket| socket.as_str()) {
+        Some(socket) if pid.is_some() => agent::AgentClient::connect_timeout(socket, agent::AGENT_PORT, Some(INSPECT_AGENT_TIMEOUT))
+            .and_then(|mut client| client.metrics())
+            .map(|metrics| serde_json::to_value(metrics).unwrap_or_default())
+            .unwrap_or_else(|e| serde_json::json!({ "error": format!("{:#}", e) })),
+        _ => serde_json::Value::Null,
+    };
+    
+    let inspection = serde_json::json!({
+        "name": env::var(RUNTIME_NAME_VAR).ok(),
+        "extends": runtime.extends,
+        "labels": runtime.labels,
+        "state": {
+            "status": status,
+            "pid": pid,
+            "health": read_trimmed(get_health_file_path()),
+            "boot_timing": pid.and_then(|_| read_boot_timings()),
+        },
+        "record": get_store(&global).load()?,
+        "configuration": configuration,
+        "vm": vm,
+        "guest": guest,
+        "endpoints": endpoints,
+        "events": events,
+    });
+    println!("{}", serde_json::to_string_pretty(&inspection).context("Failed to serialize the inspection")?);
+    
+    Ok(())
+}
+
+// Live view of every runtime of the configuration file
+fn show_top(interval: f64, once: bool) -> Result<()> {
+    if !interval.is_finite() || interval <= 0.0 {
+        bail!("The refresh interval must be a positive number of seconds");
+    }
+    
+    // Every runtime of the configuration file, and the unnamed one if it is running
+    let mut targets: Vec<top::Target> = Manifest::load(&get_config_file_path())
+        .map(|manifest| manifest.runtimes)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|runtime| top::Target { pid_file: runtime_pid_file_path(Some(&runtime.name)).into(), name: runtime.name })
+        .collect();
+    let unnamed = runtime_pid_file_path(None);
+    if Path::new(&unnamed).exists() {
+        targets.insert(0, top::Target { name: "(unnamed)".to_string(), pid_file: unnamed.into() });
+    }
+    if targets.is_empty() {
+        bail!("No runtimes are defined in {} and no unnamed hypervisor is running", get_config_file_path());
+    }
+    
+    top::run(&targets, std::time::Duration::from_secs_f64(interval), once)
+}
+
+// Snapshots go to `<state dir>/snapshots/<runtime>-<timestamp>` unless a directory is given
+fn snapshot_vm(directory: Option<&String>) -> Result<()> {
+    let (global, runtime) = load_runtime()?;
+    let retention = get_snapshot_retention(&runtime)?;
+    let directory = match directory {
+        Some(directory) => directory.clone(),
+        None => {
+            let name = env::var(RUNTIME_NAME_VAR).unwrap_or_else(|_| "hypervisor".to_string());
+            format!("{}/snapshots/{}-{}", get_state_dir(&global), name, chrono::Local::now().format("%Y%m%d-%H%M%S"))
+        },
+    };
+    // Retention deletes the directory with the snapshot, so it must not hold anything else
+    if std::fs::read_dir(&directory).is_ok_and(|mut entries| entries.next().is_some()) {
+        bail!("Snapshot directory {} is not empty; snapshots are written to a new or empty directory", directory);
+    }
+    std::fs::create_dir_all(&directory)
+        .context(format!("Failed to create snapshot directory: {}", directory))?;
+    
+    info!("Taking snapshot into {}", directory);
+    
+    // The VM is paused while the snapshot is taken, and resumed also when taking it failed
+    let events = get_event_sink(&global)?;
+    let socket_path = get_api_socket_path();
+    let result = control::pause(&socket_path).and_then(|()| {
+        events.emit(EventKind::Paused, Some("snapshot"));
+        let taken = control::snapshot(&socket_path, &directory);
+        let resumed = control::resume(&socket_path);
+        if resumed.is_ok() {
+            events.emit(EventKind::Resumed, Some("snapshot"));
+        }
+        taken.and(resumed)
+    });
+    get_audit_log(&global).record("snapshot", audit::outcome(serde_json::json!({ "directory": directory }), &result));
+    events.flush();
+    result?;
+    get_store(&global).update(|record| {
+        record.snapshots.retain(|recorded| recorded.path != directory);
+        record.snapshots.push(store::Snapshot {
+            path: directory.clone(),
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        });
+    });
+    println!("Snapshot written to {}", directory);
+    
+    apply_snapshot_retention(&global, &retention)
+}
+
+// Retention of the snapshots of the runtime, from the environment or the configuration file
+fn get_snapshot_retention(runtime: &RuntimeConfig) -> Result<Retention> {
+    let keep = match env::var(SNAPSHOT_KEEP_VAR) {
+        Ok(s) => Some(s.trim().parse::<usize>().context(format!("Invalid value for {}: {}", SNAPSHOT_KEEP_VAR, s))?),
+        Err(_) => runtime.snapshot_keep,
+    };
+    if keep == Some(0) {
+        bail!("{} must keep at least one snapshot", SNAPSHOT_KEEP_VAR);
+    }
+    let max_age = env::var(SNAPSHOT_MAX_AGE_VAR).ok().or_else(|| runtime.snapshot_max_age.clone())
+        .map(|age| snapshots::parse_age(&age).context(format!("Invalid value for {}", SNAPSHOT_MAX_AGE_VAR)))
+        .transpose()?;
+    Ok(Retention { keep, max_age })
+}
+
+// Delete the snapshots of the runtime the retention drops, and forget them
+fn apply_snapshot_retention(global: &GlobalConfig, retention: &Retention) -> Result<()> {
+    if retention.is_unlimited() {
+        return Ok(());
+    }
+    let store = get_store(global);
+    let recorded = store.load()?.map(|record| record.snapshots).unwrap_or_default();
+    for snapshot in retention.expired(&recorded, chrono::Utc::now()) {
+        delete_snapshot(&store, &snapshot)?;
+        println!("Deleted snapshot {}", snapshot.path);
+    }
+    Ok(())
+}
+
+// Delete the directory of a snapshot and its entry in the state record
+fn delete_snapshot(store: &Store, snapshot: &store::Snapshot) -> Result<()> {
+    snapshots::remove(snapshot)?;
+    store.update(|record| record.snapshots.retain(|recorded| recorded.path != snapshot.path));
+    Ok(())
+}
+
+// Snapshots of the runtime, oldest first, as a table or as JSON
+fn list_snapshots(json: bool) -> Result<()> {
+    let (global, _) = load_runtime()?;
+    let recorded = get_store(&global).load()?.map(|record| record.snapshots).unwrap_or_default();
+    if json {
+        let snapshots: Vec<serde_json::Value> = recorded.iter().map(|snapshot| serde_json::json!({
+            "name": snapshots::name(snapshot),
+            "path": snapshot.path,
+            "time": snapshot.time,
+            "bytes": snapshots::size(snapshot),
+        })).collect();
+        println!("{}", serde_json::to_string_pretty(&snapshots)?);
+        return Ok(());
+    }
+    
+    println!("{:<40} {:<20} {:>10}  PATH", "NAME", "TIME", "SIZE");
+    for snapshot in &recorded {
+        let size = match snapshots::size(snapshot) {
+            Some(size) => format!("{:.1}G", size as f64 / (1u64 << 30) as f64),
+            None => "missing".to_string(),
+        };
+        println!("{:<40} {:<20} {:>10}  {}", snapshots::name(snapshot), snapshot.time, size, snapshot.path);
+    }
+    
+    Ok(())
+}
+
+// Delete the snapshots of the runtime given by name or path
+fn delete_snapshots(names: &[String]) -> Result<()> {
+    let (global, _) = load_runtime()?;
+    let store = get_store(&global);
+    let recorded = store.load()?.map(|record| record.snapshots).unwrap_or_default();
+    let mut selected = Vec::new();
+    for name in names {
+        let snapshot = recorded.iter()
+            .find(|snapshot| snapshot.path == *name || snapshots::name(snapshot) == *name)
+            .ok_or_else(|| anyhow!("Runtime has no snapshot {}; `snapshots list` shows those it has", name))?;
+        selected.push(snapshot.clone());
+    }
+    for snapshot in &selected {
+        delete_snapshot(&store, snapshot)?;
+        println!("Deleted snapshot {}", snapshot.path);
+    }
+    Ok(())
+}
+
+// Apply the retention of the runtime without taking a snapshot
+fn prune_snapshots() -> Result<()> {
+    let (global, runtime) = load_runtime()?;
+    apply_snapshot_retention(&global, &get_snapshot_retention(&runtime)?)
+}
+
+// Walk the hash chain of the audit log
+fn verify_audit_log(file: Option<&String>) -> Result<()> {
+    let path = match file {
+        Some(file) => file.clone(),
+        None => get_audit_log(&load_runtime()?.0).path().to_string(),
+    };
+    let count = audit::verify(&path)?;
+    println!("{}: {} record(s), chain intact", path, count);
+    
+    Ok(())
+}
+
+fn remove_network_device(id: &str) -> Result<()> {
+    info!("Removing network device: {}", id);
+    
+    let removed = control::remove_device(&get_api_socket_path(), id);
+    let (global, _) = load_runtime()?;
+    get_audit_log(&global).record("unplug_device", audit::outcome(serde_json::json!({ "id": id }), &removed));
+    removed?;
+    println!("Network device {} removed", id);
+    
+    Ok(())
+}
+
+fn run_agent_command(matches: &clap::ArgMatches) -> Result<()> {
+    let (verb, sub_matches) = matches.subcommand().unwrap();
+    
+    if verb == "serve" {
+        let port = sub_matches.get_one::<u32>("port").copied().unwrap_or(agent::AGENT_PORT);
+        return agent::serve(port);
+    }
+    
+    if verb == "ready" {
+        agent::signal_ready()?;
+        println!("Ready signal sent");
+        return Ok(());
+    }
+    
+    if let Some(name) = sub_matches.get_one::<String>("name") {
+        env::set_var(RUNTIME_NAME_VAR, name);
+    }
+    
+    let mut client = agent::AgentClient::connect(&get_vsock_socket_path(), agent::AGENT_PORT)?;
+    match verb {
+        "ping" => {
+            let version = client.ping()?;
+            println!("Guest agent is alive (protocol version {})", version);
+        },
+        "metrics" => {
+            let metrics = client.metrics()?;
+            println!("Uptime: {:.0}s", metrics.uptime_secs);
+            println!("Load average: {:.2} {:.2} {:.2}",
+                     metrics.load_average[0], metrics.load_average[1], metrics.load_average[2]);
+            println!("Memory: {} MiB available of {} MiB",
+                     metrics.memory_available_kb / 1024, metrics.memory_total_kb / 1024);
+            for gpu in &metrics.gpus {
+                let or_na = |value: Option<String>| value.unwrap_or_else(|| "n/a".to_string());
+                println!("GPU {} ({}, {}): {}% busy, {} of {} MiB used, {} C, {} W",
+                         gpu.index, gpu.name, gpu.pci_bus_id,
+                         or_na(gpu.utilization_percent.map(|percent| format!("{:.0}", percent))),
+                         or_na(gpu.memory_used_bytes.map(|bytes| (bytes >> 20).to_string())),
+                         or_na(gpu.memory_total_bytes.map(|bytes| (bytes >> 20).to_string())),
+                         or_na(gpu.temperature_celsius.map(|celsius| format!("{:.0}", celsius))),
+                         or_na(gpu.power_watts.map(|watts| format!("{:.0}", watts))));
+            }
+        },
+        "shutdown" => {
+            client.shutdown()?;
+            println!("Guest is powering off");
+        },
+        _ => unreachable!(),
+    }
+    
+    Ok(())
+}
+
+fn exec_in_guest(command: &[String]) -> Result<i32> {
+    let mut client = agent::AgentClient::connect(&get_vsock_socket_path(), agent::AGENT_PORT)?;
+    
+    let mut stdout = std::io::stdout();
+    let mut stderr = std::io::stderr();
+    let code = client.exec(command, |stream, data| {
+        let _ = match stream {
+            agent::OutputStream::Stdout => stdout.write_all(data).and_then(|_| stdout.flush()),
+            agent::OutputStream::Stderr => stderr.write_all(data),
+        };
+    })?;
+    
+    Ok(code)
+}
+
+// Fetch an attestation report from the confidential guest and print it as JSON
+fn attest_guest(name: &str, nonce: Option<&String>, output: Option<&String>) -> Result<()> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+    
+    let to_hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    
+    // A fresh random nonce proves the report was produced for this request
+    let nonce = match nonce {
+        Some(hex) => {
+            if hex.len() % 2 != 0 || hex.len() > 2 * agent::ATTESTATION_NONCE_SIZE || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("Nonce must be an even number of hex digits, at most {}", 2 * agent::ATTESTATION_NONCE_SIZE);
+            }
+            (0..hex.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .context(format!("Invalid nonce: {}", hex))?
+        },
+        None => {
+            let mut nonce = vec![0u8; agent::ATTESTATION_NONCE_SIZE];
+            std::fs::File::open("/dev/urandom")
+                .and_then(|mut urandom| urandom.read_exact(&mut nonce))
+                .context("Failed to generate nonce")?;
+            nonce
+        },
+    };
+    
+    let mut client = agent::AgentClient::connect(&get_vsock_socket_path(), agent::AGENT_PORT)?;
+    let (provider, report) = client.attest(&nonce)?;
+    
+    // The raw report is what verifiers such as snpguest or Intel DCAP consume
+    if let Some(output) = output {
+        std::fs::write(output, &report)
+            .context(format!("Failed to write attestation report: {}", output))?;
+        info!("Wrote {} byte attestation report to {}", report.len(), output);
+    }
+    
+    let evidence = serde_json::json!({
+        "runtime": name,
+        "provider": provider,
+        "nonce": to_hex(&nonce),
+        "report_sha256": format!("{:x}", Sha256::digest(&report)),
+        "report": to_hex(&report),
+    });
+    println!("{}", serde_json::to_string_pretty(&evidence)?);
+    
+    Ok(())
+}
+
+// Copy between "host:<path>" and "<name>:<path>" in either direction
+fn copy_file(source: &str, destination: &str) -> Result<()> {
+    let split = |location: &str| -> Result<(String, String)> {
+        location.split_once(':')
+            .filter(|(side, path)| !side.is_empty() && !path.is_empty())
+            .map(|(side, path)| (side.to_string(), path.to_string()))
+            .ok_or_else(|| anyhow!("Expected host:<path> or <name>:<path>, got {}", location))
+    };
+    
+    let (source_side, source_path) = split(source)?;
+    let (destination_side, destination_path) = split(destination)?;
+    
+    let (name, push) = match (source_side.as_str(), destination_side.as_str()) {
+        ("host", "host") => bail!("One side of cp must be a runtime"),
+        ("host", name) => (name.to_string(), true),
+        (name, "host") => (name.to_string(), false),
+        _ => bail!("One side of cp must be host:<path>"),
+    };
+    
+    env::set_var(RUNTIME_NAME_VAR, &name);
+    let mut client = agent::AgentClient::connect(&get_vsock_socket_path(), agent::AGENT_PORT)?;
+    
+    if push {
+        client.push_file(&source_path, &destination_path)?;
+    } else {
+        client.pull_file(&source_path, &destination_path)?;
+    }
+    
+    info!("Copied {} to {}", source, destination);
+    
+    Ok(())
+}
+
+fn show_logs(source: &str, follow: bool) -> Result<()> {
+    let log_path = if source == "guest" {
+        let (global, _) = load_runtime()?;
+        get_guest_log_path(&get_state_dir(&global))
+    } else {
+        let log_filepath = env::var(LOG_FILEPATH_VAR).unwrap_or_else(|_| DEFAULT_LOG_FILEPATH.to_string());
+        if log_filepath.starts_with("/dev/") {
+            bail!("Hypervisor logs are written to {}; set {} to a file or read them from the journal", log_filepath, LOG_FILEPATH_VAR);
+        }
+        log_filepath
+    };
+    
+    let mut file = File::open(&log_path)
+        .context(format!("Failed to open log file: {}", log_path))?;
+    let mut stdout = std::io::stdout();
+    
+    loop {
+        std::io::copy(&mut file, &mut stdout)
+            .context(format!("Failed to read log file: {}", log_path))?;
+        stdout.flush()?;
+        
+        if !follow {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(500));
+    }
+    
+    Ok(())
+}
+
+fn pull_image(reference: &str) -> Result<()> {
+    let image = oci::OciReference::parse(reference)?;
+    info!("Pulling {}", image);
+    
+    // Always contact the registry so a moved tag is picked up
+    let store = images::ImageStore::open()?;
+    let path = oci::pull(&store, &image)?;
+    println!("{}", path.display());
+    
+    Ok(())
+}
+
+// Build a system image from a container image into the image store and print its image://
+// reference, for system_image
+fn build_system_image(reference: &str, name: Option<&String>, filesystem: build::Filesystem, size: Option<&String>) -> Result<()> {
+    let image = oci::OciReference::parse(reference)?;
+    let size = size.map(|size| parse_size(size).context("Invalid image size")).transpose()?;
+    let name = match name {
+        Some(name) => name.clone(),
+        None => {
+            let repository = image.repository.rsplit('/').next().unwrap_or(&image.repository);
+            match image.reference.strip_prefix("sha256:") {
+                Some(digest) => format!("{}@{}", repository, &digest[..digest.len().min(12)]),
+                None => format!("{}:{}", repository, image.reference),
+            }
+        },
+    };
+    info!("Building image://{} from {}", name, image);
+    
+    let store = images::ImageStore::open()?;
+    let built = build::build_image(&store, &image, &name, filesystem, size)?;
+    println!("{}", built);
+    
+    Ok(())
+}
+
+// Map each stored image reference to the runtimes that use it. Runtimes come from the
+// configuration file; the current environment counts as a runtime named "environment".
+fn image_users() -> Result<BTreeMap<String, Vec<String>>> {
+    let mut users: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    
+    let config_file = get_config_file_path();
+    if Path::new(&config_file).exists() {
+        let manifest = Manifest::load(&config_file)?;
+        for runtime in &manifest.runtimes {
+            for location in [&runtime.kernel, &runtime.system_image, &runtime.config_image].into_iter().flatten() {
+                for key in images::store_keys(location)? {
+                    users.entry(key).or_default().push(runtime.name.clone());
+                }
+            }
+        }
+    }
+    
+    for var in [KERNEL_FILEPATH_VAR, SYSTEM_IMAGE_FILEPATH_VAR, CONFIG_IMAGE_FILEPATH_VAR] {
+        if let Ok(location) = env::var(var) {
+            for key in images::store_keys(&location)? {
+                users.entry(key).or_default().push("environment".to_string());
+            }
+        }
+    }
+    
+    Ok(users)
+}
+
+fn list_images() -> Result<()> {
+    let store = images::ImageStore::open()?;
+    let users = image_users()?;
+    
+    println!("{:<60} {:<19} {:>10}  RUNTIMES", "REFERENCE", "DIGEST", "SIZE");
+    for (reference, entry, size) in store.list()? {
+        let size = match size {
+            Some(size) => format!("{:.1}G", size as f64 / (1u64 << 30) as f64),
+            None => "missing".to_string(),
+        };
+        let runtimes = users.get(&reference).map(|names| names.join(",")).unwrap_or_else(|| "-".to_string());
+        let digest: String = entry.digest.chars().take(19).collect();
+        println!("{:<60} {:<19} {:>10}  {}", reference, digest, size, runtimes);
+    }
+    
+    Ok(())
+}
+
+// GPUs and accelerators of the host and the runtimes holding them, as a table or as JSON for schedulers
+fn list_devices(json: bool) -> Result<()> {
+    let config_file = get_config_file_path();
+    let global = match Path::new(&config_file).exists() {
+        true => Manifest::load(&config_file)?.global,
+        false => GlobalConfig::default(),
+    };
+    let claims = devices::Claims::new(&get_state_dir(&global)).load();
+    let mut devices = devices::accelerators()?;
+    for device in &mut devices {
+        device.claim = claims.get(&device.address).cloned();
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+    
+    println!("{:<13} {:<10} {:<12} {:<10} {:>5} {:>4} {:>7}  {:<12} PASSABLE", "ADDRESS", "ID", "VENDOR", "DRIVER", "GROUP", "NUMA", "MEMORY", "RUNTIME");
+    for device in &devices {
+        let group = device.iommu_group.map(|group| group.to_string()).unwrap_or_else(|| "-".to_string());
+        let numa_node = device.numa_node.map(|node| node.to_string()).unwrap_or_else(|| "-".to_string());
+        let memory = device.memory.map(|memory| format!("{}G", memory.div_ceil(1 << 30))).unwrap_or_else(|| "-".to_string());
+        let runtime = device.claim.as_ref()
+            .map(|claim| claim.runtime.clone().unwrap_or_else(|| "(unnamed)".to_string()))
+            .unwrap_or_else(|| "-".to_string());
+        let passable = match &device.reason {
+            Some(reason) => format!("no, {}", reason),
+            None => "yes".to_string(),
+        };
+        println!("{:<13} {:<10} {:<12} {:<10} {:>5} {:>4} {:>7}  {:<12} {}", device.address, format!("{}:{}", device.vendor_id, device.device_id),
+                 device.vendor.as_deref().unwrap_or("-"), device.driver.as_deref().unwrap_or("-"), group, numa_node, memory, runtime, passable);
+    }
+    
+    Ok(())
+}
+
+fn prune_images() -> Result<()> {
+    let store = images::ImageStore::open()?;
+    let keep = image_users()?.into_keys().collect();
+    
+    let summary = store.prune(&keep)?;
+    for reference in &summary.references {
+        println!("Removed {}", reference);
+    }
+    println!("Deleted {} files, reclaimed {:.1}G",
+             summary.files, summary.bytes as f64 / (1u64 << 30) as f64);
+    
+    Ok(())
+}
+
+// Remove what stopped runtimes left behind, whether or not the configuration file still defines
+// them: PID files, sockets and state files, guest logs, and the overlays of ephemeral runtimes
+// that crashed. State records go only once the configuration file no longer defines the runtime.
+fn prune_stale_state(dry_run: bool) -> Result<()> {
+    let config_file = get_config_file_path();
+    let manifest = match Path::new(&config_file).exists() {
+        true => Some(Manifest::load(&config_file)?),
+        false => None,
+    };
+    let global = manifest.as_ref().map(|manifest| manifest.global.clone()).unwrap_or_default();
+    let pid_file = runtime_pid_file_path(None);
+    let runtime_dir = Path::new(&pid_file).parent().unwrap_or(Path::new("."));
+    let prefix = Path::new(&pid_file).file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let state_dir = get_state_dir(&global);
+    
+    let mut stale = prune::runtime_files(runtime_dir, &prefix)?;
+    let running = prune::running(runtime_dir, &prefix, &state_dir)?;
+    // Whatever runs them, files still in use stay
+    let open = prune::open_files(&running.iter().map(|runtime| runtime.pid).collect::<Vec<_>>())?;
+    stale.extend(prune::guest_logs(&Path::new(&state_dir).join("logs"), |name| {
+        // The unnamed runtime writes hypervisor-guest.log
+        let stem = match name {
+            "hypervisor" => prefix.clone(),
+            name => format!("{}-{}", prefix, name),
+        };
+        running.iter().any(|runtime| runtime.stem == stem)
+    }, &open)?);
+    match running.iter().find(|runtime| runtime.booting) {
+        Some(runtime) => println!("Keeping overlays while {} is booting", runtime.stem),
+        None => stale.extend(prune::overlays(&Path::new(&state_dir).join("overlays"), &open)?),
+    }
+    if let Some(manifest) = &manifest {
+        stale.extend(prune::records(&state_dir, |name| {
+            manifest.runtimes.iter().any(|runtime| runtime.name == name)
+                || running.iter().any(|runtime| runtime.stem == format!("{}-{}", prefix, name))
+        })?);
+    }
+    
+    let (files, bytes) = prune::remove(&stale, dry_run)?;
+    let size = bytes as f64 / (1u64 << 30) as f64;
+    if dry_run {
+        println!("Would delete {} files, reclaiming {:.1}G", files, size);
+    } else {
+        println!("Deleted {} files, reclaimed {:.1}G", files, size);
+    }
+    
+    Ok(())
+}
+
+// Function to show environment variables and their current values
+fn create_command_app() -> ClapCommand {
+    ClapCommand::new("vllmd-hypervisor")
+        .version("0.1.0")
+        .author("vllmd-hypervisor")
+        .about("VLLMD: Purpose-built hypervisor for secure machine learning inference workloads")
+        .arg(clap::Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .help("Log more; repeat for debug and trace output")
+            .global(true)
+            .action(clap::ArgAction::Count))
+        .arg(clap::Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .help("Log less; repeat to silence errors too")
+            .global(true)
+            .action(clap::ArgAction::Count))
+        .arg(clap::Arg::new("no-color")
+            .long("no-color")
+            .help("Print plain text without ANSI colors, as with NO_COLOR set")
+            .global(true)
+            .action(clap::ArgAction::SetTrue))
+        .subcommand(
+            ClapCommand::new("start")
+                .about("Start the hypervisor")
+                .arg(clap::Arg::new("wait-ready")
+                    .long("wait-ready")
+                    .help("Report readiness to systemd only once the guest signals its workload is ready")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("run-as")
+                    .long("run-as")
+                    .value_name("USER[:GROUP]")
+                    .help("Drop root privileges to this user before the VMM starts"))
+                .arg(clap::Arg::new("boot-timeout")
+                    .long("boot-timeout")
+                    .value_name("SECONDS")
+                    .help("Give up and clean up when the VM has not booted, or a guest that reports readiness is not ready, within this many seconds")
+                    .value_parser(clap::value_parser!(u64).range(1..)))
+                .arg(clap::Arg::new("print-cmdline")
+                    .long("print-cmdline")
+                    .help("Print the merged kernel command line and exit without starting the VM")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("lenient")
+                    .long("lenient")
+                    .help("Log unknown VLLMD_HYPERVISOR_* variables and values that do not parse, and use the defaults, instead of failing")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("dry-run")
+                    .long("dry-run")
+                    .help("Resolve, validate and check the configuration, print the VM configuration and host changes start would make, and exit without starting the VM")
+                    .conflicts_with("print-cmdline")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("all")
+                    .long("all")
+                    .help("Start every runtime of the configuration file and wait until they have all exited")
+                    .conflicts_with_all(["print-cmdline", "dry-run"])
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("parallel")
+                    .long("parallel")
+                    .value_name("COUNT")
+                    .help("Runtimes --all boots at the same time")
+                    .requires("all")
+                    .value_parser(clap::value_parser!(usize)))
+        )
+        .subcommand(
+            ClapCommand::new("stop")
+                .about("Stop the hypervisor")
+                .arg(clap::Arg::new("all")
+                    .long("all")
+                    .help("Stop every runtime of the configuration file and every runtime registered in the state store, and wait for them to exit")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("selector")
+                    .long("selector")
+                    .value_name("SELECTOR")
+                    .help("Stop every running runtime of the configuration file whose labels match, e.g. tier=dev,model!=llama-70b, and wait for them to exit"))
+                .arg(clap::Arg::new("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Seconds the runtimes --all or --selector stops may take to exit")
+                    .default_value("600")
+                    .value_parser(clap::value_parser!(u64)))
+        )
+        .subcommand(
+            ClapCommand::new("restart")
+                .about("Shut the VM down and boot it again in place, with the configuration file and images read again")
+                .arg(clap::Arg::new("all")
+                    .long("all")
+                    .help("Restart every running runtime of the configuration file, a batch at a time")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("selector")
+                    .long("selector")
+                    .value_name("SELECTOR")
+                    .help("Restart only the runtimes whose labels match")
+                    .requires("all"))
+                .arg(clap::Arg::new("max-unavailable")
+                    .long("max-unavailable")
+                    .value_name("COUNT")
+                    .help("Runtimes --all restarts at the same time")
+                    .requires("all")
+                    .default_value("1")
+                    .value_parser(clap::value_parser!(usize)))
+                .arg(clap::Arg::new("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Seconds each runtime may take to be ready again")
+                    .default_value("600")
+                    .value_parser(clap::value_parser!(u64)))
+        )
+        .subcommand(
+            ClapCommand::new("upgrade")
+                .about("Hand the running VM to the hypervisor binary installed now, without rebooting the guest")
+                .arg(clap::Arg::new("all")
+                    .long("all")
+                    .help("Upgrade every running runtime of the configuration file, a batch at a time")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("selector")
+                    .long("selector")
+                    .value_name("SELECTOR")
+                    .help("Upgrade only the runtimes whose labels match")
+                    .requires("all"))
+                .arg(clap::Arg::new("max-unavailable")
+                    .long("max-unavailable")
+                    .value_name("COUNT")
+                    .help("Runtimes --all upgrades at the same time")
+                    .requires("all")
+                    .default_value("1")
+                    .value_parser(clap::value_parser!(usize)))
+                .arg(clap::Arg::new("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Seconds each runtime may take to be back")
+                    .default_value("600")
+                    .value_parser(clap::value_parser!(u64)))
+        )
+        .subcommand(
+            ClapCommand::new("up")
+                .about("Start, restart and stop runtimes until the running ones match the configuration file")
+                .arg(clap::Arg::new("file")
+                    .short('f')
+                    .long("file")
+                    .value_name("FILE")
+                    .help("Configuration file to apply instead of VLLMD_HYPERVISOR_CONFIG"))
+                .arg(clap::Arg::new("wait-ready")
+                    .long("wait-ready")
+                    .help("Count a runtime as started only once its guest reports ready")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Seconds each runtime may take to stop or be ready again")
+                    .default_value("600")
+                    .value_parser(clap::value_parser!(u64)))
+        )
+        .subcommand(
+            ClapCommand::new("down")
+                .about("Stop every runtime of the configuration file and wait for them to exit")
+                .arg(clap::Arg::new("file")
+                    .short('f')
+                    .long("file")
+                    .value_name("FILE")
+                    .help("Configuration file whose runtimes to stop instead of VLLMD_HYPERVISOR_CONFIG"))
+                .arg(clap::Arg::new("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Seconds the runtimes may take to exit")
+                    .default_value("600")
+                    .value_parser(clap::value_parser!(u64)))
+        )
+        .subcommand(
+            ClapCommand::new("drain")
+                .about("Stop every runtime and keep VMs from booting on the host until uncordon, for maintenance")
+                .arg(clap::Arg::new("snapshot")
+                    .long("snapshot")
+                    .help("Snapshot the running runtimes of the configuration file before stopping them")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("reason")
+                    .long("reason")
+                    .value_name("TEXT")
+                    .help("Why the host is drained, shown when start refuses to boot"))
+                .arg(clap::Arg::new("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Seconds the runtimes may take to exit")
+                    .default_value("600")
+                    .value_parser(clap::value_parser!(u64)))
+        )
+        .subcommand(
+            ClapCommand::new("uncordon")
+                .about("Let VMs boot on a drained host again")
+        )
+        .subcommand(
+            ClapCommand::new("enable-nested")
+                .about("Reload the host KVM module with nested virtualization on; no VM of the host may run")
+        )
+        .subcommand(
+            ClapCommand::new("dbus")
+                .about("Serve org.vllmd.Hypervisor1 on D-Bus to start, stop and watch the runtimes of the configuration file")
+                .arg(clap::Arg::new("session")
+                    .long("session")
+                    .help("Own the name on the session bus of the user instead of the system bus, without polkit checks")
+                    .action(clap::ArgAction::SetTrue))
+        )
+        .subcommand(
+            ClapCommand::new("status")
+                .about("Check hypervisor status")
+                .arg(clap::Arg::new("diff")
+                    .long("diff")
+                    .help("Compare the running VM with what the configuration file and environment define now")
+                    .action(clap::ArgAction::SetTrue))
+        )
+        .subcommand(
+            ClapCommand::new("list")
+                .about("List the runtimes of the configuration file with their state and labels")
+                .arg(clap::Arg::new("selector")
+                    .long("selector")
+                    .value_name("SELECTOR")
+                    .help("List only the runtimes whose labels match, e.g. tier=prod"))
+        )
+        .subcommand(
+            ClapCommand::new("events")
+                .about("Subscribe to the host lifecycle events and print them as JSON lines")
+        )
+        .subcommand(
+            ClapCommand::new("top")
+                .about("Show the CPU, memory, disk and network use of all runtimes, refreshed live")
+                .arg(clap::Arg::new("interval")
+                    .long("interval")
+                    .value_name("SECONDS")
+                    .help("Seconds between refreshes")
+                    .value_parser(clap::value_parser!(f64))
+                    .default_value("1"))
+                .arg(clap::Arg::new("once")
+                    .long("once")
+                    .help("Print a single view after one interval and exit")
+                    .action(clap::ArgAction::SetTrue))
+        )
+        .subcommand(
+            ClapCommand::new("info")
+                .about("Show the vCPUs, memory and devices of the running VM")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                .arg(clap::Arg::new("json")
+                    .long("json")
+                    .help("Print the details as JSON")
+                    .action(clap::ArgAction::SetTrue))
+        )
+        .subcommand(
+            ClapCommand::new("remote")
+                .about("Send a request to the API socket of a running runtime")
+                .subcommand_required(true)
+                .subcommands(["pause", "resume", "resize", "info"].map(|action| {
+                    let about = match action {
+                        "pause" => "Pause the vCPUs of the VM",
+                        "resume" => "Let the vCPUs of a paused VM run again",
+                        "resize" => "Change the vCPUs, memory or balloon size of the running VM",
+                        _ => "Print the vm.info response of the VMM as JSON",
+                    };
+                    ClapCommand::new(action)
+                        .about(about)
+                        .arg(clap::Arg::new("name")
+                            .value_name("NAME")
+                            .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                }))
+                .mut_subcommand("resize", |resize| resize
+                    .arg(clap::Arg::new("vcpus")
+                        .long("vcpus")
+                        .value_name("COUNT")
+                        .help("Number of vCPUs, up to the maximum the VM booted with")
+                        .value_parser(clap::value_parser!(u8).range(1..)))
+                    .arg(clap::Arg::new("memory")
+                        .long("memory")
+                        .value_name("SIZE")
+                        .help("Memory size, such as 32G, within the hotplug range of the VM"))
+                    .arg(clap::Arg::new("balloon")
+                        .long("balloon")
+                        .value_name("SIZE")
+                        .help("Memory the balloon takes away from the guest"))
+                    .group(clap::ArgGroup::new("size")
+                        .args(["vcpus", "memory", "balloon"])
+                        .multiple(true)
+                        .required(true)))
+        )
+        .subcommand(
+            ClapCommand::new("inspect")
+                .about("Print the configuration, state, devices, sockets and recent events of a runtime as JSON")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+        )
+        .subcommand(
+            ClapCommand::new("doctor")
+                .about("Check that the host can run the configured runtime")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+        )
+        .subcommand(
+            ClapCommand::new("env")
+                .about("Show environment variables and their values")
+                .arg(clap::Arg::new("show-colors")
+                    .long("show-colors")
+                    .help("Display brand color information")
+                    .action(clap::ArgAction::SetTrue))
+                .arg(clap::Arg::new("export")
+                    .long("export")
+                    .value_name("PATH")
+                    .help("Write the resolved variables to PATH as a .env / systemd EnvironmentFile, or - for stdout"))
+                .arg(clap::Arg::new("check")
+                    .long("check")
+                    .help("Validate the variables and exit non-zero if any is invalid or a required one is missing")
+                    .conflicts_with("export")
+                    .action(clap::ArgAction::SetTrue))
+        )
+        .subcommand(
+            ClapCommand::new("audit")
+                .about("Inspect the audit log of privileged operations")
+                .subcommand_required(true)
+                .subcommand(
+                    ClapCommand::new("verify")
+                        .about("Check the hash chain of the audit log and exit non-zero if it is broken")
+                        .arg(clap::Arg::new("file")
+                            .long("file")
+                            .value_name("PATH")
+                            .help("Audit log to check instead of the configured one"))
+                )
+        )
+        .subcommand(
+            ClapCommand::new("vars")
+                .about("Query the variable registry (VARIABLES.md)")
+                .subcommand_required(true)
+                .subcommand(
+                    ClapCommand::new("list")
+                        .about("List documented variables")
+                        .arg(clap::Arg::new("scope")
+                            .long("scope")
+                            .value_name("SCOPE")
+                            .help("Only variables of SCOPE: global, runtime, network or env"))
+                        .arg(clap::Arg::new("type")
+                            .long("type")
+                            .value_name("TYPE")
+                            .help("Only variables of TYPE, such as string, integer, boolean or array"))
+                        .arg(clap::Arg::new("file")
+                            .long("file")
+                            .value_name("PATH")
+                            .help("Registry to read instead of the one embedded at build time"))
+                )
+                .subcommand(
+                    ClapCommand::new("check")
+                        .about("Check naming conventions and cross-check the registry against this binary")
+                        .arg(clap::Arg::new("file")
+                            .long("file")
+                            .value_name("PATH")
+                            .help("Registry to read instead of the one embedded at build time"))
+                        .arg(clap::Arg::new("scripts")
+                            .value_name("SCRIPT")
+                            .help("Shell scripts whose VLLMD_* variables must be documented")
+                            .num_args(0..))
+                )
+        )
+        .subcommand(
+            ClapCommand::new("add-net")
+                .about("Hotplug a network device into the running VM")
+                .arg(clap::Arg::new("tap")
+                    .long("tap")
+                    .value_name("TAP")
+                    .help("Name of the host tap device")
+                    .required(true))
+                .arg(clap::Arg::new("mac")
+                    .long("mac")
+                    .value_name("MAC")
+                    .help("Guest MAC address"))
+                .arg(clap::Arg::new("id")
+                    .long("id")
+                    .value_name("ID")
+                    .help("Device identifier, used by remove-net"))
+                .arg(clap::Arg::new("num-queues")
+                    .long("num-queues")
+                    .value_name("COUNT")
+                    .help("Number of virtio queues, two per receive/transmit pair (default: two per vCPU)")
+                    .value_parser(clap::value_parser!(usize)))
+                .arg(clap::Arg::new("queue-size")
+                    .long("queue-size")
+                    .value_name("SIZE")
+                    .help("Descriptors in each virtio queue, a power of two")
+                    .value_parser(clap::value_parser!(u16)))
+        )
+        .subcommand(
+            ClapCommand::new("remove-net")
+                .about("Hot-remove a network device from the running VM")
+                .arg(clap::Arg::new("id")
+                    .value_name("ID")
+                    .help("Identifier of the device to remove")
+                    .required(true))
+        )
+        .subcommand(
+            ClapCommand::new("snapshot")
+                .about("Pause the running VM, write a snapshot and resume it")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                .arg(clap::Arg::new("dir")
+                    .long("dir")
+                    .value_name("DIR")
+                    .help("Directory to write the snapshot to, by default a new one under the state directory"))
+        )
+        .subcommand(
+            ClapCommand::new("snapshots")
+                .about("Manage the snapshots taken of a runtime")
+                .subcommand_required(true)
+                .subcommand(
+                    ClapCommand::new("list")
+                        .about("List the snapshots of the runtime, oldest first")
+                        .arg(clap::Arg::new("name")
+                            .value_name("NAME")
+                            .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                        .arg(clap::Arg::new("json")
+                            .long("json")
+                            .help("Print a JSON array instead of a table")
+                            .action(clap::ArgAction::SetTrue))
+                )
+                .subcommand(
+                    ClapCommand::new("delete")
+                        .about("Delete snapshots of the runtime, by name or path")
+                        .arg(clap::Arg::new("snapshot")
+                            .value_name("SNAPSHOT")
+                            .help("Name or path of the snapshot, as `snapshots list` shows it")
+                            .required(true)
+                            .num_args(1..))
+                        .arg(clap::Arg::new("name")
+                            .long("name")
+                            .value_name("NAME")
+                            .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                )
+                .subcommand(
+                    ClapCommand::new("prune")
+                        .about("Delete the snapshots the retention of the runtime no longer keeps")
+                        .arg(clap::Arg::new("name")
+                            .value_name("NAME")
+                            .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                )
+        )
+        .subcommand(
+            ClapCommand::new("import")
+                .about("Print a [[runtimes]] entry translated from the VM definition of another tool")
+                .subcommand_required(true)
+                .subcommand(
+                    ClapCommand::new("libvirt")
+                        .about("Translate libvirt domain XML, as written by virsh dumpxml")
+                        .arg(clap::Arg::new("file")
+                            .value_name("FILE")
+                            .help("Domain XML file, - for stdin")
+                            .required(true))
+                        .arg(clap::Arg::new("name")
+                            .long("name")
+                            .value_name("NAME")
+                            .help("Runtime name instead of the domain name"))
+                        .arg(clap::Arg::new("index")
+                            .long("index")
+                            .value_name("INDEX")
+                            .help("Runtime index, by default one past the highest of the configuration file")
+                            .value_parser(clap::value_parser!(u32)))
+                )
+                .subcommand(
+                    ClapCommand::new("qemu-args")
+                        .about("Translate a QEMU command line, given after -- or in a script")
+                        .arg(clap::Arg::new("file")
+                            .long("file")
+                            .value_name("FILE")
+                            .help("Script running QEMU, - for stdin"))
+                        .arg(clap::Arg::new("name")
+                            .long("name")
+                            .value_name("NAME")
+                            .help("Runtime name instead of the one of -name"))
+                        .arg(clap::Arg::new("index")
+                            .long("index")
+                            .value_name("INDEX")
+                            .help("Runtime index, by default one past the highest of the configuration file")
+                            .value_parser(clap::value_parser!(u32)))
+                        .arg(clap::Arg::new("args")
+                            .value_name("ARGS")
+                            .help("QEMU command line, after --")
+                            .num_args(1..)
+                            .last(true)
+                            .allow_hyphen_values(true))
+                        .group(clap::ArgGroup::new("command")
+                            .args(["file", "args"])
+                            .required(true))
+                )
+        )
+        .subcommand(
+            ClapCommand::new("pull")
+                .about("Pull an OCI artifact into the local image store")
+                .arg(clap::Arg::new("reference")
+                    .value_name("REFERENCE")
+                    .help("Image reference, e.g. oci://ghcr.io/org/image:tag#disk.raw")
+                    .required(true))
+        )
+        .subcommand(
+            ClapCommand::new("build-image")
+                .about("Build a bootable system image from an OCI container image and add it to the image store")
+                .arg(clap::Arg::new("reference")
+                    .value_name("REFERENCE")
+                    .help("Container image reference, e.g. oci://ghcr.io/org/vllm-server:0.6")
+                    .required(true))
+                .arg(clap::Arg::new("name")
+                    .long("name")
+                    .value_name("NAME")
+                    .help("Name of the image, referred to as image://<name>; by default the repository name and tag"))
+                .arg(clap::Arg::new("filesystem")
+                    .long("filesystem")
+                    .value_name("FILESYSTEM")
+                    .value_parser(["ext4", "erofs"])
+                    .default_value("ext4")
+                    .help("Root filesystem: writable ext4 or read-only erofs"))
+                .arg(clap::Arg::new("size")
+                    .long("size")
+                    .value_name("SIZE")
+                    .help("Size of an ext4 filesystem, e.g. 40G; by default the contents with a quarter and 256M to spare"))
+        )
+        .subcommand(
+            ClapCommand::new("images")
+                .about("Manage the local image store")
+                .subcommand_required(true)
+                .subcommand(ClapCommand::new("list").about("List stored images and the runtimes using them"))
+                .subcommand(ClapCommand::new("prune").about("Remove images no runtime refers to"))
+        )
+        .subcommand(
+            ClapCommand::new("devices")
+                .about("Inspect the PCI devices of the host")
+                .subcommand_required(true)
+                .subcommand(
+                    ClapCommand::new("list")
+                        .about("List GPUs and accelerators with their driver, IOMMU group, NUMA node and whether they can be passed through")
+                        .arg(clap::Arg::new("json")
+                            .long("json")
+                            .help("Print a JSON array instead of a table")
+                            .action(clap::ArgAction::SetTrue))
+                )
+        )
+        .subcommand(
+            ClapCommand::new("prune")
+                .about("Remove the PID files, sockets, guest logs and overlays of runtimes that are not running")
+                .arg(clap::Arg::new("dry-run")
+                    .long("dry-run")
+                    .help("Only print what would be removed")
+                    .action(clap::ArgAction::SetTrue))
+        )
+        .subcommand(
+            ClapCommand::new("console")
+                .about("Attach to the guest serial console (Ctrl-] detaches)")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+        )
+        .subcommand(
+            ClapCommand::new("logs")
+                .about("Show hypervisor or guest application logs")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                .arg(clap::Arg::new("source")
+                    .long("source")
+                    .value_name("SOURCE")
+                    .value_parser(["host", "guest"])
+                    .default_value("host")
+                    .help("Log source: the hypervisor log or the guest log channel"))
+                .arg(clap::Arg::new("follow")
+                    .long("follow")
+                    .short('f')
+                    .help("Keep printing new log lines")
+                    .action(clap::ArgAction::SetTrue))
+        )
+        .subcommand(
+            ClapCommand::new("exec")
+                .about("Run a command inside the guest through the agent")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name, defaults to VLLMD_HYPERVISOR_RUNTIME_NAME"))
+                .arg(clap::Arg::new("command")
+                    .value_name("COMMAND")
+                    .help("Command and arguments, after --")
+                    .num_args(1..)
+                    .last(true)
+                    .required(true))
+        )
+        .subcommand(
+            ClapCommand::new("cp")
+                .about("Copy a file between the host and a guest through the agent")
+                .arg(clap::Arg::new("source")
+                    .value_name("SOURCE")
+                    .help("host:<path> or <name>:<path>")
+                    .required(true))
+                .arg(clap::Arg::new("destination")
+                    .value_name("DESTINATION")
+                    .help("host:<path> or <name>:<path>")
+                    .required(true))
+        )
+        .subcommand(
+            ClapCommand::new("attest")
+                .about("Fetch an attestation report from a confidential guest")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name")
+                    .required(true))
+                .arg(clap::Arg::new("nonce")
+                    .long("nonce")
+                    .value_name("HEX")
+                    .help("Nonce bound into the report, up to 64 bytes in hex; random by default"))
+                .arg(clap::Arg::new("output")
+                    .long("output")
+                    .short('o')
+                    .value_name("FILE")
+                    .help("Also write the raw report to FILE for external verifiers"))
+        )
+        .subcommand(
+            ClapCommand::new("forward")
+                .about("Forward a host port to a port inside the guest")
+                .arg(clap::Arg::new("name")
+                    .value_name("NAME")
+                    .help("Runtime name")
+                    .required(true))
+                .arg(clap::Arg::new("ports")
+                    .value_name("HOST_PORT:GUEST_PORT")
+                    .help("Host port and guest port, e.g. 8000:8000")
+                    .required(true))
+                .arg(clap::Arg::new("mode")
+                    .long("mode")
+                    .value_name("MODE")
+                    .value_parser(["vsock", "dnat"])
+                    .default_value("vsock")
+                    .help("Relay through the guest agent over vsock, or install iptables DNAT rules to a bridged guest"))
+                .arg(clap::Arg::new("guest-ip")
+                    .long("guest-ip")
+                    .value_name("ADDRESS")
+                    .required_if_eq("mode", "dnat")
+                    .help("Guest address on the bridged network, for --mode dnat"))
+                .arg(clap::Arg::new("address")
+                    .long("address")
+                    .value_name("ADDRESS")
+                    .default_value("127.0.0.1")
+                    .help("Host address the vsock proxy listens on"))
+                .arg(clap::Arg::new("remove")
+                    .long("remove")
+                    .action(clap::ArgAction::SetTrue)
+                    .requires("guest-ip")
+                    .help("Remove the iptables DNAT rules added with --mode dnat"))
+        )
+        .subcommand(
+            ClapCommand::new("agent")
+                .about("Run or talk to the guest agent over vsock")
+                .subcommand_required(true)
+                .subcommand(
+                    ClapCommand::new("serve")
+                        .about("Run the guest agent (inside the guest)")
+                        .arg(clap::Arg::new("port")
+                            .long("port")
+                            .value_name("PORT")
+                            .value_parser(clap::value_parser!(u32))
+                            .help("vsock port to listen on"))
+                )
+                .subcommand(
+                    ClapCommand::new("ready")
+                        .about("Signal the host that the guest workload is ready (inside the guest)")
+                )
+                .subcommand(
+                    ClapCommand::new("ping")
+                        .about("Check that the guest agent answers")
+                        .arg(clap::Arg::new("name").value_name("NAME").help("Runtime name"))
+                )
+                .subcommand(
+                    ClapCommand::new("metrics")
+                        .about("Show guest resource usage reported by the agent")
+                        .arg(clap::Arg::new("name").value_name("NAME").help("Runtime name"))
+                )
+                .subcommand(
+                    ClapCommand::new("shutdown")
+                        .about("Ask the guest to power off")
+                        .arg(clap::Arg::new("name").value_name("NAME").help("Runtime name"))
+                )
+        )
+}
+
+// Environment variables vllmd-hypervisor reads, with their defaults and descriptions
+fn environment_vars() -> Vec<(&'static str, Option<String>, &'static str)> {
+    // Convert CPU count to a string first so it lives long enough
+    let cpu_count_str = DEFAULT_CPU_COUNT.to_string();
+    let default_start_parallelism = DEFAULT_START_PARALLELISM.to_string();
+    let default_textfile_interval = DEFAULT_TEXTFILE_INTERVAL_SECS.to_string();
+    
+    let vars = [
+        (LOG_FILEPATH_VAR, Some(DEFAULT_LOG_FILEPATH), "Path where logs will be written"),
+        (LOG_TIMESTAMP_FORMAT_VAR, Some("compact"), "Timestamp of log lines: compact (YYYYMMDD-HHMMSS), rfc3339 or epoch-millis"),
+        (LOG_UTC_VAR, Some("off"), "Log timestamps in UTC instead of local time"),
+        (KERNEL_FILEPATH_VAR, None, "Path to the VM kernel file (required unless booting through firmware)"),
+        (SYSTEM_IMAGE_FILEPATH_VAR, None, "Path to the system disk image (required)"),
+        (SYSTEM_IMAGE_EPHEMERAL_VAR, Some("off"), "Boot from a throwaway qcow2 overlay of the system image"),
+        (GUEST_LOG_VAR, Some("off"), "Write the guest virtio console to a log file read by `logs --source guest`"),
+        (VSOCK_CID_VAR, None, "Guest CID of the vsock device; enables vsock when set"),
+        (VSOCK_SOCKET_PATH_VAR, None, "Host unix socket of the vsock device, next to the PID file by default"),
+        (TPM_VAR, Some("off"), "Attach a vTPM backed by swtpm"),
+        (NESTED_VAR, Some("off"), "Let the guest run its own VMs; needs nested virtualization in the host KVM module"),
+        (CONFIDENTIAL_VAR, Some("off"), "Confidential computing mode: sev-snp or tdx"),
+        (HOST_DATA_VAR, None, "64 hex digits of host data included in SEV-SNP attestation reports"),
+        (FIRMWARE_FILEPATH_VAR, None, "UEFI firmware to boot through, such as CLOUDHV.fd or TDVF for TDX"),
+        (INITRAMFS_FILEPATH_VAR, None, "Initramfs loaded with the kernel"),
+        (QGS_SOCKET_PATH_VAR, Some(confidential::DEFAULT_QGS_SOCKET_PATH), "Host socket of the TDX Quote Generation Service"),
+        (TRUST_PUBLIC_KEYS_VAR, None, "Comma-separated public keys that must have signed the kernel and system image"),
+        (TRUST_TOOL_VAR, Some("minisign"), "Signature verification tool: minisign or cosign"),
+        (TRUST_ENFORCE_VAR, Some("on"), "Refuse to boot when signature verification fails"),
+        (PROBE_URL_VAR, None, "URL of the in-guest inference health endpoint to probe"),
+        (PROBE_INTERVAL_VAR, Some("10"), "Seconds between endpoint probes"),
+        (PROBE_THRESHOLD_VAR, Some("3"), "Consecutive probe failures before the endpoint is unhealthy"),
+        (PROBE_KIND_VAR, Some("http"), "Check run by the probe: http or agent"),
+        (PROBE_ACTION_VAR, Some("log"), "Action on a failed probe: log, event, reboot or shutdown"),
+        (FREE_PAGE_REPORTING_VAR, Some("off"), "Return memory the guest frees to the host through a balloon device"),
+        (RNG_VAR, Some(DEFAULT_RNG), "Host entropy source of the guest virtio-rng device, such as /dev/hwrng"),
+        (STATE_DIR_VAR, None, "Directory for runtime state data such as overlays"),
+        (CONFIG_IMAGE_FILEPATH_VAR, None, "Path to the configuration disk image (required)"),
+        (CPU_COUNT_VAR, Some(cpu_count_str.as_str()), "Number of virtual CPUs"),
+        (MAX_CPU_COUNT_VAR, None, "Number of virtual CPUs the VM can be resized to without a reboot, the vCPU count by default"),
+        (MEMORY_CONFIG_VAR, Some(DEFAULT_MEMORY_CONFIG), "Memory configuration string"),
+        (CPU_CONFIG_VAR, None, "CPU configuration string: max_phys_bits, kvm_hyperv, features and topology of the vCPUs"),
+        (DEVICE_FILEPATH_LIST_VAR, None, "Comma-separated list of device paths to add"),
+        (DISK_LIST_VAR, None, "Semicolon-separated list of additional disks"),
+        (RATE_LIMIT_GROUP_LIST_VAR, None, "Semicolon-separated list of disk rate limit groups"),
+        (DISK_RATE_LIMIT_GROUP_VAR, None, "Rate limit group of the images and of every disk without limits of its own"),
+        (MEMORY_ZONE_LIST_VAR, None, "Semicolon-separated list of guest memory zones"),
+        (SGX_EPC_LIST_VAR, None, "Semicolon-separated list of guest SGX EPC sections"),
+        (CONFIG_VAR, None, "Path to the TOML runtime configuration file"),
+        (RUNTIME_NAME_VAR, None, "Name of the runtime to load from the configuration file"),
+        (CMDLINE_VAR, None, "Kernel command line parameters, merged over the [global] ones, with generated ones for what neither sets"),
+        (SECCOMP_VAR, Some("true"), "Seccomp filtering of the VMM: true (enforce), log or false"),
+        (LANDLOCK_VAR, Some("off"), "Confine the VMM with Landlock to the paths the runtime uses"),
+        (GPU_RESET_VAR, Some("off"), "Reset passed-through devices before boot and after shutdown"),
+        (DEVICE_RETRIES_VAR, Some("2"), "Retries of a device check, reset or configuration that failed, before start gives up"),
+        (DEVICE_RETRY_DELAY_MS_VAR, Some("500"), "Milliseconds before the first device retry, doubling for each one after"),
+        (WATCHDOG_VAR, Some("off"), "Guest watchdog device and the action once it resets the guest: off, reset or crash"),
+        (ON_REBOOT_VAR, Some("restart"), "What happens when the guest reboots itself: restart or stop"),
+        (SNAPSHOT_KEEP_VAR, None, "Snapshots of the runtime to keep, the newest first"),
+        (SNAPSHOT_MAX_AGE_VAR, None, "Age such as 7d after which snapshots of the runtime are deleted"),
+        (VCPU_SCHED_VAR, None, "Scheduling of the vCPU threads: fifo:<priority>, rr:<priority> or nice:<value>"),
+        (OOM_SCORE_ADJ_VAR, None, "oom_score_adj of the hypervisor process, from -1000 (never killed) to 1000"),
+        (BACKEND_VAR, Some("auto"), "Hypervisor backend: auto, kvm, mshv or mock"),
+        (DEBUG_VAR, None, "Set to any value to enable debug logging"),
+        (EVENT_SOCKET_DIR_VAR, None, "Host directory of the unix sockets lifecycle events are sent to, next to the PID files by default"),
+        (EVENT_LOG_FILEPATH_VAR, None, "File lifecycle events are appended to as JSON lines"),
+        (WEBHOOK_URL_VAR, None, "URL booted, ready, crashed and shutdown events are POSTed to"),
+        (AUDIT_LOG_FILEPATH_VAR, None, "Hash-chained log of privileged operations, audit.jsonl in the state directory by default"),
+        (START_PARALLELISM_VAR, Some(default_start_parallelism.as_str()), "Runtimes `start --all` boots at the same time"),
+        (TEXTFILE_DIR_VAR, None, "Directory of the node_exporter textfile collector the metrics of the runtime are written to"),
+        (TEXTFILE_INTERVAL_VAR, Some(default_textfile_interval.as_str()), "Seconds between two writes of the metrics file"),
+    ];
+    
+    vars.into_iter()
+        .map(|(name, default, description)| (name, default.map(String::from), description))
+        .collect()
+}
+
+// Quote a value for both POSIX shells and systemd EnvironmentFile= files
+fn quote_env_value(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Write every variable as set in the environment or defaulted as `VAR="value"`, and the unset
+// ones without a default commented out, to `path` or to stdout for `-`
+fn export_environment_vars(path: &str) -> Result<()> {
+    let mut contents = String::from("# Generated by vllmd-hypervisor env --export\n");
+    for (var_name, default, description) in environment_vars() {
+        contents.push_str(&format!("\n# {}\n", description));
+        match env::var(var_name).ok().or(default) {
+            Some(value) if value.contains('\n') => bail!("Value of {} contains a newline", var_name),
+            Some(value) => contents.push_str(&format!("{}={}\n", var_name, quote_env_value(&value))),
+            None => contents.push_str(&format!("#{}=\n", var_name)),
+        }
+    }
+    
+    if path == "-" {
+        print!("{}", contents);
+        return Ok(());
     }
     
-    info!("VM shutdown complete");
+    // Replace the file in one step so a unit reading it never sees half of it
+    let temp_path = format!("{}.tmp", path);
+    std::fs::write(&temp_path, contents)
+        .context(format!("Failed to write environment file: {}", temp_path))?;
+    std::fs::rename(&temp_path, path)
+        .context(format!("Failed to write environment file: {}", path))?;
+    println!("Environment written to {}", path);
     
     Ok(())
 }
 
-fn stop_hypervisor() -> Result<()> {
-    info!("Stopping hypervisor");
+// Fail unless `path` exists; remote images are fetched by `start` and not checked here
+fn check_path_exists(path: &str) -> Result<()> {
+    if path.starts_with("oci://") || path.starts_with("https://") || path.starts_with("http://")
+        || path.starts_with(images::BUILT_SCHEME) {
+        return Ok(());
+    }
+    if !Path::new(path).exists() {
+        bail!("{} does not exist", path);
+    }
+    Ok(())
+}
+
+// Check the value of one variable the way `start` reads it
+fn check_environment_var(var_name: &str, value: &str) -> Result<()> {
+    let trimmed = value.trim();
+    match var_name {
+        KERNEL_FILEPATH_VAR | FIRMWARE_FILEPATH_VAR | INITRAMFS_FILEPATH_VAR | SYSTEM_IMAGE_FILEPATH_VAR
+        | CONFIG_IMAGE_FILEPATH_VAR => check_path_exists(value),
+        // The configuration file is only read for a named runtime
+        CONFIG_VAR if env::var(RUNTIME_NAME_VAR).is_ok() => check_path_exists(value),
+        LOG_FILEPATH_VAR | EVENT_SOCKET_DIR_VAR | EVENT_LOG_FILEPATH_VAR | AUDIT_LOG_FILEPATH_VAR => match Path::new(value).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => bail!("Directory {} does not exist", dir.display()),
+            _ => Ok(()),
+        },
+        WEBHOOK_URL_VAR => WebhookConfig::new(trimmed).validate(),
+        DEVICE_FILEPATH_LIST_VAR | TRUST_PUBLIC_KEYS_VAR => value.split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .try_for_each(check_path_exists),
+        CPU_COUNT_VAR | MAX_CPU_COUNT_VAR => match trimmed.parse::<u8>() {
+            Ok(count) if count > 0 => Ok(()),
+            _ => bail!("Expected a vCPU count from 1 to 255, got {}", value),
+        },
+        VSOCK_CID_VAR => match trimmed.parse::<u32>() {
+            Ok(cid) if cid >= 3 => Ok(()),
+            _ => bail!("Expected a guest CID of 3 or more, got {}", value),
+        },
+        PROBE_INTERVAL_VAR | PROBE_THRESHOLD_VAR | START_PARALLELISM_VAR | SNAPSHOT_KEEP_VAR | TEXTFILE_INTERVAL_VAR => match trimmed.parse::<u32>() {
+            Ok(number) if number > 0 => Ok(()),
+            _ => bail!("Expected a positive number, got {}", value),
+        },
+        DEVICE_RETRIES_VAR => trimmed.parse::<u32>().map(|_| ())
+            .map_err(|_| anyhow!("Expected a number of retries, got {}", value)),
+        DEVICE_RETRY_DELAY_MS_VAR => trimmed.parse::<u64>().map(|_| ())
+            .map_err(|_| anyhow!("Expected a number of milliseconds, got {}", value)),
+        OOM_SCORE_ADJ_VAR => match trimmed.parse::<i32>() {
+            Ok(adj) if (-1000..=1000).contains(&adj) => Ok(()),
+            _ => bail!("Expected a number from -1000 to 1000, got {}", value),
+        },
+        MEMORY_CONFIG_VAR => parse_memory_string(value).map(|_| ()),
+        CPU_CONFIG_VAR => parse_cpu_string(value).map(|_| ()),
+        DISK_LIST_VAR => value.split(';')
+            .filter(|s| !s.is_empty())
+            .try_for_each(|disk| parse_disk_string(disk).map(|_| ())),
+        RATE_LIMIT_GROUP_LIST_VAR => value.split(';')
+            .filter(|s| !s.is_empty())
+            .try_for_each(|group| parse_rate_limit_group_string(group).map(|_| ())),
+        MEMORY_ZONE_LIST_VAR => value.split(';')
+            .filter(|s| !s.is_empty())
+            .try_for_each(|zone| parse_memory_zone_string(zone).map(|_| ())),
+        SGX_EPC_LIST_VAR => value.split(';')
+            .filter(|s| !s.is_empty())
+            .try_for_each(|section| parse_sgx_epc_string(section).map(|_| ())),
+        CMDLINE_VAR => CmdlineBuilder::new().add("runtime", value).map(|_| ()),
+        SECCOMP_VAR => parse_seccomp_action(value).map(|_| ()),
+        VCPU_SCHED_VAR => VcpuSched::parse(value).map(|_| ()),
+        BACKEND_VAR => BackendKind::parse(value).map(|_| ()),
+        CONFIDENTIAL_VAR => get_confidential_mode(None).map(|_| ()),
+        HOST_DATA_VAR if trimmed.len() != 64 || !trimmed.chars().all(|c| c.is_ascii_hexdigit()) => {
+            bail!("Expected 64 hex digits")
+        },
+        TRUST_TOOL_VAR => SignatureTool::parse(value).map(|_| ()),
+        PROBE_KIND_VAR => ProbeKind::parse(value).map(|_| ()),
+        PROBE_ACTION_VAR => ProbeAction::parse(value).map(|_| ()),
+        WATCHDOG_VAR => WatchdogAction::parse(value).map(|_| ()),
+        ON_REBOOT_VAR => RebootPolicy::parse(value).map(|_| ()),
+        SNAPSHOT_MAX_AGE_VAR => snapshots::parse_age(value).map(|_| ()),
+        LOG_TIMESTAMP_FORMAT_VAR => TimestampFormat::parse(value).map(|_| ()),
+        SYSTEM_IMAGE_EPHEMERAL_VAR | FREE_PAGE_REPORTING_VAR | GUEST_LOG_VAR | TPM_VAR | NESTED_VAR | LANDLOCK_VAR
+        | GPU_RESET_VAR | TRUST_ENFORCE_VAR | LOG_UTC_VAR => parse_bool_var(var_name).map(|_| ()),
+        RUNTIME_NAME_VAR => load_runtime().map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+// Fail on VLLMD_HYPERVISOR_* variables that neither this binary nor the tools next to it read,
+// and on values `start` cannot use, naming each variable. With --lenient the problems are
+// returned for logging instead, and the variables are taken out of the environment, so every
+// setting they would have made comes from the configuration file or its default. The file and
+// the runtime to load from it stay, since dropping those would boot a different runtime.
+fn check_environment_strict() -> Result<Vec<String>> {
+    let mut known: Vec<String> = environment_vars().into_iter().map(|(name, _, _)| name.to_string()).collect();
+    known.extend(registry::Registry::embedded().variables.into_iter()
+        .filter(|variable| variable.scope == registry::Scope::Env)
+        .map(|variable| variable.name));
     
-    // Get VM PID
-    let pid = match get_vm_pid() {
-        Ok(pid) => pid,
-        Err(e) => {
-            info!("No running hypervisor found: {}", e);
-            return Ok(());
+    let mut problems = Vec::new();
+    let mut ignored = Vec::new();
+    for (name, value) in env::vars() {
+        if !name.starts_with("VLLMD_HYPERVISOR_") {
+            continue;
         }
-    };
-    
-    info!("Sending SIGTERM to hypervisor process with PID: {}", pid);
+        if !known.contains(&name) {
+            problems.push(format!("Unknown environment variable {}", name));
+        } else if let Err(e) = check_environment_var(&name, &value) {
+            problems.push(format!("Invalid value for {}: {:#}", name, e));
+            if name == CONFIG_VAR || name == RUNTIME_NAME_VAR {
+                continue;
+            }
+        } else {
+            continue;
+        }
+        ignored.push(name);
+    }
     
-    // On Unix, we can send a signal to another process
-    #[cfg(unix)]
-    {
-        use nix::sys::signal::{kill, Signal};
-        use nix::unistd::Pid;
-        
-        kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
-            .map_err(|e| anyhow!("Failed to send SIGTERM to process {}: {}", pid, e))?;
-        
-        info!("SIGTERM sent successfully");
+    if !problems.is_empty() && !LENIENT_ENV.load(Ordering::SeqCst) {
+        bail!("{} (start with --lenient to use the defaults instead)", problems.join("; "));
     }
+    for name in ignored {
+        env::remove_var(name);
+    }
+    Ok(problems)
+}
+
+// Validate every variable of the current environment and print one line per variable. Boot
+// artifacts are required unless the selected runtime of the configuration file provides them.
+fn check_environment_vars() -> Result<()> {
+    let runtime = load_runtime().map(|(_, runtime)| runtime).unwrap_or_default();
+    let firmware = env::var(FIRMWARE_FILEPATH_VAR).is_ok() || runtime.firmware.is_some();
+    let required = [
+        (KERNEL_FILEPATH_VAR, runtime.kernel.is_some() || firmware),
+        (SYSTEM_IMAGE_FILEPATH_VAR, runtime.system_image.is_some()),
+        (CONFIG_IMAGE_FILEPATH_VAR, runtime.config_image.is_some()),
+    ];
     
-    // On non-Unix platforms, this won't work
-    #[cfg(not(unix))]
-    {
-        info!("Stop command not supported on this platform");
+    let mut failures = 0;
+    for (var_name, default, _) in environment_vars() {
+        let (status, detail) = match env::var(var_name) {
+            Ok(value) => match check_environment_var(var_name, &value) {
+                Ok(()) => ("ok", value),
+                Err(e) => ("invalid", format!("{:#}", e)),
+            },
+            Err(_) if required.iter().any(|(required, provided)| *required == var_name && !provided) => {
+                ("missing", "required".to_string())
+            },
+            Err(_) => ("unset", default.map(|default| format!("default {}", default)).unwrap_or_default()),
+        };
+        if status == "invalid" || status == "missing" {
+            failures += 1;
+        }
+        println!("{:<8} {:<42} {}", status, var_name, detail);
     }
     
+    if failures > 0 {
+        bail!("{} environment variable(s) invalid or missing", failures);
+    }
     Ok(())
 }
 
-fn check_hypervisor_status() -> Result<()> {
-    info!("Checking hypervisor status");
+fn load_registry(file: Option<&String>) -> Result<registry::Registry> {
+    match file {
+        Some(path) => registry::Registry::load(path),
+        None => Ok(registry::Registry::embedded()),
+    }
+}
+
+fn list_registry_vars(file: Option<&String>, scope: Option<&String>, kind: Option<&String>) -> Result<()> {
+    let registry = load_registry(file)?;
+    let scope = scope.map(|scope| registry::Scope::parse(scope)).transpose()?;
     
-    // Get VM PID
-    let pid = match get_vm_pid() {
-        Ok(pid) => pid,
-        Err(e) => {
-            info!("No running hypervisor found: {}", e);
-            println!("Status: Not running");
-            return Ok(());
+    println!("{:<42} {:<8} {:<8} {:<24} DESCRIPTION", "NAME", "SCOPE", "TYPE", "DEFAULT");
+    for variable in &registry.variables {
+        if scope.is_some_and(|scope| variable.scope != scope)
+            || kind.is_some_and(|kind| !variable.kind.eq_ignore_ascii_case(kind)) {
+            continue;
         }
-    };
+        println!("{:<42} {:<8} {:<8} {:<24} {}", variable.name, variable.scope.as_str(), variable.kind,
+                 variable.default, variable.description);
+    }
     
-    // Check if process is running
-    #[cfg(unix)]
-    {
-        use nix::sys::signal::{kill, Signal};
-        use nix::unistd::Pid;
-        
-        match kill(Pid::from_raw(pid as i32), Signal::SIGCONT) {
-            Ok(_) => {
-                info!("Hypervisor is running with PID: {}", pid);
-                println!("Status: Running (PID: {})", pid);
-            },
-            Err(_) => {
-                info!("Hypervisor process with PID {} is not running", pid);
-                println!("Status: Not running (stale PID file)");
-                
-                // Remove stale PID file
-                let pid_file = get_pid_file_path();
-                if let Err(e) = std::fs::remove_file(&pid_file) {
-                    debug!("Failed to remove stale PID file {}: {}", pid_file, e);
-                }
-            }
+    Ok(())
+}
+
+fn check_registry_vars(file: Option<&String>, scripts: &[&String]) -> Result<()> {
+    let registry = load_registry(file)?;
+    let vars: Vec<(&str, Option<String>)> = environment_vars().into_iter()
+        .map(|(name, default, _)| (name, default))
+        .collect();
+    
+    let mut issues = registry.check(&vars);
+    for script in scripts {
+        let contents = std::fs::read_to_string(script)
+            .context(format!("Failed to read script: {}", script))?;
+        for name in registry::undocumented_script_vars(&registry, &contents) {
+            issues.push(registry::Issue { kind: "undocumented", name, problem: format!("used in {} but not in the registry", script) });
         }
     }
     
-    // On non-Unix platforms, this won't work
-    #[cfg(not(unix))]
-    {
-        println!("Status: Unknown (status check not supported on this platform)");
+    for issue in &issues {
+        println!("{:<12} {:<42} {}", issue.kind, issue.name, issue.problem);
+    }
+    if !issues.is_empty() {
+        bail!("{} problem(s) in the variable registry", issues.len());
     }
     
+    println!("{} variables checked, no problems found", registry.variables.len());
     Ok(())
 }
 
-// Function to show environment variables and their current values
-fn create_command_app() -> ClapCommand {
-    ClapCommand::new("vllmd-hypervisor")
-        .version("0.1.0")
-        .author("vllmd-hypervisor")
-        .about("VLLMD: Purpose-built hypervisor for secure machine learning inference workloads")
-        .subcommand(ClapCommand::new("start").about("Start the hypervisor"))
-        .subcommand(ClapCommand::new("stop").about("Stop the hypervisor"))
-        .subcommand(ClapCommand::new("status").about("Check hypervisor status"))
-        .subcommand(
-            ClapCommand::new("env")
-                .about("Show environment variables and their values")
-                .arg(clap::Arg::new("show-colors")
-                    .long("show-colors")
-                    .help("Display brand color information")
-                    .action(clap::ArgAction::SetTrue))
-        )
-}
-
 fn show_environment_vars(show_colors: bool) -> Result<()> {
     use termimad::{MadSkin, crossterm::style::Color};
     
-    // Convert CPU count to a string first so it lives long enough
-    let cpu_count_str = DEFAULT_CPU_COUNT.to_string();
+    let vars = environment_vars();
     
-    let vars = [
-        (LOG_FILEPATH_VAR, Some(DEFAULT_LOG_FILEPATH), "Path where logs will be written"),
-        (KERNEL_FILEPATH_VAR, None, "Path to the VM kernel file (required)"),
-        (SYSTEM_IMAGE_FILEPATH_VAR, None, "Path to the system disk image (required)"),
-        (CONFIG_IMAGE_FILEPATH_VAR, None, "Path to the configuration disk image (required)"),
-        (CPU_COUNT_VAR, Some(cpu_count_str.as_str()), "Number of virtual CPUs"),
-        (MEMORY_CONFIG_VAR, Some(DEFAULT_MEMORY_CONFIG), "Memory configuration string"),
-        (DEVICE_FILEPATH_LIST_VAR, None, "Comma-separated list of device paths to add"),
-        (CMDLINE_VAR, None, "Kernel command line parameters"),
-        (DEBUG_VAR, None, "Set to any value to enable debug logging"),
-    ];
+    if !use_color(std::io::stdout().is_terminal()) {
+        return print_environment_vars_plain(&vars, show_colors);
+    }
     
     // Build markdown
     let mut markdown = String::from("# Environment Variables for vllmd-hypervisor\n\n");
@@ -551,6 +5038,36 @@ fn show_environment_vars(show_colors: bool) -> Result<()> {
     Ok(())
 }
 
+// The variable table as aligned plain text, for pipes, files and NO_COLOR
+fn print_environment_vars_plain(vars: &[(&str, Option<String>, &str)], show_colors: bool) -> Result<()> {
+    let values: Vec<String> = vars.iter()
+        .map(|(var_name, default, _)| match env::var(var_name) {
+            Ok(val) => val,
+            Err(_) => match default {
+                Some(def) => format!("{} (default)", def),
+                None => "not set".to_string(),
+            },
+        })
+        .collect();
+    let name_width = vars.iter().map(|(var_name, _, _)| var_name.len()).max().unwrap_or(0);
+    let value_width = values.iter().map(String::len).max().unwrap_or(0);
+    
+    println!("{:<name_width$}  {:<value_width$}  DESCRIPTION", "VARIABLE", "VALUE");
+    for ((var_name, _, description), value) in vars.iter().zip(&values) {
+        println!("{:<name_width$}  {:<value_width$}  {}", var_name, value, description);
+    }
+    
+    if show_colors {
+        println!();
+        println!("{:<9} {:<8} DESCRIPTION", "BRAND", "HEX");
+        println!("{:<9} {:<8} Vibrant Green", "Primary", "#00EA8C");
+        println!("{:<9} {:<8} Bright Blue", "Accent", "#0ACCF9");
+        println!("{:<9} {:<8} Orange", "Emphasis", "#EA8C00");
+    }
+    
+    Ok(())
+}
+
 fn main() -> Result<()> {
     // Create the command line app
     let app = create_command_app();
@@ -558,15 +5075,106 @@ fn main() -> Result<()> {
     // Parse command line arguments
     let matches = app.get_matches();
     
+    // Every output path checks NO_COLOR, including the logger
+    if matches.get_flag("no-color") {
+        env::set_var("NO_COLOR", "1");
+    }
+    
+    // -v and -q take precedence over RUST_LOG
+    let verbosity = matches.get_count("verbose") as i8 - matches.get_count("quiet") as i8;
+    if verbosity != 0 {
+        env::remove_var("RUST_LOG");
+    }
+    
     // Determine command
     let command = if matches.subcommand_matches("start").is_some() {
         CommandVerb::Start
     } else if matches.subcommand_matches("stop").is_some() {
         CommandVerb::Stop
+    } else if matches.subcommand_matches("restart").is_some() {
+        CommandVerb::Restart
+    } else if matches.subcommand_matches("upgrade").is_some() {
+        CommandVerb::Upgrade
+    } else if matches.subcommand_matches("dbus").is_some() {
+        CommandVerb::Dbus
+    } else if matches.subcommand_matches("up").is_some() {
+        CommandVerb::Up
+    } else if matches.subcommand_matches("down").is_some() {
+        CommandVerb::Down
+    } else if matches.subcommand_matches("drain").is_some() {
+        CommandVerb::Drain
+    } else if matches.subcommand_matches("uncordon").is_some() {
+        CommandVerb::Uncordon
+    } else if matches.subcommand_matches("enable-nested").is_some() {
+        CommandVerb::EnableNested
+    } else if matches.subcommand_matches("list").is_some() {
+        CommandVerb::List
+    } else if matches.subcommand_matches("doctor").is_some() {
+        CommandVerb::Doctor
     } else if matches.subcommand_matches("status").is_some() {
         CommandVerb::Status
+    } else if matches.subcommand_matches("info").is_some() {
+        CommandVerb::Info
+    } else if matches.subcommand_matches("inspect").is_some() {
+        CommandVerb::Inspect
+    } else if matches.subcommand_matches("top").is_some() {
+        CommandVerb::Top
+    } else if matches.subcommand_matches("events").is_some() {
+        CommandVerb::Events
     } else if matches.subcommand_matches("env").is_some() {
         CommandVerb::Env
+    } else if let Some(vars_matches) = matches.subcommand_matches("vars") {
+        if vars_matches.subcommand_matches("check").is_some() {
+            CommandVerb::VarsCheck
+        } else {
+            CommandVerb::VarsList
+        }
+    } else if matches.subcommand_matches("audit").is_some() {
+        CommandVerb::AuditVerify
+    } else if matches.subcommand_matches("add-net").is_some() {
+        CommandVerb::AddNet
+    } else if matches.subcommand_matches("remove-net").is_some() {
+        CommandVerb::RemoveNet
+    } else if matches.subcommand_matches("snapshot").is_some() {
+        CommandVerb::Snapshot
+    } else if let Some(snapshots_matches) = matches.subcommand_matches("snapshots") {
+        match snapshots_matches.subcommand_name() {
+            Some("delete") => CommandVerb::SnapshotsDelete,
+            Some("prune") => CommandVerb::SnapshotsPrune,
+            _ => CommandVerb::SnapshotsList,
+        }
+    } else if matches.subcommand_matches("pull").is_some() {
+        CommandVerb::Pull
+    } else if matches.subcommand_matches("build-image").is_some() {
+        CommandVerb::BuildImage
+    } else if matches.subcommand_matches("remote").is_some() {
+        CommandVerb::Remote
+    } else if matches.subcommand_matches("import").is_some() {
+        CommandVerb::Import
+    } else if let Some(images_matches) = matches.subcommand_matches("images") {
+        if images_matches.subcommand_matches("prune").is_some() {
+            CommandVerb::ImagesPrune
+        } else {
+            CommandVerb::ImagesList
+        }
+    } else if matches.subcommand_matches("devices").is_some() {
+        CommandVerb::DevicesList
+    } else if matches.subcommand_matches("prune").is_some() {
+        CommandVerb::Prune
+    } else if matches.subcommand_matches("console").is_some() {
+        CommandVerb::Console
+    } else if matches.subcommand_matches("logs").is_some() {
+        CommandVerb::Logs
+    } else if matches.subcommand_matches("agent").is_some() {
+        CommandVerb::Agent
+    } else if matches.subcommand_matches("exec").is_some() {
+        CommandVerb::Exec
+    } else if matches.subcommand_matches("cp").is_some() {
+        CommandVerb::Copy
+    } else if matches.subcommand_matches("attest").is_some() {
+        CommandVerb::Attest
+    } else if matches.subcommand_matches("forward").is_some() {
+        CommandVerb::Forward
     } else {
         // If no subcommand is provided or an invalid one was given, show help message
         let mut app = create_command_app();
@@ -575,40 +5183,433 @@ fn main() -> Result<()> {
         return Ok(());
     };
     
+    // start logs to its log file once its configuration is loaded; the other commands log
+    // warnings and errors to stderr in the same format
+    if !matches!(command, CommandVerb::Start) {
+        setup_logger(DEFAULT_LOG_FILEPATH, log_level(log::LevelFilter::Warn, verbosity), false)?;
+    }
+    
     // Execute command
     match command {
         CommandVerb::Start => {
+            let start_matches = matches.subcommand_matches("start").unwrap();
+            
+            // Each runtime reads its configuration in its own process
+            if start_matches.get_flag("all") {
+                let log_filepath = env::var(LOG_FILEPATH_VAR).unwrap_or_else(|_| DEFAULT_LOG_FILEPATH.to_string());
+                setup_logger(&log_filepath, log_level(log::LevelFilter::Info, verbosity), false)?;
+                let run_as = start_matches.get_one::<String>("run-as");
+                if let Some(spec) = run_as {
+                    RunAs::parse(spec)?;
+                }
+                return start_all(start_matches.get_flag("wait-ready"), run_as,
+                                 start_matches.get_one::<usize>("parallel").copied(), verbosity,
+                                 start_matches.get_flag("lenient"), start_matches.get_one::<u64>("boot-timeout").copied());
+            }
+            
+            // A typo in an environment file fails here rather than boot a smaller VM
+            LENIENT_ENV.store(start_matches.get_flag("lenient"), Ordering::SeqCst);
+            let ignored = check_environment_strict()?;
+            
             // Load configuration from environment
-            let config = HypervisorConfig::from_env()?;
+            let mut config = HypervisorConfig::from_env()?;
+            
+            if start_matches.get_flag("print-cmdline") {
+                for message in &config.cmdline_overrides {
+                    eprintln!("override: {}", message);
+                }
+                println!("{}", config.cmdline);
+                return Ok(());
+            }
+            add_runtime_devices(&mut config)?;
+            if start_matches.get_flag("dry-run") {
+                return dry_run_start(config);
+            }
+            
+            // Setup logger; after a restart, keep the log of the process this one replaced
+            let base = if config.debug { log::LevelFilter::Debug } else { log::LevelFilter::Info };
+            let restarted = Path::new(&get_restart_file_path()).exists();
+            setup_logger(&config.log_filepath, log_level(base, verbosity), restarted)?;
+            // A runtime that boots again in place is not a new boot
+            if !restarted {
+                check_not_drained(&config.state_dir)?;
+            }
+            for problem in &ignored {
+                warn!("{}; using the default as --lenient is set", problem);
+            }
             
-            // Setup logger
-            setup_logger(&config.log_filepath, config.debug)?;
+            let wait_ready = start_matches.get_flag("wait-ready");
+            let run_as = start_matches.get_one::<String>("run-as")
+                .map(|spec| RunAs::parse(spec))
+                .transpose()?;
             
-            // Start hypervisor
-            start_hypervisor(&config)?;
+            let claims = allocate_gpus(&mut config, true)?;
+            
+            // Start hypervisor, then record and let the webhooks hear about how it ended
+            let boot_timeout = start_matches.get_one::<u64>("boot-timeout").map(|secs| std::time::Duration::from_secs(*secs));
+            let result = start_hypervisor(&config, wait_ready, run_as.as_ref(), boot_timeout);
+            // The process keeps its GPUs when it boots again in place
+            if let Some(claims) = claims.filter(|_| !matches!(result, Ok(Exit::Restart | Exit::Upgrade))) {
+                if let Err(e) = claims.release() {
+                    warn!("Failed to release GPUs: {:#}", e);
+                }
+            }
+            match &result {
+                Ok(exit) => {
+                    let (reason, detail) = exit.reason();
+                    config.store.exit(reason, detail);
+                },
+                Err(e) => config.store.exit("error", Some(format!("{:#}", e))),
+            }
+            config.events.flush();
+            // Failures a fresh process can cure get their own exit code, for Restart=on-failure
+            // and for telling them apart in the journal
+            let failure = match result? {
+                Exit::Restart | Exit::Upgrade => {
+                    restart_in_place()?;
+                    None
+                },
+                Exit::Stop | Exit::Poweroff | Exit::Reboot => None,
+                Exit::Crashed => Some((EXIT_CODE_WATCHDOG, "The guest reset on its own and watchdog is crash".to_string())),
+                Exit::Panic => Some((EXIT_CODE_GUEST_PANIC, "The guest kernel panicked".to_string())),
+                Exit::VmmError(e) => Some((EXIT_CODE_VMM_ERROR, format!("The VMM stopped: {}", e))),
+                Exit::BootTimeout(secs) => Some((EXIT_CODE_BOOT_TIMEOUT, format!("The VM did not boot, or the guest was not ready, within {}s", secs))),
+            };
+            if let Some((code, message)) = failure {
+                eprintln!("Error: {}", message);
+                std::process::exit(code);
+            }
         },
         CommandVerb::Stop => {
-            // Setup minimal logging
-            env_logger::init();
-            
-            // Stop hypervisor
-            stop_hypervisor()?;
+            // Stop the hypervisor, or every runtime registered or matching the selector
+            let stop_matches = matches.subcommand_matches("stop").unwrap();
+            let selector = stop_matches.get_one::<String>("selector")
+                .map(|selector| Selector::parse(selector))
+                .transpose()?;
+            if stop_matches.get_flag("all") || selector.is_some() {
+                stop_runtimes(stop_matches.get_flag("all"), selector.as_ref(), *stop_matches.get_one::<u64>("timeout").unwrap())?;
+            } else {
+                stop_hypervisor()?;
+            }
+        },
+        CommandVerb::Restart => {
+            let restart_matches = matches.subcommand_matches("restart").unwrap();
+            let selector = restart_matches.get_one::<String>("selector")
+                .map(|selector| Selector::parse(selector))
+                .transpose()?;
+            restart_runtimes(restart_matches.get_flag("all"), selector.as_ref(),
+                             *restart_matches.get_one::<usize>("max-unavailable").unwrap(),
+                             *restart_matches.get_one::<u64>("timeout").unwrap(), false)?;
+        },
+        CommandVerb::Upgrade => {
+            let upgrade_matches = matches.subcommand_matches("upgrade").unwrap();
+            let selector = upgrade_matches.get_one::<String>("selector")
+                .map(|selector| Selector::parse(selector))
+                .transpose()?;
+            restart_runtimes(upgrade_matches.get_flag("all"), selector.as_ref(),
+                             *upgrade_matches.get_one::<usize>("max-unavailable").unwrap(),
+                             *upgrade_matches.get_one::<u64>("timeout").unwrap(), true)?;
+        },
+        CommandVerb::Dbus => {
+            dbus::serve(BusRuntimes, matches.subcommand_matches("dbus").unwrap().get_flag("session"))?;
+        },
+        CommandVerb::Up => {
+            let up_matches = matches.subcommand_matches("up").unwrap();
+            let config_file = up_matches.get_one::<String>("file").cloned().unwrap_or_else(get_config_file_path);
+            up_runtimes(&config_file, up_matches.get_flag("wait-ready"), *up_matches.get_one::<u64>("timeout").unwrap())?;
+        },
+        CommandVerb::Down => {
+            let down_matches = matches.subcommand_matches("down").unwrap();
+            let config_file = down_matches.get_one::<String>("file").cloned().unwrap_or_else(get_config_file_path);
+            down_runtimes(&config_file, *down_matches.get_one::<u64>("timeout").unwrap())?;
+        },
+        CommandVerb::Drain => {
+            let drain_matches = matches.subcommand_matches("drain").unwrap();
+            drain_host(drain_matches.get_flag("snapshot"), drain_matches.get_one::<String>("reason"),
+                       *drain_matches.get_one::<u64>("timeout").unwrap())?;
+        },
+        CommandVerb::Uncordon => {
+            uncordon_host()?;
+        },
+        CommandVerb::EnableNested => {
+            enable_nested_host()?;
         },
         CommandVerb::Status => {
-            // Setup minimal logging
-            env_logger::init();
-            
             // Check hypervisor status
-            check_hypervisor_status()?;
+            check_hypervisor_status(matches.subcommand_matches("status").unwrap().get_flag("diff"))?;
+        },
+        CommandVerb::List => {
+            let selector = matches.subcommand_matches("list").unwrap().get_one::<String>("selector")
+                .map(|selector| Selector::parse(selector))
+                .transpose()?;
+            list_runtimes(selector.as_ref())?;
+        },
+        CommandVerb::Doctor => {
+            if let Some(name) = matches.subcommand_matches("doctor").unwrap().get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            let (global, runtime) = load_runtime()?;
+            let memory_size = guest_memory_size(&get_memory_config(runtime.memory_gb.or(global.default_memory_gb)), &runtime.memory_zones)?;
+            let mut device_paths = get_device_filepath_list(&runtime.gpus);
+            device_paths.extend(resolve_devices(&runtime.devices)?.0);
+            let mut checks = doctor::run(get_backend(runtime.backend)?, get_confidential_mode(runtime.confidential)?,
+                                         get_nested(runtime.nested)?, &get_qgs_socket_path(), &device_paths, memory_size);
+            checks.extend(doctor::health(&device_paths));
+            let failures = doctor::report(&checks);
+            if failures > 0 {
+                bail!("{} host check(s) failed", failures);
+            }
         },
         CommandVerb::Env => {
             // Get any options from the env subcommand
             let env_matches = matches.subcommand_matches("env").unwrap();
             let show_colors = env_matches.get_flag("show-colors");
             
+            if let Some(path) = env_matches.get_one::<String>("export") {
+                return export_environment_vars(path);
+            }
+            if env_matches.get_flag("check") {
+                return check_environment_vars();
+            }
+            
             // Show environment variables
             show_environment_vars(show_colors)?;
         },
+        CommandVerb::VarsList => {
+            let list_matches = matches.subcommand_matches("vars").unwrap().subcommand_matches("list").unwrap();
+            
+            list_registry_vars(
+                list_matches.get_one::<String>("file"),
+                list_matches.get_one::<String>("scope"),
+                list_matches.get_one::<String>("type"),
+            )?;
+        },
+        CommandVerb::VarsCheck => {
+            let check_matches = matches.subcommand_matches("vars").unwrap().subcommand_matches("check").unwrap();
+            let scripts: Vec<&String> = check_matches.get_many::<String>("scripts").map(|scripts| scripts.collect()).unwrap_or_default();
+            
+            check_registry_vars(check_matches.get_one::<String>("file"), &scripts)?;
+        },
+        CommandVerb::AuditVerify => {
+            let verify_matches = matches.subcommand_matches("audit").unwrap().subcommand_matches("verify").unwrap();
+            
+            verify_audit_log(verify_matches.get_one::<String>("file"))?;
+        },
+        CommandVerb::AddNet => {
+            let add_net_matches = matches.subcommand_matches("add-net").unwrap();
+            
+            // Hotplug the network device
+            add_network_device(
+                add_net_matches.get_one::<String>("tap").unwrap(),
+                add_net_matches.get_one::<String>("mac"),
+                add_net_matches.get_one::<String>("id"),
+                add_net_matches.get_one::<usize>("num-queues").copied(),
+                add_net_matches.get_one::<u16>("queue-size").copied(),
+            )?;
+        },
+        CommandVerb::RemoveNet => {
+            let remove_net_matches = matches.subcommand_matches("remove-net").unwrap();
+            
+            // Hot-remove the network device
+            remove_network_device(remove_net_matches.get_one::<String>("id").unwrap())?;
+        },
+        CommandVerb::Info => {
+            let info_matches = matches.subcommand_matches("info").unwrap();
+            
+            if let Some(name) = info_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            show_vm_info(info_matches.get_flag("json"))?;
+        },
+        CommandVerb::Remote => {
+            let (action, action_matches) = matches.subcommand_matches("remote").unwrap().subcommand().unwrap();
+            if let Some(name) = action_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            remote_command(action, action_matches)?;
+        },
+        CommandVerb::Import => {
+            let (format, import_matches) = matches.subcommand_matches("import").unwrap().subcommand().unwrap();
+            
+            import_runtime(format, import_matches)?;
+        },
+        CommandVerb::Inspect => {
+            if let Some(name) = matches.subcommand_matches("inspect").unwrap().get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            inspect_runtime()?;
+        },
+        CommandVerb::Events => {
+            let (global, _) = load_runtime()?;
+            events::subscribe(&get_event_socket_dir(&global), |event| println!("{}", event))?;
+        },
+        CommandVerb::Top => {
+            let top_matches = matches.subcommand_matches("top").unwrap();
+            
+            show_top(*top_matches.get_one::<f64>("interval").unwrap(), top_matches.get_flag("once"))?;
+        },
+        CommandVerb::Snapshot => {
+            let snapshot_matches = matches.subcommand_matches("snapshot").unwrap();
+            
+            if let Some(name) = snapshot_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            snapshot_vm(snapshot_matches.get_one::<String>("dir"))?;
+        },
+        CommandVerb::SnapshotsList => {
+            let list_matches = matches.subcommand_matches("snapshots").unwrap().subcommand_matches("list").unwrap();
+            
+            if let Some(name) = list_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            list_snapshots(list_matches.get_flag("json"))?;
+        },
+        CommandVerb::SnapshotsDelete => {
+            let delete_matches = matches.subcommand_matches("snapshots").unwrap().subcommand_matches("delete").unwrap();
+            
+            if let Some(name) = delete_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            let names: Vec<String> = delete_matches.get_many::<String>("snapshot").unwrap().cloned().collect();
+            delete_snapshots(&names)?;
+        },
+        CommandVerb::SnapshotsPrune => {
+            let prune_matches = matches.subcommand_matches("snapshots").unwrap().subcommand_matches("prune").unwrap();
+            
+            if let Some(name) = prune_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            prune_snapshots()?;
+        },
+        CommandVerb::Pull => {
+            let pull_matches = matches.subcommand_matches("pull").unwrap();
+            
+            // Fetch the artifact into the image store
+            pull_image(pull_matches.get_one::<String>("reference").unwrap())?;
+        },
+        CommandVerb::BuildImage => {
+            let build_matches = matches.subcommand_matches("build-image").unwrap();
+            let filesystem = match build_matches.get_one::<String>("filesystem").unwrap().as_str() {
+                "erofs" => build::Filesystem::Erofs,
+                _ => build::Filesystem::Ext4,
+            };
+            
+            build_system_image(
+                build_matches.get_one::<String>("reference").unwrap(),
+                build_matches.get_one::<String>("name"),
+                filesystem,
+                build_matches.get_one::<String>("size"),
+            )?;
+        },
+        CommandVerb::ImagesList => {
+            list_images()?;
+        },
+        CommandVerb::ImagesPrune => {
+            prune_images()?;
+        },
+        CommandVerb::DevicesList => {
+            let list_matches = matches.subcommand_matches("devices").unwrap().subcommand_matches("list").unwrap();
+            
+            list_devices(list_matches.get_flag("json"))?;
+        },
+        CommandVerb::Prune => {
+            prune_stale_state(matches.subcommand_matches("prune").unwrap().get_flag("dry-run"))?;
+        },
+        CommandVerb::Console => {
+            let console_matches = matches.subcommand_matches("console").unwrap();
+            
+            // Per-runtime socket paths are derived from the runtime name
+            if let Some(name) = console_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            console::attach(&get_console_socket_path())?;
+        },
+        CommandVerb::Agent => {
+            run_agent_command(matches.subcommand_matches("agent").unwrap())?;
+        },
+        CommandVerb::Exec => {
+            let exec_matches = matches.subcommand_matches("exec").unwrap();
+            
+            if let Some(name) = exec_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            let command: Vec<String> = exec_matches.get_many::<String>("command").unwrap().cloned().collect();
+            
+            // Exit with the guest command's status so scripts and health checks can use it
+            let code = exec_in_guest(&command)?;
+            std::process::exit(code);
+        },
+        CommandVerb::Copy => {
+            let cp_matches = matches.subcommand_matches("cp").unwrap();
+            
+            copy_file(
+                cp_matches.get_one::<String>("source").unwrap(),
+                cp_matches.get_one::<String>("destination").unwrap(),
+            )?;
+        },
+        CommandVerb::Attest => {
+            let attest_matches = matches.subcommand_matches("attest").unwrap();
+            let name = attest_matches.get_one::<String>("name").unwrap();
+            env::set_var(RUNTIME_NAME_VAR, name);
+            
+            attest_guest(
+                name,
+                attest_matches.get_one::<String>("nonce"),
+                attest_matches.get_one::<String>("output"),
+            )?;
+        },
+        CommandVerb::Forward => {
+            let forward_matches = matches.subcommand_matches("forward").unwrap();
+            let name = forward_matches.get_one::<String>("name").unwrap();
+            env::set_var(RUNTIME_NAME_VAR, name);
+            
+            let (host_port, guest_port) = forward::parse_ports(forward_matches.get_one::<String>("ports").unwrap())?;
+            
+            // Bridged guests are reachable directly; otherwise relay through the agent over vsock
+            let guest_ip = forward_matches.get_one::<String>("guest-ip");
+            match forward_matches.get_one::<String>("mode").unwrap().as_str() {
+                "dnat" => forward::dnat(
+                    host_port,
+                    guest_ip.unwrap(),
+                    guest_port,
+                    &format!("vllmd-hypervisor {}", name),
+                    forward_matches.get_flag("remove"),
+                )?,
+                _ => {
+                    if guest_ip.is_some() {
+                        bail!("--guest-ip only applies to --mode dnat");
+                    }
+                    forward::proxy(
+                        &get_vsock_socket_path(),
+                        forward_matches.get_one::<String>("address").unwrap(),
+                        host_port,
+                        guest_port,
+                    )?
+                },
+            }
+        },
+        CommandVerb::Logs => {
+            let logs_matches = matches.subcommand_matches("logs").unwrap();
+            
+            if let Some(name) = logs_matches.get_one::<String>("name") {
+                env::set_var(RUNTIME_NAME_VAR, name);
+            }
+            
+            show_logs(
+                logs_matches.get_one::<String>("source").unwrap(),
+                logs_matches.get_flag("follow"),
+            )?;
+        },
     }
     
     Ok(())
@@ -0,0 +1,499 @@
+use anyhow::{Result, anyhow, bail, Context};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::hypervisor::{DiskConfig, MemoryZoneConfig, RateLimitGroupConfig, SgxEpcConfig};
+use crate::probe::ProbeConfig;
+use crate::balloon::BalloonPolicy;
+use crate::watchdog::{RebootPolicy, WatchdogAction};
+use crate::confidential::ConfidentialMode;
+use crate::trust::TrustPolicy;
+use crate::sched::VcpuSched;
+use crate::backend::BackendKind;
+use crate::webhook::WebhookConfig;
+use crate::devices::DeviceConfig;
+
+/// TOML runtime configuration file (see vllmd-hypervisor-config-schema.json)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Settings shared by all runtimes
+    #[serde(default)]
+    pub global: GlobalConfig,
+
+    /// Runtime definitions
+    #[serde(default)]
+    pub runtimes: Vec<RuntimeConfig>,
+
+    /// Shared runtime settings `[[runtimes]]` entries extend (`[templates.<name>]`); merged
+    /// into the runtimes when the file is loaded
+    #[serde(default)]
+    pub templates: toml::Table,
+
+    /// Host network settings
+    #[serde(default)]
+    pub network: NetworkConfig,
+}
+
+/// The `[global]` section
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GlobalConfig {
+    /// Username for systemd services
+    pub user: Option<String>,
+
+    /// Directory path for state data
+    pub state_dir: Option<String>,
+
+    /// Directory path for configuration files
+    pub config_dir: Option<String>,
+
+    /// Default memory allocation in GB
+    pub default_memory_gb: Option<u64>,
+
+    /// Default vCPU allocation
+    pub default_cpus: Option<u8>,
+
+    /// Kernel command line parameters shared by all runtimes
+    pub cmdline: Option<String>,
+
+    /// Signatures required on boot artifacts (`[global.trust]`)
+    pub trust: Option<TrustPolicy>,
+
+    /// Host directory of the sockets lifecycle events are sent to
+    pub event_socket_dir: Option<String>,
+
+    /// File lifecycle events are appended to as JSON lines
+    pub event_log: Option<String>,
+
+    /// Hash-chained log of privileged operations, `audit.jsonl` in the state directory by default
+    pub audit_log: Option<String>,
+
+    /// HTTP endpoints lifecycle events are POSTed to (`[[global.webhooks]]`)
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// Runtimes `start --all` boots at the same time
+    pub start_parallelism: Option<usize>,
+
+    /// Directory of the node_exporter textfile collector runtime metrics are written to
+    pub textfile_dir: Option<String>,
+
+    /// Seconds between two writes of the metrics file of a runtime
+    pub textfile_interval_secs: Option<u64>,
+}
+
+/// A `[[runtimes]]` entry
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Unique index (used in systemd service names)
+    pub index: u32,
+
+    /// Runtime name
+    pub name: String,
+
+    /// Template the settings of the entry are merged over
+    pub extends: Option<String>,
+
+    /// Free-form `key = "value"` labels, shown by `list` and matched by `--selector`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    /// GPU PCI addresses to pass through, or how many GPUs to allocate at start
+    #[serde(default)]
+    pub gpus: Gpus,
+
+    /// Requirements the allocated GPUs must meet, such as `vendor=nvidia,memory>=80G`
+    pub gpu_selector: Option<String>,
+
+    /// Reset the GPUs before boot and after shutdown
+    pub gpu_reset: Option<bool>,
+
+    /// Retries of a device check, reset or configuration that failed before start gives up
+    pub device_retries: Option<u32>,
+
+    /// Milliseconds before the first device retry, doubling for each one after
+    pub device_retry_delay_ms: Option<u64>,
+
+    /// Guest watchdog device and what happens once it resets the guest
+    pub watchdog: Option<WatchdogAction>,
+
+    /// What happens when the guest reboots itself: restart or stop
+    pub on_reboot: Option<RebootPolicy>,
+
+    /// Snapshots of the runtime to keep, the newest first
+    pub snapshot_keep: Option<usize>,
+
+    /// Age such as "7d" after which snapshots of the runtime are deleted
+    pub snapshot_max_age: Option<String>,
+
+    /// PCI devices other than GPUs to pass through (`[[runtimes.devices]]`)
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+
+    /// Memory allocation in GB
+    pub memory_gb: Option<u64>,
+
+    /// Number of vCPUs, or `{ boot, max }` to boot with fewer than the VM can be resized to
+    pub cpus: Option<Cpus>,
+
+    /// What the vCPUs show the guest, as in `max_phys_bits=46,features=amx`
+    pub cpu_config: Option<String>,
+
+    /// Path to kernel
+    pub kernel: Option<String>,
+
+    /// Path to system image
+    pub system_image: Option<String>,
+
+    /// Boot from a throwaway overlay of the system image
+    pub ephemeral: Option<bool>,
+
+    /// Report free guest pages to the host through a balloon device
+    pub free_page_reporting: Option<bool>,
+
+    /// Host entropy source of the guest virtio-rng device
+    pub rng: Option<String>,
+
+    /// Write the guest virtio console to a log file
+    pub guest_log: Option<bool>,
+
+    /// Guest CID of the vsock device
+    pub vsock_cid: Option<u32>,
+
+    /// Attach a vTPM backed by swtpm
+    pub tpm: Option<bool>,
+
+    /// Let the guest run its own VMs; needs nested virtualization in the host KVM module
+    pub nested: Option<bool>,
+
+    /// Confine the VMM with Landlock to the paths the runtime uses
+    pub landlock: Option<bool>,
+
+    /// Scheduling of the vCPU threads (`fifo:<priority>`, `rr:<priority>` or `nice:<value>`)
+    pub vcpu_sched: Option<VcpuSched>,
+
+    /// oom_score_adj of the hypervisor process (-1000 to 1000)
+    pub oom_score_adj: Option<i32>,
+
+    /// Hypervisor backend (`auto`, `kvm` or `mshv`)
+    pub backend: Option<BackendKind>,
+
+    /// Confidential computing mode; for SEV-SNP `kernel` is an IGVM image
+    pub confidential: Option<ConfidentialMode>,
+
+    /// Host data included in SEV-SNP attestation reports (64 hex digits)
+    pub host_data: Option<String>,
+
+    /// UEFI firmware to boot through (CLOUDHV.fd, rust-hypervisor-firmware, or TDVF for TDX)
+    pub firmware: Option<String>,
+
+    /// Initramfs loaded with the kernel
+    pub initramfs: Option<String>,
+
+    /// Detached signature of the kernel, next to it by default
+    pub kernel_signature: Option<String>,
+
+    /// Detached signature of the system image, next to it by default
+    pub system_image_signature: Option<String>,
+
+    /// Path to config image
+    pub config_image: Option<String>,
+
+    /// Kernel command line parameters, merged over the `[global]` ones
+    pub cmdline: Option<String>,
+
+    /// Additional disks (`[[runtimes.disks]]`)
+    #[serde(default)]
+    pub disks: Vec<DiskConfig>,
+
+    /// Rate limit groups shared by disks (`[[runtimes.rate_limit_groups]]`)
+    #[serde(default)]
+    pub rate_limit_groups: Vec<RateLimitGroupConfig>,
+
+    /// Rate limit group of the system and config images and of every disk without limits of its own
+    pub disk_rate_limit_group: Option<String>,
+
+    /// Virtio queues of network devices hotplugged with `add-net`; two per vCPU when unset
+    pub net_num_queues: Option<usize>,
+
+    /// Descriptors in each virtio queue of network devices hotplugged with `add-net`
+    pub net_queue_size: Option<u16>,
+
+    /// Zones guest memory is made of instead of `memory_gb` (`[[runtimes.memory_zones]]`)
+    #[serde(default)]
+    pub memory_zones: Vec<MemoryZoneConfig>,
+
+    /// SGX Enclave Page Cache sections of the guest (`[[runtimes.sgx_epc]]`)
+    #[serde(default)]
+    pub sgx_epc: Vec<SgxEpcConfig>,
+
+    /// Liveness check of the guest workload (`[runtimes.probe]`)
+    pub probe: Option<ProbeConfig>,
+
+    /// Balloon sizing that follows guest load (`[runtimes.balloon]`)
+    pub balloon: Option<BalloonPolicy>,
+
+    /// Runtimes `start --all` starts, and waits to be ready when they can report it, before this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+/// The `gpus` of a runtime
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Gpus {
+    /// PCI addresses of the GPUs, e.g. `["0000:41:00.0"]`
+    Addresses(Vec<String>),
+    /// Number of GPUs allocated from those no other runtime claims
+    Count(u32),
+}
+
+/// The `cpus` of a runtime
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Cpus {
+    /// Number of vCPUs, all of them online from boot
+    Count(u8),
+    /// vCPUs online at boot and the most `remote resize --vcpus` can bring online
+    Range(CpuRange),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuRange {
+    pub boot: u8,
+    pub max: u8,
+}
+
+impl Cpus {
+    pub fn boot(&self) -> u8 {
+        match self {
+            Cpus::Count(count) => *count,
+            Cpus::Range(range) => range.boot,
+        }
+    }
+
+    /// The maximum, None when it follows the vCPUs at boot
+    pub fn max(&self) -> Option<u8> {
+        match self {
+            Cpus::Count(_) => None,
+            Cpus::Range(range) => Some(range.max),
+        }
+    }
+}
+
+impl Default for Gpus {
+    fn default() -> Self {
+        Gpus::Addresses(Vec::new())
+    }
+}
+
+impl Gpus {
+    /// The PCI addresses; none when the GPUs are allocated at start
+    pub fn addresses(&self) -> &[String] {
+        match self {
+            Gpus::Addresses(addresses) => addresses,
+            Gpus::Count(_) => &[],
+        }
+    }
+}
+
+/// The `[network]` section
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Default host network interface
+    pub default_interface: Option<String>,
+
+    /// Name of the bridge interface
+    pub bridge_name: Option<String>,
+}
+
+impl Manifest {
+    /// Load and parse a TOML configuration file
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Failed to read configuration file: {}", path))?;
+
+        let mut document: toml::Table = toml::from_str(&contents)
+            .context(format!("Failed to parse configuration file: {}", path))?;
+        let manifest: Self = if apply_templates(&mut document).context(format!("Invalid configuration file: {}", path))? {
+            toml::Value::Table(document).try_into()
+        } else {
+            // Straight from the text, so errors point at their line
+            toml::from_str(&contents)
+        }.context(format!("Failed to parse configuration file: {}", path))?;
+        manifest.start_order()
+            .context(format!("Invalid configuration file: {}", path))?;
+        for runtime in &manifest.runtimes {
+            for key in runtime.labels.keys() {
+                check_label_key(key)
+                    .context(format!("Invalid label of runtime {} in {}", runtime.name, path))?;
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Runtimes in start order whose labels match `selector`, or all of them without one
+    pub fn select(&self, selector: Option<&Selector>) -> Result<Vec<&RuntimeConfig>> {
+        let mut runtimes = self.start_order()?;
+        if let Some(selector) = selector {
+            runtimes.retain(|runtime| selector.matches(&runtime.labels));
+        }
+        Ok(runtimes)
+    }
+
+    /// Runtimes in file order, except that each comes after the runtimes it depends on. Fails
+    /// when a runtime depends on an unknown runtime or the dependencies form a cycle.
+    pub fn start_order(&self) -> Result<Vec<&RuntimeConfig>> {
+        for runtime in &self.runtimes {
+            for dependency in &runtime.depends_on {
+                if dependency == &runtime.name {
+                    bail!("Runtime {} depends on itself", runtime.name);
+                }
+                self.runtime(dependency)
+                    .context(format!("Runtime {} depends on an unknown runtime", runtime.name))?;
+            }
+        }
+
+        let mut ordered: Vec<&RuntimeConfig> = Vec::new();
+        let mut remaining: Vec<&RuntimeConfig> = self.runtimes.iter().collect();
+        while !remaining.is_empty() {
+            let Some(position) = remaining.iter().position(|runtime| {
+                runtime.depends_on.iter().all(|dependency| ordered.iter().any(|done| &done.name == dependency))
+            }) else {
+                let names: Vec<&str> = remaining.iter().map(|runtime| runtime.name.as_str()).collect();
+                bail!("Runtimes {} cannot be ordered: their dependencies form a cycle", names.join(", "));
+            };
+            ordered.push(remaining.remove(position));
+        }
+        Ok(ordered)
+    }
+
+    /// Find a runtime definition by name
+    pub fn runtime(&self, name: &str) -> Result<&RuntimeConfig> {
+        self.runtimes.iter()
+            .find(|runtime| runtime.name == name)
+            .ok_or_else(|| anyhow!("Runtime {} is not defined in the configuration file", name))
+    }
+}
+
+/// A `--selector` of comma-separated `key=value` (or `key==value`) and `key!=value` requirements,
+/// all of which a runtime's labels must meet. A runtime without the label does not equal any value.
+#[derive(Debug, Clone, Default)]
+pub struct Selector {
+    requirements: Vec<(String, bool, String)>,
+}
+
+impl Selector {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut requirements = Vec::new();
+        for requirement in spec.split(',').map(str::trim).filter(|requirement| !requirement.is_empty()) {
+            let (key, equal, value) = if let Some((key, value)) = requirement.split_once("!=") {
+                (key, false, value)
+            } else if let Some((key, value)) = requirement.split_once("==").or_else(|| requirement.split_once('=')) {
+                (key, true, value)
+            } else {
+                bail!("Invalid selector requirement '{}': expected key=value or key!=value", requirement);
+            };
+            check_label_key(key.trim()).context(format!("Invalid selector requirement '{}'", requirement))?;
+            requirements.push((key.trim().to_string(), equal, value.trim().to_string()));
+        }
+        if requirements.is_empty() {
+            bail!("The selector is empty");
+        }
+        Ok(Self { requirements })
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter()
+            .all(|(key, equal, value)| (labels.get(key) == Some(value)) == *equal)
+    }
+}
+
+// Label keys follow the Prometheus label name rules, so labels can be exported as they are
+fn check_label_key(key: &str) -> Result<()> {
+    let valid = key.chars().next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !key.starts_with("__");
+    if !valid {
+        bail!("Label key '{}' must be letters, digits and underscores, not start with a digit or __", key);
+    }
+    Ok(())
+}
+
+// Merge the templates runtimes extend into them, returning whether any runtime extends one.
+// Settings of the runtime win over those of its template, which win over those of the template
+// it extends; tables such as `probe` merge key by key while lists are replaced as a whole.
+fn apply_templates(document: &mut toml::Table) -> Result<bool> {
+    let templates = match document.get("templates") {
+        Some(toml::Value::Table(templates)) => templates.clone(),
+        Some(_) => bail!("templates must be a table of templates"),
+        None => toml::Table::new(),
+    };
+    for (name, template) in &templates {
+        let Some(template) = template.as_table() else {
+            bail!("Template {} must be a table", name);
+        };
+        for key in ["index", "name"] {
+            if template.contains_key(key) {
+                bail!("Template {} cannot set {}; every runtime sets its own", name, key);
+            }
+        }
+    }
+
+    let mut extended = false;
+    let Some(toml::Value::Array(runtimes)) = document.get_mut("runtimes") else { return Ok(false) };
+    for runtime in runtimes.iter_mut() {
+        let Some(runtime) = runtime.as_table_mut() else { continue };
+        let Some(extends) = runtime.get("extends") else { continue };
+        let label = runtime.get("name").and_then(|name| name.as_str()).unwrap_or("without a name").to_string();
+        let Some(template) = extends.as_str() else {
+            bail!("extends of runtime {} must be the name of a template", label);
+        };
+        let mut merged = resolve_template(&templates, template, &mut Vec::new())
+            .context(format!("Runtime {} extends an invalid template", label))?;
+        merge(&mut merged, std::mem::take(runtime));
+        *runtime = merged;
+        extended = true;
+    }
+    Ok(extended)
+}
+
+// Settings of template `name`, merged over those of the templates it extends
+fn resolve_template(templates: &toml::Table, name: &str, chain: &mut Vec<String>) -> Result<toml::Table> {
+    if chain.iter().any(|seen| seen == name) {
+        chain.push(name.to_string());
+        bail!("Templates {} extend each other in a cycle", chain.join(" -> "));
+    }
+    let Some(template) = templates.get(name).and_then(|template| template.as_table()) else {
+        bail!("Template {} is not defined in the configuration file", name);
+    };
+    let mut template = template.clone();
+    chain.push(name.to_string());
+    let resolved = match template.remove("extends") {
+        Some(toml::Value::String(parent)) => {
+            let mut resolved = resolve_template(templates, &parent, chain)?;
+            merge(&mut resolved, template);
+            resolved
+        }
+        Some(_) => bail!("extends of template {} must be the name of a template", name),
+        None => template,
+    };
+    chain.pop();
+    Ok(resolved)
+}
+
+// Merge `overrides` over `base`: tables merge key by key, any other value replaces the base one
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
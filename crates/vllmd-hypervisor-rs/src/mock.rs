@@ -0,0 +1,490 @@
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+
+use crate::hypervisor::{BootTimings, HypervisorBackend, HypervisorError, HypervisorManager, VmConfig, VmExit, VmState};
+use crate::tpm::{Swtpm, SwtpmSlot};
+
+/// Backend that walks the VM state transitions without running a VMM, so the CLI and lifecycle
+/// logic can be exercised in containers without /dev/kvm. Configuration is validated exactly as
+/// for a real VM; booting succeeds at once and a guest with vsock reports ready right after.
+/// The VM has a thread per vCPU named like those of Cloud Hypervisor, and its API socket takes
+/// `vm.reboot`, which replaces them as a reboot inside the guest does, `vm.power-button`, after
+/// which the guest powers itself off, and `vm.resize`, which adds or removes vCPUs up to the
+/// maximum the VM booted with. A vTPM runs the swtpm found on PATH,
+/// and `VLLMD_MOCK_BOOT_DELAY_MS` holds up the boot, as a VMM that hangs would, until the boot
+/// timeout of the VM gives up on it.
+#[derive(Default)]
+pub struct MockBackend {
+    state: VmState,
+    config: Option<VmConfig>,
+    // Sender of the pending ready signal, fired by start()
+    ready_sender: RefCell<Option<Sender<()>>>,
+    // Error start() returns instead of booting
+    start_failure: Option<String>,
+    timings: BootTimings,
+    vcpus: Arc<Mutex<Vec<Vcpu>>>,
+    // Set by the API socket once the guest has powered off
+    powered_off: Arc<AtomicBool>,
+    swtpm: SwtpmSlot,
+}
+
+// Variable holding the milliseconds start() takes before booting
+const BOOT_DELAY_VAR: &str = "VLLMD_MOCK_BOOT_DELAY_MS";
+
+// Thread standing in for a vCPU; it runs until its sender is dropped
+struct Vcpu {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+// Replace the vCPU threads with `count` new ones
+fn replace_vcpus(vcpus: &mut Vec<Vcpu>, count: usize) {
+    resize_vcpus(vcpus, 0);
+    resize_vcpus(vcpus, count);
+}
+
+// Stop the highest vCPUs or start new ones until there are `count`. Each new thread is named
+// before this returns, so the host never sees a vCPU without its name.
+fn resize_vcpus(vcpus: &mut Vec<Vcpu>, count: usize) {
+    while vcpus.len() > count {
+        let vcpu = vcpus.pop().unwrap();
+        drop(vcpu.stop);
+        let _ = vcpu.thread.join();
+    }
+    for index in vcpus.len()..count {
+        let (stop, stopped) = channel::<()>();
+        let (started, running) = channel();
+        let thread = std::thread::Builder::new()
+            .name(format!("vcpu{}", index))
+            .spawn(move || {
+                let _ = started.send(());
+                let _ = stopped.recv();
+            })
+            .expect("Failed to spawn mock vCPU thread");
+        let _ = running.recv();
+        vcpus.push(Vcpu { stop, thread });
+    }
+}
+
+// Answer requests on the API socket until the process exits
+fn serve_api(listener: UnixListener, vcpus: Arc<Mutex<Vec<Vcpu>>>, powered_off: Arc<AtomicBool>, max_vcpus: u8) {
+    for stream in listener.incoming().flatten() {
+        if let Err(e) = answer(stream, &vcpus, &powered_off, max_vcpus) {
+            warn!("Mock API request failed: {}", e);
+        }
+    }
+}
+
+fn answer(mut stream: UnixStream, vcpus: &Mutex<Vec<Vcpu>>, powered_off: &AtomicBool, max_vcpus: u8) -> std::io::Result<()> {
+    // Read the head and as much body as Content-Length announces
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let (head, body_start, length) = loop {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..end]).into_owned();
+            let length = head.lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            break (head, end + 4, end + 4 + length);
+        }
+    };
+    while request.len() < length {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let path = head.split_whitespace().nth(1).unwrap_or_default();
+    let status = match path {
+        "/api/v1/vm.reboot" => {
+            let mut vcpus = vcpus.lock().unwrap();
+            let count = vcpus.len();
+            replace_vcpus(&mut vcpus, count);
+            info!("Mock VM reset by an API request");
+            "204 No Content"
+        },
+        "/api/v1/vm.power-button" => {
+            // The guest answers the ACPI power button by powering off
+            powered_off.store(true, Ordering::SeqCst);
+            info!("Mock VM powered off by its guest");
+            "204 No Content"
+        },
+        "/api/v1/vm.resize" => {
+            let resize = serde_json::from_slice::<serde_json::Value>(&request[body_start..]).unwrap_or_default();
+            // Only the vCPUs are simulated; a request without them changes nothing
+            match resize.get("desired_vcpus").filter(|desired| !desired.is_null()).map(|desired| desired.as_u64()) {
+                None => "204 No Content",
+                Some(Some(count)) if (1..=max_vcpus as u64).contains(&count) => {
+                    resize_vcpus(&mut vcpus.lock().unwrap(), count as usize);
+                    info!("Mock VM resized to {} vCPUs", count);
+                    "204 No Content"
+                },
+                _ => "400 Bad Request",
+            }
+        },
+        _ => "404 Not Found",
+    };
+    stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Backend whose start fails with `message`, as a VMM that cannot boot would
+    #[cfg(test)]
+    pub fn failing_start(message: &str) -> Self {
+        Self { start_failure: Some(message.to_string()), ..Self::default() }
+    }
+
+    // The scheduling policy applies to the fake vCPU threads like to real ones
+    fn apply_vcpu_sched(&self) {
+        if let Some(sched) = self.config.as_ref().and_then(|config| config.vcpu_sched) {
+            if let Err(e) = sched.apply() {
+                warn!("Failed to apply vCPU scheduling: {:#}", e);
+            }
+        }
+    }
+
+    fn require_vsock(&self) -> Result<()> {
+        match self.config.as_ref().and_then(|config| config.vsock.as_ref()) {
+            Some(_) => Ok(()),
+            None => Err(anyhow!(HypervisorError::ConfigError("vsock is not configured".to_string()))),
+        }
+    }
+}
+
+impl HypervisorBackend for MockBackend {
+    fn configure(&mut self, config: VmConfig) -> Result<()> {
+        if self.state != VmState::Created {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Created state to configure, current state: {:?}", self.state)
+            )));
+        }
+
+        HypervisorManager::validate_config(&config)?;
+
+        info!("Mock hypervisor configured for VM {}", config.id);
+        self.config = Some(config);
+        self.state = VmState::Configured;
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if self.state != VmState::Configured {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Configured state to start, current state: {:?}", self.state)
+            )));
+        }
+        if let Some(message) = &self.start_failure {
+            return Err(anyhow!(HypervisorError::StartError(message.clone())));
+        }
+
+        let config = self.config.as_ref().expect("configured VM has a configuration");
+        if let Some(tpm) = &config.tpm {
+            self.swtpm.set(Swtpm::spawn(&tpm.state_dir, &tpm.socket_path)?);
+        }
+        if let Some(delay) = std::env::var(BOOT_DELAY_VAR).ok().and_then(|delay| delay.parse().ok()) {
+            let delay = std::time::Duration::from_millis(delay);
+            // As the VMM is given up on, so is the mock
+            if let Some(timeout) = config.boot_timeout.filter(|timeout| *timeout < delay) {
+                std::thread::sleep(timeout);
+                return Err(anyhow!(HypervisorError::BootTimeout(timeout.as_secs())));
+            }
+            std::thread::sleep(delay);
+        }
+        // Like the VMM threads, the API socket and the vCPUs belong to the user
+        if let Some(run_as) = &config.run_as {
+            if let Some(sched) = config.vcpu_sched {
+                sched.allow_unprivileged()?;
+            }
+            run_as.drop_privileges()?;
+        }
+        if let Some(path) = &config.api_socket_path {
+            let listener = UnixListener::bind(path)
+                .map_err(|e| anyhow!(HypervisorError::StartError(format!("Failed to bind API socket {}: {}", path, e))))?;
+            let vcpus = self.vcpus.clone();
+            let powered_off = self.powered_off.clone();
+            let max_vcpus = config.max_vcpu_count;
+            std::thread::spawn(move || serve_api(listener, vcpus, powered_off, max_vcpus));
+        }
+        replace_vcpus(&mut self.vcpus.lock().unwrap(), config.vcpu_count as usize);
+
+        // Nothing to wait for, so every phase takes no time
+        self.timings = BootTimings { vmm_init_ms: Some(0), vm_create_ms: Some(0), vm_boot_ms: Some(0), guest_ready_ms: None };
+        self.state = VmState::Running;
+        info!("Mock VM booted");
+        self.apply_vcpu_sched();
+
+        if let Some(sender) = self.ready_sender.borrow_mut().take() {
+            let _ = sender.send(());
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        if !self.is_running() && self.state != VmState::Paused {
+            info!("No running VM to shut down");
+            self.swtpm.stop();
+            return Ok(());
+        }
+
+        replace_vcpus(&mut self.vcpus.lock().unwrap(), 0);
+        self.swtpm.stop();
+        self.state = VmState::Shutdown;
+        info!("Mock VM shut down");
+        Ok(())
+    }
+
+    fn reboot(&mut self) -> Result<()> {
+        if !self.is_running() {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Running state to reboot, current state: {:?}", self.state)
+            )));
+        }
+
+        let mut vcpus = self.vcpus.lock().unwrap();
+        let count = vcpus.len();
+        replace_vcpus(&mut vcpus, count);
+        drop(vcpus);
+        self.state = VmState::Running;
+        info!("Mock VM rebooted");
+        self.apply_vcpu_sched();
+        Ok(())
+    }
+
+    fn guest_reset(&mut self) {
+        if self.is_running() {
+            self.state = VmState::Running;
+            self.apply_vcpu_sched();
+        }
+    }
+
+    fn vcpus_resized(&mut self) {
+        if self.is_running() {
+            self.apply_vcpu_sched();
+        }
+    }
+
+    fn exited(&mut self) -> Option<VmExit> {
+        // The mock guest runs until the host stops it or it is told to power off
+        if self.is_running() && self.powered_off.swap(false, Ordering::SeqCst) {
+            return Some(VmExit::Poweroff);
+        }
+        None
+    }
+
+    fn suspend(&mut self, directory: &str) -> Result<()> {
+        if !self.is_running() {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Running state to suspend, current state: {:?}", self.state)
+            )));
+        }
+        if let Some(config) = self.config.as_ref() {
+            HypervisorManager::check_snapshottable(config)?;
+        }
+
+        info!("Mock VM suspended into {}", directory);
+        self.state = VmState::Paused;
+        Ok(())
+    }
+
+    fn resume_suspended(&mut self, ready: bool) -> Result<()> {
+        if self.state != VmState::Paused {
+            return Err(anyhow!(HypervisorError::InvalidState(
+                format!("VM must be in Paused state to resume, current state: {:?}", self.state)
+            )));
+        }
+
+        info!("Mock VM resumed after a failed handoff");
+        self.state = if ready { VmState::Ready } else { VmState::Running };
+        Ok(())
+    }
+
+    fn mark_ready(&mut self) {
+        if self.state == VmState::Running {
+            self.state = VmState::Ready;
+        }
+    }
+
+    fn ready_signal(&self) -> Result<Receiver<()>> {
+        self.require_vsock()?;
+
+        let (sender, receiver) = channel();
+        *self.ready_sender.borrow_mut() = Some(sender);
+        Ok(receiver)
+    }
+
+    fn quote_relay(&self, _qgs_socket_path: &str) -> Result<()> {
+        self.require_vsock()
+    }
+
+    fn state(&self) -> VmState {
+        self.state
+    }
+
+    fn boot_timings(&self) -> BootTimings {
+        self.timings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{parse_memory_string, CpuConfig, VsockConfig};
+    use seccompiler::SeccompAction;
+    use std::path::PathBuf;
+
+    // Directory with an empty kernel, system image and config image, unique to the calling test
+    fn artifacts() -> PathBuf {
+        let dir = crate::scratch::dir("vllmd-mock");
+        std::fs::write(dir.join("vmlinux"), b"").unwrap();
+        std::fs::write(dir.join("system.raw"), b"").unwrap();
+        std::fs::write(dir.join("config.raw"), b"").unwrap();
+        dir
+    }
+
+    fn config(dir: &std::path::Path) -> VmConfig {
+        VmConfig {
+            id: "mock".to_string(),
+            kernel_path: Some(dir.join("vmlinux").to_string_lossy().into_owned()),
+            cmdline: String::new(),
+            system_image_path: dir.join("system.raw").to_string_lossy().into_owned(),
+            config_image_path: dir.join("config.raw").to_string_lossy().into_owned(),
+            vcpu_count: 1,
+            max_vcpu_count: 1,
+            cpu_config: CpuConfig::default(),
+            memory_config: parse_memory_string("size=1G").unwrap(),
+            memory_zones: Vec::new(),
+            sgx_epc: Vec::new(),
+            oem_strings: Vec::new(),
+            restore_path: None,
+            device_paths: Vec::new(),
+            disks: Vec::new(),
+            rate_limit_groups: Vec::new(),
+            image_rate_limit_group: None,
+            debug: false,
+            api_socket_path: None,
+            ephemeral_overlay_path: None,
+            free_page_reporting: false,
+            rng_source: "/dev/urandom".to_string(),
+            balloon: false,
+            serial_socket_path: None,
+            console_log_path: None,
+            vsock: None,
+            tpm: None,
+            confidential: None,
+            host_data: None,
+            firmware_path: None,
+            initramfs_path: None,
+            seccomp: SeccompAction::Trap,
+            landlock: false,
+            landlock_paths: Vec::new(),
+            vcpu_sched: None,
+            run_as: None,
+            boot_timeout: None,
+            watchdog: false,
+            backend: crate::backend::BackendKind::Mock,
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let dir = artifacts();
+        let mut backend = MockBackend::new();
+        assert_eq!(backend.state(), VmState::Created);
+
+        backend.configure(config(&dir)).unwrap();
+        assert_eq!(backend.state(), VmState::Configured);
+        assert!(!backend.is_running());
+
+        backend.start().unwrap();
+        assert!(backend.is_running());
+        assert!(!backend.is_ready());
+
+        backend.mark_ready();
+        assert!(backend.is_ready());
+
+        // A rebooted guest has to report ready again
+        backend.reboot().unwrap();
+        assert_eq!(backend.state(), VmState::Running);
+
+        backend.shutdown().unwrap();
+        assert_eq!(backend.state(), VmState::Shutdown);
+        assert!(backend.reboot().is_err());
+    }
+
+    #[test]
+    fn transitions_out_of_order_fail() {
+        let dir = artifacts();
+        let mut backend = MockBackend::new();
+        assert!(backend.start().is_err());
+        assert!(backend.reboot().is_err());
+
+        // Ready only follows a running guest
+        backend.mark_ready();
+        assert_eq!(backend.state(), VmState::Created);
+
+        backend.configure(config(&dir)).unwrap();
+        assert!(backend.configure(config(&dir)).is_err());
+    }
+
+    #[test]
+    fn configure_validates() {
+        let dir = artifacts();
+        let mut vm_config = config(&dir);
+        vm_config.kernel_path = Some(dir.join("missing").to_string_lossy().into_owned());
+
+        let mut backend = MockBackend::new();
+        let error = backend.configure(vm_config).unwrap_err();
+        assert!(error.to_string().contains("Kernel path does not exist"), "{}", error);
+        assert_eq!(backend.state(), VmState::Created);
+    }
+
+    #[test]
+    fn failed_start_shuts_down_cleanly() {
+        let dir = artifacts();
+        let mut backend = MockBackend::failing_start("no /dev/kvm");
+        backend.configure(config(&dir)).unwrap();
+
+        assert!(backend.start().unwrap_err().to_string().contains("no /dev/kvm"));
+        assert_eq!(backend.state(), VmState::Configured);
+        backend.shutdown().unwrap();
+    }
+
+    #[test]
+    fn ready_signal_needs_vsock() {
+        let dir = artifacts();
+        let mut backend = MockBackend::new();
+        backend.configure(config(&dir)).unwrap();
+        assert!(backend.ready_signal().is_err());
+        assert!(backend.quote_relay("/run/qgs.sock").is_err());
+
+        let mut vm_config = config(&dir);
+        vm_config.vsock = Some(VsockConfig { cid: 3, socket_path: dir.join("vsock.sock").to_string_lossy().into_owned() });
+        let mut backend = MockBackend::new();
+        backend.configure(vm_config).unwrap();
+
+        let receiver = backend.ready_signal().unwrap();
+        assert!(receiver.try_recv().is_err());
+        backend.start().unwrap();
+        assert!(receiver.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{Context, Result, bail};
+use log::{error, info, warn};
+use std::path::Path;
+use std::process::Command;
+
+/// KVM vendor modules; the VMM hands the guest the VMX or SVM of the host CPU when the loaded one
+/// has nested virtualization enabled
+const MODULES: [&str; 2] = ["kvm_intel", "kvm_amd"];
+
+/// Where the kernel lists loaded modules and their parameters
+const SYS_MODULE: &str = "/sys/module";
+
+/// The KVM vendor module the host has loaded
+pub fn module() -> Option<&'static str> {
+    module_in(Path::new(SYS_MODULE))
+}
+
+fn module_in(root: &Path) -> Option<&'static str> {
+    MODULES.into_iter().find(|module| root.join(module).exists())
+}
+
+fn enabled_in(root: &Path, module: &str) -> bool {
+    std::fs::read_to_string(root.join(module).join("parameters/nested"))
+        .map(|value| matches!(value.trim(), "Y" | "1"))
+        .unwrap_or(false)
+}
+
+// VMs that hold the module open; it can only be reloaded once there are none
+fn users_in(root: &Path, module: &str) -> Option<u32> {
+    std::fs::read_to_string(root.join(module).join("refcnt")).ok()
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Host support for nested virtualization, in the same form as `confidential::tdx_host_support`
+pub fn host_support() -> Vec<(String, bool)> {
+    host_support_in(Path::new(SYS_MODULE))
+}
+
+fn host_support_in(root: &Path) -> Vec<(String, bool)> {
+    match module_in(root) {
+        Some(module) if enabled_in(root, module) => vec![(format!("{} has nested virtualization enabled", module), true)],
+        Some(module) => vec![(format!("{} has nested virtualization enabled (set `options {} nested=1` in /etc/modprobe.d \
+                                       and reload {}, or run `vllmd-hypervisor enable-nested`, while no VM runs)", module, module, module), false)],
+        None => vec![("kvm_intel or kvm_amd is loaded for nested virtualization".to_string(), false)],
+    }
+}
+
+/// Turn nested virtualization on, for `enable-nested`. The parameter is read-only once the module
+/// is loaded, so this reloads it, which the kernel refuses while any VM on the host holds it. Only
+/// the host module changes: the guest CPU is configured as without nesting, and gets VMX or SVM
+/// because KVM then reports it as supported.
+pub fn enable() -> Result<()> {
+    enable_in(Path::new(SYS_MODULE), modprobe)
+}
+
+fn enable_in(root: &Path, mut modprobe: impl FnMut(&[&str]) -> Result<()>) -> Result<()> {
+    let Some(module) = module_in(root) else {
+        bail!("Nested virtualization needs kvm_intel or kvm_amd, and neither is loaded");
+    };
+    if enabled_in(root, module) {
+        return Ok(());
+    }
+    if let Some(users) = users_in(root, module).filter(|users| *users > 0) {
+        bail!("{} has nested virtualization disabled and {} VM(s) hold it, so it cannot be reloaded; \
+               set `options {} nested=1` in /etc/modprobe.d and reload it once they stop", module, users, module);
+    }
+
+    warn!("Reloading {} with nested=1 to enable nested virtualization", module);
+    modprobe(&["-r", module])?;
+    if let Err(e) = modprobe(&[module, "nested=1"]) {
+        // Without the module no VM on the host can start, so load it back as it was
+        if let Err(restore) = modprobe(&[module]) {
+            error!("Failed to load {} again after enabling nested virtualization failed: {:#}", module, restore);
+        }
+        return Err(e);
+    }
+    if !enabled_in(root, module) {
+        bail!("{} still has nested virtualization disabled after reloading it with nested=1", module);
+    }
+    info!("Nested virtualization enabled in {}", module);
+    Ok(())
+}
+
+fn modprobe(args: &[&str]) -> Result<()> {
+    let output = Command::new("modprobe").args(args).output().context("Failed to run modprobe")?;
+    if !output.status.success() {
+        bail!("modprobe {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::path::PathBuf;
+
+    // sysfs module directory with `module` loaded, unique to the calling test
+    fn sys_module(module: &str, nested: &str, refcnt: &str) -> PathBuf {
+        let root = crate::scratch::dir("vllmd-nested");
+        std::fs::create_dir_all(root.join(module).join("parameters")).unwrap();
+        std::fs::create_dir_all(root.join("kvm")).unwrap();
+        std::fs::write(root.join(module).join("parameters/nested"), nested).unwrap();
+        std::fs::write(root.join(module).join("refcnt"), refcnt).unwrap();
+        root
+    }
+
+    #[test]
+    fn module_parameter() {
+        let root = sys_module("kvm_amd", "1\n", "0\n");
+        assert_eq!(module_in(&root), Some("kvm_amd"));
+        assert!(enabled_in(&root, "kvm_amd"));
+        assert_eq!(host_support_in(&root), [("kvm_amd has nested virtualization enabled".to_string(), true)]);
+
+        let root = sys_module("kvm_intel", "N\n", "2\n");
+        assert_eq!(module_in(&root), Some("kvm_intel"));
+        assert!(!enabled_in(&root, "kvm_intel"));
+        assert_eq!(users_in(&root, "kvm_intel"), Some(2));
+        let support = host_support_in(&root);
+        assert!(!support[0].1 && support[0].0.contains("options kvm_intel nested=1"), "{:?}", support);
+
+        std::fs::remove_dir_all(root.join("kvm_intel")).unwrap();
+        assert_eq!(module_in(&root), None);
+        assert!(!host_support_in(&root)[0].1);
+    }
+
+    #[test]
+    fn module_in_use_is_not_reloaded() {
+        let root = sys_module("kvm_intel", "N\n", "1\n");
+        let mut calls = Vec::new();
+        let error = enable_in(&root, |args| { calls.push(args.join(" ")); Ok(()) }).unwrap_err();
+        assert!(error.to_string().contains("1 VM(s) hold it"), "{}", error);
+        assert!(calls.is_empty(), "{:?}", calls);
+
+        // Nothing to do when it is on already
+        let root = sys_module("kvm_intel", "Y\n", "1\n");
+        enable_in(&root, |args| panic!("modprobe {:?}", args)).unwrap();
+    }
+
+    #[test]
+    fn reload_sets_the_parameter() {
+        let root = sys_module("kvm_intel", "N\n", "0\n");
+        let mut calls = Vec::new();
+        enable_in(&root, |args| {
+            calls.push(args.join(" "));
+            if args == ["kvm_intel", "nested=1"] {
+                std::fs::write(root.join("kvm_intel/parameters/nested"), "Y\n").unwrap();
+            }
+            Ok(())
+        }).unwrap();
+        assert_eq!(calls, ["-r kvm_intel", "kvm_intel nested=1"]);
+    }
+
+    #[test]
+    fn failed_reload_loads_the_module_again() {
+        let root = sys_module("kvm_amd", "0\n", "0\n");
+        let mut calls = Vec::new();
+        let error = enable_in(&root, |args| {
+            calls.push(args.join(" "));
+            match args {
+                ["kvm_amd", "nested=1"] => Err(anyhow!("modprobe kvm_amd nested=1 failed")),
+                _ => Ok(()),
+            }
+        }).unwrap_err();
+        assert!(error.to_string().contains("nested=1 failed"), "{}", error);
+        assert_eq!(calls, ["-r kvm_amd", "kvm_amd nested=1", "kvm_amd"]);
+    }
+}
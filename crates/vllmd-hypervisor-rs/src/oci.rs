@@ -0,0 +1,379 @@
+use anyhow::{Result, anyhow, bail, Context};
+use log::info;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+
+use crate::images::ImageStore;
+
+// Manifest media types accepted from registries
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json";
+
+// Layer annotation carrying the file name (set by `oras push`)
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// A parsed `oci://registry/repository[:tag|@digest][#title]` reference
+#[derive(Debug, Clone)]
+pub struct OciReference {
+    /// Registry host, optionally with a port
+    pub registry: String,
+
+    /// Repository path within the registry
+    pub repository: String,
+
+    /// Tag or "sha256:..." manifest digest
+    pub reference: String,
+
+    /// Title of the layer to select when the artifact has several
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    media_type: Option<String>,
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    config: ContainerConfig,
+}
+
+/// How a container image runs its process, from the `config` object of its image configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    /// `KEY=value` environment entries
+    pub env: Option<Vec<String>>,
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+}
+
+/// A container image pulled into the image store
+#[derive(Debug, Clone)]
+pub struct ContainerImage {
+    /// Blob paths of the filesystem layers, lowest first
+    pub layers: Vec<PathBuf>,
+    pub config: ContainerConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+impl OciReference {
+    /// Parse an `oci://` reference; the tag defaults to "latest"
+    pub fn parse(location: &str) -> Result<Self> {
+        let rest = location.strip_prefix("oci://")
+            .ok_or_else(|| anyhow!("OCI reference must start with oci://: {}", location))?;
+
+        let (rest, title) = match rest.split_once('#') {
+            Some((rest, title)) if !title.is_empty() => (rest, Some(title.to_string())),
+            Some(_) => bail!("Empty layer title in OCI reference: {}", location),
+            None => (rest, None),
+        };
+
+        let (registry, path) = rest.split_once('/')
+            .ok_or_else(|| anyhow!("OCI reference has no repository: {}", location))?;
+
+        // A ':' after the last '/' separates the tag; '@' separates a digest
+        let (repository, reference) = if let Some((repository, digest)) = path.split_once('@') {
+            (repository, digest.to_string())
+        } else {
+            match path.rsplit_once(':') {
+                Some((repository, tag)) if !tag.contains('/') => (repository, tag.to_string()),
+                _ => (path, "latest".to_string()),
+            }
+        };
+
+        if registry.is_empty() || repository.is_empty() || reference.is_empty() {
+            bail!("Invalid OCI reference: {}", location);
+        }
+
+        Ok(Self {
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+            reference,
+            title,
+        })
+    }
+
+    fn url(&self, kind: &str, reference: &str) -> String {
+        format!("https://{}/v2/{}/{}/{}", self.registry, self.repository, kind, reference)
+    }
+}
+
+impl std::fmt::Display for OciReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if self.reference.starts_with("sha256:") { '@' } else { ':' };
+        write!(f, "oci://{}/{}{}{}", self.registry, self.repository, separator, self.reference)?;
+        if let Some(title) = &self.title {
+            write!(f, "#{}", title)?;
+        }
+        Ok(())
+    }
+}
+
+/// Registry client holding the bearer token for one repository
+struct Registry {
+    agent: ureq::Agent,
+    token: Option<String>,
+}
+
+impl Registry {
+    fn new() -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().build(),
+            token: None,
+        }
+    }
+
+    /// GET a registry URL, performing the anonymous token handshake on a 401 challenge
+    fn get(&mut self, url: &str, accept: Option<&str>) -> Result<ureq::Response> {
+        for _ in 0..2 {
+            let mut request = self.agent.get(url);
+            if let Some(accept) = accept {
+                request = request.set("Accept", accept);
+            }
+            if let Some(token) = &self.token {
+                request = request.set("Authorization", &format!("Bearer {}", token));
+            }
+
+            match request.call() {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(401, response)) if self.token.is_none() => {
+                    let challenge = response.header("www-authenticate")
+                        .ok_or_else(|| anyhow!("Registry returned 401 without a challenge for {}", url))?
+                        .to_string();
+                    self.token = Some(self.fetch_token(&challenge)?);
+                },
+                Err(ureq::Error::Status(code, _)) => bail!("Registry request {} failed with status {}", url, code),
+                Err(e) => return Err(anyhow!("Registry request {} failed: {}", url, e)),
+            }
+        }
+
+        bail!("Registry denied access to {}", url)
+    }
+
+    /// Request an anonymous pull token as described by a `Bearer realm=...` challenge
+    fn fetch_token(&self, challenge: &str) -> Result<String> {
+        let params = challenge.strip_prefix("Bearer ")
+            .ok_or_else(|| anyhow!("Unsupported registry authentication: {}", challenge))?;
+
+        let mut realm = None;
+        let mut query = Vec::new();
+        for param in params.split(',') {
+            let Some((key, value)) = param.trim().split_once('=') else { continue };
+            let value = value.trim_matches('"');
+            match key {
+                "realm" => realm = Some(value),
+                "service" | "scope" => query.push((key, value)),
+                _ => {}
+            }
+        }
+
+        let realm = realm.ok_or_else(|| anyhow!("Registry challenge has no realm: {}", challenge))?;
+        let mut request = self.agent.get(realm);
+        for (key, value) in query {
+            request = request.query(key, value);
+        }
+
+        let body = request.call()
+            .map_err(|e| anyhow!("Failed to obtain registry token from {}: {}", realm, e))?
+            .into_string()
+            .context("Failed to read registry token response")?;
+        let response: TokenResponse = serde_json::from_str(&body)
+            .context("Failed to parse registry token response")?;
+
+        response.token.or(response.access_token)
+            .ok_or_else(|| anyhow!("Registry token response from {} contains no token", realm))
+    }
+
+    fn manifest(&mut self, image: &OciReference, reference: &str) -> Result<Manifest> {
+        let body = self.get(&image.url("manifests", reference), Some(MANIFEST_ACCEPT))?
+            .into_string()
+            .context(format!("Failed to read manifest for {}", image))?;
+        check_digest(&body, reference).context(format!("Refusing the manifest of {}", image))?;
+
+        serde_json::from_str(&body)
+            .context(format!("Failed to parse manifest for {}", image))
+    }
+}
+
+/// Pull an OCI artifact layer into the image store and return its blob path
+pub fn pull(store: &ImageStore, image: &OciReference) -> Result<PathBuf> {
+    let mut registry = Registry::new();
+    let manifest = platform_manifest(&mut registry, image)?;
+
+    let layer = match &image.title {
+        Some(title) => manifest.layers.iter()
+            .find(|layer| layer.annotations.get(TITLE_ANNOTATION) == Some(title))
+            .ok_or_else(|| anyhow!("{} has no layer titled {}", image, title))?,
+        None if manifest.layers.len() == 1 => &manifest.layers[0],
+        None => {
+            let titles: Vec<&str> = manifest.layers.iter()
+                .filter_map(|layer| layer.annotations.get(TITLE_ANNOTATION).map(String::as_str))
+                .collect();
+            bail!("{} has {} layers, select one with #<title> (available: {})",
+                image, manifest.layers.len(), titles.join(", "));
+        },
+    };
+
+    let blob = fetch_blob(&mut registry, store, image, &layer.digest)?;
+    store.tag(&image.to_string(), &layer.digest, None)?;
+    Ok(blob)
+}
+
+/// Pull the layers and configuration of a container image into the image store. The layers are
+/// not tagged, so `images prune` removes them once they are no longer needed.
+pub fn pull_container(store: &ImageStore, image: &OciReference) -> Result<ContainerImage> {
+    let mut registry = Registry::new();
+    let manifest = platform_manifest(&mut registry, image)?;
+
+    let config = manifest.config
+        .ok_or_else(|| anyhow!("{} is not a container image: its manifest has no configuration", image))?;
+    let config = fetch_blob(&mut registry, store, image, &config.digest)?;
+    let config: ImageConfig = serde_json::from_str(&std::fs::read_to_string(&config)?)
+        .context(format!("Failed to parse the image configuration of {}", image))?;
+
+    let mut layers = Vec::new();
+    for layer in &manifest.layers {
+        layers.push(fetch_blob(&mut registry, store, image, &layer.digest)?);
+    }
+    Ok(ContainerImage { layers, config: config.config })
+}
+
+// Manifest of the reference; image indexes list per-platform manifests, of which the one
+// matching this host is picked
+fn platform_manifest(registry: &mut Registry, image: &OciReference) -> Result<Manifest> {
+    let mut manifest = registry.manifest(image, &image.reference)?;
+
+    if !manifest.manifests.is_empty() || manifest.media_type.as_deref().is_some_and(|t| t.contains("index") || t.contains("list")) {
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            other => other,
+        };
+        let digest = select_platform(&manifest.manifests, arch)
+            .context(format!("Image index for {}", image))?
+            .digest.clone();
+        manifest = registry.manifest(image, &digest)?;
+    }
+    Ok(manifest)
+}
+
+// Entry of an image index for linux/`arch`. An index of a single manifest without a platform, as
+// for artifacts, has nothing to choose from; otherwise a guest cannot run another architecture.
+fn select_platform<'a>(manifests: &'a [Descriptor], arch: &str) -> Result<&'a Descriptor> {
+    if let Some(manifest) = manifests.iter().find(|m| m.platform.as_ref().is_some_and(|p| p.os == "linux" && p.architecture == arch)) {
+        return Ok(manifest);
+    }
+    match manifests {
+        [] => bail!("lists no manifests"),
+        [manifest] if manifest.platform.is_none() => Ok(manifest),
+        _ => {
+            let platforms: Vec<String> = manifests.iter()
+                .map(|m| m.platform.as_ref().map_or("unknown".to_string(), |p| format!("{}/{}", p.os, p.architecture)))
+                .collect();
+            bail!("has no manifest for linux/{} (available: {})", arch, platforms.join(", "))
+        },
+    }
+}
+
+// A manifest pulled by digest must hash to it, or the registry served something else
+fn check_digest(body: &str, reference: &str) -> Result<()> {
+    let Some(expected) = reference.strip_prefix("sha256:") else {
+        return Ok(());
+    };
+    let actual = format!("{:x}", Sha256::digest(body.as_bytes()));
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!("its digest is sha256:{}, not the {} it was pulled by", actual, reference);
+    }
+    Ok(())
+}
+
+// Blob path of `digest`, downloaded unless the store has it
+fn fetch_blob(registry: &mut Registry, store: &ImageStore, image: &OciReference, digest: &str) -> Result<PathBuf> {
+    let blob = store.blob_path(digest)?;
+    if !blob.exists() {
+        info!("Downloading {} ({})", image, digest);
+
+        let download = store.download_path(digest.trim_start_matches("sha256:"));
+        let mut reader = registry.get(&image.url("blobs", digest), None)?.into_reader();
+        let mut file = File::create(&download)
+            .context(format!("Failed to create {}", download.display()))?;
+        std::io::copy(&mut reader, &mut file)
+            .context(format!("Failed to download {}", digest))?;
+
+        store.commit(&download, Some(digest))?;
+    }
+    Ok(blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(digest: &str, platform: Option<(&str, &str)>) -> Descriptor {
+        Descriptor {
+            digest: digest.to_string(),
+            annotations: HashMap::new(),
+            platform: platform.map(|(os, architecture)| Platform { os: os.to_string(), architecture: architecture.to_string() }),
+        }
+    }
+
+    #[test]
+    fn platform_of_the_host_is_selected() {
+        let index = [descriptor("sha256:a", Some(("linux", "arm64"))), descriptor("sha256:b", Some(("linux", "amd64")))];
+        assert_eq!(select_platform(&index, "amd64").unwrap().digest, "sha256:b");
+
+        let error = select_platform(&index[..1], "amd64").unwrap_err();
+        assert_eq!(error.to_string(), "has no manifest for linux/amd64 (available: linux/arm64)");
+        let index = [descriptor("sha256:a", Some(("windows", "amd64"))), descriptor("sha256:b", None)];
+        assert!(select_platform(&index, "amd64").unwrap_err().to_string().ends_with("(available: windows/amd64, unknown)"));
+
+        // An artifact index has one manifest and no platform
+        assert_eq!(select_platform(&[descriptor("sha256:c", None)], "amd64").unwrap().digest, "sha256:c");
+        assert!(select_platform(&[], "amd64").is_err());
+    }
+
+    #[test]
+    fn manifest_digest_is_checked() {
+        let body = r#"{"schemaVersion":2}"#;
+        let digest = format!("sha256:{:x}", Sha256::digest(body.as_bytes()));
+        check_digest(body, &digest).unwrap();
+        check_digest(body, "latest").unwrap();
+
+        let error = check_digest(r#"{"schemaVersion":3}"#, &digest).unwrap_err();
+        assert!(error.to_string().ends_with(&format!("not the {} it was pulled by", digest)), "{}", error);
+    }
+}
@@ -0,0 +1,71 @@
+use log::warn;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::drift::HOST_ONLY_FIELDS;
+
+/// SMBIOS OEM strings that tell software in the guest, such as cloud-init, which runtime it is
+/// without networking: its name, the VM UUID, its labels and a hash of the configuration it
+/// booted with. The VMM takes them as a list, so strings with `,`, `[`, `]` or `"` are left out.
+pub fn strings(name: Option<&str>, vm_id: &str, labels: &BTreeMap<String, String>, configuration: &Value) -> Vec<String> {
+    let mut strings = Vec::new();
+    if let Some(name) = name {
+        strings.push(format!("vllmd:name={}", name));
+    }
+    strings.push(format!("vllmd:uuid={}", vm_id));
+    strings.extend(labels.iter().map(|(key, value)| format!("vllmd:label.{}={}", key, value)));
+    strings.push(format!("vllmd:config_sha256={}", configuration_hash(configuration)));
+    strings.retain(|string| {
+        let listable = !string.contains([',', '[', ']', '"']);
+        if !listable {
+            warn!("Leaving out OEM string {}, which the VMM cannot take", string);
+        }
+        listable
+    });
+    strings
+}
+
+/// SHA-256 of the resolved configuration, without the host-only fields as those are not part of
+/// the VM
+pub fn configuration_hash(configuration: &Value) -> String {
+    use sha2::{Digest, Sha256};
+    let mut configuration = configuration.clone();
+    if let Some(fields) = configuration.as_object_mut() {
+        for field in HOST_ONLY_FIELDS {
+            fields.remove(*field);
+        }
+    }
+    format!("{:x}", Sha256::digest(configuration.to_string().as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_name_the_runtime_and_leave_out_unlistable_ones() {
+        let labels = BTreeMap::from([
+            ("model".to_string(), "llama-70b".to_string()),
+            ("team".to_string(), "a,b".to_string()),
+        ]);
+        let configuration = serde_json::json!({ "cpu_count": 8 });
+        let listed = strings(Some("llama"), "5f0c", &labels, &configuration);
+        assert_eq!(listed, [
+            "vllmd:name=llama".to_string(),
+            "vllmd:uuid=5f0c".to_string(),
+            "vllmd:label.model=llama-70b".to_string(),
+            format!("vllmd:config_sha256={}", configuration_hash(&configuration)),
+        ]);
+        assert_eq!(strings(None, "5f0c", &BTreeMap::new(), &configuration)[0], "vllmd:uuid=5f0c");
+    }
+
+    #[test]
+    fn configuration_hash_ignores_host_only_fields() {
+        let configuration = serde_json::json!({ "cpu_count": 8, "labels": { "model": "llama" }, "log_filepath": "/var/log/a.log" });
+        let hash = configuration_hash(&configuration);
+        assert_eq!(hash.len(), 64);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hash, configuration_hash(&serde_json::json!({ "cpu_count": 8 })));
+        assert_ne!(hash, configuration_hash(&serde_json::json!({ "cpu_count": 12 })));
+    }
+}
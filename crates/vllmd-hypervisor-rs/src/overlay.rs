@@ -0,0 +1,172 @@
+use anyhow::{Result, anyhow, bail, Context};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// qcow2 layout constants
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const QCOW_VERSION: u32 = 3;
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+const REFCOUNT_ORDER: u32 = 4; // 16-bit refcounts
+const HEADER_LENGTH: u32 = 104;
+const BACKING_FORMAT_EXTENSION: u32 = 0xe279_2aca;
+
+/// Create a qcow2 overlay at `overlay_path` whose backing file is the raw image at `base_path`.
+///
+/// The base image is never written: all guest writes land in the overlay, so a single golden
+/// image can back many runtimes.
+pub fn create_qcow2_overlay(base_path: &str, overlay_path: &str) -> Result<()> {
+    let base = std::fs::canonicalize(base_path)
+        .context(format!("Failed to resolve base image: {}", base_path))?;
+    let base_name = base.to_str()
+        .ok_or_else(|| anyhow!("Base image path is not valid UTF-8: {}", base.display()))?;
+
+    let virtual_size = image_size(&base)?;
+
+    // Cluster 0 holds the header, cluster 1 the refcount table, cluster 2 the first
+    // refcount block, followed by the L1 table
+    let l2_entries = CLUSTER_SIZE / 8;
+    let l1_size = virtual_size.div_ceil(CLUSTER_SIZE * l2_entries);
+    let l1_clusters = (l1_size * 8).div_ceil(CLUSTER_SIZE).max(1);
+    let refcount_table_offset = CLUSTER_SIZE;
+    let refcount_block_offset = 2 * CLUSTER_SIZE;
+    let l1_table_offset = 3 * CLUSTER_SIZE;
+    let total_clusters = 3 + l1_clusters;
+
+    // Header extensions: backing format, then the end marker
+    let mut extensions = Vec::new();
+    extensions.extend_from_slice(&BACKING_FORMAT_EXTENSION.to_be_bytes());
+    extensions.extend_from_slice(&3u32.to_be_bytes());
+    extensions.extend_from_slice(b"raw\0\0\0\0\0");
+    extensions.extend_from_slice(&[0u8; 8]);
+
+    let backing_file_offset = HEADER_LENGTH as u64 + extensions.len() as u64;
+    if backing_file_offset + base_name.len() as u64 > CLUSTER_SIZE {
+        bail!("Base image path is too long for a qcow2 header: {}", base_name);
+    }
+
+    let mut image = vec![0u8; (total_clusters * CLUSTER_SIZE) as usize];
+
+    let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+    header.extend_from_slice(&QCOW_MAGIC.to_be_bytes());
+    header.extend_from_slice(&QCOW_VERSION.to_be_bytes());
+    header.extend_from_slice(&backing_file_offset.to_be_bytes());
+    header.extend_from_slice(&(base_name.len() as u32).to_be_bytes());
+    header.extend_from_slice(&CLUSTER_BITS.to_be_bytes());
+    header.extend_from_slice(&virtual_size.to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes()); // crypt_method
+    header.extend_from_slice(&(l1_size as u32).to_be_bytes());
+    header.extend_from_slice(&l1_table_offset.to_be_bytes());
+    header.extend_from_slice(&refcount_table_offset.to_be_bytes());
+    header.extend_from_slice(&1u32.to_be_bytes()); // refcount_table_clusters
+    header.extend_from_slice(&0u32.to_be_bytes()); // nb_snapshots
+    header.extend_from_slice(&0u64.to_be_bytes()); // snapshots_offset
+    header.extend_from_slice(&0u64.to_be_bytes()); // incompatible_features
+    header.extend_from_slice(&0u64.to_be_bytes()); // compatible_features
+    header.extend_from_slice(&0u64.to_be_bytes()); // autoclear_features
+    header.extend_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+    header.extend_from_slice(&HEADER_LENGTH.to_be_bytes());
+
+    image[..header.len()].copy_from_slice(&header);
+    image[HEADER_LENGTH as usize..backing_file_offset as usize].copy_from_slice(&extensions);
+    image[backing_file_offset as usize..backing_file_offset as usize + base_name.len()]
+        .copy_from_slice(base_name.as_bytes());
+
+    // The refcount table points at the single refcount block
+    let table = refcount_table_offset as usize;
+    image[table..table + 8].copy_from_slice(&refcount_block_offset.to_be_bytes());
+
+    // Every metadata cluster is referenced exactly once
+    let block = refcount_block_offset as usize;
+    for cluster in 0..total_clusters as usize {
+        image[block + cluster * 2..block + cluster * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+    }
+
+    if let Some(parent) = Path::new(overlay_path).parent() {
+        std::fs::create_dir_all(parent)
+            .context(format!("Failed to create directory: {}", parent.display()))?;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(overlay_path)
+        .context(format!("Failed to create overlay: {}", overlay_path))?;
+
+    file.write_all(&image)
+        .context(format!("Failed to write overlay: {}", overlay_path))?;
+
+    Ok(())
+}
+
+/// Size of an image file or block device in bytes
+fn image_size(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)
+        .context(format!("Failed to open base image: {}", path.display()))?;
+
+    file.seek(SeekFrom::End(0))
+        .context(format!("Failed to determine size of base image: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(image: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(image[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn be64(image: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(image[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn overlay_header_layout() {
+        let dir = std::env::temp_dir().join(format!("vllmd-overlay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("base.raw");
+        // Each L1 entry covers 512 MiB at 64 KiB clusters, so 3 GiB takes six of them
+        File::create(&base).unwrap().set_len(3 << 30).unwrap();
+        let overlay = dir.join("overlay").join("system.qcow2");
+        create_qcow2_overlay(base.to_str().unwrap(), overlay.to_str().unwrap()).unwrap();
+
+        let image = std::fs::read(&overlay).unwrap();
+        let base_name = std::fs::canonicalize(&base).unwrap().to_str().unwrap().to_string();
+        assert_eq!(image.len() as u64, 4 * CLUSTER_SIZE);
+        assert_eq!(&image[..4], b"QFI\xfb");
+        assert_eq!(be32(&image, 4), 3);
+        assert_eq!(be32(&image, 20), CLUSTER_BITS);
+        assert_eq!(be64(&image, 24), 3 << 30);
+        assert_eq!(be32(&image, 36), 6); // l1_size
+        assert_eq!(be64(&image, 40), 3 * CLUSTER_SIZE); // l1_table_offset
+        assert_eq!(be64(&image, 48), CLUSTER_SIZE); // refcount_table_offset
+        assert_eq!(be32(&image, 56), 1); // refcount_table_clusters
+        assert_eq!(be32(&image, 96), REFCOUNT_ORDER);
+        assert_eq!(be32(&image, 100), HEADER_LENGTH);
+
+        // Backing format extension, then the end marker and the backing file name
+        assert_eq!(be32(&image, 104), BACKING_FORMAT_EXTENSION);
+        assert_eq!(be32(&image, 108), 3);
+        assert_eq!(&image[112..115], b"raw");
+        assert_eq!(be64(&image, 120), 0);
+        let backing_offset = be64(&image, 8) as usize;
+        assert_eq!(backing_offset, 128);
+        assert_eq!(be32(&image, 16) as usize, base_name.len());
+        assert_eq!(&image[backing_offset..backing_offset + base_name.len()], base_name.as_bytes());
+
+        // The refcount table points at the block, which counts each of the four clusters once
+        assert_eq!(be64(&image, CLUSTER_SIZE as usize), 2 * CLUSTER_SIZE);
+        let block = 2 * CLUSTER_SIZE as usize;
+        for cluster in 0..4 {
+            assert_eq!(&image[block + cluster * 2..block + cluster * 2 + 2], &[0, 1]);
+        }
+        assert_eq!(&image[block + 8..block + 10], &[0, 0]);
+
+        // An existing overlay is never overwritten
+        assert!(create_qcow2_overlay(base.to_str().unwrap(), overlay.to_str().unwrap()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
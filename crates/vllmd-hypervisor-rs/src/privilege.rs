@@ -0,0 +1,169 @@
+use anyhow::{Result, anyhow, bail, Context};
+use log::info;
+use nix::unistd::{self, Gid, Group, Uid, User};
+use std::ffi::CString;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Unprivileged identity the hypervisor switches to before the VMM starts
+#[derive(Debug, Clone)]
+pub struct RunAs {
+    pub user: String,
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+impl RunAs {
+    /// Parse `user[:group]`; without a group the user's primary group is used
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (user_name, group_name) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+
+        let user = User::from_name(user_name)
+            .context(format!("Failed to look up user {}", user_name))?
+            .ok_or_else(|| anyhow!("No such user: {}", user_name))?;
+        if user.uid.is_root() {
+            bail!("--run-as must name an unprivileged user, not {}", user_name);
+        }
+
+        let gid = match group_name {
+            Some(group_name) => Group::from_name(group_name)
+                .context(format!("Failed to look up group {}", group_name))?
+                .ok_or_else(|| anyhow!("No such group: {}", group_name))?
+                .gid,
+            None => user.gid,
+        };
+
+        Ok(Self { user: user.name, uid: user.uid, gid })
+    }
+
+    // Whether the permission bits of `path` grant the identity all of `bits` (4 read, 2 write, 1 search)
+    fn allows(&self, path: &Path, bits: u32) -> bool {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return false;
+        };
+        let shift = if metadata.uid() == self.uid.as_raw() {
+            6
+        } else if metadata.gid() == self.gid.as_raw() {
+            3
+        } else {
+            0
+        };
+        (metadata.mode() >> shift) & bits == bits
+    }
+
+    // Whether the identity may reach `path` and has all of `bits` on it
+    fn can_open(&self, path: &Path, bits: u32) -> bool {
+        let Ok(path) = std::fs::canonicalize(path) else {
+            return false;
+        };
+        path.ancestors().skip(1).all(|ancestor| self.allows(ancestor, 0o1)) && self.allows(&path, bits)
+    }
+
+    // Whether the identity may reach `dir` and create and remove entries in it
+    fn can_write(&self, dir: &Path) -> bool {
+        self.can_open(dir, 0o3)
+    }
+
+    /// Fail unless the identity can update and clean up the runtime files in each directory
+    pub fn check_writable(&self, dirs: &[&str]) -> Result<()> {
+        let denied: Vec<&str> = dirs.iter()
+            .copied()
+            .filter(|dir| !self.can_write(Path::new(dir)))
+            .collect();
+        if !denied.is_empty() {
+            bail!("User {} cannot write to {}; the runtime files there are updated after dropping privileges",
+                  self.user, denied.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Fail unless the identity can open each of `paths`, as `(path, access)` with access `r` or
+    /// `rw`, or create it in its directory when it does not exist yet. The VMM opens them after
+    /// the switch. Directories are left to `check_writable`.
+    pub fn check_accessible(&self, paths: &[(String, &str)]) -> Result<()> {
+        let denied: Vec<String> = paths.iter()
+            .filter(|(path, access)| {
+                let path = Path::new(path);
+                match std::fs::metadata(path) {
+                    Ok(metadata) if metadata.is_dir() => false,
+                    Ok(_) => !self.can_open(path, if *access == "rw" { 0o6 } else { 0o4 }),
+                    Err(_) => path.parent().is_none_or(|dir| !self.can_write(dir)),
+                }
+            })
+            .map(|(path, access)| format!("{} ({})", path, access))
+            .collect();
+        if !denied.is_empty() {
+            bail!("User {} cannot open {}; the VMM opens them after dropping privileges", self.user, denied.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Hand the files created as root over to the identity, skipping any that do not exist
+    pub fn chown(&self, paths: &[&str]) -> Result<()> {
+        for path in paths.iter().filter(|path| Path::new(path).exists()) {
+            unistd::chown(Path::new(path), Some(self.uid), Some(self.gid))
+                .context(format!("Failed to change the owner of {} to {}", path, self.user))?;
+        }
+        Ok(())
+    }
+
+    /// Hand `paths` over to the identity while this process runs. It cannot put the owners back
+    /// itself once it has given up root, so a child process that keeps root does, with the
+    /// original modes, as soon as this process exits, however it ends.
+    pub fn lend(&self, paths: &[&str]) -> Result<()> {
+        let mut lent = Vec::new();
+        for path in paths.iter().filter(|path| Path::new(path).exists()) {
+            let metadata = std::fs::metadata(path).context(format!("Failed to read {}", path))?;
+            lent.push((CString::new(*path)?, metadata.uid(), metadata.gid(), metadata.mode() & 0o7777));
+        }
+        if lent.is_empty() {
+            return Ok(());
+        }
+
+        // The write end stays open until this process exits; the child waits for the EOF
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to create a pipe");
+        }
+        match unsafe { libc::fork() } {
+            -1 => return Err(std::io::Error::last_os_error()).context("Failed to fork to restore device owners"),
+            // Only async-signal-safe calls in the child, since other threads may hold locks
+            0 => unsafe {
+                libc::close(pipe[1]);
+                // systemd and Ctrl-C signal the whole group when the runtime stops
+                libc::signal(libc::SIGTERM, libc::SIG_IGN);
+                libc::signal(libc::SIGINT, libc::SIG_IGN);
+                let mut byte = 0u8;
+                while libc::read(pipe[0], (&mut byte as *mut u8).cast(), 1) < 0 && *libc::__errno_location() == libc::EINTR {}
+                for (path, uid, gid, mode) in &lent {
+                    libc::chown(path.as_ptr(), *uid, *gid);
+                    libc::chmod(path.as_ptr(), *mode);
+                }
+                libc::_exit(0);
+            },
+            _ => unsafe { libc::close(pipe[0]); },
+        }
+        self.chown(paths)
+    }
+
+    /// Switch the whole process to the identity for good; changing the uid away from root also
+    /// clears all capabilities. Call it before the VMM starts: threads created later inherit the
+    /// identity, while glibc would make the running VMM threads take part in the set*id calls,
+    /// which their seccomp filters do not allow.
+    pub fn drop_privileges(&self) -> Result<()> {
+        unistd::setgroups(&[self.gid]).context("Failed to drop supplementary groups")?;
+        unistd::setgid(self.gid).context(format!("Failed to switch to group {}", self.gid))?;
+        unistd::setuid(self.uid).context(format!("Failed to switch to user {}", self.user))?;
+
+        if unistd::setuid(Uid::from_raw(0)).is_ok() {
+            bail!("Process could regain root after dropping privileges");
+        }
+
+        info!("Dropped privileges to {} (uid {}, gid {})", self.user, self.uid, self.gid);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use crate::agent::{AgentClient, AGENT_PORT};
+use anyhow::{Result, bail};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What each liveness check exercises
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// HTTP GET of `url`, healthy on a 2xx status
+    #[default]
+    Http,
+    /// Ping of the guest agent over vsock
+    Agent,
+}
+
+impl ProbeKind {
+    /// Parse the value of an environment variable
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "http" => Ok(ProbeKind::Http),
+            "agent" => Ok(ProbeKind::Agent),
+            _ => bail!("Unknown probe kind '{}', expected http or agent", s),
+        }
+    }
+}
+
+/// Remediation taken once the check becomes unhealthy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeAction {
+    /// Only log the failure
+    #[default]
+    Log,
+    /// Log a structured event and report it to systemd
+    Event,
+    /// Reboot the guest
+    Reboot,
+    /// Shut the VM down and exit
+    Shutdown,
+}
+
+impl ProbeAction {
+    /// Parse the value of an environment variable
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "log" => Ok(ProbeAction::Log),
+            "event" => Ok(ProbeAction::Event),
+            "reboot" => Ok(ProbeAction::Reboot),
+            "shutdown" => Ok(ProbeAction::Shutdown),
+            _ => bail!("Unknown probe action '{}', expected log, event, reboot or shutdown", s),
+        }
+    }
+}
+
+/// Liveness check of the workload inside the guest (`[runtimes.probe]`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeConfig {
+    /// Check to run
+    #[serde(default)]
+    pub kind: ProbeKind,
+
+    /// URL that answers 2xx when the workload is serving, e.g. "http://10.0.0.2:8000/health"
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// Seconds between probes
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+
+    /// Consecutive failures before the endpoint is considered unhealthy
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+
+    /// Seconds to wait for each response
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Remediation once the check fails `threshold` times in a row
+    #[serde(default)]
+    pub action: ProbeAction,
+
+    /// Seconds after each boot during which failures do not count until a check has passed, so
+    /// a workload still loading its model is not declared unhealthy
+    #[serde(default = "default_start_period_secs")]
+    pub start_period_secs: u64,
+}
+
+fn default_interval_secs() -> u64 { 10 }
+fn default_threshold() -> u32 { 3 }
+fn default_timeout_secs() -> u64 { 5 }
+fn default_start_period_secs() -> u64 { 300 }
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            kind: ProbeKind::default(),
+            url: None,
+            interval_secs: default_interval_secs(),
+            threshold: default_threshold(),
+            timeout_secs: default_timeout_secs(),
+            action: ProbeAction::default(),
+            start_period_secs: default_start_period_secs(),
+        }
+    }
+}
+
+impl ProbeConfig {
+    /// Check that the configuration can be run
+    pub fn validate(&self) -> Result<()> {
+        if self.kind == ProbeKind::Http && self.url.is_none() {
+            bail!("HTTP probe requires a url");
+        }
+        if self.interval_secs == 0 || self.threshold == 0 {
+            bail!("Probe interval and threshold must be greater than zero");
+        }
+        Ok(())
+    }
+
+    /// Human-readable target of the check, used in log messages
+    pub fn target(&self) -> String {
+        match self.kind {
+            ProbeKind::Http => self.url.clone().unwrap_or_default(),
+            ProbeKind::Agent => "guest agent".to_string(),
+        }
+    }
+}
+
+/// Health of the probed endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    Unhealthy,
+}
+
+impl Health {
+    /// Name used in the health file read by `status`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// Probe the endpoint once, returning whether it answered with a 2xx status
+pub fn check(agent: &ureq::Agent, url: &str) -> bool {
+    match agent.get(url).call() {
+        Ok(response) => (200..300).contains(&response.status()),
+        Err(e) => {
+            debug!("Probe of {} failed: {}", url, e);
+            false
+        }
+    }
+}
+
+/// Ping the guest agent once, returning whether it answered
+pub fn check_agent(vsock_socket_path: &str, timeout: Duration) -> bool {
+    let result = AgentClient::connect(vsock_socket_path, AGENT_PORT)
+        .and_then(|mut client| {
+            client.set_timeout(Some(timeout))?;
+            client.ping()
+        });
+    match result {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("Agent ping over {} failed: {}", vsock_socket_path, e);
+            false
+        }
+    }
+}
+
+/// Turns the results of the checks into changes of health. Failures count once a check has
+/// passed, or once the start period has run out without one.
+#[derive(Debug)]
+pub struct Tracker {
+    threshold: u32,
+    start_period: Duration,
+    armed_at: Instant,
+    passed: bool,
+    failures: u32,
+    health: Option<Health>,
+}
+
+impl Tracker {
+    pub fn new(config: &ProbeConfig, now: Instant) -> Self {
+        Self {
+            threshold: config.threshold,
+            start_period: Duration::from_secs(config.start_period_secs),
+            armed_at: now,
+            passed: false,
+            failures: 0,
+            health: None,
+        }
+    }
+
+    /// Start over with a new start period, as after the guest rebooted
+    pub fn rearm(&mut self, now: Instant) {
+        self.armed_at = now;
+        self.passed = false;
+        self.failures = 0;
+        self.health = None;
+    }
+
+    /// Record the result of a check made at `now`, returning the health when it changed
+    pub fn record(&mut self, passed: bool, now: Instant) -> Option<Health> {
+        let new_health = if passed {
+            self.passed = true;
+            self.failures = 0;
+            Some(Health::Healthy)
+        } else if !self.passed && now.duration_since(self.armed_at) < self.start_period {
+            self.health
+        } else {
+            self.failures += 1;
+            if self.failures >= self.threshold { Some(Health::Unhealthy) } else { self.health }
+        };
+        if new_health == self.health {
+            return None;
+        }
+        self.health = new_health;
+        new_health
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// Handle on the probe thread
+pub struct Probe {
+    /// Gets the new health each time it changes
+    pub health: Receiver<Health>,
+    rearm: Sender<()>,
+}
+
+impl Probe {
+    /// Start over as for a new boot, after the guest rebooted
+    pub fn rearm(&self) {
+        let _ = self.rearm.send(());
+    }
+}
+
+/// Run the check on an interval in a background thread, which stops once the `Probe` is
+/// dropped. Agent checks connect through `vsock_socket_path`.
+pub fn spawn(config: ProbeConfig, vsock_socket_path: String) -> Probe {
+    let (sender, receiver) = channel();
+    let (rearm_sender, rearm) = channel::<()>();
+
+    thread::spawn(move || {
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let agent = ureq::AgentBuilder::new()
+            .timeout(timeout)
+            .build();
+        let target = config.target();
+
+        let mut tracker = Tracker::new(&config, Instant::now());
+        loop {
+            if rearm.try_iter().count() > 0 {
+                debug!("Probe of {} starts over after a reboot", target);
+                tracker.rearm(Instant::now());
+            }
+            let passed = match config.kind {
+                ProbeKind::Http => check(&agent, config.url.as_deref().unwrap_or_default()),
+                ProbeKind::Agent => check_agent(&vsock_socket_path, timeout),
+            };
+
+            if let Some(changed) = tracker.record(passed, Instant::now()) {
+                match changed {
+                    Health::Healthy => info!("Probe of {} is healthy", target),
+                    Health::Unhealthy => warn!("Probe of {} failed {} times in a row", target, tracker.failures()),
+                }
+                if sender.send(changed).is_err() {
+                    return;
+                }
+            }
+
+            thread::sleep(Duration::from_secs(config.interval_secs));
+        }
+    });
+
+    Probe { health: receiver, rearm: rearm_sender }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(now: Instant) -> Tracker {
+        Tracker::new(&ProbeConfig { threshold: 2, start_period_secs: 60, ..ProbeConfig::default() }, now)
+    }
+
+    #[test]
+    fn failures_wait_for_the_start_period() {
+        let start = Instant::now();
+        let mut tracker = tracker(start);
+        // A workload still loading fails every check without being declared unhealthy
+        for secs in 0..60 {
+            assert_eq!(tracker.record(false, start + Duration::from_secs(secs)), None);
+        }
+        assert_eq!(tracker.record(false, start + Duration::from_secs(60)), None);
+        assert_eq!(tracker.record(false, start + Duration::from_secs(70)), Some(Health::Unhealthy));
+        assert_eq!(tracker.record(false, start + Duration::from_secs(80)), None);
+    }
+
+    #[test]
+    fn failures_count_after_the_first_pass() {
+        let start = Instant::now();
+        let mut tracker = tracker(start);
+        assert_eq!(tracker.record(true, start), Some(Health::Healthy));
+        assert_eq!(tracker.record(false, start + Duration::from_secs(1)), None);
+        assert_eq!(tracker.record(false, start + Duration::from_secs(2)), Some(Health::Unhealthy));
+        assert_eq!(tracker.record(true, start + Duration::from_secs(3)), Some(Health::Healthy));
+    }
+
+    #[test]
+    fn rearm_starts_a_new_start_period() {
+        let start = Instant::now();
+        let mut tracker = tracker(start);
+        tracker.record(true, start);
+        tracker.record(false, start + Duration::from_secs(1));
+        assert_eq!(tracker.record(false, start + Duration::from_secs(2)), Some(Health::Unhealthy));
+
+        // The rebooted guest gets a start period of its own, then is probed like a new one
+        let rebooted = start + Duration::from_secs(3);
+        tracker.rearm(rebooted);
+        assert_eq!(tracker.record(false, rebooted + Duration::from_secs(10)), None);
+        assert_eq!(tracker.record(false, rebooted + Duration::from_secs(20)), None);
+        assert_eq!(tracker.record(true, rebooted + Duration::from_secs(30)), Some(Health::Healthy));
+        tracker.record(false, rebooted + Duration::from_secs(31));
+        assert_eq!(tracker.record(false, rebooted + Duration::from_secs(32)), Some(Health::Unhealthy));
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Result, Context};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::fleet::{is_alive, running_pid};
+use crate::store::{self, Store};
+
+/// Files next to a PID file that only mean something while its hypervisor runs. The host event
+/// socket is shared by every runtime, and diagnostic dumps are kept for the bug report.
+const RUNTIME_EXTENSIONS: &[&str] = &[
+    "pid", "sock", "console.sock", "vsock", "tpm.sock", "state", "health", "timing.json", "config.json", "restart",
+    "upgrade",
+];
+
+/// A file to remove and its size
+#[derive(Debug, Clone)]
+pub struct Stale {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+impl Stale {
+    fn new(path: PathBuf) -> Self {
+        let bytes = std::fs::symlink_metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+        Self { path, bytes }
+    }
+}
+
+/// A hypervisor that is running, by the stem of its PID file
+#[derive(Debug, Clone)]
+pub struct Running {
+    pub stem: String,
+    pub pid: u32,
+    /// No state file yet: the VM is still being created and may not have opened its overlay
+    pub booting: bool,
+}
+
+/// The hypervisors that are running, by their PID files in `runtime_dir` and their state records
+/// in `state_dir`; `prefix` is as for `runtime_files`
+pub fn running(runtime_dir: &Path, prefix: &str, state_dir: &str) -> Result<Vec<Running>> {
+    let mut running = Vec::new();
+    for entry in read_dir(runtime_dir)? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(stem) = name.strip_suffix(".pid").filter(|stem| is_runtime_stem(stem, prefix)) else { continue };
+        if let Some(pid) = running_pid(&entry.path()) {
+            let booting = !entry.path().with_extension("state").exists();
+            running.push(Running { stem: stem.to_string(), pid, booting });
+        }
+    }
+    // A hypervisor started with another runtime directory, by another user or a system service,
+    // has its PID file elsewhere but shares the state directory
+    for name in store::runtimes(state_dir)? {
+        let stem = match &name {
+            Some(name) => format!("{}-{}", prefix, name),
+            None => prefix.to_string(),
+        };
+        if running.iter().any(|runtime| runtime.stem == stem) {
+            continue;
+        }
+        let Ok(Some(record)) = Store::new(state_dir, name.as_deref()).load() else { continue };
+        if let Some(pid) = record.pid.filter(|pid| is_alive(*pid)) {
+            let booting = record.state.is_some_and(|state| state.state == "booting");
+            running.push(Running { stem, pid, booting });
+        }
+    }
+    Ok(running)
+}
+
+/// Files open in any process this user can look into. The open files of the `hypervisors` must
+/// be readable, as their overlays are in use without a process of the user holding them.
+pub fn open_files(hypervisors: &[u32]) -> Result<Vec<PathBuf>> {
+    for pid in hypervisors {
+        std::fs::read_dir(format!("/proc/{}/fd", pid))
+            .context(format!("Failed to read the open files of hypervisor {}; run prune as its user or as root", pid))?;
+    }
+    let mut open = Vec::new();
+    for process in read_dir(Path::new("/proc"))? {
+        if !process.file_name().to_string_lossy().bytes().all(|byte| byte.is_ascii_digit()) {
+            continue;
+        }
+        // Other users' processes, and those that exited meanwhile, cannot be looked into
+        if let Ok(fds) = std::fs::read_dir(process.path().join("fd")) {
+            open.extend(fds.flatten().filter_map(|fd| std::fs::read_link(fd.path()).ok()));
+        }
+    }
+    Ok(open)
+}
+
+/// PID files, sockets and state files in `runtime_dir` of the runtimes whose hypervisor no longer
+/// runs. `prefix` is the file stem of the PID file of the unnamed runtime; named runtimes add
+/// `-<name>` to it.
+pub fn runtime_files(runtime_dir: &Path, prefix: &str) -> Result<Vec<Stale>> {
+    let mut runtimes: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for entry in read_dir(runtime_dir)? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Runtime names have no dots, so the extension starts at the first one
+        let Some((stem, extension)) = name.split_once('.') else { continue };
+        // Guest connections to a vsock port get a socket of their own, `<socket>_<port>`
+        let known = RUNTIME_EXTENSIONS.contains(&extension) || extension.strip_prefix("vsock_").is_some();
+        if is_runtime_stem(stem, prefix) && known {
+            runtimes.entry(stem.to_string()).or_default().push(entry.path());
+        }
+    }
+
+    let mut stale = Vec::new();
+    for (stem, paths) in runtimes {
+        if running_pid(&runtime_dir.join(format!("{}.pid", stem))).is_none() {
+            stale.extend(paths.into_iter().map(Stale::new));
+        }
+    }
+    Ok(stale)
+}
+
+/// Guest logs (`<name>-guest.log`) in `log_dir` of the runtimes `running` says are stopped and
+/// that no process holds `open`
+pub fn guest_logs(log_dir: &Path, running: impl Fn(&str) -> bool, open: &[PathBuf]) -> Result<Vec<Stale>> {
+    let mut stale = Vec::new();
+    for entry in read_dir(log_dir)? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(runtime) = name.strip_suffix("-guest.log") {
+            if !running(runtime) && !is_open(&entry.path(), open) {
+                stale.push(Stale::new(entry.path()));
+            }
+        }
+    }
+    Ok(stale)
+}
+
+/// Overlays in `overlay_dir` that no process holds `open`. Each overlay is named after the id of
+/// the VM that created it, so a crashed hypervisor leaves its overlay behind. The VMM runs inside
+/// the hypervisor process, so an overlay in use is among its open files.
+pub fn overlays(overlay_dir: &Path, open: &[PathBuf]) -> Result<Vec<Stale>> {
+    let mut stale = Vec::new();
+    for entry in read_dir(overlay_dir)? {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "qcow2") && !is_open(&path, open) {
+            stale.push(Stale::new(path));
+        }
+    }
+    Ok(stale)
+}
+
+// Whether `path` is among the `open` files, which /proc names by their canonical paths
+fn is_open(path: &Path, open: &[PathBuf]) -> bool {
+    let canonical = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    open.contains(&canonical)
+}
+
+/// State records in `state_dir` of named runtimes `keep` says are gone. The record of the unnamed
+/// runtime stays, as nothing tells whether it will run again.
+pub fn records(state_dir: &str, keep: impl Fn(&str) -> bool) -> Result<Vec<Stale>> {
+    Ok(store::runtimes(state_dir)?
+        .into_iter()
+        .flatten()
+        .filter(|name| !keep(name))
+        .map(|name| Stale::new(Store::new(state_dir, Some(&name)).path().to_path_buf()))
+        .collect())
+}
+
+/// Remove `stale`, or with `dry_run` only print what would be removed, returning the number of
+/// files and bytes
+pub fn remove(stale: &[Stale], dry_run: bool) -> Result<(usize, u64)> {
+    let mut bytes = 0;
+    for file in stale {
+        if dry_run {
+            println!("Would remove {}", file.path.display());
+        } else {
+            std::fs::remove_file(&file.path)
+                .context(format!("Failed to remove {}", file.path.display()))?;
+            println!("Removed {}", file.path.display());
+        }
+        bytes += file.bytes;
+    }
+    Ok((stale.len(), bytes))
+}
+
+// Stem of the PID file of the unnamed runtime (`prefix`) or of a named one (`prefix-<name>`)
+fn is_runtime_stem(stem: &str, prefix: &str) -> bool {
+    stem == prefix || stem.strip_prefix(prefix).is_some_and(|name| name.starts_with('-'))
+}
+
+// Entries of `dir`, none when it does not exist
+fn read_dir(dir: &Path) -> Result<Vec<std::fs::DirEntry>> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.flatten().collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).context(format!("Failed to read {}", dir.display())),
+    }
+}
@@ -0,0 +1,339 @@
+use anyhow::{Result, anyhow, Context};
+use serde::de::{self, Deserialize, Visitor};
+use std::collections::BTreeSet;
+
+use crate::manifest::{GlobalConfig, NetworkConfig, RuntimeConfig};
+
+/// VARIABLES.md as of this build, used unless another registry file is given. It lives in the
+/// crate so it is packaged with it; vllmd-hypervisor/VARIABLES.md links to it for the scripts.
+const EMBEDDED_REGISTRY: &str = include_str!("../VARIABLES.md");
+
+// "Used By" entry of the variables this binary reads
+const BINARY_NAME: &str = "vllmd-hypervisor";
+
+/// Where a variable is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// Key of the `[global]` section
+    Global,
+    /// Key of a `[[runtimes]]` entry
+    Runtime,
+    /// Key of the `[network]` section
+    Network,
+    /// Environment variable
+    Env,
+}
+
+impl Scope {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "global" => Ok(Scope::Global),
+            "runtime" => Ok(Scope::Runtime),
+            "network" => Ok(Scope::Network),
+            "env" | "environment" => Ok(Scope::Env),
+            other => Err(anyhow!("Unknown variable scope '{}', expected global, runtime, network or env", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Global => "global",
+            Scope::Runtime => "runtime",
+            Scope::Network => "network",
+            Scope::Env => "env",
+        }
+    }
+
+    // Configuration file sections and the keys their parser accepts
+    fn config_sections() -> [(Scope, &'static [&'static str]); 3] {
+        [
+            (Scope::Global, field_names::<GlobalConfig>()),
+            (Scope::Runtime, field_names::<RuntimeConfig>()),
+            (Scope::Network, field_names::<NetworkConfig>()),
+        ]
+    }
+}
+
+/// A row of one of the VARIABLES.md tables
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub scope: Scope,
+    /// Type column of configuration keys; inferred from the name and default for environment variables
+    pub kind: String,
+    pub default: String,
+    pub description: String,
+    /// Scripts and binaries reading the variable (environment variables only)
+    pub used_by: Vec<String>,
+}
+
+impl Variable {
+    fn read_by_binary(&self) -> bool {
+        self.used_by.iter().any(|user| user == BINARY_NAME)
+    }
+
+    // Default as a plain value, when the column holds a single code span such as `off`
+    fn default_value(&self) -> Option<&str> {
+        let value = self.default.strip_prefix('`')?.strip_suffix('`')?;
+        (!value.contains('`')).then_some(value)
+    }
+
+    // Naming convention violations
+    fn naming_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let name = self.name.as_str();
+
+        if self.scope != Scope::Env {
+            if !name.starts_with(|c: char| c.is_ascii_lowercase())
+                || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+                problems.push("configuration keys are lower snake_case".to_string());
+            }
+            return problems;
+        }
+
+        if !name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') {
+            problems.push("environment variables are upper snake_case".to_string());
+        }
+        if !name.starts_with("VLLMD_") {
+            problems.push("does not start with VLLMD_".to_string());
+        } else if self.read_by_binary() && !name.starts_with("VLLMD_HYPERVISOR_") {
+            problems.push(format!("is read by {} but does not start with VLLMD_HYPERVISOR_", BINARY_NAME));
+        }
+
+        // A list of paths is named like a single path plus _LIST
+        let stem = name.strip_suffix("_LIST").unwrap_or(name);
+        if stem.ends_with("_FILE_PATH") {
+            problems.push("file paths end with _FILEPATH, not _FILE_PATH".to_string());
+        } else if stem.contains("PATH") && !stem.ends_with("_PATH") && !stem.ends_with("_FILEPATH") {
+            problems.push("paths end with _PATH or _FILEPATH".to_string());
+        }
+        if self.description.to_ascii_lowercase().contains("separated list") && !name.ends_with("_LIST") {
+            problems.push("lists end with _LIST".to_string());
+        }
+        if name.contains("COUNT") && !name.ends_with("_COUNT") {
+            problems.push("counts end with _COUNT".to_string());
+        }
+        problems
+    }
+}
+
+/// Problem found by `Registry::check`
+#[derive(Debug, Clone)]
+pub struct Issue {
+    /// "naming", "undocumented", "unused" or "default"
+    pub kind: &'static str,
+    pub name: String,
+    pub problem: String,
+}
+
+impl Issue {
+    fn new(kind: &'static str, name: &str, problem: impl Into<String>) -> Self {
+        Self { kind, name: name.to_string(), problem: problem.into() }
+    }
+}
+
+/// The variables documented in VARIABLES.md
+#[derive(Debug, Clone)]
+pub struct Registry {
+    pub variables: Vec<Variable>,
+}
+
+impl Registry {
+    /// Registry embedded in the binary at build time
+    pub fn embedded() -> Self {
+        Self::parse(EMBEDDED_REGISTRY)
+    }
+
+    /// Registry read from a VARIABLES.md file
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Failed to read variable registry: {}", path))?;
+        let registry = Self::parse(&contents);
+        if registry.variables.is_empty() {
+            return Err(anyhow!("No variable tables found in {}", path));
+        }
+        Ok(registry)
+    }
+
+    /// Parse the variable tables of VARIABLES.md. Configuration keys are listed under the
+    /// "Configuration Variables" heading in one table per section, and environment variables
+    /// under "Environment Variables"; rows whose first cell is not a code span are skipped.
+    pub fn parse(markdown: &str) -> Self {
+        let mut variables = Vec::new();
+        let mut environment = None;
+        let mut scope = None;
+        let mut columns: Vec<String> = Vec::new();
+
+        for line in markdown.lines().map(str::trim) {
+            if let Some(heading) = line.strip_prefix("## ") {
+                environment = match heading.trim() {
+                    "Configuration Variables" => Some(false),
+                    "Environment Variables" => Some(true),
+                    _ => None,
+                };
+                scope = environment.and_then(|environment| environment.then_some(Scope::Env));
+                continue;
+            }
+            if let Some(heading) = line.strip_prefix("### ") {
+                scope = match (environment, heading.trim()) {
+                    (Some(true), _) => Some(Scope::Env),
+                    (Some(false), "Global Section") => Some(Scope::Global),
+                    (Some(false), "Runtime Section") => Some(Scope::Runtime),
+                    (Some(false), "Network Section") => Some(Scope::Network),
+                    _ => None,
+                };
+                continue;
+            }
+
+            let Some(scope) = scope else { continue };
+            if !line.starts_with('|') {
+                columns.clear();
+                continue;
+            }
+
+            let cells: Vec<&str> = line.trim_matches('|').split('|').map(str::trim).collect();
+            if columns.is_empty() {
+                columns = cells.iter().map(|cell| cell.to_ascii_lowercase()).collect();
+                continue;
+            }
+            let Some(name) = cells[0].strip_prefix('`').and_then(|name| name.strip_suffix('`')) else {
+                continue;
+            };
+
+            let cell = |column: &str| columns.iter().position(|name| name == column)
+                .and_then(|index| cells.get(index))
+                .map(|cell| cell.to_string())
+                .unwrap_or_default();
+            let mut variable = Variable {
+                name: name.to_string(),
+                scope,
+                kind: cell("type"),
+                default: cell("default"),
+                description: cell("description"),
+                used_by: cell("used by").split(',').map(|user| user.trim().to_string())
+                    .filter(|user| !user.is_empty())
+                    .collect(),
+            };
+            if variable.kind.is_empty() {
+                variable.kind = infer_kind(&variable).to_string();
+            }
+            variables.push(variable);
+        }
+
+        Self { variables }
+    }
+
+    pub fn get(&self, scope: Scope, name: &str) -> Option<&Variable> {
+        self.variables.iter().find(|variable| variable.scope == scope && variable.name == name)
+    }
+
+    /// Check naming conventions, and cross-check the registry against the environment variables
+    /// the binary reads (name and default) and the keys the configuration file parser accepts
+    pub fn check(&self, environment_vars: &[(&str, Option<String>)]) -> Vec<Issue> {
+        let mut issues = Vec::new();
+
+        let mut seen = BTreeSet::new();
+        for variable in &self.variables {
+            if !seen.insert((variable.scope, variable.name.as_str())) {
+                issues.push(Issue::new("naming", &variable.name, format!("documented twice in the {} scope", variable.scope.as_str())));
+            }
+            for problem in variable.naming_problems() {
+                issues.push(Issue::new("naming", &variable.name, problem));
+            }
+        }
+
+        for (name, default) in environment_vars {
+            let Some(variable) = self.get(Scope::Env, name) else {
+                issues.push(Issue::new("undocumented", name, format!("read by {} but not in the registry", BINARY_NAME)));
+                continue;
+            };
+            if !variable.read_by_binary() {
+                issues.push(Issue::new("undocumented", name, format!("read by {} but documented as used by {}",
+                                                                      BINARY_NAME, variable.used_by.join(", "))));
+            }
+            if let (Some(documented), Some(default)) = (variable.default_value(), default) {
+                if documented != default {
+                    issues.push(Issue::new("default", name, format!("documented as {} but {} defaults to {}",
+                                                                     documented, BINARY_NAME, default)));
+                }
+            }
+        }
+        for variable in self.variables.iter().filter(|variable| variable.scope == Scope::Env && variable.read_by_binary()) {
+            if !environment_vars.iter().any(|(name, _)| *name == variable.name) {
+                issues.push(Issue::new("unused", &variable.name, format!("documented as used by {} but not read by it", BINARY_NAME)));
+            }
+        }
+
+        for (scope, keys) in Scope::config_sections() {
+            for key in keys {
+                if self.get(scope, key).is_none() {
+                    issues.push(Issue::new("undocumented", key, format!("{} key accepted by the configuration file but not in the registry", scope.as_str())));
+                }
+            }
+            for variable in self.variables.iter().filter(|variable| variable.scope == scope) {
+                if !keys.contains(&variable.name.as_str()) {
+                    issues.push(Issue::new("unused", &variable.name, format!("{} key not accepted by the configuration file", scope.as_str())));
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+// Type of an environment variable, which VARIABLES.md does not record
+fn infer_kind(variable: &Variable) -> &'static str {
+    if variable.name.ends_with("_LIST") {
+        return "array";
+    }
+    match variable.default_value() {
+        Some("on" | "off" | "true" | "false") => "boolean",
+        Some(value) if value.parse::<i64>().is_ok() => "integer",
+        _ => "string",
+    }
+}
+
+/// `VLLMD_HYPERVISOR_*` variables referenced as `$VAR` or `${VAR}` in a shell script that the
+/// registry does not document
+pub fn undocumented_script_vars(registry: &Registry, script: &str) -> Vec<String> {
+    let mut undocumented = BTreeSet::new();
+    for reference in script.split('$').skip(1) {
+        let name: String = reference.trim_start_matches('{')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+            .collect();
+        if name.starts_with("VLLMD_HYPERVISOR_") && registry.get(Scope::Env, &name).is_none() {
+            undocumented.insert(name);
+        }
+    }
+    undocumented.into_iter().collect()
+}
+
+// Deserializer that records the field names a struct asks for instead of reading any data
+struct FieldNames(Option<&'static [&'static str]>);
+
+impl<'de> de::Deserializer<'de> for &mut FieldNames {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> std::result::Result<V::Value, Self::Error> {
+        Err(de::Error::custom("only structs have field names"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str],
+                                           _visitor: V) -> std::result::Result<V::Value, Self::Error> {
+        self.0 = Some(fields);
+        Err(de::Error::custom("field names recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+// Keys accepted by a configuration file section
+fn field_names<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    let mut names = FieldNames(None);
+    let _ = T::deserialize(&mut names);
+    names.0.unwrap_or(&[])
+}
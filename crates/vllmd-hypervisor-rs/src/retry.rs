@@ -0,0 +1,103 @@
+use anyhow::Result;
+use log::warn;
+use std::fmt;
+use std::time::Duration;
+
+/// Error of a step that fails the same way however often it is tried, such as a check of the
+/// configuration; `RetryPolicy::run` returns it without trying again
+#[derive(Debug)]
+pub struct Permanent(pub String);
+
+impl fmt::Display for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Permanent {}
+
+/// How often a host-side step that can fail for a moment is tried again before `start` gives up,
+/// such as preparing a device the previous VM released a moment ago
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries after the first one
+    pub retries: u32,
+    /// Wait before the first retry; each later retry waits twice as long as the one before
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Run `step` until it succeeds, the retries are used up or it fails with a `Permanent`
+    /// error, returning its last error
+    pub fn run<T>(&self, what: &str, mut step: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut delay = self.delay;
+        let mut retry = 0;
+        loop {
+            match step() {
+                Ok(value) => return Ok(value),
+                Err(e) if retry < self.retries && e.downcast_ref::<Permanent>().is_none() => {
+                    retry += 1;
+                    warn!("{} failed, retry {} of {} in {}ms: {:#}", what, retry, self.retries, delay.as_millis(), e);
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, bail};
+    use std::time::Instant;
+
+    #[test]
+    fn retries_with_a_doubling_delay() {
+        let policy = RetryPolicy { retries: 3, delay: Duration::from_millis(20) };
+        let mut tries = Vec::new();
+        let result: Result<()> = policy.run("Step", || {
+            tries.push(Instant::now());
+            bail!("busy")
+        });
+        assert_eq!(result.unwrap_err().to_string(), "busy");
+        // The first try and three retries, 20, 40 and 80ms apart
+        assert_eq!(tries.len(), 4);
+        for (gap, wanted) in tries.windows(2).zip([20, 40, 80]) {
+            assert!(gap[1] - gap[0] >= Duration::from_millis(wanted), "{:?}", gap[1] - gap[0]);
+        }
+    }
+
+    #[test]
+    fn stops_at_the_first_success() {
+        let policy = RetryPolicy { retries: 5, delay: Duration::from_millis(1) };
+        let mut tries = 0;
+        let value = policy.run("Step", || {
+            tries += 1;
+            if tries < 3 { bail!("busy") } else { Ok(tries) }
+        });
+        assert_eq!(value.unwrap(), 3);
+
+        // No retries: the first error is the result
+        let mut tries = 0;
+        let result: Result<()> = RetryPolicy::default().run("Step", || {
+            tries += 1;
+            bail!("busy")
+        });
+        assert!(result.is_err());
+        assert_eq!(tries, 1);
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let policy = RetryPolicy { retries: 3, delay: Duration::from_secs(60) };
+        let mut tries = 0;
+        let result: Result<()> = policy.run("Step", || {
+            tries += 1;
+            Err(anyhow!(Permanent("no /dev/kvm".to_string())))
+        });
+        assert!(result.unwrap_err().downcast_ref::<Permanent>().is_some());
+        assert_eq!(tries, 1);
+    }
+}
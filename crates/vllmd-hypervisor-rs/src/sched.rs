@@ -0,0 +1,123 @@
+use anyhow::{Result, anyhow, bail};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Scheduling of the vCPU threads: a real-time policy with a priority, or a nice value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum VcpuSched {
+    /// SCHED_FIFO with priority 1-99
+    Fifo(i32),
+    /// SCHED_RR with priority 1-99
+    RoundRobin(i32),
+    /// SCHED_OTHER with nice value -20 to 19
+    Nice(i32),
+}
+
+impl VcpuSched {
+    /// Parse `fifo:<priority>`, `rr:<priority>` or `nice:<value>`
+    pub fn parse(s: &str) -> Result<Self> {
+        let (policy, value) = s.trim().split_once(':')
+            .ok_or_else(|| anyhow!("Expected fifo:<priority>, rr:<priority> or nice:<value>, got {}", s))?;
+        let value: i32 = value.trim().parse()
+            .map_err(|_| anyhow!("Invalid number '{}' in vCPU scheduling {}", value, s))?;
+
+        let sched = match policy.trim().to_lowercase().as_str() {
+            "fifo" => VcpuSched::Fifo(value),
+            "rr" => VcpuSched::RoundRobin(value),
+            "nice" => VcpuSched::Nice(value),
+            _ => bail!("Unknown vCPU scheduling policy '{}', expected fifo, rr or nice", policy),
+        };
+
+        match sched {
+            VcpuSched::Fifo(priority) | VcpuSched::RoundRobin(priority) if !(1..=99).contains(&priority) => {
+                bail!("Real-time priority {} is outside 1-99", priority)
+            },
+            VcpuSched::Nice(nice) if !(-20..=19).contains(&nice) => bail!("Nice value {} is outside -20 to 19", nice),
+            sched => Ok(sched),
+        }
+    }
+
+    /// Raise RLIMIT_RTPRIO or RLIMIT_NICE so this process can still apply the policy once
+    /// `--run-as` has taken CAP_SYS_NICE away. Limits survive the switch to another user.
+    pub fn allow_unprivileged(&self) -> Result<()> {
+        let (resource, limit) = match *self {
+            VcpuSched::Fifo(priority) | VcpuSched::RoundRobin(priority) => (libc::RLIMIT_RTPRIO, priority as libc::rlim_t),
+            // A nice value of n needs a limit of 20 - n
+            VcpuSched::Nice(nice) if nice < 0 => (libc::RLIMIT_NICE, (20 - nice) as libc::rlim_t),
+            VcpuSched::Nice(_) => return Ok(()),
+        };
+        let rlimit = libc::rlimit { rlim_cur: limit, rlim_max: limit };
+        // SAFETY: rlimit outlives the call
+        if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+            bail!("Failed to raise the scheduling limit for {:?}: {}", self, std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Set the policy of one thread
+    fn apply_to(&self, tid: libc::pid_t) -> std::io::Result<()> {
+        let result = match *self {
+            VcpuSched::Fifo(priority) | VcpuSched::RoundRobin(priority) => {
+                let policy = if matches!(self, VcpuSched::Fifo(_)) { libc::SCHED_FIFO } else { libc::SCHED_RR };
+                let param = libc::sched_param { sched_priority: priority };
+                // SAFETY: param outlives the call and tid is a thread of this process
+                unsafe { libc::sched_setscheduler(tid, policy, &param) }
+            },
+            // SAFETY: setpriority only reads its arguments; on Linux PRIO_PROCESS with a tid targets one thread
+            VcpuSched::Nice(nice) => unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) },
+        };
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    /// Apply the policy to the vCPU threads of this process, returning how many were changed.
+    /// Cloud Hypervisor names them `vcpu<N>`; a reboot creates new ones, so apply again after it.
+    pub fn apply(&self) -> Result<usize> {
+        let mut applied = 0;
+        for entry in std::fs::read_dir("/proc/self/task")? {
+            let entry = entry?;
+            let comm = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            if !comm.trim().starts_with("vcpu") {
+                continue;
+            }
+
+            let Some(tid) = entry.file_name().to_str().and_then(|tid| tid.parse::<libc::pid_t>().ok()) else {
+                continue;
+            };
+            match self.apply_to(tid) {
+                Ok(_) => applied += 1,
+                Err(e) => warn!("Failed to set scheduling of {} (thread {}): {}", comm.trim(), tid, e),
+            }
+        }
+
+        if applied == 0 {
+            bail!("No vCPU threads found to apply {:?} to", self);
+        }
+        info!("Applied {:?} to {} vCPU thread(s)", self, applied);
+
+        Ok(applied)
+    }
+}
+
+impl TryFrom<String> for VcpuSched {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::parse(&s)
+    }
+}
+
+impl From<VcpuSched> for String {
+    fn from(sched: VcpuSched) -> Self {
+        match sched {
+            VcpuSched::Fifo(priority) => format!("fifo:{}", priority),
+            VcpuSched::RoundRobin(priority) => format!("rr:{}", priority),
+            VcpuSched::Nice(nice) => format!("nice:{}", nice),
+        }
+    }
+}
@@ -0,0 +1,13 @@
+// Included by the CLI tests as well, so it only uses std
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// New directory `<prefix>-<pid>-<n>` in the system temporary directory, unique to the caller
+/// among the tests of this process
+pub fn dir(prefix: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("{}-{}-{}", prefix, std::process::id(),
+                                                COUNT.fetch_add(1, Ordering::SeqCst)));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Duration, Utc};
+use std::path::Path;
+
+use crate::store::Snapshot;
+
+/// Files Cloud Hypervisor writes into a snapshot directory
+const SNAPSHOT_FILES: &[&str] = &["config.json", "state.json", "memory-ranges"];
+
+/// How many snapshots of a runtime to keep and for how long; snapshots either limit drops are
+/// deleted after each new one
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    /// Keep only the newest this many
+    pub keep: Option<usize>,
+    /// Delete those older than this
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.keep.is_none() && self.max_age.is_none()
+    }
+
+    /// Snapshots of `snapshots`, oldest first, that the retention drops at `now`; the newest
+    /// always stays, however old it is
+    pub fn expired(&self, snapshots: &[Snapshot], now: DateTime<Utc>) -> Vec<Snapshot> {
+        let first_kept = self.keep.map_or(0, |keep| snapshots.len().saturating_sub(keep));
+        snapshots.iter().enumerate()
+            .filter(|(index, snapshot)| {
+                let too_many = *index < first_kept;
+                let too_old = self.max_age.is_some_and(|max_age| *index + 1 < snapshots.len() && age(snapshot, now) > max_age);
+                too_many || too_old
+            })
+            .map(|(_, snapshot)| snapshot.clone())
+            .collect()
+    }
+}
+
+/// Parse an age such as "90m", "12h", "7d" or "2w"
+pub fn parse_age(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: i64 = number.parse().context(format!("Invalid age '{}', expected a number and s, m, h, d or w", s))?;
+    let age = match unit {
+        "s" => Duration::try_seconds(number),
+        "m" => Duration::try_minutes(number),
+        "h" => Duration::try_hours(number),
+        "d" => Duration::try_days(number),
+        "w" => Duration::try_weeks(number),
+        _ => bail!("Invalid age '{}', expected a number and s, m, h, d or w", s),
+    };
+    age.ok_or_else(|| anyhow!("Age '{}' is too large", s))
+}
+
+// Time since the snapshot was taken; a time that does not parse counts as now
+fn age(snapshot: &Snapshot, now: DateTime<Utc>) -> Duration {
+    DateTime::parse_from_rfc3339(&snapshot.time)
+        .map(|time| now - time.with_timezone(&Utc))
+        .unwrap_or_else(|_| Duration::zero())
+}
+
+/// Bytes the snapshot takes on disk, None once its directory is gone
+pub fn size(snapshot: &Snapshot) -> Option<u64> {
+    let path = Path::new(&snapshot.path);
+    path.is_dir().then(|| directory_size(path))
+}
+
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else { return 0 };
+    entries.flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+            _ => entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+        })
+        .sum()
+}
+
+/// Delete the files Cloud Hypervisor wrote for the snapshot, and its directory once that is
+/// empty. Anything else in the directory is left alone, and a snapshot that is already gone is
+/// not an error.
+pub fn remove(snapshot: &Snapshot) -> Result<()> {
+    let path = Path::new(&snapshot.path);
+    for file in SNAPSHOT_FILES {
+        match std::fs::remove_file(path.join(file)) {
+            Ok(()) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e).context(format!("Failed to delete snapshot {}", snapshot.path)),
+        }
+    }
+    match std::fs::remove_dir(path) {
+        Ok(()) => Ok(()),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::DirectoryNotEmpty) => Ok(()),
+        Err(e) => Err(e).context(format!("Failed to delete snapshot {}", snapshot.path)),
+    }
+}
+
+/// Short name of the snapshot, its directory name
+pub fn name(snapshot: &Snapshot) -> String {
+    Path::new(&snapshot.path).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| snapshot.path.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("90m").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_age("7d").unwrap(), Duration::days(7));
+        assert_eq!(parse_age("2w").unwrap(), Duration::weeks(2));
+        assert!(parse_age("7").is_err());
+        assert!(parse_age("7y").is_err());
+        assert_eq!(parse_age("99999999999999w").unwrap_err().to_string(), "Age '99999999999999w' is too large");
+        assert!(parse_age("9223372036854775807s").is_err());
+        assert_eq!(parse_age(" 12h ").unwrap(), Duration::hours(12));
+        assert_eq!(parse_age("30s").unwrap(), Duration::seconds(30));
+        assert!(parse_age("h").is_err());
+        assert!(parse_age("-1d").is_err());
+        assert!(parse_age("1.5h").is_err());
+    }
+
+    // Snapshots taken the given hours before `now`, oldest first
+    fn taken(now: DateTime<Utc>, hours_ago: &[i64]) -> Vec<Snapshot> {
+        hours_ago.iter().map(|hours| Snapshot {
+            path: format!("/snapshots/{}h", hours),
+            time: (now - Duration::hours(*hours)).to_rfc3339(),
+        }).collect()
+    }
+
+    fn paths(snapshots: &[Snapshot]) -> Vec<&str> {
+        snapshots.iter().map(|snapshot| snapshot.path.as_str()).collect()
+    }
+
+    #[test]
+    fn max_age_drops_old_snapshots_but_the_newest() {
+        let now = Utc::now();
+        let retention = Retention { keep: None, max_age: Some(parse_age("1d").unwrap()) };
+        let snapshots = taken(now, &[72, 30, 20, 1]);
+        assert_eq!(paths(&retention.expired(&snapshots, now)), ["/snapshots/72h", "/snapshots/30h"]);
+        // The newest stays however old it is
+        let snapshots = taken(now, &[72, 48]);
+        assert_eq!(paths(&retention.expired(&snapshots, now)), ["/snapshots/72h"]);
+        // A time that does not parse counts as now
+        let mut snapshots = taken(now, &[72, 1]);
+        snapshots[0].time = "yesterday".to_string();
+        assert!(retention.expired(&snapshots, now).is_empty());
+    }
+
+    #[test]
+    fn keep_and_max_age_each_drop_snapshots() {
+        let now = Utc::now();
+        let snapshots = taken(now, &[72, 30, 20, 1]);
+        let retention = Retention { keep: Some(3), max_age: None };
+        assert_eq!(paths(&retention.expired(&snapshots, now)), ["/snapshots/72h"]);
+        let retention = Retention { keep: Some(3), max_age: Some(parse_age("25h").unwrap()) };
+        assert_eq!(paths(&retention.expired(&snapshots, now)), ["/snapshots/72h", "/snapshots/30h"]);
+        assert!(Retention::default().is_unlimited());
+        assert!(Retention::default().expired(&snapshots, now).is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::{Result, Context};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Record file name of the unnamed runtime
+const UNNAMED: &str = "hypervisor";
+
+/// A lifecycle state and when the runtime entered it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transition {
+    /// booting, running, ready, stopped or failed
+    pub state: String,
+    /// RFC 3339 UTC timestamp
+    pub time: String,
+}
+
+/// How the latest hypervisor of the runtime ended
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitRecord {
+    /// stopped, restart, upgrade, poweroff, reboot, panic, watchdog, vmm_error, boot_timeout or error
+    pub reason: String,
+    /// The error, for an exit with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub time: String,
+}
+
+/// A snapshot taken of the runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub path: String,
+    pub time: String,
+}
+
+/// What is known about a runtime across hypervisor processes and host reboots
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// Assigned when the record is created and kept from then on
+    pub uuid: String,
+    /// VMs booted for the runtime, restarts included
+    #[serde(default)]
+    pub boot_count: u64,
+    #[serde(default)]
+    pub state: Option<Transition>,
+    #[serde(default)]
+    pub last_exit: Option<ExitRecord>,
+    /// Hypervisor that runs the runtime, None once it exited. Unlike its PID file, the record is
+    /// found by a process with another runtime directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// Oldest first
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+    /// Configuration file the latest VM of a named runtime was booted from, which `up` and `down`
+    /// tell their runtimes apart by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<String>,
+}
+
+impl Record {
+    fn new() -> Self {
+        Self { uuid: uuid::Uuid::new_v4().to_string(), boot_count: 0, state: None, last_exit: None, pid: None, snapshots: Vec::new(), manifest: None }
+    }
+}
+
+/// The record of one runtime in `<state dir>/runtimes/<name>.json`
+#[derive(Debug, Clone)]
+pub struct Store {
+    path: PathBuf,
+}
+
+impl Store {
+    pub fn new(state_dir: &str, runtime: Option<&str>) -> Self {
+        Self { path: Path::new(state_dir).join("runtimes").join(format!("{}.json", runtime.unwrap_or(UNNAMED))) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The record, None when the runtime never ran
+    pub fn load(&self) -> Result<Option<Record>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .context(format!("Failed to parse state record: {}", self.path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(format!("Failed to read state record: {}", self.path.display())),
+        }
+    }
+
+    /// Apply `change` to the record, creating it first. A failure to write is logged as a warning
+    /// but does not stop the operation, so a full disk cannot take a running VM down.
+    pub fn update(&self, change: impl FnOnce(&mut Record)) {
+        if let Err(e) = self.modify(change) {
+            warn!("Failed to write state record {}: {:#}", self.path.display(), e);
+        }
+    }
+
+    /// UUID of the runtime, creating its record first, so every VM booted for it carries the same one
+    pub fn uuid(&self) -> Result<String> {
+        let mut uuid = String::new();
+        self.modify(|record| uuid = record.uuid.clone())?;
+        Ok(uuid)
+    }
+
+    /// Record that the runtime entered `state`
+    pub fn transition(&self, state: &str) {
+        self.update(|record| record.state = Some(Transition { state: state.to_string(), time: now() }));
+    }
+
+    /// Record that hypervisor `pid` took the runtime over and is booting its VM
+    pub fn booting(&self, pid: u32) {
+        self.update(|record| {
+            record.pid = Some(pid);
+            record.state = Some(Transition { state: "booting".to_string(), time: now() });
+        });
+    }
+
+    /// Record how the hypervisor ended and the state that leaves the runtime in
+    pub fn exit(&self, reason: &str, detail: Option<String>) {
+        let state = if detail.is_some() { "failed" } else { "stopped" };
+        self.update(|record| {
+            record.pid = None;
+            record.last_exit = Some(ExitRecord { reason: reason.to_string(), detail, time: now() });
+            record.state = Some(Transition { state: state.to_string(), time: now() });
+        });
+    }
+
+    // The file is rewritten in place rather than replaced, so a hypervisor that gave up root keeps
+    // write access to a record it was handed; the lock keeps `snapshot` and the hypervisor apart
+    fn modify(&self, change: impl FnOnce(&mut Record)) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create state record directory: {}", parent.display()))?;
+        }
+        let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true).mode(0o644).open(&self.path)
+            .context(format!("Failed to open state record: {}", self.path.display()))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error()).context(format!("Failed to lock state record: {}", self.path.display()));
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut record = if contents.trim().is_empty() {
+            Record::new()
+        } else {
+            serde_json::from_str(&contents).context(format!("Failed to parse state record: {}", self.path.display()))?
+        };
+        change(&mut record);
+
+        let json = serde_json::to_string_pretty(&record)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(0)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Why and since when the host is drained
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Drain {
+    pub time: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// What is known about the host rather than one of its runtimes, in `<state dir>/host.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct HostRecord {
+    /// Set by `drain` and cleared by `uncordon`; no VM boots in between
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drained: Option<Drain>,
+}
+
+fn host_record_path(state_dir: &str) -> PathBuf {
+    Path::new(state_dir).join("host.json")
+}
+
+fn load_host_record(state_dir: &str) -> Result<HostRecord> {
+    let path = host_record_path(state_dir);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents).context(format!("Failed to parse host record: {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HostRecord::default()),
+        Err(e) => Err(e).context(format!("Failed to read host record: {}", path.display())),
+    }
+}
+
+// Written next to the record and renamed over it, so a reader never sees half a record
+fn save_host_record(state_dir: &str, record: &HostRecord) -> Result<()> {
+    let path = host_record_path(state_dir);
+    std::fs::create_dir_all(state_dir).context(format!("Failed to create state directory: {}", state_dir))?;
+    let temporary = path.with_extension("json.tmp");
+    std::fs::write(&temporary, serde_json::to_string_pretty(record)?)
+        .context(format!("Failed to write host record: {}", temporary.display()))?;
+    std::fs::rename(&temporary, &path).context(format!("Failed to write host record: {}", path.display()))
+}
+
+/// The drain of the host, None when VMs may boot
+pub fn drained(state_dir: &str) -> Result<Option<Drain>> {
+    Ok(load_host_record(state_dir)?.drained)
+}
+
+/// Mark the host as drained; a host that already is keeps the time and reason of the first drain
+pub fn drain(state_dir: &str, reason: Option<String>) -> Result<()> {
+    let mut record = load_host_record(state_dir)?;
+    if record.drained.is_none() {
+        record.drained = Some(Drain { time: now(), reason });
+    }
+    save_host_record(state_dir, &record)
+}
+
+/// Let VMs boot on the host again; the drain it lifted, None when the host was not drained
+pub fn uncordon(state_dir: &str) -> Result<Option<Drain>> {
+    let mut record = load_host_record(state_dir)?;
+    let drained = record.drained.take();
+    if drained.is_some() {
+        save_host_record(state_dir, &record)?;
+    }
+    Ok(drained)
+}
+
+/// Names of the runtimes with a record in `state_dir`; the unnamed runtime is None
+pub fn runtimes(state_dir: &str) -> Result<Vec<Option<String>>> {
+    let dir = Path::new(state_dir).join("runtimes");
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("Failed to read {}", dir.display())),
+    };
+    let mut names: Vec<Option<String>> = entries.flatten()
+        .filter_map(|entry| entry.file_name().to_string_lossy().strip_suffix(".json").map(str::to_string))
+        .map(|name| (name != UNNAMED).then_some(name))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
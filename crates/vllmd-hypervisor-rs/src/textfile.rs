@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::agent::{self, AgentClient};
+use crate::control;
+use crate::doctor::process_status;
+use crate::store::Store;
+use crate::top;
+
+/// Labels every metric carries, which the labels of a runtime cannot take on its info metric
+const RESERVED_LABELS: &[&str] = &["runtime", "uuid"];
+
+/// The runtime the metrics describe and where the hypervisor process finds them
+pub struct Source {
+    /// Name of the runtime, the `runtime` label of every metric
+    pub runtime: String,
+    pub uuid: String,
+    /// Labels of the runtime, exported on `vllmd_runtime_info`
+    pub labels: BTreeMap<String, String>,
+    pub api_socket_path: String,
+    /// None without a vsock device, and so without a guest agent to ask
+    pub vsock_socket_path: Option<String>,
+    pub state_file_path: String,
+    pub store: Store,
+}
+
+/// The `.prom` file of a runtime in the directory of the node_exporter textfile collector. It is
+/// rewritten every interval while the VM runs and deleted when this is dropped, so a runtime
+/// that stopped leaves no stale metrics behind.
+pub struct Textfile {
+    path: PathBuf,
+    // Set once the file is deleted, so a write still under way does not bring it back
+    removed: Arc<Mutex<bool>>,
+}
+
+impl Textfile {
+    /// Write the file named `name` in `dir` now and then every `interval`
+    pub fn spawn(dir: &str, name: &str, interval: Duration, source: Source) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .context(format!("Failed to create textfile directory: {}", dir))?;
+        let path = Path::new(dir).join(format!("{}.prom", name));
+        write(&path, &render(&source))?;
+
+        let removed = Arc::new(Mutex::new(false));
+        let (thread_path, thread_removed) = (path.clone(), removed.clone());
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let metrics = render(&source);
+            let removed = thread_removed.lock().unwrap_or_else(|e| e.into_inner());
+            if *removed {
+                return;
+            }
+            if let Err(e) = write(&thread_path, &metrics) {
+                warn!("{:#}", e);
+            }
+        });
+        Ok(Self { path, removed })
+    }
+}
+
+impl Drop for Textfile {
+    fn drop(&mut self) {
+        *self.removed.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// The collector reads the directory at any time, so the file is written beside it under a name
+// without the .prom extension and renamed over it
+fn write(path: &Path, metrics: &str) -> Result<()> {
+    let partial = path.with_extension("prom.partial");
+    std::fs::write(&partial, metrics)
+        .context(format!("Failed to write metrics file: {}", partial.display()))?;
+    std::fs::rename(&partial, path)
+        .context(format!("Failed to replace metrics file: {}", path.display()))
+}
+
+/// The metrics of the runtime in the Prometheus text format. What the VMM or the guest agent
+/// cannot tell right now is left out rather than reported as zero.
+pub fn render(source: &Source) -> String {
+    let mut metrics = Metrics { runtime: &source.runtime, text: String::new() };
+
+    let mut info = vec![("uuid", source.uuid.clone())];
+    info.extend(source.labels.iter()
+        .filter(|(key, _)| !RESERVED_LABELS.contains(&key.as_str()))
+        .map(|(key, value)| (key.as_str(), value.clone())));
+    metrics.family("vllmd_runtime_info", "gauge", "Identity and labels of the runtime, always 1", &[(info, 1.0)]);
+
+    if let Ok(state) = std::fs::read_to_string(&source.state_file_path) {
+        metrics.family("vllmd_runtime_state", "gauge", "State of the runtime, 1 for the one it is in",
+                       &[(vec![("state", state.trim().to_string())], 1.0)]);
+    }
+    if let Ok(Some(record)) = source.store.load() {
+        metrics.gauge("vllmd_runtime_boots_total", "counter", "VMs booted for the runtime, restarts included", record.boot_count as f64);
+    }
+
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    let (cpu_ticks, vcpu_ticks) = top::cpu_ticks(std::process::id());
+    metrics.gauge("vllmd_runtime_cpu_seconds_total", "counter", "CPU time of the hypervisor process", cpu_ticks as f64 / ticks_per_second);
+    metrics.gauge("vllmd_runtime_vcpu_seconds_total", "counter", "CPU time of the vCPU threads", vcpu_ticks as f64 / ticks_per_second);
+    if let Some(rss) = process_status("self", "VmRSS").and_then(|rss| rss.trim_end_matches("kB").trim().parse::<u64>().ok()) {
+        metrics.gauge("vllmd_runtime_resident_memory_bytes", "gauge", "Resident memory of the hypervisor process, guest memory included", (rss * 1024) as f64);
+    }
+
+    if let Ok(details) = control::info(&source.api_socket_path) {
+        let memory = details.memory.size + details.memory.hotplugged_size;
+        metrics.gauge("vllmd_runtime_vcpus", "gauge", "vCPUs of the VM", details.vcpus.boot as f64);
+        metrics.gauge("vllmd_runtime_max_vcpus", "gauge", "vCPUs the VM can be resized to", details.vcpus.max as f64);
+        metrics.gauge("vllmd_runtime_memory_bytes", "gauge", "Guest memory, hotplugged memory included", memory as f64);
+        metrics.gauge("vllmd_runtime_balloon_bytes", "gauge", "Guest memory the balloon holds", memory.saturating_sub(details.memory.actual_size) as f64);
+    }
+    if let Ok(devices) = control::counters(&source.api_socket_path) {
+        let samples: Vec<_> = devices.into_iter()
+            .flat_map(|(device, counters)| counters.into_iter()
+                .map(move |(counter, value)| (vec![("device", device.clone()), ("counter", counter)], value as f64)))
+            .collect();
+        metrics.family("vllmd_runtime_device_counter_total", "counter", "Counters the VMM keeps for each disk and network device", &samples);
+    }
+
+    if let Some(guest) = source.vsock_socket_path.as_deref().and_then(guest_metrics) {
+        metrics.gauge("vllmd_guest_uptime_seconds", "gauge", "Time since the guest booted", guest.uptime_secs);
+        metrics.gauge("vllmd_guest_memory_total_bytes", "gauge", "Memory of the guest as its kernel sees it", (guest.memory_total_kb * 1024) as f64);
+        metrics.gauge("vllmd_guest_memory_available_bytes", "gauge", "Memory the guest has available", (guest.memory_available_kb * 1024) as f64);
+        metrics.family("vllmd_guest_load_average", "gauge", "Load average of the guest",
+                       &[(vec![("minutes", "1".to_string())], guest.load_average[0]),
+                         (vec![("minutes", "5".to_string())], guest.load_average[1]),
+                         (vec![("minutes", "15".to_string())], guest.load_average[2])]);
+
+        let gpu = |value: fn(&agent::GuestGpu) -> Option<f64>| -> Vec<_> {
+            guest.gpus.iter()
+                .filter_map(|gpu| value(gpu).map(|value| (vec![("gpu", gpu.index.to_string()), ("pci_bus_id", gpu.pci_bus_id.clone()),
+                                                                ("name", gpu.name.clone())], value)))
+                .collect()
+        };
+        metrics.family("vllmd_guest_gpu_utilization_ratio", "gauge", "Utilization of each GPU the guest drives",
+                       &gpu(|gpu| gpu.utilization_percent.map(|percent| percent / 100.0)));
+        metrics.family("vllmd_guest_gpu_memory_used_bytes", "gauge", "GPU memory in use",
+                       &gpu(|gpu| gpu.memory_used_bytes.map(|bytes| bytes as f64)));
+        metrics.family("vllmd_guest_gpu_memory_total_bytes", "gauge", "GPU memory",
+                       &gpu(|gpu| gpu.memory_total_bytes.map(|bytes| bytes as f64)));
+        metrics.family("vllmd_guest_gpu_temperature_celsius", "gauge", "GPU temperature",
+                       &gpu(|gpu| gpu.temperature_celsius));
+        metrics.family("vllmd_guest_gpu_power_watts", "gauge", "GPU power draw",
+                       &gpu(|gpu| gpu.power_watts));
+    }
+
+    metrics.text
+}
+
+// What the guest agent reports, if it answers
+fn guest_metrics(vsock_socket_path: &str) -> Option<agent::GuestMetrics> {
+    if !Path::new(vsock_socket_path).exists() {
+        return None;
+    }
+    AgentClient::connect_timeout(vsock_socket_path, agent::AGENT_PORT, Some(top::AGENT_TIMEOUT)).ok()?
+        .metrics().ok()
+}
+
+// Metric families of one runtime in the text exposition format
+struct Metrics<'a> {
+    runtime: &'a str,
+    text: String,
+}
+
+impl Metrics<'_> {
+    fn gauge(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+        self.family(name, kind, help, &[(Vec::new(), value)]);
+    }
+
+    // The help and type of a family and one sample per label set; a family without samples is
+    // left out altogether
+    fn family(&mut self, name: &str, kind: &str, help: &str, samples: &[(Vec<(&str, String)>, f64)]) {
+        if samples.is_empty() {
+            return;
+        }
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let mut pairs = vec![format!("runtime=\"{}\"", escape(self.runtime))];
+            pairs.extend(labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, escape(value))));
+            let _ = writeln!(self.text, "{}{{{}}} {}", name, pairs.join(","), value);
+        }
+    }
+}
+
+// Label values escape backslashes, double quotes and line feeds
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
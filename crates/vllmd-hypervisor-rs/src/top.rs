@@ -0,0 +1,241 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::agent::{self, AgentClient, GuestGpu};
+use crate::control;
+use crate::doctor::process_status;
+
+/// How long a sample waits for the guest agent; a guest without one must not stall the view
+pub const AGENT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A registered runtime and the files `start` keeps for it
+pub struct Target {
+    pub name: String,
+    pub pid_file: PathBuf,
+}
+
+impl Target {
+    fn runtime_file(&self, extension: &str) -> PathBuf {
+        self.pid_file.with_extension(extension)
+    }
+
+    // PID of the hypervisor, if it is alive
+    fn pid(&self) -> Option<u32> {
+        let pid: u32 = std::fs::read_to_string(&self.pid_file).ok()?.trim().parse().ok()?;
+        std::path::Path::new(&format!("/proc/{}", pid)).exists().then_some(pid)
+    }
+}
+
+// Cumulative counters of one runtime at one point in time
+struct Sample {
+    at: Instant,
+    pid: u32,
+    /// CPU time of all threads and of the vCPU threads, in clock ticks
+    cpu_ticks: u64,
+    vcpu_ticks: u64,
+    /// Summed over all disks or network devices; None without an API socket
+    counters: Option<BTreeMap<String, u64>>,
+    /// GPUs as the guest agent reports them; None without an agent
+    gpus: Option<Vec<GuestGpu>>,
+}
+
+// One line of the view
+struct Row {
+    name: String,
+    pid: Option<u32>,
+    state: String,
+    cpu: Option<f64>,
+    vcpu: Option<f64>,
+    rss: Option<u64>,
+    balloon: Option<u64>,
+    rates: Option<BTreeMap<String, f64>>,
+    /// Mean utilization and summed used memory of the guest GPUs
+    gpu: Option<f64>,
+    gpu_memory: Option<u64>,
+}
+
+/// User plus system time of every thread of `pid`, and of those among them that run vCPUs, in
+/// clock ticks
+pub fn cpu_ticks(pid: u32) -> (u64, u64) {
+    let (mut total, mut vcpu) = (0, 0);
+    let Ok(tasks) = std::fs::read_dir(format!("/proc/{}/task", pid)) else {
+        return (0, 0);
+    };
+    for task in tasks.flatten() {
+        let Ok(stat) = std::fs::read_to_string(task.path().join("stat")) else { continue };
+        // The thread name is in parentheses and may contain spaces; fields 14 and 15 follow it
+        let Some((head, fields)) = stat.rsplit_once(')') else { continue };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let ticks: u64 = fields.get(11..13).unwrap_or_default().iter().filter_map(|field| field.parse::<u64>().ok()).sum();
+        total += ticks;
+        if head.split_once('(').is_some_and(|(_, name)| name.starts_with("vcpu")) {
+            vcpu += ticks;
+        }
+    }
+    (total, vcpu)
+}
+
+// Disk and network counters of the VM, summed per counter name over its devices
+fn device_counters(socket: &str) -> Option<BTreeMap<String, u64>> {
+    let mut totals = BTreeMap::new();
+    for counters in control::counters(socket).ok()?.into_values() {
+        for (counter, value) in counters {
+            *totals.entry(counter).or_insert(0) += value;
+        }
+    }
+    Some(totals)
+}
+
+// GPUs the guest agent reports, if the runtime has a vsock device and the agent answers
+fn guest_gpus(target: &Target) -> Option<Vec<GuestGpu>> {
+    let socket = target.runtime_file("vsock");
+    if !socket.exists() {
+        return None;
+    }
+    let mut client = AgentClient::connect_timeout(&socket.to_string_lossy(), agent::AGENT_PORT, Some(AGENT_TIMEOUT)).ok()?;
+    client.metrics().ok().map(|metrics| metrics.gpus)
+}
+
+fn sample(target: &Target, pid: u32) -> Sample {
+    let (cpu_ticks, vcpu_ticks) = cpu_ticks(pid);
+    Sample {
+        at: Instant::now(),
+        pid,
+        cpu_ticks,
+        vcpu_ticks,
+        counters: device_counters(&target.runtime_file("sock").to_string_lossy()),
+        gpus: guest_gpus(target),
+    }
+}
+
+fn row(target: &Target, previous: Option<&Sample>, current: Option<&Sample>) -> Row {
+    let mut row = Row {
+        name: target.name.clone(),
+        pid: current.map(|sample| sample.pid),
+        state: "stopped".to_string(),
+        cpu: None,
+        vcpu: None,
+        rss: None,
+        balloon: None,
+        rates: None,
+        gpu: None,
+        gpu_memory: None,
+    };
+    let Some(current) = current else { return row };
+
+    row.state = std::fs::read_to_string(target.runtime_file("state"))
+        .map(|state| state.trim().to_string())
+        .unwrap_or_else(|_| "starting".to_string());
+    row.rss = process_status(&current.pid.to_string(), "VmRSS")
+        .and_then(|rss| rss.trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kib| kib * 1024);
+    row.balloon = control::info(&target.runtime_file("sock").to_string_lossy()).ok()
+        .map(|details| (details.memory.size + details.memory.hotplugged_size).saturating_sub(details.memory.actual_size));
+    if let Some(gpus) = current.gpus.as_ref().filter(|gpus| !gpus.is_empty()) {
+        let utilization: Vec<f64> = gpus.iter().filter_map(|gpu| gpu.utilization_percent).collect();
+        row.gpu = (!utilization.is_empty()).then(|| utilization.iter().sum::<f64>() / utilization.len() as f64);
+        row.gpu_memory = gpus.iter().map(|gpu| gpu.memory_used_bytes).sum();
+    }
+
+    // Utilization and rates need the previous sample of the same process
+    let Some(previous) = previous.filter(|previous| previous.pid == current.pid) else { return row };
+    let elapsed = current.at.duration_since(previous.at).as_secs_f64();
+    if elapsed <= 0.0 {
+        return row;
+    }
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    let percent = |now: u64, before: u64| now.saturating_sub(before) as f64 / ticks_per_second / elapsed * 100.0;
+    row.cpu = Some(percent(current.cpu_ticks, previous.cpu_ticks));
+    row.vcpu = Some(percent(current.vcpu_ticks, previous.vcpu_ticks));
+    if let (Some(now), Some(before)) = (&current.counters, &previous.counters) {
+        row.rates = Some(now.iter()
+            .map(|(counter, value)| {
+                let delta = value.saturating_sub(before.get(counter).copied().unwrap_or(0));
+                (counter.clone(), delta as f64 / elapsed)
+            })
+            .collect());
+    }
+    row
+}
+
+// Byte count or rate with a binary unit suffix
+fn human(bytes: f64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{:.0}{}", value, units[unit]) } else { format!("{:.1}{}", value, units[unit]) }
+}
+
+fn render(rows: &[Row], interval: Duration) -> String {
+    let dash = || "-".to_string();
+    let mut out = format!("vllmd-hypervisor top - {} - {} runtime(s), every {:.1}s\n\n",
+                          chrono::Local::now().format("%H:%M:%S"), rows.len(), interval.as_secs_f64());
+    out.push_str(&format!("{:<20} {:>8} {:<8} {:>6} {:>6} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9} {:>6} {:>8}\n",
+                          "NAME", "PID", "STATE", "CPU%", "VCPU%", "RSS", "BALLOON", "DISK-R/s", "DISK-W/s", "NET-RX/s", "NET-TX/s",
+                          "GPU%", "GPU-MEM"));
+    for row in rows {
+        let rate = |counter: &str| row.rates.as_ref()
+            .and_then(|rates| rates.get(counter))
+            .map(|rate| human(*rate))
+            .unwrap_or_else(dash);
+        out.push_str(&format!("{:<20} {:>8} {:<8} {:>6} {:>6} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9} {:>6} {:>8}\n",
+                              row.name,
+                              row.pid.map(|pid| pid.to_string()).unwrap_or_else(dash),
+                              row.state,
+                              row.cpu.map(|cpu| format!("{:.1}", cpu)).unwrap_or_else(dash),
+                              row.vcpu.map(|vcpu| format!("{:.1}", vcpu)).unwrap_or_else(dash),
+                              row.rss.map(|rss| human(rss as f64)).unwrap_or_else(dash),
+                              row.balloon.map(|balloon| human(balloon as f64)).unwrap_or_else(dash),
+                              rate("read_bytes"), rate("write_bytes"), rate("rx_bytes"), rate("tx_bytes"),
+                              row.gpu.map(|gpu| format!("{:.1}", gpu)).unwrap_or_else(dash),
+                              row.gpu_memory.map(|memory| human(memory as f64)).unwrap_or_else(dash)));
+    }
+    out
+}
+
+/// Refresh a view of the CPU, memory, disk, network and guest GPU use of every target each
+/// `interval`, until interrupted. CPU utilization is a percentage of one host CPU, so a busy 4 vCPU
+/// guest shows up to 400%; GPU utilization is the mean over the GPUs of the guest. With `once`,
+/// print a single view after the first interval and return.
+pub fn run(targets: &[Target], interval: Duration, once: bool) -> Result<()> {
+    let mut previous: Vec<Option<Sample>> = targets.iter().map(|_| None).collect();
+    let mut stdout = std::io::stdout();
+    let mut first = true;
+
+    loop {
+        let current: Vec<Option<Sample>> = targets.iter()
+            .map(|target| target.pid().map(|pid| sample(target, pid)))
+            .collect();
+
+        if !first {
+            let rows: Vec<Row> = targets.iter()
+                .zip(previous.iter().zip(current.iter()))
+                .map(|(target, (previous, current))| row(target, previous.as_ref(), current.as_ref()))
+                .collect();
+            let view = render(&rows, interval);
+            if once {
+                print!("{}", view);
+                return Ok(());
+            }
+            if stdout.is_terminal() {
+                // Clear the terminal and draw from the top left
+                write!(stdout, "\x1b[H\x1b[2J{}", view)?;
+            } else {
+                // Piped output gets one view after the other
+                writeln!(stdout, "{}", view)?;
+            }
+            stdout.flush()?;
+        }
+
+        first = false;
+        previous = current;
+        std::thread::sleep(interval);
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{Result, bail, Context};
+use log::{info, warn, debug};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long swtpm gets to create its control socket
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A software TPM (swtpm) serving one VM over a unix socket
+pub struct Swtpm {
+    child: Child,
+    socket_path: String,
+}
+
+impl Swtpm {
+    /// Start swtpm with its persistent state in `state_dir` and wait for `socket_path` to appear
+    pub fn spawn(state_dir: &str, socket_path: &str) -> Result<Self> {
+        std::fs::create_dir_all(state_dir)
+            .context(format!("Failed to create TPM state directory: {}", state_dir))?;
+
+        if Path::new(socket_path).exists() {
+            std::fs::remove_file(socket_path)
+                .context(format!("Failed to remove stale TPM socket: {}", socket_path))?;
+        }
+
+        info!("Starting swtpm with state in {}", state_dir);
+
+        // --terminate makes swtpm exit once the VMM closes the control channel
+        let mut child = Command::new("swtpm")
+            .args(["socket", "--tpm2", "--terminate"])
+            .arg("--tpmstate").arg(format!("dir={}", state_dir))
+            .arg("--ctrl").arg(format!("type=unixio,path={}", socket_path))
+            .stdin(Stdio::null())
+            .spawn()
+            .context("Failed to run swtpm; is it installed?")?;
+
+        let started = Instant::now();
+        while !Path::new(socket_path).exists() {
+            if let Some(status) = child.try_wait().context("Failed to check on swtpm")? {
+                bail!("swtpm exited with {} before creating {}", status, socket_path);
+            }
+            if started.elapsed() > SOCKET_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("swtpm did not create {} within {:?}", socket_path, SOCKET_TIMEOUT);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        debug!("swtpm (PID {}) listening on {}", child.id(), socket_path);
+
+        Ok(Self {
+            child,
+            socket_path: socket_path.to_string(),
+        })
+    }
+
+    /// Stop swtpm if it is still running and remove its socket
+    pub fn stop(&mut self) {
+        match self.child.try_wait() {
+            Ok(Some(_)) => {},
+            _ => {
+                info!("Stopping swtpm");
+                if let Err(e) = self.child.kill() {
+                    warn!("Failed to stop swtpm: {}", e);
+                }
+                let _ = self.child.wait();
+            }
+        }
+
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Place for the swtpm of a VM; stopping it removes the swtpm held and its socket
+#[derive(Clone, Default)]
+pub struct SwtpmSlot(Arc<Mutex<Option<Swtpm>>>);
+
+impl SwtpmSlot {
+    /// Keep `swtpm`, stopping the one held before
+    pub fn set(&self, swtpm: Swtpm) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(swtpm);
+    }
+
+    /// Stop the swtpm held, if any, and remove its socket
+    pub fn stop(&self) {
+        let swtpm = self.0.lock().unwrap_or_else(PoisonError::into_inner).take();
+        drop(swtpm);
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{Result, bail, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+
+use crate::audit::AuditLog;
+
+/// Tool that checks detached signatures
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureTool {
+    /// `minisign -V`, signatures in `<file>.minisig`
+    #[default]
+    Minisign,
+    /// `cosign verify-blob`, signatures in `<file>.sig`
+    Cosign,
+}
+
+impl SignatureTool {
+    /// Parse the value of an environment variable
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "minisign" => Ok(SignatureTool::Minisign),
+            "cosign" => Ok(SignatureTool::Cosign),
+            _ => bail!("Unknown signature tool '{}', expected minisign or cosign", s),
+        }
+    }
+
+    /// Default location of the detached signature of `path`
+    pub fn signature_path(&self, path: &str) -> String {
+        match self {
+            SignatureTool::Minisign => format!("{}.minisig", path),
+            SignatureTool::Cosign => format!("{}.sig", path),
+        }
+    }
+}
+
+/// Signatures the kernel and system image must carry before boot (`[global.trust]`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrustPolicy {
+    /// Tool used to check signatures
+    #[serde(default)]
+    pub tool: SignatureTool,
+
+    /// Public key files; a signature from any of them is accepted
+    pub public_keys: Vec<String>,
+
+    /// Refuse to boot when verification fails; otherwise only record the failure
+    #[serde(default = "default_enforce")]
+    pub enforce: bool,
+
+    /// cosign only: accept signatures without a transparency log entry
+    #[serde(default)]
+    pub offline: bool,
+}
+
+fn default_enforce() -> bool { true }
+
+impl TrustPolicy {
+    /// Verify the detached signature of `path` (at `signature`, or the tool's default location),
+    /// returning the public key that signed it
+    pub fn verify(&self, path: &str, signature: Option<&str>) -> Result<String> {
+        if self.public_keys.is_empty() {
+            bail!("Trust policy has no public keys");
+        }
+
+        let signature = signature.map(String::from).unwrap_or_else(|| self.tool.signature_path(path));
+        if !Path::new(&signature).exists() {
+            bail!("Signature {} for {} does not exist", signature, path);
+        }
+
+        let mut failures = Vec::new();
+        for public_key in &self.public_keys {
+            let mut command = match self.tool {
+                SignatureTool::Minisign => {
+                    let mut command = Command::new("minisign");
+                    command.args(["-V", "-q", "-p", public_key, "-m", path, "-x", &signature]);
+                    command
+                },
+                SignatureTool::Cosign => {
+                    let mut command = Command::new("cosign");
+                    command.args(["verify-blob", "--key", public_key, "--signature", &signature]);
+                    if self.offline {
+                        command.arg("--insecure-ignore-tlog=true");
+                    }
+                    command.arg(path);
+                    command
+                },
+            };
+
+            let output = command.output()
+                .context(format!("Failed to run {:?}; is it installed?", self.tool))?;
+            if output.status.success() {
+                return Ok(public_key.clone());
+            }
+            failures.push(format!("{}: {}", public_key, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+
+        bail!("No trusted key verifies {} ({})", path, failures.join("; "))
+    }
+
+    /// Verify a boot artifact and record the result in the audit log, unless there is none, as
+    /// for a dry run. Fails only when the policy is enforced.
+    pub fn check(&self, kind: &str, path: &str, signature: Option<&str>, audit: Option<&AuditLog>) -> Result<()> {
+        let record = |result: &str, detail: String| if let Some(audit) = audit {
+            audit.record("verify_signature", serde_json::json!({
+                "kind": kind,
+                "path": path,
+                "tool": format!("{:?}", self.tool).to_lowercase(),
+                "result": result,
+                "detail": detail,
+            }));
+        };
+        match self.verify(path, signature) {
+            Ok(public_key) => {
+                info!("Signature verified: {} {} signed by {}", kind, path, public_key);
+                record("verified", public_key);
+                Ok(())
+            },
+            Err(e) if self.enforce => {
+                warn!("Signature rejected: {} {}: {:#}", kind, path, e);
+                record("rejected", format!("{:#}", e));
+                Err(e.context(format!("Refusing to boot unverified {}", kind)))
+            },
+            Err(e) => {
+                warn!("Signature not verified, booting anyway: {} {}: {:#}", kind, path, e);
+                record("unverified", format!("{:#}", e));
+                Ok(())
+            },
+        }
+    }
+}
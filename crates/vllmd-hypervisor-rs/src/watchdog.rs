@@ -0,0 +1,245 @@
+use crate::dump;
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often the vCPU threads are looked at for a reset, and the guest log for a panic
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the guest kernel prints when it panics
+const PANIC_MESSAGE: &[u8] = b"Kernel panic - not syncing";
+
+/// What the host does once the guest watchdog has reset the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogAction {
+    /// Let the guest boot again; it is not ready until it reports ready again
+    Reset,
+    /// Shut the VM down and exit with an error, leaving recovery to systemd or an operator
+    Crash,
+}
+
+impl WatchdogAction {
+    /// Parse the value of an environment variable; `off` disables the watchdog
+    pub fn parse(s: &str) -> Result<Option<Self>> {
+        match s.trim().to_lowercase().as_str() {
+            "off" | "" => Ok(None),
+            "reset" => Ok(Some(WatchdogAction::Reset)),
+            "crash" => Ok(Some(WatchdogAction::Crash)),
+            _ => bail!("Unknown watchdog action '{}', expected off, reset or crash", s),
+        }
+    }
+}
+
+/// What the host does when the guest reboots itself (`on_reboot`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RebootPolicy {
+    /// Let the guest boot again in the same VM
+    #[default]
+    Restart,
+    /// Shut the VM down as if the guest had powered off
+    Stop,
+}
+
+impl RebootPolicy {
+    /// Parse the value of an environment variable
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "restart" => Ok(RebootPolicy::Restart),
+            "stop" => Ok(RebootPolicy::Stop),
+            _ => bail!("Unknown reboot policy '{}', expected restart or stop", s),
+        }
+    }
+}
+
+/// What changed about the vCPU threads since the last check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcpuChange {
+    /// The guest reset: the vCPUs it booted with run on new threads
+    Reset,
+    /// vCPUs were added or removed, as `remote resize --vcpus` does, without a reset
+    Resized,
+}
+
+/// Notices the guest resetting without the host asking for it. The VMM handles a watchdog
+/// expiry, like a reboot from inside the guest, by resetting the VM on its own. A reset replaces
+/// every vCPU thread, so new threads for the `vcpu<N>` the VM booted with mean one happened.
+/// vCPUs hotplugged above those come and go with resizes, which another process may ask for, and
+/// only count as a resize.
+pub struct ResetDetector {
+    boot_vcpus: u8,
+    threads: BTreeMap<u8, u32>,
+    checked: Instant,
+}
+
+impl ResetDetector {
+    /// Start from the vCPU threads of the running VM, which booted with `boot_vcpus` of them
+    pub fn new(boot_vcpus: u8) -> Self {
+        Self { boot_vcpus, threads: vcpu_threads(), checked: Instant::now() }
+    }
+
+    /// Take the current vCPU threads as they are, after a reboot the host asked for
+    pub fn rebase(&mut self) {
+        self.threads = vcpu_threads();
+    }
+
+    /// How the vCPUs changed since the last call; cheap to call on every loop iteration
+    pub fn check(&mut self) -> Option<VcpuChange> {
+        if self.checked.elapsed() < CHECK_INTERVAL {
+            return None;
+        }
+        self.checked = Instant::now();
+
+        let threads = vcpu_threads();
+        // Between tearing down the old vCPUs and creating the new ones there are none
+        if threads.is_empty() {
+            return None;
+        }
+        let change = compare(&self.threads, &threads, self.boot_vcpus);
+        self.threads = threads;
+        change
+    }
+}
+
+// A boot vCPU on another thread than before is a reset; any other difference is a resize. A boot
+// vCPU that is gone was unplugged, which only a resize below the boot count does.
+fn compare(before: &BTreeMap<u8, u32>, after: &BTreeMap<u8, u32>, boot_vcpus: u8) -> Option<VcpuChange> {
+    if before.is_empty() {
+        return None;
+    }
+    let replaced = after.iter()
+        .any(|(index, tid)| *index < boot_vcpus && before.get(index).is_some_and(|before| before != tid));
+    if replaced {
+        Some(VcpuChange::Reset)
+    } else if before != after {
+        Some(VcpuChange::Resized)
+    } else {
+        None
+    }
+}
+
+// Thread IDs of the vCPUs by index, from their `vcpu<N>` names
+fn vcpu_threads() -> BTreeMap<u8, u32> {
+    dump::threads().into_iter()
+        .filter_map(|thread| Some((thread.name.strip_prefix("vcpu")?.parse::<u8>().ok()?, thread.tid)))
+        .collect()
+}
+
+/// Notices a guest kernel panic in the guest log. A panicked kernel halts, or resets the VM with
+/// `panic=<secs>` on its command line, rather than powering off, so the VMM never reports it.
+pub struct PanicWatch {
+    path: PathBuf,
+    offset: u64,
+    checked: Instant,
+}
+
+impl PanicWatch {
+    /// Watch the guest log at `path` from where it ends now; what it holds is of an earlier VM
+    pub fn new(path: &Path) -> Self {
+        let offset = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        Self { path: path.to_path_buf(), offset, checked: Instant::now() }
+    }
+
+    /// Whether the guest panicked; cheap to call on every loop iteration
+    pub fn check(&mut self) -> bool {
+        if self.checked.elapsed() < CHECK_INTERVAL {
+            return false;
+        }
+        self.checked = Instant::now();
+        self.panicked()
+    }
+
+    /// Whether the guest log shows a panic, reading it right away
+    pub fn panicked(&mut self) -> bool {
+        guest_panicked(&self.path, &mut self.offset)
+    }
+}
+
+// Whether the log at `path` shows a kernel panic after `offset`, which moves past what was read.
+// The tail is read again next time, so a message the guest wrote in two pieces is still found.
+fn guest_panicked(path: &Path, offset: &mut u64) -> bool {
+    let Ok(mut file) = File::open(path) else { return false };
+    // A log truncated since the last read starts over
+    if file.metadata().is_ok_and(|metadata| metadata.len() < *offset) {
+        *offset = 0;
+    }
+    let mut contents = Vec::new();
+    if file.seek(SeekFrom::Start(*offset)).and_then(|_| file.read_to_end(&mut contents)).is_err() {
+        return false;
+    }
+    if contents.windows(PANIC_MESSAGE.len()).any(|window| window == PANIC_MESSAGE) {
+        return true;
+    }
+    *offset += contents.len().saturating_sub(PANIC_MESSAGE.len() - 1) as u64;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threads(pairs: &[(u8, u32)]) -> BTreeMap<u8, u32> {
+        pairs.iter().copied().collect()
+    }
+
+    #[test]
+    fn new_boot_vcpu_threads_are_a_reset() {
+        let before = threads(&[(0, 100), (1, 101)]);
+        assert_eq!(compare(&before, &before, 2), None);
+        assert_eq!(compare(&before, &threads(&[(0, 200), (1, 201)]), 2), Some(VcpuChange::Reset));
+        // Part way through creating the new vCPUs
+        assert_eq!(compare(&before, &threads(&[(0, 200)]), 2), Some(VcpuChange::Reset));
+        // Nothing to compare against yet
+        assert_eq!(compare(&BTreeMap::new(), &before, 2), None);
+    }
+
+    #[test]
+    fn hotplug_is_a_resize() {
+        let before = threads(&[(0, 100), (1, 101)]);
+        let grown = threads(&[(0, 100), (1, 101), (2, 150), (3, 151)]);
+        assert_eq!(compare(&before, &grown, 2), Some(VcpuChange::Resized));
+        assert_eq!(compare(&grown, &before, 2), Some(VcpuChange::Resized));
+        // Unplugged and plugged again between two checks
+        assert_eq!(compare(&grown, &threads(&[(0, 100), (1, 101), (2, 160), (3, 161)]), 2), Some(VcpuChange::Resized));
+        // Below the boot count
+        assert_eq!(compare(&before, &threads(&[(0, 100)]), 2), Some(VcpuChange::Resized));
+        // A reset after growing replaces the boot vCPUs as well
+        assert_eq!(compare(&grown, &threads(&[(0, 200), (1, 201), (2, 202), (3, 203)]), 2), Some(VcpuChange::Reset));
+    }
+
+    #[test]
+    fn guest_panicked_reads_what_was_appended() {
+        let path = std::env::temp_dir().join(format!("vllmd-panic-{}.log", std::process::id()));
+        let append = |text: &str| {
+            use std::io::Write;
+            std::fs::OpenOptions::new().create(true).append(true).open(&path).unwrap().write_all(text.as_bytes()).unwrap();
+        };
+        let _ = std::fs::remove_file(&path);
+
+        // No log yet
+        let mut offset = 0;
+        assert!(!guest_panicked(&path, &mut offset));
+
+        // A panic of an earlier VM is left behind
+        append("[    1.0] Kernel panic - not syncing: VFS: Unable to mount root fs\n");
+        let mut watch = PanicWatch::new(&path);
+        assert!(!watch.panicked());
+        append("[    0.0] Linux version 6.1\n[    2.0] Kernel pan");
+        assert!(!watch.panicked());
+        // The message the guest wrote in two pieces
+        append("ic - not syncing: Attempted to kill init!\n");
+        assert!(watch.panicked());
+
+        // A log truncated by a new VM is read from its start
+        std::fs::write(&path, "[    0.0] Linux version 6.1\n".repeat(10)).unwrap();
+        let mut watch = PanicWatch::new(&path);
+        std::fs::write(&path, "Kernel panic - not syncing: Fatal exception\n").unwrap();
+        assert!(watch.panicked());
+        let _ = std::fs::remove_file(&path);
+    }
+}
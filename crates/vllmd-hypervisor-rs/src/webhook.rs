@@ -0,0 +1,133 @@
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+use crate::events::EventKind;
+
+/// HTTP endpoint notified of lifecycle events (`[[global.webhooks]]`)
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// URL each event is POSTed to as JSON
+    pub url: String,
+
+    /// Extra request headers, such as `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// Events to deliver
+    #[serde(default = "default_events")]
+    pub events: Vec<EventKind>,
+
+    /// Attempts after the first failed one
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+
+    /// Milliseconds before the first retry, doubled for each further one
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+
+    /// Seconds to wait for each response
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+// Header values often carry credentials, and the configuration is logged at start
+impl std::fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let headers: BTreeMap<&str, &str> = self.headers.keys().map(|name| (name.as_str(), "<redacted>")).collect();
+        f.debug_struct("WebhookConfig")
+            .field("url", &self.url)
+            .field("headers", &headers)
+            .field("events", &self.events)
+            .field("retries", &self.retries)
+            .field("backoff_ms", &self.backoff_ms)
+            .field("timeout_secs", &self.timeout_secs)
+            .finish()
+    }
+}
+
+fn default_events() -> Vec<EventKind> {
+    vec![EventKind::Booted, EventKind::Ready, EventKind::Crashed, EventKind::Shutdown]
+}
+fn default_retries() -> u32 { 3 }
+fn default_backoff_ms() -> u64 { 500 }
+fn default_timeout_secs() -> u64 { 5 }
+
+impl WebhookConfig {
+    /// Webhook for `url` with the default events and retry policy
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            headers: BTreeMap::new(),
+            events: default_events(),
+            retries: default_retries(),
+            backoff_ms: default_backoff_ms(),
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+
+    /// Check that the configuration can be used
+    pub fn validate(&self) -> Result<()> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            bail!("Webhook URL must start with http:// or https://: {}", self.url);
+        }
+        if self.timeout_secs == 0 {
+            bail!("Webhook timeout must be greater than zero: {}", self.url);
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.events.contains(&kind)
+    }
+
+    /// POST `body` to the webhook. Failed deliveries are retried with exponential backoff, except
+    /// for client errors other than 408 and 429, which a retry would not fix.
+    pub fn deliver(&self, body: &str) -> Result<()> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(self.timeout_secs))
+            .build();
+
+        let mut backoff = Duration::from_millis(self.backoff_ms);
+        let mut attempt = 0;
+        loop {
+            let mut request = agent.post(&self.url).set("Content-Type", "application/json");
+            for (name, value) in &self.headers {
+                request = request.set(name, value);
+            }
+
+            let error = match request.send_string(body) {
+                Ok(_) => return Ok(()),
+                Err(ureq::Error::Status(code, _)) if (400..500).contains(&code) && code != 408 && code != 429 => {
+                    bail!("Webhook {} rejected the event with status {}", self.url, code);
+                },
+                Err(e) => e,
+            };
+
+            if attempt >= self.retries {
+                return Err(anyhow!("Webhook {} failed after {} attempt(s): {}", self.url, attempt + 1, error));
+            }
+            attempt += 1;
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_header_values() {
+        let mut webhook = WebhookConfig::new("https://example.com/hook");
+        webhook.headers.insert("Authorization".to_string(), "Bearer secret-token".to_string());
+        let debug = format!("{:?}", webhook);
+        assert!(!debug.contains("secret-token"), "{}", debug);
+        assert!(debug.contains(r#"headers: {"Authorization": "<redacted>"}"#), "{}", debug);
+        assert!(debug.contains("url: \"https://example.com/hook\""), "{}", debug);
+    }
+}
@@ -0,0 +1,1720 @@
+//! CLI tests against the mock hypervisor backend; they need no /dev/kvm and run in any container.
+
+mod common;
+
+use common::{stderr, stdout, wait_for, Host, Started};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::process::Stdio;
+use std::time::Duration;
+
+#[test]
+fn start_status_stop() {
+    let host = Host::mock();
+    let mut start = host.start();
+
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    let status = stdout(&host.run(&["status"]));
+    assert!(status.contains(&format!("Running (PID: {}", start.0.id())), "{}", status);
+    assert!(status.contains("Boot: vmm init 0.00s, vm create 0.00s, vm boot 0.00s, total"), "{}", status);
+    assert!(host.log().contains("Boot timing: vmm init"), "{}", host.log());
+
+    assert!(host.run(&["stop"]).status.success());
+    let exit = start.0.wait().unwrap();
+    assert!(exit.success(), "start exited with {}\n{}", exit, host.log());
+
+    assert!(!host.runtime_file("pid").exists());
+    assert!(!host.runtime_file("timing.json").exists());
+    assert!(stdout(&host.run(&["status"])).contains("Not running"));
+    assert!(host.log().contains("Mock VM shut down"), "{}", host.log());
+}
+
+#[test]
+fn stop_without_vm() {
+    let host = Host::mock();
+    assert!(host.run(&["stop"]).status.success());
+    assert!(stdout(&host.run(&["status"])).contains("Not running"));
+}
+
+#[test]
+fn print_cmdline_merges_layers() {
+    let host = Host::mock();
+    let output = host.command(&["start", "--print-cmdline"])
+        .env("VLLMD_HYPERVISOR_CMDLINE", "console=hvc0 quiet -- --init-arg")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let cmdline = stdout(&output);
+    assert!(cmdline.contains("root=/dev/vda1 rw"), "{}", cmdline);
+    assert!(cmdline.contains("console=hvc0") && !cmdline.contains("console=ttyS0"), "{}", cmdline);
+    assert!(cmdline.trim_end().ends_with("quiet -- --init-arg"), "{}", cmdline);
+    assert!(host.state().is_none());
+
+    // A root of its own leaves the generated one out
+    let output = host.command(&["start", "--print-cmdline"]).env("VLLMD_HYPERVISOR_CMDLINE", "root=/dev/vdb1 ro").output().unwrap();
+    let cmdline = stdout(&output);
+    assert!(cmdline.contains("root=/dev/vdb1 ro") && !cmdline.contains("vda1") && !cmdline.contains(" rw"), "{}", cmdline);
+    assert!(cmdline.contains("console=ttyS0"), "{}", cmdline);
+}
+
+#[test]
+fn dry_run_prints_the_vm_without_starting_it() {
+    let host = Host::mock();
+    let output = host.command(&["start", "--dry-run"]).env("VLLMD_HYPERVISOR_SYSTEM_IMAGE_EPHEMERAL", "on").output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let report: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert!(report["vm_config"].is_object(), "{}", report);
+    assert!(report["checks"].as_array().unwrap().iter().all(|check| check["status"] != "fail"), "{}", report);
+    let changes = report["host_changes"].as_array().unwrap();
+    assert!(changes.iter().any(|change| change.as_str().unwrap().starts_with("create overlay ")), "{}", report);
+    assert!(host.state().is_none());
+    assert!(!host.runtime_file("pid").exists());
+    assert!(!std::path::Path::new(&host.path("state/overlays")).exists());
+
+    // Signatures are checked without an audit record
+    let output = host.command(&["start", "--dry-run"])
+        .env("VLLMD_HYPERVISOR_TRUST_PUBLIC_KEYS", host.path("key.pub"))
+        .env("VLLMD_HYPERVISOR_TRUST_ENFORCE", "off")
+        .env("VLLMD_HYPERVISOR_AUDIT_LOG_FILEPATH", host.path("audit.jsonl"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(std::fs::read_to_string(host.path("audit.jsonl")).unwrap_or_default().is_empty());
+
+    // Validation fails the dry run as it fails start
+    let output = host.command(&["start", "--dry-run"])
+        .env("VLLMD_HYPERVISOR_DISK_LIST", format!("path={},format=qcow2", host.path("config.raw")))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("is configured as Qcow2 but the image is Raw"), "{}", stderr(&output));
+}
+
+#[test]
+fn runtimes_inherit_templates() {
+    let host = Host::mock();
+    let config = r#"
+[templates.base]
+cmdline = "quiet loglevel=3"
+
+[templates.large]
+extends = "base"
+memory_gb = 2
+
+[[runtimes]]
+index = 1
+name = "alpha"
+extends = "large"
+
+[[runtimes]]
+index = 2
+name = "beta"
+extends = "large"
+cmdline = "loglevel=7"
+"#;
+    std::fs::write(host.path("config.toml"), config).unwrap();
+    let cmdline = |name: &str| {
+        let output = host.command(&["start", "--print-cmdline"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", name).output().unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        stdout(&output)
+    };
+    assert!(cmdline("alpha").contains("quiet loglevel=3"), "{}", cmdline("alpha"));
+    assert!(cmdline("beta").contains("loglevel=7") && !cmdline("beta").contains("quiet"), "{}", cmdline("beta"));
+
+    std::fs::write(host.path("config.toml"), config.replace("[templates.base]", "[templates.base]\nextends = \"large\"")).unwrap();
+    let output = host.command(&["start", "--print-cmdline"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "alpha").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Templates large -> base -> large extend each other in a cycle"), "{}", stderr(&output));
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    let host = Host::mock();
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_BACKEND", "xen").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Unknown hypervisor backend 'xen'"), "{}", stderr(&output));
+
+    let output = host.command(&["start"]).env_remove("VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH"), "{}", stderr(&output));
+
+    let output = host.command(&["start"])
+        .env("VLLMD_HYPERVISOR_NESTED", "on")
+        .env("VLLMD_HYPERVISOR_CONFIDENTIAL", "sev-snp")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Nested virtualization is not available to confidential guests"), "{}", stderr(&output));
+}
+
+#[test]
+fn environment_typos_fail_start_unless_lenient() {
+    let host = Host::mock();
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_CPU_COUNT", "eight").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Invalid value for VLLMD_HYPERVISOR_CPU_COUNT"), "{}", stderr(&output));
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_CPU_CONT", "8").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Unknown environment variable VLLMD_HYPERVISOR_CPU_CONT"), "{}", stderr(&output));
+
+    let output = host.command(&["start", "--lenient", "--print-cmdline"])
+        .env("VLLMD_HYPERVISOR_CPU_COUNT", "eight")
+        .env("VLLMD_HYPERVISOR_CPU_CONT", "8")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn lenient_start_ignores_every_bad_value() {
+    let host = Host::mock();
+    let bad = [
+        ("VLLMD_HYPERVISOR_SECCOMP", "strict"),
+        ("VLLMD_HYPERVISOR_DEVICE_RETRIES", "-1"),
+        ("VLLMD_HYPERVISOR_TEXTFILE_INTERVAL", "0"),
+        ("VLLMD_HYPERVISOR_GPU_RESET", "maybe"),
+    ];
+    let output = host.command(&["start", "--print-cmdline"]).envs(bad).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Invalid value for VLLMD_HYPERVISOR_SECCOMP"), "{}", stderr(&output));
+
+    let output = host.command(&["start", "--lenient", "--print-cmdline"]).envs(bad).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // Without its runtime, start would boot another one
+    let output = host.command(&["start", "--lenient", "--print-cmdline"])
+        .env("VLLMD_HYPERVISOR_RUNTIME_NAME", "missing")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn configuration_is_validated_without_kvm() {
+    let host = Host::mock();
+    let output = host.command(&["start"])
+        .env("VLLMD_HYPERVISOR_DISK_LIST", format!("path={},format=qcow2", host.path("config.raw")))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("is configured as Qcow2 but the image is Raw"), "{}", stderr(&output));
+}
+
+#[test]
+fn env_export_writes_resolved_values() {
+    let host = Host::mock();
+    let path = host.path("vllmd.env");
+    let output = host.command(&["env", "--export", &path])
+        .env("VLLMD_HYPERVISOR_CMDLINE", r#"quiet init="/bin/$SHELL""#)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    assert!(lines.contains(&r#"VLLMD_HYPERVISOR_CMDLINE="quiet init=\"/bin/\$SHELL\"""#), "{}", contents);
+    assert!(lines.contains(&r#"VLLMD_HYPERVISOR_BACKEND="mock""#), "{}", contents);
+    assert!(lines.contains(&r#"VLLMD_HYPERVISOR_CPU_COUNT="4""#), "{}", contents);
+    assert!(lines.contains(&"#VLLMD_HYPERVISOR_VSOCK_CID="), "{}", contents);
+}
+
+#[test]
+fn env_check_reports_each_variable() {
+    let host = Host::mock();
+    let output = host.run(&["env", "--check"]);
+    assert!(output.status.success(), "{}{}", stdout(&output), stderr(&output));
+
+    let output = host.command(&["env", "--check"])
+        .env("VLLMD_HYPERVISOR_CPU_COUNT", "many")
+        .env("VLLMD_HYPERVISOR_MEMORY_CONFIG", "size=lots")
+        .env("VLLMD_HYPERVISOR_KERNEL_FILEPATH", host.path("missing"))
+        .env_remove("VLLMD_HYPERVISOR_CONFIG_IMAGE_FILEPATH")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("4 environment variable(s) invalid or missing"), "{}", stderr(&output));
+
+    let report = stdout(&output);
+    let status = |var: &str| report.lines()
+        .find(|line| line.split_whitespace().nth(1) == Some(var))
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap_or_default()
+        .to_string();
+    assert_eq!(status("VLLMD_HYPERVISOR_CPU_COUNT"), "invalid");
+    assert_eq!(status("VLLMD_HYPERVISOR_MEMORY_CONFIG"), "invalid");
+    assert_eq!(status("VLLMD_HYPERVISOR_KERNEL_FILEPATH"), "invalid");
+    assert_eq!(status("VLLMD_HYPERVISOR_CONFIG_IMAGE_FILEPATH"), "missing");
+    assert_eq!(status("VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH"), "ok");
+    assert_eq!(status("VLLMD_HYPERVISOR_VSOCK_CID"), "unset");
+}
+
+#[test]
+fn variable_registry_matches_binary() {
+    let host = Host::mock();
+    let output = host.run(&["vars", "check"]);
+    assert!(output.status.success(), "{}{}", stdout(&output), stderr(&output));
+
+    let output = host.run(&["vars", "list", "--scope", "runtime", "--type", "boolean"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let names: Vec<String> = stdout(&output).lines().skip(1)
+        .filter_map(|line| line.split_whitespace().next().map(String::from))
+        .collect();
+    assert!(names.contains(&"ephemeral".to_string()) && names.contains(&"tpm".to_string()), "{:?}", names);
+    assert!(!names.iter().any(|name| name.starts_with("VLLMD_") || name == "kernel"), "{:?}", names);
+}
+
+#[test]
+fn top_shows_running_vm() {
+    let host = Host::mock();
+    let start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+
+    let output = host.run(&["top", "--once", "--interval", "0.2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let view = stdout(&output);
+    let row: Vec<&str> = view.lines()
+        .find(|line| line.starts_with("(unnamed)"))
+        .unwrap_or_else(|| panic!("no row for the VM:\n{}", view))
+        .split_whitespace()
+        .collect();
+    assert_eq!(row[1], start.0.id().to_string());
+    assert_eq!(row[2], "running");
+    assert!(row[3].parse::<f64>().is_ok(), "{}", view);
+
+    assert!(host.run(&["stop"]).status.success());
+}
+
+#[test]
+fn inspect_reports_running_and_stopped_runtime() {
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_EVENT_LOG_FILEPATH", &host.path("events.jsonl"));
+    let inspect = || -> serde_json::Value {
+        let output = host.run(&["inspect"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        serde_json::from_str(&stdout(&output)).unwrap_or_else(|e| panic!("{}: {}", e, stdout(&output)))
+    };
+
+    let stopped = inspect();
+    assert_eq!(stopped["state"]["status"], "stopped", "{}", stopped);
+    assert_eq!(stopped["configuration"]["cpu_count"], 4, "{}", stopped);
+    assert!(stopped["vm"].is_null(), "{}", stopped);
+
+    let start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    let running = inspect();
+    assert_eq!(running["state"]["status"], "running", "{}", running);
+    assert_eq!(running["state"]["pid"], start.0.id(), "{}", running);
+    assert_eq!(running["configuration"]["backend"], "mock", "{}", running);
+    assert_eq!(running["configuration"]["seccomp"], "on", "{}", running);
+    assert_eq!(running["endpoints"]["api_socket"], host.runtime_file("sock").to_string_lossy().as_ref(), "{}", running);
+    let events: Vec<&str> = running["events"].as_array().unwrap().iter().map(|event| event["event"].as_str().unwrap()).collect();
+    assert_eq!(events, ["configured", "booted"], "{}", running);
+
+    assert!(host.run(&["stop"]).status.success());
+}
+
+#[test]
+fn lifecycle_events_are_published() {
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_EVENT_LOG_FILEPATH", &host.path("events.jsonl"));
+
+    let subscribe = || Started(host.command(&["events"]).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap());
+    let mut subscribers = [subscribe(), subscribe()];
+    let sockets: Vec<_> = subscribers.iter()
+        .map(|subscriber| host.runtime_file("events").join(format!("{}.sock", subscriber.0.id())))
+        .collect();
+    wait_for("the event sockets", Duration::from_secs(10), || sockets.iter().all(|socket| socket.exists()));
+
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+
+    let events = |lines: &str| -> Vec<String> {
+        lines.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap_or_else(|e| panic!("{}: {}", e, line)))
+            .map(|event| event["event"].as_str().unwrap().to_string())
+            .collect()
+    };
+    let logged = std::fs::read_to_string(host.path("events.jsonl")).unwrap();
+    assert_eq!(events(&logged), ["configured", "booted", "shutdown"], "{}", logged);
+
+    for subscriber in &mut subscribers {
+        subscriber.0.kill().unwrap();
+        let mut received = String::new();
+        subscriber.0.stdout.take().unwrap().read_to_string(&mut received).unwrap();
+        assert_eq!(events(&received), ["configured", "booted", "shutdown"], "{}", received);
+    }
+}
+
+#[test]
+fn webhooks_receive_events() {
+    // Minimal HTTP endpoint answering every request with 200 and recording its body
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, bodies) = std::sync::mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            // Recorded before the answer, so the body is there once the delivery completes
+            let _ = sender.send(String::from_utf8(body).unwrap());
+            let _ = reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+    });
+
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_WEBHOOK_URL", &url);
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+
+    // start waits for its deliveries, so every event has arrived once it exited
+    let events: Vec<String> = bodies.try_iter()
+        .map(|body| serde_json::from_str::<serde_json::Value>(&body).unwrap()["event"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(events, ["booted", "shutdown"], "{}", host.log());
+}
+
+#[test]
+fn audit_log_chain_detects_tampering() {
+    let mut host = Host::mock();
+    let path = host.path("audit.jsonl");
+    host.set("VLLMD_HYPERVISOR_AUDIT_LOG_FILEPATH", &path);
+
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let operations: Vec<String> = log.lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["operation"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(operations, ["create", "boot", "stop", "shutdown"], "{}", log);
+    let output = host.run(&["audit", "verify"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("4 record(s), chain intact"), "{}", stdout(&output));
+
+    std::fs::write(&path, log.replacen("\"operation\":\"stop\"", "\"operation\":\"noop\"", 1)).unwrap();
+    let output = host.run(&["audit", "verify"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("record 2 was modified"), "{}", stderr(&output));
+}
+
+#[test]
+fn sigusr1_writes_diagnostic_dump() {
+    let host = Host::mock();
+    let start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+
+    let kill = std::process::Command::new("kill").args(["-USR1", &start.0.id().to_string()]).status().unwrap();
+    assert!(kill.success());
+    let path = host.runtime_file("dump.json");
+    wait_for("the dump file", Duration::from_secs(10), || path.exists());
+
+    let dump: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(dump["state"], "Running", "{}", dump);
+    assert_eq!(dump["pid"], start.0.id(), "{}", dump);
+    assert!(dump["config"]["system_image"].is_string(), "{}", dump);
+    assert!(!dump["threads"].as_array().unwrap().is_empty(), "{}", dump);
+    assert!(host.log().contains("Diagnostic dump: {"), "{}", host.log());
+
+    // The VM keeps running
+    assert_eq!(host.state().as_deref(), Some("running"));
+    assert!(host.run(&["stop"]).status.success());
+}
+
+#[test]
+fn sigusr2_reopens_log_file() {
+    let host = Host::mock();
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+
+    // What logrotate does without copytruncate
+    let rotated = host.path("hypervisor.log.1");
+    std::fs::rename(host.path("hypervisor.log"), &rotated).unwrap();
+    let kill = std::process::Command::new("kill").args(["-USR2", &start.0.id().to_string()]).status().unwrap();
+    assert!(kill.success());
+    wait_for("the log file to be reopened", Duration::from_secs(10), || host.log().contains("reopened log file"));
+
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+    assert!(host.log().contains("Shutting down VM"), "{}", host.log());
+    let old = std::fs::read_to_string(&rotated).unwrap();
+    assert!(old.contains("VM started successfully") && !old.contains("Shutting down VM"), "{}", old);
+}
+
+#[test]
+fn piped_output_is_plain_text() {
+    let host = Host::mock();
+    let output = host.run(&["env", "--show-colors"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let table = stdout(&output);
+    assert!(!table.contains('\x1b'), "{}", table);
+    assert!(table.lines().any(|line| line.starts_with("VLLMD_HYPERVISOR_CPU_COUNT") && line.contains("4 (default)")), "{}", table);
+
+    let mut start = Started(host.command(&["start", "--no-color"]).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap());
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+    assert!(!host.log().contains('\x1b') && !host.log().contains('«'), "{}", host.log());
+}
+
+#[test]
+fn verbosity_flags_set_log_level() {
+    let host = Host::mock();
+    assert_eq!(stderr(&host.run(&["status"])), "");
+
+    let output = host.run(&["status", "-v"]);
+    assert!(stderr(&output).contains("info   Checking hypervisor status"), "{}", stderr(&output));
+
+    let output = host.command(&["-q", "status"]).env("RUST_LOG", "debug").output().unwrap();
+    assert_eq!(stderr(&output), "");
+    assert!(stdout(&output).contains("Not running"));
+}
+
+#[test]
+fn log_timestamp_format_is_configurable() {
+    let host = Host::mock();
+    let first_field = |output: &std::process::Output| {
+        stderr(output).split_whitespace().next().unwrap_or_default().to_string()
+    };
+
+    let output = host.command(&["status", "-v"]).env("VLLMD_HYPERVISOR_LOG_TIMESTAMP_FORMAT", "epoch-millis").output().unwrap();
+    assert!(first_field(&output).parse::<u64>().is_ok(), "{}", stderr(&output));
+
+    let output = host.command(&["status", "-v"])
+        .env("VLLMD_HYPERVISOR_LOG_TIMESTAMP_FORMAT", "rfc3339")
+        .env("VLLMD_HYPERVISOR_LOG_UTC", "on")
+        .output()
+        .unwrap();
+    let timestamp = first_field(&output);
+    assert!(chrono::DateTime::parse_from_rfc3339(&timestamp).is_ok() && timestamp.ends_with('Z'), "{}", stderr(&output));
+
+    let output = host.command(&["status"]).env("VLLMD_HYPERVISOR_LOG_TIMESTAMP_FORMAT", "iso").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Unknown log timestamp format 'iso'"), "{}", stderr(&output));
+}
+
+#[test]
+fn start_all_boots_every_runtime() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "alpha"
+
+[[runtimes]]
+index = 2
+name = "beta"
+
+[[runtimes]]
+index = 3
+name = "gamma"
+gpus = ["0000:ff:1f.7"]
+"#).unwrap();
+
+    let summary_path = host.path("summary.out");
+    let summary_file = std::fs::File::create(&summary_path).unwrap();
+    let mut all = Started(host.command(&["start", "--all", "--parallel", "2"])
+        .stdout(summary_file)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap());
+    let summary = || std::fs::read_to_string(&summary_path).unwrap_or_default();
+    wait_for("the summary", Duration::from_secs(30), || summary().contains("runtime(s) started"));
+
+    let summary = summary();
+    assert!(summary.contains("alpha  running (PID: "), "{}", summary);
+    assert!(summary.contains("beta   running (PID: "), "{}", summary);
+    assert!(summary.contains("gamma  failed after") && summary.contains("Device path does not exist"), "{}", summary);
+    assert!(summary.contains("2 of 3 runtime(s) started"), "{}", summary);
+    let status = host.command(&["status"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "beta").output().unwrap();
+    assert!(stdout(&status).contains("Status: Running"), "{}", stdout(&status));
+    assert!(std::fs::read_to_string(host.path("hypervisor-alpha.log")).unwrap().contains("VM started successfully"));
+
+    // SIGTERM reaches every runtime; the failed one makes the exit status non-zero
+    let kill = std::process::Command::new("kill").args(["-TERM", &all.0.id().to_string()]).status().unwrap();
+    assert!(kill.success());
+    assert!(!all.0.wait().unwrap().success());
+    for name in ["alpha", "beta"] {
+        assert!(!std::path::Path::new(&host.path(&format!("run/vllmd-hypervisor-{}.pid", name))).exists(), "{}", name);
+    }
+    assert!(host.log().contains("Stopping runtime alpha"), "{}", host.log());
+}
+
+#[test]
+fn start_all_honors_dependencies() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "router"
+depends_on = ["embedding"]
+
+[[runtimes]]
+index = 2
+name = "embedding"
+
+[[runtimes]]
+index = 3
+name = "broken"
+gpus = ["0000:ff:1f.7"]
+
+[[runtimes]]
+index = 4
+name = "orphan"
+depends_on = ["broken"]
+"#).unwrap();
+
+    let summary_path = host.path("summary.out");
+    let summary_file = std::fs::File::create(&summary_path).unwrap();
+    let mut all = Started(host.command(&["start", "--all"])
+        .stdout(summary_file)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap());
+    let summary = || std::fs::read_to_string(&summary_path).unwrap_or_default();
+    wait_for("the summary", Duration::from_secs(30), || summary().contains("runtime(s) started"));
+
+    let summary = summary();
+    assert!(summary.contains("orphan     failed after 0.00s: dependency broken did not start"), "{}", summary);
+    assert!(summary.contains("2 of 4 runtime(s) started"), "{}", summary);
+    let log = host.log();
+    let embedding_started = log.find("Runtime embedding: running").unwrap();
+    let router_launched = log.find("Starting runtime router").unwrap();
+    assert!(embedding_started < router_launched, "{}", log);
+
+    let kill = std::process::Command::new("kill").args(["-TERM", &all.0.id().to_string()]).status().unwrap();
+    assert!(kill.success());
+    assert!(!all.0.wait().unwrap().success());
+
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "a"
+depends_on = ["b"]
+
+[[runtimes]]
+index = 2
+name = "b"
+depends_on = ["a"]
+"#).unwrap();
+    let output = host.run(&["start", "--all"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Runtimes a, b cannot be ordered: their dependencies form a cycle"), "{}", stderr(&output));
+}
+
+#[test]
+fn restart_all_rolls_through_runtimes() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "alpha"
+
+[[runtimes]]
+index = 2
+name = "beta"
+"#).unwrap();
+
+    let summary_path = host.path("summary.out");
+    let summary_file = std::fs::File::create(&summary_path).unwrap();
+    let mut all = Started(host.command(&["start", "--all"]).stdout(summary_file).stderr(Stdio::null()).spawn().unwrap());
+    wait_for("the summary", Duration::from_secs(30), || {
+        std::fs::read_to_string(&summary_path).unwrap_or_default().contains("2 of 2 runtime(s) started")
+    });
+    let pid_file = |name: &str| host.path(&format!("run/vllmd-hypervisor-{}.pid", name));
+    let alpha_pid = std::fs::read_to_string(pid_file("alpha")).unwrap();
+
+    let output = host.run(&["restart", "--all", "--max-unavailable", "1"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let lines: Vec<String> = stdout(&output).lines().map(String::from).collect();
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    assert!(lines[0].starts_with("alpha  back after") && lines[1].starts_with("beta   back after"), "{:?}", lines);
+
+    // Restarted in place: same PID, log kept, still supervised by start --all
+    assert_eq!(std::fs::read_to_string(pid_file("alpha")).unwrap(), alpha_pid);
+    let log = std::fs::read_to_string(host.path("hypervisor-alpha.log")).unwrap();
+    assert_eq!(log.matches("VM started successfully").count(), 2, "{}", log);
+    assert!(log.contains("Restart complete"), "{}", log);
+    assert!(!std::path::Path::new(&host.path("run/vllmd-hypervisor-alpha.restart")).exists());
+    let audit = std::fs::read_to_string(host.path("state/audit.jsonl")).unwrap();
+    assert_eq!(audit.matches("\"operation\":\"restart\"").count(), 2, "{}", audit);
+
+    let kill = std::process::Command::new("kill").args(["-TERM", &all.0.id().to_string()]).status().unwrap();
+    assert!(kill.success());
+    assert!(all.0.wait().unwrap().success());
+}
+
+#[test]
+fn status_diff_reports_configuration_drift() {
+    let host = Host::mock();
+    let _start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+
+    let output = host.run(&["status", "--diff"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Drift: none"), "{}", stdout(&output));
+
+    let output = host.command(&["status", "--diff"]).env("VLLMD_HYPERVISOR_CPU_COUNT", "3").output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Drift: 1 change(s), 1 requiring a restart"), "{}", stdout(&output));
+    assert!(stdout(&output).contains("  cpu_count: "), "{}", stdout(&output));
+    assert!(stdout(&output).contains(" -> 3 (restart)"), "{}", stdout(&output));
+}
+
+#[test]
+fn upgrade_hands_the_vm_to_a_new_process() {
+    let host = Host::mock();
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    let pid = std::fs::read_to_string(host.runtime_file("pid")).unwrap();
+
+    let output = host.run(&["upgrade"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("back after"), "{}", stdout(&output));
+
+    // Same PID, the VM restored rather than booted, and the handoff cleaned up
+    assert_eq!(std::fs::read_to_string(host.runtime_file("pid")).unwrap(), pid);
+    assert_eq!(host.state().as_deref(), Some("running"));
+    assert!(!host.runtime_file("upgrade").exists());
+    let audit = std::fs::read_to_string(host.path("state/audit.jsonl")).unwrap();
+    for operation in ["upgrade", "suspend", "restore"] {
+        assert!(audit.contains(&format!("\"operation\":\"{}\"", operation)), "{}", audit);
+    }
+    let record: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(host.path("state/runtimes/hypervisor.json")).unwrap()).unwrap();
+    assert_eq!(record["boot_count"], 1);
+
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+}
+
+#[test]
+fn failed_upgrade_resumes_the_vm() {
+    let host = Host::mock();
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    // The handoff cannot be written where a directory is in the way
+    std::fs::create_dir_all(host.runtime_file("upgrade")).unwrap();
+
+    let output = host.run(&["upgrade"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Failed to write upgrade file"), "{}", stderr(&output));
+    assert_eq!(host.state().as_deref(), Some("running"));
+    assert!(host.log().contains("Upgrade failed, the VM runs on"), "{}", host.log());
+
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+}
+
+#[test]
+fn selector_lists_and_stops_labelled_runtimes() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[templates.dev]
+labels = { tier = "dev" }
+
+[[runtimes]]
+index = 1
+name = "alpha"
+extends = "dev"
+labels = { model = "llama-8b" }
+
+[[runtimes]]
+index = 2
+name = "beta"
+labels = { tier = "prod", model = "llama-70b" }
+"#).unwrap();
+
+    let mut started = Vec::new();
+    for name in ["alpha", "beta"] {
+        started.push(Started(host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", name)
+            .stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap()));
+        let state = host.path(&format!("run/vllmd-hypervisor-{}.state", name));
+        wait_for("the VM to run", Duration::from_secs(10), || std::fs::read_to_string(&state).ok().as_deref() == Some("running"));
+    }
+
+    let output = host.run(&["list"]);
+    let lines: Vec<String> = stdout(&output).lines().map(String::from).collect();
+    assert!(lines[0].starts_with("NAME   INDEX  STATE    PID"), "{:?}", lines);
+    assert!(lines[1].starts_with("alpha  1      running") && lines[1].ends_with("model=llama-8b,tier=dev"), "{:?}", lines);
+    let output = host.run(&["list", "--selector", "tier!=dev"]);
+    assert_eq!(stdout(&output).lines().count(), 2, "{}", stdout(&output));
+    assert!(stdout(&output).contains("model=llama-70b,tier=prod"), "{}", stdout(&output));
+
+    let output = host.run(&["stop", "--selector", "tier=dev"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("alpha  stopping (PID: "), "{}", stdout(&output));
+    assert!(started[0].0.wait().unwrap().success());
+    assert!(started[1].0.try_wait().unwrap().is_none());
+    assert!(stdout(&host.run(&["list", "--selector", "model=llama-8b"])).contains("alpha  1      stopped"));
+
+    let output = host.run(&["list", "--selector", "tier"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("expected key=value or key!=value"), "{}", stderr(&output));
+}
+
+#[test]
+fn guest_not_ready_within_the_boot_timeout_is_shut_down() {
+    let host = Host::mock();
+    // Nothing listens on the probed port, so the guest never becomes ready
+    let output = host.command(&["start", "--boot-timeout", "1"])
+        .env("VLLMD_HYPERVISOR_PROBE_URL", "http://127.0.0.1:9/health")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(6), "{}", stderr(&output));
+    assert!(stderr(&output).contains("The VM did not boot, or the guest was not ready, within 1s"), "{}", stderr(&output));
+    assert!(!host.runtime_file("pid").exists());
+    assert!(host.log().contains("Guest not ready within 1s of booting"));
+}
+
+#[test]
+fn hung_boot_leaves_no_swtpm_behind() {
+    use std::os::unix::fs::PermissionsExt;
+    let mut host = Host::mock();
+    // Stand-in for swtpm that creates its socket, records its PID and runs until it is killed
+    std::fs::create_dir_all(host.path("bin")).unwrap();
+    let swtpm = host.path("bin/swtpm");
+    std::fs::write(&swtpm, "#!/bin/sh\n\
+        for arg; do case \"$arg\" in type=unixio,path=*) socket=\"${arg#type=unixio,path=}\" ;; esac; done\n\
+        echo $$ > \"$socket.pid\"\n\
+        touch \"$socket\"\n\
+        exec sleep 60\n").unwrap();
+    std::fs::set_permissions(&swtpm, std::fs::Permissions::from_mode(0o755)).unwrap();
+    host.set("PATH", &format!("{}:/usr/bin:/bin", host.path("bin")));
+    host.set("VLLMD_HYPERVISOR_TPM", "on");
+    host.set("VLLMD_MOCK_BOOT_DELAY_MS", "30000");
+
+    let output = host.run(&["start", "--boot-timeout", "1"]);
+    assert_eq!(output.status.code(), Some(6), "{}", stderr(&output));
+    assert!(stderr(&output).contains("The VM did not boot, or the guest was not ready, within 1s"), "{}", stderr(&output));
+    // Given up on, the VM is shut down as any other
+    assert!(host.log().contains("The VM did not boot within 1s; giving up"), "{}", host.log());
+    assert!(host.log().contains("VM shutdown complete"), "{}", host.log());
+    let record: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(host.path("state/runtimes/hypervisor.json")).unwrap()).unwrap();
+    assert_eq!(record["last_exit"]["reason"], "boot_timeout", "{}", record);
+    assert!(!host.runtime_file("pid").exists());
+    assert!(!host.runtime_file("tpm.sock").exists());
+    let pid = std::fs::read_to_string(host.runtime_file("tpm.sock.pid")).unwrap();
+    assert!(!std::path::Path::new(&format!("/proc/{}", pid.trim())).exists(), "swtpm (PID {}) outlived start", pid.trim());
+}
+
+#[test]
+fn drained_host_refuses_to_boot_until_uncordon() {
+    let host = Host::mock();
+    let started = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    let output = host.run(&["drain", "--reason", "driver update"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("(unnamed)  stopped after "), "{}", stdout(&output));
+    drop(started);
+
+    let output = host.run(&["start"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("(driver update); run uncordon to boot VMs on it again"), "{}", stderr(&output));
+
+    assert!(stdout(&host.run(&["uncordon"])).contains("Host uncordoned, drained since "));
+    assert_eq!(stdout(&host.run(&["uncordon"])).trim(), "Host was not drained");
+    let _started = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+}
+
+#[test]
+fn drain_snapshot_skips_runtimes_that_cannot_be_snapshotted() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), "[[runtimes]]\nindex = 1\nname = \"alpha\"\n").unwrap();
+    let mut started = Started(host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "alpha")
+        .stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap());
+    let state = host.path("run/vllmd-hypervisor-alpha.state");
+    wait_for("the VM to run", Duration::from_secs(10), || std::fs::read_to_string(&state).ok().as_deref() == Some("running"));
+    // As recorded for a VM booted with a GPU
+    let recorded = host.path("run/vllmd-hypervisor-alpha.config.json");
+    let mut config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&recorded).unwrap()).unwrap();
+    config["device_filepath_list"] = serde_json::json!(["/sys/bus/pci/devices/0000:01:00.0"]);
+    std::fs::write(&recorded, config.to_string()).unwrap();
+
+    let output = host.run(&["drain", "--snapshot"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Not snapshotting alpha: it has passthrough devices"), "{}", stderr(&output));
+    assert!(stdout(&output).contains("alpha  stopped after "), "{}", stdout(&output));
+    assert!(started.0.try_wait().unwrap().is_some());
+    assert!(!host.path("state/snapshots").exists());
+}
+
+#[test]
+fn stop_all_stops_defined_and_registered_runtimes() {
+    let host = Host::mock();
+    let runtimes = |names: &[&str]| names.iter().enumerate()
+        .map(|(index, name)| format!("[[runtimes]]\nindex = {}\nname = \"{}\"\n", index + 1, name))
+        .collect::<String>();
+    std::fs::write(host.path("config.toml"), runtimes(&["alpha", "beta"])).unwrap();
+
+    let mut started = Vec::new();
+    for name in ["alpha", "beta"] {
+        started.push(Started(host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", name)
+            .stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap()));
+        let state = host.path(&format!("run/vllmd-hypervisor-{}.state", name));
+        wait_for("the VM to run", Duration::from_secs(10), || std::fs::read_to_string(&state).ok().as_deref() == Some("running"));
+    }
+    // beta keeps running after the configuration file drops it
+    std::fs::write(host.path("config.toml"), runtimes(&["alpha"])).unwrap();
+
+    let output = host.run(&["stop", "--all"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = stdout(&output);
+    assert!(report.contains("alpha  stopping (PID: ") && report.contains("beta   stopping (PID: "), "{}", report);
+    assert!(report.contains("alpha  stopped after ") && report.contains("beta   stopped after "), "{}", report);
+    for runtime in &mut started {
+        assert!(runtime.0.try_wait().unwrap().is_some_and(|status| status.success()));
+    }
+
+    let output = host.run(&["stop", "--all"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("beta   not running"), "{}", stdout(&output));
+}
+
+#[test]
+fn prune_removes_files_of_stopped_runtimes() {
+    let host = Host::mock();
+    let start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+
+    // What a crashed runtime leaves behind, next to the files of the running one
+    std::fs::create_dir_all(host.path("state/logs")).unwrap();
+    std::fs::create_dir_all(host.path("state/overlays")).unwrap();
+    let stale = ["run/vllmd-hypervisor-ghost.pid", "run/vllmd-hypervisor-ghost.sock", "run/vllmd-hypervisor-ghost.vsock_1024",
+                 "state/logs/ghost-guest.log", "state/overlays/0b7e6d1c-system.qcow2"];
+    for path in stale {
+        std::fs::write(host.path(path), "4194304").unwrap();
+    }
+    std::fs::write(host.path("run/vllmd-hypervisor-ghost.dump.json"), "{}").unwrap();
+
+    let output = host.run(&["prune", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = stdout(&output);
+    for path in stale {
+        assert!(report.contains(&format!("Would remove {}", host.path(path))), "{}", report);
+        assert!(std::path::Path::new(&host.path(path)).exists());
+    }
+    assert!(report.contains("Would delete 5 files"), "{}", report);
+
+    let output = host.run(&["prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Deleted 5 files"), "{}", stdout(&output));
+    for path in stale {
+        assert!(!std::path::Path::new(&host.path(path)).exists(), "{}", path);
+    }
+    assert!(std::path::Path::new(&host.path("run/vllmd-hypervisor-ghost.dump.json")).exists());
+    assert_eq!(host.state().as_deref(), Some("running"));
+    assert_eq!(std::fs::read_to_string(host.runtime_file("pid")).unwrap().trim(), start.0.id().to_string());
+
+    // A runtime started with another runtime directory, as by a system service, runs all the same
+    std::fs::write(host.path("config.toml"), "[[runtimes]]\nindex = 1\nname = \"other\"\n").unwrap();
+    std::fs::create_dir_all(host.path("service")).unwrap();
+    let _other = Started(host.command(&["start"]).env("XDG_RUNTIME_DIR", host.path("service"))
+        .env("VLLMD_HYPERVISOR_RUNTIME_NAME", "other").stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap());
+    wait_for("the other VM to run", Duration::from_secs(10), || {
+        std::fs::read_to_string(host.path("state/runtimes/other.json")).is_ok_and(|record| record.contains("\"running\""))
+    });
+    std::fs::write(host.path("state/logs/other-guest.log"), "booted").unwrap();
+    // And a file a process holds open is in use, whoever it belongs to
+    let held = host.path("state/overlays/3c9d2e4f-system.qcow2");
+    let _open = std::fs::File::create(&held).unwrap();
+    let output = host.run(&["prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Deleted 0 files"), "{}", stdout(&output));
+    assert!(std::path::Path::new(&host.path("state/logs/other-guest.log")).exists());
+    assert!(std::path::Path::new(&held).exists());
+
+    assert!(host.run(&["stop"]).status.success());
+    assert!(host.command(&["stop"]).env("XDG_RUNTIME_DIR", host.path("service"))
+        .env("VLLMD_HYPERVISOR_RUNTIME_NAME", "other").output().unwrap().status.success());
+}
+
+#[test]
+fn prune_keeps_decompressed_images_in_use() {
+    // A zstd frame of eight RLE blocks decompressing to 1 MiB of zeros
+    let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd, 0xa0];
+    frame.extend_from_slice(&(1u32 << 20).to_le_bytes());
+    for block in 0..8u32 {
+        let header = u32::from(block == 7) | 1 << 1 | (128 << 10) << 3;
+        frame.extend_from_slice(&header.to_le_bytes()[..3]);
+        frame.push(0);
+    }
+    let mut host = Host::mock();
+    std::fs::write(host.path("system.raw.zst"), frame).unwrap();
+    host.set("VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH", &host.path("system.raw.zst"));
+    let blob_dir = host.path(".local/share/vllmd/images/blobs/sha256");
+    let blobs = || std::fs::read_dir(&blob_dir).unwrap().count();
+
+    // Resolving the configuration decompresses the image into the store
+    let output = host.run(&["inspect"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let list = stdout(&host.run(&["images", "list"]));
+    assert!(list.contains("zstd:file://") && list.contains("environment"), "{}", list);
+
+    let output = host.run(&["images", "prune"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stdout(&output).contains("Removed"), "{}", stdout(&output));
+    assert_eq!(blobs(), 1);
+
+    // Once nothing uses the image, its decompressed copy goes
+    host.set("VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH", &host.path("system.raw"));
+    let output = host.run(&["images", "prune"]);
+    assert!(stdout(&output).contains("Removed zstd:file://"), "{}", stdout(&output));
+    assert_eq!(blobs(), 0);
+}
+
+#[test]
+fn prune_waits_for_pulls_in_progress() {
+    use std::os::unix::io::AsRawFd;
+
+    let host = Host::mock();
+    assert!(host.run(&["images", "list"]).status.success());
+    let partial = host.path(".local/share/vllmd/images/downloads/runtime.raw.partial");
+    std::fs::write(&partial, b"half an image").unwrap();
+
+    // A pull in another process holds the store open
+    let lock = std::fs::File::open(host.path(".local/share/vllmd/images/lock")).unwrap();
+    assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_SH) }, 0);
+    let mut prune = host.command(&["images", "prune"]).stdout(Stdio::piped()).spawn().unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(prune.try_wait().unwrap().is_none());
+    assert!(std::path::Path::new(&partial).exists());
+
+    drop(lock);
+    let output = prune.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(!std::path::Path::new(&partial).exists());
+}
+
+#[test]
+fn snapshots_are_listed_pruned_and_deleted() {
+    let host = Host::mock();
+    let snapshots: Vec<String> = (1..=3).map(|n| host.path(&format!("snapshots/hypervisor-{}", n))).collect();
+    for snapshot in &snapshots {
+        std::fs::create_dir_all(snapshot).unwrap();
+        std::fs::write(format!("{}/state.json", snapshot), "{}").unwrap();
+    }
+    std::fs::create_dir_all(host.path("state/runtimes")).unwrap();
+    let record = serde_json::json!({
+        "uuid": "0b6f6b0e-5a2e-4c57-9a59-3f1e2c4d5e6f",
+        "snapshots": [
+            { "path": snapshots[0], "time": "2026-01-01T00:00:00Z" },
+            { "path": snapshots[1], "time": "2026-01-02T00:00:00Z" },
+            { "path": snapshots[2], "time": "2026-01-03T00:00:00Z" },
+        ],
+    });
+    std::fs::write(host.path("state/runtimes/hypervisor.json"), record.to_string()).unwrap();
+
+    let output = host.run(&["snapshots", "list"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("hypervisor-2"), "{}", stdout(&output));
+
+    let output = host.command(&["snapshots", "prune"]).env("VLLMD_HYPERVISOR_SNAPSHOT_KEEP", "2").output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!std::path::Path::new(&snapshots[0]).exists());
+    assert!(std::path::Path::new(&snapshots[1]).exists());
+
+    let output = host.run(&["snapshots", "delete", "hypervisor-2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!std::path::Path::new(&snapshots[1]).exists());
+    let output = host.run(&["snapshots", "list", "--json"]);
+    let listed: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["path"], snapshots[2].as_str());
+
+    let output = host.run(&["snapshots", "delete", "hypervisor-1"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Runtime has no snapshot hypervisor-1"), "{}", stderr(&output));
+}
+
+#[test]
+fn snapshots_only_delete_their_own_files() {
+    let host = Host::mock();
+    let snapshots: Vec<String> = (1..=2).map(|n| host.path(&format!("snapshots/hypervisor-{}", n))).collect();
+    for snapshot in &snapshots {
+        std::fs::create_dir_all(snapshot).unwrap();
+        for file in ["config.json", "state.json", "memory-ranges"] {
+            std::fs::write(format!("{}/{}", snapshot, file), "{}").unwrap();
+        }
+    }
+    std::fs::write(format!("{}/notes.txt", snapshots[0]), "kept").unwrap();
+    std::fs::create_dir_all(host.path("state/runtimes")).unwrap();
+    let record = serde_json::json!({
+        "uuid": "0b6f6b0e-5a2e-4c57-9a59-3f1e2c4d5e6f",
+        "snapshots": [
+            { "path": snapshots[0], "time": "2026-01-01T00:00:00Z" },
+            { "path": snapshots[1], "time": "2026-01-02T00:00:00Z" },
+        ],
+    });
+    std::fs::write(host.path("state/runtimes/hypervisor.json"), record.to_string()).unwrap();
+
+    let output = host.command(&["snapshots", "prune"]).env("VLLMD_HYPERVISOR_SNAPSHOT_KEEP", "1").output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!std::path::Path::new(&format!("{}/state.json", snapshots[0])).exists());
+    assert_eq!(std::fs::read_to_string(format!("{}/notes.txt", snapshots[0])).unwrap(), "kept");
+    assert!(std::path::Path::new(&format!("{}/state.json", snapshots[1])).exists());
+
+    // A directory with anything in it is not written into, so retention cannot delete it later
+    let output = host.command(&["snapshot", "--dir", &snapshots[0]]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("is not empty"), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(format!("{}/notes.txt", snapshots[0])).unwrap(), "kept");
+}
+
+#[test]
+fn state_record_survives_the_hypervisor() {
+    let host = Host::mock();
+    let record_path = host.path("state/runtimes/hypervisor.json");
+    let record = || -> serde_json::Value { serde_json::from_str(&std::fs::read_to_string(&record_path).unwrap()).unwrap() };
+
+    for boot in 1..=2 {
+        let mut start = host.start();
+        wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+        assert_eq!(record()["state"]["state"], "running");
+        assert!(host.run(&["stop"]).status.success());
+        assert!(start.0.wait().unwrap().success(), "{}", host.log());
+
+        let stopped = record();
+        assert_eq!(stopped["boot_count"], boot);
+        assert_eq!(stopped["state"]["state"], "stopped");
+        assert_eq!(stopped["last_exit"]["reason"], "stopped");
+        // Every VM of the runtime boots under its UUID
+        let configured = format!("Mock hypervisor configured for VM {}", stopped["uuid"].as_str().unwrap());
+        assert!(host.log().contains(&configured), "{}", host.log());
+    }
+
+    // A second hypervisor of a running runtime would take over its files and its overlay
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    let output = host.run(&["start"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(&format!("The runtime is already running (PID: {})", start.0.id())), "{}", stderr(&output));
+    assert_eq!(host.state().as_deref(), Some("running"));
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+
+    // The record outlives the runtime files, and the runtime keeps its identity
+    assert!(!host.runtime_file("pid").exists());
+    let output = host.run(&["inspect"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let inspection: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(inspection["record"]["uuid"], record()["uuid"]);
+}
+
+#[test]
+fn run_as_drops_root_before_the_vmm_starts() {
+    use std::os::unix::fs::PermissionsExt;
+    // Switching users takes root
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let host = Host::mock();
+    let mode = |path: &str, mode: u32| std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    mode(&host.path(""), 0o755);
+    mode(&host.path("run"), 0o777);
+
+    // The VMM opens the images as the user, so a system image only root can write fails the start
+    let output = host.run(&["start", "--run-as", "nobody"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("User nobody cannot open"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("system.raw (rw)"), "{}", stderr(&output));
+
+    // The default seccomp filters stay on
+    mode(&host.path("system.raw"), 0o666);
+    let mut start = Started(host.command(&["start", "--run-as", "nobody"]).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap());
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    let status = std::fs::read_to_string(format!("/proc/{}/status", start.0.id())).unwrap();
+    assert!(status.lines().any(|line| line.starts_with("Uid:\t65534\t")), "{}", status);
+    assert!(host.log().contains("Dropped privileges to nobody"), "{}", host.log());
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+}
+
+#[test]
+fn metrics_are_written_for_the_textfile_collector() {
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_TEXTFILE_DIR", &host.path("textfile"))
+        .set("VLLMD_HYPERVISOR_TEXTFILE_INTERVAL", "1");
+    let metrics_path = host.path("textfile/vllmd-hypervisor.prom");
+
+    let mut start = host.start();
+    wait_for("the running state in the metrics", Duration::from_secs(10), || {
+        std::fs::read_to_string(&metrics_path).is_ok_and(|metrics| metrics.contains("vllmd_runtime_state{runtime=\"hypervisor\",state=\"running\"} 1"))
+    });
+    let metrics = std::fs::read_to_string(&metrics_path).unwrap();
+    let record: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(host.path("state/runtimes/hypervisor.json")).unwrap()).unwrap();
+    let uuid = record["uuid"].as_str().unwrap();
+    assert!(metrics.contains(&format!("vllmd_runtime_info{{runtime=\"hypervisor\",uuid=\"{}\"}} 1", uuid)), "{}", metrics);
+    assert!(metrics.contains("# TYPE vllmd_runtime_cpu_seconds_total counter"), "{}", metrics);
+
+    // A stopped runtime leaves nothing for node_exporter to export
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+    assert!(!std::path::Path::new(&metrics_path).exists());
+}
+
+#[test]
+fn up_reconciles_running_runtimes_with_the_file() {
+    let host = Host::mock();
+    let runtimes = host.path("runtimes.toml");
+    let runtime = |index: u32, name: &str, cpus: u32| format!("[[runtimes]]\nindex = {}\nname = \"{}\"\ncpus = {}\n\n", index, name, cpus);
+    let pid = |name: &str| std::fs::read_to_string(host.path(&format!("run/vllmd-hypervisor-{}.pid", name))).unwrap();
+    std::fs::write(&runtimes, runtime(1, "alpha", 2) + &runtime(2, "beta", 2)).unwrap();
+
+    // The hypervisors outlive `up`, so a failing test still has to take them down
+    struct Down<'a>(&'a Host, &'a str);
+    impl Drop for Down<'_> {
+        fn drop(&mut self) {
+            self.0.run(&["down", "-f", self.1]);
+        }
+    }
+    let _down = Down(&host, &runtimes);
+
+    let output = host.run(&["up", "-f", &runtimes]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("2 started, 0 restarted, 0 stopped, 0 unchanged"), "{}", stdout(&output));
+    assert!(std::fs::read_to_string(host.path("hypervisor-alpha.log")).unwrap().contains("VM started successfully"));
+    let beta = pid("beta");
+
+    // Unchanged runtimes are left alone; a changed one boots again in place, a removed one stops
+    std::fs::write(&runtimes, runtime(2, "beta", 4)).unwrap();
+    let output = host.run(&["up", "-f", &runtimes]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = stdout(&output);
+    assert!(report.contains("alpha  removed, stopping (PID: ") && report.contains("alpha  stopped after"), "{}", report);
+    assert!(report.contains("beta  back after"), "{}", report);
+    assert!(report.contains("0 started, 1 restarted, 1 stopped, 0 unchanged"), "{}", report);
+    assert_eq!(pid("beta"), beta);
+    assert!(!std::path::Path::new(&host.path("run/vllmd-hypervisor-alpha.pid")).exists());
+
+    let output = host.run(&["up", "-f", &runtimes]);
+    assert!(stdout(&output).contains("beta  unchanged"), "{}", stdout(&output));
+
+    let output = host.run(&["down", "-f", &runtimes]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("beta  stopped after"), "{}", stdout(&output));
+    assert!(!std::path::Path::new(&host.path("run/vllmd-hypervisor-beta.pid")).exists());
+}
+
+#[test]
+fn remote_needs_a_running_runtime() {
+    let host = Host::mock();
+
+    let output = host.run(&["remote", "pause"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Runtime (unnamed) is not running"), "{}", stderr(&output));
+
+    let output = host.run(&["remote", "resize", "--memory", "32Q"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Invalid size unit"), "{}", stderr(&output));
+
+    let output = host.run(&["remote", "resize"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--vcpus"), "{}", stderr(&output));
+}
+
+#[test]
+fn libvirt_domain_imports_as_a_runtime() {
+    let host = Host::new();
+    std::fs::write(host.path("config.toml"), "[[runtimes]]\nindex = 3\nname = \"llama\"\n").unwrap();
+    std::fs::write(host.path("domain.xml"), r#"
+<domain type='kvm'>
+  <name>mistral</name>
+  <memory unit='MiB'>49152</memory>
+  <vcpu>8</vcpu>
+  <devices>
+    <disk type='file' device='disk'>
+      <source file='/var/lib/libvirt/images/mistral.raw'/>
+      <target dev='vda'/>
+    </disk>
+    <disk type='block' device='disk'>
+      <driver name='qemu' type='raw' cache='none'/>
+      <source dev='/dev/nvme1n1'/>
+      <target dev='vdb'/>
+    </disk>
+    <interface type='network'/>
+    <hostdev mode='subsystem' type='pci' managed='yes'>
+      <source><address domain='0x0000' bus='0x41' slot='0x00' function='0x1'/></source>
+    </hostdev>
+  </devices>
+</domain>"#).unwrap();
+
+    let output = host.run(&["import", "libvirt", &host.path("domain.xml")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Not imported: <interface> of type network"), "{}", stderr(&output));
+    let entry = stdout(&output);
+    for line in ["index = 4", "name = \"mistral\"", "memory_gb = 48", "cpus = 8", "gpus = [\"0000:41:00.1\"]",
+                 "system_image = \"/var/lib/libvirt/images/mistral.raw\"", "path = \"/dev/nvme1n1\"", "cache = \"none\""] {
+        assert!(entry.contains(line), "{}", entry);
+    }
+
+    // The entry appends to the configuration file as is
+    let config = std::fs::read_to_string(host.path("config.toml")).unwrap();
+    std::fs::write(host.path("config.toml"), format!("{}\n{}", config, entry)).unwrap();
+    let output = host.run(&["list"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("mistral"), "{}", stdout(&output));
+}
+
+#[test]
+fn qemu_command_line_imports_as_a_runtime() {
+    let host = Host::new();
+    std::fs::write(host.path("qemu.sh"), r#"#!/bin/sh
+set -e
+# GPU VM of the old host
+exec qemu-system-x86_64 -enable-kvm -name guest=llama,debug-threads=on \
+  -m 64G -smp 16 -cpu host \
+  -drive "file=/images/llama.raw,if=virtio,format=raw" \
+  -device vfio-pci,host=41:00.0 \
+  -netdev tap,id=net0
+"#).unwrap();
+
+    let output = host.run(&["import", "qemu-args", "--file", &host.path("qemu.sh")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Not imported: -netdev tap,id=net0"), "{}", stderr(&output));
+    let entry = stdout(&output);
+    for line in ["index = 1", "name = \"llama\"", "memory_gb = 64", "cpus = 16", "gpus = [\"0000:41:00.0\"]",
+                 "system_image = \"/images/llama.raw\""] {
+        assert!(entry.contains(line), "{}", entry);
+    }
+
+    let output = host.run(&["import", "qemu-args", "--", "qemu-system-x86_64", "-m", "8192"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("pass --name"), "{}", stderr(&output));
+}
+
+#[test]
+fn built_image_must_be_in_the_store() {
+    let mut host = Host::mock();
+    host.set("XDG_DATA_HOME", &host.path("data"));
+    host.set("VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH", "image://vllm-server:0.6");
+
+    let output = host.run(&["start"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("image://vllm-server:0.6 is not in the image store; create it with build-image"), "{}", stderr(&output));
+}
+
+#[test]
+fn devices_list_prints_json() {
+    let mut host = Host::mock();
+    let sys = host.path("sys");
+    host.set("VLLMD_MOCK_SYSFS", &sys);
+    let group = host.path("sys/kernel/iommu_groups/12");
+    std::fs::create_dir_all(format!("{}/devices", group)).unwrap();
+    std::fs::create_dir_all(host.path("sys/bus/pci/drivers/vfio-pci")).unwrap();
+    let device = |address: &str, class: &str, numa_node: &str| {
+        let path = host.path(&format!("sys/bus/pci/devices/{}", address));
+        std::fs::create_dir_all(&path).unwrap();
+        for (attribute, value) in [("class", class), ("vendor", "0x10de"), ("device", "0x2330"), ("numa_node", numa_node)] {
+            std::fs::write(format!("{}/{}", path, attribute), format!("{}\n", value)).unwrap();
+        }
+        path
+    };
+    // A passable GPU with an 80 GiB BAR, a GPU without an IOMMU group, and a NIC
+    let gpu = device("0000:41:00.0", "0x030200", "-1");
+    std::os::unix::fs::symlink(&group, format!("{}/iommu_group", gpu)).unwrap();
+    std::os::unix::fs::symlink(host.path("sys/bus/pci/drivers/vfio-pci"), format!("{}/driver", gpu)).unwrap();
+    std::fs::write(format!("{}/resource", gpu), "0x00000000fa000000 0x00000000faffffff 0x0000000000040200\n\
+                                                  0x0000020000000000 0x00000213ffffffff 0x000000000014220c\n").unwrap();
+    device("0000:42:00.0", "0x120000", "1");
+    device("0000:43:00.0", "0x020000", "0");
+
+    let output = host.run(&["devices", "list", "--json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let devices: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap_or_else(|e| panic!("{}: {}", e, stdout(&output)));
+    let devices = devices.as_array().unwrap();
+    assert_eq!(devices.len(), 2, "{:?}", devices);
+    assert_eq!(devices[0]["address"], "0000:41:00.0");
+    assert_eq!(devices[0]["vendor"], "NVIDIA");
+    assert_eq!(devices[0]["device_id"], "2330");
+    assert_eq!(devices[0]["class"], "030200");
+    assert_eq!(devices[0]["driver"], "vfio-pci");
+    assert_eq!(devices[0]["iommu_group"], 12);
+    assert!(devices[0]["numa_node"].is_null());
+    assert_eq!(devices[0]["memory"], 80u64 << 30);
+    assert_eq!(devices[0]["passable"], true);
+    assert_eq!(devices[1]["address"], "0000:42:00.0");
+    assert_eq!(devices[1]["numa_node"], 1);
+    assert_eq!(devices[1]["passable"], false);
+    assert_eq!(devices[1]["reason"], "in no IOMMU group (is the IOMMU enabled?)");
+}
+
+
+#[test]
+fn gpus_are_allocated_from_those_matching_the_selector() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "pool"
+gpus = 2
+gpu_selector = "vendor=nobody"
+
+[[runtimes]]
+index = 2
+name = "pinned"
+gpus = ["0000:41:00.0"]
+gpu_selector = "memory>=80G"
+"#).unwrap();
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "pool").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("2 GPU(s) requested but no GPU of the host meets gpu_selector"), "{}", stderr(&output));
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "pinned").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("gpu_selector needs gpus to be a number of GPUs"), "{}", stderr(&output));
+}
+
+#[test]
+fn nvme_device_must_exist() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "cache"
+devices = [{ type = "nvme", pci = "0000:ff:1f.7", namespace = 1 }]
+"#).unwrap();
+
+    for command in [&["start"][..], &["start", "--dry-run"]] {
+        let output = host.command(command).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "cache").output().unwrap();
+        assert!(!output.status.success());
+        assert!(stderr(&output).contains("No PCI device 0000:ff:1f.7"), "{}", stderr(&output));
+    }
+
+    // Only what boots the VM looks the device up
+    let output = host.command(&["inspect"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "cache").output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let inspection: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert!(inspection["configuration"].get("error").is_none(), "{}", inspection);
+    assert_eq!(inspection["configuration"]["device_list"][0]["pci"], "0000:ff:1f.7", "{}", inspection);
+}
+
+#[test]
+fn rdma_fields_need_an_rdma_device() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "fabric"
+devices = [{ type = "nvme", pci = "0000:ff:1f.6", vf = 1 }]
+"#).unwrap();
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "fabric").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("vf, node_guid and port_guid only apply to RDMA devices"), "{}", stderr(&output));
+}
+
+#[test]
+fn memory_zones_are_validated() {
+    let host = Host::mock();
+    let output = host.command(&["start"])
+        .env("VLLMD_HYPERVISOR_MEMORY_ZONE_LIST", "id=node0,size=1G,host_numa_node=4095")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("host NUMA node 4095, which the host does not have"), "{}", stderr(&output));
+
+    let output = host.command(&["start"])
+        .env("VLLMD_HYPERVISOR_MEMORY_ZONE_LIST", "id=node0,size=1G,guest_numa_node=0,guest_cpus=0-1:8")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("assigns vCPU 8, but the VM has 4 vCPUs"), "{}", stderr(&output));
+}
+
+#[test]
+fn sgx_epc_sections_are_parsed() {
+    let host = Host::mock();
+    let output = host.command(&["start"])
+        .env("VLLMD_HYPERVISOR_SGX_EPC_LIST", "id=epc0,size=64M,prefault=maybe")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Invalid prefault value in SGX EPC section: maybe"), "{}", stderr(&output));
+}
+
+#[test]
+fn hugepage_size_needs_a_pool() {
+    let host = Host::mock();
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_MEMORY_CONFIG", "size=1G,hugepage_size=3M").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("expected a page size such as 2M or 1G"), "{}", stderr(&output));
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_MEMORY_CONFIG", "size=1G,hugepage_size=64G").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("The host has no 64G hugepage pool"), "{}", stderr(&output));
+}
+
+#[test]
+fn thp_mode_is_validated() {
+    let host = Host::mock();
+    let output = host.command(&["env", "--check"]).env("VLLMD_HYPERVISOR_MEMORY_CONFIG", "size=1G,thp=always").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("expected on, off or never"), "{}", stdout(&output));
+}
+
+#[test]
+fn memory_string_is_strict() {
+    let host = Host::mock();
+    let check = |memory: &str| {
+        let output = host.command(&["env", "--check"]).env("VLLMD_HYPERVISOR_MEMORY_CONFIG", memory).output().unwrap();
+        stdout(&output)
+    };
+    assert!(check("size=1G,hotplug_method=virtio-mem").contains("hotplug_method in memory configuration needs a hotplug_size"));
+    assert!(check("size=1G,hotplug=4G").contains("Unknown memory configuration option 'hotplug'"));
+    assert!(check("size=20000000T").contains("Invalid size in memory configuration: Size is too large: 20000000T"));
+    assert!(check("size=16GiB,hotplug_size=1TB").lines().any(|line| line.starts_with("ok") && line.contains("VLLMD_HYPERVISOR_MEMORY_CONFIG")));
+    assert!(check("size=16GiB,hotplug_size=1TB,hotplug_method=virtio-mem").lines().any(|line| line.starts_with("ok") && line.contains("VLLMD_HYPERVISOR_MEMORY_CONFIG")));
+}
+
+#[test]
+fn runtimes_boot_with_fewer_vcpus_than_their_maximum() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "elastic"
+cpus = { boot = 2, max = 8 }
+"#).unwrap();
+    let output = host.command(&["inspect"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "elastic").output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let inspection: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(inspection["configuration"]["cpu_count"], 2, "{}", inspection);
+    assert_eq!(inspection["configuration"]["max_cpu_count"], 8, "{}", inspection);
+
+    let output = host.command(&["start"])
+        .env("VLLMD_HYPERVISOR_RUNTIME_NAME", "elastic")
+        .env("VLLMD_HYPERVISOR_CPU_COUNT", "16")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("The runtime boots with 16 vCPUs, more than its maximum of 8"), "{}", stderr(&output));
+}
+
+#[test]
+fn cpu_config_is_validated() {
+    let host = Host::mock();
+    let check = |cpu: &str| {
+        let output = host.command(&["env", "--check"]).env("VLLMD_HYPERVISOR_CPU_CONFIG", cpu).output().unwrap();
+        stdout(&output)
+    };
+    assert!(check("features=avx512").contains("Unknown CPU feature 'avx512'"));
+    assert!(check("max_phys_bits=64").contains("Invalid max_phys_bits in CPU configuration"));
+    assert!(check("max_phys_bits=46,features=amx,kvm_hyperv=on").lines().any(|line| line.starts_with("ok") && line.contains("VLLMD_HYPERVISOR_CPU_CONFIG")));
+
+    // The topology has to lay out every vCPU
+    let output = host.command(&["start", "--dry-run"])
+        .env("VLLMD_HYPERVISOR_CPU_COUNT", "8")
+        .env("VLLMD_HYPERVISOR_CPU_CONFIG", "topology=2:2:1:1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("CPU topology 2:2:1:1 makes 4 vCPUs, the VM has 8"), "{}", stderr(&output));
+}
+
+#[test]
+fn balloon_policy_is_validated() {
+    let host = Host::mock();
+    std::fs::write(host.path("config.toml"), r#"
+[[runtimes]]
+index = 1
+name = "idle"
+memory_gb = 1
+balloon = { floor = "2G" }
+
+[[runtimes]]
+index = 2
+name = "nightly"
+balloon = { floor = "1G", busy_hours = ["8am-6pm"] }
+"#).unwrap();
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "idle").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Balloon floor 2G is above the ceiling of guest memory"), "{}", stderr(&output));
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_RUNTIME_NAME", "nightly").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Invalid balloon busy_hours window '8am-6pm'"), "{}", stderr(&output));
+}
+
+#[test]
+fn disk_rate_limit_group_must_be_defined() {
+    let host = Host::mock();
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_DISK_RATE_LIMIT_GROUP", "runtime").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("The system and config images reference undefined rate limit group: runtime"), "{}", stderr(&output));
+}
+
+#[test]
+fn queue_size_must_be_a_power_of_two() {
+    let host = Host::mock();
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_DISK_LIST", format!("path={},queue_size=100", host.path("config.raw"))).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("has queue_size 100, which is not a power of two"), "{}", stderr(&output));
+}
+
+#[test]
+fn rng_source_is_checked() {
+    let host = Host::mock();
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_RNG", host.path("missing")).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Entropy source does not exist"), "{}", stderr(&output));
+
+    let output = host.command(&["start"]).env("VLLMD_HYPERVISOR_RNG", "off").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("always gives the guest a virtio-rng device"), "{}", stderr(&output));
+}
+
+#[test]
+fn watchdog_action_is_validated() {
+    let host = Host::mock();
+    let output = host.command(&["env", "--check"]).env("VLLMD_HYPERVISOR_WATCHDOG", "panic").output().unwrap();
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("expected off, reset or crash"), "{}", stdout(&output));
+}
+
+#[test]
+fn guest_panic_and_poweroff_end_the_vm() {
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_GUEST_LOG", "on");
+    let record = || -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(host.path("state/runtimes/hypervisor.json")).unwrap()).unwrap()
+    };
+
+    // A guest powering itself off is a clean exit
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.api("power-button", "").contains("204"));
+    assert_eq!(start.0.wait().unwrap().code(), Some(0), "{}", host.log());
+    assert_eq!(record()["last_exit"]["reason"], "poweroff");
+
+    // A panicked kernel halts without powering off; its log tells
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    std::fs::write(host.path("state/logs/hypervisor-guest.log"), "[    2.0] Kernel panic - not syncing: Attempted to kill init!\n").unwrap();
+    assert_eq!(start.0.wait().unwrap().code(), Some(3), "{}", host.log());
+    assert!(host.log().contains("Guest kernel panicked"), "{}", host.log());
+    assert_eq!(record()["last_exit"]["reason"], "panic");
+}
+
+#[test]
+fn guest_reboot_follows_the_reboot_policy() {
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_EVENT_LOG_FILEPATH", &host.path("events.jsonl"));
+    let events = || std::fs::read_to_string(host.path("events.jsonl")).unwrap_or_default();
+
+    // A reboot inside the guest replaces the vCPU threads, and the VM keeps running
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.api("reboot", "").contains("204"));
+    wait_for("the reboot event", Duration::from_secs(10), || events().contains("\"rebooted\""));
+    assert!(events().contains("guest reboot"), "{}", events());
+    assert_eq!(host.state().as_deref(), Some("running"));
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+
+    host.set("VLLMD_HYPERVISOR_ON_REBOOT", "stop");
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.api("reboot", "").contains("204"));
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+    assert!(host.log().contains("Guest rebooted and on_reboot is stop"), "{}", host.log());
+    let record: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(host.path("state/runtimes/hypervisor.json")).unwrap()).unwrap();
+    assert_eq!(record["last_exit"]["reason"], "reboot", "{}", record);
+}
+
+#[test]
+fn vcpu_hotplug_is_not_a_reboot() {
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_EVENT_LOG_FILEPATH", &host.path("events.jsonl"));
+    host.set("VLLMD_HYPERVISOR_CPU_COUNT", "2");
+    host.set("VLLMD_HYPERVISOR_MAX_CPU_COUNT", "4");
+    host.set("VLLMD_HYPERVISOR_ON_REBOOT", "stop");
+    let events = || std::fs::read_to_string(host.path("events.jsonl")).unwrap_or_default();
+
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    for vcpus in ["4", "1", "3"] {
+        let output = host.run(&["remote", "resize", "--vcpus", vcpus]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        // Longer than the reset detector takes to look at the vCPUs again
+        std::thread::sleep(Duration::from_millis(1500));
+    }
+    let output = host.run(&["remote", "resize", "--vcpus", "5"]);
+    assert!(!output.status.success());
+
+    assert_eq!(host.state().as_deref(), Some("running"), "{}", host.log());
+    assert!(!events().contains("\"rebooted\"") && !events().contains("\"shutdown\""), "{}", events());
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+}
+
+#[test]
+fn vcpu_sched_applies_to_hotplugged_vcpus() {
+    let mut host = Host::mock();
+    host.set("VLLMD_HYPERVISOR_CPU_COUNT", "1");
+    host.set("VLLMD_HYPERVISOR_MAX_CPU_COUNT", "3");
+    host.set("VLLMD_HYPERVISOR_VCPU_SCHED", "nice:5");
+
+    let mut start = host.start();
+    wait_for("the VM to run", Duration::from_secs(10), || host.state().as_deref() == Some("running"));
+    assert!(host.log().contains("Applied Nice(5) to 1 vCPU thread(s)"), "{}", host.log());
+    let output = host.run(&["remote", "resize", "--vcpus", "3"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    wait_for("the new vCPUs to be scheduled", Duration::from_secs(10), || host.log().contains("Applied Nice(5) to 3 vCPU thread(s)"));
+
+    // So do the vCPUs of a guest that reset
+    assert!(host.api("reboot", "").contains("204"));
+    wait_for("the reset vCPUs to be scheduled", Duration::from_secs(10),
+             || host.log().matches("Applied Nice(5) to 3 vCPU thread(s)").count() == 2);
+    assert!(host.log().contains("Guest rebooted; waiting"), "{}", host.log());
+    assert!(host.run(&["stop"]).status.success());
+    assert!(start.0.wait().unwrap().success(), "{}", host.log());
+}
+
+#[test]
+fn reboot_policy_excludes_watchdog_reset() {
+    let host = Host::mock();
+    let output = host.command(&["start"])
+        .env("VLLMD_HYPERVISOR_WATCHDOG", "reset")
+        .env("VLLMD_HYPERVISOR_ON_REBOOT", "stop")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("watchdog = reset needs on_reboot = restart"), "{}", stderr(&output));
+}
+
+#[test]
+fn forward_mode_is_explicit() {
+    let host = Host::mock();
+    let output = host.run(&["forward", "runtime-1", "8000:8000", "--guest-ip", "10.0.0.2"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--guest-ip only applies to --mode dnat"), "{}", stderr(&output));
+
+    let output = host.run(&["forward", "runtime-1", "8000:8000", "--mode", "dnat"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--guest-ip"), "{}", stderr(&output));
+}
@@ -0,0 +1,138 @@
+//! Helpers shared by the integration tests. Each test binary uses a different subset of them.
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+#[path = "../../src/scratch.rs"]
+mod scratch;
+
+pub const BINARY: &str = env!("CARGO_BIN_EXE_vllmd-hypervisor");
+
+/// Scratch directory holding boot artifacts, runtime files and logs, with an environment that
+/// points vllmd-hypervisor at nothing else
+pub struct Host {
+    pub dir: PathBuf,
+    env: Vec<(String, String)>,
+}
+
+impl Host {
+    /// Host with an empty kernel, system image and config image of 1 MiB each
+    pub fn new() -> Self {
+        let dir = scratch::dir("vllmd-test");
+        std::fs::create_dir_all(dir.join("run")).unwrap();
+        for artifact in ["vmlinux", "system.raw", "config.raw"] {
+            std::fs::File::create(dir.join(artifact)).unwrap().set_len(1 << 20).unwrap();
+        }
+
+        let mut host = Self { dir, env: Vec::new() };
+        let base = [
+            ("HOME", host.path("")),
+            ("XDG_RUNTIME_DIR", host.path("run")),
+            ("VLLMD_HYPERVISOR_CONFIG", host.path("config.toml")),
+            ("VLLMD_HYPERVISOR_STATE_DIR", host.path("state")),
+            ("VLLMD_HYPERVISOR_LOG_FILEPATH", host.path("hypervisor.log")),
+            ("VLLMD_HYPERVISOR_KERNEL_FILEPATH", host.path("vmlinux")),
+            ("VLLMD_HYPERVISOR_SYSTEM_IMAGE_FILEPATH", host.path("system.raw")),
+            ("VLLMD_HYPERVISOR_CONFIG_IMAGE_FILEPATH", host.path("config.raw")),
+        ];
+        for (key, value) in base {
+            host.set(key, &value);
+        }
+        host
+    }
+
+    /// Host whose VMs run on the mock backend
+    pub fn mock() -> Self {
+        let mut host = Self::new();
+        host.set("VLLMD_HYPERVISOR_BACKEND", "mock");
+        host
+    }
+
+    /// Set an environment variable for every command run on this host
+    pub fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        self.env.retain(|(existing, _)| existing != key);
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(BINARY);
+        command.args(args).env_clear().envs(self.env.iter().map(|(key, value)| (key, value)));
+        command
+    }
+
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    /// Run `start` in the background
+    pub fn start(&self) -> Started {
+        Started(self.command(&["start"]).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap())
+    }
+
+    /// Lifecycle state written by `start` for `status`
+    pub fn state(&self) -> Option<String> {
+        std::fs::read_to_string(self.runtime_file("state")).ok()
+    }
+
+    /// PID file, sockets and state files of the runtime, which `start` shares its stem with
+    pub fn runtime_file(&self, extension: &str) -> PathBuf {
+        Path::new(&self.path("run/vllmd-hypervisor.pid")).with_extension(extension)
+    }
+
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.join("hypervisor.log")).unwrap_or_default()
+    }
+
+    /// Send `PUT /api/v1/vm.<command>` to the API socket of the running VM and return the status
+    /// line of the answer
+    pub fn api(&self, command: &str, body: &str) -> String {
+        let mut socket = UnixStream::connect(self.runtime_file("sock")).unwrap();
+        write!(socket, "PUT /api/v1/vm.{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+               command, body.len(), body).unwrap();
+        let mut answer = String::new();
+        socket.read_to_string(&mut answer).unwrap();
+        answer.lines().next().unwrap_or_default().to_string()
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// `start` running in the background, killed if the test fails before stopping it
+pub struct Started(pub Child);
+
+impl Drop for Started {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Poll `condition` until it holds, failing the test after `timeout`
+pub fn wait_for(what: &str, timeout: Duration, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
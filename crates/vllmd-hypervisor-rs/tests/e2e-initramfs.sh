@@ -0,0 +1,36 @@
+#!/bin/sh
+# Build the busybox initramfs booted by tests/e2e.rs. Its init prints VLLMD-E2E-READY on the
+# console every second, so the test can attach to the serial console at any point.
+#
+# Usage: tests/e2e-initramfs.sh [OUTPUT]   (default: e2e-initramfs.cpio.gz)
+# BUSYBOX selects a statically linked busybox binary; the one on PATH is used otherwise.
+set -eu
+
+output=$(realpath -m "${1:-e2e-initramfs.cpio.gz}")
+busybox=${BUSYBOX:-$(command -v busybox)}
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+mkdir -p "$work/bin" "$work/dev" "$work/proc" "$work/sys"
+cp "$busybox" "$work/bin/busybox"
+for applet in sh echo sleep mount; do
+    ln -s busybox "$work/bin/$applet"
+done
+
+cat > "$work/init" <<'INIT'
+#!/bin/sh
+mount -t devtmpfs devtmpfs /dev
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+# The archive has no device nodes, so the kernel could not hand init a console
+exec > /dev/console 2>&1
+while true; do
+    echo VLLMD-E2E-READY
+    sleep 1
+done
+INIT
+chmod +x "$work/init"
+
+(cd "$work" && find . | cpio -o -H newc --quiet) | gzip > "$output"
+echo "Wrote $output"
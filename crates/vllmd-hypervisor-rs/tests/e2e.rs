@@ -0,0 +1,145 @@
+//! End-to-end tests that boot a real guest: a small kernel with the busybox initramfs built by
+//! `tests/e2e-initramfs.sh`, whose init prints a marker on the serial console. They need
+//! /dev/kvm (or /dev/mshv) and are ignored by default:
+//!
+//! ```sh
+//! tests/e2e-initramfs.sh /tmp/e2e-initramfs.cpio.gz
+//! VLLMD_E2E_KERNEL=/path/to/vmlinux VLLMD_E2E_INITRAMFS=/tmp/e2e-initramfs.cpio.gz \
+//!     cargo test --test e2e -- --ignored
+//! ```
+
+mod common;
+
+use common::{stdout, wait_for, Host, Started};
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Printed every second by the initramfs init
+const MARKER: &str = "VLLMD-E2E-READY";
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn artifact(var: &str) -> String {
+    std::env::var(var).unwrap_or_else(|_| panic!("{} must be set; see the top of tests/e2e.rs", var))
+}
+
+// Host booting the test kernel and initramfs in a small VM
+fn guest_host() -> Host {
+    let mut host = Host::new();
+    host.set("VLLMD_HYPERVISOR_KERNEL_FILEPATH", &artifact("VLLMD_E2E_KERNEL"))
+        .set("VLLMD_HYPERVISOR_INITRAMFS_FILEPATH", &artifact("VLLMD_E2E_INITRAMFS"))
+        .set("VLLMD_HYPERVISOR_CPU_COUNT", "1")
+        .set("VLLMD_HYPERVISOR_MEMORY_CONFIG", "size=512M");
+    host
+}
+
+// Read the serial console until the guest prints the marker
+fn wait_for_marker(host: &Host) {
+    let socket = host.runtime_file("console.sock");
+    wait_for("the serial console socket", BOOT_TIMEOUT, || socket.exists());
+
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    let deadline = Instant::now() + BOOT_TIMEOUT;
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 4096];
+    while !String::from_utf8_lossy(&output).contains(MARKER) {
+        let console = String::from_utf8_lossy(&output);
+        assert!(Instant::now() < deadline, "no {} on the serial console:\n{}\n{}", MARKER, console, host.log());
+        match stream.read(&mut buffer) {
+            Ok(0) => panic!("serial console closed before {}:\n{}\n{}", MARKER, console, host.log()),
+            Ok(read) => output.extend_from_slice(&buffer[..read]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+            Err(e) => panic!("failed to read the serial console: {}", e),
+        }
+    }
+}
+
+// Stop the VM and check that `start` exits cleanly and removes its runtime files
+fn stop_and_check_teardown(host: &Host, mut start: Started) {
+    assert!(host.run(&["stop"]).status.success());
+    let exit = start.0.wait().unwrap();
+    assert!(exit.success(), "start exited with {}\n{}", exit, host.log());
+
+    for extension in ["pid", "sock", "console.sock", "state"] {
+        assert!(!host.runtime_file(extension).exists(), "{} left behind", extension);
+    }
+    assert!(stdout(&host.run(&["status"])).contains("Not running"));
+    assert!(host.log().contains("VM shutdown complete"), "{}", host.log());
+}
+
+#[test]
+#[ignore = "boots a VM; needs /dev/kvm and VLLMD_E2E_KERNEL/VLLMD_E2E_INITRAMFS"]
+fn boot_status_stop() {
+    let host = guest_host();
+    let start = host.start();
+
+    wait_for_marker(&host);
+    assert_eq!(host.state().as_deref(), Some("running"));
+    let status = stdout(&host.run(&["status"]));
+    assert!(status.contains(&format!("Running (PID: {}", start.0.id())), "{}", status);
+
+    stop_and_check_teardown(&host, start);
+}
+
+#[test]
+#[ignore = "boots a VM; needs /dev/kvm and VLLMD_E2E_KERNEL/VLLMD_E2E_INITRAMFS"]
+fn snapshot_running_guest() {
+    let host = guest_host();
+    let start = host.start();
+    wait_for_marker(&host);
+
+    let snapshot_dir = host.path("snapshot");
+    let output = host.run(&["snapshot", "--dir", &snapshot_dir]);
+    assert!(output.status.success(), "{}\n{}", common::stderr(&output), host.log());
+    for file in ["config.json", "state.json"] {
+        assert!(host.dir.join("snapshot").join(file).exists(), "snapshot has no {}", file);
+    }
+
+    // The guest resumes after the snapshot and keeps printing
+    wait_for_marker(&host);
+    assert_eq!(host.state().as_deref(), Some("running"));
+
+    stop_and_check_teardown(&host, start);
+}
+
+#[test]
+#[ignore = "boots a VM; needs /dev/kvm and VLLMD_E2E_KERNEL/VLLMD_E2E_INITRAMFS"]
+fn ephemeral_overlay_is_removed() {
+    let mut host = guest_host();
+    host.set("VLLMD_HYPERVISOR_SYSTEM_IMAGE_EPHEMERAL", "1");
+    let start = host.start();
+    wait_for_marker(&host);
+
+    let overlays = || std::fs::read_dir(host.dir.join("state/overlays")).map(|dir| dir.count()).unwrap_or(0);
+    assert_eq!(overlays(), 1);
+
+    stop_and_check_teardown(&host, start);
+    assert_eq!(overlays(), 0);
+}
+
+#[test]
+#[ignore = "boots a VM as nobody; needs /dev/kvm, root and VLLMD_E2E_KERNEL/VLLMD_E2E_INITRAMFS"]
+fn run_as_boots_the_vmm_without_root() {
+    let host = guest_host();
+    // The VMM opens the images as nobody, under the default seccomp filters
+    let mode = |path: PathBuf, mode: u32| std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    mode(host.dir.clone(), 0o755);
+    mode(host.dir.join("run"), 0o777);
+    mode(host.dir.join("system.raw"), 0o666);
+    let start = Started(host.command(&["start", "--run-as", "nobody"]).spawn().unwrap());
+    wait_for_marker(&host);
+
+    let status = std::fs::read_to_string(format!("/proc/{}/status", start.0.id())).unwrap();
+    assert!(status.lines().any(|line| line.starts_with("Uid:\t65534\t")), "{}", status);
+
+    // Records after the switch still reach the root-owned audit log
+    stop_and_check_teardown(&host, start);
+    let audit = std::fs::read_to_string(host.dir.join("state/audit.jsonl")).unwrap();
+    assert!(audit.lines().any(|line| line.contains("\"operation\":\"shutdown\"")), "{}", audit);
+    assert!(host.run(&["audit", "verify"]).status.success());
+}
@@ -94,6 +94,7 @@ fi
 parse_toml() {
     python3 - "$CONFIG_PATH" <<EOF
 import sys
+import copy
 import json
 try:
     import tomli as toml
@@ -104,9 +105,35 @@ except ImportError:
         print("Error: No TOML parser found. Install with: pip install tomli")
         sys.exit(1)
 
+def merge(base, overrides):
+    # Tables merge key by key, any other value replaces the base one
+    for key, value in overrides.items():
+        if isinstance(value, dict) and isinstance(base.get(key), dict):
+            merge(base[key], value)
+        else:
+            base[key] = value
+    return base
+
+def resolve(templates, name, chain):
+    # Settings of a template merged over those of the templates it extends
+    if name in chain:
+        raise ValueError("templates " + " -> ".join(chain + [name]) + " extend each other in a cycle")
+    if name not in templates:
+        raise ValueError("template " + name + " is not defined")
+    template = copy.deepcopy(templates[name])
+    parent = template.pop("extends", None)
+    if parent is None:
+        return template
+    return merge(resolve(templates, parent, chain + [name]), template)
+
 try:
     with open(sys.argv[1], "rb") as f:
         config = toml.load(f)
+    templates = config.pop("templates", {})
+    config["runtimes"] = [
+        merge(resolve(templates, runtime["extends"], []), runtime) if "extends" in runtime else runtime
+        for runtime in config.get("runtimes", [])
+    ]
     print(json.dumps(config))
 except Exception as e:
     print(f"Error parsing TOML: {e}", file=sys.stderr)
@@ -300,6 +327,51 @@ for runtime in config["runtimes"]:
 '
 fi
 
+# Order the units of runtimes with depends_on after the units of their dependencies
+echo "$CONFIG_JSON" | DRY_RUN="$DRY_RUN" SYSTEMD_USER_DIR="$SYSTEMD_USER_DIR" python3 -c '
+import sys
+import json
+import os
+
+config = json.load(sys.stdin)
+dry_run = os.environ["DRY_RUN"] == "1"
+systemd_user_dir = os.environ["SYSTEMD_USER_DIR"]
+
+indexes = {runtime["name"]: runtime["index"] for runtime in config.get("runtimes", [])
+           if "index" in runtime and "name" in runtime}
+
+for runtime in config.get("runtimes", []):
+    depends_on = runtime.get("depends_on", [])
+    if not depends_on or "index" not in runtime:
+        continue
+
+    index = runtime["index"]
+    units = []
+    for name in depends_on:
+        if name not in indexes:
+            print(f"Error: Runtime {index} depends on unknown runtime {name}")
+            sys.exit(1)
+        units.append(f"vllmd-runtime@{indexes[name]}.service")
+    units = " ".join(units)
+
+    # With Type=notify and start --wait-ready in the dependency, After= waits for its readiness
+    content = f"[Unit]\nRequires={units}\nAfter={units}\n"
+    path = os.path.join(systemd_user_dir, f"vllmd-runtime@{index}.service.d", "depends-on.conf")
+
+    if dry_run:
+        print(f"[DRY RUN] Would create file: {path}")
+        print("[DRY RUN] Content would be:")
+        print("[DRY RUN] ----------------------------------------")
+        for line in content.splitlines():
+            print(f"[DRY RUN] {line}")
+        print("[DRY RUN] ----------------------------------------")
+    else:
+        os.makedirs(os.path.dirname(path), exist_ok=True)
+        with open(path, "w") as f:
+            f.write(content)
+        print(f"Created dependency drop-in for runtime-{index}")
+'
+
 # Reload systemd user daemon
 if [[ "$DRY_RUN" -eq 1 ]]; then
     print_dry_run "Would reload systemd user daemon"